@@ -0,0 +1,183 @@
+//! Benchmarks of the conversion's hot paths on synthetic filesystems. Run with `cargo bench`.
+
+use std::convert::TryFrom;
+use std::marker::PhantomData;
+use std::mem::size_of;
+use std::ops::Range;
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use ofs_convert_rs::allocator::Allocator;
+use ofs_convert_rs::ext4::{
+    BlockSize, Extent, ExtentBlockAllocator, ExtentHeader, ExtentTree, ExtentTreeElement, ExtentTreeLevel,
+    EXTENT_ENTRIES_IN_INODE,
+};
+use ofs_convert_rs::fat::{ClusterIdx, FatFs, FatImage, FatImageBuilder, FatTableIndex, TestFile, ROOT_FAT_IDX};
+use ofs_convert_rs::ranges::{FreeRangeCursor, NotCoveredRange, Ranges};
+use ofs_convert_rs::serialization::FatTreeSerializer;
+
+const KIB: usize = 1024;
+const MIB: usize = 1024 * KIB;
+
+/// A directory tree with `width` children per directory and `depth` levels of directories, whose files have sizes
+/// between 0 and 16 KiB.
+fn synthetic_tree(width: usize, depth: usize) -> Vec<TestFile> {
+    (0..width)
+        .map(|idx| {
+            let name = format!("file with a long name {}", idx);
+            if depth > 0 && idx % 4 == 0 {
+                TestFile::Directory { name, children: synthetic_tree(width, depth - 1) }
+            } else {
+                TestFile::RegularFile { name, size: (idx * 1237 % (16 * KIB)) as u32 }
+            }
+        })
+        .collect()
+}
+
+fn fragmented_image() -> FatImage {
+    FatImageBuilder::new(64 * MIB, KIB).fragmented().build(&synthetic_tree(16, 3))
+}
+
+fn fat_used_ranges(c: &mut Criterion) {
+    let mut image = fragmented_image();
+    // SAFETY: Safe because `image` contains a consistent FAT32 filesystem and outlives `fat_fs`.
+    let fat_fs = unsafe { FatFs::new(image.as_mut_ptr(), image.len(), PhantomData) }.unwrap();
+    c.bench_function("FatFs::used_ranges (fragmented, 64 MiB, 1 KiB clusters)", |b| {
+        b.iter(|| black_box(fat_fs.used_ranges()))
+    });
+}
+
+/// Compares `FatFs::used_ranges` to inserting every used cluster into `Ranges` individually, which it used to do.
+fn fat_used_ranges_full(c: &mut Criterion) {
+    let mut image = FatImageBuilder::new(64 * MIB, KIB).build(&[TestFile::RegularFile {
+        name: "large file".to_string(),
+        size: 60 * MIB as u32,
+    }]);
+    // SAFETY: Safe because `image` contains a consistent FAT32 filesystem and outlives `fat_fs`.
+    let fat_fs = unsafe { FatFs::new(image.as_mut_ptr(), image.len(), PhantomData) }.unwrap();
+
+    let mut group = c.benchmark_group("FatFs::used_ranges (60 MiB file, 1 KiB clusters)");
+    group.bench_function("coalesced runs", |b| b.iter(|| black_box(fat_fs.used_ranges())));
+    group.bench_function("one cluster at a time", |b| {
+        b.iter(|| {
+            let mut ranges: Ranges<ClusterIdx> = Ranges::new();
+            ranges.insert(0..fat_fs.boot_sector().first_data_cluster());
+            for (fat_idx, fat_cell) in fat_fs.fat_table().iter().enumerate().skip(usize::from(ROOT_FAT_IDX)) {
+                if !fat_cell.is_free() {
+                    let cluster_idx = FatTableIndex::try_from(fat_idx).unwrap().to_cluster_idx(fat_fs.boot_sector());
+                    ranges.insert(cluster_idx..cluster_idx + 1);
+                }
+            }
+            black_box(ranges)
+        })
+    });
+    group.finish();
+}
+
+fn ranges_insert(c: &mut Criterion) {
+    const RANGE_COUNT: u32 = 20_000;
+    // visits every range exactly once in a scattered order, since 7919 is coprime to `RANGE_COUNT`
+    let ranges: Vec<Range<u32>> = (0..RANGE_COUNT)
+        .map(|idx| {
+            let start = (idx * 7919 % RANGE_COUNT) * 4;
+            start..start + 2
+        })
+        .collect();
+    c.bench_function("Ranges::insert (20000 scattered ranges)", |b| {
+        b.iter(|| black_box(Ranges::from(ranges.iter().cloned())))
+    });
+}
+
+/// Compares walking over every gap of a heavily fragmented `Ranges` with a `FreeRangeCursor` to searching for each gap
+/// with `Ranges::next_not_covered`, which the allocator used to do.
+fn ranges_free_gaps(c: &mut Criterion) {
+    const RANGE_COUNT: u32 = 100_000;
+    let ranges = Ranges::from((0..RANGE_COUNT).map(|idx| 3 * idx..3 * idx + 2));
+    let gap_end = |gap| match gap {
+        NotCoveredRange::Bounded(range) => Some(range.end),
+        NotCoveredRange::Unbounded(_) => None,
+    };
+
+    let mut group = c.benchmark_group("Ranges gap walk (100000 ranges)");
+    group.bench_function("FreeRangeCursor", |b| {
+        b.iter(|| {
+            let mut cursor = FreeRangeCursor::new(&ranges, 0);
+            while let Some(end) = gap_end(cursor.next_free(&ranges)) {
+                cursor.advance_to(&ranges, end);
+            }
+            black_box(cursor.position())
+        })
+    });
+    group.bench_function("Ranges::next_not_covered", |b| {
+        b.iter(|| {
+            let mut position = 0;
+            while let Some(end) = gap_end(ranges.next_not_covered(position)) {
+                position = end;
+            }
+            black_box(position)
+        })
+    });
+    group.finish();
+}
+
+fn extent_tree(c: &mut Criterion) {
+    const EXTENT_COUNT: usize = 20_000;
+    const BLOCK_SIZE: BlockSize = 4096;
+    let cluster_size = BLOCK_SIZE as usize;
+    let cluster_count = 2 * ExtentTree::required_block_count(EXTENT_COUNT, BLOCK_SIZE);
+
+    c.bench_function("ExtentTree::add_extent (20000 extents, 4 KiB blocks)", |b| {
+        b.iter_batched_ref(
+            || vec![0_u64; cluster_count * cluster_size / size_of::<u64>()],
+            |memory| {
+                // SAFETY: Safe because `memory` outlives `allocator` and is not accessed by anyone else.
+                let allocator = unsafe {
+                    Allocator::new(
+                        memory.as_mut_ptr() as *mut u8,
+                        memory.len() * size_of::<u64>(),
+                        cluster_size,
+                        Ranges::new(),
+                        PhantomData,
+                    )
+                };
+                let header = ExtentHeader::new(EXTENT_ENTRIES_IN_INODE);
+                let mut root_entries = [ExtentTreeElement { header }; EXTENT_ENTRIES_IN_INODE as usize];
+                // SAFETY: Safe because the root level has a valid header and no valid entries.
+                let root_level = unsafe { ExtentTreeLevel::new(&mut root_entries) };
+                let mut tree = ExtentTree::new(root_level, ExtentBlockAllocator::new(&allocator, BLOCK_SIZE, 1));
+                for idx in 0..EXTENT_COUNT {
+                    // the data blocks are never accessed, so they can be arbitrary
+                    let extent = Extent::new(2 * idx..2 * idx + 1, idx as u32);
+                    tree.add_extent(extent).unwrap();
+                }
+            },
+            BatchSize::LargeInput,
+        )
+    });
+}
+
+fn directory_serialization(c: &mut Criterion) {
+    c.bench_function("FatTreeSerializer::serialize_directory_tree (fragmented, 64 MiB)", |b| {
+        b.iter_batched_ref(
+            fragmented_image,
+            |image| {
+                // SAFETY: Safe because `image` contains a consistent FAT32 filesystem and outlives the serializer.
+                let (fat_fs, allocator) =
+                    unsafe { FatFs::new_with_allocator(image.as_mut_ptr(), image.len(), PhantomData) }.unwrap();
+                let mut serializer = FatTreeSerializer::new(allocator, fat_fs, Ranges::new());
+                serializer.serialize_directory_tree().unwrap();
+            },
+            BatchSize::LargeInput,
+        )
+    });
+}
+
+criterion_group!(
+    benches,
+    fat_used_ranges,
+    fat_used_ranges_full,
+    ranges_insert,
+    ranges_free_gaps,
+    extent_tree,
+    directory_serialization
+);
+criterion_main!(benches);
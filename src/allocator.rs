@@ -3,14 +3,18 @@ use std::convert::TryFrom;
 use std::fmt::{Display, Formatter};
 use std::marker::PhantomData;
 use std::ops::Range;
+use std::rc::Rc;
 use std::slice;
 
 use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
 
+use crate::error::ErrorCategory;
 use crate::ext4::BlockIdx;
 use crate::fat::ClusterIdx;
-use crate::ranges::{NotCoveredRange, Ranges};
-use crate::util::{AddUsize, FromU32};
+use crate::ranges::{FreeRangeCursor, NotCoveredRange, Ranges};
+use crate::trace::{Trace, TraceEvent};
+use crate::util::{AddUsize, Clusters, FromU32};
 
 /// An `AllocatedClusterIdx` represents a cluster that was allocated by an `Allocator` and functions as a token to
 /// access that cluster, either through the `Allocator` itself or through the `AllocatedReader` derived from it.
@@ -36,9 +40,12 @@ impl AllocatedClusterIdx {
         self.0
     }
 
+    /// Returns the index of the cluster's first ext4 block.
     /// SAFETY: This is safe since it cannot be converted back to an `AllocatedClusterIdx` or to a `DataClusterIdx`.
-    pub fn as_block_idx(&self) -> BlockIdx {
-        BlockIdx::fromx(self.0)
+    pub fn first_block_idx(&self, blocks_per_cluster: u32) -> BlockIdx {
+        Clusters::from(self.0)
+            .first_block_idx(blocks_per_cluster)
+            .expect("An allocated cluster lies within the partition, so its blocks can be indexed")
     }
 }
 
@@ -101,6 +108,85 @@ impl Iterator for AllocatedIterMut<'_> {
     }
 }
 
+/// What an `Allocator` allocates a cluster for, so that `AllocatorStats` can tell the allocations apart.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AllocationPurpose {
+    /// the serialized FAT directory tree
+    Archive,
+    /// copies of file data that would be overwritten by ext4 metadata
+    Relocation,
+    /// extent tree nodes and slow symlink targets
+    Metadata,
+    /// ext4 directory entries
+    Dentries,
+}
+
+/// The number of clusters an `Allocator` has allocated, by purpose.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct AllocatorStats {
+    pub archive: usize,
+    pub relocation: usize,
+    pub metadata: usize,
+    pub dentries: usize,
+    /// the index after the highest allocated cluster, or 0 if nothing was allocated
+    pub high_water_mark: ClusterIdx,
+}
+
+impl AllocatorStats {
+    pub fn total(&self) -> usize {
+        self.archive + self.relocation + self.metadata + self.dentries
+    }
+
+    fn record(&mut self, range: &Range<ClusterIdx>, purpose: AllocationPurpose) {
+        let count = match purpose {
+            AllocationPurpose::Archive => &mut self.archive,
+            AllocationPurpose::Relocation => &mut self.relocation,
+            AllocationPurpose::Metadata => &mut self.metadata,
+            AllocationPurpose::Dentries => &mut self.dentries,
+        };
+        *count += usize::fromx(range.end - range.start);
+        self.high_water_mark = self.high_water_mark.max(range.end);
+    }
+}
+
+/// The interface through which the serialization and the deserialization allocate clusters, so that tests can
+/// replace `Allocator` with allocators that fail or fragment on purpose. Implementations must uphold the guarantees of
+/// `Allocator`: a cluster is only handed out once, and only clusters that may be overwritten are handed out.
+pub trait ClusterAllocator {
+    /// Returns a cluster range that may be exclusively used by the caller, with 1 <= `range.len()` <= `max_length`.
+    /// Returns an error if `max_length` is 0, since the range could not satisfy both bounds.
+    fn allocate(&self, max_length: u32, purpose: AllocationPurpose) -> Result<AllocatedRange>;
+
+    /// Returns a cluster that may be exclusively used by the caller.
+    fn allocate_one(&self, purpose: AllocationPurpose) -> Result<AllocatedClusterIdx> {
+        Ok(Range::from(self.allocate(1, purpose)?).start)
+    }
+
+    /// PANICS: Panics if `idx` out of bounds. This is only possible if `idx` was not allocated by `self`.
+    // The exclusive access comes from `idx` rather than from `self`: an allocator hands out every
+    // `AllocatedClusterIdx` only once (see its invariant), so only the owner of `idx` can reach the cluster, while
+    // `self` stays shared so that the serialization can keep allocating while it writes to its clusters.
+    #[allow(clippy::mut_from_ref)]
+    fn cluster_mut(&self, idx: &mut AllocatedClusterIdx) -> &mut [u8];
+
+    /// The number of clusters that can still be allocated, i.e. the number of clusters that allocating until the
+    /// allocation fails would return. The dry run compares this to the clusters the deserialization needs, so it must
+    /// be exact. After `split_into_reader`, the clusters before the split are not counted, so once the archive has
+    /// been finished and split off, see `StreamArchiver::into_reader`, the figure only changes by allocating.
+    fn free_block_count(&self) -> usize;
+
+    fn stats(&self) -> AllocatorStats;
+
+    /// Records `event` in the trace of the conversion, if there is one, see `Allocator::set_trace`.
+    fn trace(&self, event: TraceEvent);
+
+    /// Splits the allocator into an `AllocatedReader` and an allocator: the `AllocatedReader` can only read clusters
+    /// that were allocated by `self`, the allocator can only write and read clusters that could have been allocated by
+    /// `self` but were not yet allocated.
+    fn split_into_reader<'r>(self) -> (AllocatedReader<'r>, Self)
+    where Self: Sized + 'r;
+}
+
 /// Allocates clusters that are not marked as in use (specifically, clusters that are marked as free in the FAT and
 /// which will not be overwritten by Ext4 block group metadata). Callers are guaranteed that a cluster allocated to them
 /// will not be accessed anywhere else. They can access such a cluster through the methods `cluster` and `cluster_mut`.
@@ -114,12 +200,17 @@ pub struct Allocator<'a> {
     fs_ptr: *mut u8,
     /// clusters outside this range can neither be allocated nor accessed over the methods `cluster` and `cluster_mut`
     valid_cluster_indices: Range<ClusterIdx>,
-    /// the cluster that the Allocator will try to allocate next.
-    /// Invariant: `valid_cluster_indices.contains(cursor.get())`
-    cursor: Cell<ClusterIdx>,
+    /// points to the cluster that the Allocator will try to allocate next.
+    /// Invariant: `valid_cluster_indices.contains(cursor.get().position())`, `cursor` walks over `used_ranges`, and
+    /// every cluster before the cursor is either in `used_ranges` or has been allocated
+    cursor: Cell<FreeRangeCursor<ClusterIdx>>,
     /// clusters that will not be allocated
     used_ranges: Ranges<ClusterIdx>,
     cluster_size: usize,
+    /// includes the clusters allocated before a call to `split_into_reader`
+    stats: Cell<AllocatorStats>,
+    /// records the allocations, and the other steps of the conversion that write to the partition, for `--trace`
+    trace: Option<Rc<Trace>>,
     _lifetime: PhantomData<&'a ()>,
 }
 
@@ -137,36 +228,38 @@ impl<'a> Allocator<'a> {
             u32::try_from(fs_len / cluster_size).expect("FAT32 cannot have more than 2^32 clusters");
         Self {
             fs_ptr,
-            cursor: Cell::new(0),
+            cursor: Cell::new(FreeRangeCursor::new(&used_ranges, 0)),
             valid_cluster_indices: 0..valid_cluster_count,
             used_ranges,
             cluster_size,
+            stats: Cell::new(AllocatorStats::default()),
+            trace: None,
             _lifetime,
         }
     }
 
-    pub fn forbid(&mut self, range: Range<ClusterIdx>) {
-        self.used_ranges.insert(range);
-    }
-
-    pub fn block_size(&self) -> usize {
-        self.cluster_size
+    /// Records every allocation in `trace`. Since every part of the conversion that writes to the partition has
+    /// access to the `Allocator`, they record their steps through `Allocator::trace` as well.
+    pub fn set_trace(&mut self, trace: Option<Rc<Trace>>) {
+        self.trace = trace;
     }
 
-    /// Returns a cluster that may be exclusively used by the caller.
-    pub fn allocate_one(&self) -> Result<AllocatedClusterIdx> {
-        Ok(Range::from(self.allocate(1)?).start)
+    pub fn forbid(&mut self, range: Range<ClusterIdx>) {
+        self.used_ranges.insert(range);
+        // inserting may have shifted the ranges that the cursor refers to
+        self.cursor.set(FreeRangeCursor::new(&self.used_ranges, self.cursor_position()));
     }
 
-    /// Returns a cluster range that may be exclusively used by the caller, with 1 <= `range.len()` <= `max_length`.
-    pub fn allocate(&self, max_length: u32) -> Result<AllocatedRange> {
-        let free_range = self.find_next_free_range(self.cursor.get())?;
-        let desired_end = free_range.start.saturating_add(max_length);
-        let range_end = free_range.end.min(desired_end);
-        self.cursor.set(range_end);
-        Ok(AllocatedRange(
-            AllocatedClusterIdx(free_range.start)..AllocatedClusterIdx(range_end),
-        ))
+    /// Continues the allocation of an earlier `Allocator` with the same used ranges that stopped at `end` after
+    /// allocating `stats`: the clusters before `end` are not allocated again, and the stats continue from `stats`.
+    /// SAFETY: The clusters before `end` that are not in the used ranges must have been allocated by the earlier
+    /// `Allocator`, and they must only be accessed through an `AllocatedClusterIdx` that it returned, e.g. by
+    /// reading a `StreamArchiver`'s archive that it allocated.
+    pub unsafe fn resume(&mut self, end: ClusterIdx, stats: AllocatorStats) {
+        let mut cursor = self.cursor.get();
+        cursor.advance_to(&self.used_ranges, end);
+        self.cursor.set(cursor);
+        self.stats.set(stats);
     }
 
     /// PANICS: Panics if `idx` out of bounds. This is only possible if `idx` was not allocated by `self`.
@@ -179,21 +272,6 @@ impl<'a> Allocator<'a> {
         unsafe { slice::from_raw_parts(self.fs_ptr.add_usize(start_byte), self.cluster_size) }
     }
 
-    /// PANICS: Panics if `idx` out of bounds. This is only possible if `idx` was not allocated by `self`.
-    pub fn cluster_mut(&self, idx: &mut AllocatedClusterIdx) -> &mut [u8] {
-        let start_byte = self
-            .cluster_start_byte(idx)
-            .unwrap_or_else(|| panic!("Attempted to access invalid cluster {}", idx));
-        // SAFETY: The data is valid and since `idx` is unique and we borrowed it mutably, nobody else can access the
-        // data.
-        unsafe { slice::from_raw_parts_mut(self.fs_ptr.add_usize(start_byte), self.cluster_size) }
-    }
-
-    pub fn free_block_count(&self) -> usize {
-        self.used_ranges
-            .free_element_count(self.cursor.get()..self.fs_end_cluster_idx())
-    }
-
     /// Returns the offset from `self.fs_ptr` at which the cluster `idx` starts or None if the cluster is not covered by
     /// `self`, i.e. if `idx` is not in `self.valid_cluster_indices`.
     fn cluster_start_byte(&self, idx: &AllocatedClusterIdx) -> Option<usize> {
@@ -206,40 +284,96 @@ impl<'a> Allocator<'a> {
     }
 
     /// Returns the next range at or after `self.cursor` that is not used, or Err if such a range does not exist.
-    fn find_next_free_range(&self, cursor: u32) -> Result<Range<ClusterIdx>> {
-        let non_used_range = match self.used_ranges.next_not_covered(cursor) {
+    /// Moves `self.cursor` to the start of that range. The cursor only skips used clusters and `allocate` allocates
+    /// from the start of the returned range, so no free cluster is left behind the cursor and there is no need to
+    /// wrap around before failing.
+    fn find_next_free_range(&self) -> Result<Range<ClusterIdx>> {
+        let mut cursor = self.cursor.get();
+        let non_used_range = match cursor.next_free(&self.used_ranges) {
             NotCoveredRange::Bounded(range) => range,
             NotCoveredRange::Unbounded(start) => start..self.fs_end_cluster_idx(),
         };
+        self.cursor.set(cursor);
 
         if non_used_range.is_empty() {
-            bail!("No free clusters left in the filesystem")
+            Err(ErrorCategory::InsufficientSpace.error("No free clusters left in the filesystem"))
         } else {
             Ok(non_used_range)
         }
     }
 
+    fn cursor_position(&self) -> ClusterIdx {
+        self.cursor.get().position()
+    }
+
     fn fs_end_cluster_idx(&self) -> ClusterIdx {
         self.valid_cluster_indices.end
     }
+}
+
+impl ClusterAllocator for Allocator<'_> {
+    fn allocate(&self, max_length: u32, purpose: AllocationPurpose) -> Result<AllocatedRange> {
+        if max_length == 0 {
+            bail!("Tried to allocate 0 clusters for {:?}", purpose);
+        }
+        let free_range = self.find_next_free_range()?;
+        let desired_end = free_range.start.saturating_add(max_length);
+        let range_end = free_range.end.min(desired_end);
+        let mut cursor = self.cursor.get();
+        cursor.advance_to(&self.used_ranges, range_end);
+        self.cursor.set(cursor);
+        let mut stats = self.stats.get();
+        stats.record(&(free_range.start..range_end), purpose);
+        self.stats.set(stats);
+        self.trace(TraceEvent::Allocation { purpose, start: free_range.start, end: range_end });
+        Ok(AllocatedRange(
+            AllocatedClusterIdx(free_range.start)..AllocatedClusterIdx(range_end),
+        ))
+    }
+
+    fn cluster_mut(&self, idx: &mut AllocatedClusterIdx) -> &mut [u8] {
+        let start_byte = self
+            .cluster_start_byte(idx)
+            .unwrap_or_else(|| panic!("Attempted to access invalid cluster {}", idx));
+        // SAFETY: The data is valid and since `idx` is unique and we borrowed it mutably, nobody else can access the
+        // data.
+        unsafe { slice::from_raw_parts_mut(self.fs_ptr.add_usize(start_byte), self.cluster_size) }
+    }
+
+    /// Exact because of the invariant of `self.cursor`: the clusters before it are used or allocated, and `allocate`
+    /// hands out every cluster at or after it that is not used before failing.
+    fn free_block_count(&self) -> usize {
+        self.used_ranges
+            .free_element_count(self.cursor_position()..self.fs_end_cluster_idx())
+    }
+
+    fn stats(&self) -> AllocatorStats {
+        self.stats.get()
+    }
 
-    /// Splits the `Allocator` into an `AllocatedReader` and an `Allocator`: the `AllocatedReader` can
-    /// only read clusters that were allocated by `self`, the `Allocator` can only write and read
-    /// clusters that could have been allocated by `self` but were not yet allocated.
-    pub fn split_into_reader(self) -> (AllocatedReader<'a>, Self) {
+    fn trace(&self, event: TraceEvent) {
+        if let Some(trace) = &self.trace {
+            trace.record(event);
+        }
+    }
+
+    fn split_into_reader<'r>(self) -> (AllocatedReader<'r>, Self)
+    where Self: Sized + 'r {
         let reader = AllocatedReader {
             fs_ptr: self.fs_ptr,
-            valid_cluster_indices: self.valid_cluster_indices.start..self.cursor.get(),
+            valid_cluster_indices: self.valid_cluster_indices.start..self.cursor_position(),
             cluster_size: self.cluster_size,
-            _lifetime: self._lifetime,
+            _lifetime: PhantomData,
         };
 
         let allocator = Self {
             fs_ptr: self.fs_ptr,
-            valid_cluster_indices: self.cursor.get()..self.valid_cluster_indices.end,
+            valid_cluster_indices: self.cursor_position()..self.valid_cluster_indices.end,
             cursor: self.cursor,
             used_ranges: self.used_ranges,
             cluster_size: self.cluster_size,
+            stats: self.stats,
+            trace: self.trace,
             _lifetime: self._lifetime,
         };
 
@@ -247,7 +381,6 @@ impl<'a> Allocator<'a> {
     }
 }
 
-
 /// Allows to read clusters that were allocated by the `Allocator` instance that produced `self`, but not to allocate
 /// any clusters.
 pub struct AllocatedReader<'a> {
@@ -258,6 +391,11 @@ pub struct AllocatedReader<'a> {
 }
 
 impl<'a> AllocatedReader<'a> {
+    /// The index after the last cluster that `self` can read
+    pub fn end(&self) -> ClusterIdx {
+        self.valid_cluster_indices.end
+    }
+
     /// PANICS: Panics if `idx` out of bounds. This is only possible if `idx` was not allocated by the `Allocator` that
     /// produced `self`.
     pub fn cluster(&self, idx: &AllocatedClusterIdx) -> &'a [u8] {
@@ -279,3 +417,268 @@ impl<'a> AllocatedReader<'a> {
         }
     }
 }
+
+#[cfg(test)]
+pub mod tests {
+    use std::collections::HashSet;
+    use std::mem::size_of;
+
+    use num::Integer;
+
+    use super::*;
+    use crate::error::exit_code;
+
+    /// Behaves like the `Allocator` it wraps until it has allocated `remaining` times, then fails every allocation
+    /// like an `Allocator` without free clusters does. Its `free_block_count` is that of the `Allocator`, so it also
+    /// fails allocations that a dry run expects to succeed.
+    pub struct FailingAllocator<'a> {
+        inner: Allocator<'a>,
+        remaining: Cell<usize>,
+    }
+
+    impl<'a> FailingAllocator<'a> {
+        pub fn new(inner: Allocator<'a>, remaining: usize) -> Self {
+            Self { inner, remaining: Cell::new(remaining) }
+        }
+    }
+
+    impl ClusterAllocator for FailingAllocator<'_> {
+        fn allocate(&self, max_length: u32, purpose: AllocationPurpose) -> Result<AllocatedRange> {
+            match self.remaining.get().checked_sub(1) {
+                Some(remaining) => {
+                    self.remaining.set(remaining);
+                    self.inner.allocate(max_length, purpose)
+                }
+                None => Err(ErrorCategory::InsufficientSpace.error("No free clusters left in the filesystem")),
+            }
+        }
+
+        fn cluster_mut(&self, idx: &mut AllocatedClusterIdx) -> &mut [u8] {
+            self.inner.cluster_mut(idx)
+        }
+
+        fn free_block_count(&self) -> usize {
+            self.inner.free_block_count()
+        }
+
+        fn stats(&self) -> AllocatorStats {
+            self.inner.stats()
+        }
+
+        fn trace(&self, event: TraceEvent) {
+            self.inner.trace(event);
+        }
+
+        fn split_into_reader<'r>(self) -> (AllocatedReader<'r>, Self)
+        where Self: Sized + 'r {
+            let (reader, inner) = self.inner.split_into_reader();
+            (reader, Self { inner, remaining: self.remaining })
+        }
+    }
+
+    /// Hands out one cluster per allocation without wasting any, so that consecutive allocations are usually adjacent.
+    pub struct SingleClusterAllocator<'a>(pub Allocator<'a>);
+
+    impl ClusterAllocator for SingleClusterAllocator<'_> {
+        fn allocate(&self, max_length: u32, purpose: AllocationPurpose) -> Result<AllocatedRange> {
+            self.0.allocate(max_length.min(1), purpose)
+        }
+
+        fn cluster_mut(&self, idx: &mut AllocatedClusterIdx) -> &mut [u8] {
+            self.0.cluster_mut(idx)
+        }
+
+        fn free_block_count(&self) -> usize {
+            self.0.free_block_count()
+        }
+
+        fn stats(&self) -> AllocatorStats {
+            self.0.stats()
+        }
+
+        fn trace(&self, event: TraceEvent) {
+            self.0.trace(event);
+        }
+
+        fn split_into_reader<'r>(self) -> (AllocatedReader<'r>, Self)
+        where Self: Sized + 'r {
+            let (reader, inner) = self.0.split_into_reader();
+            (reader, Self(inner))
+        }
+    }
+
+    /// Hands out single clusters that are never adjacent, by wasting the cluster after each allocation, so that
+    /// everything allocated through it is as fragmented as possible.
+    pub struct FragmentedAllocator<'a>(pub Allocator<'a>);
+
+    impl ClusterAllocator for FragmentedAllocator<'_> {
+        fn allocate(&self, max_length: u32, purpose: AllocationPurpose) -> Result<AllocatedRange> {
+            if max_length == 0 {
+                bail!("Tried to allocate 0 clusters for {:?}", purpose);
+            }
+            let range = self.0.allocate(1, purpose)?;
+            // the wasted cluster is counted in the stats, and it does not matter if there is none left
+            let _ = self.0.allocate(1, purpose);
+            Ok(range)
+        }
+
+        fn cluster_mut(&self, idx: &mut AllocatedClusterIdx) -> &mut [u8] {
+            self.0.cluster_mut(idx)
+        }
+
+        /// Each allocation uses up two clusters, except for the last one if a single cluster is left.
+        fn free_block_count(&self) -> usize {
+            self.0.free_block_count().div_ceil(&2)
+        }
+
+        fn stats(&self) -> AllocatorStats {
+            self.0.stats()
+        }
+
+        fn trace(&self, event: TraceEvent) {
+            self.0.trace(event);
+        }
+
+        fn split_into_reader<'r>(self) -> (AllocatedReader<'r>, Self)
+        where Self: Sized + 'r {
+            let (reader, inner) = self.0.split_into_reader();
+            (reader, Self(inner))
+        }
+    }
+
+    #[test]
+    fn allocates_every_free_cluster() {
+        const CLUSTER_SIZE: usize = 1024;
+        const CLUSTER_COUNT: u32 = 64;
+        let mut memory = vec![0_u64; usize::fromx(CLUSTER_COUNT) * CLUSTER_SIZE / size_of::<u64>()];
+        let used_ranges = Ranges::from([0..4, 10..12, 30..40]);
+        // SAFETY: safe because `memory` outlives `allocator` and no other `Allocator` exists
+        let mut allocator = unsafe {
+            Allocator::new(
+                memory.as_mut_ptr() as *mut u8,
+                usize::fromx(CLUSTER_COUNT) * CLUSTER_SIZE,
+                CLUSTER_SIZE,
+                used_ranges,
+                PhantomData,
+            )
+        };
+        assert_eq!(allocator.free_block_count(), 48);
+
+        let mut allocated = HashSet::new();
+        let mut allocate = |allocator: &Allocator, max_length| -> Result<()> {
+            let range: Range<ClusterIdx> = allocator.allocate(max_length, AllocationPurpose::Metadata)?.into();
+            assert!(!range.is_empty() && range.len() <= usize::fromx(max_length));
+            for cluster_idx in range {
+                assert!(allocated.insert(cluster_idx), "Cluster {} was allocated twice", cluster_idx);
+            }
+            Ok(())
+        };
+        allocate(&allocator, 3).unwrap();
+        allocate(&allocator, 100).unwrap();
+        // forbidding clusters in front of the cursor does not leave free clusters behind it
+        allocator.forbid(50..55);
+        assert_eq!(allocator.free_block_count(), 48 - 6 - 5);
+        let error = loop {
+            if let Err(error) = allocate(&allocator, 7) {
+                break error;
+            }
+        };
+
+        assert_eq!(exit_code(&error), ErrorCategory::InsufficientSpace.exit_code());
+        assert_eq!(allocated.len(), 48 - 5);
+        assert_eq!(allocator.free_block_count(), 0);
+        assert_eq!(allocator.stats().metadata, 48 - 5);
+    }
+
+    /// Leaves `count` clusters of `allocator` free, starting at the next one it would allocate, by forbidding the ones
+    /// after them. Since an `Allocator` allocates in order, it then allocates the same clusters as before until they
+    /// run out.
+    pub fn limit_free_clusters(allocator: &mut Allocator, mut count: usize) {
+        let mut position = allocator.cursor_position();
+        loop {
+            let free_range = match allocator.used_ranges.next_not_covered(position) {
+                NotCoveredRange::Bounded(range) => range,
+                NotCoveredRange::Unbounded(start) => start..allocator.fs_end_cluster_idx(),
+            };
+            if free_range.is_empty() || free_range.len() >= count {
+                let end = free_range.start + ClusterIdx::try_from(count.min(free_range.len())).unwrap();
+                allocator.forbid(end..allocator.fs_end_cluster_idx());
+                return;
+            }
+            count -= free_range.len();
+            position = free_range.end;
+        }
+    }
+
+    /// Allocates until the allocation fails and returns the number of allocated clusters.
+    fn allocate_all(allocator: &dyn ClusterAllocator) -> usize {
+        let mut count = 0;
+        while let Ok(range) = allocator.allocate(5, AllocationPurpose::Metadata) {
+            count += usize::fromx(range.len());
+        }
+        count
+    }
+
+    #[test]
+    fn free_block_count_is_exact() {
+        const CLUSTER_SIZE: usize = 1024;
+        const CLUSTER_COUNT: u32 = 64;
+        let mut memory = vec![0_u64; usize::fromx(CLUSTER_COUNT) * CLUSTER_SIZE / size_of::<u64>()];
+        let new_allocator = |memory: &mut Vec<u64>| {
+            // SAFETY: safe because `memory` outlives the allocator and only one allocator exists at a time
+            unsafe {
+                Allocator::new(
+                    memory.as_mut_ptr() as *mut u8,
+                    usize::fromx(CLUSTER_COUNT) * CLUSTER_SIZE,
+                    CLUSTER_SIZE,
+                    Ranges::from([0..4, 10..12, 30..40, 63..64]),
+                    PhantomData,
+                )
+            }
+        };
+
+        let allocator = new_allocator(&mut memory);
+        let free_count = allocator.free_block_count();
+        assert_eq!(allocate_all(&allocator), free_count);
+
+        // like the archive, which is split off after its last page has been written
+        let allocator = new_allocator(&mut memory);
+        for _ in 0..9 {
+            allocator.allocate_one(AllocationPurpose::Archive).unwrap();
+        }
+        let (reader, mut allocator) = allocator.split_into_reader();
+        assert_eq!(reader.end(), 15);
+        let free_count = allocator.free_block_count();
+        assert_eq!(free_count, 47 - 9);
+        allocator.forbid(20..25);
+        assert_eq!(allocator.free_block_count(), free_count - 5);
+        limit_free_clusters(&mut allocator, 17);
+        assert_eq!(allocator.free_block_count(), 17);
+        assert_eq!(allocate_all(&allocator), 17);
+        assert_eq!(allocator.stats().high_water_mark, 47);
+
+        let allocator = FragmentedAllocator(new_allocator(&mut memory));
+        let free_count = allocator.free_block_count();
+        assert_eq!(allocate_all(&allocator), free_count);
+    }
+
+    #[test]
+    fn rejects_zero_length_allocation() {
+        const CLUSTER_SIZE: usize = 1024;
+        let mut memory = vec![0_u64; 4 * CLUSTER_SIZE / size_of::<u64>()];
+        // SAFETY: safe because `memory` outlives `allocator` and no other `Allocator` exists
+        let allocator = unsafe {
+            Allocator::new(
+                memory.as_mut_ptr() as *mut u8,
+                4 * CLUSTER_SIZE,
+                CLUSTER_SIZE,
+                Ranges::new(),
+                PhantomData,
+            )
+        };
+        assert!(allocator.allocate(0, AllocationPurpose::Relocation).is_err());
+        // the failed request leaves every cluster free
+        assert_eq!(allocator.free_block_count(), 4);
+        assert_eq!(allocator.stats().total(), 0);
+    }
+}
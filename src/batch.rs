@@ -0,0 +1,151 @@
+use std::any::Any;
+use std::io::{BufRead, Write};
+use std::panic::{self, AssertUnwindSafe};
+
+use anyhow::{bail, Context, Result};
+use ofs_convert_rs::conversion::ConversionSummary;
+use ofs_convert_rs::error::{exit_code, ErrorCategory};
+use serde::Serialize;
+
+/// The result of converting one partition, printed as a line of JSON
+#[derive(Debug, Serialize)]
+struct PartitionResult<'a> {
+    path: &'a str,
+    /// the exit code that converting only this partition would have had
+    exit_code: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    summary: Option<ConversionSummary>,
+}
+
+impl<'a> PartitionResult<'a> {
+    fn new(path: &'a str, result: Result<ConversionSummary>) -> Self {
+        match result {
+            Ok(summary) => Self {
+                path,
+                exit_code: 0,
+                error: None,
+                summary: Some(summary),
+            },
+            Err(e) => Self {
+                path,
+                exit_code: exit_code(&e),
+                error: Some(format!("{:#}", e)),
+                summary: None,
+            },
+        }
+    }
+}
+
+/// Reads newline-separated partition paths from `input` and converts each of them with `convert`, writing one line of
+/// JSON per partition to `output` as soon as its conversion has finished. Empty lines are skipped. A failed or
+/// panicking conversion does not stop the remaining ones; if any conversion failed, returns Err once all paths have
+/// been processed.
+pub fn convert_paths<I, O, F>(input: I, mut output: O, mut convert: F) -> Result<()>
+where
+    I: BufRead,
+    O: Write,
+    F: FnMut(&str) -> Result<ConversionSummary>,
+{
+    let mut partition_count = 0;
+    let mut failure_count = 0;
+    for line in input.lines() {
+        let path = line.context("Unable to read the partition paths")?;
+        if path.is_empty() {
+            continue;
+        }
+
+        // every conversion opens the partition and builds its allocator from scratch, so a panic only affects the
+        // partition that caused it
+        let result = panic::catch_unwind(AssertUnwindSafe(|| convert(&path))).unwrap_or_else(|payload| {
+            Err(ErrorCategory::ConversionFailed.error(format!("The conversion panicked: {}", panic_message(&*payload))))
+        });
+        let partition_result = PartitionResult::new(&path, result);
+        partition_count += 1;
+        if partition_result.exit_code != 0 {
+            failure_count += 1;
+        }
+        serde_json::to_writer(&mut output, &partition_result)?;
+        writeln!(output)?;
+        output.flush()?;
+    }
+
+    if failure_count > 0 {
+        bail!("{} of {} conversions failed", failure_count, partition_count);
+    }
+    Ok(())
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown cause"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ofs_convert_rs::serialization::DentryOrder;
+    use serde_json::Value;
+
+    use super::*;
+
+    #[test]
+    fn reports_every_partition() {
+        let input = "ok.img\n\nfull.img\npanic.img\nlast.img\n".as_bytes();
+        let mut output = Vec::new();
+        let result = convert_paths(input, &mut output, |path| match path {
+            "full.img" => Err(ErrorCategory::InsufficientSpace.error("Not enough space")),
+            "panic.img" => panic!("Unexpected cluster"),
+            _ => Ok(summary()),
+        });
+        assert!(result.is_err());
+
+        let lines: Vec<Value> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0]["path"], "ok.img");
+        assert_eq!(lines[0]["exit_code"], 0);
+        assert_eq!(lines[0]["file_count"], 3);
+        assert!(lines[0].get("error").is_none());
+        assert_eq!(lines[1]["path"], "full.img");
+        assert_eq!(lines[1]["exit_code"], ErrorCategory::InsufficientSpace.exit_code());
+        assert_eq!(lines[1]["error"], "Not enough space: Insufficient free space");
+        assert!(lines[1].get("file_count").is_none());
+        assert_eq!(lines[2]["exit_code"], ErrorCategory::ConversionFailed.exit_code());
+        assert!(lines[2]["error"].as_str().unwrap().contains("Unexpected cluster"));
+        assert_eq!(lines[3]["path"], "last.img");
+        assert_eq!(lines[3]["exit_code"], 0);
+    }
+
+    #[test]
+    fn succeeds_if_every_conversion_succeeds() {
+        let mut output = Vec::new();
+        assert!(convert_paths("a.img\nb.img".as_bytes(), &mut output, |_| Ok(summary())).is_ok());
+        assert_eq!(String::from_utf8(output).unwrap().lines().count(), 2);
+    }
+
+    fn summary() -> ConversionSummary {
+        ConversionSummary {
+            file_count: 3,
+            directory_count: 1,
+            seconds: 0.5,
+            moved_bytes: 4096,
+            free_bytes: 1 << 20,
+            free_block_count: 256,
+            free_inode_count: 100,
+            truncated_file_count: 0,
+            skipped_file_count: 0,
+            dentry_order: DentryOrder::Fat,
+            spot_check: None,
+            fat_metadata_sha256: None,
+        }
+    }
+}
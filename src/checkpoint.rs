@@ -0,0 +1,98 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::fat_checksums::FatMetadataChecksums;
+use crate::options::ConversionOptions;
+use crate::serialization::{ArchiveBitFile, ArchiveLocation};
+use crate::spot_check::SpotCheckSample;
+
+/// A conversion that `convert --stop-after-plan` stopped after the serialization and the dry run, before it modified
+/// the FAT filesystem. The serialized directory tree and the relocated file data are stored in clusters that are free
+/// in the FAT filesystem, so `convert --continue` only has to write the ext4 filesystem, as long as the FAT filesystem
+/// has not been modified in between.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// the version of ofs-convert-rs that stopped the conversion, since the serialized directory tree can only be read
+    /// by the same version
+    pub version: String,
+    pub partition_path: String,
+    pub options: ConversionOptions,
+    pub state: ConversionState,
+}
+
+/// What a conversion has done when it stops after the dry run
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ConversionState {
+    /// the `FatFs::fingerprint` of the FAT filesystem, which the serialization does not change
+    pub fingerprint: u64,
+    /// whether the last block group lacks its superblock backup, see `--allow-tight-fit`
+    pub tight_fit: bool,
+    /// the number of serialized files and directories, excluding the root directory
+    pub file_count: usize,
+    pub archive: ArchiveLocation,
+    pub report: SerializationReport,
+}
+
+/// What the serialization found out about the files, which is reported after the conversion
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SerializationReport {
+    /// the regular files whose cluster chains were shorter than their size
+    pub truncated_file_count: usize,
+    /// the files that could not be converted and were skipped
+    pub skipped_file_count: usize,
+    /// the files with the FAT archive flag, if they were listed
+    pub archive_bit_files: Vec<ArchiveBitFile>,
+    /// the files sampled before the serialization, if `ConversionOptions::spot_check` is set
+    #[serde(default)]
+    pub spot_check_samples: Vec<SpotCheckSample>,
+    /// the checksums of the FAT metadata before the serialization, if `ConversionOptions::checksum_fat_metadata` is
+    /// set
+    #[serde(default)]
+    pub fat_metadata_checksums: Option<FatMetadataChecksums>,
+    /// the warnings and notes about the conversion, e.g. about truncated or skipped files, which the caller reports
+    #[serde(default)]
+    pub messages: Vec<String>,
+}
+
+impl SerializationReport {
+    /// Prints `self.messages`, like `ConversionStats::print_messages`.
+    pub fn print_messages(&self) {
+        for message in &self.messages {
+            eprintln!("{}", message);
+        }
+    }
+}
+
+impl Checkpoint {
+    pub fn new(partition_path: String, options: ConversionOptions, state: ConversionState) -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            partition_path,
+            options,
+            state,
+        }
+    }
+
+    /// Loads the checkpoint saved at `path`. Fails if it was saved by a different version of ofs-convert-rs.
+    pub fn load(path: &str) -> Result<Self> {
+        let file = File::open(path).with_context(|| format!("Unable to open {}", path))?;
+        let checkpoint: Self = serde_json::from_reader(BufReader::new(file))
+            .with_context(|| format!("{} is not a valid checkpoint", path))?;
+        if checkpoint.version != env!("CARGO_PKG_VERSION") {
+            bail!(
+                "The conversion was stopped by version {} of ofs-convert-rs, so it can only be continued by that \
+                 version",
+                checkpoint.version
+            );
+        }
+        Ok(checkpoint)
+    }
+
+    pub fn save(&self, path: &str) -> Result<()> {
+        let file = File::create(path).with_context(|| format!("Unable to create {}", path))?;
+        serde_json::to_writer_pretty(BufWriter::new(file), self).with_context(|| format!("Unable to write {}", path))
+    }
+}
@@ -0,0 +1,591 @@
+use std::os::unix::io::RawFd;
+
+use anyhow::{bail, Context, Result};
+use chrono::NaiveDate;
+use clap::{ArgGroup, Args, Parser, Subcommand};
+use clap_complete::Shell;
+use ofs_convert_rs::io_priority::IoPriority;
+use ofs_convert_rs::messages::Lang;
+use ofs_convert_rs::options::{MkfsTime, UuidSource};
+use ofs_convert_rs::profile::{parse_inode_ratio, parse_reserved_percent, Ext4Params, Profile};
+use ofs_convert_rs::serialization::{DentryOrder, InvalidAttributesPolicy};
+use ofs_convert_rs::tune::{parse_label, parse_uuid};
+use uuid::Uuid;
+
+/// Converts a FAT32 filesystem to ext4 in place. `ofs-convert-rs [OPTIONS] PARTITION_PATH` is short for
+/// `ofs-convert-rs convert [OPTIONS] PARTITION_PATH`.
+#[derive(Debug, Parser)]
+#[clap(
+    name = "ofs-convert-rs",
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
+pub struct Cli {
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+    #[clap(flatten)]
+    pub convert: ConvertArgs,
+    /// The language of the prompts, the summary and the error messages: 'en' or 'de'. Defaults to the language of the
+    /// locale (LC_ALL, LC_MESSAGES or LANG)
+    #[clap(long, arg_enum, global = true, value_name = "LANG")]
+    pub lang: Option<Lang>,
+}
+
+impl Cli {
+    /// Returns the subcommand, which is `convert` if none was given.
+    pub fn into_command(self) -> Command {
+        self.command.unwrap_or(Command::Convert(self.convert))
+    }
+}
+
+// the command is parsed once, so the size of `ConvertArgs` does not matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Convert a FAT32 filesystem to ext4 (the default)
+    Convert(ConvertArgs),
+    /// Like `convert --dry-run`: check whether a FAT32 filesystem can be converted with the given options, without
+    /// modifying it
+    DryRun(ConvertArgs),
+    /// Compare the free space of a FAT32 filesystem to the space the ext4 metadata will need, without converting it
+    Estimate(EstimateArgs),
+    /// Convert a FAT32 filesystem according to a plan saved by `convert --save-plan`
+    Execute(ExecuteArgs),
+    /// Print the geometry of a FAT32 filesystem, how many of its clusters are free, how many files it contains and
+    /// whether its boot sector is intact
+    InspectFat(InspectFatArgs),
+    /// Restore the damaged boot sector of a FAT32 filesystem from its backup boot sector, which `convert` otherwise
+    /// offers before converting. A converted filesystem cannot be restored to FAT32, since the conversion overwrites
+    /// the FAT
+    Restore(RestoreArgs),
+    /// For developers: compare the ext4 metadata of two conversions of the same FAT32 filesystem, e.g. by different
+    /// versions of ofs-convert-rs, ignoring the fields that are random or depend on the time of the conversion
+    DiffMeta(DiffMetaArgs),
+    /// Write the ext4 metadata of a converted filesystem to a file that can be attached to a bug report, like
+    /// `e2image -r`: a sparse file that e2fsck and debugfs can read like the filesystem, without the file contents
+    /// and, unless --keep-names is given, with scrambled file names
+    ExportMetadata(ExportMetadataArgs),
+    /// Change the label, the UUID or the reserved blocks of a converted (or any other) ext4 filesystem by editing its
+    /// superblocks, like tune2fs
+    Tune(TuneArgs),
+    /// For developers: print the steps recorded by `convert --trace`, one per line
+    TraceDump(TraceDumpArgs),
+    /// Print a completion script for SHELL to stdout
+    Completions {
+        #[clap(arg_enum, value_name = "SHELL")]
+        shell: Shell,
+    },
+}
+
+#[derive(Debug, Args)]
+pub struct EstimateArgs {
+    /// The partition containing the FAT32 filesystem. It is only read, so it may be mounted, but then the estimate may
+    /// be inconsistent
+    #[clap(value_name = "PARTITION_PATH")]
+    pub partition_path: String,
+
+    #[clap(flatten)]
+    pub ext4: Ext4Args,
+}
+
+#[derive(Debug, Args)]
+pub struct ExecuteArgs {
+    /// The plan, which contains the partition path and the options. The conversion fails if the FAT32 filesystem has
+    /// been modified since the plan was saved
+    #[clap(value_name = "PLAN_PATH")]
+    pub plan_path: String,
+
+    /// Print how many clusters and inodes the conversion allocated
+    #[clap(short, long)]
+    pub verbose: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct InspectFatArgs {
+    /// The partition containing the FAT32 filesystem. It is only read, so it may be mounted, but then the output may
+    /// be inconsistent
+    #[clap(value_name = "PARTITION_PATH")]
+    pub partition_path: String,
+}
+
+#[derive(Debug, Args)]
+pub struct RestoreArgs {
+    /// The partition containing the FAT32 filesystem. It must be unmounted
+    #[clap(value_name = "PARTITION_PATH")]
+    pub partition_path: String,
+}
+
+#[derive(Debug, Args)]
+pub struct DiffMetaArgs {
+    /// The partition containing the first converted ext4 filesystem. It is only read
+    #[clap(value_name = "FIRST_PATH")]
+    pub first_path: String,
+
+    /// The partition containing the second converted ext4 filesystem. It is only read
+    #[clap(value_name = "SECOND_PATH")]
+    pub second_path: String,
+}
+
+#[derive(Debug, Args)]
+pub struct ExportMetadataArgs {
+    /// The partition containing the converted ext4 filesystem. It is only read
+    #[clap(value_name = "PARTITION_PATH")]
+    pub partition_path: String,
+
+    /// The file to write the metadata to, which is overwritten if it exists. It is as large as the filesystem, but
+    /// only the metadata occupies space, so compress it with a tool that handles sparse files, e.g. `tar -Sczf`
+    #[clap(value_name = "OUTPUT_PATH")]
+    pub output_path: String,
+
+    /// Keep the file and directory names instead of replacing them with names of the same length
+    #[clap(long)]
+    pub keep_names: bool,
+}
+
+#[derive(Debug, Args)]
+#[clap(group(ArgGroup::new("changes").required(true).multiple(true).args(&["label", "uuid", "reserved-percent"])))]
+pub struct TuneArgs {
+    /// The partition containing the ext4 filesystem. It must be unmounted
+    #[clap(value_name = "PARTITION_PATH")]
+    pub partition_path: String,
+
+    /// Set the volume label to LABEL, which may be up to 16 bytes long
+    #[clap(long, value_name = "LABEL", value_parser = parse_label)]
+    pub label: Option<String>,
+
+    /// Set the UUID to UUID, or to a random one if UUID is 'random'
+    #[clap(long, value_name = "UUID", value_parser = parse_uuid)]
+    pub uuid: Option<Uuid>,
+
+    /// Reserve PERCENT percent of the blocks for root
+    #[clap(long, value_name = "PERCENT", value_parser = parse_reserved_percent)]
+    pub reserved_percent: Option<u8>,
+}
+
+#[derive(Debug, Args)]
+pub struct TraceDumpArgs {
+    /// The trace written by `convert --trace`
+    #[clap(value_name = "TRACE_PATH")]
+    pub trace_path: String,
+}
+
+#[derive(Debug, Args)]
+pub struct ConvertArgs {
+    /// The partition containing the FAT32 filesystem that should be converted. This will usually be a block device
+    /// (e.g. /dev/sda1), but it can also be a file containing a disk image. The filesystem must be unmounted and
+    /// cannot be modified by another process during the conversion
+    #[clap(value_name = "PARTITION_PATH", required_unless_present_any = &["stdin-paths", "print-options", "continue-from"])]
+    pub partition_path: Option<String>,
+
+    /// Skip fsck (can lead to unexpected errors and data loss if the input filesystem is inconsistent)
+    #[clap(short, long)]
+    pub force: bool,
+
+    /// Repair the FAT32 filesystem with `fsck.fat -a` before checking it. Without this, the conversion only proceeds
+    /// on its own if fsck.fat reports nothing but harmless issues like a set dirty bit
+    #[clap(long, conflicts_with_all = &["force", "dry-run"])]
+    pub fsck_auto_fix: bool,
+
+    /// Convert the partition even if it contains an ext4 superblock, which usually means that it has been converted
+    /// already. Only use this if the superblock is a leftover of a filesystem that the FAT32 filesystem replaced
+    #[clap(long)]
+    pub force_reconvert: bool,
+
+    /// Print how many clusters and inodes the conversion allocated
+    #[clap(short, long)]
+    pub verbose: bool,
+
+    #[clap(flatten)]
+    pub ext4: Ext4Args,
+
+    /// Convert Windows shortcuts (.lnk files) that point to a file on the same volume into symlinks. Shortcuts that
+    /// cannot be converted are kept as regular files
+    #[clap(long)]
+    pub convert_shortcuts: bool,
+
+    /// Access the partition with O_DIRECT instead of a memory mapping, for storage stacks that reject writes through a
+    /// memory mapping. The conversion runs on a copy of the partition in memory that is read in chunks of 1 MiB when
+    /// it is first accessed, which requires as much free memory as the conversion reads or writes, and the partition
+    /// is only modified once the conversion has succeeded. Requires userfaultfd support in the kernel
+    #[clap(long)]
+    pub direct_io: bool,
+
+    /// Convert the FAT filesystem starting at byte OFFSET of the raw image that an Android sparse image expands to,
+    /// e.g. behind a partition table. Sparse images are detected by their header and converted without expanding them
+    /// on disk: the conversion runs on a copy of the expanded image in memory, and the image is replaced by a new
+    /// sparse image afterwards
+    #[cfg(feature = "sparse-images")]
+    #[clap(long, value_name = "OFFSET")]
+    pub sparse_offset: Option<usize>,
+
+    /// Read newline-separated partition paths from stdin instead of PARTITION_PATH and convert them one after another,
+    /// printing one JSON object per partition to stdout. A failed conversion does not stop the remaining ones, and
+    /// questions are answered with no
+    #[clap(long, conflicts_with = "partition-path")]
+    pub stdin_paths: bool,
+
+    /// Truncate file names that are longer than ext4's limit of 255 bytes in UTF-8, keeping their extension. Without
+    /// this flag, the conversion fails if such names exist
+    #[clap(long)]
+    pub truncate_long_names: bool,
+
+    /// The order of the entries in every directory: 'fat' (default) keeps the order of the FAT directory, 'sorted'
+    /// sorts them by name. The converted directories have no hash index, so this is the order in which `ls -U` lists
+    /// them and in which `telldir` positions stay valid
+    #[clap(long, arg_enum, value_name = "ORDER")]
+    pub dentry_order: Option<DentryOrder>,
+
+    /// Zero the former FAT boot sector, reserved sectors and FAT tables where they are not reused by ext4. Without
+    /// this flag, only the FAT32 signatures in these regions are erased
+    #[clap(long)]
+    pub wipe_fat_remnants: bool,
+
+    /// The creation time of the ext4 filesystem: 'now' (default), 'from-fat' for the time the FAT volume label was
+    /// set, which is usually when the volume was formatted, or a Unix timestamp
+    #[clap(long, value_name = "TIME", value_parser = MkfsTime::parse)]
+    pub mkfs_time: Option<MkfsTime>,
+
+    /// The UUID of the ext4 filesystem: 'random' (default) or 'from-fat-serial', which derives it from the FAT serial
+    /// number so that setups that identify the volume by its serial number keep working. The serial 1234-ABCD becomes
+    /// the UUID 1234abcd-0000-8000-8000-000000000000
+    #[clap(long, arg_enum, value_name = "SOURCE")]
+    pub uuid: Option<UuidSource>,
+
+    /// After reading the directory tree, read it back from its serialized form and compare every file to the FAT
+    /// filesystem before modifying it. This is a self-check for debugging the converter; it reads the FAT filesystem
+    /// twice
+    #[clap(long)]
+    pub verify_archival: bool,
+
+    /// Choose N regular files at random and compare their content before and after the conversion, by hashing it
+    /// once through the FAT cluster chains and once through the ext4 extents. Unlike --verify-archival, this reads
+    /// only the sampled files, and checks the converted filesystem itself
+    #[clap(long, value_name = "N")]
+    pub spot_check: Option<usize>,
+
+    /// Record the SHA-256 checksums of the original boot sector and FATs in the summary before they are overwritten,
+    /// e.g. for an audit trail that proves which FAT filesystem was converted
+    #[clap(long)]
+    pub checksum_fat_metadata: bool,
+
+    /// If the conversion does not fit into the free space, retry without the superblock backup in the last block
+    /// group. This saves a few blocks, but leaves the filesystem with a single superblock backup. ext4 has no reserved
+    /// GDT blocks that could be dropped as well, since the converter does not enable online resizing
+    #[clap(long)]
+    pub allow_tight_fit: bool,
+
+    /// Give every inode a random generation number instead of 0, like the kernel does for newly created files. NFS
+    /// uses the generation number to detect stale file handles, so this is recommended for volumes exported via NFS
+    #[clap(long)]
+    pub randomize_generation: bool,
+
+    /// Set the access time of the converted files to their modification time. FAT only records the date of the last
+    /// access, which shows up as an access at midnight, and many systems never update it
+    #[clap(long)]
+    pub drop_atime: bool,
+
+    /// Turn regular files whose content is the same as that of a file converted before them into hard links to it,
+    /// saving the space of the copies. Every copy is read and compared byte by byte. The copies share the inode of the
+    /// first file, so they lose their own timestamps and read-only flag, and changing one of them changes all of them
+    #[clap(long)]
+    pub dedup_hardlink: bool,
+
+    /// Make USER and GROUP the owner of the converted files and the root directory instead of the user running the
+    /// conversion. Both may be names or numeric IDs; if GROUP is omitted, it is USER's login group. lost+found is
+    /// always owned by root
+    #[clap(long, value_name = "USER[:GROUP]")]
+    pub owner: Option<String>,
+
+    /// Take USER and GROUP of --owner as numeric IDs without consulting the user and group databases (e.g. NSS), which
+    /// may be missing or differ from the target system in an initramfs or a container. GROUP is required then
+    #[clap(long, requires = "owner")]
+    pub numeric_owner: bool,
+
+    /// Add the empty directories of DIR_OR_SPEC to the converted filesystem. If DIR_OR_SPEC is a directory, its
+    /// subdirectories are copied with their permissions and owners. Otherwise, it is a file with one directory per
+    /// line as `PATH [MODE [OWNER]]`, e.g. `/srv/www 750 www-data:www-data`, where MODE is octal (default: 755) and
+    /// OWNER is like --owner (default: the owner of the converted files). Directories that the FAT filesystem already
+    /// contains are kept, but the conversion fails if a path names a file
+    #[clap(long, value_name = "DIR_OR_SPEC")]
+    pub skeleton: Option<String>,
+
+    /// Write the files whose FAT archive attribute is set to FILE, one per line as their modification time (a Unix
+    /// timestamp) and their path, separated by a tab. Backup tools use the attribute to find files modified since the
+    /// last backup, but ext4 has no equivalent, so it is lost otherwise
+    #[clap(long, value_name = "FILE", conflicts_with = "stdin-paths")]
+    pub archive_bit_list: Option<String>,
+
+    /// Write the creation time of every converted file in FAT and its birth time (crtime) in ext4 to FILE, one per
+    /// line as two Unix timestamps with nanoseconds and the path, separated by tabs. The crtimes are read back from
+    /// the converted filesystem, and the conversion fails if one of them differs from the FAT creation time
+    #[clap(long, value_name = "FILE", conflicts_with = "stdin-paths")]
+    pub crtime_list: Option<String>,
+
+    /// Record every cluster allocation, extent and directory entry that the conversion writes to FILE, in a compact
+    /// binary format that `ofs-convert-rs trace-dump FILE` prints. Attach it to a report of a failed conversion. With
+    /// --allow-tight-fit or --trial-run, FILE contains the last attempt
+    #[clap(long, value_name = "FILE", conflicts_with = "stdin-paths")]
+    pub trace: Option<String>,
+
+    /// Write the progress of the conversion to the open file descriptor FD as newline-delimited JSON, for front-ends
+    /// that would otherwise parse the human-readable output. Every object has the phase (serialize, dry-run, write,
+    /// finalize or done), the percentage of the phase that is complete and the current path, if any, e.g.
+    /// {"phase":"write","percent":42,"path":"/DCIM/1.JPG"}. An object is written whenever the phase or the percentage
+    /// changes
+    #[clap(long, value_name = "FD", conflicts_with = "stdin-paths")]
+    pub quiet_json_progress: Option<RawFd>,
+
+    /// Skip files that cannot be converted, e.g. because of an invalid timestamp or a cluster chain that leaves the
+    /// data region, and list them at the end. Their space will be free after the conversion. Errors that affect the
+    /// whole filesystem still stop the conversion
+    #[clap(long, overrides_with = "fail-fast")]
+    pub collect_errors: bool,
+
+    /// Stop the conversion at the first file that cannot be converted (the default)
+    #[clap(long, overrides_with = "collect-errors")]
+    pub fail_fast: bool,
+
+    /// What to do with files whose FAT attributes are an invalid combination, e.g. both a directory and the volume
+    /// label, or a directory without clusters: 'reject' (default) treats them as files that cannot be converted,
+    /// 'skip' leaves them out and 'regular-file' converts them as regular files. They are never converted as
+    /// directories, since their clusters do not contain directory entries
+    #[clap(long, arg_enum, value_name = "POLICY")]
+    pub invalid_attributes: Option<InvalidAttributesPolicy>,
+
+    /// Convert a copy-on-write mapping of the partition first, which leaves the partition untouched, print the result
+    /// and ask whether to convert the partition itself. The trial keeps every block it writes in memory
+    #[clap(long, conflicts_with = "stdin-paths")]
+    pub trial_run: bool,
+
+    /// Plan the conversion without modifying the partition: print the layout of the ext4 filesystem and the space
+    /// estimate, save them along with the options to FILE as JSON, and exit. `ofs-convert-rs execute FILE` performs
+    /// the conversion later
+    #[clap(long, value_name = "FILE", conflicts_with_all = &["stdin-paths", "print-options"])]
+    pub save_plan: Option<String>,
+
+    /// With --save-plan, look for files that cannot be converted as they are (names that are too long for ext4, files
+    /// that --collect-errors would skip and files with invalid attributes), ask how to handle each kind and record the
+    /// answers in the plan, so that executing it asks nothing
+    #[clap(long, requires = "save-plan")]
+    pub resolve_conflicts: bool,
+
+    /// Read the directory tree and perform the dry run, save the state of the conversion to FILE as JSON and exit
+    /// before the FAT32 filesystem is modified. The serialized directory tree is stored in the free space of the
+    /// FAT32 filesystem, so the filesystem must not be modified until `--continue FILE` finishes the conversion, e.g.
+    /// in a maintenance window
+    #[clap(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = &["stdin-paths", "print-options", "save-plan", "trial-run"]
+    )]
+    pub stop_after_plan: Option<String>,
+
+    /// Finish a conversion stopped by --stop-after-plan, with the partition and the options saved in FILE. Only the
+    /// ext4 filesystem is written; if the FAT32 filesystem has been modified since, the conversion fails without
+    /// modifying it
+    #[clap(
+        long = "continue",
+        value_name = "FILE",
+        conflicts_with_all = &["partition-path", "stdin-paths", "print-options", "save-plan", "stop-after-plan", "trial-run"]
+    )]
+    pub continue_from: Option<String>,
+
+    /// Abort before the FAT32 filesystem is modified if less than N percent of the space for files would be free after
+    /// the conversion, as predicted by the dry run (default: 0)
+    #[clap(long, value_name = "N", value_parser = parse_percent)]
+    pub min_free_space_after: Option<u8>,
+
+    /// Scan the FAT32 filesystem without modifying it and list everything the conversion with these options would
+    /// alter or lose: files that would be skipped, left out, renamed or shortened, attributes without an equivalent in
+    /// ext4, and what happens to the owners, permissions and timestamps of every file. With --verbose, every affected
+    /// file is listed. Exits with an error if a file would make the conversion fail
+    #[clap(
+        long,
+        conflicts_with_all = &["stdin-paths", "print-options", "save-plan", "stop-after-plan", "continue-from", "trial-run"]
+    )]
+    pub report_unsupported: bool,
+
+    /// Read the directory tree and perform the dry run on a copy-on-write mapping of the partition like --trial-run,
+    /// print how many inodes and clusters the conversion would need and exit without modifying the partition. Exits
+    /// with an error if the conversion would fail, e.g. because of names that are too long or too little space.
+    /// Questions are answered with no
+    #[clap(
+        long,
+        conflicts_with_all = &[
+            "stdin-paths", "print-options", "save-plan", "stop-after-plan", "continue-from", "trial-run",
+            "report-unsupported"
+        ]
+    )]
+    pub dry_run: bool,
+
+    /// Print the options resulting from the profile and the other arguments, and exit without converting
+    #[clap(long)]
+    pub print_options: bool,
+
+    /// Zero the inode tables and write the block bitmaps of the block groups that the conversion uses with N threads
+    /// (default: one per CPU). Use 1 to avoid competing with other processes for CPU time
+    #[clap(long, value_name = "N", value_parser = parse_threads)]
+    pub threads: Option<usize>,
+
+    /// Copy and zero at most MB_PER_S megabytes per second while relocating file data, initializing inode tables and
+    /// wiping FAT remnants, so that a conversion on shared storage does not starve other workloads
+    #[clap(long, value_name = "MB_PER_S", value_parser = parse_bwlimit)]
+    pub bwlimit: Option<u32>,
+
+    /// Lower the IO priority of the conversion like ionice: 'idle' only uses the storage when no other process does,
+    /// 'low' is the lowest best-effort priority
+    #[clap(long, arg_enum, value_name = "PRIORITY")]
+    pub io_priority: Option<IoPriority>,
+
+    /// Skip files larger than BYTES bytes. Their data is not converted and their space will be free after the
+    /// conversion
+    #[clap(long, value_name = "BYTES")]
+    pub exclude_size_over: Option<u64>,
+
+    /// Skip files last modified before DATE (format: YYYY-MM-DD, interpreted as UTC). Their data is not converted and
+    /// their space will be free after the conversion
+    #[clap(long, value_name = "DATE", value_parser = parse_date)]
+    pub exclude_older_than: Option<i64>,
+}
+
+/// The options that shape the ext4 filesystem
+#[derive(Debug, Args)]
+pub struct Ext4Args {
+    /// Create an ext4 filesystem with 4 KiB blocks that are allocated in clusters the size of a FAT cluster (requires
+    /// a FAT cluster size greater than 4 KiB and a kernel with bigalloc support)
+    #[clap(long)]
+    pub bigalloc: bool,
+
+    /// Choose the ext4 parameters for a typical use: 'sdcard' (no reserved blocks), 'server' (5% of the blocks
+    /// reserved for root) or 'archive' (one inode per 64 KiB and no reserved blocks). --inode-ratio and
+    /// --reserved-percent override the profile's values
+    #[clap(long, arg_enum, value_name = "PROFILE")]
+    pub profile: Option<Profile>,
+
+    /// Create one inode per BYTES bytes of the filesystem (a power of two, default: 16384). The ratio is lowered if
+    /// the filesystem would have fewer inodes than files
+    #[clap(long, value_name = "BYTES", value_parser = parse_inode_ratio)]
+    pub inode_ratio: Option<u32>,
+
+    /// Reserve PERCENT percent of the blocks for root (default: 0)
+    #[clap(long, value_name = "PERCENT", value_parser = parse_reserved_percent)]
+    pub reserved_percent: Option<u8>,
+}
+
+impl ConvertArgs {
+    /// Returns the arguments of `convert --dry-run` for the `dry-run` subcommand. Fails if they include an option that
+    /// `--dry-run` conflicts with.
+    pub fn into_dry_run(self) -> Result<Self> {
+        let conflicting_options = [
+            ("--stdin-paths", self.stdin_paths),
+            ("--print-options", self.print_options),
+            ("--save-plan", self.save_plan.is_some()),
+            ("--stop-after-plan", self.stop_after_plan.is_some()),
+            ("--continue-from", self.continue_from.is_some()),
+            ("--trial-run", self.trial_run),
+            ("--report-unsupported", self.report_unsupported),
+        ];
+        if let Some((option, _)) = conflicting_options.iter().find(|(_, is_set)| *is_set) {
+            bail!("dry-run cannot be used with {}", option);
+        }
+        Ok(Self { dry_run: true, ..self })
+    }
+}
+
+impl Ext4Args {
+    /// Returns the parameters of the profile, overridden by the explicitly chosen ones.
+    pub fn ext4_params(&self) -> Ext4Params {
+        let mut ext4_params = self.profile.map_or_else(Ext4Params::default, Profile::ext4_params);
+        if let Some(inode_ratio) = self.inode_ratio {
+            ext4_params.inode_ratio = inode_ratio;
+        }
+        if let Some(reserved_percent) = self.reserved_percent {
+            ext4_params.reserved_percent = reserved_percent;
+        }
+        ext4_params
+    }
+}
+
+fn parse_threads(value: &str) -> Result<usize> {
+    let threads = value.parse().context("Expected a number")?;
+    if threads == 0 {
+        bail!("Must be at least 1");
+    }
+    Ok(threads)
+}
+
+fn parse_bwlimit(value: &str) -> Result<u32> {
+    let bwlimit = value.parse().context("Expected a whole number of megabytes per second")?;
+    if bwlimit == 0 {
+        bail!("Must be at least 1");
+    }
+    Ok(bwlimit)
+}
+
+fn parse_percent(value: &str) -> Result<u8> {
+    let percent = value.parse().context("Expected a whole percentage")?;
+    if percent > 100 {
+        bail!("Expected at most 100 percent");
+    }
+    Ok(percent)
+}
+
+/// Parses a date in the format YYYY-MM-DD and returns the Unix timestamp of its start in UTC.
+fn parse_date(date: &str) -> Result<i64> {
+    let date = NaiveDate::parse_from_str(date, "%Y-%m-%d").context("Expected a date in the format YYYY-MM-DD")?;
+    Ok(date.and_hms(0, 0, 0).timestamp())
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
+
+    use super::*;
+
+    #[test]
+    fn cli_is_consistent() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn convert_is_the_default_subcommand() {
+        let parse = |args: &[&str]| Cli::try_parse_from(args).map(Cli::into_command);
+
+        for args in [&["ofs-convert-rs", "-f", "a.img"][..], &["ofs-convert-rs", "convert", "-f", "a.img"]] {
+            match parse(args).unwrap() {
+                Command::Convert(convert) => {
+                    assert_eq!(convert.partition_path.as_deref(), Some("a.img"));
+                    assert!(convert.force);
+                }
+                command => panic!("Expected convert, got {:?}", command),
+            }
+        }
+        assert!(matches!(
+            parse(&["ofs-convert-rs", "completions", "bash"]).unwrap(),
+            Command::Completions { shell: Shell::Bash }
+        ));
+        assert!(parse(&["ofs-convert-rs", "--stdin-paths"]).is_ok());
+        assert!(parse(&["ofs-convert-rs", "-f"]).is_err());
+        assert!(parse(&["ofs-convert-rs", "--threads", "0", "a.img"]).is_err());
+        // the partition path is saved along with the state
+        assert!(parse(&["ofs-convert-rs", "--continue", "state.json"]).is_ok());
+        assert!(parse(&["ofs-convert-rs", "--continue", "state.json", "a.img"]).is_err());
+        match parse(&["ofs-convert-rs", "--collect-errors", "--fail-fast", "a.img"]).unwrap() {
+            Command::Convert(convert) => assert!(!convert.collect_errors),
+            command => panic!("Expected convert, got {:?}", command),
+        }
+    }
+
+    #[test]
+    fn dry_run_subcommand_is_convert_dry_run() {
+        let parse = |args: &[&str]| match Cli::try_parse_from(args).map(Cli::into_command).unwrap() {
+            Command::DryRun(args) => args.into_dry_run(),
+            command => panic!("Expected dry-run, got {:?}", command),
+        };
+
+        let dry_run = parse(&["ofs-convert-rs", "dry-run", "--bigalloc", "a.img"]).unwrap();
+        assert!(dry_run.dry_run);
+        assert!(dry_run.ext4.bigalloc);
+        assert!(parse(&["ofs-convert-rs", "dry-run", "--save-plan", "plan.json", "a.img"]).is_err());
+    }
+}
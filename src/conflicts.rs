@@ -0,0 +1,216 @@
+use std::io::{self, Write};
+
+use anyhow::{bail, Context, Result};
+use itertools::Itertools;
+use text_io::try_read;
+
+use crate::error::ErrorCategory;
+use crate::ext4::EXT4_NAME_MAX_LEN;
+use crate::fat::{FatFile, FatFs, ROOT_FAT_IDX};
+use crate::messages::{tr, trf};
+use crate::options::ConversionOptions;
+use crate::serialization::{check_convertible, InvalidAttributesPolicy, SkippedFile};
+
+/// The number of files of a category that are listed before asking how to handle them
+const LISTED_FILE_COUNT: usize = 5;
+
+/// One of the answers to a question asked by a `Prompter`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Choice {
+    /// the character the user types to choose this answer
+    pub key: char,
+    /// the English description, which is translated when it is shown
+    pub description: &'static str,
+}
+
+const RENAME_ALL: Choice = Choice { key: 'r', description: "rename all" };
+const SKIP_ALL: Choice = Choice { key: 's', description: "skip all" };
+const CONVERT_ALL: Choice = Choice {
+    key: 'c',
+    description: "convert all to regular files",
+};
+const ABORT: Choice = Choice { key: 'a', description: "abort" };
+
+/// Asks the user how to proceed, so that the questions can be answered by something other than the terminal, e.g. a
+/// test.
+pub trait Prompter {
+    /// Asks `question` and returns the index of the answer in `choices` the user has chosen.
+    fn choose(&mut self, question: &str, choices: &[Choice]) -> Result<usize>;
+}
+
+/// Asks the questions on the command line until the user gives one of the possible answers.
+pub struct TerminalPrompter;
+
+impl Prompter for TerminalPrompter {
+    fn choose(&mut self, question: &str, choices: &[Choice]) -> Result<usize> {
+        let options = choices
+            .iter()
+            .map(|choice| format!("{}: {}", choice.key, tr(choice.description)))
+            .join(", ");
+        loop {
+            eprint!("{} [{}] ", question, options);
+            io::stderr().flush()?;
+            let answer: String = try_read!("{}\n").context("Unable to read the answer")?;
+            let answer = answer.trim().to_lowercase();
+            if let Some(idx) = choices.iter().position(|choice| answer == choice.key.to_string()) {
+                return Ok(idx);
+            }
+            eprintln!(
+                "{}",
+                trf("Please answer {}", &[&choices.iter().map(|choice| choice.key).join("/")])
+            );
+        }
+    }
+}
+
+/// The files that cannot be converted with the default options, by category. Unlike the serializer, which only reports
+/// the files of the first category that fails the conversion, `Conflicts::scan` finds all of them before anything is
+/// modified, so that the user can decide how to handle each category at once.
+#[derive(Debug, Default, PartialEq)]
+pub struct Conflicts {
+    /// the paths of the files whose names are longer than ext4 allows, see `LongNameTruncator`
+    pub long_names: Vec<String>,
+    /// the files that cannot be converted, see `check_convertible`
+    pub unconvertible_files: Vec<SkippedFile>,
+    /// the paths of the files whose attributes are invalid, see `InvalidAttributesPolicy`
+    pub invalid_attributes: Vec<String>,
+}
+
+impl Conflicts {
+    /// Walks the directory tree of `fat_fs` without modifying it. Excluding files with `--exclude-size-over` and the
+    /// like is not taken into account, so the conflicts may include files that the conversion would leave out.
+    pub fn scan(fat_fs: &FatFs) -> Result<Self> {
+        fat_fs.check_directory(ROOT_FAT_IDX).context("The root directory is damaged")?;
+        let mut conflicts = Self::default();
+        conflicts.scan_directory(fat_fs, fat_fs.root_dir().collect(), "");
+        Ok(conflicts)
+    }
+
+    fn scan_directory(&mut self, fat_fs: &FatFs, children: Vec<FatFile>, dir_path: &str) {
+        for file in children {
+            let path = format!("{}/{}", dir_path, file.name);
+            if file.dentry.attribute_problem().is_some() {
+                self.invalid_attributes.push(path);
+                continue;
+            }
+            if file.dentry.is_volume_label() {
+                continue;
+            }
+            if file.name.len() > EXT4_NAME_MAX_LEN {
+                self.long_names.push(path.clone());
+            }
+            if let Err(error) = check_convertible(&file, fat_fs) {
+                self.unconvertible_files
+                    .push(SkippedFile { path, error: format!("{:#}", error) });
+                continue;
+            }
+            if file.dentry.is_dir() {
+                let grandchildren = fat_fs
+                    .read_dir(&file)
+                    .expect("`check_convertible` has checked the directory")
+                    .collect();
+                self.scan_directory(fat_fs, grandchildren, &path);
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.long_names.is_empty() && self.unconvertible_files.is_empty() && self.invalid_attributes.is_empty()
+    }
+
+    /// Asks `prompter` how to handle each category of conflicts that `options` do not handle yet and records the
+    /// answers in `options`, so that the conversion does not need to ask again. Fails with `ErrorCategory::Aborted`
+    /// if the user chooses to abort.
+    pub fn resolve(&self, options: &mut ConversionOptions, prompter: &mut dyn Prompter) -> Result<()> {
+        if !self.long_names.is_empty() && !options.truncate_long_names {
+            let problem = trf("{} names are longer than the 255 bytes ext4 allows:", &[&self.long_names.len()]);
+            print_files(&problem, &self.long_names);
+            match prompter.choose(tr("Truncate these names?"), &[RENAME_ALL, ABORT])? {
+                0 => options.truncate_long_names = true,
+                _ => bail!(ErrorCategory::Aborted),
+            }
+        }
+        if !self.unconvertible_files.is_empty() && !options.collect_errors {
+            let files = self.unconvertible_files.iter().map(ToString::to_string).collect_vec();
+            print_files(&trf("{} files cannot be converted:", &[&files.len()]), &files);
+            match prompter.choose(tr("Leave these files out of the conversion?"), &[SKIP_ALL, ABORT])? {
+                0 => options.collect_errors = true,
+                _ => bail!(ErrorCategory::Aborted),
+            }
+        }
+        if !self.invalid_attributes.is_empty() && options.invalid_attributes == InvalidAttributesPolicy::Reject {
+            let problem = trf("{} files have invalid attributes:", &[&self.invalid_attributes.len()]);
+            print_files(&problem, &self.invalid_attributes);
+            match prompter.choose(tr("How should these files be converted?"), &[SKIP_ALL, CONVERT_ALL, ABORT])? {
+                0 => options.invalid_attributes = InvalidAttributesPolicy::Skip,
+                1 => options.invalid_attributes = InvalidAttributesPolicy::RegularFile,
+                _ => bail!(ErrorCategory::Aborted),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Prints `heading`, which states the problem, and the first of `files`.
+fn print_files(heading: &str, files: &[String]) {
+    eprintln!("{}", heading);
+    for file in files.iter().take(LISTED_FILE_COUNT) {
+        eprintln!("  {}", file);
+    }
+    if files.len() > LISTED_FILE_COUNT {
+        eprintln!("  {}", trf("and {} more", &[&(files.len() - LISTED_FILE_COUNT)]));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::exit_code;
+    use crate::fat::{FatImageBuilder, TestFile};
+
+    /// Gives the answers it has been created with in order
+    struct ScriptedPrompter(Vec<Choice>);
+
+    impl Prompter for ScriptedPrompter {
+        fn choose(&mut self, _question: &str, choices: &[Choice]) -> Result<usize> {
+            let answer = self.0.remove(0);
+            Ok(choices.iter().position(|&choice| choice == answer).unwrap())
+        }
+    }
+
+    #[test]
+    fn conflicts_are_resolved_per_category() {
+        let long_name = "長".repeat(90);
+        let files = [
+            TestFile::RegularFile { name: long_name.clone(), size: 10 },
+            TestFile::RegularFile { name: "undated".to_string(), size: 10 },
+            TestFile::Directory { name: "label dir".to_string(), children: vec![] },
+            TestFile::RegularFile { name: "fine".to_string(), size: 10 },
+        ];
+        let mut image = FatImageBuilder::new(32 * 1024 * 1024, 1024).build(&files);
+        image.patch_root_dentry("undated", |dentry| dentry.mod_date = 0);
+        image.patch_root_dentry("label dir", |dentry| dentry.attrs = 0x18);
+        let fat_fs = FatFs::from_slice(image.as_mut_slice()).unwrap();
+        let conflicts = Conflicts::scan(&fat_fs).unwrap();
+        assert_eq!(conflicts.long_names, [format!("/{}", long_name)]);
+        let unconvertible_paths: Vec<_> = conflicts.unconvertible_files.iter().map(|file| &file.path).collect();
+        assert_eq!(unconvertible_paths, ["/undated"]);
+        assert_eq!(conflicts.invalid_attributes, ["/label dir"]);
+
+        let mut options = ConversionOptions::default();
+        let mut prompter = ScriptedPrompter(vec![RENAME_ALL, SKIP_ALL, CONVERT_ALL]);
+        conflicts.resolve(&mut options, &mut prompter).unwrap();
+        assert!(options.truncate_long_names);
+        assert!(options.collect_errors);
+        assert_eq!(options.invalid_attributes, InvalidAttributesPolicy::RegularFile);
+        // the recorded decisions are not asked for again
+        conflicts.resolve(&mut options, &mut ScriptedPrompter(vec![])).unwrap();
+
+        let mut options = ConversionOptions::default();
+        let error = conflicts
+            .resolve(&mut options, &mut ScriptedPrompter(vec![RENAME_ALL, ABORT]))
+            .unwrap_err();
+        assert_eq!(exit_code(&error), ErrorCategory::Aborted.exit_code());
+        assert!(!options.collect_errors);
+    }
+}
@@ -0,0 +1,229 @@
+use std::io::{self, Write};
+use std::mem::size_of;
+use std::ops::Range;
+
+use anyhow::{bail, Context, Result};
+use text_io::try_read;
+
+use crate::conversion::{build_superblock, warn_large_blocks};
+use crate::error::ErrorCategory;
+use crate::estimate::COMMON_PAGE_SIZE;
+use crate::ext4;
+#[cfg(feature = "sparse-images")]
+use crate::ext4::{SuperBlock, FIRST_BLOCK_PADDING};
+use crate::fat::{find_backup_boot_sector, BootSector, FatFs};
+use crate::fsck::{self, FsckOutcome};
+#[cfg(feature = "image-formats")]
+use crate::image;
+use crate::messages::{tr, trf};
+use crate::options::{ConversionOptions, UuidSource};
+#[cfg(feature = "sparse-images")]
+use crate::partition::BlockAccess;
+use crate::partition::{Partition, ReadOnlyPartition};
+#[cfg(feature = "sparse-images")]
+use crate::sparse::SparseImage;
+
+/// Checks that the partition at `partition_path` contains a consistent FAT32 filesystem and calls `convert` with the
+/// path to convert, which differs from `partition_path` if it is an image that has to be exported first.
+pub fn with_checked_partition<T, F>(partition_path: &str, options: &ConversionOptions, convert: F) -> Result<T>
+where F: FnOnce(&str) -> Result<T> {
+    // the NBD device must stay connected until the conversion has finished
+    #[cfg(feature = "image-formats")]
+    let nbd_device = image::connect_if_not_raw(partition_path).context(ErrorCategory::Io)?;
+    #[cfg(feature = "image-formats")]
+    let partition_path = nbd_device.as_ref().map_or(partition_path, image::NbdDevice::path);
+
+    #[cfg(feature = "sparse-images")]
+    if SparseImage::detect(partition_path).context(ErrorCategory::Io)? {
+        check_sparse_image(partition_path, options)?;
+        return convert(partition_path);
+    }
+    {
+        let partition = ReadOnlyPartition::open(partition_path).context(ErrorCategory::Io)?;
+        check_ext4_signature(partition.as_slice(), options.force_reconvert)?;
+    }
+    check_boot_sector(partition_path, options.interactive)?;
+    if !options.force {
+        if options.fsck_auto_fix {
+            fsck::auto_fix(partition_path).context(ErrorCategory::FsckFailed)?;
+        }
+        match fsck::check(partition_path) {
+            Ok(FsckOutcome::Clean) => (),
+            Ok(FsckOutcome::BenignOnly(issues)) => eprintln!(
+                "{}",
+                trf(
+                    "Warning: fsck.fat only reported issues that do not affect the conversion: {}",
+                    &[&issues.join(", ")],
+                )
+            ),
+            Ok(FsckOutcome::Errors) => {
+                return Err(ErrorCategory::FsckFailed
+                    .error(tr("fsck failed. Running ofs-convert-rs on an inconsistent FAT32 partition \
+                               can lead to unexpected errors and data loss. To force the conversion, run \
+                               again with the '-f' flag.")))
+            }
+            Err(e) => {
+                eprintln!("{}: {:#}", tr("Error"), e);
+                eprintln!(
+                    "{}",
+                    tr(
+                        "Running ofs-convert-rs on an inconsistent FAT32 partition can lead to unexpected errors and \
+                         data loss."
+                    )
+                );
+                if !ask_user(tr("Run anyway?"), options.interactive)? {
+                    bail!(ErrorCategory::Aborted);
+                }
+            }
+        }
+    }
+    // Without bigalloc, the ext4 blocks are as large as the clusters, so only clusters larger than a page can lead to
+    // the warning. Counting the files walks the directory tree, which fsck has checked by now.
+    if !options.bigalloc {
+        let partition = ReadOnlyPartition::open(partition_path).context(ErrorCategory::Io)?;
+        let boot_sector = BootSector::from_bytes(partition.as_slice()).context(ErrorCategory::InvalidFilesystem)?;
+        if boot_sector.cluster_size() > COMMON_PAGE_SIZE {
+            let fat_fs = FatFs::from_slice(partition.as_slice()).context(ErrorCategory::InvalidFilesystem)?;
+            let inode_ratio = options.ext4_params.inode_ratio;
+            let (superblock, _) = build_superblock(
+                fat_fs.boot_sector(),
+                false,
+                inode_ratio,
+                fat_fs.file_count(),
+                0,
+                UuidSource::Random,
+            )?;
+            warn_large_blocks(&fat_fs, &superblock, inode_ratio);
+        }
+    }
+    convert(partition_path)
+}
+
+/// The checks of `with_checked_partition` for a sparse image, which can only be read through `SparseImage`: the boot
+/// sector must be intact, since restoring it from the backup is not supported, and fsck.fat cannot check the image.
+#[cfg(feature = "sparse-images")]
+fn check_sparse_image(image_path: &str, options: &ConversionOptions) -> Result<()> {
+    let mut image = SparseImage::open(image_path, options.sparse_offset).context(ErrorCategory::Io)?;
+    let mut first_bytes = [0; FIRST_BLOCK_PADDING + size_of::<SuperBlock>()];
+    let first_bytes_len = first_bytes.len().min(image.len());
+    image
+        .read_at(0, &mut first_bytes[..first_bytes_len])
+        .context(ErrorCategory::Io)?;
+    check_ext4_signature(&first_bytes[..first_bytes_len], options.force_reconvert)?;
+    let mut boot_sector = [0; size_of::<BootSector>()];
+    image
+        .read_at(0, &mut boot_sector[..size_of::<BootSector>().min(image.len())])
+        .context(ErrorCategory::Io)?;
+    BootSector::from_bytes(&boot_sector[..size_of::<BootSector>().min(image.len())])
+        .context(ErrorCategory::InvalidFilesystem)?;
+    if !options.force {
+        eprintln!("Error: fsck.fat cannot check a sparse image");
+        eprintln!(
+            "{}",
+            tr(
+                "Running ofs-convert-rs on an inconsistent FAT32 partition can lead to unexpected errors and data \
+                 loss."
+            )
+        );
+        if !ask_user(tr("Run anyway?"), options.interactive)? {
+            bail!(ErrorCategory::Aborted);
+        }
+    }
+    Ok(())
+}
+
+/// Returns an error if `partition` contains an ext4 superblock unless `force_reconvert` is set. The superblock lies in
+/// the reserved sectors of a FAT32 filesystem, so the partition has most likely been converted already: its FAT32 boot
+/// sector may have survived the conversion, but the rest of the FAT32 filesystem has not.
+pub fn check_ext4_signature(partition: &[u8], force_reconvert: bool) -> Result<()> {
+    if !force_reconvert && ext4::has_ext4_signature(partition) {
+        return Err(ErrorCategory::InvalidFilesystem.error(
+            "The partition contains an ext4 filesystem, it has probably been converted already. If it contains a \
+             FAT32 filesystem and the ext4 superblock is a leftover of an earlier filesystem, run again with \
+             --force-reconvert.",
+        ));
+    }
+    Ok(())
+}
+
+/// Checks that the partition starts with a FAT32 boot sector. If the boot sector is damaged but the backup boot sector
+/// is intact, offers to restore the boot sector from the backup.
+fn check_boot_sector(partition_path: &str, interactive: bool) -> Result<()> {
+    let mut partition = Partition::open(partition_path).context(ErrorCategory::Io)?;
+    let partition_bytes = partition.as_mut_slice();
+    let (error, backup_range) = match inspect_boot_sector(partition_bytes)? {
+        BootSectorState::Intact { backup_differs } => {
+            if backup_differs {
+                eprintln!("Warning: The backup boot sector differs from the boot sector, ignoring the backup.");
+            }
+            return Ok(());
+        }
+        BootSectorState::Restorable { error, backup_range } => (error, backup_range),
+    };
+    eprintln!("{}: {:#}", tr("Error"), error);
+    if !ask_user(
+        tr("The boot sector is damaged, but the backup boot sector is intact. Restore it from the backup?"),
+        interactive,
+    )? {
+        bail!(ErrorCategory::Aborted);
+    }
+    partition_bytes.copy_within(backup_range, 0);
+    partition
+        .flush()
+        .context(ErrorCategory::Io)
+        .context("Unable to restore the boot sector")
+}
+
+/// The state of the boot sector of a FAT32 filesystem, see `inspect_boot_sector`
+pub enum BootSectorState {
+    /// whether the backup boot sector exists and differs from the boot sector, in which case it is ignored
+    Intact { backup_differs: bool },
+    /// the boot sector is damaged with `error`, but the backup boot sector at `backup_range` is intact
+    Restorable {
+        error: anyhow::Error,
+        backup_range: Range<usize>,
+    },
+}
+
+/// Checks the boot sector of the FAT32 filesystem in `partition`. Returns an error if it is damaged and cannot be
+/// restored from an intact backup boot sector.
+pub fn inspect_boot_sector(partition: &[u8]) -> Result<BootSectorState> {
+    let error = match BootSector::from_bytes(partition) {
+        Ok(boot_sector) => {
+            let backup = boot_sector
+                .backup_boot_sector_range()
+                .and_then(|range| partition.get(range.start..range.start + size_of::<BootSector>()));
+            let backup_differs = matches!(backup, Some(backup) if backup != &partition[..size_of::<BootSector>()]);
+            return Ok(BootSectorState::Intact { backup_differs });
+        }
+        Err(e) => e,
+    };
+    // a FAT12 or FAT16 boot sector is intact, so restoring a backup would not help
+    if BootSector::detect_legacy_fat(partition).is_some() {
+        return Err(error.context(ErrorCategory::InvalidFilesystem));
+    }
+
+    match find_backup_boot_sector(partition) {
+        Some(backup_range) => Ok(BootSectorState::Restorable { error, backup_range }),
+        None => Err(error
+            .context(ErrorCategory::InvalidFilesystem)
+            .context("The boot sector is damaged and no intact backup boot sector was found")),
+    }
+}
+
+/// Asks the user a yes/no question on the command line, defaulting to no. If the user cannot be asked, i.e. if
+/// `interactive` is false, the answer is no.
+pub fn ask_user(question: &str, interactive: bool) -> Result<bool> {
+    if !interactive {
+        eprintln!("{} {} {}", question, tr("[y/N]"), tr("n (not interactive)"));
+        return Ok(false);
+    }
+    eprint!("{} {} ", question, tr("[y/N]"));
+    io::stderr().flush()?;
+    let answer: String = try_read!("{}\n")?;
+    Ok(is_yes(&answer))
+}
+
+fn is_yes(s: &str) -> bool {
+    ["y", "yes", tr("y"), tr("yes")].contains(&s.trim().to_lowercase().as_str())
+}
@@ -0,0 +1,216 @@
+use std::mem::size_of;
+use std::ops::Range;
+
+use anyhow::{Context, Result};
+
+use crate::error::ErrorCategory;
+use crate::estimate::LargeBlockWarning;
+use crate::ext4::{BlockCount, BlockIdx, InodeCount, SuperBlock, FIRST_BLOCK_PADDING};
+use crate::fat::{BootSector, ClusterIdx, FatFs};
+use crate::options::{ConversionOptions, UuidSource};
+use crate::ranges::Ranges;
+use crate::util::{Blocks, Clusters, FromU32};
+
+/// The layout of the ext4 metadata that the serialization keeps free of file data. The serialization relocates the
+/// file data in the way of the ext4 metadata, so the metadata's layout has to be known beforehand. The provisional
+/// superblock has an inode for every file in the FAT filesystem, so its inode tables are at least as large as those
+/// of the final superblock, which only counts the serialized files.
+pub struct ProvisionalLayout {
+    pub superblock: SuperBlock,
+    pub inode_ratio: u32,
+    pub tight_fit: bool,
+    /// the blocks saved by omitting the superblock backup in the last block group with `tight_fit`
+    pub saved_blocks: BlockCount,
+    pub forbidden_ranges: Ranges<ClusterIdx>,
+    /// the files and directories in the FAT filesystem, excluding the root directory
+    pub fat_file_count: usize,
+}
+
+impl ProvisionalLayout {
+    pub fn new(fat_fs: &FatFs, options: &ConversionOptions, mkfs_time: u32, tight_fit: bool) -> Result<Self> {
+        let fat_file_count = fat_fs.file_count();
+        let (mut superblock, inode_ratio) = build_superblock(
+            fat_fs.boot_sector(),
+            options.bigalloc,
+            options.ext4_params.inode_ratio,
+            fat_file_count,
+            mkfs_time,
+            options.uuid,
+        )?;
+        let saved_blocks = if tight_fit { superblock.remove_last_backup() } else { 0 };
+        Ok(Self {
+            forbidden_ranges: forbidden_ranges(&superblock, fat_fs.cluster_count()),
+            superblock,
+            inode_ratio,
+            tight_fit,
+            saved_blocks,
+            fat_file_count,
+        })
+    }
+
+    /// Returns the superblock for the `file_count` serialized files, adding a warning about each compromise it makes to
+    /// `messages`.
+    pub fn choose_superblock(
+        &self,
+        boot_sector: &BootSector,
+        options: &ConversionOptions,
+        file_count: usize,
+        mkfs_time: u32,
+        messages: &mut Vec<String>,
+    ) -> Result<SuperBlock> {
+        let inode_ratio = options.ext4_params.inode_ratio;
+        let (mut final_superblock, final_inode_ratio) =
+            build_superblock(boot_sector, options.bigalloc, inode_ratio, file_count, mkfs_time, options.uuid)?;
+        if self.tight_fit {
+            final_superblock.remove_last_backup();
+        }
+        // the final layout can only be used if the serialization kept its metadata free as well, which is the case
+        // unless it keeps a last block group that the provisional superblock left out
+        let (mut superblock, actual_inode_ratio) = if is_covered(
+            &forbidden_ranges(&final_superblock, boot_sector.cluster_count()),
+            &self.forbidden_ranges,
+        ) {
+            (final_superblock, final_inode_ratio)
+        } else {
+            (self.superblock, self.inode_ratio)
+        };
+        superblock.set_reserved_percent(options.ext4_params.reserved_percent);
+        if actual_inode_ratio != inode_ratio {
+            messages.push(lowered_inode_ratio_message(actual_inode_ratio, file_count));
+        }
+        if self.saved_blocks > 0 {
+            messages.push(format!(
+                "Warning: Omitted the superblock backup in the last block group, which saves {} blocks. If the \
+                 superblock in block group 0 and its backup in block group 1 are both damaged, the filesystem cannot \
+                 be repaired.",
+                self.saved_blocks
+            ));
+        }
+        Ok(superblock)
+    }
+}
+
+/// Creates the superblock for converting a FAT filesystem with `file_count` files and directories (excluding the root
+/// directory), lowering `inode_ratio` if the filesystem would have fewer inodes than that. Returns the superblock along
+/// with the inode ratio it was created with.
+pub fn build_superblock(
+    boot_sector: &BootSector,
+    bigalloc: bool,
+    inode_ratio: u32,
+    file_count: usize,
+    mkfs_time: u32,
+    uuid: UuidSource,
+) -> Result<(SuperBlock, u32)> {
+    // every file needs an inode, and so does lost+found
+    let min_inode_count = InodeCount::try_from(file_count + 1).unwrap_or(InodeCount::MAX);
+    SuperBlock::from_with_min_inode_count(
+        boot_sector,
+        bigalloc,
+        inode_ratio,
+        min_inode_count,
+        mkfs_time,
+        uuid == UuidSource::FromFatSerial,
+    )
+    .context(ErrorCategory::UnsupportedGeometry)
+}
+
+/// Warns if the blocks of `superblock` are too large for most kernels to mount the filesystem, and estimates how
+/// `--bigalloc` would change the conversion, see `LargeBlockWarning`.
+pub fn warn_large_blocks(fat_fs: &FatFs, superblock: &SuperBlock, inode_ratio: u32) {
+    let bigalloc_superblock = build_superblock(
+        fat_fs.boot_sector(),
+        true,
+        inode_ratio,
+        fat_fs.file_count(),
+        0,
+        UuidSource::Random,
+    )
+    .ok()
+    .map(|(bigalloc_superblock, _)| bigalloc_superblock);
+    if let Some(warning) = LargeBlockWarning::new(fat_fs, superblock, bigalloc_superblock.as_ref()) {
+        warning.print();
+    }
+}
+
+pub fn lowered_inode_ratio_message(inode_ratio: u32, file_count: usize) -> String {
+    format!(
+        "Lowered the inode ratio to {} bytes per inode so that the {} files fit",
+        inode_ratio, file_count
+    )
+}
+
+/// Returns true if every cluster in `ranges` is also in `covering_ranges`.
+pub fn is_covered(ranges: &Ranges<ClusterIdx>, covering_ranges: &Ranges<ClusterIdx>) -> bool {
+    ranges.into_iter().all(|range| {
+        covering_ranges
+            .split_overlapping(range.clone())
+            .iter()
+            .all(|(_, is_covered)| *is_covered)
+    })
+}
+
+/// Zeroes the FAT signatures in `signature_ranges` that lie within `remnant_ranges`, so that tools like blkid identify
+/// the partition as ext4 only. Signatures outside of `remnant_ranges` have been overwritten by ext4 metadata.
+pub fn erase_fat_signatures(partition: &mut [u8], signature_ranges: &[Range<usize>], remnant_ranges: &[Range<usize>]) {
+    for signature_range in signature_ranges {
+        let is_remnant = remnant_ranges
+            .iter()
+            .any(|range| range.start <= signature_range.start && signature_range.end <= range.end);
+        if is_remnant {
+            if let Some(signature) = partition.get_mut(signature_range.clone()) {
+                signature.fill(0);
+            }
+        }
+    }
+}
+
+/// Returns the byte ranges that belonged to the FAT metadata (i.e. the boot sector, the reserved sectors and the FAT
+/// tables), which span the first `fat_metadata_len` bytes of the partition, and are not used by the ext4 filesystem
+/// described by `superblock`.
+pub fn fat_remnant_ranges(superblock: &SuperBlock, fat_metadata_len: usize) -> Vec<Range<usize>> {
+    let block_size = usize::fromx(superblock.block_size());
+    // ext4 never uses the first `FIRST_BLOCK_PADDING` bytes, and if the first block contains the superblock, the
+    // remainder of the first block after the superblock is unused as well
+    let superblock_end = FIRST_BLOCK_PADDING + size_of::<SuperBlock>();
+    let first_block_ranges = [0..FIRST_BLOCK_PADDING, superblock_end..block_size.max(superblock_end)];
+
+    let fat_metadata_blocks = 0..fat_metadata_len / block_size;
+    let unused_blocks = superblock
+        .block_group_overhead_ranges()
+        .split_overlapping(fat_metadata_blocks)
+        .into_iter()
+        .filter(|(_, is_overhead)| !is_overhead)
+        .map(|(blocks, _)| blocks.start * block_size..blocks.end * block_size);
+    first_block_ranges
+        .into_iter()
+        .chain(unused_blocks)
+        .filter(|range| !range.is_empty())
+        .collect()
+}
+
+/// Returns the ranges of `ClusterIdx`s in the partition described by `superblock` that may not contain any file data.
+pub fn forbidden_ranges(superblock: &SuperBlock, cluster_count: u32) -> Ranges<ClusterIdx> {
+    let forbidden_ranges = superblock.block_group_overhead_ranges();
+    let mut forbidden_ranges = into_cluster_idx_ranges(forbidden_ranges, superblock.blocks_per_cluster());
+    let last_ext_cluster_idx = ClusterIdx::try_from(superblock.cluster_count_with_padding())
+        .expect("ext4 block count <= FAT32 cluster count, so the index fits into a ClusterIdx");
+    let overhanging_block_range = last_ext_cluster_idx..cluster_count;
+    forbidden_ranges.insert(overhanging_block_range);
+    forbidden_ranges
+}
+
+/// Returns the ranges of clusters that contain at least one block in `ranges`.
+fn into_cluster_idx_ranges(ranges: Ranges<BlockIdx>, blocks_per_cluster: u32) -> Ranges<ClusterIdx> {
+    let to_cluster_idx = |clusters: Clusters| {
+        clusters
+            .to_u32()
+            .expect("ext4 cluster count <= FAT32 cluster count, so the indices fit into a ClusterIdx")
+    };
+    ranges
+        .into_iter()
+        .map(|range| {
+            to_cluster_idx(Blocks::from(range.start).to_clusters_rounding_down(blocks_per_cluster))
+                ..to_cluster_idx(Blocks::from(range.end).to_clusters_rounding_up(blocks_per_cluster))
+        })
+        .collect()
+}
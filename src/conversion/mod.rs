@@ -0,0 +1,11 @@
+mod checks;
+mod layout;
+mod path;
+mod pipeline;
+mod stats;
+
+pub use self::checks::*;
+pub use self::layout::*;
+pub use self::path::*;
+pub use self::pipeline::*;
+pub use self::stats::*;
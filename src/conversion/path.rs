@@ -0,0 +1,180 @@
+use std::marker::PhantomData;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+
+use crate::checkpoint::ConversionState;
+use crate::conversion::{
+    ask_user, convert, resume_conversion, run_conversion, with_checked_partition, Conversion, ConversionStats,
+    DryRunReport, StopPoint,
+};
+use crate::error::ErrorCategory;
+use crate::messages::tr;
+use crate::options::ConversionOptions;
+#[cfg(feature = "sparse-images")]
+use crate::partition::BufferedPartition;
+use crate::partition::{BlockAccess, DemandPagedPartition, DirectIoPartition, Partition};
+#[cfg(feature = "sparse-images")]
+use crate::sparse::SparseImage;
+
+/// Checks the partition at `partition_path` and converts it. Returns the conversion's stats and duration.
+pub fn convert_path(partition_path: &str, options: &ConversionOptions) -> Result<(ConversionStats, Duration)> {
+    with_checked_partition(partition_path, options, |partition_path| {
+        if options.trial_run {
+            let start_time = Instant::now();
+            // SAFETY: We've done our best to ensure the partition at `partition_path` contains a consistent FAT32
+            // filesystem
+            let stats = unsafe { ofs_convert(partition_path, options, true)? };
+            println!("Trial conversion, the partition has not been modified:");
+            stats.print_messages();
+            stats.print_summary(start_time.elapsed());
+            if !ask_user(tr("Convert the partition?"), options.interactive)? {
+                bail!(ErrorCategory::Aborted);
+            }
+        }
+
+        let start_time = Instant::now();
+        // SAFETY: We've done our best to ensure the partition at `partition_path` contains a consistent FAT32
+        // filesystem
+        let stats = unsafe { ofs_convert(partition_path, options, false)? };
+        Ok((stats, start_time.elapsed()))
+    })
+}
+
+/// Checks the partition at `partition_path` and converts it until the dry run, see `run_conversion`.
+pub fn stop_path_after_plan(partition_path: &str, options: &ConversionOptions) -> Result<ConversionState> {
+    with_checked_partition(partition_path, options, |partition_path| {
+        let conversion = with_partition(partition_path, options, false, |partition_ptr, partition_len, lifetime| {
+            // SAFETY: We've done our best to ensure the partition at `partition_path` contains a consistent FAT32
+            // filesystem
+            unsafe { run_conversion(partition_ptr, partition_len, lifetime, options, StopPoint::AfterPlan) }
+        })?;
+        match conversion {
+            Conversion::Stopped(state) => Ok(state),
+            _ => unreachable!("The conversion stops after the dry run if asked to"),
+        }
+    })
+}
+
+/// Checks the partition at `partition_path` and converts it until the dry run on a copy-on-write mapping, like
+/// `--trial-run`, so that the partition is not modified. Questions are answered with no, since restoring the boot
+/// sector would modify the partition.
+pub fn dry_run_path(partition_path: &str, options: &ConversionOptions) -> Result<DryRunReport> {
+    let options = ConversionOptions { interactive: false, ..options.clone() };
+    with_checked_partition(partition_path, &options, |partition_path| {
+        let conversion = with_partition(partition_path, &options, true, |partition_ptr, partition_len, lifetime| {
+            // SAFETY: We've done our best to ensure the partition at `partition_path` contains a consistent FAT32
+            // filesystem
+            unsafe { run_conversion(partition_ptr, partition_len, lifetime, &options, StopPoint::AfterDryRun) }
+        })?;
+        match conversion {
+            Conversion::DryRun(report) => Ok(report),
+            _ => unreachable!("The conversion stops after the dry run if asked to"),
+        }
+    })
+}
+
+/// Checks the partition at `partition_path` and finishes the conversion that `stop_path_after_plan` stopped with
+/// `state`. Returns the conversion's stats and the duration of the finished part.
+pub fn continue_path(
+    partition_path: &str,
+    options: &ConversionOptions,
+    state: &ConversionState,
+) -> Result<(ConversionStats, Duration)> {
+    with_checked_partition(partition_path, options, |partition_path| {
+        let start_time = Instant::now();
+        let stats = with_partition(partition_path, options, false, |partition_ptr, partition_len, lifetime| {
+            // SAFETY: We've done our best to ensure the partition at `partition_path` contains a consistent FAT32
+            // filesystem, and `resume_conversion` checks that it is the one `state` was created for.
+            unsafe { resume_conversion(partition_ptr, partition_len, lifetime, options, state) }
+        })?;
+        Ok((stats, start_time.elapsed()))
+    })
+}
+
+/// Converts the partition at `partition_path`. If `trial` is set, the partition is not modified, see
+/// `with_partition`.
+/// SAFETY: `partition_path` must point to a partition containing a consistent FAT32 filesystem.
+unsafe fn ofs_convert(partition_path: &str, options: &ConversionOptions, trial: bool) -> Result<ConversionStats> {
+    with_partition(partition_path, options, trial, |partition_ptr, partition_len, lifetime| {
+        // SAFETY: Safe because the caller guarantees that the partition contains a FAT32 filesystem.
+        unsafe { convert(partition_ptr, partition_len, lifetime, options) }
+    })
+}
+
+/// Opens the partition at `partition_path` and calls `convert_partition` with its memory, which is valid for reads and
+/// writes of the given length for the given lifetime. If `trial` is set, `convert_partition` runs on a copy-on-write
+/// mapping or, with `direct_io` or for a sparse image, on the copy in memory, so the partition is not modified.
+pub fn with_partition<T, F>(
+    partition_path: &str,
+    options: &ConversionOptions,
+    trial: bool,
+    convert_partition: F,
+) -> Result<T>
+where
+    F: FnOnce(*mut u8, usize, PhantomData<&()>) -> Result<T>,
+{
+    #[cfg(feature = "sparse-images")]
+    if SparseImage::detect(partition_path).context(ErrorCategory::Io)? {
+        let backend = SparseImage::open(partition_path, options.sparse_offset).context(ErrorCategory::Io)?;
+        return with_buffered_partition(backend, trial, convert_partition);
+    }
+    if options.direct_io {
+        let backend = DirectIoPartition::open(partition_path).context(ErrorCategory::Io)?;
+        with_demand_paged_partition(backend, trial, convert_partition)
+    } else {
+        let partition = if trial {
+            Partition::open_copy_on_write(partition_path)
+        } else {
+            Partition::open(partition_path)
+        };
+        let mut partition = partition.context(ErrorCategory::Io)?;
+        let result = convert_partition(partition.as_mut_ptr(), partition.len(), partition.lifetime)?;
+        if !trial {
+            partition.flush().context(ErrorCategory::Io)?;
+        }
+        Ok(result)
+    }
+}
+
+/// Calls `convert_partition` with a copy of the partition behind `backend` that is loaded into memory where it is
+/// accessed and, unless `trial` is set, writes the modified parts back, see `with_partition`.
+fn with_demand_paged_partition<B, T, F>(backend: B, trial: bool, convert_partition: F) -> Result<T>
+where
+    B: BlockAccess + Send + 'static,
+    F: FnOnce(*mut u8, usize, PhantomData<&()>) -> Result<T>,
+{
+    let mut partition = DemandPagedPartition::new(backend).context(ErrorCategory::Io)?;
+    let result = convert_partition(partition.as_mut_ptr(), partition.len(), partition.lifetime);
+    // if a chunk could not be read, the conversion saw zeros instead, which is the actual cause of any error
+    partition.stop().context(ErrorCategory::Io)?;
+    let result = result?;
+    if trial {
+        return Ok(result);
+    }
+    partition
+        .write_back()
+        .context(ErrorCategory::ConversionFailed)
+        .context("Unable to write the converted filesystem to the partition")?;
+    Ok(result)
+}
+
+/// Loads the partition behind `backend` into memory, calls `convert_partition` with the copy and, unless `trial` is
+/// set, writes the modified parts back, see `with_partition`.
+#[cfg(feature = "sparse-images")]
+fn with_buffered_partition<B, T, F>(backend: B, trial: bool, convert_partition: F) -> Result<T>
+where
+    B: BlockAccess,
+    F: FnOnce(*mut u8, usize, PhantomData<&()>) -> Result<T>,
+{
+    let mut partition = BufferedPartition::load(backend).context(ErrorCategory::Io)?;
+    let result = convert_partition(partition.as_mut_ptr(), partition.len(), partition.lifetime)?;
+    if trial {
+        return Ok(result);
+    }
+    partition
+        .write_back()
+        .context(ErrorCategory::ConversionFailed)
+        .context("Unable to write the converted filesystem to the partition")?;
+    Ok(result)
+}
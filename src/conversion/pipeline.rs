@@ -0,0 +1,1663 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::marker::PhantomData;
+use std::mem;
+use std::rc::Rc;
+
+use anyhow::{bail, Context, Result};
+
+use crate::checkpoint::{ConversionState, SerializationReport};
+use crate::conversion::{erase_fat_signatures, fat_remnant_ranges, ConversionStats, DryRunReport, ProvisionalLayout};
+use crate::crtime::{CrtimeMapping, CrtimeSource, Timestamp};
+use crate::error::{exit_code, ErrorCategory};
+use crate::estimate::SpaceEstimate;
+use crate::ext4::{self, SuperBlock};
+use crate::fat::{BootSector, FatFs};
+use crate::fat_checksums::FatMetadataChecksums;
+use crate::messages::tr;
+use crate::options::ConversionOptions;
+use crate::progress::{JsonProgress, Phase, Progress};
+use crate::serialization::{
+    ConversionPolicy, ConvertedFile, ErrorPolicy, Ext4TreeDeserializer, FatTreeSerializer, LongNameTruncator,
+    OwnerPolicy, Reader, ShortcutConverter,
+};
+use crate::spot_check::{SpotCheckReport, SpotCheckSample};
+use crate::trace::Trace;
+use crate::util::{FromU32, RateLimiter};
+
+/// The number of bytes of the FAT remnants that `--wipe-fat-remnants` zeroes at a time, so that `--bwlimit` can pace it
+const WIPE_CHUNK_LEN: usize = 1 << 20;
+
+/// The callback of `ConversionHooks::on_file_converted`, shared by the attempts of `run_conversion`
+type FileCatalog = Rc<RefCell<dyn FnMut(ConvertedFile)>>;
+
+/// What a program that embeds the converter adds to a conversion by `convert_slice_with_hooks`. The policies and the
+/// callback are shared by the attempts of the conversion, so if it starts over with a tight fit, see
+/// `ConversionOptions::allow_tight_fit`, the policies are consulted again for every file.
+#[derive(Default)]
+pub struct ConversionHooks {
+    policies: Vec<Rc<RefCell<dyn ConversionPolicy>>>,
+    catalog: Option<FileCatalog>,
+}
+
+impl ConversionHooks {
+    /// Appends `policy` to the policies that decide for every file, see `ConversionPolicy`. They are consulted after
+    /// the policies behind `--exclude-size-over`, `--exclude-older-than` and `--owner` and before the truncation of
+    /// long names, so that the names they decide are truncated as well.
+    pub fn add_policy<P: ConversionPolicy + 'static>(&mut self, policy: P) {
+        self.policies.push(Rc::new(RefCell::new(policy)));
+    }
+
+    /// Calls `callback` for every file and directory that is converted, once its inode has been created, so that the
+    /// caller can build a catalog of the converted files, e.g. to index a backup, see
+    /// `Ext4TreeDeserializer::on_file_converted`.
+    pub fn on_file_converted<F: FnMut(ConvertedFile) + 'static>(&mut self, callback: F) {
+        self.catalog = Some(Rc::new(RefCell::new(callback)));
+    }
+}
+
+/// Converts the FAT32 filesystem in the memory pointed to by `partition_ptr`. If it does not fit and
+/// `options.allow_tight_fit` is set, starts over with a tight fit.
+/// SAFETY: `partition_ptr` must be valid for reads and writes of `partition_len` bytes for the lifetime of `lifetime`
+/// and point to a consistent FAT32 filesystem.
+pub unsafe fn convert(
+    partition_ptr: *mut u8,
+    partition_len: usize,
+    lifetime: PhantomData<&()>,
+    options: &ConversionOptions,
+) -> Result<ConversionStats> {
+    // SAFETY: Safe because the caller guarantees that the memory is valid and contains a FAT32 filesystem.
+    match unsafe { run_conversion(partition_ptr, partition_len, lifetime, options, StopPoint::Never) }? {
+        Conversion::Finished(stats) => Ok(stats),
+        _ => unreachable!("The conversion only stops after the dry run if asked to"),
+    }
+}
+
+/// Converts the FAT32 filesystem in `partition` in memory, e.g. an image that was read into a buffer, into an ext4
+/// filesystem. This is the entry point for programs that embed the converter and do their own IO; the binary converts
+/// memory-mapped partitions through `convert` instead.
+///
+/// Unlike `convert`, this is safe: `partition` is borrowed exclusively for the whole conversion, and the conversion
+/// only ever reads the clusters that the FAT marks as used and only writes to the others until it has finished reading
+/// the FAT filesystem, see `FatFs::from_slice_with_allocator`. Returns an error if `partition` is not aligned to 4
+/// bytes or if the conversion fails, see `ErrorCategory` for the causes.
+pub fn convert_slice(partition: &mut [u8], options: &ConversionOptions) -> Result<ConversionStats> {
+    FatFs::check_slice(partition).context(ErrorCategory::InvalidFilesystem)?;
+    // SAFETY: Safe because `partition` is valid for reads and writes and borrowed exclusively for the conversion, which
+    // creates the only `Allocator` for it.
+    unsafe { convert(partition.as_mut_ptr(), partition.len(), PhantomData, options) }
+}
+
+/// Like `convert_slice`, but calls `on_file_converted` for every file and directory that is converted, see
+/// `ConversionHooks::on_file_converted`.
+pub fn convert_slice_with_catalog<F: FnMut(ConvertedFile) + 'static>(
+    partition: &mut [u8],
+    options: &ConversionOptions,
+    on_file_converted: F,
+) -> Result<ConversionStats> {
+    let mut hooks = ConversionHooks::default();
+    hooks.on_file_converted(on_file_converted);
+    convert_slice_with_hooks(partition, options, &hooks)
+}
+
+/// Like `convert_slice`, but with the policies and the callback of `hooks`, e.g. to give the converted files owners
+/// or extended attributes by the caller's rules.
+pub fn convert_slice_with_hooks(
+    partition: &mut [u8],
+    options: &ConversionOptions,
+    hooks: &ConversionHooks,
+) -> Result<ConversionStats> {
+    FatFs::check_slice(partition).context(ErrorCategory::InvalidFilesystem)?;
+    // SAFETY: Safe because `partition` is valid for reads and writes and borrowed exclusively for the conversion, see
+    // `convert_slice`.
+    let conversion = unsafe {
+        run_with_hooks(
+            partition.as_mut_ptr(),
+            partition.len(),
+            PhantomData,
+            options,
+            StopPoint::Never,
+            hooks,
+        )
+    }?;
+    match conversion {
+        Conversion::Finished(stats) => Ok(stats),
+        _ => unreachable!("The conversion only stops after the dry run if asked to"),
+    }
+}
+
+/// Like `convert`, but the conversion may stop after the dry run, before the FAT filesystem has been modified, see
+/// `StopPoint`.
+/// SAFETY: See `convert`.
+pub unsafe fn run_conversion(
+    partition_ptr: *mut u8,
+    partition_len: usize,
+    lifetime: PhantomData<&()>,
+    options: &ConversionOptions,
+    stop: StopPoint,
+) -> Result<Conversion> {
+    // SAFETY: Safe because the caller guarantees that the memory is valid and contains a FAT32 filesystem.
+    unsafe {
+        run_with_hooks(
+            partition_ptr,
+            partition_len,
+            lifetime,
+            options,
+            stop,
+            &ConversionHooks::default(),
+        )
+    }
+}
+
+/// Like `run_conversion`, but with `hooks`, see `convert_slice_with_hooks`.
+/// SAFETY: See `convert`.
+unsafe fn run_with_hooks(
+    partition_ptr: *mut u8,
+    partition_len: usize,
+    lifetime: PhantomData<&()>,
+    options: &ConversionOptions,
+    stop: StopPoint,
+    hooks: &ConversionHooks,
+) -> Result<Conversion> {
+    // SAFETY: Safe because the caller guarantees that the memory is valid and contains a FAT32 filesystem.
+    let result = unsafe { convert_with_layout(partition_ptr, partition_len, lifetime, options, false, stop, hooks) };
+    match result {
+        // the free space runs out during the serialization or the dry run, which only write to free clusters, so the
+        // FAT filesystem is still intact
+        Err(error) if options.allow_tight_fit && exit_code(&error) == ErrorCategory::InsufficientSpace.exit_code() => {
+            // SAFETY: Safe because the caller guarantees that the memory is valid and contains a FAT32 filesystem,
+            // and the failed conversion did not modify the filesystem.
+            let mut conversion =
+                unsafe { convert_with_layout(partition_ptr, partition_len, lifetime, options, true, stop, hooks) }?;
+            let retry_messages = [format!("Warning: {:#}", error), "Retrying with a tight fit".to_string()];
+            conversion.messages_mut().splice(0..0, retry_messages);
+            Ok(conversion)
+        }
+        result => result,
+    }
+}
+
+/// Where `run_conversion` stops
+#[derive(Clone, Copy, PartialEq)]
+pub enum StopPoint {
+    Never,
+    /// after the dry run, returning what `resume_conversion` needs to finish the conversion, see `--stop-after-plan`
+    AfterPlan,
+    /// after the dry run, returning its prediction, see `--dry-run`
+    AfterDryRun,
+}
+
+/// The outcome of `run_conversion`
+pub enum Conversion {
+    Finished(ConversionStats),
+    /// the conversion stopped after the dry run because of `--stop-after-plan`
+    Stopped(ConversionState),
+    /// the conversion stopped after the dry run because of `--dry-run`
+    DryRun(DryRunReport),
+}
+
+impl Conversion {
+    fn messages_mut(&mut self) -> &mut Vec<String> {
+        match self {
+            Self::Finished(stats) => &mut stats.messages,
+            Self::Stopped(state) => &mut state.report.messages,
+            Self::DryRun(report) => &mut report.report.messages,
+        }
+    }
+}
+
+/// Converts the FAT32 filesystem in the memory pointed to by `partition_ptr`. With `tight_fit`, the last block group
+/// does not get a superblock backup, which leaves the filesystem with a single backup. For `stop`, see
+/// `run_conversion`, for `hooks`, see `convert_slice_with_hooks`.
+/// SAFETY: `partition_ptr` must be valid for reads and writes of `partition_len` bytes for the lifetime of `lifetime`
+/// and point to a consistent FAT32 filesystem.
+unsafe fn convert_with_layout(
+    partition_ptr: *mut u8,
+    partition_len: usize,
+    lifetime: PhantomData<&()>,
+    options: &ConversionOptions,
+    tight_fit: bool,
+    stop: StopPoint,
+    hooks: &ConversionHooks,
+) -> Result<Conversion> {
+    // SAFETY: Safe because the caller guarantees that the memory is valid, and nothing has been written yet.
+    let fat_metadata_checksums = unsafe { checksum_fat_metadata(partition_ptr, partition_len, options) }?;
+    // SAFETY: Safe because the caller guarantees that the memory is valid and contains a FAT32 filesystem.
+    let (fat_fs, mut allocator) = unsafe { FatFs::new_with_allocator(partition_ptr, partition_len, lifetime) }
+        .context(ErrorCategory::InvalidFilesystem)?;
+    let trace = create_trace(options)?;
+    allocator.set_trace(trace.clone());
+    let boot_sector = *fat_fs.boot_sector();
+    let mkfs_time = options.mkfs_time.resolve(&fat_fs)?;
+    // the serialization does not modify the FAT table or the directories, so the fingerprint stays the same
+    let fingerprint = (stop == StopPoint::AfterPlan)
+        .then(|| fat_fs.fingerprint())
+        .transpose()
+        .context(ErrorCategory::InvalidFilesystem)?;
+    // sampled before the relocation, which the spot check is meant to check
+    let spot_check_samples = options
+        .spot_check
+        .map(|count| SpotCheckSample::choose(&fat_fs, count))
+        .transpose()
+        .context("Unable to sample the files for the spot check")
+        .context(ErrorCategory::InvalidFilesystem)?
+        .unwrap_or_default();
+
+    let layout = ProvisionalLayout::new(&fat_fs, options, mkfs_time, tight_fit)?;
+    for range in &layout.forbidden_ranges {
+        allocator.forbid(range.clone());
+    }
+    let progress = create_progress(options)?;
+    start_phase(progress.as_deref(), Phase::Serialize);
+    // The clusters spent on relocating the file data are gone by the time of the dry run, so a conversion that fails
+    // because of the relocation is rejected here, before anything has been serialized. Excluded files are not
+    // relocated, so if policies or ops may exclude or shrink files, the estimate is only an upper bound and the
+    // serialization has to find out.
+    if options.filter.is_empty() && !options.convert_shortcuts && !options.collect_errors && hooks.policies.is_empty() {
+        SpaceEstimate::new(&fat_fs, &layout.superblock).check_relocation()?;
+    }
+
+    let mut serializer = FatTreeSerializer::new(allocator, fat_fs, layout.forbidden_ranges.clone());
+    if !options.filter.is_empty() {
+        serializer.add_policy(options.filter);
+    }
+    if let Some(owner) = options.owner {
+        serializer.add_policy(OwnerPolicy(owner));
+    }
+    for policy in &hooks.policies {
+        serializer.add_policy(Rc::clone(policy));
+    }
+    // last, so that it sees the names decided by the other policies
+    let truncator = Rc::new(RefCell::new(LongNameTruncator::new()));
+    if options.truncate_long_names {
+        serializer.add_policy(Rc::clone(&truncator));
+    }
+    if options.convert_shortcuts {
+        serializer.add_op(ShortcutConverter);
+    }
+    if options.collect_errors {
+        serializer.set_error_policy(ErrorPolicy::CollectErrors);
+    }
+    serializer.set_invalid_attributes_policy(options.invalid_attributes);
+    serializer.set_dentry_order(options.dentry_order);
+    serializer.set_verify_archival(options.verify_archival);
+    serializer.set_min_free_percent(options.min_free_space_after);
+    if let Some(rate_limit) = options.rate_limit() {
+        serializer.set_rate_limit(rate_limit);
+    }
+    if options.dedup_hardlink {
+        serializer.fold_duplicates_into_hard_links();
+    }
+    if !options.skeleton.is_empty() {
+        serializer.set_skeleton(Rc::new(options.skeleton.clone()));
+    }
+    if options.archive_bit_list.is_some() {
+        serializer.list_archive_bit_files();
+    }
+    if let Some(progress) = progress.clone() {
+        // excluded files are never reached, so the serialization may end before 100%
+        let total = layout.fat_file_count;
+        let mut done = 0;
+        serializer.on_file_reached(move |path| {
+            done += 1;
+            progress.advance(path, done, total);
+        });
+    }
+    serializer.serialize_directory_tree().context("Serialization failed")?;
+    let mut messages = Vec::new();
+    for long_name in truncator.borrow().long_names() {
+        messages.push(format!("Warning: Truncated the name of {}", long_name));
+    }
+    let truncated_files = serializer.truncated_files();
+    for truncated_file in &truncated_files {
+        messages.push(format!("Warning: Truncated {}", truncated_file));
+    }
+    for invalid_attributes_file in serializer.invalid_attributes_files() {
+        messages.push(format!("Warning: Invalid attributes: {}", invalid_attributes_file));
+    }
+    let skipped_files = serializer.skipped_files();
+    for skipped_file in &skipped_files {
+        messages.push(format!("Warning: Skipped {}", skipped_file));
+    }
+    let exclusion_stats = serializer.exclusion_stats();
+    if exclusion_stats.file_count > 0 {
+        messages.push(format!(
+            "Excluded {} files, saving {} bytes and {} inodes",
+            exclusion_stats.file_count,
+            exclusion_stats.cluster_count * usize::fromx(boot_sector.cluster_size()),
+            exclusion_stats.file_count
+        ));
+    }
+    let hard_link_stats = serializer.hard_link_stats();
+    if hard_link_stats.link_count > 0 {
+        messages.push(format!(
+            "Turned {} duplicate files into hard links, saving {} bytes and {} inodes",
+            hard_link_stats.link_count,
+            hard_link_stats.cluster_count * usize::fromx(boot_sector.cluster_size()),
+            hard_link_stats.link_count
+        ));
+    }
+    let mut report = SerializationReport {
+        truncated_file_count: truncated_files.len(),
+        skipped_file_count: skipped_files.len(),
+        archive_bit_files: serializer.archive_bit_files(),
+        spot_check_samples,
+        fat_metadata_checksums,
+        messages,
+    };
+
+    // the directories of the skeleton need inodes as well, even those that the serialized files already contain
+    let file_count = serializer.file_count() + options.skeleton.dir_count();
+    let superblock = layout.choose_superblock(&boot_sector, options, file_count, mkfs_time, &mut report.messages)?;
+    start_phase(progress.as_deref(), Phase::DryRun);
+    if let Some(fingerprint) = fingerprint {
+        let archive = serializer
+            .into_archive_location(&superblock)
+            .context(tr("A dry run of the conversion failed"))?;
+        finish_trace(trace)?;
+        return Ok(Conversion::Stopped(ConversionState {
+            fingerprint,
+            tight_fit,
+            file_count,
+            archive,
+            report,
+        }));
+    }
+    if stop == StopPoint::AfterDryRun {
+        let prediction = serializer
+            .into_prediction(&superblock)
+            .context(tr("A dry run of the conversion failed"))?;
+        finish_trace(trace)?;
+        return Ok(Conversion::DryRun(DryRunReport {
+            file_count,
+            allocatable_inode_count: superblock.allocatable_inode_count(),
+            cluster_size: superblock.cluster_size(),
+            prediction,
+            tight_fit,
+            report,
+        }));
+    }
+    // SAFETY: Safe because the allocator's forbidden ranges cover the ext4 metadata of `superblock`
+    let deserializer = unsafe {
+        serializer
+            .into_deserializer(superblock)
+            .context(tr("A dry run of the conversion failed"))?
+    };
+
+    // SAFETY: Safe because the caller guarantees that the memory is valid, and `deserializer` writes the ext4
+    // filesystem described by `superblock`.
+    let stats = unsafe {
+        finish_conversion(
+            deserializer,
+            &superblock,
+            &boot_sector,
+            partition_ptr,
+            partition_len,
+            options,
+            report,
+            progress,
+            hooks.catalog.clone(),
+        )?
+    };
+    finish_trace(trace)?;
+    Ok(Conversion::Finished(stats))
+}
+
+/// Creates the trace of the conversion if `options.trace` is set.
+fn create_trace(options: &ConversionOptions) -> Result<Option<Rc<Trace>>> {
+    let trace = options.trace.as_deref().map(Trace::create).transpose();
+    Ok(trace.context(ErrorCategory::Io)?.map(Rc::new))
+}
+
+/// Creates the progress stream of the conversion if `options.json_progress_fd` is set.
+fn create_progress(options: &ConversionOptions) -> Result<Option<Rc<dyn Progress>>> {
+    let progress = options.json_progress_fd.map(JsonProgress::to_fd).transpose();
+    Ok(progress
+        .context(ErrorCategory::Io)?
+        .map(|progress| Rc::new(progress) as Rc<dyn Progress>))
+}
+
+pub fn start_phase(progress: Option<&dyn Progress>, phase: Phase) {
+    if let Some(progress) = progress {
+        progress.start_phase(phase);
+    }
+}
+
+/// Returns an error if the trace could not be written completely. The trace of a failed conversion is flushed when
+/// it is dropped instead.
+fn finish_trace(trace: Option<Rc<Trace>>) -> Result<()> {
+    if let Some(trace) = trace {
+        trace.finish().context("Unable to write the trace").context(ErrorCategory::Io)?;
+    }
+    Ok(())
+}
+
+/// Finishes a conversion that `run_conversion` stopped after the dry run. Fails without modifying the partition if
+/// the FAT filesystem differs from the one that was serialized.
+/// SAFETY: `partition_ptr` must be valid for reads and writes of `partition_len` bytes for the lifetime of `lifetime`
+/// and point to a consistent FAT32 filesystem. If its fingerprint matches `state.fingerprint`, the clusters that were
+/// free in it must not have been modified since the conversion stopped.
+pub unsafe fn resume_conversion(
+    partition_ptr: *mut u8,
+    partition_len: usize,
+    lifetime: PhantomData<&()>,
+    options: &ConversionOptions,
+    state: &ConversionState,
+) -> Result<ConversionStats> {
+    let mut report = state.report.clone();
+    // a checkpoint saved without the option lacks the checksums, but the FAT metadata is still the original one
+    if report.fat_metadata_checksums.is_none() {
+        // SAFETY: Safe because the caller guarantees that the memory is valid, and nothing has been written yet.
+        report.fat_metadata_checksums = unsafe { checksum_fat_metadata(partition_ptr, partition_len, options) }?;
+    }
+    // SAFETY: Safe because the caller guarantees that the memory is valid and contains a FAT32 filesystem.
+    let (fat_fs, mut allocator) = unsafe { FatFs::new_with_allocator(partition_ptr, partition_len, lifetime) }
+        .context(ErrorCategory::InvalidFilesystem)?;
+    if fat_fs.fingerprint().context(ErrorCategory::InvalidFilesystem)? != state.fingerprint {
+        bail!("The FAT filesystem has been modified since the conversion was stopped");
+    }
+    let trace = create_trace(options)?;
+    allocator.set_trace(trace.clone());
+    let boot_sector = *fat_fs.boot_sector();
+    let mkfs_time = options.mkfs_time.resolve(&fat_fs)?;
+
+    // the FAT filesystem is unchanged, so the layout is the one the serialization kept free
+    let layout = ProvisionalLayout::new(&fat_fs, options, mkfs_time, state.tight_fit)?;
+    for range in &layout.forbidden_ranges {
+        allocator.forbid(range.clone());
+    }
+    let superblock =
+        layout.choose_superblock(&boot_sector, options, state.file_count, mkfs_time, &mut report.messages)?;
+    let progress = create_progress(options)?;
+    start_phase(progress.as_deref(), Phase::DryRun);
+    // SAFETY: Safe because the allocator has the used ranges of the allocator that allocated the archive, and the
+    // caller guarantees that the archive has not been modified since.
+    let (reader, allocator) =
+        unsafe { Reader::resume(state.archive, usize::fromx(boot_sector.cluster_size()), allocator) };
+    // SAFETY: Safe because the allocator's forbidden ranges cover the ext4 metadata of `superblock`
+    let skeleton = Rc::new(options.skeleton.clone());
+    let deserializer =
+        unsafe { Ext4TreeDeserializer::new_with_dry_run(reader, allocator, fat_fs, superblock, skeleton) }
+            .context(tr("A dry run of the conversion failed"))?;
+    // SAFETY: Safe because the caller guarantees that the memory is valid, and `deserializer` writes the ext4
+    // filesystem described by `superblock`.
+    let stats = unsafe {
+        finish_conversion(
+            deserializer,
+            &superblock,
+            &boot_sector,
+            partition_ptr,
+            partition_len,
+            options,
+            report,
+            progress,
+            None,
+        )?
+    };
+    finish_trace(trace)?;
+    Ok(stats)
+}
+
+/// Hashes the FAT metadata of the partition if `options.checksum_fat_metadata` is set, see `FatMetadataChecksums`.
+/// SAFETY: `partition_ptr` must be valid for reads of `partition_len` bytes, which must not be modified during the
+/// call.
+pub unsafe fn checksum_fat_metadata(
+    partition_ptr: *mut u8,
+    partition_len: usize,
+    options: &ConversionOptions,
+) -> Result<Option<FatMetadataChecksums>> {
+    if !options.checksum_fat_metadata {
+        return Ok(None);
+    }
+    // SAFETY: Safe because the caller guarantees that the memory is valid for reads and not modified meanwhile.
+    let partition = unsafe { std::slice::from_raw_parts(partition_ptr, partition_len) };
+    let checksums = FatMetadataChecksums::compute(partition)
+        .context("Unable to checksum the FAT metadata")
+        .context(ErrorCategory::InvalidFilesystem)?;
+    Ok(Some(checksums))
+}
+
+/// Writes the ext4 filesystem described by `superblock` with `deserializer` and erases what is left of the FAT
+/// filesystem described by `boot_sector`.
+/// SAFETY: `partition_ptr` must be valid for reads and writes of `partition_len` bytes, and `deserializer` must have
+/// been created from the FAT filesystem in this memory. `progress` receives the phases from the write phase on, and
+/// `catalog` the converted files.
+#[allow(clippy::too_many_arguments)] // shared by `convert_with_layout` and `resume_conversion`, which have them all
+unsafe fn finish_conversion(
+    mut deserializer: Ext4TreeDeserializer,
+    superblock: &SuperBlock,
+    boot_sector: &BootSector,
+    partition_ptr: *mut u8,
+    partition_len: usize,
+    options: &ConversionOptions,
+    mut report: SerializationReport,
+    progress: Option<Rc<dyn Progress>>,
+    catalog: Option<FileCatalog>,
+) -> Result<ConversionStats> {
+    let fat_metadata_len = boot_sector.get_data_range().start;
+    let signature_ranges = boot_sector.signature_ranges();
+    deserializer.set_randomize_generation(options.randomize_generation);
+    deserializer.set_drop_atime(options.drop_atime);
+    if let Some(owner) = options.owner {
+        deserializer.set_owner(owner);
+    }
+    if let Some(rate_limit) = options.rate_limit() {
+        deserializer.set_rate_limit(rate_limit);
+    }
+    let crtime_sources = Rc::new(RefCell::new(Vec::new()));
+    let list_crtimes = options.crtime_list.is_some();
+    let spot_check_samples = mem::take(&mut report.spot_check_samples);
+    let spot_check_inodes = Rc::new(RefCell::new(HashMap::new()));
+    if list_crtimes || progress.is_some() || !spot_check_samples.is_empty() || catalog.is_some() {
+        let crtime_sources = Rc::clone(&crtime_sources);
+        let spot_check_inodes = Rc::clone(&spot_check_inodes);
+        let sampled_paths: HashSet<_> = spot_check_samples.iter().map(|sample| sample.path.clone()).collect();
+        let progress = progress.clone();
+        // every inode of the dry run except that of lost+found belongs to a converted file
+        let predicted_inodes = deserializer.predicted_usage().map_or(0, |usage| usage.inodes);
+        let total = usize::fromx(predicted_inodes.saturating_sub(1));
+        let mut done = 0;
+        deserializer.on_file_converted(move |file| {
+            if list_crtimes {
+                crtime_sources.borrow_mut().push(CrtimeSource {
+                    path: file.path.to_string(),
+                    inode_no: file.inode_no,
+                    fat_create_time: Timestamp {
+                        seconds: file.dentry.create_time,
+                        nanoseconds: file.dentry.create_time_ns,
+                    },
+                });
+            }
+            if sampled_paths.contains(file.path) {
+                spot_check_inodes.borrow_mut().insert(file.path.to_string(), file.inode_no);
+            }
+            if let Some(progress) = &progress {
+                done += 1;
+                progress.advance(file.path, done, total);
+            }
+            if let Some(catalog) = &catalog {
+                (catalog.borrow_mut())(file);
+            }
+        });
+    }
+    start_phase(progress.as_deref(), Phase::Write);
+    deserializer
+        .deserialize_directory_tree()
+        .context(ErrorCategory::ConversionFailed)?;
+    start_phase(progress.as_deref(), Phase::Finalize);
+    let mut stats = ConversionStats {
+        predicted_usage: deserializer.predicted_usage().expect("`into_deserializer` performs a dry run"),
+        actual_usage: deserializer.actual_usage(),
+        allocator_stats: deserializer.allocator_stats(),
+        fs_stats: deserializer.fs_stats(),
+        cluster_size: boot_sector.cluster_size(),
+        truncated_file_count: report.truncated_file_count,
+        skipped_file_count: report.skipped_file_count,
+        dentry_order: options.dentry_order,
+        archive_bit_files: report.archive_bit_files,
+        crtime_mappings: Vec::new(),
+        spot_check: None,
+        fat_metadata_checksums: report.fat_metadata_checksums,
+        messages: report.messages,
+    };
+    deserializer.finalize().context(ErrorCategory::ConversionFailed)?;
+
+    // SAFETY: Safe because the caller guarantees that the memory is valid, and `deserializer`, which borrowed it, has
+    // been consumed.
+    let partition = unsafe { std::slice::from_raw_parts_mut(partition_ptr, partition_len) };
+    let remnant_ranges = fat_remnant_ranges(superblock, fat_metadata_len);
+    erase_fat_signatures(partition, &signature_ranges, &remnant_ranges);
+    if options.wipe_fat_remnants {
+        let rate_limiter = options.rate_limit().map(RateLimiter::new);
+        for range in remnant_ranges {
+            for chunk in partition[range].chunks_mut(WIPE_CHUNK_LEN) {
+                chunk.fill(0);
+                if let Some(rate_limiter) = &rate_limiter {
+                    rate_limiter.consume(chunk.len());
+                }
+            }
+        }
+    }
+    ext4::probe(partition)
+        .context("blkid would not identify the converted filesystem as ext4")
+        .context(ErrorCategory::ConversionFailed)?;
+    stats.crtime_mappings = CrtimeMapping::read_back(partition, crtime_sources.take())
+        .context("The creation times were not preserved")
+        .context(ErrorCategory::ConversionFailed)?;
+    if options.spot_check.is_some() {
+        let report = SpotCheckReport::read_back(partition, &spot_check_samples, &spot_check_inodes.take())
+            .context("The spot check found damaged files")
+            .context(ErrorCategory::ConversionFailed)?;
+        stats.spot_check = Some(report);
+    }
+    start_phase(progress.as_deref(), Phase::Done);
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io;
+    use std::process::Command;
+    use std::time::Duration;
+
+    use itertools::Itertools;
+    use rand::rngs::ThreadRng;
+    use rand::Rng;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::conversion::{check_ext4_signature, convert_path, forbidden_ranges, is_covered};
+    use crate::diff_meta::read_inode;
+    use crate::ext4::{
+        Owner, DEFAULT_INODE_RATIO, FIRST_BLOCK_PADDING, LOST_FOUND_INODE_NO, MAX_INODE_RATIO, MIN_INODE_RATIO,
+        ROOT_INODE_NO,
+    };
+    use crate::fat::{find_backup_boot_sector, FatImage, FatImageBuilder, TestFile};
+    use crate::lohi::LoHi;
+    use crate::options::{MkfsTime, UuidSource};
+    use crate::profile::{Ext4Params, Profile};
+    use crate::serialization::tests::{shortcut_bytes, TEST_VOLUME_ID};
+    use crate::serialization::{DentryOrder, FileFilter};
+    use crate::skeleton::Skeleton;
+    use crate::trace::TraceEvent;
+    use crate::util::tests::Mount;
+    use crate::util::FromUsize;
+
+    const KIB: usize = 1024;
+    const MIB: usize = 1024 * KIB;
+
+    #[test]
+    fn conversion_never_exceeds_dry_run() {
+        let mut rng = rand::thread_rng();
+        let large_file = |size| TestFile::RegularFile { name: "large file".to_string(), size };
+        let corpus = vec![
+            ("empty", FatImageBuilder::new(32 * MIB, KIB).build(&[]), false),
+            (
+                "random 1K",
+                FatImageBuilder::new(32 * MIB, KIB).build(&random_tree(&mut rng, 3)),
+                false,
+            ),
+            (
+                "random 4K",
+                FatImageBuilder::new(32 * MIB, 4 * KIB).build(&random_tree(&mut rng, 3)),
+                false,
+            ),
+            (
+                "fragmented 2K",
+                FatImageBuilder::new(32 * MIB, 2 * KIB)
+                    .fragmented()
+                    .build(&random_tree(&mut rng, 3)),
+                false,
+            ),
+            (
+                // thousands of extents, requiring a deep extent tree
+                "fragmented large file",
+                FatImageBuilder::new(32 * MIB, KIB)
+                    .fragmented()
+                    .build(&[large_file(8 * MIB as u32), wide_directory(600)]),
+                false,
+            ),
+            (
+                // spans several block groups, so parts of it have to be relocated
+                "relocated large file",
+                FatImageBuilder::new(32 * MIB, KIB).build(&[large_file(24 * MIB as u32)]),
+                false,
+            ),
+            (
+                "random 16K",
+                FatImageBuilder::new(64 * MIB, 16 * KIB).build(&random_tree(&mut rng, 2)),
+                false,
+            ),
+            (
+                "bigalloc",
+                FatImageBuilder::new(64 * MIB, 16 * KIB).build(&random_tree(&mut rng, 2)),
+                true,
+            ),
+            (
+                "fragmented bigalloc",
+                FatImageBuilder::new(64 * MIB, 32 * KIB)
+                    .fragmented()
+                    .build(&[large_file(16 * MIB as u32), wide_directory(2000)]),
+                true,
+            ),
+        ];
+
+        for (description, image, bigalloc) in corpus {
+            let stats = convert_image(image, bigalloc)
+                .unwrap_or_else(|e| panic!("Converting the image '{}' failed: {:#}", description, e));
+            let (predicted, actual) = (stats.predicted_usage, stats.actual_usage);
+            assert!(
+                actual.inodes <= predicted.inodes && actual.clusters <= predicted.clusters,
+                "The conversion of the image '{}' used {:?}, but the dry run predicted {:?}",
+                description,
+                actual,
+                predicted
+            );
+        }
+    }
+
+    #[test]
+    fn misaligned_slice_is_rejected() {
+        let mut image = FatImageBuilder::new(32 * MIB, KIB).build(&[]);
+        let original = image.as_mut_slice().to_vec();
+        let error = convert_slice(&mut image.as_mut_slice()[1..], &ConversionOptions::default())
+            .err()
+            .unwrap();
+        assert_eq!(exit_code(&error), ErrorCategory::InvalidFilesystem.exit_code());
+        assert_eq!(image.as_mut_slice(), original.as_slice());
+    }
+
+    #[test]
+    fn root_dot_entries_are_not_converted() {
+        let files = [
+            TestFile::RegularFile { name: "file".to_string(), size: KIB as u32 },
+            TestFile::Directory {
+                name: "dir".to_string(),
+                children: random_tree(&mut rand::thread_rng(), 1),
+            },
+        ];
+        let stats = convert_image(FatImageBuilder::new(32 * MIB, KIB).root_dot_entries().build(&files), false).unwrap();
+        let expected = convert_image(FatImageBuilder::new(32 * MIB, KIB).build(&files), false).unwrap();
+        assert_eq!(stats.fs_stats.regular_file_count, expected.fs_stats.regular_file_count);
+        assert_eq!(stats.fs_stats.directory_count, expected.fs_stats.directory_count);
+    }
+
+    #[test]
+    fn allocator_stats_count_relocated_clusters() {
+        let small_file = TestFile::RegularFile { name: "small file".to_string(), size: KIB as u32 };
+        let stats = convert_image(FatImageBuilder::new(32 * MIB, KIB).build(&[small_file]), false).unwrap();
+        assert_eq!(stats.allocator_stats.relocation, 0);
+
+        // the file covers the metadata of the second block group
+        let large_file = TestFile::RegularFile {
+            name: "large file".to_string(),
+            size: 24 * MIB as u32,
+        };
+        let stats = convert_image(FatImageBuilder::new(32 * MIB, KIB).build(&[large_file]), false).unwrap();
+        let allocator_stats = stats.allocator_stats;
+        assert!(allocator_stats.relocation > 0);
+        assert!(allocator_stats.archive > 0);
+        assert_eq!(allocator_stats.metadata + allocator_stats.dentries, stats.actual_usage.clusters);
+        assert!(allocator_stats.high_water_mark as usize <= 32 * MIB / KIB);
+    }
+
+    #[test]
+    fn fs_stats_count_converted_files() {
+        let files = [
+            TestFile::Directory {
+                name: "outer".to_string(),
+                children: vec![
+                    TestFile::RegularFile { name: "a".to_string(), size: KIB as u32 },
+                    TestFile::Directory {
+                        name: "inner".to_string(),
+                        children: vec![
+                            TestFile::RegularFile { name: "b".to_string(), size: 0 },
+                            TestFile::RegularFile { name: "c".to_string(), size: 3 * KIB as u32 },
+                        ],
+                    },
+                ],
+            },
+            TestFile::RegularFile { name: "d".to_string(), size: 5 * KIB as u32 },
+        ];
+        let empty_stats = convert_image(FatImageBuilder::new(32 * MIB, KIB).build(&[]), false).unwrap();
+        let stats = convert_image(FatImageBuilder::new(32 * MIB, KIB).build(&files), false).unwrap();
+
+        assert_eq!(empty_stats.fs_stats.regular_file_count, 0);
+        assert_eq!(empty_stats.fs_stats.directory_count, 0);
+        assert_eq!(stats.fs_stats.regular_file_count, 4);
+        assert_eq!(stats.fs_stats.directory_count, 2);
+        assert_eq!(empty_stats.fs_stats.free_inode_count - stats.fs_stats.free_inode_count, 6);
+        assert!(stats.fs_stats.free_block_count < empty_stats.fs_stats.free_block_count);
+    }
+
+    #[test]
+    fn converts_shortcuts_to_symlinks() {
+        let shortcut = shortcut_bytes(TEST_VOLUME_ID, "..\\target.txt", true);
+        let files = [
+            TestFile::RegularFile { name: "target.txt".to_string(), size: KIB as u32 },
+            TestFile::Directory {
+                name: "dir".to_string(),
+                children: vec![TestFile::RegularFileWithContent { name: "target.lnk".to_string(), content: shortcut }],
+            },
+        ];
+        let mut image = FatImageBuilder::new(32 * MIB, KIB).build(&files);
+        // SAFETY: Safe because `image` contains a consistent FAT32 filesystem and outlives the conversion.
+        let stats = unsafe {
+            convert(
+                image.as_mut_ptr(),
+                image.len(),
+                PhantomData,
+                &ConversionOptions {
+                    convert_shortcuts: true,
+                    verify_archival: true,
+                    ..ConversionOptions::default()
+                },
+            )
+        }
+        .unwrap();
+        assert_eq!(stats.fs_stats.regular_file_count, 2);
+        assert_eq!(stats.predicted_usage, stats.actual_usage);
+
+        // the inodes are numbered in the order of the archive: target.txt, dir, target.lnk
+        let symlink = read_inode(image.as_mut_slice(), LOST_FOUND_INODE_NO + 3).unwrap();
+        let target = b"../target.txt";
+        assert_eq!(symlink.i_mode & 0o170_000, 0o120_000);
+        assert_eq!(symlink.size(), u64::fromx(target.len()));
+        // SAFETY: Safe because `extents`, which holds the target of a fast symlink, consists only of integers.
+        let i_block = unsafe {
+            std::slice::from_raw_parts(symlink.extents.as_ptr() as *const u8, std::mem::size_of_val(&symlink.extents))
+        };
+        assert_eq!(&i_block[..target.len()], target);
+        assert!(i_block[target.len()..].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn long_names_are_rejected_or_truncated() {
+        // 90 UCS-2 characters, 270 bytes in UTF-8
+        let long_name = "長".repeat(90);
+        let files = [TestFile::Directory {
+            name: "dir".to_string(),
+            children: vec![TestFile::RegularFile { name: long_name.clone(), size: KIB as u32 }],
+        }];
+
+        let mut image = FatImageBuilder::new(32 * MIB, KIB).build(&files);
+        let error = convert_slice(image.as_mut_slice(), &ConversionOptions::default())
+            .err()
+            .unwrap();
+        assert!(format!("{:#}", error).contains(&format!("/dir/{} (90 characters, 270 bytes in UTF-8)", long_name)));
+
+        let mut image = FatImageBuilder::new(32 * MIB, KIB).build(&files);
+        let options = ConversionOptions {
+            truncate_long_names: true,
+            ..ConversionOptions::default()
+        };
+        let stats = convert_slice(image.as_mut_slice(), &options).unwrap();
+        assert_eq!(stats.fs_stats.regular_file_count, 1);
+        // reported to the caller instead of printed
+        assert_eq!(stats.messages.len(), 1);
+        assert!(stats.messages[0].starts_with(&format!("Warning: Truncated the name of /dir/{} (", long_name)));
+    }
+
+    #[test]
+    fn erases_fat_signatures() {
+        let mut image = FatImageBuilder::new(32 * MIB, 4 * KIB).build(&[]);
+        let signature_ranges = BootSector::from_bytes(image.as_mut_slice()).unwrap().signature_ranges();
+        assert_eq!(signature_ranges.len(), 10);
+        // SAFETY: Safe because `image` contains a consistent FAT32 filesystem and outlives the conversion.
+        unsafe {
+            convert(image.as_mut_ptr(), image.len(), PhantomData, &ConversionOptions::default()).unwrap();
+        }
+
+        let partition = image.as_mut_slice();
+        // with 4 KiB blocks, all signatures lie in the unused parts of the first block
+        for range in signature_ranges {
+            assert!(partition[range].iter().all(|&byte| byte == 0));
+        }
+        assert!(BootSector::from_bytes(partition).is_err());
+        assert_eq!(find_backup_boot_sector(partition), None);
+    }
+
+    #[test]
+    fn wipe_fat_remnants_spares_ext4_metadata() {
+        let files = [TestFile::RegularFile { name: "file".to_string(), size: 4 * KIB as u32 }];
+        for cluster_size in [KIB, 4 * KIB] {
+            let mut image = FatImageBuilder::new(32 * MIB, cluster_size).build(&files);
+            let boot_sector = BootSector::from_bytes(image.as_mut_slice()).unwrap();
+            let superblock = SuperBlock::from(boot_sector, false, DEFAULT_INODE_RATIO, 0, false).unwrap();
+            let fat_metadata_len = boot_sector.get_data_range().start;
+            // SAFETY: Safe because `image` contains a consistent FAT32 filesystem and outlives the conversion.
+            unsafe {
+                convert(
+                    image.as_mut_ptr(),
+                    image.len(),
+                    PhantomData,
+                    &ConversionOptions {
+                        wipe_fat_remnants: true,
+                        ..ConversionOptions::default()
+                    },
+                )
+                .unwrap();
+            }
+
+            let block_size = usize::fromx(superblock.block_size());
+            let superblock_range = FIRST_BLOCK_PADDING..FIRST_BLOCK_PADDING + size_of::<SuperBlock>();
+            let overhead_ranges = superblock.block_group_overhead_ranges();
+            let partition = image.as_mut_slice();
+            for range in fat_remnant_ranges(&superblock, fat_metadata_len) {
+                assert!(range.end <= fat_metadata_len);
+                assert!(range.end <= superblock_range.start || range.start >= superblock_range.end);
+                if range.start >= block_size {
+                    let blocks = range.start / block_size..range.end / block_size;
+                    assert!(overhead_ranges
+                        .split_overlapping(blocks)
+                        .iter()
+                        .all(|(_, is_overhead)| !is_overhead));
+                }
+                assert!(partition[range].iter().all(|&byte| byte == 0));
+            }
+            assert!(BootSector::from_bytes(partition).is_err());
+            // SAFETY: Safe because the image is 8-aligned and contains the superblock at `superblock_range`.
+            let written_superblock = unsafe { &*(partition[superblock_range].as_ptr() as *const SuperBlock) };
+            assert_eq!(written_superblock.s_magic, superblock.s_magic);
+            assert_eq!(written_superblock.s_blocks_count_lo, superblock.s_blocks_count_lo);
+        }
+    }
+
+    #[test]
+    fn declined_trial_run_leaves_partition_unchanged() {
+        let files = [TestFile::RegularFile { name: "file".to_string(), size: 3 * MIB as u32 }];
+        let mut image = FatImageBuilder::new(32 * MIB, KIB).build(&files);
+        let image_file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(image_file.path(), image.as_mut_slice()).unwrap();
+
+        // not being interactive, the user declines the conversion after the trial
+        let options = ConversionOptions {
+            trial_run: true,
+            force: true,
+            ..ConversionOptions::default()
+        };
+        let error = convert_path(image_file.path().to_str().unwrap(), &options).err().unwrap();
+        assert_eq!(exit_code(&error), ErrorCategory::Aborted.exit_code());
+        assert!(std::fs::read(image_file.path()).unwrap() == image.as_mut_slice());
+    }
+
+    #[test]
+    fn stopped_conversion_can_be_continued() {
+        let files = [
+            TestFile::RegularFile {
+                name: "relocated".to_string(),
+                size: 24 * MIB as u32,
+            },
+            TestFile::Directory {
+                name: "dir".to_string(),
+                children: vec![TestFile::RegularFile { name: "file".to_string(), size: 5000 }],
+            },
+        ];
+        let options = ConversionOptions {
+            mkfs_time: MkfsTime::Unix(1_000_000_000),
+            uuid: UuidSource::FromFatSerial,
+            ..ConversionOptions::default()
+        };
+        let mut converted = FatImageBuilder::new(32 * MIB, KIB).build(&files);
+        // SAFETY: Safe because `converted` contains a consistent FAT32 filesystem and outlives the conversion.
+        let expected = unsafe { convert(converted.as_mut_ptr(), converted.len(), PhantomData, &options) }.unwrap();
+
+        let mut image = FatImageBuilder::new(32 * MIB, KIB).build(&files);
+        // SAFETY: Safe because `image` contains a consistent FAT32 filesystem and outlives the conversion.
+        let stopped =
+            unsafe { run_conversion(image.as_mut_ptr(), image.len(), PhantomData, &options, StopPoint::AfterPlan) };
+        let state = match stopped.unwrap() {
+            Conversion::Stopped(state) => state,
+            _ => panic!("The conversion did not stop after the dry run"),
+        };
+        assert!(state.archive.allocator_stats.relocation > 0);
+        // SAFETY: Safe because the FAT filesystem in `image` has not been modified since the conversion stopped.
+        let stats =
+            unsafe { resume_conversion(image.as_mut_ptr(), image.len(), PhantomData, &options, &state) }.unwrap();
+        // the inode timestamps of lost+found and the root directory depend on the time of the conversion, so the
+        // images are not compared byte by byte
+        assert_eq!(stats.allocator_stats, expected.allocator_stats);
+        assert_eq!(stats.fs_stats, expected.fs_stats);
+    }
+
+    #[test]
+    fn dry_run_predicts_the_conversion() {
+        let files = [TestFile::RegularFile { name: "file".to_string(), size: 5000 }, wide_directory(200)];
+        let options = ConversionOptions::default();
+        let mut image = FatImageBuilder::new(32 * MIB, KIB).build(&files);
+        // SAFETY: Safe because `image` contains a consistent FAT32 filesystem and outlives the conversion.
+        let dry_run =
+            unsafe { run_conversion(image.as_mut_ptr(), image.len(), PhantomData, &options, StopPoint::AfterDryRun) };
+        let report = match dry_run.unwrap() {
+            Conversion::DryRun(report) => report,
+            _ => panic!("The conversion did not stop after the dry run"),
+        };
+        let stats = convert_image(FatImageBuilder::new(32 * MIB, KIB).build(&files), false).unwrap();
+        assert_eq!(report.file_count, 202);
+        assert_eq!(report.prediction.usage, stats.actual_usage);
+        assert_eq!(report.prediction.data_cluster_count, 5);
+        assert_eq!(
+            u64::fromx(report.prediction.free_cluster_count),
+            stats.fs_stats.free_block_count
+        );
+
+        let long_name = TestFile::RegularFile { name: "長".repeat(90), size: 10 };
+        let mut image = FatImageBuilder::new(32 * MIB, KIB).build(&[long_name]);
+        // SAFETY: Safe because `image` contains a consistent FAT32 filesystem and outlives the conversion.
+        let dry_run =
+            unsafe { run_conversion(image.as_mut_ptr(), image.len(), PhantomData, &options, StopPoint::AfterDryRun) };
+        assert!(dry_run.is_err());
+    }
+
+    #[test]
+    fn checksums_the_fat_metadata_before_overwriting_it() {
+        let files = [TestFile::RegularFile { name: "file".to_string(), size: 5000 }];
+        let mut image = FatImageBuilder::new(32 * MIB, KIB).build(&files);
+        let original = FatMetadataChecksums::compute(image.as_mut_slice()).unwrap();
+        let options = ConversionOptions {
+            checksum_fat_metadata: true,
+            ..ConversionOptions::default()
+        };
+        // SAFETY: Safe because `image` contains a consistent FAT32 filesystem and outlives the conversion.
+        let stats = unsafe { convert(image.as_mut_ptr(), image.len(), PhantomData, &options) }.unwrap();
+        assert_eq!(original.fat_tables.len(), 2);
+        assert_eq!(original.fat_tables[0], original.fat_tables[1]);
+        assert_eq!(stats.fat_metadata_checksums, Some(original.clone()));
+        let summary = stats.summary(Duration::ZERO);
+        assert_eq!(summary.fat_metadata_sha256, Some(original));
+        // the converted partition no longer contains a boot sector to checksum
+        assert!(FatMetadataChecksums::compute(image.as_mut_slice()).is_err());
+    }
+
+    #[test]
+    fn modified_filesystem_cannot_be_continued() {
+        let files = [TestFile::RegularFile { name: "file".to_string(), size: 5000 }];
+        let mut image = FatImageBuilder::new(32 * MIB, KIB).build(&files);
+        let options = ConversionOptions::default();
+        // SAFETY: Safe because `image` contains a consistent FAT32 filesystem and outlives the conversion.
+        let stopped =
+            unsafe { run_conversion(image.as_mut_ptr(), image.len(), PhantomData, &options, StopPoint::AfterPlan) };
+        let state = match stopped.unwrap() {
+            Conversion::Stopped(state) => state,
+            _ => panic!("The conversion did not stop after the dry run"),
+        };
+
+        let renamed = [TestFile::RegularFile { name: "renamed".to_string(), size: 5000 }];
+        let mut modified = FatImageBuilder::new(32 * MIB, KIB).build(&renamed);
+        let original = modified.as_mut_slice().to_vec();
+        // SAFETY: Safe because `modified` contains a consistent FAT32 filesystem and outlives the conversion, which
+        // fails before accessing its free clusters.
+        let error = unsafe { resume_conversion(modified.as_mut_ptr(), modified.len(), PhantomData, &options, &state) }
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("has been modified"), "{:#}", error);
+        assert!(modified.as_mut_slice() == original);
+    }
+
+    #[test]
+    #[ignore] // requires sudo
+    fn mounting_does_not_rewrite_superblock() {
+        let mut rng = rand::thread_rng();
+        let mut image = FatImageBuilder::new(32 * MIB, KIB).build(&random_tree(&mut rng, 3));
+        convert_slice(image.as_mut_slice(), &ConversionOptions::default()).unwrap();
+        let image_file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(image_file.path(), image.as_mut_slice()).unwrap();
+
+        let converted = mount_independent_superblock(image_file.path());
+        // mount twice, in case the first mount leaves anything for the second one to fix
+        for _ in 0..2 {
+            let mount_dir = tempfile::tempdir().unwrap();
+            let _umount_on_drop = Mount::new(image_file.path(), mount_dir.path()).unwrap();
+        }
+        let remounted = mount_independent_superblock(image_file.path());
+        assert_eq!(converted, remounted);
+
+        let fsck_status = Command::new("fsck.ext4").arg("-fn").arg(image_file.path()).status().unwrap();
+        assert!(fsck_status.success());
+    }
+
+    /// Returns the bytes of the superblock at `path` without the fields that every mount updates.
+    fn mount_independent_superblock(path: &std::path::Path) -> Vec<u8> {
+        let mut superblock = read_superblock(&std::fs::read(path).unwrap());
+        superblock.s_mtime = 0;
+        superblock.s_wtime = 0;
+        superblock.s_mnt_count = 0;
+        superblock.s_kbytes_written = 0;
+        superblock.s_last_mounted = [0; 64];
+        // SAFETY: Safe because `superblock` consists only of integers.
+        let superblock_bytes = unsafe {
+            std::slice::from_raw_parts(&superblock as *const SuperBlock as *const u8, size_of::<SuperBlock>())
+        };
+        superblock_bytes.to_vec()
+    }
+
+    fn read_superblock(partition: &[u8]) -> SuperBlock {
+        let superblock_bytes = &partition[FIRST_BLOCK_PADDING..FIRST_BLOCK_PADDING + size_of::<SuperBlock>()];
+        // SAFETY: Safe because `superblock_bytes` is as long as a `SuperBlock`, which consists only of integers.
+        unsafe { (superblock_bytes.as_ptr() as *const SuperBlock).read_unaligned() }
+    }
+
+    #[test]
+    fn converted_partition_is_not_converted_again() {
+        let mut image = FatImageBuilder::new(32 * MIB, KIB).build(&[]);
+        check_ext4_signature(image.as_mut_slice(), false).unwrap();
+        convert_slice(image.as_mut_slice(), &ConversionOptions::default()).unwrap();
+        let error = check_ext4_signature(image.as_mut_slice(), false).unwrap_err();
+        assert_eq!(exit_code(&error), ErrorCategory::InvalidFilesystem.exit_code());
+        check_ext4_signature(image.as_mut_slice(), true).unwrap();
+    }
+
+    #[test]
+    fn mkfs_time_can_be_chosen() {
+        let convert_with = |mkfs_time| {
+            let mut image = FatImageBuilder::new(32 * MIB, KIB).build(&[]);
+            let options = ConversionOptions { mkfs_time, ..ConversionOptions::default() };
+            convert_slice(image.as_mut_slice(), &options).map(|_| {
+                let superblock = read_superblock(image.as_mut_slice());
+                (superblock.s_mkfs_time, superblock.s_wtime)
+            })
+        };
+
+        assert_eq!(
+            convert_with(MkfsTime::Unix(1_000_000_000)).unwrap(),
+            (1_000_000_000, 1_000_000_000)
+        );
+        assert!(convert_with(MkfsTime::Unix(u32::MAX)).is_err());
+        // the test images have no volume label dentry
+        let message = format!("{:#}", convert_with(MkfsTime::FromFat).unwrap_err());
+        assert!(message.contains("no volume label"));
+        assert_eq!(MkfsTime::parse("from-fat").unwrap(), MkfsTime::FromFat);
+        assert_eq!(MkfsTime::parse("1234").unwrap(), MkfsTime::Unix(1234));
+        assert!(MkfsTime::parse("yesterday").is_err());
+    }
+
+    #[test]
+    fn uuid_can_be_derived_from_fat_serial() {
+        let convert_with = |uuid| {
+            let mut image = FatImageBuilder::new(32 * MIB, KIB).build(&[]);
+            let options = ConversionOptions { uuid, ..ConversionOptions::default() };
+            convert_slice(image.as_mut_slice(), &options).unwrap();
+            Uuid::from_bytes(read_superblock(image.as_mut_slice()).s_uuid)
+        };
+
+        // the test images have the serial number 1234-5678
+        let derived = convert_with(UuidSource::FromFatSerial);
+        assert_eq!(derived.to_string(), "12345678-0000-8000-8000-000000000000");
+        assert_eq!(derived, convert_with(UuidSource::FromFatSerial));
+        assert_ne!(convert_with(UuidSource::Random), convert_with(UuidSource::Random));
+    }
+
+    #[test]
+    fn ext4_params_are_applied() {
+        let convert_with = |ext4_params| {
+            let mut image = FatImageBuilder::new(32 * MIB, KIB).build(&[]);
+            let options = ConversionOptions { ext4_params, ..ConversionOptions::default() };
+            convert_slice(image.as_mut_slice(), &options).unwrap();
+            read_superblock(image.as_mut_slice())
+        };
+
+        let default = convert_with(Ext4Params::default());
+        assert_eq!(default.s_r_blocks_count_lo, 0);
+        let archive = convert_with(Ext4Params {
+            reserved_percent: 5,
+            ..Profile::Archive.ext4_params()
+        });
+        assert_eq!(archive.s_inodes_count, default.s_inodes_count / 4);
+        assert_eq!(archive.s_r_blocks_count_lo, archive.s_blocks_count_lo / 20);
+    }
+
+    #[test]
+    fn inode_ratio_is_lowered_to_fit_files() {
+        let files: Vec<_> = (0..100)
+            .map(|idx| TestFile::RegularFile { name: idx.to_string(), size: 0 })
+            .collect();
+        let mut image = FatImageBuilder::new(32 * MIB, KIB).build(&files);
+        let options = ConversionOptions {
+            ext4_params: Ext4Params {
+                inode_ratio: MAX_INODE_RATIO,
+                ..Ext4Params::default()
+            },
+            ..ConversionOptions::default()
+        };
+        let stats = convert_slice(image.as_mut_slice(), &options).unwrap();
+        assert_eq!(stats.fs_stats.regular_file_count, 100);
+        let superblock = read_superblock(image.as_mut_slice());
+        // the inode count is rounded up to whole inode table blocks in every block group
+        assert!(superblock.allocatable_inode_count() >= 101);
+        assert!(superblock.allocatable_inode_count() < 2 * 101 + 4 * 8);
+    }
+
+    #[test]
+    fn directories_in_ext4_metadata_are_converted() {
+        let files = [
+            wide_directory(600),
+            TestFile::Directory {
+                name: "outer".to_string(),
+                children: vec![TestFile::Directory {
+                    name: "inner".to_string(),
+                    children: vec![TestFile::RegularFile { name: "file".to_string(), size: 3 * KIB as u32 }],
+                }],
+            },
+        ];
+        let mut image = FatImageBuilder::new(32 * MIB, KIB).build(&files);
+        {
+            // SAFETY: Safe because `image` contains a consistent FAT32 filesystem and outlives `fat_fs`.
+            let fat_fs = unsafe { FatFs::new(image.as_mut_ptr(), image.len(), PhantomData) }.unwrap();
+            let superblock = SuperBlock::from(fat_fs.boot_sector(), false, MIN_INODE_RATIO, 0, false).unwrap();
+            // the inode tables of the first block group cover all directories, including the root directory
+            assert!(is_covered(
+                &fat_fs.directory_ranges(),
+                &forbidden_ranges(&superblock, fat_fs.cluster_count())
+            ));
+        }
+
+        let options = ConversionOptions {
+            ext4_params: Ext4Params {
+                inode_ratio: MIN_INODE_RATIO,
+                ..Ext4Params::default()
+            },
+            verify_archival: true,
+            ..ConversionOptions::default()
+        };
+        let stats = convert_slice(image.as_mut_slice(), &options).unwrap();
+        assert_eq!(stats.fs_stats.directory_count, 3);
+        assert_eq!(stats.fs_stats.regular_file_count, 601);
+        // only the file's data is relocated, the directories are rebuilt from the serialized tree
+        assert_eq!(stats.allocator_stats.relocation, 3);
+    }
+
+    #[test]
+    fn superblock_only_counts_serialized_files() {
+        let files: Vec<_> = (0..100)
+            .map(|idx| TestFile::RegularFile { name: idx.to_string(), size: 2 * KIB as u32 })
+            .collect();
+        let convert_with = |filter| {
+            let mut image = FatImageBuilder::new(32 * MIB, KIB).build(&files);
+            let options = ConversionOptions {
+                ext4_params: Ext4Params {
+                    inode_ratio: MAX_INODE_RATIO,
+                    ..Ext4Params::default()
+                },
+                filter,
+                ..ConversionOptions::default()
+            };
+            convert_slice(image.as_mut_slice(), &options).unwrap();
+            read_superblock(image.as_mut_slice())
+        };
+
+        let all_files = convert_with(FileFilter::default());
+        let no_files = convert_with(FileFilter { max_size: Some(KIB as u64), min_mod_time: None });
+        assert!(all_files.allocatable_inode_count() >= 101);
+        // without files, the inode ratio does not need to be lowered
+        assert!(no_files.s_inodes_count < all_files.s_inodes_count);
+        assert!(no_files.allocatable_inode_count() >= 1);
+    }
+
+    #[test]
+    fn excluded_files_are_not_relocated() {
+        // the large file covers the metadata of the second block group
+        let files = [
+            TestFile::RegularFile { name: "small file".to_string(), size: KIB as u32 },
+            TestFile::RegularFile {
+                name: "large file".to_string(),
+                size: 24 * MIB as u32,
+            },
+        ];
+        let mut image = FatImageBuilder::new(32 * MIB, KIB).build(&files);
+        let filter = FileFilter {
+            max_size: Some(MIB as u64),
+            ..FileFilter::default()
+        };
+        // SAFETY: Safe because `image` contains a consistent FAT32 filesystem and outlives the conversion.
+        let stats = unsafe {
+            convert(
+                image.as_mut_ptr(),
+                image.len(),
+                PhantomData,
+                &ConversionOptions { filter, ..ConversionOptions::default() },
+            )
+        }
+        .unwrap();
+        assert_eq!(stats.allocator_stats.relocation, 0);
+        assert_eq!(stats.actual_usage.inodes, 2); // lost+found and the small file
+    }
+
+    #[test]
+    fn full_filesystem_is_insufficient_space() {
+        // leaves fewer free clusters than the ext4 metadata requires
+        let files = (0..32)
+            .map(|idx| TestFile::RegularFile {
+                name: idx.to_string(),
+                size: if idx == 0 { 600 * KIB } else { MIB } as u32,
+            })
+            .collect_vec();
+        let error = convert_image(FatImageBuilder::new(32 * MIB, KIB).build(&files), false)
+            .err()
+            .expect("The filesystem has no space for the ext4 metadata");
+        assert_eq!(exit_code(&error), ErrorCategory::InsufficientSpace.exit_code());
+        // fails before the serialization, since the file data in the way of the metadata cannot be relocated
+        assert!(format!("{:#}", error).contains("relocate"));
+    }
+
+    #[test]
+    fn tight_fit_omits_the_last_backup() {
+        let convert_with_filler = |cluster_count: usize, allow_tight_fit: bool| {
+            let filler = TestFile::RegularFile {
+                name: "filler".to_string(),
+                size: (cluster_count * KIB) as u32,
+            };
+            let mut image = FatImageBuilder::new(32 * MIB, KIB).build(&[filler]);
+            let options = ConversionOptions { allow_tight_fit, ..ConversionOptions::default() };
+            convert_slice(image.as_mut_slice(), &options).map(|_| read_superblock(image.as_mut_slice()))
+        };
+
+        // find the largest filler with which the conversion fits without a tight fit
+        let mut fits = 0;
+        // leaves only a few free clusters
+        let mut does_not_fit = (32 * MIB - 300 * KIB) / KIB;
+        let error = convert_with_filler(does_not_fit, false).err().unwrap();
+        assert_eq!(exit_code(&error), ErrorCategory::InsufficientSpace.exit_code());
+        while does_not_fit - fits > 1 {
+            let cluster_count = (fits + does_not_fit) / 2;
+            if convert_with_filler(cluster_count, false).is_ok() {
+                fits = cluster_count;
+            } else {
+                does_not_fit = cluster_count;
+            }
+        }
+
+        assert_eq!(convert_with_filler(fits, true).unwrap().s_backup_bgs, [1, 3]);
+        assert!(convert_with_filler(does_not_fit, false).is_err());
+        assert_eq!(convert_with_filler(does_not_fit, true).unwrap().s_backup_bgs, [1, 0]);
+    }
+
+    #[test]
+    fn small_clusters_with_bigalloc_are_unsupported_geometry() {
+        let error = convert_image(FatImageBuilder::new(32 * MIB, 4 * KIB).build(&[]), true)
+            .err()
+            .expect("bigalloc requires clusters larger than 4 KiB");
+        assert_eq!(exit_code(&error), ErrorCategory::UnsupportedGeometry.exit_code());
+    }
+
+    #[test]
+    fn min_free_space_after_is_checked_before_modifying() {
+        let files = [TestFile::RegularFile { name: "file".to_string(), size: 12 * MIB as u32 }, wide_directory(300)];
+        let build = || FatImageBuilder::new(32 * MIB, KIB).build(&files);
+        let convert_with = |image: &mut FatImage, min_free_space_after| {
+            let options = ConversionOptions {
+                min_free_space_after,
+                ..ConversionOptions::default()
+            };
+            convert_slice(image.as_mut_slice(), &options)
+        };
+
+        let mut image = build();
+        let error = convert_with(&mut image, 100).err().expect("no file system is entirely free");
+        assert_eq!(exit_code(&error), ErrorCategory::InsufficientSpace.exit_code());
+        assert!(BootSector::from_bytes(image.as_mut_slice()).is_ok());
+        // the prediction is exact
+        let stats = convert_with(&mut build(), 0).unwrap();
+        let message = format!("{:#}", error);
+        assert!(
+            message.contains(&format!("Only {} of the", stats.fs_stats.free_block_count)),
+            "{}",
+            message
+        );
+
+        let free_percent: u8 = message.split(['(', '%']).nth(1).unwrap().parse().unwrap();
+        assert!(convert_with(&mut build(), free_percent + 1).is_err());
+        convert_with(&mut build(), free_percent).unwrap();
+    }
+
+    #[test]
+    fn trace_records_every_allocation() {
+        let files = [TestFile::Directory {
+            name: "dir".to_string(),
+            children: vec![TestFile::RegularFile { name: "file".to_string(), size: 5000 }],
+        }];
+        let trace_file = tempfile::NamedTempFile::new().unwrap();
+        let mut image = FatImageBuilder::new(32 * MIB, KIB).build(&files);
+        let options = ConversionOptions {
+            trace: Some(trace_file.path().to_str().unwrap().to_string()),
+            ..ConversionOptions::default()
+        };
+        let stats = convert_slice(image.as_mut_slice(), &options).unwrap();
+
+        let (events, error) = Trace::read(trace_file.reopen().unwrap()).unwrap();
+        assert!(error.is_none());
+        let allocated: u32 = events
+            .iter()
+            .map(|event| match *event {
+                TraceEvent::Allocation { start, end, .. } => end - start,
+                _ => 0,
+            })
+            .sum();
+        assert_eq!(usize::fromx(allocated), stats.allocator_stats.total());
+        // '.' and '..' in the root, lost+found and dir, plus lost+found, dir and file
+        let dentry_count = events.iter().filter(|event| matches!(event, TraceEvent::Dentry { .. })).count();
+        assert_eq!(dentry_count, 9);
+        assert!(events.iter().any(|event| matches!(event, TraceEvent::Extent { .. })));
+    }
+
+    #[test]
+    fn reports_json_progress() {
+        let files: Vec<_> = (0..10)
+            .map(|idx| TestFile::RegularFile { name: idx.to_string(), size: 5000 })
+            .collect();
+        let mut image = FatImageBuilder::new(32 * MIB, KIB).build(&files);
+        let (read_fd, write_fd) = nix::unistd::pipe().unwrap();
+        let options = ConversionOptions {
+            json_progress_fd: Some(write_fd),
+            ..ConversionOptions::default()
+        };
+        convert_slice(image.as_mut_slice(), &options).unwrap();
+        nix::unistd::close(write_fd).unwrap();
+
+        // SAFETY: Safe because `read_fd` is open and owned by nobody else.
+        let output = io::read_to_string(unsafe { <File as std::os::unix::io::FromRawFd>::from_raw_fd(read_fd) });
+        let events: Vec<serde_json::Value> = output
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let phases: Vec<_> = events.iter().map(|event| event["phase"].as_str().unwrap()).dedup().collect();
+        assert_eq!(phases, ["serialize", "dry-run", "write", "finalize", "done"]);
+        for phase in ["serialize", "write"] {
+            let last = events.iter().rfind(|event| event["phase"] == phase).unwrap();
+            assert_eq!((last["percent"].as_u64(), last["path"].as_str()), (Some(100), Some("/9")));
+        }
+    }
+
+    #[test]
+    fn owner_can_be_chosen() {
+        let files = [TestFile::RegularFile { name: "file".to_string(), size: 10 }];
+        let mut image = FatImageBuilder::new(32 * MIB, KIB).build(&files);
+        let owner = Owner { uid: 100_000, gid: 1000 };
+        let options = ConversionOptions { owner: Some(owner), ..ConversionOptions::default() };
+        convert_slice(image.as_mut_slice(), &options).unwrap();
+
+        let partition = image.as_mut_slice();
+        let owner_of = |inode_no| {
+            let inode = read_inode(partition, inode_no).unwrap();
+            Owner {
+                uid: LoHi::new(&inode.i_uid, &inode.l_i_uid_high).get(),
+                gid: LoHi::new(&inode.i_gid, &inode.l_i_gid_high).get(),
+            }
+        };
+        assert_eq!(owner_of(ROOT_INODE_NO), owner);
+        assert_eq!(owner_of(LOST_FOUND_INODE_NO), Owner::ROOT);
+        assert_eq!(owner_of(LOST_FOUND_INODE_NO + 1), owner);
+    }
+
+    #[test]
+    fn duplicates_become_hard_links() {
+        let files = [
+            TestFile::RegularFile { name: "zeros".to_string(), size: 5000 },
+            TestFile::Directory {
+                name: "dir".to_string(),
+                children: vec![TestFile::RegularFile { name: "zeros copy".to_string(), size: 5000 }],
+            },
+            TestFile::RegularFileWithContent { name: "ones".to_string(), content: vec![1; 5000] },
+        ];
+        let build = || FatImageBuilder::new(32 * MIB, KIB).build(&files);
+        let stats = convert_slice(build().as_mut_slice(), &ConversionOptions::default()).unwrap();
+        let mut image = build();
+        let options = ConversionOptions {
+            dedup_hardlink: true,
+            ..ConversionOptions::default()
+        };
+        let dedup_stats = convert_slice(image.as_mut_slice(), &options).unwrap();
+
+        assert_eq!(dedup_stats.fs_stats.regular_file_count, 2);
+        // the 5 clusters of the copy are free
+        assert_eq!(dedup_stats.fs_stats.free_block_count, stats.fs_stats.free_block_count + 5);
+        let zeros = read_inode(image.as_mut_slice(), LOST_FOUND_INODE_NO + 1).unwrap();
+        assert_eq!({ zeros.i_links_count }, 2);
+        let ones = read_inode(image.as_mut_slice(), LOST_FOUND_INODE_NO + 3).unwrap();
+        assert_eq!({ ones.i_links_count }, 1);
+        assert_eq!(ones.size(), 5000);
+    }
+
+    #[test]
+    fn skeleton_is_added_after_converted_files() {
+        let files = [
+            TestFile::Directory {
+                name: "data".to_string(),
+                children: vec![TestFile::RegularFile { name: "file".to_string(), size: 10 }],
+            },
+            TestFile::RegularFile { name: "notes".to_string(), size: 10 },
+        ];
+        let build = || FatImageBuilder::new(32 * MIB, KIB).build(&files);
+        let convert_with = |image: &mut FatImage, paths: &[&str]| {
+            let mut skeleton = Skeleton::default();
+            for path in paths {
+                skeleton.add(path, 0o700, Some(Owner { uid: 1000, gid: 1000 })).unwrap();
+            }
+            let options = ConversionOptions { skeleton, ..ConversionOptions::default() };
+            convert_slice(image.as_mut_slice(), &options)
+        };
+
+        let mut image = build();
+        let stats = convert_with(&mut image, &["/data/incoming", "/srv/www"]).unwrap();
+        // data, data/incoming, srv and srv/www
+        assert_eq!(stats.fs_stats.directory_count, 4);
+        // the skeleton's directories are added after the converted files of their parent
+        let partition = image.as_mut_slice();
+        let data = read_inode(partition, LOST_FOUND_INODE_NO + 1).unwrap();
+        assert_eq!({ data.i_links_count }, 3);
+        let incoming = read_inode(partition, LOST_FOUND_INODE_NO + 3).unwrap();
+        assert!(incoming.is_dir());
+        assert_eq!({ incoming.i_mode } & 0o7777, 0o700);
+        assert_eq!({ incoming.i_uid }, 1000);
+        let srv = read_inode(partition, LOST_FOUND_INODE_NO + 5).unwrap();
+        assert_eq!({ srv.i_mode } & 0o7777, 0o755);
+        // like the converted files
+        assert_eq!({ srv.i_uid }, { data.i_uid });
+        assert!(read_inode(partition, LOST_FOUND_INODE_NO + 6).unwrap().is_dir());
+
+        // the dry run finds the conflict before anything is modified
+        let mut image = build();
+        let error = convert_with(&mut image, &["/notes/drafts"]).err().expect("notes is a file");
+        assert!(format!("{:#}", error).contains("would replace a file"), "{:#}", error);
+        assert!(BootSector::from_bytes(image.as_mut_slice()).is_ok());
+    }
+
+    #[test]
+    fn access_time_can_be_dropped() {
+        let files = [TestFile::RegularFile { name: "file".to_string(), size: 10 }];
+        let mut image = FatImageBuilder::new(32 * MIB, KIB).build(&files);
+        let mut dropped_image = FatImageBuilder::new(32 * MIB, KIB).build(&files);
+        convert_slice(image.as_mut_slice(), &ConversionOptions::default()).unwrap();
+        let options = ConversionOptions { drop_atime: true, ..ConversionOptions::default() };
+        convert_slice(dropped_image.as_mut_slice(), &options).unwrap();
+
+        let inode = read_inode(image.as_mut_slice(), LOST_FOUND_INODE_NO + 1).unwrap();
+        assert_ne!({ inode.i_atime }, { inode.i_mtime });
+        let dropped_inode = read_inode(dropped_image.as_mut_slice(), LOST_FOUND_INODE_NO + 1).unwrap();
+        assert_eq!({ dropped_inode.i_atime }, { inode.i_mtime });
+    }
+
+    #[test]
+    fn dentries_can_be_sorted() {
+        let files: Vec<_> = ["b", "C", "a.txt", "a"]
+            .iter()
+            .map(|name| TestFile::RegularFile { name: name.to_string(), size: 10 })
+            .collect();
+        let root_names = |dentry_order| {
+            let trace_file = tempfile::NamedTempFile::new().unwrap();
+            let mut image = FatImageBuilder::new(32 * MIB, KIB).build(&files);
+            let options = ConversionOptions {
+                dentry_order,
+                trace: Some(trace_file.path().to_str().unwrap().to_string()),
+                ..ConversionOptions::default()
+            };
+            let stats = convert_slice(image.as_mut_slice(), &options).unwrap();
+            assert_eq!(stats.dentry_order, dentry_order);
+
+            let (events, _) = Trace::read(trace_file.reopen().unwrap()).unwrap();
+            let partition = image.as_mut_slice();
+            events
+                .into_iter()
+                .filter_map(|event| match event {
+                    TraceEvent::Dentry {
+                        directory_inode_no: ROOT_INODE_NO, cluster, offset, ..
+                    } => {
+                        let start = usize::fromx(cluster) * KIB + usize::fromx(offset);
+                        let name_len = usize::from(partition[start + 6]);
+                        Some(String::from_utf8(partition[start + 8..start + 8 + name_len].to_vec()).unwrap())
+                    }
+                    _ => None,
+                })
+                .filter(|name| ![".", "..", "lost+found"].contains(&name.as_str()))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(root_names(DentryOrder::Fat), ["b", "C", "a.txt", "a"]);
+        assert_eq!(root_names(DentryOrder::Sorted), ["C", "a", "a.txt", "b"]);
+    }
+
+    #[test]
+    fn crtimes_are_read_back() {
+        let files = [TestFile::Directory {
+            name: "dir".to_string(),
+            children: vec![TestFile::RegularFile { name: "photo.jpg".to_string(), size: 5000 }],
+        }];
+        let convert_with = |crtime_list: Option<String>| {
+            let mut image = FatImageBuilder::new(32 * MIB, KIB).build(&files);
+            let options = ConversionOptions { crtime_list, ..ConversionOptions::default() };
+            convert_slice(image.as_mut_slice(), &options).unwrap()
+        };
+
+        assert!(convert_with(None).crtime_mappings.is_empty());
+        let mappings = convert_with(Some("crtimes".to_string())).crtime_mappings;
+        let paths: Vec<_> = mappings.iter().map(|mapping| mapping.path.as_str()).collect();
+        assert_eq!(paths, ["/dir", "/dir/photo.jpg"]);
+        // 2020-01-01 00:00:01.5, see `FatImageBuilder`
+        let create_time = Timestamp { seconds: 1_577_836_801, nanoseconds: 500_000_000 };
+        for mapping in mappings {
+            assert_eq!(mapping.fat_create_time, create_time);
+            assert_eq!(mapping.ext4_crtime, create_time);
+        }
+    }
+
+    fn convert_image(mut image: FatImage, bigalloc: bool) -> Result<ConversionStats> {
+        // SAFETY: Safe because `image` contains a consistent FAT32 filesystem and outlives the conversion.
+        unsafe {
+            convert(
+                image.as_mut_ptr(),
+                image.len(),
+                PhantomData,
+                &ConversionOptions {
+                    bigalloc,
+                    verify_archival: true,
+                    ..ConversionOptions::default()
+                },
+            )
+        }
+    }
+
+    fn wide_directory(file_count: usize) -> TestFile {
+        let children = (0..file_count)
+            .map(|idx| TestFile::RegularFile {
+                name: format!("file with a long name that needs several entries {}", idx),
+                size: 0,
+            })
+            .collect();
+        TestFile::Directory { name: "wide directory".to_string(), children }
+    }
+
+    fn random_tree(rng: &mut ThreadRng, max_depth: usize) -> Vec<TestFile> {
+        (0..rng.gen_range(0..20))
+            .map(|idx| {
+                let name_len = rng.gen_range(1..100);
+                let name = format!("{}{}", idx, "x".repeat(name_len));
+                if max_depth > 0 && rng.gen_bool(0.2) {
+                    TestFile::Directory { name, children: random_tree(rng, max_depth - 1) }
+                } else {
+                    TestFile::RegularFile { name, size: rng.gen_range(0..32 * KIB as u32) }
+                }
+            })
+            .collect()
+    }
+}
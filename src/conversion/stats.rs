@@ -0,0 +1,227 @@
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::allocator::AllocatorStats;
+use crate::checkpoint::SerializationReport;
+use crate::crtime::CrtimeMapping;
+use crate::ext4::{Ext4FsStats, InodeCount};
+use crate::fat_checksums::FatMetadataChecksums;
+use crate::messages::{tr, trf};
+use crate::serialization::{ArchiveBitFile, DentryOrder, Prediction, ResourceUsage};
+use crate::spot_check::SpotCheckReport;
+use crate::util::FromUsize;
+
+/// The resources used by a conversion
+pub struct ConversionStats {
+    /// the inodes and clusters required by the directory tree according to the dry run
+    pub predicted_usage: ResourceUsage,
+    /// the inodes and clusters actually allocated for the directory tree
+    pub actual_usage: ResourceUsage,
+    pub allocator_stats: AllocatorStats,
+    /// the state of the ext4 filesystem after the conversion
+    pub fs_stats: Ext4FsStats,
+    pub cluster_size: u32,
+    /// the regular files whose cluster chains were shorter than their size
+    pub truncated_file_count: usize,
+    /// the files that could not be converted and were skipped because of `ConversionOptions::collect_errors`
+    pub skipped_file_count: usize,
+    pub dentry_order: DentryOrder,
+    /// the converted files with the FAT archive flag, if `ConversionOptions::archive_bit_list` is set
+    pub archive_bit_files: Vec<ArchiveBitFile>,
+    /// the FAT creation times and ext4 crtimes of the converted files, if `ConversionOptions::crtime_list` is set
+    pub crtime_mappings: Vec<CrtimeMapping>,
+    /// the outcome of comparing the sampled files, if `ConversionOptions::spot_check` is set
+    pub spot_check: Option<SpotCheckReport>,
+    /// the checksums of the original FAT metadata, if `ConversionOptions::checksum_fat_metadata` is set
+    pub fat_metadata_checksums: Option<FatMetadataChecksums>,
+    /// the warnings and notes about the conversion, see `SerializationReport::messages`
+    pub messages: Vec<String>,
+}
+
+/// The outcome of a successful conversion as reported to the user
+#[derive(Debug, Serialize)]
+pub struct ConversionSummary {
+    pub file_count: InodeCount,
+    /// excluding the root directory and lost+found
+    pub directory_count: u32,
+    pub seconds: f64,
+    /// the bytes of file data relocated to make room for ext4 metadata
+    pub moved_bytes: u64,
+    pub free_bytes: u64,
+    pub free_block_count: u64,
+    pub free_inode_count: InodeCount,
+    /// the files that were truncated because their cluster chains were shorter than their size
+    pub truncated_file_count: usize,
+    /// the files that could not be converted and were skipped
+    pub skipped_file_count: usize,
+    /// the order of the dentries in the ext4 directories
+    pub dentry_order: DentryOrder,
+    /// the outcome of `--spot-check`, if requested
+    pub spot_check: Option<SpotCheckReport>,
+    /// the SHA-256 checksums of the boot sector and the FATs before the conversion, if requested
+    pub fat_metadata_sha256: Option<FatMetadataChecksums>,
+}
+
+impl ConversionStats {
+    pub fn print(&self) {
+        let allocator_stats = &self.allocator_stats;
+        println!(
+            "Allocated {} clusters: {} for the serialized directory tree, {} for relocated file data, {} for extent \
+             trees and symlinks, {} for directories (highest allocated cluster: {})",
+            allocator_stats.total(),
+            allocator_stats.archive,
+            allocator_stats.relocation,
+            allocator_stats.metadata,
+            allocator_stats.dentries,
+            allocator_stats.high_water_mark.saturating_sub(1)
+        );
+        println!(
+            "The dry run predicted {} clusters and {} inodes, the conversion used {} clusters and {} inodes",
+            self.predicted_usage.clusters,
+            self.predicted_usage.inodes,
+            self.actual_usage.clusters,
+            self.actual_usage.inodes
+        );
+        match self.dentry_order {
+            DentryOrder::Fat => println!("Wrote the directory entries in the order of the FAT directories"),
+            DentryOrder::Sorted => println!("Wrote the directory entries sorted by name"),
+        }
+    }
+
+    pub fn summary(&self, elapsed: Duration) -> ConversionSummary {
+        let fs_stats = &self.fs_stats;
+        ConversionSummary {
+            file_count: fs_stats.regular_file_count,
+            directory_count: fs_stats.directory_count,
+            seconds: elapsed.as_secs_f64(),
+            moved_bytes: u64::fromx(self.allocator_stats.relocation) * u64::from(self.cluster_size),
+            free_bytes: fs_stats.free_block_count * u64::from(fs_stats.block_size),
+            free_block_count: fs_stats.free_block_count,
+            free_inode_count: fs_stats.free_inode_count,
+            truncated_file_count: self.truncated_file_count,
+            skipped_file_count: self.skipped_file_count,
+            dentry_order: self.dentry_order,
+            spot_check: self.spot_check.clone(),
+            fat_metadata_sha256: self.fat_metadata_checksums.clone(),
+        }
+    }
+
+    /// Prints `self.messages`, which `print_summary` refers to.
+    pub fn print_messages(&self) {
+        for message in &self.messages {
+            eprintln!("{}", message);
+        }
+    }
+
+    pub fn print_summary(&self, elapsed: Duration) {
+        let summary = self.summary(elapsed);
+        println!(
+            "{}",
+            trf(
+                "Converted {} files and {} directories in {} s, moving {} bytes of file data to make room for ext4 \
+                 metadata. The ext4 filesystem has {} bytes ({} blocks) and {} inodes left.",
+                &[
+                    &summary.file_count,
+                    &summary.directory_count,
+                    &format!("{:.1}", summary.seconds),
+                    &summary.moved_bytes,
+                    &summary.free_bytes,
+                    &summary.free_block_count,
+                    &summary.free_inode_count,
+                ],
+            )
+        );
+        if summary.skipped_file_count > 0 {
+            println!(
+                "{}",
+                trf(
+                    "Skipped {} files that could not be converted, see the warnings above",
+                    &[&summary.skipped_file_count],
+                )
+            );
+        }
+        if let Some(spot_check) = &summary.spot_check {
+            println!(
+                "{}",
+                trf(
+                    "The content of {} sampled files is unchanged after the conversion",
+                    &[&spot_check.matched_count],
+                )
+            );
+            if !spot_check.unchecked_files.is_empty() {
+                println!(
+                    "{}",
+                    trf(
+                        "{} sampled files were not converted to regular files with the same path and were not checked",
+                        &[&spot_check.unchecked_files.len()],
+                    )
+                );
+            }
+        }
+        if let Some(checksums) = &summary.fat_metadata_sha256 {
+            checksums.print();
+        }
+    }
+}
+
+/// What `--dry-run` found out about the conversion without modifying the partition
+pub struct DryRunReport {
+    /// the serialized files and directories and the directories of the skeleton, excluding the root directory
+    pub file_count: usize,
+    /// the inodes of the ext4 filesystem that are not reserved
+    pub allocatable_inode_count: InodeCount,
+    pub cluster_size: u32,
+    pub prediction: Prediction,
+    /// whether the last block group lacks its superblock backup, see `--allow-tight-fit`
+    pub tight_fit: bool,
+    pub report: SerializationReport,
+}
+
+impl DryRunReport {
+    pub fn print(&self) {
+        let prediction = &self.prediction;
+        println!(
+            "{}",
+            trf(
+                "The dry run succeeded, the partition has not been modified. The conversion would create {} files and \
+                 directories, using {} of {} inodes, {} clusters of {} bytes for file data and {} for directories, \
+                 extent trees and symlinks, and leave {} of {} clusters free.",
+                &[
+                    &self.file_count,
+                    &prediction.usage.inodes,
+                    &self.allocatable_inode_count,
+                    &prediction.data_cluster_count,
+                    &self.cluster_size,
+                    &prediction.usage.clusters,
+                    &prediction.free_cluster_count,
+                    &prediction.cluster_count,
+                ],
+            )
+        );
+        if self.tight_fit {
+            println!(
+                "{}",
+                tr("The filesystem would only fit without the superblock backup in the last block group")
+            );
+        }
+        if self.report.truncated_file_count > 0 {
+            println!(
+                "{}",
+                trf(
+                    "{} files would be truncated, see the warnings above",
+                    &[&self.report.truncated_file_count],
+                )
+            );
+        }
+        if self.report.skipped_file_count > 0 {
+            println!(
+                "{}",
+                trf(
+                    "{} files would be skipped because they cannot be converted, see the warnings above",
+                    &[&self.report.skipped_file_count],
+                )
+            );
+        }
+    }
+}
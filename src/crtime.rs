@@ -0,0 +1,110 @@
+use std::fmt;
+use std::io::{self, Write};
+
+use anyhow::{bail, Context, Result};
+
+use crate::diff_meta::read_inode;
+use crate::ext4::{nanoseconds_from_extra, InodeNo};
+
+/// A point in time with the resolution of ext4 timestamps
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Timestamp {
+    /// the seconds since the Unix epoch
+    pub seconds: u32,
+    pub nanoseconds: u32,
+}
+
+/// The creation time of a converted file in FAT and its birth time (crtime) in ext4, as read back from its inode after
+/// the conversion. Many users convert to ext4 in place to keep the creation times of their photos, which tools that
+/// copy the files lose.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CrtimeMapping {
+    /// the path of the file, starting with '/' at the root of the FAT filesystem
+    pub path: String,
+    pub fat_create_time: Timestamp,
+    pub ext4_crtime: Timestamp,
+}
+
+/// A converted file whose crtime is checked by `CrtimeMapping::read_back`
+#[derive(Clone, Debug, PartialEq)]
+pub struct CrtimeSource {
+    pub path: String,
+    pub inode_no: InodeNo,
+    pub fat_create_time: Timestamp,
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{:09}", self.seconds, self.nanoseconds)
+    }
+}
+
+impl CrtimeMapping {
+    /// Reads the crtime of every file in `sources` from its inode in the ext4 filesystem in `partition`. Fails if a
+    /// crtime differs from the FAT creation time, naming the first file whose creation time was lost.
+    pub fn read_back(partition: &[u8], sources: Vec<CrtimeSource>) -> Result<Vec<Self>> {
+        let mut mappings = Vec::with_capacity(sources.len());
+        for source in sources {
+            let inode = read_inode(partition, source.inode_no)
+                .with_context(|| format!("Unable to read the inode of {}", source.path))?;
+            mappings.push(Self {
+                path: source.path,
+                fat_create_time: source.fat_create_time,
+                ext4_crtime: Timestamp {
+                    seconds: inode.i_crtime,
+                    nanoseconds: nanoseconds_from_extra(inode.i_crtime_extra),
+                },
+            });
+        }
+
+        let mut lost = mappings.iter().filter(|mapping| mapping.fat_create_time != mapping.ext4_crtime);
+        if let Some(first_lost) = lost.next() {
+            bail!(
+                "{} files lost their creation time, e.g. {} was created at {} but has the crtime {}",
+                lost.count() + 1,
+                first_lost.path,
+                first_lost.fat_create_time,
+                first_lost.ext4_crtime
+            );
+        }
+        Ok(mappings)
+    }
+
+    /// Writes one line per file to `writer`, consisting of its FAT creation time, its ext4 crtime (both as Unix
+    /// timestamps with nanoseconds) and its path, separated by tabs. FAT file names cannot contain control characters,
+    /// so a path never contains a tab or a newline.
+    pub fn write_list<W: Write>(mappings: &[Self], mut writer: W) -> io::Result<()> {
+        for mapping in mappings {
+            writeln!(writer, "{}\t{}\t{}", mapping.fat_create_time, mapping.ext4_crtime, mapping.path)?;
+        }
+        writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_one_line_per_file() {
+        let time = Timestamp { seconds: 1_577_836_801, nanoseconds: 990_000_000 };
+        let mappings = [
+            CrtimeMapping {
+                path: "/a b".to_string(),
+                fat_create_time: time,
+                ext4_crtime: time,
+            },
+            CrtimeMapping {
+                path: "/dir".to_string(),
+                fat_create_time: Timestamp { seconds: 0, nanoseconds: 0 },
+                ext4_crtime: Timestamp { seconds: 0, nanoseconds: 0 },
+            },
+        ];
+        let mut list = Vec::new();
+        CrtimeMapping::write_list(&mappings, &mut list).unwrap();
+        assert_eq!(
+            String::from_utf8(list).unwrap(),
+            "1577836801.990000000\t1577836801.990000000\t/a b\n0.000000000\t0.000000000\t/dir\n"
+        );
+    }
+}
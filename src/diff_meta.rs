@@ -0,0 +1,611 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::mem::{size_of, size_of_val};
+use std::ops::Range;
+use std::{fmt, slice};
+
+use anyhow::{bail, Context, Result};
+
+use crate::ext4::{
+    decode_xattrs, Ext4GroupDescriptor, ExtentTreeElement, InodeInner, InodeNo, SuperBlock, Xattr, EXTENT_MAGIC,
+    FIRST_BLOCK_PADDING, INODE_UNINIT, INODE_USES_EXTENTS, LOST_FOUND_INODE_NO, ROOT_INODE_NO, SUPERBLOCK_MAGIC,
+};
+use crate::lohi::LoHi;
+use crate::util::{FromU32, FromUsize};
+
+/// The superblock fields that are random or depend on the time of the conversion
+const IGNORED_SUPERBLOCK_FIELDS: [&str; 5] = ["s_uuid", "s_hash_seed", "s_mkfs_time", "s_wtime", "s_lastcheck"];
+/// The fields of the root directory and lost+found that are set to the time of the conversion
+const IGNORED_CREATION_TIME_FIELDS: [&str; 3] = ["i_atime", "i_ctime", "i_mtime"];
+/// The value shown for a field that only one of two compared structures has
+const MISSING_FIELD: &str = "(none)";
+/// The largest block size the kernel supports, as the logarithm of the block size in KiB
+const MAX_LOG_BLOCK_SIZE: u32 = 6;
+/// The length of an extent is at most this, otherwise the extent is uninitialized and its actual length is the
+/// difference
+const MAX_INITIALIZED_EXTENT_LEN: u16 = 32768;
+
+/// The ext4 data structure that a field of a `MetadataDump` belongs to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Structure {
+    SuperBlock,
+    GroupDescriptor(u32),
+    Inode(InodeNo),
+}
+
+/// The fields of the ext4 metadata of a partition, i.e. of the superblock, the group descriptors and the used inodes
+/// including their extent trees, formatted as strings. Comparing the dumps of two conversions of the same FAT
+/// filesystem shows how different versions of ofs-convert-rs convert it.
+#[derive(Debug, Default, PartialEq)]
+pub struct MetadataDump {
+    structures: BTreeMap<Structure, BTreeMap<&'static str, String>>,
+}
+
+/// The blocks of an ext4 filesystem that contain metadata, found by following its group descriptors and the extent
+/// trees of its used inodes
+pub struct MetadataBlocks {
+    pub superblock: SuperBlock,
+    /// the superblocks, group descriptor tables, bitmaps, inode tables and extent tree index blocks
+    pub blocks: Vec<Range<u64>>,
+    pub directories: Vec<DirectoryBlocks>,
+}
+
+/// The data blocks of a directory, which contain its dentries
+pub struct DirectoryBlocks {
+    pub inode_no: InodeNo,
+    /// needed for the checksums of the blocks
+    pub generation: u32,
+    pub blocks: Vec<Range<u64>>,
+}
+
+/// A run of data blocks of a file, see `read_data_extents`
+#[derive(Clone, Debug, PartialEq)]
+pub struct DataExtent {
+    pub logical_start: u64,
+    pub physical_start: u64,
+    pub len: u64,
+    /// whether the blocks contain the data; uninitialized extents read as zeros
+    pub initialized: bool,
+}
+
+/// An entry of an extent tree node
+enum ExtentTreeEntry {
+    Index {
+        logical_start: u32,
+        leaf: u64,
+    },
+    Extent {
+        logical_start: u32,
+        len: u16,
+        physical_start: u64,
+    },
+}
+
+/// A difference between two `MetadataDump`s
+#[derive(Debug, PartialEq)]
+pub enum Difference {
+    OnlyInFirst(Structure),
+    OnlyInSecond(Structure),
+    Field {
+        structure: Structure,
+        field: &'static str,
+        first: String,
+        second: String,
+    },
+}
+
+/// Inserts the Debug representation of each `$field` of `$value` into `$fields`.
+macro_rules! dump_fields {
+    ($fields:expr, $value:expr, $($field:ident),* $(,)?) => {
+        $($fields.insert(stringify!($field), format!("{:?}", $value.$field));)*
+    };
+}
+
+impl Structure {
+    /// Returns true if `field` of this structure is expected to differ between two conversions of the same FAT
+    /// filesystem.
+    fn is_ignored(self, field: &str) -> bool {
+        match self {
+            Self::SuperBlock => IGNORED_SUPERBLOCK_FIELDS.contains(&field),
+            // the checksum includes the UUID
+            Self::GroupDescriptor(_) => field == "bg_checksum",
+            Self::Inode(inode_no) => {
+                field == "i_generation"
+                    || ([ROOT_INODE_NO, LOST_FOUND_INODE_NO].contains(&inode_no)
+                        && IGNORED_CREATION_TIME_FIELDS.contains(&field))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Structure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::SuperBlock => write!(f, "superblock"),
+            Self::GroupDescriptor(block_group_idx) => write!(f, "group descriptor {}", block_group_idx),
+            Self::Inode(inode_no) => write!(f, "inode {}", inode_no),
+        }
+    }
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::OnlyInFirst(structure) => write!(f, "{}: only in the first partition", structure),
+            Self::OnlyInSecond(structure) => write!(f, "{}: only in the second partition", structure),
+            Self::Field { structure, field, first, second } => {
+                write!(f, "{}: {}: {} -> {}", structure, field, first, second)
+            }
+        }
+    }
+}
+
+impl MetadataDump {
+    /// Dumps the metadata of the ext4 filesystem in `partition`. Returns an error if it is not a valid ext4
+    /// filesystem.
+    pub fn new(partition: &[u8]) -> Result<Self> {
+        let superblock = read_superblock(partition)?;
+        let mut dump = Self { structures: BTreeMap::new() };
+        dump.insert_superblock(&superblock);
+        let reader = MetadataReader::new(partition, &superblock);
+        for block_group_idx in 0..superblock.block_group_count() {
+            let descriptor = reader.group_descriptor(&superblock, block_group_idx)?;
+            dump.insert_group_descriptor(block_group_idx, &descriptor);
+            if descriptor.bg_flags & INODE_UNINIT == 0 {
+                dump.insert_inodes(&reader, &superblock, block_group_idx, &descriptor)
+                    .with_context(|| format!("Unable to read the inodes of block group {}", block_group_idx))?;
+            }
+        }
+        Ok(dump)
+    }
+
+    /// Returns the differences between `self` and `other`, except for the fields that are random or depend on the time
+    /// of the conversion.
+    pub fn compare(&self, other: &Self) -> Vec<Difference> {
+        let mut differences = Vec::new();
+        for (&structure, fields) in &self.structures {
+            let other_fields = match other.structures.get(&structure) {
+                Some(other_fields) => other_fields,
+                None => {
+                    differences.push(Difference::OnlyInFirst(structure));
+                    continue;
+                }
+            };
+            // an inode has either "extents" or "i_block", depending on whether it uses extents
+            let field_names: BTreeSet<_> = fields.keys().chain(other_fields.keys()).copied().collect();
+            for field in field_names {
+                let value = fields.get(field).map_or(MISSING_FIELD, String::as_str);
+                let other_value = other_fields.get(field).map_or(MISSING_FIELD, String::as_str);
+                if value != other_value && !structure.is_ignored(field) {
+                    differences.push(Difference::Field {
+                        structure,
+                        field,
+                        first: value.to_string(),
+                        second: other_value.to_string(),
+                    });
+                }
+            }
+        }
+        differences.extend(
+            other
+                .structures
+                .keys()
+                .filter(|structure| !self.structures.contains_key(structure))
+                .map(|&structure| Difference::OnlyInSecond(structure)),
+        );
+        differences
+    }
+
+    fn insert_superblock(&mut self, superblock: &SuperBlock) {
+        let fields = self.structures.entry(Structure::SuperBlock).or_default();
+        #[rustfmt::skip]
+        dump_fields!(
+            fields, superblock,
+            s_inodes_count, s_blocks_count_lo, s_r_blocks_count_lo, s_free_blocks_count_lo, s_free_inodes_count,
+            s_first_data_block, s_log_block_size, s_log_cluster_size, s_blocks_per_group, s_clusters_per_group,
+            s_inodes_per_group, s_mtime, s_wtime, s_mnt_count, s_max_mnt_count, s_magic, s_state, s_errors,
+            s_minor_rev_level, s_lastcheck, s_checkinterval, s_creator_os, s_rev_level, s_def_resuid, s_def_resgid,
+            s_first_ino, s_inode_size, s_block_group_nr, s_feature_compat, s_feature_incompat, s_feature_ro_compat,
+            s_uuid, s_volume_name, s_last_mounted, s_algorithm_usage_bitmap, s_prealloc_blocks, s_prealloc_dir_blocks,
+            s_reserved_gdt_blocks, s_journal_uuid, s_journal_inum, s_journal_dev, s_last_orphan, s_hash_seed,
+            s_def_hash_version, s_jnl_backup_type, s_desc_size, s_default_mount_opts, s_first_meta_bg, s_mkfs_time,
+            s_jnl_blocks, s_blocks_count_hi, s_r_blocks_count_hi, s_free_blocks_count_hi, s_min_extra_isize,
+            s_want_extra_isize, s_flags, s_raid_stride, s_mmp_update_interval, s_mmp_block, s_raid_stripe_width,
+            s_log_groups_per_flex, s_checksum_type, s_encryption_level, s_reserved_pad, s_kbytes_written,
+            s_snapshot_inum, s_snapshot_id, s_snapshot_r_blocks_count, s_snapshot_list, s_error_count,
+            s_first_error_time, s_first_error_ino, s_first_error_block, s_first_error_func, s_first_error_line,
+            s_last_error_time, s_last_error_ino, s_last_error_line, s_last_error_block, s_last_error_func,
+            s_mount_opts, s_usr_quota_inum, s_grp_quota_inum, s_overhead_clusters, s_backup_bgs, s_encrypt_algos,
+            s_encrypt_pw_salt, s_lpf_ino, s_prj_quota_inum, s_checksum_seed, s_reserved, s_checksum,
+        );
+    }
+
+    fn insert_group_descriptor(&mut self, block_group_idx: u32, descriptor: &Ext4GroupDescriptor) {
+        let fields = self.structures.entry(Structure::GroupDescriptor(block_group_idx)).or_default();
+        #[rustfmt::skip]
+        dump_fields!(
+            fields, descriptor,
+            bg_block_bitmap_lo, bg_inode_bitmap_lo, bg_inode_table_lo, bg_free_blocks_count_lo,
+            bg_free_inodes_count_lo, bg_used_dirs_count_lo, bg_flags, bg_exclude_bitmap_lo, bg_block_bitmap_csum_lo,
+            bg_inode_bitmap_csum_lo, bg_itable_unused_lo, bg_checksum, bg_block_bitmap_hi, bg_inode_bitmap_hi,
+            bg_inode_table_hi, bg_free_blocks_count_hi, bg_free_inodes_count_hi, bg_used_dirs_count_hi,
+            bg_itable_unused_hi, bg_exclude_bitmap_hi, bg_block_bitmap_csum_hi, bg_inode_bitmap_csum_hi, bg_reserved,
+        );
+    }
+
+    /// Inserts the inodes of block group `block_group_idx` that are marked as used in its inode bitmap.
+    fn insert_inodes(
+        &mut self,
+        reader: &MetadataReader,
+        superblock: &SuperBlock,
+        block_group_idx: u32,
+        descriptor: &Ext4GroupDescriptor,
+    ) -> Result<()> {
+        for (inode_no, inode) in reader.used_inodes(superblock, block_group_idx, descriptor)? {
+            let fields = self.structures.entry(Structure::Inode(inode_no)).or_default();
+            #[rustfmt::skip]
+            dump_fields!(
+                fields, inode,
+                i_mode, i_uid, i_size_lo, i_atime, i_ctime, i_mtime, i_dtime, i_gid, i_links_count, i_blocks_lo,
+                i_flags, l_i_version, i_generation, i_file_acl_lo, i_size_high, i_obso_faddr, l_i_blocks_high,
+                l_i_file_acl_high, l_i_uid_high, l_i_gid_high, l_i_checksum_lo, l_i_reserved, i_extra_isize,
+                i_checksum_hi, i_ctime_extra, i_mtime_extra, i_atime_extra, i_crtime, i_crtime_extra, i_version_hi,
+                i_projid,
+            );
+            if inode.i_flags & INODE_USES_EXTENTS != 0 {
+                let extents = reader
+                    .extent_tree(&inode.extents)
+                    .with_context(|| format!("The extent tree of inode {} is damaged", inode_no))?;
+                fields.insert("extents", extents.join(", "));
+            } else {
+                // SAFETY: Safe because the extent tree elements only consist of integers, so they can be read as bytes.
+                let i_block =
+                    unsafe { slice::from_raw_parts(inode.extents.as_ptr() as *const u8, size_of_val(&inode.extents)) };
+                fields.insert("i_block", format!("{:?}", i_block));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl MetadataBlocks {
+    /// Finds the metadata blocks of the ext4 filesystem in `partition`. Returns an error if it is not a valid ext4
+    /// filesystem.
+    pub fn new(partition: &[u8]) -> Result<Self> {
+        let superblock = read_superblock(partition)?;
+        let reader = MetadataReader::new(partition, &superblock);
+        let mut blocks = Vec::new();
+        let mut directories = Vec::new();
+        for block_group_idx in 0..superblock.block_group_count() {
+            let start_block = u64::fromx(superblock.block_group_start_block(block_group_idx));
+            let superblock_copy_len =
+                superblock.superblock_copy_overhead(superblock.block_group_has_superblock(block_group_idx));
+            blocks.push(start_block..start_block + u64::fromx(superblock_copy_len));
+
+            let descriptor = reader.group_descriptor(&superblock, block_group_idx)?;
+            let block_bitmap = LoHi::new(&descriptor.bg_block_bitmap_lo, &descriptor.bg_block_bitmap_hi).get();
+            let inode_bitmap = LoHi::new(&descriptor.bg_inode_bitmap_lo, &descriptor.bg_inode_bitmap_hi).get();
+            blocks.push(block_bitmap..block_bitmap + 1);
+            blocks.push(inode_bitmap..inode_bitmap + 1);
+            if descriptor.bg_flags & INODE_UNINIT != 0 {
+                continue;
+            }
+            let inode_table = LoHi::new(&descriptor.bg_inode_table_lo, &descriptor.bg_inode_table_hi).get();
+            blocks.push(inode_table..inode_table + u64::fromx(superblock.inode_table_block_count()));
+
+            let used_inodes = reader
+                .used_inodes(&superblock, block_group_idx, &descriptor)
+                .with_context(|| format!("Unable to read the inodes of block group {}", block_group_idx))?;
+            for (inode_no, inode) in used_inodes {
+                if inode.i_flags & INODE_USES_EXTENTS == 0 {
+                    continue;
+                }
+                let mut data_blocks = Vec::new();
+                reader
+                    .walk_extent_tree(&inode.extents, &mut |entry| match entry {
+                        ExtentTreeEntry::Index { leaf, .. } => blocks.push(leaf..leaf + 1),
+                        ExtentTreeEntry::Extent { len, physical_start, .. } => {
+                            // longer extents are uninitialized and read as zeros
+                            let len = if len > MAX_INITIALIZED_EXTENT_LEN {
+                                len - MAX_INITIALIZED_EXTENT_LEN
+                            } else {
+                                len
+                            };
+                            data_blocks.push(physical_start..physical_start + u64::from(len));
+                        }
+                    })
+                    .with_context(|| format!("The extent tree of inode {} is damaged", inode_no))?;
+                if inode.is_dir() {
+                    directories.push(DirectoryBlocks {
+                        inode_no,
+                        generation: inode.i_generation,
+                        blocks: data_blocks,
+                    });
+                }
+            }
+        }
+        Ok(Self { superblock, blocks, directories })
+    }
+}
+
+/// Reads inode `inode_no` of the ext4 filesystem in `partition`, so that the conversion can check what it has
+/// written. Returns an error if `partition` does not contain a valid ext4 filesystem with this inode.
+pub fn read_inode(partition: &[u8], inode_no: InodeNo) -> Result<InodeInner> {
+    let inode_offset = inode_offset(partition, inode_no)?.0;
+    // SAFETY: Safe because `InodeInner` only consists of integers.
+    unsafe { read(partition, inode_offset) }
+}
+
+/// Reads the extended attributes stored in inode `inode_no` of the ext4 filesystem in `partition`, see
+/// `decode_xattrs`. Returns an error if `partition` does not contain a valid ext4 filesystem with this inode.
+pub fn read_xattrs(partition: &[u8], inode_no: InodeNo) -> Result<Vec<Xattr>> {
+    let (inode_offset, inode_size) = inode_offset(partition, inode_no)?;
+    let xattr_space = partition
+        .get(inode_offset + size_of::<InodeInner>()..inode_offset + inode_size)
+        .context("The inode lies outside the partition")?;
+    decode_xattrs(xattr_space)
+}
+
+/// Returns the offset of inode `inode_no` in `partition` and the size of an inode.
+fn inode_offset(partition: &[u8], inode_no: InodeNo) -> Result<(usize, usize)> {
+    let superblock = read_superblock(partition)?;
+    if inode_no == 0 || inode_no > superblock.s_inodes_count {
+        bail!("The ext4 filesystem has no inode {}", inode_no);
+    }
+    let reader = MetadataReader::new(partition, &superblock);
+    let block_group_idx = (inode_no - 1) / superblock.s_inodes_per_group;
+    let descriptor = reader.group_descriptor(&superblock, block_group_idx)?;
+    let inode_table_block = LoHi::new(&descriptor.bg_inode_table_lo, &descriptor.bg_inode_table_hi).get();
+    let idx = usize::fromx((inode_no - 1) % superblock.s_inodes_per_group);
+    let inode_size = usize::from(superblock.s_inode_size);
+    Ok((reader.block_offset(inode_table_block)? + idx * inode_size, inode_size))
+}
+
+/// Returns the extents of `inode` in the ext4 filesystem in `partition`, ordered by their logical start.
+pub fn read_data_extents(partition: &[u8], inode: &InodeInner) -> Result<Vec<DataExtent>> {
+    let superblock = read_superblock(partition)?;
+    if inode.i_flags & INODE_USES_EXTENTS == 0 {
+        bail!("The inode has no extent tree");
+    }
+    let mut extents = Vec::new();
+    MetadataReader::new(partition, &superblock).walk_extent_tree(&inode.extents, &mut |entry| {
+        if let ExtentTreeEntry::Extent { logical_start, len, physical_start } = entry {
+            let initialized = len <= MAX_INITIALIZED_EXTENT_LEN;
+            let len = if initialized {
+                len
+            } else {
+                len - MAX_INITIALIZED_EXTENT_LEN
+            };
+            extents.push(DataExtent {
+                logical_start: u64::from(logical_start),
+                physical_start,
+                len: u64::from(len),
+                initialized,
+            });
+        }
+    })?;
+    extents.sort_by_key(|extent| extent.logical_start);
+    Ok(extents)
+}
+
+/// Reads the superblock of the ext4 filesystem in `partition` and checks that its metadata can be read.
+pub fn read_superblock(partition: &[u8]) -> Result<SuperBlock> {
+    // SAFETY: Safe because `SuperBlock` only consists of integers.
+    let superblock: SuperBlock = unsafe { read(partition, FIRST_BLOCK_PADDING)? };
+    if superblock.s_magic != SUPERBLOCK_MAGIC {
+        bail!("The partition does not contain an ext4 filesystem");
+    }
+    if superblock.s_log_block_size > MAX_LOG_BLOCK_SIZE
+        || superblock.s_blocks_per_group == 0
+        || superblock.s_inodes_per_group == 0
+    {
+        bail!("The ext4 superblock is damaged");
+    }
+    if usize::from(superblock.s_desc_size) < size_of::<Ext4GroupDescriptor>()
+        || usize::from(superblock.s_inode_size) < size_of::<InodeInner>()
+    {
+        bail!("Only ext4 filesystems with 64 bit group descriptors and large inodes are supported");
+    }
+    Ok(superblock)
+}
+
+/// Reads the blocks that the group descriptors and the extent trees point to
+struct MetadataReader<'a> {
+    partition: &'a [u8],
+    block_size: usize,
+}
+
+impl<'a> MetadataReader<'a> {
+    fn new(partition: &'a [u8], superblock: &SuperBlock) -> Self {
+        Self {
+            partition,
+            block_size: usize::fromx(superblock.block_size()),
+        }
+    }
+
+    fn group_descriptor(&self, superblock: &SuperBlock, block_group_idx: u32) -> Result<Ext4GroupDescriptor> {
+        let gdt_start = usize::fromx(superblock.s_first_data_block + 1) * self.block_size;
+        let descriptor_offset = gdt_start + usize::fromx(block_group_idx) * usize::from(superblock.s_desc_size);
+        // SAFETY: Safe because `Ext4GroupDescriptor` only consists of integers.
+        unsafe { read(self.partition, descriptor_offset) }
+    }
+
+    fn block_offset(&self, block_idx: u64) -> Result<usize> {
+        usize::try_from(block_idx)
+            .ok()
+            .and_then(|block_idx| block_idx.checked_mul(self.block_size))
+            .filter(|&offset| offset + self.block_size <= self.partition.len())
+            .with_context(|| format!("Block {} lies outside the partition", block_idx))
+    }
+
+    fn block(&self, block_idx: u64) -> Result<&'a [u8]> {
+        let offset = self.block_offset(block_idx)?;
+        Ok(&self.partition[offset..offset + self.block_size])
+    }
+
+    /// Returns the inodes of block group `block_group_idx` that are marked as used in its inode bitmap, with their
+    /// numbers.
+    fn used_inodes(
+        &self,
+        superblock: &SuperBlock,
+        block_group_idx: u32,
+        descriptor: &Ext4GroupDescriptor,
+    ) -> Result<Vec<(InodeNo, InodeInner)>> {
+        let inode_bitmap_block = LoHi::new(&descriptor.bg_inode_bitmap_lo, &descriptor.bg_inode_bitmap_hi).get();
+        let inode_table_block = LoHi::new(&descriptor.bg_inode_table_lo, &descriptor.bg_inode_table_hi).get();
+        let inode_bitmap = self.block(inode_bitmap_block)?;
+        let inode_table_start = self.block_offset(inode_table_block)?;
+        let inodes_per_group = usize::fromx(superblock.s_inodes_per_group).min(inode_bitmap.len() * 8);
+
+        (0..inodes_per_group)
+            .filter(|idx| inode_bitmap[idx / 8] & (1 << (idx % 8)) != 0)
+            .map(|idx| {
+                let inode_offset = inode_table_start + idx * usize::from(superblock.s_inode_size);
+                // SAFETY: Safe because `InodeInner` only consists of integers.
+                let inode: InodeInner = unsafe { read(self.partition, inode_offset)? };
+                let inode_no = block_group_idx * superblock.s_inodes_per_group + u32::try_from(idx).unwrap() + 1;
+                Ok((inode_no, inode))
+            })
+            .collect()
+    }
+
+    /// Returns the extents of the extent tree whose root node is `root`, formatted as
+    /// `logical start+length@physical start`, preceded by the index entries pointing to each leaf.
+    fn extent_tree(&self, root: &[ExtentTreeElement]) -> Result<Vec<String>> {
+        let mut extents = Vec::new();
+        self.walk_extent_tree(root, &mut |entry| {
+            extents.push(match entry {
+                ExtentTreeEntry::Index { logical_start, leaf } => format!("index {}@{}", logical_start, leaf),
+                ExtentTreeEntry::Extent { logical_start, len, physical_start } => {
+                    format!("{}+{}@{}", logical_start, len, physical_start)
+                }
+            })
+        })?;
+        Ok(extents)
+    }
+
+    /// Calls `visit` with the entries of the extent tree whose root node is `root` in depth-first order, i.e. with
+    /// every index entry before the entries of the node it points to.
+    fn walk_extent_tree(&self, root: &[ExtentTreeElement], visit: &mut dyn FnMut(ExtentTreeEntry)) -> Result<()> {
+        self.walk_extent_node(root, None, visit)
+    }
+
+    fn walk_extent_node(
+        &self,
+        node: &[ExtentTreeElement],
+        expected_depth: Option<u16>,
+        visit: &mut dyn FnMut(ExtentTreeEntry),
+    ) -> Result<()> {
+        // SAFETY: Safe because every node starts with a header.
+        let header = unsafe { node[0].header };
+        if header.magic != EXTENT_MAGIC {
+            bail!("An extent tree node has no extent header");
+        }
+        if matches!(expected_depth, Some(depth) if depth != header.depth) {
+            bail!("An extent tree node has depth {} instead of {:?}", header.depth, expected_depth);
+        }
+        let entries = node
+            .get(1..=usize::from(header.valid_entry_count))
+            .context("An extent tree node has more entries than fit into it")?;
+        for entry in entries {
+            if header.depth == 0 {
+                // SAFETY: Safe because the entries of a leaf node are extents.
+                let extent = unsafe { entry.extent };
+                let physical_start: u64 = LoHi::new(&extent.physical_start_lo, &extent.physical_start_hi).get();
+                visit(ExtentTreeEntry::Extent {
+                    logical_start: extent.logical_start,
+                    len: extent.len,
+                    physical_start,
+                });
+            } else {
+                // SAFETY: Safe because the entries of an index node are indices.
+                let idx = unsafe { entry.idx };
+                let leaf: u64 = LoHi::new(&idx.leaf_lo, &idx.leaf_hi).get();
+                visit(ExtentTreeEntry::Index { logical_start: idx.logical_start, leaf });
+                let block = self.block(leaf)?;
+                let child: Vec<ExtentTreeElement> = (0..block.len() / size_of::<ExtentTreeElement>())
+                    // SAFETY: Safe because `ExtentTreeElement` only consists of integers.
+                    .map(|element_idx| unsafe { read(block, element_idx * size_of::<ExtentTreeElement>()) })
+                    .collect::<Result<_>>()?;
+                self.walk_extent_node(&child, Some(header.depth - 1), visit)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads a `T` from `bytes` at `offset`.
+/// SAFETY: Every combination of bytes must be a valid `T`.
+pub unsafe fn read<T: Copy>(bytes: &[u8], offset: usize) -> Result<T> {
+    let range = offset..offset.saturating_add(size_of::<T>());
+    match bytes.get(range) {
+        // SAFETY: Safe because the range is as long as a `T` and the caller guarantees that its bytes are a valid `T`.
+        Some(range_bytes) => Ok(unsafe { (range_bytes.as_ptr() as *const T).read_unaligned() }),
+        None => bail!("The metadata at byte {} lies outside the partition", offset),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::conversion::convert_slice;
+    use crate::fat::{FatImage, FatImageBuilder, TestFile};
+    use crate::options::ConversionOptions;
+
+    const MIB: usize = 1024 * 1024;
+
+    fn converted_dump(files: &[TestFile], options: &ConversionOptions) -> MetadataDump {
+        let mut image: FatImage = FatImageBuilder::new(32 * MIB, 1024).build(files);
+        convert_slice(image.as_mut_slice(), options).unwrap();
+        MetadataDump::new(image.as_mut_slice()).unwrap()
+    }
+
+    fn file(name: &str, size: u32) -> TestFile {
+        TestFile::RegularFile { name: name.to_string(), size }
+    }
+
+    #[test]
+    fn repeated_conversions_do_not_differ() {
+        let files = [
+            file("small", 3000),
+            TestFile::Directory {
+                name: "directory".to_string(),
+                children: vec![file("large", 300_000)],
+            },
+        ];
+        let dump = converted_dump(&files, &ConversionOptions::default());
+        assert!(dump.structures.len() > 3);
+        assert!(dump.structures[&Structure::Inode(ROOT_INODE_NO)].contains_key("extents"));
+
+        let randomized = ConversionOptions {
+            randomize_generation: true,
+            ..ConversionOptions::default()
+        };
+        assert_eq!(dump.compare(&converted_dump(&files, &randomized)), vec![]);
+    }
+
+    #[test]
+    fn reports_differences() {
+        let dump = converted_dump(&[file("small", 3000)], &ConversionOptions::default());
+        let other = converted_dump(&[file("small", 5000), file("new", 0)], &ConversionOptions::default());
+        let differences = other.compare(&dump);
+        assert!(differences.contains(&Difference::Field {
+            structure: Structure::Inode(LOST_FOUND_INODE_NO + 1),
+            field: "i_size_lo",
+            first: "5000".to_string(),
+            second: "3000".to_string(),
+        }));
+        assert!(differences.contains(&Difference::OnlyInFirst(Structure::Inode(LOST_FOUND_INODE_NO + 2))));
+        assert!(differences.iter().any(|difference| matches!(
+            difference,
+            Difference::Field {
+                structure: Structure::SuperBlock,
+                field: "s_free_inodes_count",
+                ..
+            }
+        )));
+
+        let garbage = vec![0; 4 * MIB];
+        assert!(MetadataDump::new(&garbage).is_err());
+    }
+}
@@ -0,0 +1,117 @@
+use std::fmt::{self, Display, Formatter};
+
+use crate::messages::tr;
+
+/// The exit code for errors that do not belong to an `ErrorCategory`
+pub const EXIT_FAILURE: i32 = 1;
+
+/// The category of an error, which determines the process's exit code. To categorize an error, wrap it with
+/// `.context(ErrorCategory::...)` or create it with `ErrorCategory::error`; if an error is categorized more than once,
+/// the outermost category counts.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorCategory {
+    /// `fsck.fat` found errors in the FAT32 filesystem
+    FsckFailed,
+    /// the user answered no to a question
+    Aborted,
+    /// the partition does not contain a FAT32 filesystem, or its boot sector is damaged
+    InvalidFilesystem,
+    /// the FAT32 filesystem cannot be represented as an ext4 filesystem, e.g. because of its cluster size
+    UnsupportedGeometry,
+    /// the FAT32 filesystem does not have enough free space for the conversion
+    InsufficientSpace,
+    /// the partition cannot be opened or written
+    Io,
+    /// the conversion failed after it started modifying the filesystem
+    ConversionFailed,
+}
+
+impl ErrorCategory {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Self::FsckFailed => 2,
+            Self::Aborted => 3,
+            Self::InvalidFilesystem => 4,
+            Self::UnsupportedGeometry => 5,
+            Self::InsufficientSpace => 6,
+            Self::Io => 7,
+            Self::ConversionFailed => 8,
+        }
+    }
+
+    /// Creates an error in this category that displays `message`.
+    pub fn error<M: Display + Send + Sync + 'static>(self, message: M) -> anyhow::Error {
+        anyhow::Error::new(self).context(message)
+    }
+}
+
+impl Display for ErrorCategory {
+    fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+        let description = match self {
+            Self::FsckFailed => "fsck.fat found errors in the filesystem",
+            Self::Aborted => "Aborted by user",
+            Self::InvalidFilesystem => "Not a valid FAT32 filesystem",
+            Self::UnsupportedGeometry => "Unsupported filesystem geometry",
+            Self::InsufficientSpace => "Insufficient free space",
+            Self::Io => "Unable to access the partition",
+            Self::ConversionFailed => {
+                "Conversion failed unexpectedly. The FAT partition may have been left in an inconsistent status."
+            }
+        };
+        formatter.write_str(tr(description))
+    }
+}
+
+impl std::error::Error for ErrorCategory {}
+
+pub fn exit_code(error: &anyhow::Error) -> i32 {
+    error
+        .downcast_ref::<ErrorCategory>()
+        .map_or(EXIT_FAILURE, |category| category.exit_code())
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::{anyhow, Context, Result};
+    use itertools::Itertools;
+
+    use super::*;
+
+    const ALL_CATEGORIES: [ErrorCategory; 7] = [
+        ErrorCategory::FsckFailed,
+        ErrorCategory::Aborted,
+        ErrorCategory::InvalidFilesystem,
+        ErrorCategory::UnsupportedGeometry,
+        ErrorCategory::InsufficientSpace,
+        ErrorCategory::Io,
+        ErrorCategory::ConversionFailed,
+    ];
+
+    #[test]
+    fn exit_codes_are_distinct() {
+        let exit_codes = ALL_CATEGORIES.iter().map(|category| category.exit_code()).collect_vec();
+        assert!(exit_codes.iter().all(|&code| code != 0 && code != EXIT_FAILURE));
+        assert!(exit_codes.iter().all_unique());
+    }
+
+    #[test]
+    fn finds_category_below_context() {
+        for category in ALL_CATEGORIES {
+            let error = Err::<(), _>(category.error("message")).context("outer message").unwrap_err();
+            assert_eq!(exit_code(&error), category.exit_code());
+            assert_eq!(error.to_string(), "outer message");
+        }
+    }
+
+    #[test]
+    fn outermost_category_counts() {
+        let result: Result<()> = Err(ErrorCategory::InsufficientSpace.error("No free clusters left"));
+        let error = result.context(ErrorCategory::ConversionFailed).unwrap_err();
+        assert_eq!(exit_code(&error), ErrorCategory::ConversionFailed.exit_code());
+    }
+
+    #[test]
+    fn uncategorized_error_has_generic_exit_code() {
+        assert_eq!(exit_code(&anyhow!("message")), EXIT_FAILURE);
+    }
+}
@@ -0,0 +1,328 @@
+use std::ops::Range;
+
+use anyhow::Result;
+use nix::unistd::{sysconf, SysconfVar};
+use serde::{Deserialize, Serialize};
+
+use crate::conversion::forbidden_ranges;
+use crate::error::ErrorCategory;
+use crate::ext4::SuperBlock;
+use crate::fat::{ClusterIdx, FatFs};
+use crate::messages::trf;
+use crate::ranges::Ranges;
+
+/// The page size of most kernels, including every x86_64 kernel
+pub const COMMON_PAGE_SIZE: u32 = 4096;
+
+/// Compares the free space of a FAT32 filesystem to the space that the ext4 metadata will occupy, without modifying
+/// the filesystem. The clusters needed for directories, extent trees and the serialized directory tree depend on the
+/// directory tree and are only known after the dry run of a conversion.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct SpaceEstimate {
+    pub cluster_size: u32,
+    /// the free cluster count cached in the FsInfo sector, if it is recorded
+    pub fs_info_free_clusters: Option<u32>,
+    /// the free clusters according to the FAT table
+    pub free_clusters: u32,
+    /// the data clusters that the ext4 metadata will occupy
+    pub metadata_clusters: u32,
+    /// the clusters among `metadata_clusters` that contain file data, which will be relocated to free clusters
+    pub relocated_clusters: u32,
+    /// the clusters among `metadata_clusters` that contain FAT directories, which are read before the ext4 metadata
+    /// is written and rebuilt elsewhere, so they are not relocated
+    pub directory_clusters: u32,
+}
+
+impl SpaceEstimate {
+    /// `superblock` must have been created from `fat_fs.boot_sector()`.
+    pub fn new(fat_fs: &FatFs, superblock: &SuperBlock) -> Self {
+        let boot_sector = fat_fs.boot_sector();
+        let first_data_cluster = boot_sector.first_data_cluster();
+        let data_clusters = first_data_cluster..first_data_cluster + boot_sector.data_cluster_count();
+        let used_ranges = fat_fs.used_ranges();
+        let directory_ranges = fat_fs.directory_ranges();
+
+        let mut metadata_clusters = 0;
+        let mut used_clusters = 0;
+        let mut directory_clusters = 0;
+        // the ext4 metadata in the FAT metadata region does not take any space away from the files
+        let forbidden_data_ranges = forbidden_ranges(superblock, fat_fs.cluster_count())
+            .split_overlapping(data_clusters)
+            .into_iter()
+            .filter(|(_, is_forbidden)| *is_forbidden)
+            .map(|(range, _)| range);
+        for range in forbidden_data_ranges {
+            metadata_clusters += range_len(&range);
+            used_clusters += covered_len(&used_ranges, range.clone());
+            directory_clusters += covered_len(&directory_ranges, range);
+        }
+
+        Self {
+            cluster_size: fat_fs.cluster_size(),
+            fs_info_free_clusters: fat_fs
+                .fs_info()
+                .and_then(|fs_info| fs_info.free_cluster_count(boot_sector.data_cluster_count())),
+            free_clusters: fat_fs.free_cluster_count(),
+            metadata_clusters,
+            relocated_clusters: used_clusters - directory_clusters,
+            directory_clusters,
+        }
+    }
+
+    /// Returns the clusters that remain for the directory tree after the ext4 metadata has been created and the file
+    /// data in its way has been relocated, or None if the free clusters do not suffice for that.
+    pub fn remaining_clusters(&self) -> Option<u32> {
+        // the free clusters among `metadata_clusters` are lost, and the relocated ones take up a free cluster each
+        self.free_clusters.checked_sub(self.metadata_clusters - self.directory_clusters)
+    }
+
+    /// Returns an `ErrorCategory::InsufficientSpace` error if the free clusters outside the ext4 metadata do not
+    /// suffice to relocate the file data in its way. The relocation happens during the serialization, i.e. before the
+    /// dry run, so this has to be checked separately.
+    pub fn check_relocation(&self) -> Result<()> {
+        // the used clusters among `metadata_clusters` are the relocated and the directory clusters
+        let free_metadata_clusters = self.metadata_clusters - self.relocated_clusters - self.directory_clusters;
+        let available_clusters = self.free_clusters - free_metadata_clusters;
+        if self.relocated_clusters > available_clusters {
+            return Err(ErrorCategory::InsufficientSpace.error(trf(
+                "{} free clusters are required to relocate the file data in the way of the ext4 metadata but only {} \
+                 are available",
+                &[&self.relocated_clusters, &available_clusters],
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn print(&self) {
+        let bytes = |clusters| u64::from(clusters) * u64::from(self.cluster_size);
+        match self.fs_info_free_clusters {
+            Some(fs_info_free_clusters) => println!(
+                "Free clusters according to the FsInfo sector: {} ({} bytes)",
+                fs_info_free_clusters,
+                bytes(fs_info_free_clusters)
+            ),
+            None => println!("Free clusters according to the FsInfo sector: not recorded"),
+        }
+        println!(
+            "Free clusters according to the FAT: {} ({} bytes)",
+            self.free_clusters,
+            bytes(self.free_clusters)
+        );
+        if matches!(self.fs_info_free_clusters, Some(count) if count != self.free_clusters) {
+            println!("The FsInfo sector is out of date, the FAT is authoritative");
+        }
+        println!(
+            "The ext4 metadata will occupy {} clusters ({} bytes), {} of which contain file data that will be \
+             relocated and {} of which contain FAT directories",
+            self.metadata_clusters,
+            bytes(self.metadata_clusters),
+            self.relocated_clusters,
+            self.directory_clusters
+        );
+        match self.remaining_clusters() {
+            Some(remaining_clusters) => println!(
+                "{} clusters ({} bytes) remain for directories, extent trees and the serialized directory tree",
+                remaining_clusters,
+                bytes(remaining_clusters)
+            ),
+            None => println!(
+                "The conversion will not fit: {} more free clusters are needed",
+                self.metadata_clusters - self.directory_clusters - self.free_clusters
+            ),
+        }
+    }
+}
+
+/// Linux only mounts ext4 filesystems whose blocks are no larger than its page size, so converting a FAT filesystem
+/// with clusters larger than 4 KiB creates a filesystem that most kernels cannot mount. With bigalloc, the blocks are
+/// 4 KiB and are allocated in clusters the size of a FAT cluster instead, which changes the layout of the ext4
+/// metadata and therefore the file data that has to be relocated.
+#[derive(Debug, PartialEq)]
+pub struct LargeBlockWarning {
+    block_size: u32,
+    cluster_size: u32,
+    /// the page size of the running kernel
+    page_size: u32,
+    estimate: SpaceEstimate,
+    /// the estimate of a conversion with bigalloc, unless its superblock could not be created
+    bigalloc_estimate: Option<SpaceEstimate>,
+}
+
+impl LargeBlockWarning {
+    /// Returns a warning if the blocks of `superblock` are larger than the pages of most kernels. `bigalloc_superblock`
+    /// is the superblock that `--bigalloc` would create instead. Both must have been created from
+    /// `fat_fs.boot_sector()`.
+    pub fn new(fat_fs: &FatFs, superblock: &SuperBlock, bigalloc_superblock: Option<&SuperBlock>) -> Option<Self> {
+        Self::with_page_size(fat_fs, superblock, bigalloc_superblock, kernel_page_size())
+    }
+
+    fn with_page_size(
+        fat_fs: &FatFs,
+        superblock: &SuperBlock,
+        bigalloc_superblock: Option<&SuperBlock>,
+        page_size: u32,
+    ) -> Option<Self> {
+        if superblock.block_size() <= COMMON_PAGE_SIZE {
+            return None;
+        }
+        Some(Self {
+            block_size: superblock.block_size(),
+            cluster_size: fat_fs.cluster_size(),
+            page_size,
+            estimate: SpaceEstimate::new(fat_fs, superblock),
+            bigalloc_estimate: bigalloc_superblock
+                .map(|bigalloc_superblock| SpaceEstimate::new(fat_fs, bigalloc_superblock)),
+        })
+    }
+
+    pub fn print(&self) {
+        let kib = |bytes| bytes / 1024;
+        eprintln!(
+            "Warning: The ext4 filesystem will have blocks of {} KiB, but kernels with pages of {} KiB, like every \
+             x86_64 kernel, can only mount ext4 filesystems with blocks of up to {} KiB.",
+            kib(self.block_size),
+            kib(COMMON_PAGE_SIZE),
+            kib(COMMON_PAGE_SIZE)
+        );
+        if self.page_size >= self.block_size {
+            eprintln!(
+                "This kernel has pages of {} KiB, so it can mount the filesystem.",
+                kib(self.page_size)
+            );
+        } else {
+            eprintln!("This kernel cannot mount the filesystem either.");
+        }
+        eprintln!(
+            "To create blocks of {} KiB that are allocated in clusters of {} KiB instead, convert with --bigalloc, \
+             which requires a kernel with bigalloc support (Linux 3.2 or newer).",
+            kib(COMMON_PAGE_SIZE),
+            kib(self.cluster_size)
+        );
+        if let Some(bigalloc_estimate) = &self.bigalloc_estimate {
+            let bytes = |clusters| u64::from(clusters) * u64::from(self.cluster_size);
+            eprintln!(
+                "With --bigalloc, the ext4 metadata will occupy {} instead of {} clusters, and {} instead of {} bytes \
+                 of file data will be relocated.",
+                bigalloc_estimate.metadata_clusters,
+                self.estimate.metadata_clusters,
+                bytes(bigalloc_estimate.relocated_clusters),
+                bytes(self.estimate.relocated_clusters)
+            );
+        }
+    }
+}
+
+/// Returns the page size of the running kernel, or the most common page size if it is unknown.
+fn kernel_page_size() -> u32 {
+    sysconf(SysconfVar::PAGE_SIZE)
+        .ok()
+        .flatten()
+        .and_then(|page_size| u32::try_from(page_size).ok())
+        .unwrap_or(COMMON_PAGE_SIZE)
+}
+
+fn range_len(range: &Range<ClusterIdx>) -> u32 {
+    range.end - range.start
+}
+
+/// Returns the number of clusters in `range` that `ranges` covers.
+fn covered_len(ranges: &Ranges<ClusterIdx>, range: Range<ClusterIdx>) -> u32 {
+    ranges
+        .split_overlapping(range)
+        .iter()
+        .filter(|(_, is_covered)| *is_covered)
+        .map(|(range, _)| range_len(range))
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::marker::PhantomData;
+
+    use super::*;
+    use crate::ext4::{DEFAULT_INODE_RATIO, MIN_INODE_RATIO};
+    use crate::fat::{FatImageBuilder, TestFile};
+
+    const MIB: usize = 1024 * 1024;
+
+    fn estimate(files: &[TestFile]) -> SpaceEstimate {
+        estimate_with_inode_ratio(files, DEFAULT_INODE_RATIO)
+    }
+
+    fn estimate_with_inode_ratio(files: &[TestFile], inode_ratio: u32) -> SpaceEstimate {
+        let mut image = FatImageBuilder::new(32 * MIB, 1024).build(files);
+        // SAFETY: Safe because `image` contains a consistent FAT32 filesystem and outlives `fat_fs`.
+        let fat_fs = unsafe { FatFs::new(image.as_mut_ptr(), image.len(), PhantomData) }.unwrap();
+        let superblock = SuperBlock::from(fat_fs.boot_sector(), false, inode_ratio, 0, false).unwrap();
+        SpaceEstimate::new(&fat_fs, &superblock)
+    }
+
+    #[test]
+    fn counts_relocated_clusters() {
+        let empty = estimate(&[]);
+        assert_eq!(empty.relocated_clusters, 0);
+        assert!(empty.metadata_clusters > 0);
+
+        // the large file covers the metadata of the second block group
+        let file = TestFile::RegularFile { name: "file".to_string(), size: 24 * MIB as u32 };
+        let with_file = estimate(&[file]);
+        assert_eq!(with_file.metadata_clusters, empty.metadata_clusters);
+        assert!(with_file.relocated_clusters > 0);
+        assert_eq!(with_file.free_clusters, empty.free_clusters - 24 * 1024);
+        assert!(with_file.remaining_clusters().unwrap() < empty.remaining_clusters().unwrap());
+        assert!(with_file.check_relocation().is_ok());
+    }
+
+    #[test]
+    fn full_filesystem_does_not_fit() {
+        let files: Vec<TestFile> = (0..32)
+            .map(|idx| TestFile::RegularFile {
+                name: idx.to_string(),
+                size: if idx == 0 { 600 * 1024 } else { MIB } as u32,
+            })
+            .collect();
+        let estimate = estimate(&files);
+        assert_eq!(estimate.remaining_clusters(), None);
+        assert!(estimate.check_relocation().is_err());
+    }
+
+    #[test]
+    fn warns_about_blocks_larger_than_pages() {
+        let warning = |cluster_size, page_size| {
+            let mut image = FatImageBuilder::new(64 * MIB, cluster_size).build(&[]);
+            // SAFETY: Safe because `image` contains a consistent FAT32 filesystem and outlives `fat_fs`.
+            let fat_fs = unsafe { FatFs::new(image.as_mut_ptr(), image.len(), PhantomData) }.unwrap();
+            let superblock = SuperBlock::from(fat_fs.boot_sector(), false, DEFAULT_INODE_RATIO, 0, false).unwrap();
+            let bigalloc_superblock = SuperBlock::from(fat_fs.boot_sector(), true, DEFAULT_INODE_RATIO, 0, false).ok();
+            LargeBlockWarning::with_page_size(&fat_fs, &superblock, bigalloc_superblock.as_ref(), page_size)
+        };
+
+        assert_eq!(warning(4096, 4096), None);
+        let large_blocks = warning(16384, 4096).unwrap();
+        assert_eq!(large_blocks.block_size, 16384);
+        let bigalloc_estimate = large_blocks.bigalloc_estimate.unwrap();
+        // bigalloc has smaller block bitmaps and inode tables, since the inode ratio applies to blocks of 4 KiB
+        assert_ne!(bigalloc_estimate, large_blocks.estimate);
+        assert_eq!(warning(16384, 65536).unwrap().page_size, 65536);
+    }
+
+    #[test]
+    fn directories_are_not_relocated() {
+        // the inode table of the first block group covers the start of the data region
+        let empty = estimate_with_inode_ratio(&[], MIN_INODE_RATIO);
+        assert_eq!(empty.directory_clusters, 1);
+        assert_eq!(empty.relocated_clusters, 0);
+
+        let directory = TestFile::Directory {
+            name: "dir".to_string(),
+            children: vec![TestFile::RegularFile { name: "file".to_string(), size: 2048 }],
+        };
+        let with_directory = estimate_with_inode_ratio(&[directory], MIN_INODE_RATIO);
+        assert_eq!(with_directory.directory_clusters, 2);
+        assert_eq!(with_directory.relocated_clusters, 2);
+        assert_eq!(
+            with_directory.remaining_clusters().unwrap(),
+            empty.remaining_clusters().unwrap() - 2
+        );
+    }
+}
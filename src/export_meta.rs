@@ -0,0 +1,241 @@
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+
+use anyhow::{bail, Context, Result};
+
+use crate::diff_meta::MetadataBlocks;
+use crate::ext4::{decode_dentry_len, Ext4DentryTail, InodeNo, FIRST_BLOCK_PADDING, ROOT_INODE_NO};
+use crate::util::FromU32;
+
+/// The characters of scrambled names. It contains neither '.' nor '+', so a scrambled name never collides with ".",
+/// ".." or "lost+found", which are kept.
+const SCRAMBLED_NAME_CHARS: &[u8; 64] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz-_";
+/// The length of the fixed part of a dentry: the inode number, the dentry length, the name length and the file type
+const DENTRY_HEADER_LEN: usize = 8;
+
+/// What `export_metadata` has written
+#[derive(Debug, Default, PartialEq)]
+pub struct MetadataExport {
+    /// the metadata blocks that are not entirely zero, including the directory blocks
+    pub block_count: usize,
+    pub directory_block_count: usize,
+    pub scrambled_name_count: usize,
+}
+
+/// Writes the metadata of the ext4 filesystem in `partition` to `output` like `e2image -r`: `output` becomes a sparse
+/// file as large as the filesystem, which contains the metadata blocks and the directory blocks at their offsets and
+/// holes instead of the file contents, so e2fsck and debugfs can examine it like the filesystem itself. Unless
+/// `keep_names` is set, every name except ".", ".." and "lost+found" is replaced by a name of the same length, like
+/// `e2image -s` does, and the checksums of the directory blocks are updated. Fast symlinks keep their targets, which
+/// are stored in their inodes.
+pub fn export_metadata(partition: &[u8], output: &File, keep_names: bool) -> Result<MetadataExport> {
+    let metadata = MetadataBlocks::new(partition)?;
+    let superblock = &metadata.superblock;
+    let block_size = usize::fromx(superblock.block_size());
+    let fs_len = superblock.block_count_with_padding() * block_size;
+    if partition.len() < fs_len {
+        bail!("The partition is smaller than the ext4 filesystem");
+    }
+    output.set_len(u64::try_from(fs_len)?)?;
+
+    let mut export = MetadataExport::default();
+    let mut write_block = |block_idx: u64, block: &[u8]| -> Result<()> {
+        if block.iter().any(|&byte| byte != 0) {
+            output.write_all_at(block, block_idx * u64::try_from(block_size)?)?;
+            export.block_count += 1;
+        }
+        Ok(())
+    };
+    let block = |block_idx: u64| -> Result<Vec<u8>> {
+        let start = usize::try_from(block_idx)? * block_size;
+        partition
+            .get(start..start + block_size)
+            .map(<[u8]>::to_vec)
+            .with_context(|| format!("Block {} lies outside the partition", block_idx))
+    };
+
+    for block_idx in metadata.blocks.iter().flat_map(Clone::clone) {
+        let mut data = block(block_idx)?;
+        if block_idx == 0 {
+            // the bytes in front of the superblock are not part of the filesystem, e.g. the remnants of a boot sector
+            data[..FIRST_BLOCK_PADDING.min(block_size)].fill(0);
+        }
+        write_block(block_idx, &data)?;
+    }
+    for directory in &metadata.directories {
+        let mut scrambler = NameScrambler::new(directory.inode_no);
+        let seed = Ext4DentryTail::directory_seed(superblock.checksum_seed(), directory.inode_no, directory.generation);
+        for block_idx in directory.blocks.iter().flat_map(Clone::clone) {
+            let mut data = block(block_idx)?;
+            let has_tail = superblock.has_metadata_csum() && Ext4DentryTail::is_present(&data);
+            if !keep_names {
+                let dentries_len = if has_tail {
+                    block_size - Ext4DentryTail::LEN
+                } else {
+                    block_size
+                };
+                scrambler
+                    .scramble_block(&mut data[..dentries_len])
+                    .with_context(|| format!("Unable to scramble the names in block {}", block_idx))?;
+                if has_tail {
+                    Ext4DentryTail::write(&mut data, seed);
+                }
+            }
+            write_block(block_idx, &data)?;
+            export.directory_block_count += 1;
+        }
+        export.scrambled_name_count += scrambler.scrambled_name_count();
+    }
+    output.sync_all()?;
+    Ok(export)
+}
+
+/// Replaces the names in the blocks of one directory by names of the same length that are unique within the directory
+struct NameScrambler {
+    inode_no: InodeNo,
+    /// the number of names scrambled so far, by their length
+    counters: [u64; 256],
+}
+
+impl NameScrambler {
+    fn new(inode_no: InodeNo) -> Self {
+        Self { inode_no, counters: [0; 256] }
+    }
+
+    fn scrambled_name_count(&self) -> usize {
+        self.counters.iter().sum::<u64>() as usize
+    }
+
+    /// Scrambles the names of the dentries in `dentries`, a directory block without its tail, and zeroes the bytes
+    /// after each name, which may contain the remnants of other names.
+    fn scramble_block(&mut self, dentries: &mut [u8]) -> Result<()> {
+        let mut offset = 0;
+        while offset < dentries.len() {
+            let header = dentries
+                .get(offset..offset + DENTRY_HEADER_LEN)
+                .context("A dentry crosses the end of the block")?;
+            let dentry_len = decode_dentry_len(u16::from_le_bytes([header[4], header[5]]));
+            let name_len = usize::from(header[6]);
+            if dentry_len < DENTRY_HEADER_LEN + name_len || offset + dentry_len > dentries.len() {
+                bail!("The dentry at byte {} is damaged", offset);
+            }
+            let (name, slack) = dentries[offset + DENTRY_HEADER_LEN..offset + dentry_len].split_at_mut(name_len);
+            // unused dentries, e.g. the one that fills an empty block, have no name
+            if !name.is_empty() && !self.is_kept(name) {
+                self.scramble(name)?;
+            }
+            slack.fill(0);
+            offset += dentry_len;
+        }
+        Ok(())
+    }
+
+    fn is_kept(&self, name: &[u8]) -> bool {
+        name == b"." || name == b".." || (self.inode_no == ROOT_INODE_NO && name == b"lost+found")
+    }
+
+    /// Overwrites `name` with the next unused name of its length, which consists of `SCRAMBLED_NAME_CHARS`.
+    fn scramble(&mut self, name: &mut [u8]) -> Result<()> {
+        let counter = &mut self.counters[name.len()];
+        let capacity = u32::try_from(name.len())
+            .ok()
+            .and_then(|len| (SCRAMBLED_NAME_CHARS.len() as u64).checked_pow(len));
+        if matches!(capacity, Some(capacity) if *counter >= capacity) {
+            bail!(
+                "Directory {} has more than {} names of {} bytes, which cannot be scrambled; export it with \
+                 --keep-names",
+                self.inode_no,
+                capacity.unwrap(),
+                name.len()
+            );
+        }
+        let mut remaining = *counter;
+        for byte in name.iter_mut().rev() {
+            *byte = SCRAMBLED_NAME_CHARS[(remaining % SCRAMBLED_NAME_CHARS.len() as u64) as usize];
+            remaining /= SCRAMBLED_NAME_CHARS.len() as u64;
+        }
+        *counter += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::NamedTempFile;
+
+    use super::*;
+    use crate::conversion::convert_slice;
+    use crate::diff_meta::{read_inode, MetadataDump};
+    use crate::ext4::LOST_FOUND_INODE_NO;
+    use crate::fat::{FatImage, FatImageBuilder, TestFile};
+    use crate::lohi::LoHi;
+    use crate::options::ConversionOptions;
+
+    const MIB: usize = 1024 * 1024;
+
+    #[test]
+    fn exports_metadata_without_contents_and_names() {
+        let files = [
+            TestFile::RegularFileWithContent {
+                name: "secret.txt".to_string(),
+                content: vec![0xA5; 10_000],
+            },
+            TestFile::Directory {
+                name: "private".to_string(),
+                children: (0..100)
+                    .map(|idx| TestFile::RegularFile { name: format!("f{}", idx), size: 10 })
+                    .collect(),
+            },
+        ];
+        let mut image: FatImage = FatImageBuilder::new(32 * MIB, 1024).build(&files);
+        convert_slice(image.as_mut_slice(), &ConversionOptions::default()).unwrap();
+        let partition = image.as_mut_slice();
+
+        let output = NamedTempFile::new().unwrap();
+        let export = export_metadata(partition, output.as_file(), false).unwrap();
+        let exported = std::fs::read(output.path()).unwrap();
+        assert!(exported.len() <= partition.len());
+        // the root directory, lost+found (which spans several blocks) and "private"
+        assert!(export.directory_block_count >= 3);
+        assert_eq!(export.scrambled_name_count, 2 + 100);
+        assert_eq!(
+            MetadataDump::new(&exported)
+                .unwrap()
+                .compare(&MetadataDump::new(partition).unwrap()),
+            vec![]
+        );
+
+        let secret = read_inode(partition, LOST_FOUND_INODE_NO + 1).unwrap();
+        // SAFETY: Safe because the file is small enough for its extents to fit into the inode.
+        let extent = unsafe { secret.extents[1].extent };
+        let block_size = MetadataBlocks::new(partition).unwrap().superblock.block_size() as usize;
+        let physical_start: u64 = LoHi::new(&extent.physical_start_lo, &extent.physical_start_hi).get();
+        let start = usize::try_from(physical_start).unwrap() * block_size;
+        assert_eq!(partition[start], 0xA5);
+        assert!(exported[start..start + 10_000].iter().all(|&byte| byte == 0));
+
+        let contains = |bytes: &[u8], name: &[u8]| bytes.windows(name.len()).any(|window| window == name);
+        assert!(!contains(&exported, b"secret.txt"));
+        assert!(!contains(&exported, b"private"));
+        assert!(contains(&exported, b"lost+found"));
+
+        let output = NamedTempFile::new().unwrap();
+        let export = export_metadata(partition, output.as_file(), true).unwrap();
+        assert_eq!(export.scrambled_name_count, 0);
+        assert!(contains(&std::fs::read(output.path()).unwrap(), b"secret.txt"));
+    }
+
+    #[test]
+    fn scrambled_names_are_unique() {
+        let mut scrambler = NameScrambler::new(ROOT_INODE_NO);
+        let mut names: Vec<[u8; 1]> = vec![[0]; SCRAMBLED_NAME_CHARS.len()];
+        for name in &mut names {
+            scrambler.scramble(name).unwrap();
+        }
+        names.sort_unstable();
+        names.dedup();
+        assert_eq!(names.len(), SCRAMBLED_NAME_CHARS.len());
+        assert!(scrambler.scramble(&mut [0]).is_err());
+        assert!(scrambler.scramble(&mut [0; 2]).is_ok());
+    }
+}
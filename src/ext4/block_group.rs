@@ -1,7 +1,10 @@
 use std::mem::{size_of, MaybeUninit};
 use std::ops::Range;
+use std::slice;
 
+use anyhow::{bail, Result};
 use num::Integer;
+use rayon::prelude::*;
 
 use crate::bitmap::Bitmap;
 use crate::ext4::{
@@ -10,34 +13,61 @@ use crate::ext4::{
 };
 use crate::util::{AddUsize, FromU32};
 
+/// The number of bytes of an inode table that a thread zeroes at a time, see `BlockGroup::init_inodes`
+const INODE_TABLE_ZEROING_CHUNK_SIZE: usize = 1 << 16;
+
+/// The metadata of a block group. The bitmaps and the inode table are only written once the block group receives data
+/// or inodes, so that block groups without either can keep the `BLOCK_UNINIT` and `INODE_UNINIT` flags and are never
+/// written at all.
 pub struct BlockGroup<'a> {
     pub superblock: Option<&'a mut MaybeUninit<SuperBlock>>,
     pub gdt: Option<&'a mut [MaybeUninit<Ext4GroupDescriptor>]>,
-    pub data_block_bitmap: Bitmap<'a>,
-    pub inode_bitmap: Bitmap<'a>,
-    pub inode_table_ptr: *mut u8,
-    pub inode_table_len: usize,
+    data_block_bitmap: Bitmap<'a>,
+    inode_bitmap: Bitmap<'a>,
+    inode_table_ptr: *mut u8,
+    inode_table_len: usize,
+    info: Ext4BlockGroupConstructionInfo,
+    /// The clusters marked as used, relative to the start of the block group. They are written to the block bitmap
+    /// by `write_block_bitmap`.
+    used_cluster_ranges: Vec<Range<BlockIdx>>,
+    inodes_initialized: bool,
 }
 
+/// An inode in the inode table: its fields and the rest of its slot, which holds its extended attributes, see
+/// `IN_INODE_XATTR_LEN`
+pub type InodeSlot<'a> = (&'a mut InodeInner, &'a mut [u8]);
+
+// SAFETY: Safe because `inode_table_ptr` points to the inode table, which belongs exclusively to this block group like
+// the memory of the other fields.
+unsafe impl Send for BlockGroup<'_> {}
+
 impl<'a> BlockGroup<'a> {
+    /// Only the inodes of the first block group are initialized, since it contains the special inodes.
     /// PANICS: Panics if `block_group_metadata.len() != info.overhead * info.block_size`.
     pub fn new(mut block_group_metadata: &'a mut [u8], info: Ext4BlockGroupConstructionInfo) -> Self {
         let remaining_blocks = &mut block_group_metadata;
         let superblock = Self::init_superblock(remaining_blocks, info);
         let gdt = Self::init_gdt(remaining_blocks, info);
-        let data_block_bitmap = Self::init_data_block_bitmap(remaining_blocks, info);
-        let inode_bitmap = Self::init_inode_bitmap(remaining_blocks, info);
-        let (inode_table_ptr, inode_table_len) = Self::init_inode_table(remaining_blocks, info);
+        let data_block_bitmap = Bitmap::new(Self::split_off_blocks(remaining_blocks, 1, info));
+        let inode_bitmap = Bitmap::new(Self::split_off_blocks(remaining_blocks, 1, info));
+        let inode_table = Self::split_off_blocks(remaining_blocks, info.inode_table_block_count, info);
         assert!(remaining_blocks.is_empty());
 
-        Self {
+        let mut instance = Self {
             superblock,
             gdt,
             data_block_bitmap,
             inode_bitmap,
-            inode_table_ptr,
-            inode_table_len,
+            inode_table_ptr: inode_table.as_mut_ptr(),
+            inode_table_len: inode_table.len(),
+            info,
+            used_cluster_ranges: Vec::new(),
+            inodes_initialized: false,
+        };
+        if info.is_first_block_group {
+            instance.init_inodes();
         }
+        instance
     }
 
     fn init_superblock<'b>(
@@ -82,59 +112,59 @@ impl<'a> BlockGroup<'a> {
         }
     }
 
-    fn init_data_block_bitmap<'b>(
+    /// Removes the first `block_count` blocks from `block_group_metadata` and returns them.
+    fn split_off_blocks<'b>(
         block_group_metadata: &'b mut &'a mut [u8],
+        block_count: BlockCount,
         info: Ext4BlockGroupConstructionInfo,
-    ) -> Bitmap<'a> {
+    ) -> &'a mut [u8] {
         let metadata_blocks = std::mem::take(block_group_metadata);
-        let (bitmap_bytes, remaining_blocks) = Self::split_at_block_mut(metadata_blocks, 1, info);
+        let (blocks, remaining_blocks) = Self::split_at_block_mut(metadata_blocks, block_count, info);
         *block_group_metadata = remaining_blocks;
+        blocks
+    }
 
-        let mut bitmap = Bitmap::new(bitmap_bytes);
+    /// Writes the block bitmap if any clusters were marked as used, otherwise the block group's `BLOCK_UNINIT` flag
+    /// remains set and the bitmap is not needed. The last block group's bitmap is always written.
+    pub fn write_block_bitmap(&mut self) {
+        if self.used_cluster_ranges.is_empty() && !self.info.is_last_block_group {
+            return;
+        }
+
+        // one bit per cluster
+        let bitmap = &mut self.data_block_bitmap;
         bitmap.clear_all();
-        for overhead_block_idx in 0..info.overhead {
-            bitmap.set(overhead_block_idx);
+        for overhead_cluster_idx in 0..self.info.overhead_cluster_count() {
+            bitmap.set(overhead_cluster_idx);
+        }
+        for nonexistent_cluster_idx in self.info.cluster_count()..bitmap.len() {
+            bitmap.set(nonexistent_cluster_idx);
         }
-        for nonexistent_block_idx in info.blocks_count..bitmap.len() {
-            bitmap.set(nonexistent_block_idx);
+        for cluster_idx in self.used_cluster_ranges.drain(..).flatten() {
+            bitmap.set(cluster_idx);
         }
-        bitmap
     }
 
-    fn init_inode_bitmap<'b>(
-        block_group_metadata: &'b mut &'a mut [u8],
-        info: Ext4BlockGroupConstructionInfo,
-    ) -> Bitmap<'a> {
-        let metadata_blocks = std::mem::take(block_group_metadata);
-        let (bitmap_bytes, remaining_blocks) = Self::split_at_block_mut(metadata_blocks, 1, info);
-        *block_group_metadata = remaining_blocks;
-
-        let mut bitmap = Bitmap::new(bitmap_bytes);
+    /// Writes the inode bitmap and zeroes the inode table, using the global rayon thread pool.
+    fn init_inodes(&mut self) {
+        let bitmap = &mut self.inode_bitmap;
         bitmap.clear_all();
-        if info.is_first_block_group {
-            Self::mark_special_inodes_as_used(&mut bitmap);
+        if self.info.is_first_block_group {
+            for used_inode_no in SPECIAL_INODES {
+                bitmap.set(usize::fromx(used_inode_no - FIRST_EXISTING_INODE));
+            }
         }
-        for nonexistent_inode_idx in usize::fromx(info.inodes_count)..bitmap.len() {
+        for nonexistent_inode_idx in usize::fromx(self.info.inodes_count)..bitmap.len() {
             bitmap.set(nonexistent_inode_idx);
         }
-        bitmap
-    }
-
-    fn mark_special_inodes_as_used(inode_bitmap: &mut Bitmap) {
-        for used_inode_idx in SPECIAL_INODES {
-            inode_bitmap.set(usize::fromx(used_inode_idx - FIRST_EXISTING_INODE));
-        }
-    }
-
-    fn init_inode_table<'b>(
-        block_group_metadata: &'b mut &'a mut [u8],
-        info: Ext4BlockGroupConstructionInfo,
-    ) -> (*mut u8, usize) {
-        let metadata_blocks = std::mem::take(block_group_metadata);
-        let (table, remaining_blocks) = Self::split_at_block_mut(metadata_blocks, info.inode_table_block_count, info);
-        *block_group_metadata = remaining_blocks;
-        table.fill(0);
-        (table.as_mut_ptr(), table.len())
+        // SAFETY: Safe because the inode table belongs to this block group and no inode has been handed out yet.
+        let inode_table = unsafe { slice::from_raw_parts_mut(self.inode_table_ptr, self.inode_table_len) };
+        // Zeroing an inode table takes long enough to be worth splitting, since writing the memory of the partition
+        // for the first time means reading it from the partition.
+        inode_table
+            .par_chunks_mut(INODE_TABLE_ZEROING_CHUNK_SIZE)
+            .for_each(|chunk| chunk.fill(0));
+        self.inodes_initialized = true;
     }
 
     fn split_at_block_mut(
@@ -146,34 +176,72 @@ impl<'a> BlockGroup<'a> {
         slice.split_at_mut(mid_byte)
     }
 
-    pub fn mark_relative_range_as_used(&mut self, relative_range: Range<BlockIdx>) {
-        for block_idx in relative_range {
-            self.data_block_bitmap.set(block_idx);
+    /// The number of bytes that `allocate_relative_inode` zeroes before it allocates the next inode, which is the size
+    /// of the inode table if no inode has been allocated in the block group yet, otherwise 0.
+    pub fn pending_inode_table_zeroing(&self) -> usize {
+        if self.inodes_initialized {
+            0
+        } else {
+            self.inode_table_len
         }
     }
 
-    /// PANICS: Panics if `relative_inode_no` is already allocated or is out of bounds.
-    pub fn allocate_relative_inode(&mut self, relative_inode_no: InodeCount, inode_size: u16) -> &'a mut InodeInner {
-        assert!(
-            !self.inode_bitmap.get(usize::fromx(relative_inode_no)),
-            "Tried to allocate already used inode with relative index {}",
-            relative_inode_no
-        );
+    /// `relative_range` is given in clusters relative to the start of the block group. The range is only written to
+    /// the block bitmap by `write_block_bitmap`.
+    pub fn mark_relative_range_as_used(&mut self, relative_range: Range<BlockIdx>) {
+        self.used_cluster_ranges.push(relative_range);
+    }
+
+    /// Initializes the inodes of the block group if this is the first inode allocated in it. Returns an error if
+    /// `relative_inode_no` is already allocated.
+    /// PANICS: Panics if `relative_inode_no` is out of bounds.
+    pub fn allocate_relative_inode(&mut self, relative_inode_no: InodeCount, inode_size: u16) -> Result<InodeSlot<'a>> {
+        if !self.inodes_initialized {
+            self.init_inodes();
+        }
+        if self.inode_bitmap.get(usize::fromx(relative_inode_no)) {
+            bail!("Tried to allocate already used inode with relative index {}", relative_inode_no);
+        }
 
         self.inode_bitmap.set(usize::fromx(relative_inode_no));
         // SAFETY: Safe since the bitmap ensures we don't use the same `relative_inode_no` twice.
-        unsafe { self.get_relative_inode(relative_inode_no, inode_size) }
+        Ok(unsafe { self.get_relative_inode(relative_inode_no, inode_size) })
+    }
+
+    /// Returns the inode `relative_inode_no`, which `allocate_relative_inode` has allocated before.
+    /// SAFETY: Undefined behavior if the inode returned by `allocate_relative_inode` or a previous call is still in
+    /// use.
+    /// PANICS: Panics if `relative_inode_no` is out of bounds.
+    pub unsafe fn allocated_relative_inode(
+        &mut self,
+        relative_inode_no: InodeCount,
+        inode_size: u16,
+    ) -> Result<InodeSlot<'a>> {
+        if !self.inodes_initialized || !self.inode_bitmap.get(usize::fromx(relative_inode_no)) {
+            bail!("Tried to access unused inode with relative index {}", relative_inode_no);
+        }
+        // SAFETY: Safe because the caller guarantees that no other reference to the inode is in use.
+        Ok(unsafe { self.get_relative_inode(relative_inode_no, inode_size) })
     }
 
     /// SAFETY: Undefined behavior if the function is called twice with the same `relative_inode_no`.
-    unsafe fn get_relative_inode(&mut self, relative_inode_no: InodeCount, inode_size: u16) -> &'a mut InodeInner {
+    /// PANICS: Panics if `inode_size` is smaller than `InodeInner`.
+    unsafe fn get_relative_inode(&mut self, relative_inode_no: InodeCount, inode_size: u16) -> InodeSlot<'a> {
         let offset = usize::fromx(relative_inode_no) * usize::from(inode_size);
         assert!(offset + usize::from(inode_size) <= self.inode_table_len);
+        let rest_len = usize::from(inode_size)
+            .checked_sub(size_of::<InodeInner>())
+            .expect("The inodes are smaller than `InodeInner`");
         // SAFETY: safe because the inode is within the partition.
-        let ptr = unsafe { self.inode_table_ptr.add_usize(offset) as *mut InodeInner };
-        // SAFETY: safe because we have exclusive access to that inode and because its memory was initialized with
-        // zeroes.
-        unsafe { &mut *ptr }
+        let ptr = unsafe { self.inode_table_ptr.add_usize(offset) };
+        // SAFETY: safe because we have exclusive access to that inode, because its memory was initialized with
+        // zeroes, and because the fields and the rest of the slot do not overlap.
+        unsafe {
+            (
+                &mut *(ptr as *mut InodeInner),
+                slice::from_raw_parts_mut(ptr.add(size_of::<InodeInner>()), rest_len),
+            )
+        }
     }
 }
 
@@ -197,7 +265,9 @@ pub struct Ext4BlockGroupConstructionInfo {
     pub inode_table_block_count: BlockCount,
     pub superblock_construction_info: SuperBlockConstructionInfo,
     pub block_size: BlockSize,
+    pub blocks_per_cluster: u32,
     pub is_first_block_group: bool,
+    pub is_last_block_group: bool,
     pub overhead: BlockCount,
 }
 
@@ -236,8 +306,20 @@ impl Ext4BlockGroupConstructionInfo {
             inode_table_block_count: superblock.inode_table_block_count(),
             superblock_construction_info,
             block_size: superblock.block_size(),
+            blocks_per_cluster: superblock.blocks_per_cluster(),
             overhead: superblock.block_group_overhead(has_superblock),
             is_first_block_group: block_group_idx == 0,
+            is_last_block_group: block_group_idx == superblock.block_group_count() - 1,
         }
     }
+
+    /// The number of clusters in the block group. A block group always consists of whole clusters.
+    pub fn cluster_count(&self) -> BlockCount {
+        self.blocks_count / BlockCount::fromx(self.blocks_per_cluster)
+    }
+
+    /// The number of clusters containing block group metadata. With bigalloc, the last one may be partially unused.
+    pub fn overhead_cluster_count(&self) -> BlockCount {
+        self.overhead.div_ceil(&BlockCount::fromx(self.blocks_per_cluster))
+    }
 }
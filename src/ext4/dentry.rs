@@ -4,10 +4,16 @@ use std::mem::size_of;
 use anyhow::{bail, Result};
 use num::Integer;
 
-use crate::ext4::InodeNo;
+use crate::ext4::{crc32c, InodeNo, MAX_BLOCK_SIZE};
+use crate::util::FromU32;
 
-const EXT4_NAME_MAX_LEN: usize = 255;
+pub const EXT4_NAME_MAX_LEN: usize = 255;
 const ALIGNMENT: usize = 4;
+/// The file type that marks an `Ext4DentryTail`
+const DENTRY_TAIL_FILE_TYPE: u8 = 0xDE;
+/// The on-disk `dentry_len` of a dentry that spans an entire 64 KiB block, whose length does not fit into a u16. Like
+/// the kernel's `EXT4_MAX_REC_LEN`, it is not a multiple of 4, so it cannot be mistaken for an actual length.
+const MAX_BLOCK_DENTRY_LEN: u16 = 0xFFFF;
 
 pub struct Ext4Dentry {
     pub inner: Ext4DentrySized,
@@ -18,16 +24,45 @@ pub struct Ext4Dentry {
 #[repr(C, packed)]
 pub struct Ext4DentrySized {
     inode_no: InodeNo,
-    /// Always a multiple of 4 to ensure alignment
+    /// Always a multiple of 4 to ensure alignment, except for `MAX_BLOCK_DENTRY_LEN`, see `encode_dentry_len`
     dentry_len: u16,
-    name_len: u16,
+    name_len: u8,
+    /// Requires the `filetype` feature, otherwise this is the high byte of `name_len`
+    file_type: FileType,
+}
+
+/// The end of every directory block if the metadata_csum feature is enabled. It looks like an unused dentry to drivers
+/// that do not know it, and holds the checksum of the rest of the block.
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+pub struct Ext4DentryTail {
+    reserved_inode_no: InodeNo,
+    dentry_len: u16,
+    reserved_name_len: u8,
+    file_type: u8,
+    checksum: u32,
+}
+
+/// The type of the file that a dentry points to, stored in the dentry so that directory listings do not need to read
+/// the inode.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum FileType {
+    Unknown = 0,
+    RegularFile = 1,
+    Directory = 2,
+    CharDevice = 3,
+    BlockDevice = 4,
+    Fifo = 5,
+    Socket = 6,
+    Symlink = 7,
 }
 
 impl Ext4Dentry {
     // = aligned_length(EXT4_NAME_MAX_LEN + size_of::<Ext4DentrySized>(), ALIGNMENT);
     pub const MAX_LEN: usize = EXT4_NAME_MAX_LEN + 1 + size_of::<Ext4DentrySized>();
 
-    pub fn new(inode_no: InodeNo, name: String) -> Result<Self> {
+    pub fn new(inode_no: InodeNo, name: String, file_type: FileType) -> Result<Self> {
         // FAT32 allows names up to 255 UCS-2 characters, which may be longer than 255 bytes
         if name.len() > EXT4_NAME_MAX_LEN {
             bail!("Length of file name '{}' exceeds 255 bytes", name);
@@ -36,7 +71,8 @@ impl Ext4Dentry {
         let inner = Ext4DentrySized {
             inode_no,
             dentry_len,
-            name_len: u16::try_from(name.len()).unwrap(),
+            name_len: u8::try_from(name.len()).unwrap(),
+            file_type,
         };
         Ok(Self { inner, name })
     }
@@ -54,14 +90,125 @@ impl Ext4Dentry {
 }
 
 impl Ext4DentrySized {
-    /// PANICS: Panics if incrementing the dentry length by `num` would break alignment or cause `self.dentry_len` to
-    /// overflow.
-    pub fn increment_dentry_len(&mut self, num: u16) {
-        assert!(usize::from(num) % ALIGNMENT == 0);
-        self.dentry_len = self.dentry_len.checked_add(num).unwrap();
+    /// Returns a dentry that marks `dentry_len` bytes as unused, e.g. an entire directory block that contains no files.
+    pub fn inode_no(&self) -> InodeNo {
+        self.inode_no
+    }
+
+    /// PANICS: Panics if `dentry_len` is not a multiple of 4 or larger than `MAX_BLOCK_SIZE`.
+    pub fn unused(dentry_len: usize) -> Self {
+        assert!(dentry_len % ALIGNMENT == 0);
+        Self {
+            inode_no: 0,
+            dentry_len: encode_dentry_len(dentry_len),
+            name_len: 0,
+            file_type: FileType::Unknown,
+        }
+    }
+
+    /// The length of the dentry in bytes, including its name and padding.
+    pub fn dentry_len(&self) -> usize {
+        decode_dentry_len(self.dentry_len)
+    }
+
+    /// PANICS: Panics if incrementing the dentry length by `num` would break alignment or make the dentry larger than
+    /// `MAX_BLOCK_SIZE`.
+    pub fn increment_dentry_len(&mut self, num: usize) {
+        assert!(num % ALIGNMENT == 0);
+        self.dentry_len = encode_dentry_len(self.dentry_len() + num);
+    }
+}
+
+impl Ext4DentryTail {
+    pub const LEN: usize = size_of::<Self>();
+
+    /// Returns the tail of a directory block whose other bytes have the checksum `checksum`.
+    pub fn new(checksum: u32) -> Self {
+        Self {
+            reserved_inode_no: 0,
+            dentry_len: Self::LEN as u16,
+            reserved_name_len: 0,
+            file_type: DENTRY_TAIL_FILE_TYPE,
+            checksum,
+        }
+    }
+
+    /// Returns whether `block` ends with a tail.
+    pub fn is_present(block: &[u8]) -> bool {
+        match block.len().checked_sub(Self::LEN) {
+            // SAFETY: Safe because the range is as long as an `Ext4DentryTail`, which only consists of integers.
+            Some(start) => {
+                let tail = unsafe { (block[start..].as_ptr() as *const Self).read_unaligned() };
+                tail.file_type == DENTRY_TAIL_FILE_TYPE && usize::from(tail.dentry_len) == Self::LEN
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the seed of the checksums of the blocks of the directory with the inode `inode_no` and the generation
+    /// `generation`, given the filesystem's `checksum_seed`.
+    pub fn directory_seed(checksum_seed: u32, inode_no: InodeNo, generation: u32) -> u32 {
+        let inode_seed = crc32c(checksum_seed, &inode_no.to_le_bytes());
+        crc32c(inode_seed, &generation.to_le_bytes())
+    }
+
+    /// Writes the tail to the end of `block`, with the checksum of the rest of `block`. `directory_seed` is the result
+    /// of `directory_seed`.
+    /// PANICS: Panics if `block` is shorter than `Self::LEN`.
+    pub fn write(block: &mut [u8], directory_seed: u32) {
+        let (dentries, tail) = block.split_at_mut(block.len() - Self::LEN);
+        let tail_value = Self::new(crc32c(directory_seed, dentries));
+        // SAFETY: Safe because `tail` is as long as an `Ext4DentryTail`, which is packed and can be written unaligned.
+        unsafe { (tail.as_mut_ptr() as *mut Self).write_unaligned(tail_value) };
     }
 }
 
 fn aligned_length(n: usize, alignment: usize) -> usize {
     n.next_multiple_of(&alignment)
 }
+
+/// Encodes the length of a dentry like the kernel's `ext4_rec_len_to_disk`: a dentry cannot cross a block boundary, so
+/// the only length that does not fit into a u16 is that of a dentry spanning an entire 64 KiB block, which is stored as
+/// `MAX_BLOCK_DENTRY_LEN`. This happens for the unused dentry of an empty block and for a single dentry padded to the
+/// end of its block if the filesystem has no checksum tails. Block sizes above 64 KiB are not supported, so their
+/// encoding is not needed.
+/// PANICS: Panics if `dentry_len` is larger than `MAX_BLOCK_SIZE`.
+fn encode_dentry_len(dentry_len: usize) -> u16 {
+    match u16::try_from(dentry_len) {
+        Ok(dentry_len) => dentry_len,
+        Err(_) if dentry_len == usize::fromx(MAX_BLOCK_SIZE) => MAX_BLOCK_DENTRY_LEN,
+        Err(_) => panic!("A dentry of {} bytes is larger than a block", dentry_len),
+    }
+}
+
+/// Decodes a length encoded by `encode_dentry_len`.
+pub fn decode_dentry_len(dentry_len: u16) -> usize {
+    match dentry_len {
+        MAX_BLOCK_DENTRY_LEN => usize::fromx(MAX_BLOCK_SIZE),
+        dentry_len => usize::from(dentry_len),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dentry_spanning_64_kib_block() {
+        let mut dentry = Ext4DentrySized::unused(usize::fromx(MAX_BLOCK_SIZE) - 12);
+        assert_eq!(dentry.dentry_len(), 65524);
+        dentry.increment_dentry_len(12);
+        assert_eq!({ dentry.dentry_len }, MAX_BLOCK_DENTRY_LEN);
+        assert_eq!(dentry.dentry_len(), 65536);
+
+        assert_eq!({ Ext4DentrySized::unused(65536).dentry_len }, MAX_BLOCK_DENTRY_LEN);
+        assert_eq!(Ext4DentrySized::unused(4096).dentry_len(), 4096);
+    }
+
+    #[test]
+    #[should_panic]
+    fn dentry_cannot_exceed_64_kib() {
+        let mut dentry = Ext4DentrySized::unused(65536);
+        dentry.increment_dentry_len(4);
+    }
+}
@@ -0,0 +1,362 @@
+use std::convert::TryFrom;
+use std::mem::size_of;
+
+use anyhow::{Context, Result};
+use num::Integer;
+
+use crate::ext4::{BlockCount, BlockSize, Ext4Dentry, Ext4DentrySized, Ext4DentryTail, ExtentTree};
+
+/// The size that mke2fs preallocates for lost+found, so that fsck can reconnect orphaned files without allocating
+/// blocks
+const LOST_FOUND_MIN_SIZE: usize = 16 * 1024;
+
+/// Keeps track of where the next dentry of a directory is placed, and of the clusters, the size and the extent tree
+/// that the directory has as a result, see `DirBuilder`.
+///
+/// Dentries never cross a block boundary. A directory consists of clusters that each contain `blocks_per_cluster`
+/// blocks; the first cluster is allocated when the directory is created. Every cluster is a separate extent. If the
+/// metadata_csum feature is enabled, every block ends with an `Ext4DentryTail`, which is not available to dentries.
+pub struct DirectoryLayout {
+    block_size: usize,
+    blocks_per_cluster: usize,
+    /// the length of the `Ext4DentryTail` at the end of every block, or 0 without metadata_csum
+    tail_len: usize,
+    /// Invariant: `position_in_block <= block_size - tail_len`
+    position_in_block: usize,
+    /// Invariant: `block_in_cluster < blocks_per_cluster`
+    block_in_cluster: usize,
+    /// the clusters of the directory, including the one that is being written
+    /// Invariant: `cluster_count * blocks_per_cluster` fits into a u32, like every file's block count
+    cluster_count: usize,
+}
+
+impl DirectoryLayout {
+    pub fn new(block_size: usize, blocks_per_cluster: usize, has_checksum_tail: bool) -> Self {
+        assert!(blocks_per_cluster > 0);
+        Self {
+            block_size,
+            blocks_per_cluster,
+            tail_len: if has_checksum_tail { Ext4DentryTail::LEN } else { 0 },
+            position_in_block: 0,
+            block_in_cluster: 0,
+            cluster_count: 1,
+        }
+    }
+
+    /// True if a dentry of length `dentry_len` fits into the current block.
+    pub fn fits(&self, dentry_len: usize) -> bool {
+        dentry_len <= self.remaining_space()
+    }
+
+    /// Skips the rest of the current block. Returns true if the next block is in a new cluster, in which case the
+    /// caller has to add a cluster to the directory. Fails if the directory would have more than `u32::MAX` blocks.
+    pub fn next_block(&mut self) -> Result<bool> {
+        if self.block_in_cluster + 1 < self.blocks_per_cluster {
+            self.position_in_block = 0;
+            self.block_in_cluster += 1;
+            Ok(false)
+        } else {
+            self.add_cluster()?;
+            self.position_in_block = 0;
+            self.block_in_cluster = 0;
+            Ok(true)
+        }
+    }
+
+    /// Appends a cluster to the directory without writing to it, e.g. to preallocate lost+found. Fails if the
+    /// directory would have more than `u32::MAX` blocks.
+    pub fn add_cluster(&mut self) -> Result<()> {
+        // This only fails with billions of files, so it's just a formality.
+        self.cluster_count = self
+            .cluster_count
+            .checked_add(1)
+            .filter(|&cluster_count| {
+                let block_count = cluster_count.checked_mul(self.blocks_per_cluster);
+                matches!(block_count, Some(block_count) if u32::try_from(block_count).is_ok())
+            })
+            .context("Directory contains too many files")?;
+        Ok(())
+    }
+
+    pub fn cluster_count(&self) -> usize {
+        self.cluster_count
+    }
+
+    /// The first logical block of the last cluster, i.e. the logical start of its extent.
+    pub fn last_cluster_logical_start(&self) -> u32 {
+        u32::try_from((self.cluster_count - 1) * self.blocks_per_cluster).expect("The block count fits into a u32")
+    }
+
+    /// The size of the directory in bytes, i.e. its `i_size`.
+    pub fn size(&self) -> u64 {
+        (self.cluster_count * self.blocks_per_cluster * self.block_size) as u64
+    }
+
+    /// The clusters that the directory occupies, including those of its extent tree.
+    pub fn used_clusters(&self) -> BlockCount {
+        let block_size = BlockSize::try_from(self.block_size).expect("The block size fits into a u32");
+        self.cluster_count + ExtentTree::required_block_count(self.cluster_count, block_size)
+    }
+
+    /// Reserves `dentry_len` bytes at `self.position_in_cluster()`.
+    /// The caller must have checked that the dentry fits into the current block.
+    pub fn advance(&mut self, dentry_len: usize) {
+        debug_assert!(self.fits(dentry_len), "Attempted to write a dentry across a block boundary");
+        self.position_in_block += dentry_len;
+    }
+
+    /// The offset at which the next dentry is placed, relative to the start of the current cluster.
+    pub fn position_in_cluster(&self) -> usize {
+        self.block_start_in_cluster() + self.position_in_block
+    }
+
+    /// The offset of the current block, relative to the start of the current cluster.
+    pub fn block_start_in_cluster(&self) -> usize {
+        self.block_in_cluster * self.block_size
+    }
+
+    pub fn remaining_space(&self) -> usize {
+        self.dentry_space() - self.position_in_block
+    }
+
+    /// The number of bytes of every block that are available to dentries.
+    pub fn dentry_space(&self) -> usize {
+        self.block_size - self.tail_len
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    pub fn blocks_per_cluster(&self) -> usize {
+        self.blocks_per_cluster
+    }
+
+    /// The number of clusters that lost+found is preallocated with: like mke2fs, at least 16 KiB and two blocks.
+    pub fn lost_found_cluster_count(&self) -> usize {
+        let cluster_size = self.block_size * self.blocks_per_cluster;
+        let min_size = LOST_FOUND_MIN_SIZE.max(2 * self.block_size);
+        min_size.div_ceil(&cluster_size)
+    }
+}
+
+/// Builds the blocks of a linear ext4 directory: places every dentry according to a `DirectoryLayout`, pads the last
+/// dentry of every block to the end of the block's dentry space and writes the block's `Ext4DentryTail` if the
+/// metadata_csum feature is enabled. The caller allocates the clusters and adds them to the directory's extent tree,
+/// and passes the current cluster, i.e. the last one, to every method that writes.
+///
+/// `DentryWriter` and `DryRunDirectoryWriter` both use it, the latter in the simulation mode, in which no cluster is
+/// passed and only the layout is kept track of. This way, the dry run needs exactly as many clusters for a directory as
+/// the actual conversion, and every feature of the directory format that affects where dentries are placed, such as an
+/// htree index, has to be implemented only once.
+pub struct DirBuilder {
+    layout: DirectoryLayout,
+    /// the seed of the checksums in the `Ext4DentryTail` of every block if the metadata_csum feature is enabled and
+    /// the builder is not simulating
+    checksum_seed: Option<u32>,
+    /// the offset of the last dentry in the current block, relative to the start of the current cluster
+    previous_dentry: Option<usize>,
+}
+
+impl DirBuilder {
+    /// Creates a builder for a directory whose first cluster the caller has allocated. `checksum_seed` is the seed of
+    /// the directory's block checksums, see `Ext4DentryTail::directory_seed`, or None without metadata_csum.
+    pub fn new(block_size: usize, blocks_per_cluster: usize, checksum_seed: Option<u32>) -> Self {
+        Self {
+            layout: DirectoryLayout::new(block_size, blocks_per_cluster, checksum_seed.is_some()),
+            checksum_seed,
+            previous_dentry: None,
+        }
+    }
+
+    /// Creates a builder that places dentries like one created by `new`, but is never passed any clusters.
+    pub fn simulation(block_size: usize, blocks_per_cluster: usize, has_checksum_tail: bool) -> Self {
+        Self {
+            layout: DirectoryLayout::new(block_size, blocks_per_cluster, has_checksum_tail),
+            checksum_seed: None,
+            previous_dentry: None,
+        }
+    }
+
+    pub fn layout(&self) -> &DirectoryLayout {
+        &self.layout
+    }
+
+    /// Moves on to the next block, after finishing the current one in `cluster`, if a dentry of `dentry_len` bytes
+    /// does not fit into the current block. Returns true if the next block is in a new cluster, which the caller has to
+    /// allocate, prepare with `clear_cluster` and add to the directory before writing to it.
+    pub fn make_room(&mut self, dentry_len: usize, cluster: Option<&mut [u8]>) -> Result<bool> {
+        if self.layout.fits(dentry_len) {
+            return Ok(false);
+        }
+        self.finish_block(cluster);
+        self.layout.next_block()
+    }
+
+    /// Writes `dentry` to `cluster` at the current position and returns its offset in the cluster. The caller must have
+    /// made room for it with `make_room`.
+    pub fn write_dentry(&mut self, dentry: &Ext4Dentry, cluster: Option<&mut [u8]>) -> usize {
+        let dentry_len = usize::from(dentry.dentry_len());
+        let offset = self.layout.position_in_cluster();
+        self.layout.advance(dentry_len);
+        if let Some(cluster) = cluster {
+            let name = dentry.serialize_name();
+            let dentry_bytes = &mut cluster[offset..offset + dentry_len];
+            let name_start = size_of::<Ext4DentrySized>();
+            // SAFETY: Safe because `dentry_bytes` is longer than an `Ext4DentrySized`, which is packed.
+            unsafe { (dentry_bytes.as_mut_ptr() as *mut Ext4DentrySized).write(dentry.inner) };
+            dentry_bytes[name_start..name_start + name.len()].copy_from_slice(&name);
+            self.previous_dentry = Some(offset);
+        }
+        offset
+    }
+
+    /// Appends a cluster to the directory without writing to it, e.g. to preallocate lost+found, see
+    /// `DirectoryLayout::add_cluster`. The caller has to allocate it and prepare it with `clear_cluster`.
+    pub fn add_empty_cluster(&mut self) -> Result<()> {
+        self.layout.add_cluster()
+    }
+
+    /// Turns every block of `cluster` into an empty directory block. Writing to the blocks of a cluster happens one
+    /// after another, but they all become part of the directory at once, so they have to be valid until then.
+    pub fn clear_cluster(&self, cluster: &mut [u8]) {
+        let empty_block_dentry = Ext4DentrySized::unused(self.layout.dentry_space());
+        for block in cluster.chunks_exact_mut(self.layout.block_size()) {
+            // SAFETY: Safe because the block is larger than an `Ext4DentrySized`, which is packed.
+            unsafe { (block.as_mut_ptr() as *mut Ext4DentrySized).write(empty_block_dentry) };
+            if let Some(checksum_seed) = self.checksum_seed {
+                Ext4DentryTail::write(block, checksum_seed);
+            }
+        }
+    }
+
+    /// Extends the last dentry of the current block to the end of the block's dentry space and writes the block's
+    /// checksum tail, so that the block is complete. In a 64 KiB block without a checksum tail, a single dentry then
+    /// spans the entire block, see `Ext4DentrySized::increment_dentry_len`. Writing to the block again requires calling
+    /// this again.
+    pub fn finish_block(&mut self, cluster: Option<&mut [u8]>) {
+        let previous_dentry = self.previous_dentry.take();
+        let cluster = match cluster {
+            Some(cluster) => cluster,
+            None => return,
+        };
+        if let Some(offset) = previous_dentry {
+            let dentry_bytes = &mut cluster[offset..offset + size_of::<Ext4DentrySized>()];
+            // SAFETY: Safe because `write_dentry` has written an `Ext4DentrySized` at `offset`, which is packed.
+            let dentry = unsafe { &mut *(dentry_bytes.as_mut_ptr() as *mut Ext4DentrySized) };
+            dentry.increment_dentry_len(self.layout.remaining_space());
+        }
+        if let Some(checksum_seed) = self.checksum_seed {
+            let block_start = self.layout.block_start_in_cluster();
+            let block_size = self.layout.block_size();
+            Ext4DentryTail::write(&mut cluster[block_start..block_start + block_size], checksum_seed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ext4::FileType;
+
+    fn dentry_len_at(cluster: &[u8], offset: usize) -> usize {
+        // SAFETY: Safe because the slice is longer than an `Ext4DentrySized`, which is packed.
+        unsafe { (cluster[offset..].as_ptr() as *const Ext4DentrySized).read() }.dentry_len()
+    }
+
+    #[test]
+    fn simulation_places_dentries_like_the_builder() {
+        let mut cluster = vec![0_u8; 2048];
+        let mut builder = DirBuilder::new(1024, 2, Some(1));
+        let mut simulation = DirBuilder::simulation(1024, 2, true);
+        builder.clear_cluster(&mut cluster);
+        assert!(Ext4DentryTail::is_present(&cluster[1024..]));
+
+        // a dentry of 12 bytes and three of 264 bytes fill 804 of the 1012 bytes, the fourth one starts the next block
+        let mut names = vec![".".to_string()];
+        names.extend((0..4).map(|idx| idx.to_string().repeat(255)));
+        let mut offsets = Vec::new();
+        for name in names {
+            let dentry = Ext4Dentry::new(12, name, FileType::RegularFile).unwrap();
+            let dentry_len = usize::from(dentry.dentry_len());
+            let new_cluster = builder.make_room(dentry_len, Some(&mut cluster)).unwrap();
+            assert_eq!(simulation.make_room(dentry_len, None).unwrap(), new_cluster);
+            assert!(!new_cluster);
+            let offset = builder.write_dentry(&dentry, Some(&mut cluster));
+            assert_eq!(simulation.write_dentry(&dentry, None), offset);
+            offsets.push(offset);
+        }
+        builder.finish_block(Some(&mut cluster));
+        assert_eq!(offsets, [0, 12, 276, 540, 1024]);
+
+        // the last dentry of each block is padded up to the checksum tail
+        assert_eq!(dentry_len_at(&cluster, 540), 1012 - 540);
+        assert_eq!(dentry_len_at(&cluster, 1024), 1012);
+        assert!(Ext4DentryTail::is_present(&cluster[..1024]));
+        assert!(Ext4DentryTail::is_present(&cluster[1024..]));
+        assert_eq!(simulation.layout().size(), builder.layout().size());
+    }
+
+    #[test]
+    fn fills_blocks_before_clusters() {
+        let mut layout = DirectoryLayout::new(1024, 2, false);
+        layout.advance(1000);
+        assert!(!layout.fits(32));
+        assert!(!layout.next_block().unwrap());
+        assert_eq!(layout.position_in_cluster(), 1024);
+        layout.advance(1024);
+        assert_eq!(layout.remaining_space(), 0);
+        assert!(layout.next_block().unwrap());
+        assert_eq!(layout.position_in_cluster(), 0);
+        assert_eq!((layout.cluster_count(), layout.size()), (2, 4096));
+        assert_eq!(layout.last_cluster_logical_start(), 2);
+    }
+
+    #[test]
+    fn lost_found_is_16_kib() {
+        assert_eq!(DirectoryLayout::new(1024, 1, false).lost_found_cluster_count(), 16);
+        assert_eq!(DirectoryLayout::new(4096, 1, false).lost_found_cluster_count(), 4);
+        assert_eq!(DirectoryLayout::new(4096, 16, false).lost_found_cluster_count(), 1);
+        assert_eq!(DirectoryLayout::new(65536, 1, false).lost_found_cluster_count(), 2);
+    }
+
+    #[test]
+    fn one_block_per_cluster() {
+        let mut layout = DirectoryLayout::new(4096, 1, false);
+        assert!(layout.fits(4096));
+        layout.advance(12);
+        assert!(layout.next_block().unwrap());
+        assert_eq!(layout.position_in_cluster(), 0);
+    }
+
+    #[test]
+    fn reserves_checksum_tail() {
+        let mut layout = DirectoryLayout::new(1024, 2, true);
+        assert!(!layout.fits(1024));
+        layout.advance(1012);
+        assert_eq!(layout.remaining_space(), 0);
+        assert!(!layout.next_block().unwrap());
+        assert_eq!(layout.block_start_in_cluster(), 1024);
+        assert_eq!(layout.remaining_space(), 1012);
+    }
+
+    #[test]
+    fn counts_extent_tree_blocks() {
+        let mut layout = DirectoryLayout::new(1024, 1, false);
+        // the inode holds 4 extents besides the header, so the fifth cluster requires an extent tree block
+        for _ in 0..3 {
+            layout.add_cluster().unwrap();
+        }
+        assert_eq!(layout.used_clusters(), 4);
+        layout.add_cluster().unwrap();
+        assert_eq!(layout.used_clusters(), 6);
+        assert_eq!(layout.size(), 5 * 1024);
+    }
+
+    #[test]
+    fn block_count_fits_into_u32() {
+        let mut layout = DirectoryLayout::new(1024, 1 << 31, false);
+        assert!(layout.add_cluster().is_err());
+        assert_eq!(layout.cluster_count(), 1);
+    }
+}
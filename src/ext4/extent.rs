@@ -7,17 +7,18 @@ use anyhow::{bail, Context, Result};
 use num::Integer;
 use static_assertions::const_assert_eq;
 
-use crate::allocator::{AllocatedClusterIdx, Allocator};
+use crate::allocator::{AllocatedClusterIdx, AllocationPurpose, ClusterAllocator};
 use crate::ext4::{BlockCount, BlockIdx, BlockSize, EXTENT_ENTRIES_IN_INODE};
+use crate::fat::ClusterIdx;
 use crate::lohi::{LoHi, LoHiMut};
-use crate::util::{checked_add, FromU32, FromUsize};
+use crate::util::{checked_add, Clusters, FromU32, FromUsize};
 
 const_assert_eq!(size_of::<Extent>(), size_of::<ExtentTreeElement>());
 const_assert_eq!(size_of::<ExtentHeader>(), size_of::<ExtentTreeElement>());
 const_assert_eq!(size_of::<ExtentIdx>(), size_of::<ExtentTreeElement>());
 
 const EXTENT_TREE_LEAF_DEPTH: u16 = 0;
-const EXTENT_MAGIC: u16 = 0xF30A;
+pub const EXTENT_MAGIC: u16 = 0xF30A;
 const MAX_EXTENT_ENTRIES_PER_BLOCK: usize = u16::MAX as usize; // must fit into `ExtentHeader.max_entry_count`
 
 #[repr(C)]
@@ -58,13 +59,20 @@ pub struct ExtentHeader {
 }
 
 impl Extent {
-    pub const MAX_LEN: usize = u16::MAX as usize;
+    /// Longer extents would be interpreted as uninitialized. This is a multiple of every possible bigalloc cluster
+    /// ratio, so extents never end in the middle of a cluster because of this limit.
+    pub const MAX_LEN: usize = 1 << 15;
 
     /// PANICS: Panics if `range.len() > Extent::MAX_LEN`.
     pub fn new(data_range: Range<BlockIdx>, logical_start: u32) -> Self {
+        assert!(
+            data_range.len() <= Self::MAX_LEN,
+            "Attempted to create an extent longer than {} blocks",
+            Self::MAX_LEN
+        );
         let mut instance = Self {
             logical_start,
-            len: u16::try_from(data_range.len()).expect("Attempted to create an extent longer than 65535 blocks"),
+            len: u16::try_from(data_range.len()).unwrap(),
             physical_start_hi: 0,
             physical_start_lo: 0,
         };
@@ -101,28 +109,56 @@ impl Extent {
         }
         Ok(extents)
     }
+
+    /// Returns the extents of a regular file of size `file_size` whose data is stored in the clusters `data_ranges`.
+    /// Blocks beyond the first `file_size` bytes are not part of any extent.
+    pub fn from_file_clusters<I>(
+        data_ranges: I,
+        file_size: u64,
+        block_size: BlockSize,
+        blocks_per_cluster: u32,
+    ) -> Result<Vec<Self>>
+    where
+        I: IntoIterator<Item = Range<ClusterIdx>>,
+    {
+        let mut remaining_blocks = BlockCount::try_from(file_size.div_ceil(&u64::from(block_size)))?;
+        let first_block_idx = |cluster_idx: ClusterIdx| {
+            Clusters::from(cluster_idx)
+                .first_block_idx(blocks_per_cluster)
+                .expect("The file's clusters lie within the partition, so their blocks can be indexed")
+        };
+        let used_data_ranges = data_ranges.into_iter().map(|range| {
+            let start = first_block_idx(range.start);
+            let end = first_block_idx(range.end);
+            let used_len = (end - start).min(remaining_blocks);
+            remaining_blocks -= used_len;
+            start..start + used_len
+        });
+        Self::from_ranges(used_data_ranges)
+    }
 }
 
 impl ExtentIdx {
-    pub fn new(logical_start: u32, physical_start: AllocatedClusterIdx) -> Self {
-        Self {
-            logical_start,
-            leaf_lo: physical_start.into(),
-            leaf_hi: 0,
-            _padding: 0,
-        }
+    pub fn new(logical_start: u32, leaf: u64) -> Self {
+        let mut instance = Self { logical_start, leaf_lo: 0, leaf_hi: 0, _padding: 0 };
+        LoHiMut::new(&mut instance.leaf_lo, &mut instance.leaf_hi).set(leaf);
+        instance
+    }
+
+    pub fn leaf(&self) -> u64 {
+        LoHi::new(&self.leaf_lo, &self.leaf_hi).get()
     }
 
     /// SAFETY: Safe only if `self` is consistent, i.e. if the block with the referenced index contains a consistent
     /// extent tree level.
-    unsafe fn level_mut<'a>(&'a mut self, allocator: &'a Allocator<'a>) -> ExtentTreeLevel<'a> {
-        // SAFETY: Safe since `self.leaf_lo` came from an `AllocatedClusterIdx`, and since it only survives as long as
+    unsafe fn level_mut<'a>(&'a mut self, allocator: ExtentBlockAllocator<'a>) -> ExtentTreeLevel<'a> {
+        let cluster_idx = u32::try_from(self.leaf() / u64::from(allocator.blocks_per_cluster))
+            .expect("The leaf is the first block of an allocated cluster");
+        // SAFETY: Safe since `cluster_idx` came from an `AllocatedClusterIdx`, and since it only survives as long as
         // we have a mutable borrow on `self`, ensuring it cannot be duplicated.
         unsafe {
-            let mut allocated_cluster_idx = AllocatedClusterIdx::new(self.leaf_lo);
-            let block = allocator.cluster_mut(&mut allocated_cluster_idx);
-            let (_, entries, _) = block.align_to_mut::<ExtentTreeElement>();
-            ExtentTreeLevel::new(entries)
+            let mut allocated_cluster_idx = AllocatedClusterIdx::new(cluster_idx);
+            ExtentTreeLevel::new(allocator.entries_mut(&mut allocated_cluster_idx))
         }
     }
 }
@@ -179,36 +215,95 @@ pub struct ExtentTreeLevel<'a> {
     all_entries: &'a mut [ExtentTreeElement],
 }
 
+/// Provides the blocks for the extent tree levels below the inode. Every such level occupies the first block of a newly
+/// allocated cluster; unless bigalloc is enabled, that block is the entire cluster.
+#[derive(Clone, Copy)]
+pub struct ExtentBlockAllocator<'a> {
+    allocator: &'a dyn ClusterAllocator,
+    block_size: BlockSize,
+    blocks_per_cluster: u32,
+}
+
+impl<'a> ExtentBlockAllocator<'a> {
+    pub fn new(allocator: &'a dyn ClusterAllocator, block_size: BlockSize, blocks_per_cluster: u32) -> Self {
+        Self { allocator, block_size, blocks_per_cluster }
+    }
+
+    /// Allocates a cluster for a new extent tree level. Returns the index of the cluster, the index of its first block,
+    /// and the entries within that block.
+    /// SAFETY: The returned entries are uninitialized.
+    unsafe fn allocate_level(&self) -> Result<(BlockIdx, u64, &'a mut [ExtentTreeElement])> {
+        let mut cluster_idx = self.allocator.allocate_one(AllocationPurpose::Metadata)?;
+        let cluster = BlockIdx::fromx(cluster_idx.as_cluster_idx());
+        let block = u64::fromx(cluster_idx.first_block_idx(self.blocks_per_cluster));
+        // SAFETY: Passed on to the caller.
+        let entries = unsafe { self.entries_mut(&mut cluster_idx) };
+        Ok((cluster, block, entries))
+    }
+
+    /// Returns the entries in the first block of the cluster `cluster_idx`, limited to as many as an `ExtentHeader`
+    /// can describe.
+    /// SAFETY: Safe if the block contains a consistent extent tree level or if the caller treats the entries as
+    /// uninitialized.
+    unsafe fn entries_mut(&self, cluster_idx: &mut AllocatedClusterIdx) -> &'a mut [ExtentTreeElement] {
+        let allocator: &'a dyn ClusterAllocator = self.allocator;
+        let block = &mut allocator.cluster_mut(cluster_idx)[..usize::fromx(self.block_size)];
+        // SAFETY: Passed on to the caller.
+        let (_, entries, _) = unsafe { block.align_to_mut::<ExtentTreeElement>() };
+        let entry_count = entries.len().min(MAX_EXTENT_ENTRIES_PER_BLOCK);
+        &mut entries[..entry_count]
+    }
+}
+
 pub struct ExtentTree<'a> {
     /// The root level located inside the inode (`exxt_header` and `extents`)
     root: ExtentTreeLevel<'a>,
-    allocator: &'a Allocator<'a>,
+    allocator: ExtentBlockAllocator<'a>,
 }
 
 impl<'a> ExtentTree<'a> {
-    pub fn new(root_level: ExtentTreeLevel<'a>, allocator: &'a Allocator<'a>) -> Self {
+    pub fn new(root_level: ExtentTreeLevel<'a>, allocator: ExtentBlockAllocator<'a>) -> Self {
         Self { root: root_level, allocator }
     }
 
+    /// Returns the number of blocks (one per cluster with bigalloc) that an extent tree with `extent_count` extents
+    /// occupies outside of the inode.
+    /// PANICS: Panics if a block of `block_size` bytes cannot hold a header and at least two entries.
     pub fn required_block_count(extent_count: usize, block_size: BlockSize) -> BlockCount {
-        if extent_count == 0 {
+        let root_capacity = usize::from(EXTENT_ENTRIES_IN_INODE) - 1;
+        if extent_count <= root_capacity {
+            // the extents fit into the inode
             return 0;
         }
 
-        let extents_per_block = (usize::fromx(block_size) / size_of::<ExtentTreeElement>()) - 1;
-        let level_count = 1
-            + (extent_count as f64 / (EXTENT_ENTRIES_IN_INODE - 1) as f64)
-                .log(extents_per_block as f64)
-                .ceil() as u32;
+        let extents_per_block = Self::extents_per_block(block_size);
+        assert!(
+            extents_per_block >= 2,
+            "Extent tree blocks of {} bytes are too small",
+            block_size
+        );
 
+        // Each level below the root holds at most `extents_per_block` times as many extents as the level above it.
+        // Since `extents_per_block >= 2`, the capacities grow until they saturate at `usize::MAX`, which is no less
+        // than `extent_count`, so the loop terminates and each level contains at least one block.
         let mut result = 0;
-        for level in 1..level_count {
-            let blocks_in_level = extent_count.div_ceil(&extents_per_block.pow(level));
-            result += blocks_in_level;
+        let mut level_capacity = root_capacity;
+        let mut extents_per_subtree: usize = 1;
+        while level_capacity < extent_count {
+            level_capacity = level_capacity.saturating_mul(extents_per_block);
+            extents_per_subtree = extents_per_subtree.saturating_mul(extents_per_block);
+            result += extent_count.div_ceil(&extents_per_subtree);
         }
         result
     }
 
+    /// Returns the number of extents or extent indices that fit into a block below the root, which is the number of
+    /// entries `ExtentBlockAllocator` provides, minus one for the header.
+    fn extents_per_block(block_size: BlockSize) -> usize {
+        let entry_count = (usize::fromx(block_size) / size_of::<ExtentTreeElement>()).min(MAX_EXTENT_ENTRIES_PER_BLOCK);
+        entry_count.saturating_sub(1)
+    }
+
     pub fn add_extent(&mut self, extent: Extent) -> Result<Vec<BlockIdx>> {
         self.root.add_extent(extent, self.allocator).or_else(|_| {
             let block_for_previous_root = self.make_deeper()?;
@@ -221,14 +316,11 @@ impl<'a> ExtentTree<'a> {
         })
     }
 
+    /// Returns the index of the cluster allocated for the previous root.
     fn make_deeper(&mut self) -> Result<BlockIdx> {
-        let mut new_block_idx = self.allocator.allocate_one()?;
-        let block_idx = new_block_idx.as_block_idx();
-        let new_block = self.allocator.cluster_mut(&mut new_block_idx);
         // SAFETY: Safe since we later overwrite the first `root_slice.len()` entries and mark all others as invalid
-        let (_, mut new_entries, _) = unsafe { new_block.align_to_mut::<ExtentTreeElement>() };
-        let entry_count = new_entries.len().min(MAX_EXTENT_ENTRIES_PER_BLOCK);
-        new_entries = &mut new_entries[..entry_count];
+        let (cluster_idx, block_idx, new_entries) = unsafe { self.allocator.allocate_level()? };
+        let entry_count = new_entries.len();
         assert!(entry_count >= usize::from(EXTENT_ENTRIES_IN_INODE));
         self.root.header.max_entry_count = u16::try_from(entry_count - 1).unwrap();
 
@@ -237,9 +329,9 @@ impl<'a> ExtentTree<'a> {
 
         *self.root.header = ExtentHeader::from_child(*self.root.header, EXTENT_ENTRIES_IN_INODE);
         self.root
-            .append_extent_idx(ExtentIdx::new(0, new_block_idx))
+            .append_extent_idx(ExtentIdx::new(0, block_idx))
             .expect("Unable to add ExtentIdx within the inode");
-        Ok(block_idx)
+        Ok(cluster_idx)
     }
 }
 
@@ -270,9 +362,9 @@ impl<'a> ExtentTreeLevel<'a> {
         }
     }
 
-    /// Returns the `BlockIdx`s of the extent tree blocks allocated for this operation, or None if the tree below
-    /// `self` is already full.
-    pub fn add_extent(&mut self, extent: Extent, allocator: &Allocator<'a>) -> Result<Vec<BlockIdx>> {
+    /// Returns the indices of the clusters allocated for this operation, or None if the tree below `self` is already
+    /// full.
+    pub fn add_extent(&mut self, extent: Extent, allocator: ExtentBlockAllocator<'_>) -> Result<Vec<BlockIdx>> {
         // try to append directly to self
         if self.header.is_leaf() {
             // if this did not work, there is nothing we as a leaf can do about it
@@ -293,7 +385,7 @@ impl<'a> ExtentTreeLevel<'a> {
     }
 
     /// PANICS: Panics if `self` is a leaf level.
-    fn last_child_level<'b>(&'b mut self, allocator: &'b Allocator<'b>) -> ExtentTreeLevel<'b> {
+    fn last_child_level<'b>(&'b mut self, allocator: ExtentBlockAllocator<'b>) -> ExtentTreeLevel<'b> {
         assert!(
             !self.header.is_leaf(),
             "Attempted to access the child of a leaf level in the extent tree"
@@ -309,10 +401,13 @@ impl<'a> ExtentTreeLevel<'a> {
         }
     }
 
-    /// Returns the `BlockIdx`s of the extent tree blocks allocated for this operation.
-    fn add_extent_with_new_leaf(&mut self, extent: Extent, allocator: &Allocator<'_>) -> Result<Vec<BlockIdx>> {
-        let allocated_block = self.add_child_level(extent.logical_start, allocator)?;
-        let mut child_level = self.last_child_level(allocator);
+    /// Returns the indices of the clusters allocated for this operation.
+    fn add_extent_with_new_leaf(
+        &mut self,
+        extent: Extent,
+        allocator: ExtentBlockAllocator<'_>,
+    ) -> Result<Vec<BlockIdx>> {
+        let (allocated_block, mut child_level) = self.add_child_level(extent.logical_start, allocator)?;
         if child_level.header.is_leaf() {
             child_level
                 .append_extent(extent)
@@ -327,25 +422,29 @@ impl<'a> ExtentTreeLevel<'a> {
         }
     }
 
-    /// Returns the `BlockIdx` of the block allocated for the new child level, or None if no child level can be added
-    /// because `self` is full.
-    fn add_child_level(&mut self, logical_start: u32, allocator: &Allocator<'_>) -> Result<BlockIdx> {
+    /// Returns the index of the cluster allocated for the new child level together with the new level, or Err if no
+    /// child level can be added because `self` is full. Unless it is a leaf, the new level has no children yet and is
+    /// therefore inconsistent until the caller adds one.
+    fn add_child_level<'b>(
+        &mut self,
+        logical_start: u32,
+        allocator: ExtentBlockAllocator<'b>,
+    ) -> Result<(BlockIdx, ExtentTreeLevel<'b>)> {
         if self.header.is_full() {
             bail!("Extent tree level full, cannot add new child level");
         }
 
-        let mut new_child_block_idx = allocator.allocate_one()?;
-        let block_idx = new_child_block_idx.as_block_idx();
-        let new_child_block = allocator.cluster_mut(&mut new_child_block_idx);
         // SAFETY: Safe because we replace the header and regard all other entries as invalid.
-        let (_, mut entries, _) = unsafe { new_child_block.align_to_mut::<ExtentTreeElement>() };
-        let entry_count = entries.len().min(MAX_EXTENT_ENTRIES_PER_BLOCK);
-        entries = &mut entries[..entry_count];
-
+        let (cluster_idx, block_idx, entries) = unsafe { allocator.allocate_level()? };
+        let entry_count = entries.len();
         entries[0].header = ExtentHeader::from_parent(*self.header, u16::try_from(entry_count).unwrap());
+        self.append_extent_idx(ExtentIdx::new(logical_start, block_idx))?;
 
-        self.append_extent_idx(ExtentIdx::new(logical_start, new_child_block_idx))
-            .and(Ok(block_idx))
+        // We cannot use `ExtentTreeLevel::new` since a new non-leaf level fails its consistency check.
+        let (header_slice, all_entries) = entries.split_at_mut(1);
+        // SAFETY: Safe because we just initialized the header.
+        let header = unsafe { &mut header_slice[0].header };
+        Ok((cluster_idx, ExtentTreeLevel { header, all_entries }))
     }
 
     /// PANICS: Panics if `self` is not a leaf level
@@ -381,7 +480,18 @@ impl<'a> ExtentTreeLevel<'a> {
 
 #[cfg(test)]
 mod tests {
+    use std::marker::PhantomData;
+
+    use rand::Rng;
+
     use super::*;
+    use crate::allocator::Allocator;
+    use crate::ext4::{MAX_BLOCK_SIZE, MIN_BLOCK_SIZE};
+    use crate::ranges::Ranges;
+
+    fn supported_block_sizes() -> impl Iterator<Item = BlockSize> {
+        (MIN_BLOCK_SIZE.trailing_zeros()..=MAX_BLOCK_SIZE.trailing_zeros()).map(|shift| 1 << shift)
+    }
 
     #[test]
     fn inode_extents() {
@@ -389,6 +499,13 @@ mod tests {
             ExtentTree::required_block_count(usize::from(EXTENT_ENTRIES_IN_INODE) - 1, 1024),
             0,
         );
+        // the inode holds 4 extents, and only the fifth one moves them into a leaf block
+        for block_size in [1024, 4096] {
+            for extent_count in 0..=4 {
+                assert_eq!(allocated_extent_tree_blocks(extent_count, block_size, 1), 0);
+            }
+            assert_eq!(allocated_extent_tree_blocks(5, block_size, 1), 1);
+        }
     }
 
     #[test]
@@ -440,18 +557,162 @@ mod tests {
         assert_eq!(ExtentTree::required_block_count(extent_count, BLOCK_SIZE), block_count);
     }
 
+    #[test]
+    fn giant_extent_count() {
+        // the block count of deep trees must be exact, and even absurd extent counts must not overflow
+        const BLOCK_SIZE: BlockSize = 1024;
+        let (extent_count, block_count) = perfect_extent_tree(7, BLOCK_SIZE);
+        assert_eq!(ExtentTree::required_block_count(extent_count, BLOCK_SIZE), block_count);
+        assert_eq!(
+            ExtentTree::required_block_count(extent_count + 1, BLOCK_SIZE),
+            block_count + 1 + 6
+        );
+        assert!(ExtentTree::required_block_count(usize::MAX, BLOCK_SIZE) > usize::MAX / 84);
+    }
+
+    #[test]
+    fn perfect_trees_for_all_block_sizes() {
+        for block_size in supported_block_sizes() {
+            for extent_count in 0..=usize::from(EXTENT_ENTRIES_IN_INODE) - 1 {
+                assert_eq!(ExtentTree::required_block_count(extent_count, block_size), 0);
+            }
+
+            let extents_per_block = ExtentTree::extents_per_block(block_size);
+            assert_eq!(extents_per_block, usize::fromx(block_size) / 12 - 1);
+            // up to the deepest tree whose extent count fits into a usize
+            let mut level_count = 2;
+            while let Some((extent_count, block_count)) = checked_perfect_extent_tree(level_count, block_size) {
+                let message = format!("{} levels, block size {}", level_count, block_size);
+                assert_eq!(
+                    ExtentTree::required_block_count(extent_count, block_size),
+                    block_count,
+                    "{}",
+                    message
+                );
+                // one more extent adds a level and a path from the second level to a new leaf
+                assert_eq!(
+                    ExtentTree::required_block_count(extent_count + 1, block_size),
+                    block_count + level_count,
+                    "{}",
+                    message
+                );
+                // removing a full leaf removes exactly one block
+                assert_eq!(
+                    ExtentTree::required_block_count(extent_count - extents_per_block, block_size),
+                    block_count - 1,
+                    "{}",
+                    message
+                );
+                level_count += 1;
+            }
+            assert!(level_count > 4);
+            assert!(ExtentTree::required_block_count(usize::MAX, block_size) > 0);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "too small")]
+    fn tiny_blocks_are_rejected() {
+        ExtentTree::required_block_count(usize::from(EXTENT_ENTRIES_IN_INODE), 24);
+    }
+
+    #[test]
+    fn required_block_count_matches_extent_tree_for_all_block_sizes() {
+        for block_size in supported_block_sizes() {
+            let extents_per_block = ExtentTree::extents_per_block(block_size);
+            let root_capacity = usize::from(EXTENT_ENTRIES_IN_INODE) - 1;
+            // the boundaries of the first two levels below the inode
+            let boundaries = [root_capacity, extents_per_block, root_capacity * extents_per_block];
+            for extent_count in boundaries.iter().flat_map(|&count| [count - 1, count, count + 1]) {
+                assert_eq!(
+                    allocated_extent_tree_blocks(extent_count, block_size, 1),
+                    ExtentTree::required_block_count(extent_count, block_size),
+                    "extent count {}, block size {}",
+                    extent_count,
+                    block_size,
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn required_block_count_matches_extent_tree() {
+        let mut rng = rand::thread_rng();
+        for (block_size, blocks_per_cluster) in [(1024, 1), (4096, 1), (4096, 4)] {
+            for extent_count in (0..20).map(|_| rng.gen_range(0..40_000)).chain([4, 5, 340, 341, 1360, 1361]) {
+                assert_eq!(
+                    allocated_extent_tree_blocks(extent_count, block_size, blocks_per_cluster),
+                    ExtentTree::required_block_count(extent_count, block_size),
+                    "extent count {}, block size {}, {} blocks per cluster",
+                    extent_count,
+                    block_size,
+                    blocks_per_cluster,
+                );
+            }
+        }
+    }
+
+    /// Builds an extent tree with `extent_count` extents and returns the number of clusters allocated for it.
+    fn allocated_extent_tree_blocks(extent_count: usize, block_size: BlockSize, blocks_per_cluster: u32) -> usize {
+        let cluster_size = usize::fromx(block_size * blocks_per_cluster);
+        // leave room for more clusters than expected so that an overestimate does not go unnoticed
+        let cluster_count = 2 * ExtentTree::required_block_count(extent_count, block_size) + 1;
+        let mut memory = vec![0_u64; cluster_count * cluster_size / size_of::<u64>()];
+        // SAFETY: Safe because `memory` outlives `allocator` and is not accessed by anyone else.
+        let allocator = unsafe {
+            Allocator::new(
+                memory.as_mut_ptr() as *mut u8,
+                memory.len() * size_of::<u64>(),
+                cluster_size,
+                Ranges::new(),
+                PhantomData,
+            )
+        };
+
+        let header = ExtentHeader::new(EXTENT_ENTRIES_IN_INODE);
+        let mut root_entries = [ExtentTreeElement { header }; EXTENT_ENTRIES_IN_INODE as usize];
+        // SAFETY: Safe because the root level has a valid header and no valid entries.
+        let root_level = unsafe { ExtentTreeLevel::new(&mut root_entries) };
+        let extent_block_allocator = ExtentBlockAllocator::new(&allocator, block_size, blocks_per_cluster);
+        let mut tree = ExtentTree::new(root_level, extent_block_allocator);
+
+        let mut allocated_block_count = 0;
+        for i in 0..extent_count {
+            // the data blocks are never accessed, so they can be arbitrary
+            let extent = Extent::new(i..i + 1, u32::try_from(i).unwrap());
+            allocated_block_count += tree.add_extent(extent).unwrap().len();
+        }
+        allocated_block_count
+    }
+
+    #[test]
+    fn file_extents_end_with_file_size() {
+        let extents = Extent::from_file_clusters([10..12, 20..30], 9 * 4096 + 1, 4096, 4).unwrap();
+        let ranges: Vec<_> = extents.iter().map(Extent::as_range).collect();
+        assert_eq!(ranges, [40..48, 80..82]);
+        assert_eq!(extents[1].logical_start, 8);
+
+        let extents = Extent::from_file_clusters([10..12, 20..30], 0, 4096, 4).unwrap();
+        assert!(extents.is_empty());
+    }
+
     /// Returns the extent count and block count of an extent tree with `level_count` levels in which adding one more
     /// extent would require adding another level.
     fn perfect_extent_tree(level_count: usize, block_size: BlockSize) -> (usize, usize) {
+        checked_perfect_extent_tree(level_count, block_size).expect("The extent count overflows a usize")
+    }
+
+    /// Like `perfect_extent_tree`, but returns None if the counts overflow.
+    fn checked_perfect_extent_tree(level_count: usize, block_size: BlockSize) -> Option<(usize, usize)> {
         assert!(level_count > 0);
         let extents_per_block = (usize::fromx(block_size) / size_of::<ExtentTreeElement>()) - 1;
         assert!(extents_per_block > 1);
         let mut current_level_extent_count = usize::from(EXTENT_ENTRIES_IN_INODE) - 1;
-        let mut current_level_block_count = 0; // inode
+        let mut current_level_block_count: usize = 0; // inode
         for _current_level in 1..level_count {
-            current_level_block_count += current_level_extent_count;
-            current_level_extent_count *= extents_per_block;
+            current_level_block_count = current_level_block_count.checked_add(current_level_extent_count)?;
+            current_level_extent_count = current_level_extent_count.checked_mul(extents_per_block)?;
         }
-        (current_level_extent_count, current_level_block_count)
+        Some((current_level_extent_count, current_level_block_count))
     }
 }
@@ -2,32 +2,61 @@
 #![allow(clippy::needless_option_as_deref)]
 
 use std::convert::TryFrom;
-use std::mem::MaybeUninit;
+use std::mem::{size_of, size_of_val, MaybeUninit};
 use std::ops::Range;
+use std::slice;
 
 use anyhow::{bail, Result};
 use num::Integer;
+use rayon::prelude::*;
+use static_assertions::const_assert_eq;
+use uuid::Uuid;
 
-use crate::allocator::Allocator;
+use crate::allocator::{AllocationPurpose, ClusterAllocator};
 use crate::ext4::{
-    BlockGroup, BlockGroupIdx, BlockIdx, Ext4BlockGroupConstructionInfo, Ext4GroupDescriptor, Extent, Inode, InodeNo,
-    SuperBlock, FIRST_EXISTING_INODE, FIRST_NON_RESERVED_INODE, LOST_FOUND_INODE_NO, ROOT_INODE_NO,
+    BlockCount, BlockGroup, BlockGroupIdx, BlockIdx, BlockSize, Ext4BlockGroupConstructionInfo, Ext4GroupDescriptor,
+    Extent, ExtentBlockAllocator, Inode, InodeCount, InodeNo, Owner, SuperBlock, FIRST_EXISTING_INODE,
+    FIRST_NON_RESERVED_INODE, LOST_FOUND_INODE_NO, ROOT_INODE_NO,
 };
-use crate::fat::BootSector;
-use crate::util::{AddUsize, FromU32};
+use crate::trace::TraceEvent;
+use crate::util::{AddUsize, Blocks, Bytes, FromU32, RateLimiter};
+
+// the on-disk sizes, which means the structs have no padding and can be compared byte by byte
+const_assert_eq!(size_of::<SuperBlock>(), 1024);
+const_assert_eq!(size_of::<Ext4GroupDescriptor>(), 64);
+
+/// The state of an `Ext4Fs` after the directory tree has been converted
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Ext4FsStats {
+    /// excluding the root directory and lost+found
+    pub directory_count: u32,
+    pub regular_file_count: InodeCount,
+    pub free_block_count: u64,
+    pub free_inode_count: InodeCount,
+    pub block_size: BlockSize,
+}
 
 pub struct Ext4Fs<'a> {
+    /// Only used to locate the metadata in `block_groups` within the partition
+    partition_ptr: *const u8,
     block_groups: Vec<BlockGroup<'a>>,
     /// Used for allocating inodes
     last_allocated_inode_no: InodeNo,
+    /// Whether inodes get a random generation number instead of 0
+    randomize_generation: bool,
+    /// the owner of the root directory and of the inodes initialized from dentries
+    owner: Owner,
+    /// limits the rate at which the inode tables are zeroed
+    rate_limiter: Option<RateLimiter>,
+    /// Whether `finalize` has run, otherwise `drop` completes the filesystem as far as possible
+    finalized: bool,
 }
 
 impl<'a> Ext4Fs<'a> {
-    /// SAFETY: Safe if `partition_ptr` is valid for reads for `boot_sector.partition_len()` many bytes, and no memory
-    /// belonging to a block in `SuperBlock::from(boot_sector).block_group_overhead_ranges()` is dereferenced for the
-    /// duration of the lifetime `'a` by someone other than `self`.
-    pub unsafe fn from(partition_ptr: *mut u8, boot_sector: &BootSector) -> Result<Self> {
-        let superblock = SuperBlock::from(boot_sector)?;
+    /// SAFETY: Safe if `partition_ptr` is valid for reads for `superblock.block_count_with_padding()` many blocks, and
+    /// no memory belonging to a block in `superblock.block_group_overhead_ranges()` is dereferenced for the duration of
+    /// the lifetime `'a` by someone other than `self`.
+    pub unsafe fn from(partition_ptr: *mut u8, superblock: SuperBlock) -> Self {
         let mut block_groups = Vec::new();
         let mut block_group_descriptors = Vec::new();
 
@@ -35,7 +64,12 @@ impl<'a> Ext4Fs<'a> {
             let info = Ext4BlockGroupConstructionInfo::new(&superblock, block_group_idx);
             block_group_descriptors.push(Ext4GroupDescriptor::new(info));
             // SAFETY: safe because the block group is within the partition.
-            let block_group_ptr = unsafe { partition_ptr.add_usize(info.start_block * usize::fromx(info.block_size)) };
+            let start_byte = Blocks::from(info.start_block)
+                .to_bytes(info.block_size)
+                .and_then(Bytes::to_usize)
+                .expect("The block group lies within the partition, so its bytes can be indexed");
+            // SAFETY: safe because the block group is within the partition.
+            let block_group_ptr = unsafe { partition_ptr.add_usize(start_byte) };
             let metadata_len = usize::fromx(superblock.block_size())
                 * superblock.block_group_overhead(superblock.block_group_has_superblock(block_group_idx));
             // SAFETY: safe because the memory is valid and we have exclusive access for the duration of `'a`
@@ -52,10 +86,52 @@ impl<'a> Ext4Fs<'a> {
             block_groups[0].gdt.as_deref_mut().expect("First ext4 block group has no GDT"),
             &block_group_descriptors,
         );
-        Ok(Self {
+        Self {
+            partition_ptr,
             block_groups,
             last_allocated_inode_no: FIRST_NON_RESERVED_INODE - 1,
-        })
+            randomize_generation: false,
+            owner: Owner::effective(),
+            rate_limiter: None,
+            finalized: false,
+        }
+    }
+
+    /// Gives every inode allocated from now on a random generation number, which NFS uses to tell a file apart from
+    /// a deleted file that had the same inode number. By default, the generation number is 0.
+    pub fn set_randomize_generation(&mut self, randomize_generation: bool) {
+        self.randomize_generation = randomize_generation;
+    }
+
+    /// Sets the owner of the root directory and the converted files, which must be set before `build_root_inode`. By
+    /// default, it is the effective user and group of the converter.
+    pub fn set_owner(&mut self, owner: Owner) {
+        self.owner = owner;
+    }
+
+    /// Limits the rate at which the inode table of a block group is zeroed when its first inode is allocated to
+    /// `bytes_per_sec`. By default, it is unlimited.
+    pub fn set_rate_limit(&mut self, bytes_per_sec: u64) {
+        self.rate_limiter = Some(RateLimiter::new(bytes_per_sec));
+    }
+
+    pub fn owner(&self) -> Owner {
+        self.owner
+    }
+
+    pub fn block_size(&self) -> BlockSize {
+        self.superblock().block_size()
+    }
+
+    /// 1 unless bigalloc is enabled
+    pub fn blocks_per_cluster(&self) -> u32 {
+        self.superblock().blocks_per_cluster()
+    }
+
+    /// The seed of the metadata checksums if the metadata_csum feature is enabled
+    pub fn metadata_checksum_seed(&self) -> Option<u32> {
+        let superblock = self.superblock();
+        superblock.has_metadata_csum().then(|| superblock.checksum_seed())
     }
 
     fn superblock(&self) -> &SuperBlock {
@@ -80,6 +156,12 @@ impl<'a> Ext4Fs<'a> {
         }
     }
 
+    fn group_descriptor_table(&self) -> &[Ext4GroupDescriptor] {
+        let table = self.block_groups[0].gdt.as_deref().expect("First ext4 block group has no GDT");
+        // SAFETY: safe because we initialized the gdt in `from`
+        unsafe { MaybeUninit::slice_assume_init_ref(table) }
+    }
+
     fn group_descriptor_table_mut(&mut self) -> &mut [Ext4GroupDescriptor] {
         let table = self.block_groups[0]
             .gdt
@@ -89,72 +171,127 @@ impl<'a> Ext4Fs<'a> {
         unsafe { MaybeUninit::slice_assume_init_mut(table) }
     }
 
+    /// Turns `inode`, initialized by `Inode::init_from_dentry`, into a symlink to `target`. Short targets are stored in
+    /// the inode (fast symlink), longer ones in a newly allocated cluster (slow symlink).
+    pub fn init_symlink(&mut self, inode: &mut Inode, target: &[u8], allocator: &dyn ClusterAllocator) -> Result<()> {
+        if Inode::symlink_cluster_count(target.len(), self.block_size())? == 0 {
+            inode.init_fast_symlink(target);
+            return Ok(());
+        }
+
+        let mut cluster = allocator.allocate_one(AllocationPurpose::Metadata)?;
+        let cluster_data = allocator.cluster_mut(&mut cluster);
+        cluster_data.fill(0);
+        cluster_data[..target.len()].copy_from_slice(target);
+
+        inode.init_slow_symlink(target.len());
+        let first_block = cluster.first_block_idx(self.blocks_per_cluster());
+        self.register_extent(inode, Extent::new(first_block..first_block + 1, 0), allocator)
+    }
+
+    /// Stores the extended attributes `encoded` by `encode_xattrs` in `inode` and enables the ext_attr feature.
+    pub fn set_xattrs(&mut self, inode: &mut Inode, encoded: &[u8]) {
+        inode.xattr_space[..encoded.len()].copy_from_slice(encoded);
+        self.superblock_mut().enable_xattrs();
+    }
+
     /// Assumes that `inode` currently has no extents.
-    pub fn set_extents<I>(&mut self, inode: &mut Inode, data_ranges: I, allocator: &Allocator<'_>) -> Result<()>
-    where I: IntoIterator<Item = Range<BlockIdx>> {
-        for extent in Extent::from_ranges(data_ranges)? {
+    pub fn set_extents<I>(&mut self, inode: &mut Inode, extents: I, allocator: &dyn ClusterAllocator) -> Result<()>
+    where I: IntoIterator<Item = Extent> {
+        for extent in extents {
             self.register_extent(inode, extent, allocator)?;
         }
         Ok(())
     }
 
-    pub fn register_extent(&mut self, inode: &mut Inode, extent: Extent, allocator: &Allocator) -> Result<()> {
-        self.mark_range_as_used(inode, extent.as_range());
+    pub fn register_extent(
+        &mut self,
+        inode: &mut Inode,
+        extent: Extent,
+        allocator: &dyn ClusterAllocator,
+    ) -> Result<()> {
+        self.mark_range_as_used(inode, self.clusters_containing(extent.as_range()))?;
 
-        let additional_blocks = inode.add_extent(extent, allocator)?;
-        for block in additional_blocks {
-            self.mark_range_as_used(inode, block..block + 1);
+        let event = TraceEvent::Extent {
+            inode_no: inode.inode_no,
+            logical_start: extent.logical_start,
+            physical_start: extent.start(),
+            len: extent.len,
+        };
+        let extent_allocator = ExtentBlockAllocator::new(allocator, self.block_size(), self.blocks_per_cluster());
+        let additional_clusters = inode.add_extent(extent, extent_allocator)?;
+        for cluster in additional_clusters {
+            self.mark_range_as_used(inode, cluster..cluster + 1)?;
         }
+        allocator.trace(event);
         Ok(())
     }
 
-    /// Returns None if the block belong to no block group. That is the case if `block_idx` is the padding block at the
-    /// start of the filesystem, or if it is beyond the end of the last block group.
-    pub fn block_group_idx_of_block(&self, block_idx: BlockIdx) -> Option<BlockGroupIdx> {
+    /// Returns the range of clusters containing the blocks in `block_range`.
+    fn clusters_containing(&self, block_range: Range<BlockIdx>) -> Range<BlockIdx> {
+        let blocks_per_cluster = BlockCount::fromx(self.blocks_per_cluster());
+        block_range.start / blocks_per_cluster..block_range.end.div_ceil(&blocks_per_cluster)
+    }
+
+    /// Returns None if the cluster belong to no block group. That is the case if `cluster_idx` is the padding block at
+    /// the start of the filesystem, or if it is beyond the end of the last block group.
+    pub fn block_group_idx_of_cluster(&self, cluster_idx: BlockIdx) -> Option<BlockGroupIdx> {
         // any block before `s_first_data_block` doesn't belong to any block group
-        let data_block_idx = block_idx.checked_sub(self.superblock().first_usable_block())?;
-        let bg_idx = data_block_idx / usize::fromx(self.superblock().s_blocks_per_group);
+        let data_cluster_idx = cluster_idx.checked_sub(self.superblock().first_usable_cluster())?;
+        let bg_idx = data_cluster_idx / usize::fromx(self.superblock().s_clusters_per_group);
+        if bg_idx >= self.block_groups.len() {
+            return None;
+        }
         BlockGroupIdx::try_from(bg_idx).ok()
     }
 
-    /// PANICS: Panics if `range` contains blocks belonging to more than one block group
-    pub fn mark_range_as_used(&mut self, inode: &mut Inode, range: Range<BlockIdx>) {
-        let block_group_idx = self
-            .block_group_idx_of_block(range.start)
-            .expect("Attempted to mark an unusable block as used");
-        let end_block_group_idx = self
-            .block_group_idx_of_block(range.end - 1)
-            .expect("Attempted to mark an unusable block as used");
-        assert_eq!(
-            block_group_idx, end_block_group_idx,
-            "Attempted to mark a range of blocks from different block groups as used"
-        );
+    /// `range` is given in clusters, which are the same as blocks unless bigalloc is enabled. Returns an error if
+    /// `range` is empty, contains clusters that belong to no block group or belong to more than one block group.
+    pub fn mark_range_as_used(&mut self, inode: &mut Inode, range: Range<BlockIdx>) -> Result<()> {
+        let (block_group_idx, end_block_group_idx) = match (
+            range.clone().next().and_then(|start| self.block_group_idx_of_cluster(start)),
+            range.clone().next_back().and_then(|end| self.block_group_idx_of_cluster(end)),
+        ) {
+            (Some(block_group_idx), Some(end_block_group_idx)) => (block_group_idx, end_block_group_idx),
+            _ => bail!("Attempted to mark the unusable clusters {:?} as used", range),
+        };
+        if block_group_idx != end_block_group_idx {
+            bail!("Attempted to mark the clusters {:?} from different block groups as used", range);
+        }
 
         let range_len = u32::try_from(range.len())
-            .expect("All blocks belong to the same block group, which has at most u32::MAX blocks");
-        self.group_descriptor_table_mut()[usize::fromx(block_group_idx)].decrement_free_blocks_count(range_len);
-        inode.increment_used_blocks(range.len(), self.superblock().block_size());
+            .expect("All clusters belong to the same block group, which has at most u32::MAX clusters");
+        let descriptor = &mut self.group_descriptor_table_mut()[usize::fromx(block_group_idx)];
+        descriptor.decrement_free_blocks_count(range_len);
+        descriptor.mark_block_bitmap_used();
+        inode.increment_used_blocks(range.len(), self.superblock().cluster_size());
 
-        let group_start_block = self.superblock().block_group_start_block(block_group_idx);
-        let relative_range = range.start - group_start_block..range.end - group_start_block;
+        let group_start_cluster = self.superblock().block_group_start_cluster(block_group_idx);
+        let relative_range = range.start - group_start_cluster..range.end - group_start_cluster;
         self.block_groups[usize::fromx(block_group_idx)].mark_relative_range_as_used(relative_range);
+        Ok(())
     }
 
-    /// PANICS: Panics if called multiple times
-    pub fn build_root_inode(&mut self) -> Inode<'a> {
-        let mut inode = self.allocate_inode_with_no(ROOT_INODE_NO, true);
-        inode.init_root();
-        inode
+    /// Returns an error if called multiple times
+    pub fn build_root_inode(&mut self) -> Result<Inode<'a>> {
+        let mut inode = self.allocate_inode_with_no(ROOT_INODE_NO, true)?;
+        inode.init_root(self.owner);
+        Ok(inode)
     }
 
-    /// PANICS: Panics if called multiple times
+    /// Must be called before any other inode is allocated by `allocate_inode`.
     pub fn build_lost_found_inode(&mut self) -> Result<Inode<'a>> {
         let mut inode = self.allocate_inode(true)?;
-        assert_eq!(inode.inode_no, LOST_FOUND_INODE_NO);
+        debug_assert_eq!(inode.inode_no, LOST_FOUND_INODE_NO);
         inode.init_lost_found();
         Ok(inode)
     }
 
+    /// The number of inodes allocated by `allocate_inode`, i.e. excluding the reserved inodes
+    pub fn allocated_inode_count(&self) -> InodeCount {
+        self.last_allocated_inode_no - (FIRST_NON_RESERVED_INODE - 1)
+    }
+
     /// Inode 11 is not officially reserved for the lost+found directory, but fsck complains if it's not there.
     /// Therefore, the inode returned by the first call to `allocate_inode` should be used for lost+found.
     pub fn allocate_inode(&mut self, is_dir: bool) -> Result<Inode<'a>> {
@@ -162,69 +299,117 @@ impl<'a> Ext4Fs<'a> {
         match inode_no.filter(|&inode_no| inode_no <= self.superblock().max_inode_no()) {
             Some(inode_no) => {
                 self.last_allocated_inode_no = inode_no;
-                Ok(self.allocate_inode_with_no(inode_no, is_dir))
+                self.allocate_inode_with_no(inode_no, is_dir)
             }
             None => bail!("No free inodes left"),
         }
     }
 
-    /// PANICS: Panics if an inode with number `inode_no` was already allocated or does not exist.
-    fn allocate_inode_with_no(&mut self, inode_no: InodeNo, is_dir: bool) -> Inode<'a> {
+    /// Returns an error if an inode with number `inode_no` was already allocated.
+    /// PANICS: Panics if an inode with number `inode_no` does not exist.
+    fn allocate_inode_with_no(&mut self, inode_no: InodeNo, is_dir: bool) -> Result<Inode<'a>> {
         let inode_size = self.superblock().s_inode_size;
+        let inodes_per_group = self.superblock().s_inodes_per_group;
         let existing_inode_no = inode_no - FIRST_EXISTING_INODE;
-        let (block_group_idx, relative_inode_no) = existing_inode_no.div_rem(&self.superblock().s_inodes_per_group);
+        let (block_group_idx, relative_inode_no) = existing_inode_no.div_rem(&inodes_per_group);
 
         let block_group = &mut self.block_groups[usize::fromx(block_group_idx)];
-        let inner = block_group.allocate_relative_inode(relative_inode_no, inode_size);
+        if let Some(rate_limiter) = &self.rate_limiter {
+            rate_limiter.consume(block_group.pending_inode_table_zeroing());
+        }
+        let (inner, xattr_space) = block_group.allocate_relative_inode(relative_inode_no, inode_size)?;
+        // the inode table is zeroed before its first inode is allocated, so the inode has never been in use
+        debug_assert_eq!(inner.i_links_count, 0);
+        inner.i_dtime = 0;
+        inner.i_generation = if self.randomize_generation { random_u32() } else { 0 };
 
         let descriptor = &mut self.group_descriptor_table_mut()[usize::fromx(block_group_idx)];
         descriptor.decrement_free_inode_count();
+        descriptor.mark_inode_used(relative_inode_no, inodes_per_group);
         if is_dir {
             descriptor.increment_used_directory_count();
         }
 
-        Inode { inode_no, inner }
+        Ok(Inode { inode_no, inner, xattr_space })
     }
 
-    fn update_superblock(&mut self) {
-        self.superblock_mut().s_free_inodes_count = self
-            .group_descriptor_table_mut()
-            .iter_mut()
-            .map(|block_group| block_group.free_inodes_count())
-            .sum();
-        let free_blocks_count = self
-            .group_descriptor_table_mut()
-            .iter_mut()
-            .map(|block_group| u64::from(block_group.free_blocks_count()))
-            .sum();
-        self.superblock_mut().set_free_blocks_count(free_blocks_count);
+    /// Returns the inode `inode_no` that was allocated before, e.g. to add a hard link to it. Returns an error if it
+    /// has not been allocated.
+    /// SAFETY: Undefined behavior if another `Inode` for `inode_no` is still in use.
+    /// PANICS: Panics if an inode with number `inode_no` does not exist.
+    pub unsafe fn allocated_inode(&mut self, inode_no: InodeNo) -> Result<Inode<'a>> {
+        let inode_size = self.superblock().s_inode_size;
+        let inodes_per_group = self.superblock().s_inodes_per_group;
+        let (block_group_idx, relative_inode_no) = (inode_no - FIRST_EXISTING_INODE).div_rem(&inodes_per_group);
+        let block_group = &mut self.block_groups[usize::fromx(block_group_idx)];
+        // SAFETY: Safe because the caller guarantees that no other `Inode` for `inode_no` is in use.
+        let (inner, xattr_space) = unsafe { block_group.allocated_relative_inode(relative_inode_no, inode_size)? };
+        Ok(Inode { inode_no, inner, xattr_space })
     }
 
-    fn backup_superblock_and_gdt(&mut self) {
-        let superblock = *self.superblock();
-        let gdt = self.group_descriptor_table_mut().to_vec();
+    pub fn stats(&self) -> Ext4FsStats {
+        let gdt = self.group_descriptor_table();
+        let used_dirs_count: u32 = gdt.iter().map(Ext4GroupDescriptor::used_dirs_count).sum();
+        // every allocated inode except lost+found belongs to a converted file; the root inode is not allocated
+        let regular_file_count = self.allocated_inode_count() - (used_dirs_count - 1);
+        Ext4FsStats {
+            directory_count: used_dirs_count - 2,
+            regular_file_count,
+            free_block_count: self.free_block_count(),
+            free_inode_count: self.free_inode_count(),
+            block_size: self.block_size(),
+        }
+    }
 
-        for backup_group_idx in superblock.backup_bgs() {
-            let block_group = &mut self.block_groups[usize::fromx(backup_group_idx)];
+    fn free_inode_count(&self) -> InodeCount {
+        self.group_descriptor_table()
+            .iter()
+            .map(Ext4GroupDescriptor::free_inodes_count)
+            .sum()
+    }
 
-            block_group
-                .superblock
-                .as_deref_mut()
-                .expect("ext4 backup block group has no superblock")
-                .write(superblock);
+    /// Unlike the group descriptors, this counts free blocks, not free clusters
+    fn free_block_count(&self) -> u64 {
+        let free_cluster_count: u64 = self
+            .group_descriptor_table()
+            .iter()
+            .map(|block_group| u64::from(block_group.free_blocks_count()))
+            .sum();
+        free_cluster_count * u64::from(self.blocks_per_cluster())
+    }
 
-            let gdt_backup = block_group.gdt.as_deref_mut().expect("ext4 backup block group has no GDT");
-            MaybeUninit::write_slice(gdt_backup, &gdt);
-        }
+    fn update_superblock(&mut self) {
+        self.superblock_mut().s_free_inodes_count = self.free_inode_count();
+        let free_block_count = self.free_block_count();
+        self.superblock_mut().set_free_blocks_count(free_block_count);
     }
-}
 
-impl Drop for Ext4Fs<'_> {
-    fn drop(&mut self) {
+    /// Writes the final free counts to the superblock and copies the superblock and GDT to the backup block groups.
+    /// Returns an error if a backup does not match the original afterwards.
+    pub fn finalize(mut self) -> Result<()> {
+        self.finalized = true;
         self.update_superblock();
+        self.write_block_group_metadata();
         self.backup_superblock_and_gdt();
+        let result = self.verify_backups();
+        self.drop_metadata();
+        result
+    }
 
-        // Manually drop `MaybeUninit`s
+    /// Writes the block bitmaps of the block groups containing data, using the global rayon thread pool, and the
+    /// checksums of the group descriptors.
+    fn write_block_group_metadata(&mut self) {
+        // the bitmaps of different block groups are disjoint, so they can be written by different threads
+        self.block_groups.par_iter_mut().for_each(BlockGroup::write_block_bitmap);
+
+        let uuid = self.superblock().s_uuid;
+        for (block_group_idx, descriptor) in (0..).zip(self.group_descriptor_table_mut()) {
+            descriptor.update_checksum(&uuid, block_group_idx);
+        }
+    }
+
+    /// Manually drops the `MaybeUninit`s, which must be called exactly once after `backup_superblock_and_gdt`.
+    fn drop_metadata(&mut self) {
         let mut block_groups_with_superblocks = vec![0];
         block_groups_with_superblocks.extend(self.superblock().backup_bgs());
         for block_group_idx in block_groups_with_superblocks {
@@ -244,4 +429,285 @@ impl Drop for Ext4Fs<'_> {
             }
         }
     }
+
+    fn backup_superblock_and_gdt(&mut self) {
+        let superblock = *self.superblock();
+        let gdt = self.group_descriptor_table_mut().to_vec();
+
+        for backup_group_idx in superblock.backup_bgs() {
+            let block_group = &mut self.block_groups[usize::fromx(backup_group_idx)];
+
+            block_group
+                .superblock
+                .as_deref_mut()
+                .expect("ext4 backup block group has no superblock")
+                .write(superblock);
+
+            let gdt_backup = block_group.gdt.as_deref_mut().expect("ext4 backup block group has no GDT");
+            MaybeUninit::write_slice(gdt_backup, &gdt);
+        }
+    }
+
+    /// Checks that every backup superblock and GDT lies within its block group's overhead and is identical to the
+    /// original.
+    fn verify_backups(&self) -> Result<()> {
+        let superblock = self.superblock();
+        let overhead_ranges = superblock.block_group_overhead_ranges();
+        fn superblock_bytes<'b>(block_group: &'b BlockGroup) -> &'b [u8] {
+            let superblock = block_group.superblock.as_deref().expect("Block group has no superblock");
+            // SAFETY: safe because we initialized the superblocks in `from` and `backup_superblock_and_gdt`
+            unsafe { as_bytes(slice::from_ref(superblock)) }
+        }
+        fn gdt_bytes<'b>(block_group: &'b BlockGroup) -> &'b [u8] {
+            let gdt = block_group.gdt.as_deref().expect("Block group has no GDT");
+            // SAFETY: safe because we initialized the GDTs in `from` and `backup_superblock_and_gdt`
+            unsafe { as_bytes(gdt) }
+        }
+        let original_superblock = superblock_bytes(&self.block_groups[0]);
+        let original_gdt = gdt_bytes(&self.block_groups[0]);
+
+        for backup_group_idx in superblock.backup_bgs() {
+            let block_group = &self.block_groups[usize::fromx(backup_group_idx)];
+            let backups = [
+                ("superblock", superblock_bytes(block_group), original_superblock),
+                ("GDT", gdt_bytes(block_group), original_gdt),
+            ];
+            for (name, backup, original) in backups {
+                let block_range = self.block_range_of(backup);
+                let within_overhead = overhead_ranges
+                    .split_overlapping(block_range.clone())
+                    .iter()
+                    .all(|(_, is_overhead)| *is_overhead);
+                if !within_overhead {
+                    bail!(
+                        "The backup {} of block group {} (blocks {:?}) lies outside of the block group overhead",
+                        name,
+                        backup_group_idx,
+                        block_range
+                    );
+                }
+                if backup != original {
+                    bail!(
+                        "The backup {} of block group {} does not match the original",
+                        name,
+                        backup_group_idx
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the range of blocks containing `bytes`, which must be part of the partition.
+    fn block_range_of(&self, bytes: &[u8]) -> Range<BlockIdx> {
+        let start_byte = bytes.as_ptr() as usize - self.partition_ptr as usize;
+        let block_size = usize::fromx(self.block_size());
+        start_byte / block_size..(start_byte + bytes.len()).div_ceil(&block_size)
+    }
+}
+
+/// Fallback in case `finalize` is not called, e.g. because the conversion failed: the superblock and its backups are
+/// written anyway so the filesystem is as consistent as possible, but the backups are not verified.
+impl Drop for Ext4Fs<'_> {
+    fn drop(&mut self) {
+        if !self.finalized {
+            self.update_superblock();
+            self.write_block_group_metadata();
+            self.backup_superblock_and_gdt();
+            self.drop_metadata();
+        }
+    }
+}
+
+/// SAFETY: Safe if `values` are initialized and `T` has no padding.
+unsafe fn as_bytes<T>(values: &[MaybeUninit<T>]) -> &[u8] {
+    // SAFETY: Safe because the slice covers exactly the memory of `values`, which the caller guarantees is initialized.
+    unsafe { slice::from_raw_parts(values.as_ptr() as *const u8, size_of_val(values)) }
+}
+
+/// Returns a random number, taken from the random part of a version 4 UUID.
+fn random_u32() -> u32 {
+    let uuid = Uuid::new_v4();
+    u32::from_le_bytes(uuid.as_bytes()[..4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diff_meta::read_inode;
+    use crate::ext4::{InodeInner, InodeOverrides, SpecialFile, DEFAULT_INODE_RATIO};
+    use crate::serialization::DentryRepresentation;
+
+    const FS_SIZE: usize = 32 << 20;
+    const BLOCK_SIZE: BlockSize = 1024; // yields several block groups in `FS_SIZE`
+
+    #[test]
+    fn finalize_accepts_intact_backups() {
+        let mut memory = vec![0_u64; FS_SIZE / size_of::<u64>()];
+        let superblock = SuperBlock::new(FS_SIZE, BLOCK_SIZE, BLOCK_SIZE, DEFAULT_INODE_RATIO, &[], 0, None).unwrap();
+        assert!(superblock.backup_bgs().next().is_some());
+        // SAFETY: safe because `memory` outlives `ext_fs`
+        let ext_fs = unsafe { Ext4Fs::from(memory.as_mut_ptr() as *mut u8, superblock) };
+        ext_fs.finalize().unwrap();
+    }
+
+    #[test]
+    fn invalid_allocations_are_errors() {
+        let mut memory = vec![0_u64; FS_SIZE / size_of::<u64>()];
+        let superblock = SuperBlock::new(FS_SIZE, BLOCK_SIZE, BLOCK_SIZE, DEFAULT_INODE_RATIO, &[], 0, None).unwrap();
+        // SAFETY: safe because `memory` outlives `ext_fs`
+        let mut ext_fs = unsafe { Ext4Fs::from(memory.as_mut_ptr() as *mut u8, superblock) };
+        let mut root = ext_fs.build_root_inode().unwrap();
+        assert!(ext_fs.build_root_inode().is_err());
+
+        let second_group_start = superblock.block_group_start_cluster(1);
+        assert!(ext_fs
+            .mark_range_as_used(&mut root, second_group_start - 1..second_group_start + 1)
+            .is_err());
+        let beyond_last_group = usize::fromx(u32::MAX);
+        assert!(ext_fs
+            .mark_range_as_used(&mut root, beyond_last_group..beyond_last_group + 1)
+            .is_err());
+        assert!(ext_fs.mark_range_as_used(&mut root, 1000..1000).is_err());
+        ext_fs.mark_range_as_used(&mut root, 1000..1010).unwrap();
+    }
+
+    #[test]
+    fn allocated_inodes_are_pristine() {
+        let mut memory = vec![u64::MAX; FS_SIZE / size_of::<u64>()];
+        let superblock = SuperBlock::new(FS_SIZE, BLOCK_SIZE, BLOCK_SIZE, DEFAULT_INODE_RATIO, &[], 0, None).unwrap();
+        // SAFETY: safe because `memory` outlives `ext_fs`
+        let mut ext_fs = unsafe { Ext4Fs::from(memory.as_mut_ptr() as *mut u8, superblock) };
+        let root = ext_fs.build_root_inode().unwrap();
+        assert_eq!((root.inner.i_dtime, root.inner.i_generation), (0, 0));
+
+        ext_fs.set_randomize_generation(true);
+        let generations: Vec<_> = (0..4)
+            .map(|_| {
+                let inode = ext_fs.allocate_inode(false).unwrap();
+                assert_eq!(inode.inner.i_dtime, 0);
+                inode.inner.i_generation
+            })
+            .collect();
+        // the chance of four random generation numbers being equal is negligible
+        assert!(generations.iter().any(|&generation| generation != generations[0]));
+    }
+
+    #[test]
+    fn only_used_block_groups_are_written() {
+        let superblock = SuperBlock::new(FS_SIZE, BLOCK_SIZE, BLOCK_SIZE, DEFAULT_INODE_RATIO, &[], 0, None).unwrap();
+        let convert = |thread_count| {
+            // not zero, so that every write is noticed
+            let mut memory = vec![u64::MAX; FS_SIZE / size_of::<u64>()];
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(thread_count).build().unwrap();
+            pool.install(|| {
+                // SAFETY: safe because `memory` outlives `ext_fs`
+                let mut ext_fs = unsafe { Ext4Fs::from(memory.as_mut_ptr() as *mut u8, superblock) };
+                let mut root = ext_fs.build_root_inode().unwrap();
+                let start_cluster = superblock.block_group_start_cluster(2);
+                ext_fs
+                    .mark_range_as_used(&mut root, start_cluster + 1000..start_cluster + 1010)
+                    .unwrap();
+                ext_fs.finalize()
+            })
+            .unwrap();
+            memory
+        };
+        let memory = convert(4);
+        assert!(memory == convert(1));
+
+        let bitmaps_and_inode_table = |block_group_idx| {
+            let info = Ext4BlockGroupConstructionInfo::new(&superblock, block_group_idx);
+            let block_size = usize::fromx(BLOCK_SIZE);
+            let end_block = info.inode_table_start_block + info.inode_table_block_count;
+            // SAFETY: safe because `memory` consists of `FS_SIZE` initialized bytes
+            let bytes = unsafe { slice::from_raw_parts(memory.as_ptr() as *const u8, FS_SIZE) };
+            &bytes[info.block_bitmap_block * block_size..end_block * block_size]
+        };
+        // block group 1 has neither data nor inodes
+        assert!(bitmaps_and_inode_table(1).iter().all(|&byte| byte == u8::MAX));
+        // block group 2 only has data
+        let block_group_2 = bitmaps_and_inode_table(2);
+        let block_size = usize::fromx(BLOCK_SIZE);
+        assert!(block_group_2[..block_size].iter().any(|&byte| byte != u8::MAX));
+        assert!(block_group_2[block_size..].iter().all(|&byte| byte == u8::MAX));
+    }
+
+    #[test]
+    fn special_files_can_be_read_back() {
+        let mut memory = vec![0_u64; FS_SIZE / size_of::<u64>()];
+        let superblock = SuperBlock::new(FS_SIZE, BLOCK_SIZE, BLOCK_SIZE, DEFAULT_INODE_RATIO, &[], 0, None).unwrap();
+        // SAFETY: safe because `memory` outlives `ext_fs`
+        let mut ext_fs = unsafe { Ext4Fs::from(memory.as_mut_ptr() as *mut u8, superblock) };
+        ext_fs.build_root_inode().unwrap();
+        let dentry = DentryRepresentation {
+            access_time: 0,
+            create_time: 0,
+            create_time_ns: 0,
+            mod_time: 0,
+            file_size: 0,
+            is_dir: false,
+            is_read_only: false,
+            overrides: InodeOverrides::default(),
+            has_xattrs: false,
+        };
+        // the file type in the mode and the first two words of `i_block`
+        let special_files = [
+            (SpecialFile::CharDevice { major: 4, minor: 64 }, 0o020_000, [0x0440, 0]),
+            (
+                SpecialFile::BlockDevice { major: 259, minor: 0x12345 },
+                0o060_000,
+                [0, 0x45 | 259 << 8 | 0x12300 << 12],
+            ),
+            (SpecialFile::Fifo, 0o010_000, [0, 0]),
+            (SpecialFile::Socket, 0o140_000, [0, 0]),
+        ];
+        let inode_nos: Vec<_> = special_files
+            .iter()
+            .map(|&(special_file, _, _)| {
+                let mut inode = ext_fs.allocate_inode(false).unwrap();
+                inode.init_from_dentry(dentry, Owner::ROOT);
+                inode.init_special_file(special_file);
+                inode.inode_no
+            })
+            .collect();
+        ext_fs.finalize().unwrap();
+
+        // SAFETY: safe because `memory` consists of `FS_SIZE` initialized bytes
+        let partition = unsafe { slice::from_raw_parts(memory.as_ptr() as *const u8, FS_SIZE) };
+        for (inode_no, (_, file_type, device_number)) in inode_nos.into_iter().zip(special_files) {
+            let inner = read_inode(partition, inode_no).unwrap();
+            assert_eq!(inner.i_mode & 0o170_000, file_type);
+            assert_eq!((inner.i_size_lo, inner.i_size_high, inner.i_flags), (0, 0, 0));
+            assert_eq!(i_block_words(&inner)[..3], [device_number[0], device_number[1], 0]);
+        }
+    }
+
+    fn i_block_words(inner: &InodeInner) -> Vec<u32> {
+        // SAFETY: Safe because the slice covers exactly the memory of `inner.extents`, which consists only of integers.
+        let i_block =
+            unsafe { slice::from_raw_parts(inner.extents.as_ptr() as *const u8, size_of_val(&inner.extents)) };
+        i_block
+            .chunks_exact(4)
+            .map(|word| u32::from_le_bytes(<[u8; 4]>::try_from(word).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn detects_corrupted_backup() {
+        let mut memory = vec![0_u64; FS_SIZE / size_of::<u64>()];
+        let superblock = SuperBlock::new(FS_SIZE, BLOCK_SIZE, BLOCK_SIZE, DEFAULT_INODE_RATIO, &[], 0, None).unwrap();
+        // SAFETY: safe because `memory` outlives `ext_fs`
+        let mut ext_fs = unsafe { Ext4Fs::from(memory.as_mut_ptr() as *mut u8, superblock) };
+        ext_fs.update_superblock();
+        ext_fs.backup_superblock_and_gdt();
+        ext_fs.verify_backups().unwrap();
+
+        let backup_group_idx = usize::fromx(superblock.backup_bgs().last().unwrap());
+        let gdt = ext_fs.block_groups[backup_group_idx].gdt.as_deref_mut().unwrap();
+        // SAFETY: safe because `backup_superblock_and_gdt` initialized the backup GDT
+        let descriptor = unsafe { gdt[0].assume_init_mut() };
+        *descriptor = Ext4GroupDescriptor::new(Ext4BlockGroupConstructionInfo::new(&superblock, 1));
+        assert!(ext_fs.verify_backups().is_err());
+    }
 }
@@ -1,7 +1,21 @@
-use crate::ext4::{Ext4BlockGroupConstructionInfo, InodeCount, SPECIAL_INODES};
+use std::mem::size_of;
+use std::slice;
+
+use crate::ext4::{
+    BlockGroupIdx, Ext4BlockGroupConstructionInfo, InodeCount, FIRST_EXISTING_INODE, FIRST_NON_RESERVED_INODE,
+    SPECIAL_INODES,
+};
 use crate::lohi::{LoHi, LoHiMut};
 use crate::util::FromUsize;
 
+// bg_flags
+pub const INODE_UNINIT: u16 = 0x1; // the inode bitmap and inode table are not initialized
+const BLOCK_UNINIT: u16 = 0x2; // the block bitmap is not initialized
+const INODE_TABLE_ZEROED: u16 = 0x4;
+
+/// The offset of `bg_checksum`, which is not part of the data it checksums
+const CHECKSUM_OFFSET: usize = 0x1E;
+
 #[repr(C)]
 #[derive(Debug, Copy, Clone, Default)]
 pub struct Ext4GroupDescriptor {
@@ -35,15 +49,29 @@ impl Ext4GroupDescriptor {
         let block_bitmap_block = u64::fromx(info.block_bitmap_block);
         let inode_bitmap_block = u64::fromx(info.inode_bitmap_block);
         let inode_table_start_block = u64::fromx(info.inode_table_start_block);
-        let special_inode_count = if info.is_first_block_group {
-            SPECIAL_INODES.len() as u32
+        let (special_inode_count, reserved_inode_count) = if info.is_first_block_group {
+            (SPECIAL_INODES.len() as u32, FIRST_NON_RESERVED_INODE - FIRST_EXISTING_INODE)
         } else {
-            0
+            (0, 0)
         };
         let free_inodes_count = info.inodes_count - special_inode_count;
-        let free_blocks_count = info.blocks_count - info.overhead;
+        // despite its name, the free blocks count is given in clusters
+        let free_blocks_count = info.cluster_count() - info.overhead_cluster_count();
+
+        // `BlockGroup` initializes the bitmaps and the inode table once the block group is used, except for the inodes
+        // of the first block group and the block bitmap of the last one, whose padding must always be written
+        let mut bg_flags = if info.is_first_block_group {
+            INODE_TABLE_ZEROED
+        } else {
+            INODE_UNINIT
+        };
+        if !info.is_last_block_group {
+            bg_flags |= BLOCK_UNINIT;
+        }
 
-        let mut instance = Self::default(); // zero every field
+        let mut instance = Self { bg_flags, ..Self::default() }; // zero every other field
+        LoHiMut::new(&mut instance.bg_itable_unused_lo, &mut instance.bg_itable_unused_hi)
+            .set(info.inodes_count - reserved_inode_count);
         LoHiMut::new(&mut instance.bg_block_bitmap_lo, &mut instance.bg_block_bitmap_hi).set(block_bitmap_block);
         LoHiMut::new(&mut instance.bg_inode_bitmap_lo, &mut instance.bg_inode_bitmap_hi).set(inode_bitmap_block);
         LoHiMut::new(&mut instance.bg_inode_table_lo, &mut instance.bg_inode_table_hi).set(inode_table_start_block);
@@ -62,6 +90,39 @@ impl Ext4GroupDescriptor {
         LoHi::new(&self.bg_free_blocks_count_lo, &self.bg_free_blocks_count_hi).get()
     }
 
+    pub fn used_dirs_count(&self) -> u32 {
+        LoHi::new(&self.bg_used_dirs_count_lo, &self.bg_used_dirs_count_hi).get()
+    }
+
+    pub fn itable_unused(&self) -> InodeCount {
+        LoHi::new(&self.bg_itable_unused_lo, &self.bg_itable_unused_hi).get()
+    }
+
+    /// Called when the block group's block bitmap is used, i.e. the block group contains data.
+    pub fn mark_block_bitmap_used(&mut self) {
+        self.bg_flags &= !BLOCK_UNINIT;
+    }
+
+    /// Called when the inode with number `relative_inode_no` within the block group is allocated. `inodes_count` is
+    /// the number of inodes in the block group.
+    pub fn mark_inode_used(&mut self, relative_inode_no: InodeCount, inodes_count: InodeCount) {
+        self.bg_flags = (self.bg_flags & !INODE_UNINIT) | INODE_TABLE_ZEROED;
+        // the inodes after the last used one are unused
+        let itable_unused = self.itable_unused().min(inodes_count - relative_inode_no - 1);
+        LoHiMut::new(&mut self.bg_itable_unused_lo, &mut self.bg_itable_unused_hi).set(itable_unused);
+    }
+
+    /// Computes `bg_checksum` as required by the `uninit_bg` feature. `uuid` is the filesystem's UUID.
+    pub fn update_checksum(&mut self, uuid: &[u8; 16], block_group_idx: BlockGroupIdx) {
+        // SAFETY: Safe because `Ext4GroupDescriptor` consists only of integers and has no padding.
+        let bytes = unsafe { slice::from_raw_parts(self as *const Self as *const u8, size_of::<Self>()) };
+        let mut crc = crc16(!0, uuid);
+        crc = crc16(crc, &block_group_idx.to_le_bytes());
+        crc = crc16(crc, &bytes[..CHECKSUM_OFFSET]);
+        crc = crc16(crc, &bytes[CHECKSUM_OFFSET + size_of::<u16>()..]);
+        self.bg_checksum = crc;
+    }
+
     pub fn decrement_free_blocks_count(&mut self, count: u32) {
         let mut free_blocks = LoHiMut::new(&mut self.bg_free_blocks_count_lo, &mut self.bg_free_blocks_count_hi);
         free_blocks -= count;
@@ -77,3 +138,26 @@ impl Ext4GroupDescriptor {
         used_dirs += 1;
     }
 }
+
+/// The CRC-16 used by ext4 (reflected polynomial 0x8005, i.e. CRC-16/ARC without the final XOR), computed bitwise since
+/// it is only used for the group descriptors.
+fn crc16(mut crc: u16, data: &[u8]) -> u16 {
+    for &byte in data {
+        crc ^= u16::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xA001 } else { crc >> 1 };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn computes_crc16() {
+        // the check value of CRC-16/ARC, which starts at 0 instead of !0
+        assert_eq!(crc16(0, b"123456789"), 0xBB3D);
+    }
+}
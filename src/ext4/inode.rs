@@ -1,27 +1,54 @@
 use std::convert::TryFrom;
+use std::mem::{size_of, size_of_val};
+use std::slice;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use chrono::prelude::*;
 use nix::unistd::{getegid, geteuid};
+use serde::{Deserialize, Serialize};
+use static_assertions::const_assert_eq;
 
-use crate::allocator::Allocator;
 use crate::ext4::{
-    BlockCount, BlockIdx, BlockSize, Extent, ExtentHeader, ExtentTree, ExtentTreeElement, ExtentTreeLevel, InodeNo,
+    BlockCount, BlockIdx, BlockSize, Extent, ExtentBlockAllocator, ExtentHeader, ExtentTree, ExtentTreeElement,
+    ExtentTreeLevel, FileType, InodeNo,
 };
-use crate::lohi::LoHiMut;
+use crate::lohi::{LoHi, LoHiMut};
 use crate::serialization::DentryRepresentation;
-use crate::util::FromUsize;
+use crate::util::{FromU32, FromUsize};
 
+/// The entries of the extent tree's root in `i_block`: the header and up to 4 extents or extent indices
 pub const EXTENT_ENTRIES_IN_INODE: u16 = 5;
 pub const EXT2_LINK_MAX: u16 = 65_000;
 pub const NON_REPRESENTABLE_LINK_COUNT: u16 = 1;
+/// The size of the inode fields beyond the 128 bytes of the original ext2 inode, which include the creation time. The
+/// superblock requires every inode to have at least this many extra bytes, otherwise the kernel expands each inode the
+/// first time it is written.
+pub const INODE_EXTRA_ISIZE: u16 = (size_of::<InodeInner>() - EXT2_GOOD_OLD_INODE_SIZE) as u16;
+/// A fast symlink stores its target in place of the extent tree, which must be longer than the target.
+pub const FAST_SYMLINK_MAX_LEN: usize = size_of::<[ExtentTreeElement; EXTENT_ENTRIES_IN_INODE as usize]>() - 1;
+
+const EXT2_GOOD_OLD_INODE_SIZE: usize = 128;
+/// The size of `i_block`, which holds the root of the extent tree
+const I_BLOCK_LEN: usize = 60;
+// the root of the extent tree uses all of `i_block`, so that a file with 4 extents needs no extent tree block
+const_assert_eq!(size_of::<[ExtentTreeElement; EXTENT_ENTRIES_IN_INODE as usize]>(), I_BLOCK_LEN);
+/// The low bits of the `_extra` timestamp fields, which extend the seconds beyond 32 bits. The remaining bits hold the
+/// nanoseconds.
+const TIMESTAMP_EPOCH_BITS: u32 = 2;
 
 // i_flags
-const INODE_USES_EXTENTS: u32 = 0x00080000;
+pub const INODE_USES_EXTENTS: u32 = 0x00080000;
 
 // i_mode
+const FILE_TYPE_MASK: u16 = 0o170_000;
+const FIFO_FLAG: u16 = 0o010_000;
+const CHAR_DEVICE_FLAG: u16 = 0o020_000;
 const DIR_FLAG: u16 = 0o040_000;
+const BLOCK_DEVICE_FLAG: u16 = 0o060_000;
 const REG_FLAG: u16 = 0o100_000;
+const SYMLINK_FLAG: u16 = 0o120_000;
+const SOCKET_FLAG: u16 = 0o140_000;
+const SYMLINK_PERMS: u16 = 0o777;
 const READ_USER: u16 = 0o000_400;
 const READ_GROUP: u16 = 0o000_040;
 const READ_OTHERS: u16 = 0o000_004;
@@ -34,9 +61,85 @@ const EXECUTE_OTHERS: u16 = 0o000_001;
 const NO_WRITE_PERMS: u16 = READ_USER | READ_GROUP | READ_OTHERS | EXECUTE_USER | EXECUTE_GROUP | EXECUTE_OTHERS;
 const DEFAULT_PERMS: u16 = NO_WRITE_PERMS | WRITE_USER;
 
+/// A file without data. FAT cannot represent these, but other sources may.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SpecialFile {
+    CharDevice { major: u32, minor: u32 },
+    BlockDevice { major: u32, minor: u32 },
+    Fifo,
+    Socket,
+}
+
+impl SpecialFile {
+    /// The file type for the file's dentry
+    pub fn file_type(self) -> FileType {
+        match self {
+            Self::CharDevice { .. } => FileType::CharDevice,
+            Self::BlockDevice { .. } => FileType::BlockDevice,
+            Self::Fifo => FileType::Fifo,
+            Self::Socket => FileType::Socket,
+        }
+    }
+
+    fn mode_flag(self) -> u16 {
+        match self {
+            Self::CharDevice { .. } => CHAR_DEVICE_FLAG,
+            Self::BlockDevice { .. } => BLOCK_DEVICE_FLAG,
+            Self::Fifo => FIFO_FLAG,
+            Self::Socket => SOCKET_FLAG,
+        }
+    }
+
+    /// Returns the first two words of `i_block` for a device file, encoded like the Linux kernel does: device numbers
+    /// that fit into 8 bits each use the old format in the first word, all others the new format in the second.
+    fn encoded_device_number(self) -> [u32; 2] {
+        let (major, minor) = match self {
+            Self::CharDevice { major, minor } | Self::BlockDevice { major, minor } => (major, minor),
+            Self::Fifo | Self::Socket => return [0, 0],
+        };
+        if major < 256 && minor < 256 {
+            [(major << 8) | minor, 0]
+        } else {
+            [0, (minor & 0xFF) | (major << 8) | ((minor & !0xFF) << 12)]
+        }
+    }
+}
+
+/// The user and group that own a file
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Owner {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl Owner {
+    /// root, which owns lost+found like in mke2fs
+    pub const ROOT: Self = Self { uid: 0, gid: 0 };
+
+    /// The effective user and group of the converter, which own the converted files by default. This does not consult
+    /// the user and group databases.
+    pub fn effective() -> Self {
+        Self {
+            uid: u32::from(geteuid()),
+            gid: u32::from(getegid()),
+        }
+    }
+}
+
+/// What a `ConversionPolicy` decided for a file instead of the defaults of the conversion
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct InodeOverrides {
+    /// the owner, or None for the owner of the converted files
+    pub owner: Option<Owner>,
+    /// the permission bits of `i_mode`, or None for 755, or 555 if the file is read-only
+    pub perms: Option<u16>,
+}
+
 pub struct Inode<'a> {
     pub inode_no: InodeNo,
     pub inner: &'a mut InodeInner,
+    /// the rest of the inode's slot in the inode table, see `IN_INODE_XATTR_LEN`
+    pub xattr_space: &'a mut [u8],
 }
 
 #[repr(C)]
@@ -77,20 +180,55 @@ pub struct InodeInner {
 }
 
 impl<'a> Inode<'a> {
-    pub fn init_from_dentry(&mut self, dentry: DentryRepresentation) {
-        self.inner.init_from_dentry(dentry);
+    pub fn init_from_dentry(&mut self, dentry: DentryRepresentation, owner: Owner) {
+        self.inner.init_from_dentry(dentry, owner);
     }
 
     pub fn init_lost_found(&mut self) {
         self.inner.init_lost_found();
     }
-    pub fn init_root(&mut self) {
-        self.inner.init_root();
+    pub fn init_root(&mut self, owner: Owner) {
+        self.inner.init_root(owner);
+    }
+
+    /// Initializes an empty directory that did not exist in the FAT filesystem, with the permission bits `perms`.
+    pub fn init_new_dir(&mut self, perms: u16, owner: Owner) {
+        self.inner.init_new_dir(perms, owner);
+    }
+
+    /// Turns an inode initialized by `init_from_dentry` into a fast symlink to `target`.
+    /// PANICS: Panics if `target` is longer than `FAST_SYMLINK_MAX_LEN`.
+    pub fn init_fast_symlink(&mut self, target: &[u8]) {
+        self.inner.init_fast_symlink(target);
+        self.set_size(u64::fromx(target.len()));
     }
 
-    pub fn increment_size(&mut self, size: u64) {
-        let mut current_size = LoHiMut::new(&mut self.inner.i_size_lo, &mut self.inner.i_size_high);
-        current_size += size;
+    /// Turns an inode initialized by `init_from_dentry` into a slow symlink to a target of `target_len` bytes. The
+    /// caller must add the extent of the block containing the target.
+    pub fn init_slow_symlink(&mut self, target_len: usize) {
+        self.inner.i_mode = SYMLINK_FLAG | SYMLINK_PERMS;
+        self.set_size(u64::fromx(target_len));
+    }
+
+    /// Turns an inode initialized by `init_from_dentry` into a device file, FIFO or socket. Such a file has no data,
+    /// so its permissions are the only part of the dentry that is kept.
+    pub fn init_special_file(&mut self, special_file: SpecialFile) {
+        self.inner.init_special_file(special_file);
+        self.set_size(0);
+    }
+
+    /// Returns the number of clusters that a symlink to a target of `target_len` bytes occupies: none for a fast
+    /// symlink, otherwise one cluster, whose first block contains the target.
+    pub fn symlink_cluster_count(target_len: usize, block_size: BlockSize) -> Result<BlockCount> {
+        if target_len == 0 {
+            bail!("Symlink target is empty");
+        } else if target_len <= FAST_SYMLINK_MAX_LEN {
+            Ok(0)
+        } else if target_len < usize::fromx(block_size) {
+            Ok(1)
+        } else {
+            bail!("Symlink target is longer than {} bytes", block_size - 1);
+        }
     }
 
     pub fn set_size(&mut self, size: u64) {
@@ -115,11 +253,22 @@ impl<'a> Inode<'a> {
         }
     }
 
-    pub fn add_extent(&mut self, extent: Extent, allocator: &Allocator<'_>) -> Result<Vec<BlockIdx>> {
+    /// Accounts for one more dentry referring to this regular file. Returns an error if the link count would exceed
+    /// `EXT2_LINK_MAX`.
+    pub fn add_hard_link(&mut self) -> Result<()> {
+        match self.inner.i_links_count.checked_add(1).filter(|&count| count <= EXT2_LINK_MAX) {
+            Some(link_count) => self.inner.i_links_count = link_count,
+            None => bail!("Inode {} would have more than {} links", self.inode_no, EXT2_LINK_MAX),
+        }
+        Ok(())
+    }
+
+    /// Returns the indices of the clusters allocated for the extent tree.
+    pub fn add_extent(&mut self, extent: Extent, allocator: ExtentBlockAllocator<'_>) -> Result<Vec<BlockIdx>> {
         self.extent_tree(allocator).add_extent(extent)
     }
 
-    fn extent_tree<'b>(&'b mut self, allocator: &'b Allocator<'b>) -> ExtentTree<'b> {
+    fn extent_tree<'b>(&'b mut self, allocator: ExtentBlockAllocator<'b>) -> ExtentTree<'b> {
         // SAFETY: Safe because the extent tree in is consistent when `self.inner` is initialized, and is kept
         // consistent when adding extents via `ExtentTree::add_extent`.
         let root_level = unsafe { ExtentTreeLevel::new(&mut self.inner.extents) };
@@ -135,28 +284,23 @@ impl<'a> Inode<'a> {
 }
 
 impl InodeInner {
-    fn init_from_dentry(&mut self, dentry: DentryRepresentation) {
-        let user_id = u32::from(geteuid());
-        let group_id = u32::from(getegid());
-        LoHiMut::new(&mut self.i_uid, &mut self.l_i_uid_high).set(user_id);
-        LoHiMut::new(&mut self.i_gid, &mut self.l_i_gid_high).set(group_id);
+    fn init_from_dentry(&mut self, dentry: DentryRepresentation, owner: Owner) {
+        self.set_owner(dentry.overrides.owner.unwrap_or(owner));
         self.i_mode = Self::mode_from_dentry(&dentry);
         self.i_crtime = dentry.create_time;
+        self.i_crtime_extra = timestamp_extra(dentry.create_time_ns);
         self.i_atime = dentry.access_time;
         self.i_mtime = dentry.mod_time;
         self.i_ctime = self.i_mtime + 1; // mimic behavior of the Linux FAT driver
         self.i_links_count = 1;
         self.i_flags = INODE_USES_EXTENTS;
+        self.i_extra_isize = INODE_EXTRA_ISIZE;
         self.init_extent_header();
     }
 
     fn init_lost_found(&mut self) {
-        const ROOT_USER_ID: u32 = 0;
-        const ROOT_GROUP_ID: u32 = 0;
-
         let now = u32::try_from(Utc::now().timestamp()).unwrap();
-        LoHiMut::new(&mut self.i_uid, &mut self.l_i_uid_high).set(ROOT_USER_ID);
-        LoHiMut::new(&mut self.i_gid, &mut self.l_i_gid_high).set(ROOT_GROUP_ID);
+        self.set_owner(Owner::ROOT);
         self.i_mode = DEFAULT_PERMS | DIR_FLAG;
         self.i_crtime = 0;
         self.i_atime = now;
@@ -164,15 +308,27 @@ impl InodeInner {
         self.i_ctime = now;
         self.i_links_count = 1;
         self.i_flags = INODE_USES_EXTENTS;
+        self.i_extra_isize = INODE_EXTRA_ISIZE;
+        self.init_extent_header();
+    }
+
+    fn init_new_dir(&mut self, perms: u16, owner: Owner) {
+        let now = u32::try_from(Utc::now().timestamp()).unwrap();
+        self.set_owner(owner);
+        self.i_mode = (perms & !FILE_TYPE_MASK) | DIR_FLAG;
+        self.i_crtime = now;
+        self.i_atime = now;
+        self.i_mtime = now;
+        self.i_ctime = now;
+        self.i_links_count = 1;
+        self.i_flags = INODE_USES_EXTENTS;
+        self.i_extra_isize = INODE_EXTRA_ISIZE;
         self.init_extent_header();
     }
 
-    fn init_root(&mut self) {
+    fn init_root(&mut self, owner: Owner) {
         let now = u32::try_from(Utc::now().timestamp()).unwrap();
-        let user_id = u32::from(geteuid());
-        let group_id = u32::from(getegid());
-        LoHiMut::new(&mut self.i_uid, &mut self.l_i_uid_high).set(user_id);
-        LoHiMut::new(&mut self.i_gid, &mut self.l_i_gid_high).set(group_id);
+        self.set_owner(owner);
         self.i_mode = DEFAULT_PERMS | DIR_FLAG;
         self.i_crtime = 0;
         self.i_atime = now;
@@ -180,20 +336,183 @@ impl InodeInner {
         self.i_ctime = now;
         self.i_links_count = 0;
         self.i_flags = INODE_USES_EXTENTS;
+        self.i_extra_isize = INODE_EXTRA_ISIZE;
         self.init_extent_header();
     }
 
+    fn set_owner(&mut self, owner: Owner) {
+        LoHiMut::new(&mut self.i_uid, &mut self.l_i_uid_high).set(owner.uid);
+        LoHiMut::new(&mut self.i_gid, &mut self.l_i_gid_high).set(owner.gid);
+    }
+
+    fn init_fast_symlink(&mut self, target: &[u8]) {
+        debug_assert!(target.len() <= FAST_SYMLINK_MAX_LEN);
+        self.i_mode = SYMLINK_FLAG | SYMLINK_PERMS;
+        self.i_flags = 0;
+        let i_block = self.clear_i_block();
+        i_block[..target.len()].copy_from_slice(target);
+    }
+
+    fn init_special_file(&mut self, special_file: SpecialFile) {
+        self.i_mode = (self.i_mode & !FILE_TYPE_MASK) | special_file.mode_flag();
+        self.i_flags = 0;
+        let i_block = self.clear_i_block();
+        for (word, value) in i_block.chunks_exact_mut(4).zip(special_file.encoded_device_number()) {
+            word.copy_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    /// Zeroes and returns `i_block`, the memory that usually holds the extent tree root, for inodes without extents.
+    fn clear_i_block(&mut self) -> &mut [u8] {
+        // SAFETY: Safe because the slice covers exactly the memory of `self.extents`, which consists only of integers.
+        let i_block =
+            unsafe { slice::from_raw_parts_mut(self.extents.as_mut_ptr() as *mut u8, size_of_val(&self.extents)) };
+        i_block.fill(0);
+        i_block
+    }
+
     fn init_extent_header(&mut self) {
         self.extents[0].header = ExtentHeader::new(EXTENT_ENTRIES_IN_INODE);
     }
 
-    fn is_dir(&self) -> bool {
-        self.i_mode & DIR_FLAG != 0
+    pub fn is_dir(&self) -> bool {
+        self.i_mode & FILE_TYPE_MASK == DIR_FLAG
+    }
+
+    pub fn is_regular_file(&self) -> bool {
+        self.i_mode & FILE_TYPE_MASK == REG_FLAG
+    }
+
+    pub fn size(&self) -> u64 {
+        LoHi::new(&self.i_size_lo, &self.i_size_high).get()
     }
 
     fn mode_from_dentry(dentry: &DentryRepresentation) -> u16 {
-        let rwx = if dentry.is_read_only { NO_WRITE_PERMS } else { DEFAULT_PERMS };
+        let rwx = match dentry.overrides.perms {
+            Some(perms) => perms & !FILE_TYPE_MASK,
+            None if dentry.is_read_only => NO_WRITE_PERMS,
+            None => DEFAULT_PERMS,
+        };
         let dir = if dentry.is_dir { DIR_FLAG } else { REG_FLAG };
         rwx | dir
     }
 }
+
+/// Returns the `_extra` field of a timestamp whose seconds fit into 32 bits and whose fraction of a second is
+/// `nanoseconds`.
+pub fn timestamp_extra(nanoseconds: u32) -> u32 {
+    nanoseconds << TIMESTAMP_EPOCH_BITS
+}
+
+/// Returns the fraction of a second in nanoseconds of a timestamp whose `_extra` field is `extra`.
+pub fn nanoseconds_from_extra(extra: u32) -> u32 {
+    extra >> TIMESTAMP_EPOCH_BITS
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::MaybeUninit;
+
+    use super::*;
+
+    fn i_block_words(inner: &InodeInner) -> Vec<u32> {
+        // SAFETY: Safe because the slice covers exactly the memory of `inner.extents`, which consists only of integers.
+        let i_block =
+            unsafe { slice::from_raw_parts(inner.extents.as_ptr() as *const u8, size_of_val(&inner.extents)) };
+        i_block
+            .chunks_exact(4)
+            .map(|word| u32::from_le_bytes(<[u8; 4]>::try_from(word).unwrap()))
+            .collect()
+    }
+
+    fn special_inode(special_file: SpecialFile) -> InodeInner {
+        // SAFETY: Safe because `InodeInner` consists only of integers, for which all zeros is a valid value.
+        let mut inner = unsafe { MaybeUninit::<InodeInner>::zeroed().assume_init() };
+        inner.i_mode = DEFAULT_PERMS | REG_FLAG;
+        inner.init_extent_header();
+        inner.init_special_file(special_file);
+        inner
+    }
+
+    #[test]
+    fn encodes_small_device_number_in_old_format() {
+        let inner = special_inode(SpecialFile::BlockDevice { major: 8, minor: 1 });
+        assert_eq!(inner.i_mode, BLOCK_DEVICE_FLAG | DEFAULT_PERMS);
+        assert_eq!(inner.i_flags, 0);
+        assert_eq!(i_block_words(&inner)[..3], [0x0801, 0, 0]);
+    }
+
+    #[test]
+    fn encodes_large_device_number_in_new_format() {
+        let inner = special_inode(SpecialFile::CharDevice { major: 259, minor: 0x12345 });
+        assert_eq!(inner.i_mode, CHAR_DEVICE_FLAG | DEFAULT_PERMS);
+        assert_eq!(i_block_words(&inner)[..3], [0, 0x45 | 259 << 8 | 0x12300 << 12, 0]);
+    }
+
+    #[test]
+    fn keeps_fraction_of_creation_time() {
+        let dentry = DentryRepresentation {
+            access_time: 0,
+            create_time: 1_577_836_801,
+            create_time_ns: 990_000_000,
+            mod_time: 0,
+            file_size: 0,
+            is_dir: false,
+            is_read_only: false,
+            overrides: InodeOverrides::default(),
+            has_xattrs: false,
+        };
+        // SAFETY: Safe because `InodeInner` consists only of integers, for which all zeros is a valid value.
+        let mut inner = unsafe { MaybeUninit::<InodeInner>::zeroed().assume_init() };
+        inner.init_from_dentry(dentry, Owner::ROOT);
+        assert_eq!(inner.i_crtime, 1_577_836_801);
+        assert_eq!(inner.i_crtime_extra & ((1 << TIMESTAMP_EPOCH_BITS) - 1), 0);
+        assert_eq!(nanoseconds_from_extra(inner.i_crtime_extra), 990_000_000);
+    }
+
+    #[test]
+    fn overrides_replace_owner_and_permissions() {
+        let dentry = DentryRepresentation {
+            access_time: 0,
+            create_time: 0,
+            create_time_ns: 0,
+            mod_time: 0,
+            file_size: 0,
+            is_dir: true,
+            is_read_only: true,
+            overrides: InodeOverrides::default(),
+            has_xattrs: false,
+        };
+        // SAFETY: Safe because `InodeInner` consists only of integers, for which all zeros is a valid value.
+        let mut inner = unsafe { MaybeUninit::<InodeInner>::zeroed().assume_init() };
+        inner.init_from_dentry(dentry, Owner { uid: 1000, gid: 1000 });
+        assert_eq!(inner.i_mode, DIR_FLAG | NO_WRITE_PERMS);
+        assert_eq!((inner.i_uid, inner.i_gid), (1000, 1000));
+
+        let overrides = InodeOverrides {
+            owner: Some(Owner { uid: 33, gid: 34 }),
+            perms: Some(0o1750),
+        };
+        inner.init_from_dentry(DentryRepresentation { overrides, ..dentry }, Owner { uid: 1000, gid: 1000 });
+        assert_eq!(inner.i_mode, DIR_FLAG | 0o1750);
+        assert_eq!((inner.i_uid, inner.i_gid), (33, 34));
+    }
+
+    #[test]
+    fn owner_is_split_into_low_and_high_halves() {
+        // SAFETY: Safe because `InodeInner` consists only of integers, for which all zeros is a valid value.
+        let mut inner = unsafe { MaybeUninit::<InodeInner>::zeroed().assume_init() };
+        inner.init_root(Owner { uid: 100_000, gid: 65_536 });
+        assert_eq!((inner.i_uid, inner.l_i_uid_high), (0x86A0, 1));
+        assert_eq!((inner.i_gid, inner.l_i_gid_high), (0, 1));
+    }
+
+    #[test]
+    fn fifo_and_socket_have_no_device_number() {
+        for (special_file, flag) in [(SpecialFile::Fifo, FIFO_FLAG), (SpecialFile::Socket, SOCKET_FLAG)] {
+            let inner = special_inode(special_file);
+            assert_eq!(inner.i_mode & FILE_TYPE_MASK, flag);
+            assert!(i_block_words(&inner).iter().all(|&word| word == 0));
+        }
+    }
+}
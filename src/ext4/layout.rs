@@ -0,0 +1,134 @@
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::ext4::{BlockCount, BlockSize, SuperBlock, DEFAULT_INODE_RATIO};
+use crate::util::{FromU32, FromUsize};
+
+/// The parameters besides the filesystem size and block size that the ext4 metadata depends on
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OverheadOptions {
+    /// the bigalloc cluster size, or None without bigalloc
+    pub bigalloc_cluster_size: Option<BlockSize>,
+    /// the number of bytes per inode, a power of two
+    pub inode_ratio: u32,
+    /// whether the last block group lacks its superblock backup, like with `--allow-tight-fit`
+    pub tight_fit: bool,
+}
+
+impl Default for OverheadOptions {
+    fn default() -> Self {
+        Self {
+            bigalloc_cluster_size: None,
+            inode_ratio: DEFAULT_INODE_RATIO,
+            tight_fit: false,
+        }
+    }
+}
+
+/// The blocks that the ext4 metadata occupies, computed with the same math as the converter's superblock, so that
+/// frontends can show it before converting.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Overhead {
+    pub block_size: BlockSize,
+    /// the blocks of the filesystem, including the padding block
+    pub block_count: BlockCount,
+    /// the whole blocks at the end of the partition that are too few for another block group and stay unused
+    pub unused_block_count: BlockCount,
+    /// 1 if the block size is 1024 bytes, since the first block is then padding, 0 otherwise
+    pub padding_block_count: BlockCount,
+    /// the superblock (or its backup), group descriptors, bitmaps and inode table of each block group
+    pub block_group_block_counts: Vec<BlockCount>,
+}
+
+impl Overhead {
+    /// Computes the overhead of an ext4 filesystem on a partition of `fs_len` bytes. Returns an error if no ext4
+    /// filesystem with these parameters fits into the partition, like the conversion would.
+    pub fn compute(fs_len: usize, block_size: BlockSize, options: OverheadOptions) -> Result<Self> {
+        if !options.inode_ratio.is_power_of_two() {
+            bail!("The inode ratio must be a power of two");
+        }
+        let cluster_size = options.bigalloc_cluster_size.unwrap_or(block_size);
+        if cluster_size < block_size {
+            bail!("The bigalloc cluster size must not be smaller than the block size");
+        }
+        let mut superblock = SuperBlock::new(fs_len, block_size, cluster_size, options.inode_ratio, &[], 0, None)?;
+        if options.tight_fit {
+            superblock.remove_last_backup();
+        }
+        Ok(Self::from_superblock(&superblock, fs_len))
+    }
+
+    /// Returns the overhead of the filesystem described by `superblock` on a partition of `fs_len` bytes.
+    pub fn from_superblock(superblock: &SuperBlock, fs_len: usize) -> Self {
+        let block_group_block_counts = (0..superblock.block_group_count())
+            .map(|block_group_idx| {
+                superblock.block_group_overhead(superblock.block_group_has_superblock(block_group_idx))
+            })
+            .collect();
+        let block_count = superblock.block_count_with_padding();
+        Self {
+            block_size: superblock.block_size(),
+            block_count,
+            unused_block_count: fs_len / BlockCount::fromx(superblock.block_size()) - block_count,
+            padding_block_count: superblock.first_usable_block(),
+            block_group_block_counts,
+        }
+    }
+
+    /// The blocks that cannot hold file data, including the unused blocks at the end of the partition
+    pub fn total_block_count(&self) -> BlockCount {
+        self.padding_block_count + self.block_group_block_counts.iter().sum::<BlockCount>() + self.unused_block_count
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        u64::fromx(self.total_block_count()) * u64::from(self.block_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ext4::HasSuperBlock;
+
+    const MIB: usize = 1024 * 1024;
+
+    #[test]
+    fn overhead_matches_superblock() {
+        let superblock = SuperBlock::new(32 * MIB + 3000, 1024, 1024, DEFAULT_INODE_RATIO, &[], 0, None).unwrap();
+        let overhead = Overhead::compute(32 * MIB + 3000, 1024, OverheadOptions::default()).unwrap();
+        assert_eq!(overhead.padding_block_count, 1);
+        // the last block would form a fifth block group, which is too small
+        assert_eq!(overhead.unused_block_count, 1);
+        assert_eq!(overhead.block_group_block_counts.len(), 4);
+        let overhead_ranges_len: BlockCount = superblock
+            .block_group_overhead_ranges()
+            .into_iter()
+            .map(|range| range.end - range.start)
+            .sum();
+        // the overhead ranges include the padding block
+        assert_eq!(overhead.total_block_count(), overhead_ranges_len + 1);
+        assert_eq!(overhead.total_bytes(), u64::fromx(overhead_ranges_len + 1) * 1024);
+
+        let backup_overhead = superblock.block_group_overhead(HasSuperBlock::YesBackup);
+        let tight =
+            Overhead::compute(32 * MIB, 1024, OverheadOptions { tight_fit: true, ..Default::default() }).unwrap();
+        let normal = Overhead::compute(32 * MIB, 1024, OverheadOptions::default()).unwrap();
+        assert_eq!(normal.block_group_block_counts[3], backup_overhead);
+        assert!(tight.block_group_block_counts[3] < backup_overhead);
+        assert_eq!(tight.block_group_block_counts[1], backup_overhead);
+    }
+
+    #[test]
+    fn rejects_invalid_options() {
+        let bigalloc = |cluster_size| OverheadOptions {
+            bigalloc_cluster_size: Some(cluster_size),
+            ..Default::default()
+        };
+        assert!(Overhead::compute(32 * MIB, 4096, bigalloc(16384)).is_ok());
+        assert!(Overhead::compute(32 * MIB, 4096, bigalloc(1024)).is_err());
+        assert!(
+            Overhead::compute(32 * MIB, 4096, OverheadOptions { inode_ratio: 3000, ..Default::default() }).is_err()
+        );
+        assert!(Overhead::compute(4 * 1024, 1024, OverheadOptions::default()).is_err());
+    }
+}
@@ -1,18 +1,28 @@
 mod block_group;
+mod checksum;
 mod dentry;
+mod dir;
 mod extent;
 mod fs;
 mod group_descriptor;
 mod inode;
+mod layout;
+mod probe;
 mod superblock;
+mod xattr;
 
 pub use self::block_group::*;
+pub use self::checksum::*;
 pub use self::dentry::*;
+pub use self::dir::*;
 pub use self::extent::*;
 pub use self::fs::*;
 pub use self::group_descriptor::*;
 pub use self::inode::*;
+pub use self::layout::*;
+pub use self::probe::*;
 pub use self::superblock::*;
+pub use self::xattr::*;
 
 /// The first block in the partition is padded with 1024 bytes. If the block size is also 1024 bytes, the entire first
 /// block is padding, and the first block group starts with the second block.
@@ -0,0 +1,135 @@
+use std::convert::TryInto;
+
+use anyhow::{bail, Result};
+
+use crate::ext4::{FIRST_BLOCK_PADDING, MAX_BLOCK_SIZE, MIN_BLOCK_SIZE};
+
+// The offsets of the superblock fields that libblkid reads, relative to the start of the superblock. They are
+// hardcoded rather than taken from `SuperBlock` so that a mistake in its layout does not go unnoticed.
+const FIRST_DATA_BLOCK_OFFSET: usize = 0x14;
+const LOG_BLOCK_SIZE_OFFSET: usize = 0x18;
+const MAGIC_OFFSET: usize = 0x38;
+const REV_LEVEL_OFFSET: usize = 0x4C;
+const FEATURE_INCOMPAT_OFFSET: usize = 0x60;
+const FEATURE_RO_COMPAT_OFFSET: usize = 0x64;
+const FLAGS_OFFSET: usize = 0x160;
+const SUPERBLOCK_LEN: usize = 1024;
+
+const MAGIC: [u8; 2] = [0x53, 0xEF];
+const FEATURE_INCOMPAT_JOURNAL_DEV: u32 = 0x8;
+const FEATURE_RO_COMPAT_METADATA_CSUM: u32 = 0x400;
+const FLAGS_TEST_FILESYS: u32 = 0x4;
+/// The features that libblkid expects ext3 to support (`EXT3_FEATURE_INCOMPAT_SUPP`): filetype, recover and meta_bg
+const EXT3_FEATURE_INCOMPAT_SUPP: u32 = 0x2 | 0x4 | 0x10;
+/// The features that libblkid expects ext3 to support (`EXT3_FEATURE_RO_COMPAT_SUPP`): sparse_super, large_file and
+/// btree_dir
+const EXT3_FEATURE_RO_COMPAT_SUPP: u32 = 0x1 | 0x2 | 0x4;
+
+/// Checks that util-linux's libblkid identifies the filesystem in `partition` as ext4, by reading the superblock from
+/// the raw bytes the way its ext4 prober does. Returns an error describing why it would not.
+pub fn probe(partition: &[u8]) -> Result<()> {
+    let superblock = match partition.get(FIRST_BLOCK_PADDING..FIRST_BLOCK_PADDING + SUPERBLOCK_LEN) {
+        Some(superblock) => superblock,
+        None => bail!("The partition is too small to contain an ext4 superblock"),
+    };
+    if superblock[MAGIC_OFFSET..MAGIC_OFFSET + MAGIC.len()] != MAGIC {
+        bail!("There is no ext4 magic number at offset {}", FIRST_BLOCK_PADDING + MAGIC_OFFSET);
+    }
+
+    let read_u32 = |offset: usize| u32::from_le_bytes(superblock[offset..offset + 4].try_into().unwrap());
+    let incompat = read_u32(FEATURE_INCOMPAT_OFFSET);
+    let ro_compat = read_u32(FEATURE_RO_COMPAT_OFFSET);
+    if read_u32(REV_LEVEL_OFFSET) == 0 {
+        bail!("The superblock has revision 0, which has no feature flags");
+    }
+    // the first block contains the superblock, so it is the first data block if it is only 1024 bytes long
+    let log_block_size = read_u32(LOG_BLOCK_SIZE_OFFSET);
+    let expected_first_data_block = u32::from(log_block_size == 0);
+    if read_u32(FIRST_DATA_BLOCK_OFFSET) != expected_first_data_block {
+        bail!(
+            "The first data block is {} instead of {} for blocks of {} bytes",
+            read_u32(FIRST_DATA_BLOCK_OFFSET),
+            expected_first_data_block,
+            1024_u64 << log_block_size.min(32)
+        );
+    }
+    // libblkid skips the superblock if its checksum is wrong, which the converter does not compute
+    if ro_compat & FEATURE_RO_COMPAT_METADATA_CSUM != 0 {
+        bail!("The superblock has the metadata_csum feature, but no checksum");
+    }
+    if incompat & FEATURE_INCOMPAT_JOURNAL_DEV != 0 {
+        bail!("The superblock has the journal_dev feature, so it is identified as an external journal");
+    }
+    if incompat & !EXT3_FEATURE_INCOMPAT_SUPP == 0 && ro_compat & !EXT3_FEATURE_RO_COMPAT_SUPP == 0 {
+        bail!("The superblock has no feature that ext3 lacks, so it is identified as ext2 or ext3");
+    }
+    if read_u32(FLAGS_OFFSET) & FLAGS_TEST_FILESYS != 0 {
+        bail!("The superblock has the test_fs flag, so it is identified as ext4dev");
+    }
+    Ok(())
+}
+
+/// Returns true if `partition` contains an ext4 superblock, i.e. its magic number and a valid block size. mkfs.fat
+/// zeroes the reserved sectors where the superblock would be, so a FAT32 filesystem rarely contains one.
+pub fn has_ext4_signature(partition: &[u8]) -> bool {
+    let superblock = match partition.get(FIRST_BLOCK_PADDING..FIRST_BLOCK_PADDING + SUPERBLOCK_LEN) {
+        Some(superblock) => superblock,
+        None => return false,
+    };
+    let log_block_size =
+        u32::from_le_bytes(superblock[LOG_BLOCK_SIZE_OFFSET..LOG_BLOCK_SIZE_OFFSET + 4].try_into().unwrap());
+    superblock[MAGIC_OFFSET..MAGIC_OFFSET + MAGIC.len()] == MAGIC
+        && log_block_size < 32
+        && u64::from(MIN_BLOCK_SIZE) << log_block_size <= u64::from(MAX_BLOCK_SIZE)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::size_of;
+
+    use super::*;
+    use crate::ext4::{SuperBlock, DEFAULT_INODE_RATIO};
+
+    const MIB: usize = 1024 * 1024;
+
+    fn partition_with(superblock: SuperBlock) -> Vec<u8> {
+        let mut partition = vec![0; 4096];
+        let bytes = &mut partition[FIRST_BLOCK_PADDING..FIRST_BLOCK_PADDING + size_of::<SuperBlock>()];
+        // SAFETY: Safe because `bytes` is as long as a `SuperBlock`.
+        unsafe { (bytes.as_mut_ptr() as *mut SuperBlock).write_unaligned(superblock) };
+        partition
+    }
+
+    #[test]
+    fn identifies_converted_superblock() {
+        for block_size in [1024, 4096] {
+            let superblock =
+                SuperBlock::new(32 * MIB, block_size, block_size, DEFAULT_INODE_RATIO, &[], 0, None).unwrap();
+            assert_eq!(size_of::<SuperBlock>(), SUPERBLOCK_LEN);
+            probe(&partition_with(superblock)).unwrap();
+            assert!(has_ext4_signature(&partition_with(superblock)));
+
+            let mut first_data_block = superblock;
+            first_data_block.s_first_data_block ^= 1;
+            assert!(probe(&partition_with(first_data_block)).is_err());
+        }
+    }
+
+    #[test]
+    fn rejects_what_blkid_rejects() {
+        let superblock = SuperBlock::new(32 * MIB, 1024, 1024, DEFAULT_INODE_RATIO, &[], 0, None).unwrap();
+        let mut shifted = vec![0; 1024];
+        shifted.append(&mut partition_with(superblock));
+        let error = probe(&shifted).unwrap_err();
+        assert!(error.to_string().contains("offset 1080"), "{:#}", error);
+
+        let mut ext3 = superblock;
+        ext3.s_feature_incompat &= EXT3_FEATURE_INCOMPAT_SUPP;
+        ext3.s_feature_ro_compat &= EXT3_FEATURE_RO_COMPAT_SUPP;
+        assert!(probe(&partition_with(ext3)).is_err());
+
+        let mut test_fs = superblock;
+        test_fs.s_flags |= FLAGS_TEST_FILESYS;
+        assert!(probe(&partition_with(test_fs)).is_err());
+    }
+}
@@ -1,43 +1,77 @@
 use std::convert::TryFrom;
+use std::mem::size_of;
+use std::slice;
 
 use anyhow::{bail, Context, Result};
 use num::Integer;
 use uuid::Uuid;
 
 use crate::ext4::{
-    BlockCount, BlockGroupCount, BlockGroupIdx, BlockIdx, BlockSize, InodeCount, InodeNo, FIRST_BLOCK_PADDING,
-    FIRST_EXISTING_INODE, FIRST_NON_RESERVED_INODE,
+    crc32c, BlockCount, BlockGroupCount, BlockGroupIdx, BlockIdx, BlockSize, InodeCount, InodeNo, FIRST_BLOCK_PADDING,
+    FIRST_EXISTING_INODE, FIRST_NON_RESERVED_INODE, INODE_EXTRA_ISIZE,
 };
 use crate::fat::BootSector;
 use crate::lohi::{LoHi, LoHiMut};
+use crate::ranges::Ranges;
 use crate::util::{exact_log2, FromU32, FromUsize};
-use crate::Ranges;
 
 pub const ROOT_INODE_NO: InodeNo = 2;
 pub const LOST_FOUND_INODE_NO: InodeNo = 11;
 
-const SUPERBLOCK_MAGIC: u16 = 61267;
+pub const SUPERBLOCK_MAGIC: u16 = 61267;
 const STATE_CLEANLY_UNMOUNTED: u16 = 1;
 const NEWEST_REVISION: u32 = 1;
 const BLOCK_SIZE_MIN_LOG2: u32 = 10;
 const DESC_SIZE_64BIT: u16 = 64;
 const ERRORS_DEFAULT: u16 = 1;
+const FLAGS_SIGNED_HASH: u32 = 0x1;
+const FLAGS_UNSIGNED_HASH: u32 = 0x2;
+const FEATURE_COMPAT_EXT_ATTR: u32 = 0x8; // allow extended attributes
 const FEATURE_COMPAT_SPARSE_SUPER2: u32 = 0x200; // use only two superblock backups
+const FEATURE_INCOMPAT_FILETYPE: u32 = 0x2; // store the file type in dentries
+const FEATURE_INCOMPAT_RECOVER: u32 = 0x4; // the journal needs to be replayed
+const FEATURE_INCOMPAT_META_BG: u32 = 0x10; // spread the group descriptors over the filesystem
 const FEATURE_INCOMPAT_EXTENTS: u32 = 0x40; // use extents to represent a file's data blocks
 const FEATURE_INCOMPAT_64BIT: u32 = 0x80; // allow filesystems bigger with more than 2^32 blocks
+const FEATURE_INCOMPAT_CSUM_SEED: u32 = 0x2000; // store the seed of the metadata checksums in the superblock
 const FEATURE_INCOMPAT_LARGEDIR: u32 = 0x4000; // allow directories bigger than 2GB
+const FEATURE_RO_COMPAT_SPARSE_SUPER: u32 = 0x1; // only keep superblock backups in groups 1 and powers of 3, 5 and 7
 const FEATURE_RO_COMPAT_LARGE_FILE: u32 = 0x2; // allow files bigger than 2GiB
 const FEATURE_RO_COMPAT_HUGE_FILE: u32 = 0x8; // allow files bigger than 2TiB, for the hell of it
+const FEATURE_RO_COMPAT_GDT_CSUM: u32 = 0x10; // uninit_bg: skip the bitmaps and inode tables of unused block groups
 const FEATURE_RO_COMPAT_DIR_NLINK: u32 = 0x20; // allow directories with more than 65000 subdirectories
-const INODE_RATIO: u32 = 16384;
-const INODE_SIZE: u16 = 256;
-const VOLUME_NAME_LEN: usize = 16;
-// Simplified because we don't use ext4 clusters
-const MAX_BLOCKS_PER_GROUP: u32 = (1 << 16) - 8;
+const FEATURE_RO_COMPAT_BIGALLOC: u32 = 0x200; // allocate blocks in clusters of multiple blocks
+/// checksum the metadata with crc32c; not enabled by the converter, which does not compute all of the checksums yet
+pub const FEATURE_RO_COMPAT_METADATA_CSUM: u32 = 0x400;
+/// The number of bytes per inode, unless chosen otherwise
+pub const DEFAULT_INODE_RATIO: u32 = 16384;
+/// The bounds of the number of bytes per inode, like in mke2fs
+pub const MIN_INODE_RATIO: u32 = 1024;
+pub const MAX_INODE_RATIO: u32 = 64 * 1024 * 1024;
+/// The reserved inodes and lost+found must fit into the first block group, and an inode bitmap is filled one byte at a
+/// time, so the smallest power of two greater than `FIRST_NON_RESERVED_INODE` that is a multiple of 8
+const MIN_INODES_PER_GROUP: u32 = 16;
+pub const INODE_SIZE: u16 = 256;
+pub const VOLUME_NAME_LEN: usize = 16;
+const MAX_CLUSTERS_PER_GROUP: u32 = (1 << 16) - 8;
 // Chosen for practicality, not actually enforced
 const MIN_USABLE_BLOCKS_PER_GROUP: BlockCount = 10;
-const MIN_BLOCK_SIZE: BlockSize = 1024;
-const MAX_BLOCK_SIZE: BlockSize = 65_536;
+pub const MIN_BLOCK_SIZE: BlockSize = 1024;
+pub const MAX_BLOCK_SIZE: BlockSize = 65_536;
+/// With bigalloc, the ext4 block size is fixed and the FAT cluster size becomes the ext4 cluster size.
+const BIGALLOC_BLOCK_SIZE: BlockSize = 4096;
+
+/// Derives a UUID from the serial number (volume ID) of a FAT filesystem, so that setups that identify the volume by
+/// its serial number can find the converted filesystem. The serial number makes up the first 8 hex digits, so the
+/// serial 1234-ABCD becomes the UUID 1234abcd-0000-8000-8000-000000000000. The version (8, i.e. custom) and the
+/// variant (RFC 4122) are set so that tools accept the UUID; everything else is zero.
+pub fn uuid_from_fat_serial(serial: u32) -> Uuid {
+    let mut bytes = [0; 16];
+    bytes[0..4].copy_from_slice(&serial.to_be_bytes());
+    bytes[6] = 0x80;
+    bytes[8] = 0x80;
+    Uuid::from_bytes(bytes)
+}
 
 #[derive(PartialEq, Clone, Copy, Debug)]
 pub enum HasSuperBlock {
@@ -60,8 +94,9 @@ pub struct SuperBlock {
     /// max value: 6
     pub s_log_block_size: u32,
     pub s_log_cluster_size: u32,
-    /// max value: 65528
+    /// max value: 65528 * 2^(s_log_cluster_size - s_log_block_size)
     pub s_blocks_per_group: u32,
+    /// max value: 65528
     pub s_clusters_per_group: u32,
     /// max value: 524288 (using current heuristic, 262112)
     pub s_inodes_per_group: u32,
@@ -153,20 +188,82 @@ pub struct SuperBlock {
 }
 
 impl SuperBlock {
-    pub fn from(boot_sector: &BootSector) -> Result<Self> {
+    /// If `bigalloc` is true, the ext4 block size is 4 KiB and the FAT cluster size becomes the ext4 cluster size;
+    /// otherwise, the FAT cluster size becomes the ext4 block size. `inode_ratio` is the number of bytes per inode, and
+    /// `mkfs_time` is the filesystem's creation time as a Unix timestamp. The UUID is derived from the FAT volume ID if
+    /// `uuid_from_fat_serial` is true, see `uuid_from_fat_serial`, and random otherwise.
+    pub fn from(
+        boot_sector: &BootSector,
+        bigalloc: bool,
+        inode_ratio: u32,
+        mkfs_time: u32,
+        uuid_from_fat_serial: bool,
+    ) -> Result<Self> {
         if boot_sector.get_data_range().start % usize::fromx(boot_sector.cluster_size()) != 0 {
-            // We want to treat FAT clusters as ext4 blocks, but we can't if they're not aligned
+            // We want to treat FAT clusters as ext4 clusters, but we can't if they're not aligned
             bail!(
                 "The FAT filesystem's data section must be aligned to its cluster size (for more info, see the -a \
                  option in the mkfs.fat man page).",
             );
         }
 
-        Self::new(boot_sector.fs_size(), boot_sector.cluster_size(), boot_sector.volume_label())
+        let cluster_size = boot_sector.cluster_size();
+        let block_size = if bigalloc {
+            if cluster_size <= BIGALLOC_BLOCK_SIZE {
+                bail!(
+                    "bigalloc requires a FAT cluster size greater than {} KiB",
+                    BIGALLOC_BLOCK_SIZE / 1024
+                );
+            }
+            BIGALLOC_BLOCK_SIZE
+        } else {
+            cluster_size
+        };
+        Self::new(
+            boot_sector.fs_size(),
+            block_size,
+            cluster_size,
+            inode_ratio,
+            boot_sector.volume_label(),
+            mkfs_time,
+            uuid_from_fat_serial.then_some(boot_sector.volume_id),
+        )
+    }
+
+    /// Like `from`, but halves `inode_ratio` until the filesystem has at least `min_inode_count` allocatable inodes or
+    /// `inode_ratio` reaches `MIN_INODE_RATIO`. Returns the superblock along with the inode ratio it was created with.
+    pub fn from_with_min_inode_count(
+        boot_sector: &BootSector,
+        bigalloc: bool,
+        inode_ratio: u32,
+        min_inode_count: InodeCount,
+        mkfs_time: u32,
+        uuid_from_fat_serial: bool,
+    ) -> Result<(Self, u32)> {
+        let mut inode_ratio = inode_ratio;
+        let mut superblock = Self::from(boot_sector, bigalloc, inode_ratio, mkfs_time, uuid_from_fat_serial)?;
+        while superblock.allocatable_inode_count() < min_inode_count && inode_ratio > MIN_INODE_RATIO {
+            inode_ratio /= 2;
+            superblock = Self::from(boot_sector, bigalloc, inode_ratio, mkfs_time, uuid_from_fat_serial)?;
+        }
+        Ok((superblock, inode_ratio))
     }
 
-    pub fn new(fs_len: usize, block_size: BlockSize, volume_label: &[u8]) -> Result<Self> {
+    /// Creates a superblock with bigalloc enabled if `cluster_size > block_size`. The UUID is derived from `fat_serial`
+    /// if it is given, see `uuid_from_fat_serial`, and random otherwise.
+    /// PANICS: Panics if `inode_ratio` is not a power of two.
+    pub fn new(
+        fs_len: usize,
+        block_size: BlockSize,
+        cluster_size: BlockSize,
+        inode_ratio: u32,
+        volume_label: &[u8],
+        mkfs_time: u32,
+        fat_serial: Option<u32>,
+    ) -> Result<Self> {
         assert!(volume_label.len() <= VOLUME_NAME_LEN);
+        assert!(block_size <= cluster_size);
+        assert!(inode_ratio.is_power_of_two());
 
         // SAFETY: This allows us to skip initializing a ton of fields to zero, but
         // CAUTION: some initialization steps rely on other fields already having been set,
@@ -176,30 +273,44 @@ impl SuperBlock {
 
         if block_size < MIN_BLOCK_SIZE {
             bail!("The FAT filesystem's cluster size must be >= 1 KiB");
-        } else if block_size > MAX_BLOCK_SIZE {
+        } else if cluster_size > MAX_BLOCK_SIZE {
             bail!("The FAT filesystem's cluster size must be <= 64 KiB");
         }
 
         let log_block_size = exact_log2(block_size).context("Invalid FAT cluster size")?;
+        let log_cluster_size = exact_log2(cluster_size).context("Invalid FAT cluster size")?;
         sb.s_log_block_size = u32::from(log_block_size) - BLOCK_SIZE_MIN_LOG2;
+        sb.s_log_cluster_size = u32::from(log_cluster_size) - BLOCK_SIZE_MIN_LOG2;
         // `s_log_block_size` must have a value before this call
         sb.s_first_data_block = if sb.first_block_is_padding() { 1 } else { 0 };
-        let block_bitmap_size = block_size * 8;
-        sb.s_blocks_per_group = block_bitmap_size.min(MAX_BLOCKS_PER_GROUP);
-
-        sb.s_mkfs_time = u32::try_from(chrono::Utc::now().timestamp()).unwrap();
-        sb.s_uuid = *Uuid::new_v4().as_bytes();
-        sb.s_volume_name[0..volume_label.len()].clone_from_slice(volume_label);
-
-        // These two fields have to have these values even if bigalloc is disabled
-        sb.s_log_cluster_size = sb.s_log_block_size;
-        sb.s_clusters_per_group = sb.s_blocks_per_group;
+        if sb.has_bigalloc() {
+            if sb.first_block_is_padding() {
+                bail!("bigalloc requires a block size greater than {} bytes", FIRST_BLOCK_PADDING);
+            }
+            sb.s_feature_ro_compat |= FEATURE_RO_COMPAT_BIGALLOC;
+        }
+        // The block bitmap has one bit per cluster
+        let cluster_bitmap_size = block_size * 8;
+        sb.s_clusters_per_group = cluster_bitmap_size.min(MAX_CLUSTERS_PER_GROUP);
+        // `s_log_block_size` and `s_log_cluster_size` must have a value before this call
+        sb.s_blocks_per_group = sb.s_clusters_per_group * sb.blocks_per_cluster();
+
+        sb.s_mkfs_time = mkfs_time;
+        sb.s_wtime = mkfs_time;
+        // like mke2fs, so that fsck does not consider the filesystem overdue for a check
+        sb.s_lastcheck = u32::try_from(chrono::Utc::now().timestamp()).unwrap();
+        sb.s_uuid = *fat_serial.map_or_else(Uuid::new_v4, uuid_from_fat_serial).as_bytes();
+        sb.set_volume_name(volume_label);
 
         let inode_bitmap_size = block_size * 8;
-        let heuristic_inodes_per_group = sb.s_blocks_per_group * block_size / INODE_RATIO;
-        sb.s_inodes_per_group = inode_bitmap_size.min(heuristic_inodes_per_group);
+        let heuristic_inodes_per_group = sb.s_blocks_per_group * block_size / inode_ratio;
+        // the inode table must fill whole blocks; all values are powers of two, so the result is a multiple of both
+        let min_inodes_per_group = MIN_INODES_PER_GROUP.max(block_size / u32::from(INODE_SIZE));
+        sb.s_inodes_per_group = inode_bitmap_size.min(heuristic_inodes_per_group).max(min_inodes_per_group);
 
         let mut block_count = fs_len / BlockCount::fromx(block_size);
+        // only whole clusters belong to the filesystem
+        block_count -= block_count % BlockCount::fromx(sb.blocks_per_cluster());
         let mut data_block_count = block_count.saturating_sub(BlockCount::fromx(sb.s_first_data_block));
         // set the intermediate value in `sb` because it is needed by the call to `sb.block_group_overhead`.
         LoHiMut::new(&mut sb.s_blocks_count_lo, &mut sb.s_blocks_count_hi).set(u64::fromx(block_count));
@@ -243,6 +354,8 @@ impl SuperBlock {
                 sb.s_backup_bgs[1] = block_group_count - 1;
             }
         }
+        // the kernel calculates and stores this value on the first mount if it is 0, so 0 is a safe fallback
+        sb.s_overhead_clusters = u32::try_from(sb.overhead_cluster_count()).unwrap_or(0);
         Ok(sb)
     }
 
@@ -250,15 +363,27 @@ impl SuperBlock {
         self.s_magic = SUPERBLOCK_MAGIC;
         self.s_state = STATE_CLEANLY_UNMOUNTED;
         self.s_feature_compat = FEATURE_COMPAT_SPARSE_SUPER2;
-        self.s_feature_incompat = FEATURE_INCOMPAT_64BIT | FEATURE_INCOMPAT_EXTENTS | FEATURE_INCOMPAT_LARGEDIR;
-        self.s_feature_ro_compat =
-            FEATURE_RO_COMPAT_LARGE_FILE | FEATURE_RO_COMPAT_HUGE_FILE | FEATURE_RO_COMPAT_DIR_NLINK;
+        self.s_feature_incompat =
+            FEATURE_INCOMPAT_FILETYPE | FEATURE_INCOMPAT_64BIT | FEATURE_INCOMPAT_EXTENTS | FEATURE_INCOMPAT_LARGEDIR;
+        self.s_feature_ro_compat = FEATURE_RO_COMPAT_LARGE_FILE
+            | FEATURE_RO_COMPAT_HUGE_FILE
+            | FEATURE_RO_COMPAT_GDT_CSUM
+            | FEATURE_RO_COMPAT_DIR_NLINK;
         self.s_desc_size = DESC_SIZE_64BIT;
         self.s_inode_size = INODE_SIZE;
         self.s_rev_level = NEWEST_REVISION;
         self.s_errors = ERRORS_DEFAULT;
         self.s_first_ino = FIRST_NON_RESERVED_INODE;
         self.s_max_mnt_count = u16::MAX;
+        self.s_min_extra_isize = INODE_EXTRA_ISIZE;
+        self.s_want_extra_isize = INODE_EXTRA_ISIZE;
+        // The kernel sets one of the hash flags on the first rw mount if neither is set, as mke2fs does depending on
+        // whether `char` is signed on the platform. Setting it here keeps the superblock unchanged by mounting.
+        self.s_flags = if std::os::raw::c_char::MIN == 0 {
+            FLAGS_UNSIGNED_HASH
+        } else {
+            FLAGS_SIGNED_HASH
+        };
     }
 
     pub fn max_inode_no(&self) -> InodeNo {
@@ -276,6 +401,19 @@ impl SuperBlock {
         default_overhead + self.superblock_copy_overhead(has_superblock)
     }
 
+    /// The number of clusters containing filesystem metadata, counted the way the kernel does: each block group's
+    /// overhead is rounded up to whole clusters, the blocks before `s_first_data_block` are rounded down.
+    pub fn overhead_cluster_count(&self) -> BlockCount {
+        let blocks_per_cluster = BlockCount::fromx(self.blocks_per_cluster());
+        let block_group_overhead: BlockCount = (0..self.block_group_count())
+            .map(|block_group_idx| {
+                self.block_group_overhead(self.block_group_has_superblock(block_group_idx))
+                    .div_ceil(&blocks_per_cluster)
+            })
+            .sum();
+        self.first_usable_cluster() + block_group_overhead
+    }
+
     pub fn superblock_copy_overhead(&self, has_superblock: HasSuperBlock) -> BlockCount {
         match has_superblock {
             HasSuperBlock::YesOriginal | HasSuperBlock::YesBackup => {
@@ -300,12 +438,49 @@ impl SuperBlock {
         1 << (self.s_log_block_size + BLOCK_SIZE_MIN_LOG2)
     }
 
+    /// The unit in which blocks are allocated. Equal to the block size unless bigalloc is enabled.
+    pub fn cluster_size(&self) -> BlockSize {
+        1 << (self.s_log_cluster_size + BLOCK_SIZE_MIN_LOG2)
+    }
+
+    pub fn blocks_per_cluster(&self) -> u32 {
+        1 << (self.s_log_cluster_size - self.s_log_block_size)
+    }
+
+    pub fn has_bigalloc(&self) -> bool {
+        self.s_log_cluster_size != self.s_log_block_size
+    }
+
+    /// Enables the ext_attr feature, which the inodes with extended attributes require.
+    pub fn enable_xattrs(&mut self) {
+        self.s_feature_compat |= FEATURE_COMPAT_EXT_ATTR;
+    }
+
+    pub fn has_metadata_csum(&self) -> bool {
+        self.s_feature_ro_compat & FEATURE_RO_COMPAT_METADATA_CSUM != 0
+    }
+
+    /// The initial value of the metadata checksums, which is derived from the UUID unless it is stored in the
+    /// superblock.
+    pub fn checksum_seed(&self) -> u32 {
+        if self.s_feature_incompat & FEATURE_INCOMPAT_CSUM_SEED != 0 {
+            self.s_checksum_seed
+        } else {
+            crc32c(!0, &self.s_uuid)
+        }
+    }
+
     /// Includes a possible first padding block that does not belong to any block group
     pub fn block_count_with_padding(&self) -> BlockCount {
         let block_count: u64 = LoHi::new(&self.s_blocks_count_lo, &self.s_blocks_count_hi).get();
         BlockCount::try_from(block_count).expect("In `Self::new` the block count fit into a usize")
     }
 
+    /// Includes a possible first padding block that does not belong to any block group
+    pub fn cluster_count_with_padding(&self) -> BlockCount {
+        self.block_count_with_padding() / BlockCount::fromx(self.blocks_per_cluster())
+    }
+
     pub fn block_count_without_padding(&self) -> BlockCount {
         self.block_count_with_padding() - BlockCount::fromx(self.s_first_data_block)
     }
@@ -342,11 +517,19 @@ impl SuperBlock {
         BlockIdx::fromx(self.s_first_data_block)
     }
 
+    pub fn first_usable_cluster(&self) -> BlockIdx {
+        self.first_usable_block() / BlockIdx::fromx(self.blocks_per_cluster())
+    }
+
     // if the block size is FIRST_BLOCK_PADDING, every block group begins one block later than normal
     pub fn block_group_start_block(&self, block_group_idx: BlockGroupIdx) -> BlockIdx {
         usize::fromx(self.s_blocks_per_group) * usize::fromx(block_group_idx) + self.first_usable_block()
     }
 
+    pub fn block_group_start_cluster(&self, block_group_idx: BlockGroupIdx) -> BlockIdx {
+        self.block_group_start_block(block_group_idx) / BlockIdx::fromx(self.blocks_per_cluster())
+    }
+
     /// Returns the block ranges that contain filesystem metadata, i.e. the ones occupied by the fields of `BlockGroup`.
     pub fn block_group_overhead_ranges(&self) -> Ranges<BlockIdx> {
         let mut overhead_ranges = Vec::new();
@@ -369,12 +552,91 @@ impl SuperBlock {
         usize::fromx(self.block_size()) <= FIRST_BLOCK_PADDING
     }
 
+    /// Reserves `percent` percent of the blocks for root.
+    pub fn set_reserved_percent(&mut self, percent: u8) {
+        let block_count: u64 = LoHi::new(&self.s_blocks_count_lo, &self.s_blocks_count_hi).get();
+        let reserved_block_count = block_count * u64::from(percent) / 100;
+        LoHiMut::new(&mut self.s_r_blocks_count_lo, &mut self.s_r_blocks_count_hi).set(reserved_block_count);
+    }
+
     pub fn set_free_blocks_count(&mut self, count: u64) {
         LoHiMut::new(&mut self.s_free_blocks_count_lo, &mut self.s_free_blocks_count_hi).set(count);
     }
 
+    /// Removes the superblock and GDT backup from the last block group, leaving only the one in block group 1. Returns
+    /// the number of blocks that become usable, which is 0 if the filesystem has fewer than 3 block groups.
+    pub fn remove_last_backup(&mut self) -> BlockCount {
+        if self.s_backup_bgs[1] == 0 {
+            return 0;
+        }
+        self.s_backup_bgs[1] = 0;
+        self.s_overhead_clusters = u32::try_from(self.overhead_cluster_count()).unwrap_or(0);
+        self.superblock_copy_overhead(HasSuperBlock::YesBackup)
+    }
+
     /// Returns the block group indices of block groups containing a superblock and gdt backup copy
     pub fn backup_bgs(&self) -> impl Iterator<Item = BlockGroupIdx> + '_ {
         self.s_backup_bgs.iter().copied().filter(|&bg_idx| bg_idx != 0)
     }
+
+    /// Returns the indices of all block groups containing a superblock, including block group 0. Unlike
+    /// `block_group_has_superblock`, which only knows the layout the converter creates, this also handles filesystems
+    /// created by mke2fs without sparse_super2.
+    pub fn superblock_bgs(&self) -> Vec<BlockGroupIdx> {
+        let block_group_count = self.block_group_count();
+        if self.s_feature_compat & FEATURE_COMPAT_SPARSE_SUPER2 != 0 {
+            return std::iter::once(0).chain(self.backup_bgs()).collect();
+        }
+        if self.s_feature_ro_compat & FEATURE_RO_COMPAT_SPARSE_SUPER == 0 {
+            return (0..block_group_count).collect();
+        }
+        let mut block_groups = vec![0, 1];
+        for base in [3, 5, 7] {
+            let mut power: u64 = base;
+            while power < u64::from(block_group_count) {
+                block_groups.push(power as BlockGroupIdx);
+                power *= base;
+            }
+        }
+        block_groups.retain(|&bg_idx| bg_idx < block_group_count);
+        block_groups.sort_unstable();
+        block_groups
+    }
+
+    pub fn has_gdt_csum(&self) -> bool {
+        self.s_feature_ro_compat & FEATURE_RO_COMPAT_GDT_CSUM != 0
+    }
+
+    pub fn needs_recovery(&self) -> bool {
+        self.s_feature_incompat & FEATURE_INCOMPAT_RECOVER != 0
+    }
+
+    pub fn has_meta_bg(&self) -> bool {
+        self.s_feature_incompat & FEATURE_INCOMPAT_META_BG != 0
+    }
+
+    /// PANICS: Panics if `volume_label` is longer than 16 bytes.
+    pub fn set_volume_name(&mut self, volume_label: &[u8]) {
+        assert!(volume_label.len() <= VOLUME_NAME_LEN);
+        self.s_volume_name = [0; VOLUME_NAME_LEN];
+        self.s_volume_name[0..volume_label.len()].clone_from_slice(volume_label);
+    }
+
+    /// Sets the UUID. With metadata_csum, the checksums of the metadata are derived from the UUID, so the current seed
+    /// is stored in the superblock first to keep them valid, like tune2fs does. Without metadata_csum, the group
+    /// descriptor checksums of uninit_bg depend on the UUID and have to be updated by the caller.
+    pub fn set_uuid(&mut self, uuid: Uuid) {
+        if self.has_metadata_csum() && self.s_feature_incompat & FEATURE_INCOMPAT_CSUM_SEED == 0 {
+            self.s_checksum_seed = self.checksum_seed();
+            self.s_feature_incompat |= FEATURE_INCOMPAT_CSUM_SEED;
+        }
+        self.s_uuid = *uuid.as_bytes();
+    }
+
+    /// Computes `s_checksum` as required by the metadata_csum feature.
+    pub fn update_checksum(&mut self) {
+        // SAFETY: Safe because `SuperBlock` consists only of integers and has no padding.
+        let bytes = unsafe { slice::from_raw_parts(self as *const Self as *const u8, size_of::<Self>()) };
+        self.s_checksum = crc32c(!0, &bytes[..size_of::<Self>() - size_of::<u32>()]);
+    }
 }
@@ -0,0 +1,178 @@
+use std::collections::HashSet;
+use std::mem::size_of;
+
+use anyhow::{bail, Context, Result};
+use num::Integer;
+
+use crate::ext4::{InodeInner, INODE_SIZE};
+
+/// The space for extended attributes in every inode, after the fields of `InodeInner`
+pub const IN_INODE_XATTR_LEN: usize = INODE_SIZE as usize - size_of::<InodeInner>();
+/// Starts the extended attributes in an inode
+const XATTR_MAGIC: u32 = 0xEA02_0000;
+/// The magic number, or the 4 zero bytes that end the entries
+const MARKER_LEN: usize = 4;
+/// The part of an entry before the name: `e_name_len`, `e_name_index`, `e_value_offs`, `e_value_inum`, `e_value_size`
+/// and `e_hash`
+const ENTRY_HEADER_LEN: usize = 16;
+/// Entries and values are padded to multiples of this
+const XATTR_ALIGN: usize = 4;
+/// The supported namespaces, by the prefix of the attribute names and the index that replaces it on disk. The others,
+/// e.g. the POSIX ACLs in "system.", have values in a special format.
+const NAMESPACES: [(&str, u8); 3] = [("user.", 1), ("trusted.", 4), ("security.", 6)];
+
+/// An extended attribute of a file
+#[derive(Clone, Debug, PartialEq)]
+pub struct Xattr {
+    /// the name including the namespace, e.g. "user.comment"
+    pub name: String,
+    pub value: Vec<u8>,
+}
+
+/// Encodes `xattrs` as the `IN_INODE_XATTR_LEN` bytes after the fields of `InodeInner`: the magic number, an entry per
+/// attribute, the end of the entries and the values. Only the namespaces in `NAMESPACES` are supported. Like in
+/// e2fsprogs, the hashes of the entries are left 0, which is only allowed for attributes in the inode. Fails if an
+/// attribute is not supported, two have the same name or they do not fit into the inode, since the converter does not
+/// allocate blocks for extended attributes.
+pub fn encode_xattrs(xattrs: &[Xattr]) -> Result<Vec<u8>> {
+    let mut names = HashSet::new();
+    let mut split_names = Vec::new();
+    for xattr in xattrs {
+        if !names.insert(xattr.name.as_str()) {
+            bail!("The extended attribute {} is set twice", xattr.name);
+        }
+        split_names.push(split_name(&xattr.name)?);
+    }
+    let entries_len: usize = split_names.iter().map(|(_, suffix)| entry_len(suffix)).sum();
+    let values_len: usize = xattrs.iter().map(|xattr| padded_len(xattr.value.len())).sum();
+    let len = MARKER_LEN + entries_len + MARKER_LEN + values_len;
+    if len > IN_INODE_XATTR_LEN {
+        bail!(
+            "The extended attributes take up {} bytes, but only {} fit into an inode",
+            len,
+            IN_INODE_XATTR_LEN
+        );
+    }
+
+    let mut encoded = vec![0; IN_INODE_XATTR_LEN];
+    encoded[..MARKER_LEN].copy_from_slice(&XATTR_MAGIC.to_le_bytes());
+    // the value offsets count from the first entry, and the values follow the end of the entries
+    let first_entry = &mut encoded[MARKER_LEN..];
+    let mut entry_start = 0;
+    let mut value_start = entries_len + MARKER_LEN;
+    for (xattr, (name_index, suffix)) in xattrs.iter().zip(split_names) {
+        first_entry[value_start..value_start + xattr.value.len()].copy_from_slice(&xattr.value);
+        let value_offset = if xattr.value.is_empty() { 0 } else { value_start };
+
+        let entry = &mut first_entry[entry_start..entry_start + entry_len(suffix)];
+        entry[0] = u8::try_from(suffix.len()).expect("`split_name` checks the length of the name");
+        entry[1] = name_index;
+        entry[2..4].copy_from_slice(&u16::try_from(value_offset).unwrap().to_le_bytes());
+        entry[8..12].copy_from_slice(&u32::try_from(xattr.value.len()).unwrap().to_le_bytes());
+        entry[ENTRY_HEADER_LEN..ENTRY_HEADER_LEN + suffix.len()].copy_from_slice(suffix.as_bytes());
+        entry_start += entry.len();
+        value_start += padded_len(xattr.value.len());
+    }
+    Ok(encoded)
+}
+
+/// Decodes the extended attributes that `encode_xattrs` encoded into `encoded`, the bytes of an inode after the fields
+/// of `InodeInner`. Returns no attributes if `encoded` does not start with the magic number.
+pub fn decode_xattrs(encoded: &[u8]) -> Result<Vec<Xattr>> {
+    if encoded.len() < MARKER_LEN || encoded[..MARKER_LEN] != XATTR_MAGIC.to_le_bytes() {
+        return Ok(Vec::new());
+    }
+    let first_entry = &encoded[MARKER_LEN..];
+    let mut xattrs = Vec::new();
+    let mut entry_start = 0;
+    loop {
+        let entry = first_entry
+            .get(entry_start..)
+            .filter(|entry| entry.len() >= MARKER_LEN)
+            .context("The extended attributes are not terminated")?;
+        if entry[..MARKER_LEN] == [0; MARKER_LEN] {
+            return Ok(xattrs);
+        }
+        let name_len = usize::from(entry[0]);
+        let prefix = match NAMESPACES.iter().find(|(_, name_index)| *name_index == entry[1]) {
+            Some((prefix, _)) => prefix,
+            None => bail!("The extended attribute has the unsupported name index {}", entry[1]),
+        };
+        let value_offset = usize::from(u16::from_le_bytes([entry[2], entry[3]]));
+        let value_len = usize::try_from(u32::from_le_bytes(entry[8..12].try_into().unwrap())).unwrap();
+        let suffix = entry
+            .get(ENTRY_HEADER_LEN..ENTRY_HEADER_LEN + name_len)
+            .context("The name of an extended attribute exceeds the inode")?;
+        let value = first_entry
+            .get(value_offset..value_offset + value_len)
+            .context("The value of an extended attribute exceeds the inode")?;
+        xattrs.push(Xattr {
+            name: format!("{}{}", prefix, String::from_utf8_lossy(suffix)),
+            value: value.to_vec(),
+        });
+        entry_start += ENTRY_HEADER_LEN + padded_len(name_len);
+    }
+}
+
+/// Splits `name` into the index of its namespace and the rest of the name, which is stored in the entry.
+fn split_name(name: &str) -> Result<(u8, &str)> {
+    let (name_index, suffix) = NAMESPACES
+        .iter()
+        .find_map(|(prefix, name_index)| Some((*name_index, name.strip_prefix(prefix)?)))
+        .with_context(|| {
+            format!(
+                "The extended attribute {} is not in one of the namespaces user., trusted. or security.",
+                name
+            )
+        })?;
+    if suffix.is_empty() || suffix.len() > usize::from(u8::MAX) {
+        bail!(
+            "The name of the extended attribute {} must have 1 to 255 bytes after the namespace",
+            name
+        );
+    }
+    Ok((name_index, suffix))
+}
+
+fn entry_len(suffix: &str) -> usize {
+    ENTRY_HEADER_LEN + padded_len(suffix.len())
+}
+
+fn padded_len(len: usize) -> usize {
+    len.div_ceil(&XATTR_ALIGN) * XATTR_ALIGN
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn xattr(name: &str, value: &[u8]) -> Xattr {
+        Xattr { name: name.to_string(), value: value.to_vec() }
+    }
+
+    #[test]
+    fn encodes_entries_and_values() {
+        let xattrs = [xattr("user.a", b"hello"), xattr("security.b", b"")];
+        let encoded = encode_xattrs(&xattrs).unwrap();
+        assert_eq!(encoded.len(), IN_INODE_XATTR_LEN);
+        assert_eq!(encoded[..4], [0x00, 0x00, 0x02, 0xEA]);
+        // two entries of 20 bytes and the end of the entries, then the value
+        let first_entry = &encoded[4..];
+        assert_eq!(first_entry[..4], [1, 1, 44, 0]);
+        assert_eq!(first_entry[8..12], 5u32.to_le_bytes());
+        assert_eq!(first_entry[16], b'a');
+        assert_eq!(first_entry[20..24], [1, 6, 0, 0]);
+        assert_eq!(first_entry[40..44], [0; 4]);
+        assert_eq!(&first_entry[44..49], b"hello");
+        assert_eq!(decode_xattrs(&encoded).unwrap(), xattrs);
+    }
+
+    #[test]
+    fn rejects_unsupported_attributes() {
+        assert!(encode_xattrs(&[xattr("system.posix_acl_access", b"")]).is_err());
+        assert!(encode_xattrs(&[xattr("user.", b"")]).is_err());
+        assert!(encode_xattrs(&[xattr("user.a", b""), xattr("user.a", b"")]).is_err());
+        assert!(encode_xattrs(&[xattr("user.a", &[0; IN_INODE_XATTR_LEN])]).is_err());
+        assert!(encode_xattrs(&[]).is_ok());
+    }
+}
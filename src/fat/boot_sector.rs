@@ -1,12 +1,39 @@
-use std::ops::Range;
+// `bail!` only expands to a `format!` without `std`
+#[cfg(not(feature = "std"))]
+use alloc::format;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::mem::size_of;
+use core::ops::Range;
 
 use anyhow::{bail, Result};
 
 use crate::fat::{ClusterIdx, FatDentry};
-use crate::util::FromU32;
+use crate::util::{FromU32, FromUsize};
 
 const FS_TYPE_FAT32: [u8; 8] = *b"FAT32   ";
 const EXT_BOOT_SIGNATURE_FAT32: u8 = 0x29;
+/// The sector in which formatting tools place the backup boot sector
+const DEFAULT_BACKUP_BOOT_SECTOR_NO: usize = 6;
+const VALID_SECTOR_SIZES: [usize; 4] = [512, 1024, 2048, 4096];
+/// The offset of the 0x55 0xAA signature at the end of a boot sector or FsInfo sector
+const SECTOR_SIGNATURE_OFFSET: usize = 510;
+/// The offsets of the lead signature "RRaA", the structure signature "rrAa" and the trail signature 0x00 0x00 0x55 0xAA
+/// in an FsInfo sector
+const FS_INFO_SIGNATURE_OFFSETS: [usize; 3] = [0, 484, 508];
+/// FAT32 cluster numbers have 28 bits, the numbers from 0x0FFFFFF7 upward are reserved and the first data cluster is
+/// numbered 2, so a FAT32 filesystem can have at most 0x0FFFFFF5 (268435445) data clusters.
+pub const MAX_DATA_CLUSTER_COUNT: u32 = 0x0FFF_FFF5;
+/// Filesystems with fewer data clusters are FAT12, since FAT12 cluster numbers have 12 bits
+const MIN_FAT16_DATA_CLUSTER_COUNT: usize = 4085;
+
+/// The FAT variants that preceded FAT32 and that ofs-convert-rs does not support
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LegacyFatVariant {
+    Fat12,
+    Fat16,
+}
 
 #[repr(C, packed)]
 #[derive(Debug, Copy, Clone, PartialEq)]
@@ -20,7 +47,8 @@ pub struct BootSector {
     pub dir_entries: u16,
     pub sector_count_1: u16,
     pub media_descriptor: u8,
-    pub unused2: u16,
+    /// the size of a FAT on FAT12 and FAT16, which is 0 on FAT32
+    pub sectors_per_fat_16: u16,
     pub sectors_per_disk_track: u16,
     pub disk_heads: u16,
     pub hidden_sectors_before_partition: u32,
@@ -41,6 +69,56 @@ pub struct BootSector {
 }
 
 impl BootSector {
+    /// Interprets the start of `bytes` as a boot sector and performs the sanity check from `validate`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<&Self> {
+        if bytes.len() < size_of::<Self>() {
+            bail!("The partition is too small to contain a boot sector");
+        }
+        if let Some(variant) = Self::detect_legacy_fat(bytes) {
+            bail!(
+                "The partition contains a {} filesystem, but only FAT32 filesystems can be converted",
+                variant
+            );
+        }
+        // SAFETY: Safe because `BootSector` is packed, consists only of integers, and fits into `bytes`.
+        unsafe { &*(bytes.as_ptr() as *const Self) }.validate()
+    }
+
+    /// Detects FAT12 and FAT16 boot sectors at the start of `bytes` like the Linux kernel does: a boot sector with a
+    /// 16-bit FAT size belongs to FAT12 if it has fewer than 4085 data clusters and to FAT16 otherwise. Returns None
+    /// for FAT32 boot sectors and for boot sectors whose geometry is not even plausible, which are probably damaged.
+    pub fn detect_legacy_fat(bytes: &[u8]) -> Option<LegacyFatVariant> {
+        if bytes.len() < size_of::<Self>() {
+            return None;
+        }
+        // SAFETY: Safe because `BootSector` is packed, consists only of integers, and fits into `bytes`.
+        unsafe { &*(bytes.as_ptr() as *const Self) }.legacy_fat_variant()
+    }
+
+    fn legacy_fat_variant(&self) -> Option<LegacyFatVariant> {
+        let bytes_per_sector = usize::from(self.bytes_per_sector);
+        if self.sectors_per_fat_16 == 0
+            || !VALID_SECTOR_SIZES.contains(&bytes_per_sector)
+            || !self.sectors_per_cluster.is_power_of_two()
+            || self.fat_count == 0
+        {
+            return None;
+        }
+        // FAT12 and FAT16 have a fixed-size root directory between the FATs and the data region
+        let root_dir_sectors =
+            num::Integer::div_ceil(&(usize::from(self.dir_entries) * size_of::<FatDentry>()), &bytes_per_sector);
+        let metadata_sectors = usize::from(self.sectors_before_fat)
+            + usize::from(self.fat_count) * usize::from(self.sectors_per_fat_16)
+            + root_dir_sectors;
+        let data_cluster_count =
+            usize::fromx(self.sector_count()).saturating_sub(metadata_sectors) / usize::from(self.sectors_per_cluster);
+        if data_cluster_count < MIN_FAT16_DATA_CLUSTER_COUNT {
+            Some(LegacyFatVariant::Fat12)
+        } else {
+            Some(LegacyFatVariant::Fat16)
+        }
+    }
+
     /// Performs a sanity check to see if this is indeed a FAT32 boot sector. A return value of `true` does not
     /// guarantee that `self` is consistent with the partition it belongs to, only that this data was meant to be a boot
     /// sector.
@@ -55,13 +133,135 @@ impl BootSector {
         if self.fs_type != FS_TYPE_FAT32 {
             bail!(
                 "Unexpected file system type: {} instead of {}",
-                std::str::from_utf8(&self.fs_type).unwrap_or("(non-printable)"),
-                std::str::from_utf8(&FS_TYPE_FAT32).unwrap_or("(non-printable)")
+                core::str::from_utf8(&self.fs_type).unwrap_or("(non-printable)"),
+                core::str::from_utf8(&FS_TYPE_FAT32).unwrap_or("(non-printable)")
             );
         }
         Ok(self)
     }
 
+    /// Checks that the geometry of the filesystem is within the limits of FAT32 and consistent with a partition of
+    /// `partition_len` bytes. Afterwards, the byte offsets computed by `self` cannot overflow, even on a 32-bit host,
+    /// since they are all within the partition.
+    pub fn check_geometry(&self, partition_len: usize) -> Result<()> {
+        let bytes_per_sector = u64::from(self.bytes_per_sector);
+        if !VALID_SECTOR_SIZES.contains(&usize::from(self.bytes_per_sector)) {
+            bail!(
+                "Unsupported sector size: {} bytes instead of one of {:?}",
+                bytes_per_sector,
+                VALID_SECTOR_SIZES
+            );
+        }
+        if !self.sectors_per_cluster.is_power_of_two() {
+            bail!(
+                "Unsupported cluster size: {} sectors instead of a power of two between 1 and 128",
+                self.sectors_per_cluster
+            );
+        }
+        if self.fat_count == 0 || self.sectors_per_fat == 0 {
+            bail!("The filesystem has no FAT table");
+        }
+
+        let fs_size = bytes_per_sector * u64::from(self.sector_count());
+        if usize::try_from(fs_size).is_err() {
+            bail!(
+                "The filesystem is {} bytes large, but a {}-bit host can address at most {} bytes",
+                fs_size,
+                usize::BITS,
+                usize::MAX
+            );
+        }
+        if fs_size > u64::fromx(partition_len) {
+            bail!(
+                "The filesystem is {} bytes large, but the partition only has {} bytes",
+                fs_size,
+                partition_len
+            );
+        }
+        let first_data_sector =
+            u64::from(self.sectors_before_fat) + u64::from(self.sectors_per_fat) * u64::from(self.fat_count);
+        if first_data_sector >= u64::from(self.sector_count()) {
+            bail!(
+                "The FAT tables end at sector {}, but the filesystem only has {} sectors",
+                first_data_sector,
+                self.sector_count()
+            );
+        }
+
+        let data_cluster_count = self.data_cluster_count();
+        if data_cluster_count > MAX_DATA_CLUSTER_COUNT {
+            bail!(
+                "The filesystem has {} data clusters, but FAT32 supports at most {}",
+                data_cluster_count,
+                MAX_DATA_CLUSTER_COUNT
+            );
+        }
+        // the first two FAT entries do not belong to data clusters
+        let fat_entry_count = u64::from(self.sectors_per_fat) * bytes_per_sector / u64::fromx(size_of::<u32>());
+        if fat_entry_count < u64::from(data_cluster_count) + 2 {
+            bail!(
+                "The FAT table has {} entries, but the filesystem has {} data clusters",
+                fat_entry_count,
+                data_cluster_count
+            );
+        }
+        Ok(())
+    }
+
+    /// Returns the range in bytes of the backup boot sector, relative to the filesystem start, or None if the
+    /// filesystem has no backup boot sector.
+    pub fn backup_boot_sector_range(&self) -> Option<Range<usize>> {
+        if self.backup_boot_sector_no == 0 || self.backup_boot_sector_no == 0xFFFF {
+            return None;
+        }
+        let start = usize::from(self.backup_boot_sector_no) * usize::from(self.bytes_per_sector);
+        Some(start..start + usize::from(self.bytes_per_sector))
+    }
+
+    /// Returns the range in bytes of the FsInfo sector, relative to the filesystem start, or None if the filesystem has
+    /// no FsInfo sector.
+    pub fn fs_info_range(&self) -> Option<Range<usize>> {
+        if self.fs_info_sector_no == 0 || self.fs_info_sector_no == 0xFFFF {
+            return None;
+        }
+        let start = usize::from(self.fs_info_sector_no) * usize::from(self.bytes_per_sector);
+        Some(start..start + usize::from(self.bytes_per_sector))
+    }
+
+    /// Returns the ranges in bytes of the signatures by which tools like blkid identify a FAT32 filesystem, i.e. the
+    /// file system type and 0x55 0xAA signature of the boot sector and the signatures of the FsInfo sector, as well as
+    /// those of their backups, relative to the filesystem start.
+    pub fn signature_ranges(&self) -> Vec<Range<usize>> {
+        let bytes_per_sector = usize::from(self.bytes_per_sector);
+        let fs_type_offset = size_of::<Self>() - FS_TYPE_FAT32.len();
+        let mut ranges = Vec::new();
+
+        let mut boot_sector_starts = vec![0];
+        boot_sector_starts.extend(self.backup_boot_sector_range().map(|range| range.start));
+        for start in boot_sector_starts {
+            ranges.push(start + fs_type_offset..start + fs_type_offset + FS_TYPE_FAT32.len());
+            ranges.push(start + SECTOR_SIGNATURE_OFFSET..start + SECTOR_SIGNATURE_OFFSET + 2);
+        }
+
+        let mut fs_info_sector_nos = Vec::new();
+        if self.fs_info_sector_no != 0 && self.fs_info_sector_no != 0xFFFF {
+            fs_info_sector_nos.push(usize::from(self.fs_info_sector_no));
+            // formatting tools place the backup FsInfo sector after the backup boot sector
+            if let Some(backup_range) = self.backup_boot_sector_range() {
+                fs_info_sector_nos.push(backup_range.start / bytes_per_sector + usize::from(self.fs_info_sector_no));
+            }
+        }
+        for sector_no in fs_info_sector_nos {
+            let start = sector_no * bytes_per_sector;
+            ranges.extend(
+                FS_INFO_SIGNATURE_OFFSETS
+                    .iter()
+                    .map(|offset| start + offset..start + offset + 4),
+            );
+        }
+        ranges
+    }
+
     /// Returns the range in bytes of the first FAT table, relative to the filesystem start
     pub fn get_fat_table_range(&self) -> Range<usize> {
         let fat_table_start_byte = usize::from(self.sectors_before_fat) * usize::from(self.bytes_per_sector);
@@ -95,6 +295,11 @@ impl BootSector {
         self.sector_count() / u32::from(self.sectors_per_cluster)
     }
 
+    /// The number of clusters in the data region, i.e. the clusters that can contain file data
+    pub fn data_cluster_count(&self) -> u32 {
+        self.sector_count().saturating_sub(self.first_data_sector()) / u32::from(self.sectors_per_cluster)
+    }
+
     /// in bytes
     pub fn fs_size(&self) -> usize {
         usize::from(self.bytes_per_sector) * usize::fromx(self.sector_count())
@@ -106,24 +311,172 @@ impl BootSector {
     }
 
     pub fn dentries_per_cluster(&self) -> usize {
-        usize::fromx(self.cluster_size()) / std::mem::size_of::<FatDentry>()
+        usize::fromx(self.cluster_size()) / size_of::<FatDentry>()
     }
 
+    /// Returns the volume label without trailing spaces, or an empty slice if the volume has no label.
     pub fn volume_label(&self) -> &[u8] {
-        if self.ext_boot_signature == 0x28 {
-            &[]
+        // the label is only present if the extended boot signature is 0x29, 0x28 indicates that only the volume ID is
+        // present
+        if self.ext_boot_signature == EXT_BOOT_SIGNATURE_FAT32 {
+            parse_volume_label(&self.volume_label)
         } else {
-            let last_character_idx = self
-                .volume_label
-                .iter()
-                .enumerate()
-                .rev()
-                .filter(|(_idx, &character)| character != b' ')
-                .map(|(idx, _character)| idx)
-                .next()
-                .unwrap_or(0);
-
-            &self.volume_label[0..last_character_idx]
+            &[]
         }
     }
 }
+
+impl fmt::Display for LegacyFatVariant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Fat12 => write!(f, "FAT12"),
+            Self::Fat16 => write!(f, "FAT16"),
+        }
+    }
+}
+
+/// Looks for a valid backup boot sector at its default position, for use if the boot sector itself is damaged and
+/// can therefore not tell where the backup is. Returns the range in bytes of the backup boot sector.
+pub fn find_backup_boot_sector(partition: &[u8]) -> Option<Range<usize>> {
+    VALID_SECTOR_SIZES
+        .iter()
+        .map(|&sector_size| {
+            DEFAULT_BACKUP_BOOT_SECTOR_NO * sector_size..(DEFAULT_BACKUP_BOOT_SECTOR_NO + 1) * sector_size
+        })
+        .find(|range| {
+            let backup = partition
+                .get(range.clone())
+                .and_then(|backup| BootSector::from_bytes(backup).ok());
+            matches!(backup, Some(backup) if usize::from(backup.bytes_per_sector) == range.len())
+        })
+}
+
+/// Strips the space padding from a FAT volume label. Returns an empty slice if the label is "NO NAME", which
+/// formatting tools write if the volume has no label.
+pub fn parse_volume_label(label: &[u8; 11]) -> &[u8] {
+    const NO_NAME: &[u8] = b"NO NAME";
+
+    let len = label.iter().rposition(|&character| character != b' ').map_or(0, |idx| idx + 1);
+    let label = &label[..len];
+    if label == NO_NAME {
+        &[]
+    } else {
+        label
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fat::FatImageBuilder;
+
+    #[test]
+    fn finds_intact_backup_boot_sector() {
+        let mut image = FatImageBuilder::new(32 * 1024 * 1024, 4096).build(&[]);
+        let partition = image.as_mut_slice();
+        let backup_range = BootSector::from_bytes(partition).unwrap().backup_boot_sector_range();
+        assert_eq!(backup_range, Some(3072..3584));
+        assert_eq!(find_backup_boot_sector(partition), backup_range);
+
+        partition[..512].fill(0);
+        assert!(BootSector::from_bytes(partition).is_err());
+        assert_eq!(find_backup_boot_sector(partition), backup_range);
+        partition.copy_within(backup_range.unwrap(), 0);
+        assert!(BootSector::from_bytes(partition).is_ok());
+    }
+
+    #[test]
+    fn checks_geometry() {
+        let mut image = FatImageBuilder::new(32 * 1024 * 1024, 4096).build(&[]);
+        let boot_sector = *BootSector::from_bytes(image.as_mut_slice()).unwrap();
+        assert!(boot_sector.check_geometry(image.len()).is_ok());
+        assert!(boot_sector.check_geometry(image.len() - 1).is_err());
+
+        let mut short_fat = boot_sector;
+        short_fat.sectors_per_fat /= 2;
+        assert!(short_fat.check_geometry(image.len()).is_err());
+
+        let mut odd_cluster_size = boot_sector;
+        odd_cluster_size.sectors_per_cluster = 3;
+        assert!(odd_cluster_size.check_geometry(image.len()).is_err());
+
+        let mut too_many_clusters = boot_sector;
+        too_many_clusters.sectors_per_cluster = 1;
+        too_many_clusters.sector_count_1 = 0;
+        too_many_clusters.sector_count_2 = u32::MAX;
+        too_many_clusters.sectors_per_fat = 1 << 23;
+        let error = too_many_clusters.check_geometry(usize::MAX).unwrap_err();
+        assert!(error.to_string().contains("FAT32 supports at most 268435445"));
+    }
+
+    #[test]
+    fn detects_legacy_fat_variants() {
+        let mut image = FatImageBuilder::new(32 * 1024 * 1024, 4096).build(&[]);
+        let fat32 = *BootSector::from_bytes(image.as_mut_slice()).unwrap();
+        assert_eq!(BootSector::detect_legacy_fat(image.as_mut_slice()), None);
+
+        // a 1.44 MB floppy, which has 2847 data clusters
+        let floppy = BootSector {
+            bytes_per_sector: 512,
+            sectors_per_cluster: 1,
+            sectors_before_fat: 1,
+            fat_count: 2,
+            dir_entries: 224,
+            sector_count_1: 2880,
+            sectors_per_fat_16: 9,
+            sectors_per_fat: 0,
+            ..fat32
+        };
+        assert_eq!(BootSector::detect_legacy_fat(&bytes_of(&floppy)), Some(LegacyFatVariant::Fat12));
+        let error = BootSector::from_bytes(&bytes_of(&floppy)).unwrap_err();
+        assert!(error.to_string().contains("FAT12"), "{:#}", error);
+
+        // 1 reserved sector, 18 FAT sectors and 14 root directory sectors precede the data clusters
+        let smallest_fat16 = BootSector { sector_count_1: 1 + 18 + 14 + 4085, ..floppy };
+        assert_eq!(
+            BootSector::detect_legacy_fat(&bytes_of(&smallest_fat16)),
+            Some(LegacyFatVariant::Fat16)
+        );
+        let largest_fat12 = BootSector { sector_count_1: 1 + 18 + 14 + 4084, ..floppy };
+        assert_eq!(
+            BootSector::detect_legacy_fat(&bytes_of(&largest_fat12)),
+            Some(LegacyFatVariant::Fat12)
+        );
+
+        let implausible = BootSector { bytes_per_sector: 0, ..floppy };
+        assert_eq!(BootSector::detect_legacy_fat(&bytes_of(&implausible)), None);
+    }
+
+    fn bytes_of(boot_sector: &BootSector) -> Vec<u8> {
+        // SAFETY: Safe because `BootSector` is packed and consists only of integers.
+        unsafe { std::slice::from_raw_parts(boot_sector as *const BootSector as *const u8, size_of::<BootSector>()) }
+            .to_vec()
+    }
+
+    #[test]
+    fn rejects_damaged_backup_boot_sector() {
+        let mut image = FatImageBuilder::new(32 * 1024 * 1024, 4096).build(&[]);
+        let partition = image.as_mut_slice();
+        partition[..512].fill(0);
+        partition[3072..3584].fill(0);
+        assert_eq!(find_backup_boot_sector(partition), None);
+    }
+
+    #[test]
+    fn volume_label_keeps_last_character() {
+        assert_eq!(parse_volume_label(b"LABEL      "), b"LABEL");
+        assert_eq!(parse_volume_label(b"ELEVENCHARS"), b"ELEVENCHARS");
+        assert_eq!(parse_volume_label(b"A          "), b"A");
+    }
+
+    #[test]
+    fn volume_label_keeps_inner_spaces() {
+        assert_eq!(parse_volume_label(b"MY LABEL   "), b"MY LABEL");
+    }
+
+    #[test]
+    fn missing_volume_label_is_empty() {
+        assert_eq!(parse_volume_label(b"           "), b"");
+        assert_eq!(parse_volume_label(b"NO NAME    "), b"");
+    }
+}
@@ -1,9 +1,12 @@
-use std::convert::TryFrom;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::convert::TryFrom;
 
 use anyhow::{Context, Result};
 use chrono::prelude::*;
 
-use crate::fat::FatTableIndex;
+use crate::fat::{FatTableIndex, ROOT_FAT_IDX};
 use crate::lohi::LoHi;
 
 #[repr(C)]
@@ -79,6 +82,15 @@ pub struct FatDentry {
 impl FatDentry {
     const DIR_FLAG: u8 = 0x10;
     const READ_ONLY_FLAG: u8 = 0x01;
+    const HIDDEN_FLAG: u8 = 0x02;
+    const SYSTEM_FLAG: u8 = 0x04;
+    const VOLUME_LABEL_FLAG: u8 = 0x08;
+    const ARCHIVE_FLAG: u8 = 0x20;
+    const RESERVED_ATTRS: u8 = 0xC0;
+    /// The characters that 8.3 names cannot contain besides control characters; '.' only appears in the dot entries
+    const INVALID_SHORT_NAME_CHARS: &'static [u8] = b"\"*+,./:;<=>?[\\]|";
+    /// The first byte of an 8.3 name that starts with 0xE5, which marks deleted dentries instead
+    const ESCAPED_E5: u8 = 0x05;
 
     pub fn first_fat_index(&self) -> FatTableIndex {
         let idx = LoHi::new(&self.first_fat_index_lo, &self.first_fat_index_hi).get();
@@ -98,6 +110,66 @@ impl FatDentry {
         self.attrs & Self::READ_ONLY_FLAG != 0
     }
 
+    pub fn is_hidden(&self) -> bool {
+        self.attrs & Self::HIDDEN_FLAG != 0
+    }
+
+    /// True iff the file belongs to the operating system, e.g. a swap file
+    pub fn is_system(&self) -> bool {
+        self.attrs & Self::SYSTEM_FLAG != 0
+    }
+
+    /// True iff the file was modified since a backup tool last cleared the flag
+    pub fn has_archive_flag(&self) -> bool {
+        self.attrs & Self::ARCHIVE_FLAG != 0
+    }
+
+    /// True iff the dentry holds the volume label instead of representing a file
+    pub fn is_volume_label(&self) -> bool {
+        self.attrs & Self::VOLUME_LABEL_FLAG != 0
+    }
+
+    /// Returns why the attributes of the dentry are an invalid combination, or None if they are valid. Whether a
+    /// dentry is a directory only depends on its directory flag, so if the dentry of a file is damaged, its data would
+    /// be read as the dentries of its "children".
+    pub fn attribute_problem(&self) -> Option<&'static str> {
+        if self.is_dir() && self.is_volume_label() {
+            Some("it is marked as both a directory and the volume label")
+        } else if self.is_dir() && self.first_fat_index() < ROOT_FAT_IDX {
+            Some("it is a directory without clusters")
+        } else {
+            None
+        }
+    }
+
+    /// Returns why the dentry is implausible, e.g. because it was read from a cluster that does not contain dentries,
+    /// or None if it is plausible. `has_long_name` tells whether LFN entries precede the dentry; if not, its 8.3
+    /// name becomes the file name and must be ASCII. Volume labels are not checked, since they allow more
+    /// characters.
+    pub fn problem(&self, has_long_name: bool) -> Option<&'static str> {
+        let short_name = self.short_name.iter().chain(self.short_extension.iter());
+        if self.attrs & Self::RESERVED_ATTRS != 0 {
+            Some("reserved attribute bits are set")
+        } else if self.is_volume_label() {
+            None
+        } else if self.short_name[0] == b' ' {
+            Some("its 8.3 name starts with a space")
+        } else if short_name.clone().enumerate().any(|(idx, &byte)| {
+            (byte < 0x20 && !(idx == 0 && byte == Self::ESCAPED_E5)) || Self::INVALID_SHORT_NAME_CHARS.contains(&byte)
+        }) {
+            Some("its 8.3 name contains invalid characters")
+        } else if !has_long_name && !short_name.clone().all(u8::is_ascii) {
+            Some("it has no long name and its 8.3 name is not ASCII")
+        } else {
+            None
+        }
+    }
+
+    /// Clears the directory and volume label flags, so that the dentry represents a regular file.
+    pub fn make_regular_file(&mut self) {
+        self.attrs &= !(Self::DIR_FLAG | Self::VOLUME_LABEL_FLAG);
+    }
+
     /// True iff the file name has an extension
     pub fn has_file_extension(&self) -> bool {
         self.short_extension[0] != b' '
@@ -137,8 +209,17 @@ impl FatDentry {
         fat_time_to_unix(self.access_date, None)
     }
 
+    /// The creation time in whole seconds, including the seconds of `create_time_10_ms`
     pub fn create_time_as_unix(&self) -> Result<u32> {
-        fat_time_to_unix(self.create_date, Some(self.create_time))
+        fat_time_to_unix(self.create_date, Some(self.create_time))?
+            .checked_add(u32::from(self.create_time_10_ms / 100))
+            .context("Timestamp does not fit into 32 bits")
+    }
+
+    /// The fraction of a second of the creation time in nanoseconds. FAT stores the creation time with a resolution
+    /// of 10 ms, unlike the other timestamps.
+    pub fn create_time_nanoseconds(&self) -> u32 {
+        u32::from(self.create_time_10_ms % 100) * 10_000_000
     }
 
     pub fn modify_time_as_unix(&self) -> Result<u32> {
@@ -161,6 +242,11 @@ pub struct LongFileName {
 }
 
 impl LongFileName {
+    /// The flag in `sequence_no` that marks the entry with the end of the name, which comes first on disk
+    const LAST_ENTRY_FLAG: u8 = 0x40;
+    /// The most LFN entries a file can have, since long names have at most 255 characters
+    const MAX_ENTRY_COUNT: u8 = 20;
+
     /// The position of this LFN entry in the complete name, 1-based. On disk, LFN entries appear
     /// in reverse order, so the first entry's `sequence_no` equals the number of entries.
     pub fn sequence_no(&self) -> u8 {
@@ -168,8 +254,29 @@ impl LongFileName {
         self.sequence_no & 0b0001_1111
     }
 
+    /// True iff this entry holds the end of the name, i.e. it is the first entry of the name on disk.
+    pub fn is_last_entry(&self) -> bool {
+        self.sequence_no & Self::LAST_ENTRY_FLAG != 0
+    }
+
+    /// Returns why the entry is implausible, e.g. because it was read from a cluster that does not contain dentries, or
+    /// None if it is plausible. Whether the entries of a name are in sequence is up to the caller.
+    pub fn problem(&self) -> Option<&'static str> {
+        if !(1..=Self::MAX_ENTRY_COUNT).contains(&self.sequence_no())
+            || self.sequence_no & !(Self::LAST_ENTRY_FLAG | 0b0001_1111) != 0
+        {
+            Some("its LFN sequence number is invalid")
+        } else if self.lfn_type != 0 || self.first_cluster != 0 {
+            Some("its LFN type or cluster is not 0")
+        } else if core::char::decode_utf16(self.to_utf16_string()).any(|c| c.is_err()) {
+            Some("its part of the long name is not valid UTF-16")
+        } else {
+            None
+        }
+    }
+
     pub fn to_utf8_string(self) -> String {
-        std::char::decode_utf16(self.to_utf16_string())
+        core::char::decode_utf16(self.to_utf16_string())
             .map(|c| c.expect("FAT long file name entry contains non-UTF16 character"))
             .collect()
     }
@@ -196,7 +303,10 @@ fn fat_time_to_unix(date: u16, time: Option<u16>) -> Result<u32> {
     let year = ((date & 0xFE00) >> 9) + 1980;
     let month = (date & 0x1E0) >> 5;
     let day = date & 0x1F;
-    let date = Utc.ymd(i32::from(year), u32::from(month), u32::from(day));
+    let date = Utc
+        .ymd_opt(i32::from(year), u32::from(month), u32::from(day))
+        .single()
+        .with_context(|| format!("Invalid FAT date {}-{:02}-{:02}", year, month, day))?;
 
     let mut hour = 0;
     let mut minute = 0;
@@ -207,6 +317,10 @@ fn fat_time_to_unix(date: u16, time: Option<u16>) -> Result<u32> {
         second = (time & 0x1F) * 2;
     }
 
-    let datetime = date.and_hms(u32::from(hour), u32::from(minute), u32::from(second));
-    u32::try_from(datetime.timestamp()).context("Timestamp after year 2038 does not fit into 32 bits")
+    let datetime = date
+        .and_hms_opt(u32::from(hour), u32::from(minute), u32::from(second))
+        .with_context(|| format!("Invalid FAT time {:02}:{:02}:{:02}", hour, minute, second))?;
+    u32::try_from(datetime.timestamp())
+        .ok()
+        .context("Timestamp after year 2038 does not fit into 32 bits")
 }
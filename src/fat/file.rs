@@ -1,9 +1,17 @@
 use std::ops::RangeInclusive;
 
+use crate::ext4::{InodeOverrides, Xattr};
 use crate::fat::{DataClusterIdx, FatDentry};
 
+#[derive(Clone)]
 pub struct FatFile {
     pub name: String,
     pub dentry: FatDentry,
     pub data_ranges: Vec<RangeInclusive<DataClusterIdx>>,
+    /// If set (by a `FileOp`), the file is converted to a symlink to this path and its data is discarded
+    pub symlink_target: Option<String>,
+    /// the owner and permissions decided by a `ConversionPolicy`
+    pub overrides: InodeOverrides,
+    /// the extended attributes decided by a `ConversionPolicy`
+    pub xattrs: Vec<Xattr>,
 }
@@ -1,25 +1,27 @@
 use std::convert::TryFrom;
 use std::iter::Step;
 use std::marker::PhantomData;
-use std::mem::size_of;
-use std::ops::RangeInclusive;
+use std::mem::{align_of, size_of};
+use std::ops::{Range, RangeInclusive};
 use std::slice;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 
 use crate::allocator::Allocator;
-use crate::ext4::Ext4Fs;
+use crate::ext4::{Ext4Fs, SuperBlock};
 use crate::fat::{
-    BootSector, Cluster, ClusterIdx, DataClusterIdx, FatFile, FatFileIter, FatIdxIter, FatTableIndex, ROOT_FAT_IDX,
+    check_pseudo_dentries, BootSector, Cluster, ClusterIdx, DataClusterIdx, FatDentry, FatFile, FatFileIter,
+    FatIdxIter, FatPseudoDentryIter, FatTableIndex, FsInfo, MAX_DIR_ENTRY_COUNT, ROOT_FAT_IDX,
 };
 use crate::ranges::Ranges;
-use crate::util::{AddUsize, ExactAlign, FromU32};
+use crate::util::{fnv1a, AddUsize, Bytes, Clusters, ExactAlign, FromU32, FNV_OFFSET_BASIS};
 
 
 /// A FAT32 partition consists of 3 regions: the reserved sectors (which include the boot sector),
 /// the file allocation table (FAT), and the data region.
 pub struct FatFs<'a> {
     boot_sector: &'a BootSector,
+    fs_info: Option<&'a FsInfo>,
     fat_table: &'a [FatTableIndex],
     data_ptr: *const u8,
     data_len: usize,
@@ -33,11 +35,13 @@ impl<'a> FatFs<'a> {
     /// - this memory represents a consistent FAT filesystem;
     /// - no pointer to one of the sections used by the FAT filesystem (i.e. the boot sector, the FAT table(s), and any
     ///   cluster that is not marked as free in the FAT table) will be dereferenced during the lifetime 'a.
+    /// Returns an error if the boot sector is invalid or its geometry does not fit into the partition.
     /// PANICS: Panics if inconsistencies are detected in the filesystem
     pub unsafe fn new(partition_ptr: *mut u8, partition_len: usize, _lifetime: PhantomData<&'a ()>) -> Result<Self> {
         assert!(size_of::<BootSector>() <= partition_len);
         // SAFETY: safe because a consistent FAT32 fs begins with a boot sector
         let boot_sector = unsafe { &*(partition_ptr as *const BootSector) }.validate()?;
+        boot_sector.check_geometry(partition_len)?;
 
         let fat_table_range = boot_sector.get_fat_table_range();
         assert!(fat_table_range.start > size_of::<BootSector>());
@@ -53,8 +57,19 @@ impl<'a> FatFs<'a> {
         assert!(data_range.start > fat_table_range.end);
         assert!(data_range.end <= partition_len);
 
+        // the FsInfo sector is optional, so a missing or damaged one is not an inconsistency
+        let fs_info = boot_sector
+            .fs_info_range()
+            .filter(|range| range.end <= fat_table_range.start)
+            .and_then(|range| {
+                // SAFETY: Safe because the FsInfo sector is within the reserved sectors before the FAT table
+                let fs_info_bytes = unsafe { slice::from_raw_parts(partition_ptr.add_usize(range.start), range.len()) };
+                FsInfo::from_bytes(fs_info_bytes).ok()
+            });
+
         Ok(Self {
             boot_sector,
+            fs_info,
             fat_table,
             // SAFETY: Safe because the data clusters are within the partition
             data_ptr: unsafe { partition_ptr.add_usize(data_range.start) },
@@ -63,6 +78,45 @@ impl<'a> FatFs<'a> {
         })
     }
 
+    /// Reads the FAT32 filesystem in `partition`. Unlike `new`, this is safe: `partition` cannot be written to while it
+    /// is borrowed, and every cluster index read from the filesystem is checked against its bounds, so an inconsistent
+    /// filesystem causes errors or panics, but no undefined behavior.
+    /// Returns an error if `partition` is not aligned to 4 bytes (which a `Vec<u8>` or a memory map usually is), if
+    /// the boot sector is invalid or if its geometry does not fit into `partition`.
+    /// PANICS: Panics if inconsistencies are detected in the filesystem
+    pub fn from_slice(partition: &'a [u8]) -> Result<Self> {
+        Self::check_slice(partition)?;
+        // SAFETY: Safe because `partition` is valid and immutable for the lifetime 'a, and `new` only reads from it.
+        // `new` asserts the filesystem's consistency instead of relying on it.
+        unsafe { Self::new(partition.as_ptr() as *mut u8, partition.len(), PhantomData) }
+    }
+
+    /// Like `new_with_allocator`, but for a partition that is borrowed exclusively for the lifetime 'a, so that its
+    /// memory stays valid and nothing else can access it. The filesystem does not have to be consistent either:
+    /// `FatFs` only reads the clusters that the FAT marks as used, and the `Allocator` only hands out the others.
+    /// Returns an error if `partition` is not aligned to 4 bytes, if the boot sector is invalid or if its geometry
+    /// does not fit into `partition`.
+    /// SAFETY: The caller must not mix the `AllocatedClusterIdx`s of the returned `Allocator` with those of another
+    /// `Allocator`, see `Allocator::new`.
+    /// PANICS: Panics if inconsistencies are detected in the filesystem
+    pub unsafe fn from_slice_with_allocator(partition: &'a mut [u8]) -> Result<(Self, Allocator<'a>)> {
+        Self::check_slice(partition)?;
+        // SAFETY: Safe because `partition` is valid for the lifetime 'a and borrowed exclusively, so only `FatFs` and
+        // `Allocator` access it, which keep apart as described above.
+        unsafe { Self::new_with_allocator(partition.as_mut_ptr(), partition.len(), PhantomData) }
+    }
+
+    /// Returns an error if `FatFs::new` cannot read `partition`'s boot sector or FAT table in place.
+    pub fn check_slice(partition: &[u8]) -> Result<()> {
+        if partition.len() < size_of::<BootSector>() {
+            bail!("The partition is too small to contain a FAT32 boot sector");
+        }
+        if partition.as_ptr().align_offset(align_of::<FatTableIndex>()) != 0 {
+            bail!("The partition is not aligned to {} bytes", align_of::<FatTableIndex>());
+        }
+        Ok(())
+    }
+
     /// SAFETY: The caller must guarantee that:
     /// - the `partition_len` bytes starting at `partition_ptr` are all valid memory;
     /// - this memory will remain valid for the lifetime 'a;
@@ -91,18 +145,24 @@ impl<'a> FatFs<'a> {
         }
     }
 
-    /// SAFETY: Safe if no block in `SuperBlock::from(self.boot_sector).block_group_overhead_ranges()` is accessed for
-    /// the duration of the lifetime 'a
-    pub unsafe fn into_ext4(self) -> Result<Ext4Fs<'a>> {
+    /// SAFETY: Safe if `self` was not created by `from_slice`, `superblock` was created from `self.boot_sector` and no
+    /// block in `superblock.block_group_overhead_ranges()` is accessed for the duration of the lifetime 'a
+    pub unsafe fn into_ext4(self, superblock: SuperBlock) -> Ext4Fs<'a> {
         let start_ptr = self.boot_sector as *const _ as *mut u8;
-        // SAFETY: Safe since `start_ptr` is the start of a consistent filesystem described by `boot_sector`.
-        unsafe { Ext4Fs::from(start_ptr, self.boot_sector) }
+        // SAFETY: Safe since `start_ptr` is the start of a consistent filesystem described by `boot_sector`, which
+        // `superblock` fits into.
+        unsafe { Ext4Fs::from(start_ptr, superblock) }
     }
 
     pub fn boot_sector(&self) -> &BootSector {
         self.boot_sector
     }
 
+    /// Returns the FsInfo sector, or None if the filesystem has no intact FsInfo sector.
+    pub fn fs_info(&self) -> Option<&FsInfo> {
+        self.fs_info
+    }
+
     pub fn fat_table(&self) -> &'a [FatTableIndex] {
         self.fat_table
     }
@@ -127,18 +187,51 @@ impl<'a> FatFs<'a> {
         !self.fat_table[data_cluster_idx.to_fat_index()].is_free()
     }
 
-    /// PANICS: Panics if `data_cluster_idx` is not a valid, in-use data cluster.
-    pub fn data_cluster(&self, data_cluster_idx: DataClusterIdx) -> &Cluster {
-        assert!(self.is_used(data_cluster_idx));
+    /// Returns an error if `data_cluster_idx` is not a valid, in-use data cluster, which means that the FAT chain it
+    /// was taken from is damaged.
+    pub fn data_cluster(&self, data_cluster_idx: DataClusterIdx) -> Result<&Cluster> {
         let cluster_size = usize::fromx(self.cluster_size());
-        let start_byte = usize::from(data_cluster_idx) * cluster_size;
-        assert!(start_byte + cluster_size <= self.data_len);
-        unsafe {
+        // a damaged FAT chain can contain any cluster index, whose offset may not fit into a usize on a 32-bit host
+        let start_byte = Clusters::from(u32::from(data_cluster_idx))
+            .to_bytes(self.cluster_size())
+            .and_then(Bytes::to_usize)
+            .filter(|start_byte| matches!(start_byte.checked_add(cluster_size), Some(end) if end <= self.data_len));
+        let start_byte = match start_byte {
+            Some(start_byte) => start_byte,
+            None => bail!(
+                "A FAT chain contains the data cluster {}, which is beyond the end of the partition",
+                usize::from(data_cluster_idx)
+            ),
+        };
+        if !self.is_used(data_cluster_idx) {
+            bail!(
+                "A FAT chain contains the data cluster {}, which is marked as free",
+                usize::from(data_cluster_idx)
+            );
+        }
+        let cluster = unsafe {
             // SAFETY: safe because the cluster is within the partition.
             let ptr = self.data_ptr.add_usize(start_byte);
             // SAFETY: safe because the memory is valid and cannot be mutated without borrowing `self` as mut.
             slice::from_raw_parts(ptr, cluster_size)
-        }
+        };
+        Ok(cluster)
+    }
+
+    /// Returns the content of the regular file `file` cluster by cluster, up to its size. Yields an error instead of a
+    /// cluster that `data_cluster` rejects.
+    pub fn file_content<'f>(&'f self, file: &'f FatFile) -> impl Iterator<Item = Result<&'f [u8]>> + 'f {
+        let mut remaining = usize::fromx(file.dentry.file_size);
+        file.data_ranges.iter().cloned().flatten().map_while(move |data_cluster_idx| {
+            if remaining == 0 {
+                return None;
+            }
+            Some(self.data_cluster(data_cluster_idx).map(|cluster| {
+                let len = remaining.min(cluster.len());
+                remaining -= len;
+                &cluster[..len]
+            }))
+        })
     }
 
     /// Given the index of a directory's first cluster, iterate over the directory's content.
@@ -147,6 +240,138 @@ impl<'a> FatFs<'a> {
         unsafe { FatFileIter::new(first_fat_idx, self) }
     }
 
+    /// Returns an error if the directory starting at `first_fat_idx` cannot be read or its clusters do not plausibly
+    /// contain dentries, see `check_pseudo_dentries`. Iterating over a directory that fails this check yields
+    /// arbitrary files or panics, e.g. if a damaged dentry points to file data.
+    pub fn check_directory(&self, first_fat_idx: FatTableIndex) -> Result<()> {
+        // both are powers of 2
+        let max_cluster_count = (MAX_DIR_ENTRY_COUNT / self.dentries_per_cluster()).max(1);
+        let mut fat_idx = first_fat_idx;
+        let mut cluster_count = 0;
+        while !fat_idx.is_chain_end() {
+            if fat_idx < ROOT_FAT_IDX || usize::from(fat_idx) >= self.fat_table.len() {
+                bail!(
+                    "The cluster chain of the directory contains the invalid FAT index {}",
+                    u32::from(fat_idx)
+                );
+            }
+            if cluster_count == max_cluster_count {
+                bail!("The directory has more than {} entries", MAX_DIR_ENTRY_COUNT);
+            }
+            self.data_cluster(fat_idx.to_data_cluster_idx())?;
+            fat_idx = self.fat_table[fat_idx];
+            cluster_count += 1;
+        }
+
+        // shorten 'a to the borrow of `self`, which `FatPseudoDentryIter` requires
+        let fat_fs: &FatFs = self;
+        // SAFETY: Safe because every cluster of the chain can be read, as checked above, and every bit pattern is a
+        // pseudo-dentry, so a cluster that does not belong to a directory only yields implausible dentries.
+        check_pseudo_dentries(unsafe { FatPseudoDentryIter::new(first_fat_idx, fat_fs) })
+    }
+
+    /// Iterates over the files and directories in the root directory, without '.' and '..'.
+    /// PANICS: Like `dir_content_iter`, the iterator panics if the root directory is damaged.
+    pub fn root_dir(&self) -> impl Iterator<Item = FatFile> + '_ {
+        // shorten 'a to the borrow of `self`, which `dir_content_iter` requires
+        let fat_fs: &FatFs = self;
+        // SAFETY: safe because `ROOT_FAT_IDX` is the first cluster of the root directory
+        unsafe { fat_fs.dir_content_iter(ROOT_FAT_IDX) }
+    }
+
+    /// Iterates over the files and directories in `dir`, without '.' and '..', or returns None if `dir` is not a
+    /// directory, its attributes are invalid (see `FatDentry::attribute_problem`) or it fails `check_directory`. `dir`
+    /// must have been read from `self`, otherwise the iterator returns arbitrary files or panics. PANICS: Like
+    /// `dir_content_iter`, the iterator panics if the directory is damaged.
+    pub fn read_dir(&self, dir: &FatFile) -> Option<impl Iterator<Item = FatFile> + '_> {
+        if !dir.dentry.is_dir()
+            || dir.dentry.attribute_problem().is_some()
+            || self.check_directory(dir.dentry.first_fat_index()).is_err()
+        {
+            return None;
+        }
+        let fat_fs: &FatFs = self;
+        // SAFETY: safe because `dir` is a directory. If it belongs to another filesystem, its clusters are still
+        // checked against the bounds of this one.
+        Some(unsafe { fat_fs.dir_content_iter(dir.dentry.first_fat_index()) })
+    }
+
+    /// Returns the time the volume label was set, which is usually when the volume was formatted, or None if the root
+    /// directory has no volume label dentry. The volume ID in the boot sector is often derived from the format time
+    /// as well, but not in a way that can be reversed.
+    pub fn volume_label_time(&self) -> Result<Option<u32>> {
+        let label_dentry = match self.root_dir().map(|file| file.dentry).find(FatDentry::is_volume_label) {
+            Some(dentry) => dentry,
+            None => return Ok(None),
+        };
+        // some formatting tools only set the modification time of the label
+        let time = if label_dentry.create_date != 0 {
+            label_dentry.create_time_as_unix()?
+        } else {
+            label_dentry.modify_time_as_unix()?
+        };
+        Ok(Some(time))
+    }
+
+    /// Walks the directory tree and returns the number of files and directories in it, excluding the root directory.
+    pub fn file_count(&'a self) -> usize {
+        let mut file_count = 0;
+        self.walk_directories(|_, children| file_count += children.len());
+        file_count
+    }
+
+    /// Walks the directory tree and returns the clusters occupied by directories, including the root directory.
+    pub fn directory_ranges(&'a self) -> Ranges<ClusterIdx> {
+        let mut ranges = Ranges::new();
+        self.walk_directories(|first_fat_idx, _| {
+            for range in self.data_ranges(first_fat_idx) {
+                ranges.insert(
+                    self.cluster_from_data_cluster(*range.start())..self.cluster_from_data_cluster(*range.end()) + 1,
+                );
+            }
+        });
+        ranges
+    }
+
+    /// Returns a checksum of the FAT table and the content of every directory, which changes if a file is created,
+    /// deleted, moved, renamed, resized or modified, but not necessarily if only its data changes. The checksum (64-bit
+    /// FNV-1a) only depends on the filesystem, so it can be compared across invocations of the converter.
+    pub fn fingerprint(&'a self) -> Result<u64> {
+        let mut hash = FNV_OFFSET_BASIS;
+        for &fat_entry in self.fat_table() {
+            hash = fnv1a(hash, &u32::from(fat_entry).to_le_bytes());
+        }
+        let mut directories = Vec::new();
+        self.walk_directories(|first_fat_idx, _| directories.push(first_fat_idx));
+        for first_fat_idx in directories {
+            for data_cluster_idx in self.data_ranges(first_fat_idx).into_iter().flatten() {
+                hash = fnv1a(hash, self.data_cluster(data_cluster_idx)?);
+            }
+        }
+        Ok(hash)
+    }
+
+    /// Calls `visit` with the first FAT index and the children of every directory in the tree. Directories with invalid
+    /// attributes are not entered, see `FatDentry::attribute_problem`, nor are directories that fail `check_directory`,
+    /// which the serializer reports.
+    fn walk_directories<F: FnMut(FatTableIndex, &[FatFile])>(&'a self, mut visit: F) {
+        let mut directories = vec![ROOT_FAT_IDX];
+        while let Some(first_fat_idx) = directories.pop() {
+            if self.check_directory(first_fat_idx).is_err() {
+                continue;
+            }
+            // SAFETY: safe because only the first FAT indices of directories are pushed to `directories`
+            let children: Vec<FatFile> = unsafe { self.dir_content_iter(first_fat_idx) }.collect();
+            visit(first_fat_idx, &children);
+            directories.extend(
+                children
+                    .iter()
+                    .filter(|file| file.dentry.is_dir() && file.dentry.attribute_problem().is_none())
+                    .map(|file| file.dentry.first_fat_index()),
+            );
+        }
+    }
+
     /// Given a file's first FAT index, follow the FAT chain and collect all of the file's FAT indices into a list of
     /// adjacent ranges.
     pub fn data_ranges(&'a self, first_fat_idx: FatTableIndex) -> Vec<RangeInclusive<DataClusterIdx>> {
@@ -171,6 +396,19 @@ impl<'a> FatFs<'a> {
         ranges
     }
 
+    /// Returns the number of data clusters that are marked as free in the FAT table.
+    pub fn free_cluster_count(&self) -> u32 {
+        let data_cluster_count = usize::fromx(self.boot_sector.data_cluster_count());
+        let free_cluster_count = self
+            .fat_table()
+            .iter()
+            .skip(usize::from(ROOT_FAT_IDX))
+            .take(data_cluster_count)
+            .filter(|fat_cell| fat_cell.is_free())
+            .count();
+        u32::try_from(free_cluster_count).expect("There are at most `data_cluster_count` free clusters")
+    }
+
     /// Returns the occupied clusters in the filesystem
     pub fn used_ranges(&self) -> Ranges<ClusterIdx> {
         let mut ranges = Ranges::new();
@@ -178,13 +416,23 @@ impl<'a> FatFs<'a> {
         let non_data_range = 0..first_data_cluster_idx;
         ranges.insert(non_data_range);
 
-        // could be optimized to build bigger ranges and call `ranges.insert` less often
+        // Coalesce consecutive used clusters into runs. Since the runs are found in ascending order, inserting them
+        // only ever appends to `ranges`.
+        let mut current_run: Option<Range<ClusterIdx>> = None;
         for (fat_idx, &fat_cell) in self.fat_table().iter().enumerate().skip(usize::from(ROOT_FAT_IDX)) {
-            if !fat_cell.is_free() {
-                let range_start = FatTableIndex::try_from(fat_idx).unwrap().to_cluster_idx(self.boot_sector());
-                ranges.insert(range_start..range_start + 1);
+            if fat_cell.is_free() {
+                if let Some(run) = current_run.take() {
+                    ranges.insert(run);
+                }
+            } else {
+                let cluster_idx = FatTableIndex::try_from(fat_idx).unwrap().to_cluster_idx(self.boot_sector());
+                let run_start = current_run.map_or(cluster_idx, |run| run.start);
+                current_run = Some(run_start..cluster_idx + 1);
             }
         }
+        if let Some(run) = current_run {
+            ranges.insert(run);
+        }
         ranges
     }
 }
@@ -195,10 +443,225 @@ mod tests {
     use std::iter::FromIterator;
 
     use super::*;
-    use crate::fat::ROOT_FAT_IDX;
+    use crate::fat::{FatImageBuilder, TestFile, ROOT_FAT_IDX};
     use crate::partition::Partition;
     use crate::util::tests::backup_copy;
 
+    #[test]
+    fn used_ranges_cover_used_clusters() {
+        let files = (0..20)
+            .map(|idx| TestFile::RegularFile { name: idx.to_string(), size: idx * 3000 })
+            .collect::<Vec<_>>();
+        for builder in [
+            FatImageBuilder::new(32 * 1024 * 1024, 1024),
+            FatImageBuilder::new(32 * 1024 * 1024, 1024).fragmented(),
+        ] {
+            let mut image = builder.build(&files);
+            // SAFETY: Safe because `image` contains a consistent FAT32 filesystem and outlives `fat_fs`.
+            let fat_fs = unsafe { FatFs::new(image.as_mut_ptr(), image.len(), PhantomData) }.unwrap();
+
+            let mut expected_ranges = Ranges::new();
+            expected_ranges.insert(0..fat_fs.boot_sector().first_data_cluster());
+            for (fat_idx, fat_cell) in fat_fs.fat_table().iter().enumerate().skip(usize::from(ROOT_FAT_IDX)) {
+                if !fat_cell.is_free() {
+                    let cluster_idx = FatTableIndex::try_from(fat_idx).unwrap().to_cluster_idx(fat_fs.boot_sector());
+                    expected_ranges.insert(cluster_idx..cluster_idx + 1);
+                }
+            }
+            let used_ranges = fat_fs.used_ranges();
+            assert!(used_ranges.into_iter().eq(expected_ranges.into_iter()));
+        }
+    }
+
+    #[test]
+    fn reads_directories_from_slice() {
+        let files = [
+            TestFile::RegularFile { name: "file".to_string(), size: 10 * 1024 },
+            TestFile::Directory {
+                name: "dir".to_string(),
+                children: vec![TestFile::RegularFile { name: "Long File Name.txt".to_string(), size: 0 }],
+            },
+        ];
+        let mut image = FatImageBuilder::new(32 * 1024 * 1024, 1024).build(&files);
+        let fat_fs = FatFs::from_slice(image.as_mut_slice()).unwrap();
+
+        let root_files = fat_fs.root_dir().collect::<Vec<_>>();
+        assert_eq!(
+            root_files.iter().map(|file| file.name.as_str()).collect::<Vec<_>>(),
+            ["file", "dir"]
+        );
+        assert!(fat_fs.read_dir(&root_files[0]).is_none());
+        let dir_files = fat_fs.read_dir(&root_files[1]).unwrap().collect::<Vec<_>>();
+        assert_eq!(dir_files.len(), 1);
+        assert_eq!(dir_files[0].name, "Long File Name.txt");
+
+        let slice = image.as_mut_slice();
+        assert!(FatFs::from_slice(&slice[1..]).is_err());
+        assert!(FatFs::from_slice(&slice[..100]).is_err());
+    }
+
+    #[test]
+    fn damaged_chains_cannot_be_read() {
+        let mut image = FatImageBuilder::new(32 * 1024 * 1024, 1024).build(&[]);
+        // SAFETY: Safe because `image` contains a consistent FAT32 filesystem and outlives `fat_fs`.
+        let fat_fs = unsafe { FatFs::new(image.as_mut_ptr(), image.len(), PhantomData) }.unwrap();
+        assert!(fat_fs.data_cluster(ROOT_FAT_IDX.to_data_cluster_idx()).is_ok());
+
+        let free_idx = FatTableIndex::try_from(usize::from(ROOT_FAT_IDX) + 1).unwrap();
+        let error = fat_fs.data_cluster(free_idx.to_data_cluster_idx()).err().unwrap();
+        assert!(error.to_string().contains("marked as free"));
+        let beyond_idx = FatTableIndex::try_from(usize::fromx(fat_fs.cluster_count()) + 2).unwrap();
+        assert!(fat_fs.data_cluster(beyond_idx.to_data_cluster_idx()).is_err());
+    }
+
+    #[test]
+    fn directories_without_dentries_are_not_entered() {
+        let files = [
+            TestFile::RegularFileWithContent {
+                name: "junk".to_string(),
+                content: (0..1024u32).map(|idx| (idx * 37 + 11) as u8).collect(),
+            },
+            TestFile::Directory {
+                name: "dir".to_string(),
+                children: vec![TestFile::RegularFile { name: "file".to_string(), size: 10 }],
+            },
+        ];
+        let mut image = FatImageBuilder::new(32 * 1024 * 1024, 1024).build(&files);
+        let fat_fs = FatFs::from_slice(image.as_mut_slice()).unwrap();
+        let first_fat_index = |name: &str| {
+            fat_fs
+                .root_dir()
+                .find(|file| file.name == name)
+                .unwrap()
+                .dentry
+                .first_fat_index()
+        };
+        let junk_idx = first_fat_index("junk");
+        assert!(fat_fs.check_directory(ROOT_FAT_IDX).is_ok());
+        assert!(fat_fs.check_directory(first_fat_index("dir")).is_ok());
+        let error = fat_fs.check_directory(junk_idx).unwrap_err().to_string();
+        assert!(error.starts_with("Entry 0 of the directory is implausible"), "{}", error);
+        assert_eq!(fat_fs.file_count(), 3);
+
+        image.patch_root_dentry("dir", |dentry| {
+            dentry.first_fat_index_lo = u32::from(junk_idx) as u16;
+            dentry.first_fat_index_hi = (u32::from(junk_idx) >> 16) as u16;
+        });
+        let fat_fs = FatFs::from_slice(image.as_mut_slice()).unwrap();
+        let dir = fat_fs.root_dir().find(|file| file.name == "dir").unwrap();
+        assert!(fat_fs.read_dir(&dir).is_none());
+        assert_eq!(fat_fs.file_count(), 2);
+    }
+
+    #[test]
+    fn directory_ranges_cover_directories() {
+        let files = [
+            TestFile::RegularFile { name: "file".to_string(), size: 10 * 1024 },
+            TestFile::Directory {
+                name: "dir".to_string(),
+                children: vec![TestFile::Directory { name: "subdir".to_string(), children: Vec::new() }],
+            },
+        ];
+        let mut image = FatImageBuilder::new(32 * 1024 * 1024, 1024).build(&files);
+        // SAFETY: Safe because `image` contains a consistent FAT32 filesystem and outlives `fat_fs`.
+        let fat_fs = unsafe { FatFs::new(image.as_mut_ptr(), image.len(), PhantomData) }.unwrap();
+        let directory_ranges = fat_fs.directory_ranges();
+
+        let root_cluster = fat_fs.boot_sector().first_data_cluster();
+        assert_eq!(
+            directory_ranges.split_overlapping(root_cluster..root_cluster + 1),
+            vec![(root_cluster..root_cluster + 1, true)]
+        );
+        // the root directory, "dir" and "subdir" take up one cluster each, the file ten
+        let directory_cluster_count: u32 = directory_ranges.into_iter().map(|range| range.end - range.start).sum();
+        assert_eq!(directory_cluster_count, 3);
+        assert_eq!(fat_fs.file_count(), 3);
+    }
+
+    #[test]
+    fn fingerprint_detects_changed_directories() {
+        let fingerprint = |name: &str, size: u32| {
+            let mut image = FatImageBuilder::new(32 * 1024 * 1024, 1024).build(&[TestFile::Directory {
+                name: "dir".to_string(),
+                children: vec![TestFile::RegularFile { name: name.to_string(), size }],
+            }]);
+            // SAFETY: Safe because `image` contains a consistent FAT32 filesystem and outlives `fat_fs`.
+            let fat_fs = unsafe { FatFs::new(image.as_mut_ptr(), image.len(), PhantomData) }.unwrap();
+            fat_fs.fingerprint().unwrap()
+        };
+
+        assert_eq!(fingerprint("file", 1000), fingerprint("file", 1000));
+        assert_ne!(fingerprint("file", 1000), fingerprint("renamed", 1000));
+        // the size changes the directory, but not the FAT table
+        assert_ne!(fingerprint("file", 1000), fingerprint("file", 1001));
+    }
+
+    #[test]
+    fn skips_dot_entries_in_root() {
+        let files = [
+            TestFile::RegularFile { name: "file".to_string(), size: 1000 },
+            TestFile::Directory {
+                name: "dir".to_string(),
+                children: vec![TestFile::RegularFile { name: "nested".to_string(), size: 0 }],
+            },
+        ];
+        let mut image = FatImageBuilder::new(32 * 1024 * 1024, 1024).root_dot_entries().build(&files);
+        // SAFETY: Safe because `image` contains a FAT32 filesystem and outlives `fat_fs`.
+        let fat_fs = unsafe { FatFs::new(image.as_mut_ptr(), image.len(), PhantomData) }.unwrap();
+
+        // SAFETY: Safe because `ROOT_FAT_IDX` is the first cluster of the root directory
+        let names: Vec<_> = unsafe { fat_fs.dir_content_iter(ROOT_FAT_IDX) }.map(|file| file.name).collect();
+        assert_eq!(names, ["file", "dir"]);
+        // SAFETY: Safe because `ROOT_FAT_IDX` is the first cluster of the root directory
+        assert_eq!(unsafe { fat_fs.dir_content_iter(ROOT_FAT_IDX) }.count(), names.len());
+        assert_eq!(fat_fs.file_count(), 3);
+    }
+
+    #[test]
+    fn counts_free_clusters() {
+        let mut empty_image = FatImageBuilder::new(32 * 1024 * 1024, 1024).build(&[]);
+        let mut image = FatImageBuilder::new(32 * 1024 * 1024, 1024)
+            .build(&[TestFile::RegularFile { name: "file".to_string(), size: 10 * 1024 }]);
+        // SAFETY: Safe because the images contain consistent FAT32 filesystems and outlive the `FatFs`s.
+        let (empty_fat_fs, fat_fs) = unsafe {
+            (
+                FatFs::new(empty_image.as_mut_ptr(), empty_image.len(), PhantomData).unwrap(),
+                FatFs::new(image.as_mut_ptr(), image.len(), PhantomData).unwrap(),
+            )
+        };
+
+        assert_eq!(empty_fat_fs.free_cluster_count() - fat_fs.free_cluster_count(), 10);
+        // only the root directory is used
+        assert_eq!(
+            empty_fat_fs.free_cluster_count(),
+            empty_fat_fs.boot_sector().data_cluster_count() - 1
+        );
+        assert!(fat_fs.fs_info().is_some());
+    }
+
+    #[test]
+    fn finds_volume_label_time() {
+        let mut image =
+            FatImageBuilder::new(32 << 20, 1024).build(&[TestFile::RegularFile { name: "label".to_string(), size: 0 }]);
+        let root_dir_start = {
+            // SAFETY: Safe because `image` contains a consistent FAT32 filesystem and outlives `fat_fs`.
+            let fat_fs = unsafe { FatFs::new(image.as_mut_ptr(), image.len(), PhantomData) }.unwrap();
+            assert_eq!(fat_fs.volume_label_time().unwrap(), None);
+            fat_fs.boot_sector().get_data_range().start
+        };
+
+        // turn the file's dentry, which follows one LFN entry, into a volume label that was set on 2021-03-04 05:06:08
+        let dentry_start = root_dir_start + size_of::<FatDentry>();
+        let dentry = &mut image.as_mut_slice()[dentry_start..dentry_start + size_of::<FatDentry>()];
+        dentry[11] = 0x08; // attrs
+        dentry[16..18].fill(0); // create date
+        dentry[22..24].copy_from_slice(&((5_u16 << 11) | (6 << 5) | 4).to_le_bytes()); // modification time
+        dentry[24..26].copy_from_slice(&((41_u16 << 9) | (3 << 5) | 4).to_le_bytes()); // modification date
+                                                                                       // SAFETY: Safe because `image` contains a consistent FAT32 filesystem and outlives `fat_fs`.
+        let fat_fs = unsafe { FatFs::new(image.as_mut_ptr(), image.len(), PhantomData) }.unwrap();
+        assert_eq!(fat_fs.volume_label_time().unwrap(), Some(1_614_834_368));
+    }
+
     #[test]
     fn iterates_over_dir_content() {
         const FAT_IMAGE_PATH: &str = "test/example_fat.img";
@@ -0,0 +1,76 @@
+use core::mem::size_of;
+
+use anyhow::{bail, Result};
+
+const LEAD_SIGNATURE: [u8; 4] = *b"RRaA";
+const STRUCT_SIGNATURE: [u8; 4] = *b"rrAa";
+const TRAIL_SIGNATURE: [u8; 4] = [0x00, 0x00, 0x55, 0xAA];
+/// The value of `free_cluster_count` and `next_free_cluster` if they are unknown
+const UNKNOWN: u32 = 0xFFFF_FFFF;
+
+/// The FsInfo sector, in which FAT32 drivers cache the number of free clusters. The cached values are only hints: a
+/// driver that does not maintain them, or a crash, leaves them out of date.
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct FsInfo {
+    pub lead_signature: [u8; 4],
+    pub reserved: [u8; 480],
+    pub struct_signature: [u8; 4],
+    pub free_cluster_count: u32,
+    pub next_free_cluster: u32,
+    pub reserved2: [u8; 12],
+    pub trail_signature: [u8; 4],
+}
+
+impl FsInfo {
+    /// Interprets the start of `bytes` as an FsInfo sector and checks its signatures.
+    pub fn from_bytes(bytes: &[u8]) -> Result<&Self> {
+        if bytes.len() < size_of::<Self>() {
+            bail!("The partition is too small to contain an FsInfo sector");
+        }
+        // SAFETY: Safe because `FsInfo` is packed, consists only of integers, and fits into `bytes`.
+        let fs_info = unsafe { &*(bytes.as_ptr() as *const Self) };
+        if fs_info.lead_signature != LEAD_SIGNATURE
+            || fs_info.struct_signature != STRUCT_SIGNATURE
+            || fs_info.trail_signature != TRAIL_SIGNATURE
+        {
+            bail!("The FsInfo sector has invalid signatures");
+        }
+        Ok(fs_info)
+    }
+
+    /// Returns the cached number of free clusters, or None if it is unknown or cannot be correct because it exceeds
+    /// `data_cluster_count`.
+    pub fn free_cluster_count(&self, data_cluster_count: u32) -> Option<u32> {
+        let free_cluster_count = self.free_cluster_count;
+        if free_cluster_count == UNKNOWN || free_cluster_count > data_cluster_count {
+            None
+        } else {
+            Some(free_cluster_count)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fat::{BootSector, FatImageBuilder};
+
+    #[test]
+    fn reads_free_cluster_count() {
+        let mut image = FatImageBuilder::new(32 * 1024 * 1024, 4096).build(&[]);
+        let partition = image.as_mut_slice();
+        let fs_info_range = BootSector::from_bytes(partition).unwrap().fs_info_range().unwrap();
+        let fs_info = FsInfo::from_bytes(&partition[fs_info_range.clone()]).unwrap();
+        // the test images do not record the free cluster count
+        assert_eq!(fs_info.free_cluster_count(100), None);
+
+        partition[fs_info_range.start + 488..fs_info_range.start + 492].copy_from_slice(&42_u32.to_le_bytes());
+        let fs_info = FsInfo::from_bytes(&partition[fs_info_range.clone()]).unwrap();
+        assert_eq!(fs_info.free_cluster_count(100), Some(42));
+        assert_eq!(fs_info.free_cluster_count(41), None);
+
+        partition[fs_info_range.start] = 0;
+        assert!(FsInfo::from_bytes(&partition[fs_info_range]).is_err());
+    }
+}
@@ -1,7 +1,9 @@
 use std::iter::Peekable;
 
+use anyhow::{bail, Result};
 use itertools::free::join;
 
+use crate::ext4::InodeOverrides;
 use crate::fat::{FatFile, FatFs, FatPseudoDentry, FatTableIndex};
 use crate::util::ExactAlign;
 
@@ -55,15 +57,12 @@ where I: Iterator<Item = &'a FatPseudoDentry>
             name: file_name,
             dentry: *dentry,
             data_ranges: self.fat_fs.data_ranges(dentry.first_fat_index()),
+            symlink_target: None,
+            overrides: InodeOverrides::default(),
+            xattrs: Vec::new(),
         };
         Some(file)
     }
-
-    fn count(self) -> usize {
-        self.pseudo_dentry_iter
-            .filter(|pseudo_dentry| pseudo_dentry.is_dentry())
-            .count()
-    }
 }
 
 impl<'a, I> FatFileIter<'a, I>
@@ -90,8 +89,58 @@ where I: Iterator<Item = &'a FatPseudoDentry>
     }
 }
 
+/// The maximum number of entries in a directory, including LFN entries, which the FAT specification limits to 65536,
+/// i.e. a directory of 2 MiB
+pub const MAX_DIR_ENTRY_COUNT: usize = 65536;
+
+/// Checks that `pseudo_dentries`, the pseudo-dentries of a directory as `FatPseudoDentryIter` yields them, plausibly
+/// are dentries: every entry must pass `FatDentry::problem` or `LongFileName::problem`, the LFN entries of a name must
+/// be in sequence and followed by a dentry, and there must not be more than `MAX_DIR_ENTRY_COUNT` entries. Clusters of
+/// file data, e.g. those a damaged dentry points to, almost certainly fail this check, whereas `FatFileIter` would turn
+/// them into arbitrary files or panic. The error names the first implausible entry, counting from 0 and skipping the
+/// entries that `FatPseudoDentryIter` skips.
+pub fn check_pseudo_dentries<'a, I>(pseudo_dentries: I) -> Result<()>
+where I: Iterator<Item = &'a FatPseudoDentry> {
+    // the sequence number of the next LFN entry while reading a long name
+    let mut next_sequence_no = None;
+    for (idx, pseudo_dentry) in pseudo_dentries.enumerate() {
+        if idx == MAX_DIR_ENTRY_COUNT {
+            bail!("The directory has more than {} entries", MAX_DIR_ENTRY_COUNT);
+        }
+        let problem = if let Some(long_file_name) = pseudo_dentry.as_long_file_name() {
+            let in_sequence = match next_sequence_no {
+                None => long_file_name.is_last_entry(),
+                Some(expected) => !long_file_name.is_last_entry() && long_file_name.sequence_no() == expected,
+            };
+            next_sequence_no = Some(long_file_name.sequence_no().saturating_sub(1));
+            long_file_name
+                .problem()
+                .or_else(|| (!in_sequence).then_some("its LFN entry is out of sequence"))
+        } else {
+            let dentry = pseudo_dentry
+                .as_dentry()
+                .expect("A pseudo-dentry is either an LFN entry or a dentry");
+            let problem = match next_sequence_no {
+                Some(0) => dentry.problem(true),
+                Some(_) => Some("its long name is incomplete"),
+                None => dentry.problem(false),
+            };
+            next_sequence_no = None;
+            problem
+        };
+        if let Some(problem) = problem {
+            bail!("Entry {} of the directory is implausible: {}", idx, problem);
+        }
+    }
+    if next_sequence_no.is_some() {
+        bail!("The directory ends with an incomplete long name");
+    }
+    Ok(())
+}
+
 /// Given the index of a directory's initial data cluster, iterates over the directory's valid
-/// pseudo-dentries (excluding the '.' and '..' directories.
+/// pseudo-dentries (excluding the '.' and '..' directories, which are skipped in the root directory as well, where some
+/// formatting tools create them although they are not allowed there).
 pub struct FatPseudoDentryIter<'a, I>
 where I: Iterator<Item = FatTableIndex>
 {
@@ -140,7 +189,11 @@ where I: Iterator<Item = FatTableIndex>
 
     fn get_next_cluster(&mut self) {
         self.current_cluster = self.fat_idx_iter.next().map(|fat_idx| {
-            let cluster = self.fat_fs.data_cluster(fat_idx.to_data_cluster_idx());
+            // an iterator cannot return errors, and a directory whose clusters cannot be read cannot be converted
+            let cluster = self
+                .fat_fs
+                .data_cluster(fat_idx.to_data_cluster_idx())
+                .unwrap_or_else(|e| panic!("Unable to read a directory: {}", e));
             // SAFETY: safe, since directory data is a sequence of pseudo-dentries
             let dentries = unsafe { cluster.exact_align_to::<FatPseudoDentry>() };
             assert_eq!(dentries.len(), self.fat_fs.dentries_per_cluster());
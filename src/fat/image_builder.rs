@@ -0,0 +1,396 @@
+use std::convert::TryFrom;
+use std::mem::size_of;
+use std::slice;
+
+use num::Integer;
+
+use crate::fat::{BootSector, FatDentry, FatFs};
+use crate::util::FromU32;
+
+const SECTOR_SIZE: usize = 512;
+const FAT_COUNT: usize = 2;
+const MIN_RESERVED_SECTORS: usize = 32;
+const FS_INFO_SECTOR_NO: u16 = 1;
+const BACKUP_BOOT_SECTOR_NO: u16 = 6;
+const ROOT_CLUSTER_NO: u32 = 2;
+const CHAIN_END: u32 = 0x0FFF_FFFF;
+
+const DIR_ATTR: u8 = 0x10;
+const ARCHIVE_ATTR: u8 = 0x20;
+const LFN_ATTR: u8 = 0x0F;
+const LAST_LFN_FLAG: u8 = 0x40;
+const LFN_CHARS_PER_ENTRY: usize = 13;
+/// 2020-01-01, all timestamps use this date at 00:00:00 unless stated otherwise
+const DATE: u16 = (40 << 9) | (1 << 5) | 1;
+/// 12:34:56, the modification time of files and directories other than the dot entries, so it differs from the access
+/// time, which FAT stores without a time of day
+const MOD_TIME: u16 = (12 << 11) | (34 << 5) | (56 / 2);
+/// The creation times of files and directories other than the dot entries are 1.5 seconds after midnight, to cover the
+/// 10 ms resolution that FAT stores them with
+const CREATE_TIME_10_MS: u8 = 150;
+
+/// A file that `FatImageBuilder` creates in the image. `RegularFile`s are filled with zeros.
+pub enum TestFile {
+    Directory { name: String, children: Vec<TestFile> },
+    RegularFile { name: String, size: u32 },
+    RegularFileWithContent { name: String, content: Vec<u8> },
+}
+
+/// Creates FAT32 filesystem images in memory, replacing `mkfs.fat` and a mount in tests and benchmarks. Every file gets
+/// a long file name and a generated 8.3 name.
+pub struct FatImageBuilder {
+    sector_count: usize,
+    sectors_per_cluster: u8,
+    fragmented: bool,
+    root_dot_entries: bool,
+}
+
+/// A FAT32 image with the same alignment as a memory-mapped partition.
+pub struct FatImage(Vec<u64>);
+
+impl FatImage {
+    pub fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.0.as_mut_ptr() as *mut u8
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len() * size_of::<u64>()
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        // SAFETY: Safe because the slice covers exactly the memory of `self.0` and borrows it mutably.
+        unsafe { slice::from_raw_parts_mut(self.as_mut_ptr(), self.len()) }
+    }
+
+    /// Lets `patch` modify the dentry of the file called `name` in the root directory, e.g. to damage it.
+    /// PANICS: Panics if the root directory contains no such file.
+    pub fn patch_root_dentry<F: FnOnce(&mut FatDentry)>(&mut self, name: &str, patch: F) {
+        let mut dentry = FatFs::from_slice(self.as_mut_slice())
+            .unwrap()
+            .root_dir()
+            .find(|file| file.name == name)
+            .expect("The root directory contains no such file")
+            .dentry;
+        let bytes = self.as_mut_slice();
+        let start = bytes
+            .windows(size_of::<FatDentry>())
+            .position(|window| window == dentry_bytes(&dentry))
+            .unwrap();
+        patch(&mut dentry);
+        bytes[start..start + size_of::<FatDentry>()].copy_from_slice(dentry_bytes(&dentry));
+    }
+}
+
+impl FatImageBuilder {
+    /// PANICS: Panics if `size` is not a multiple of `cluster_size` or if `cluster_size` is not a valid FAT32 cluster
+    /// size for 512-byte sectors.
+    pub fn new(size: usize, cluster_size: usize) -> Self {
+        assert_eq!(size % cluster_size, 0);
+        assert_eq!(cluster_size % SECTOR_SIZE, 0);
+        Self {
+            sector_count: size / SECTOR_SIZE,
+            sectors_per_cluster: u8::try_from(cluster_size / SECTOR_SIZE).unwrap(),
+            fragmented: false,
+            root_dot_entries: false,
+        }
+    }
+
+    /// Allocates only every other free cluster for a file, so that files are fragmented and interleave each other.
+    pub fn fragmented(mut self) -> Self {
+        self.fragmented = true;
+        self
+    }
+
+    /// Writes '.' and '..' entries into the root directory, like some formatting tools do even though the root
+    /// directory must not have them.
+    pub fn root_dot_entries(mut self) -> Self {
+        self.root_dot_entries = true;
+        self
+    }
+
+    /// PANICS: Panics if the files do not fit into the image.
+    pub fn build(&self, root_children: &[TestFile]) -> FatImage {
+        let mut writer = ImageWriter::new(self.sector_count, self.sectors_per_cluster, self.fragmented);
+        let root_chain = writer.allocate(ImageWriter::directory_cluster_count(
+            root_children,
+            self.root_dot_entries,
+            writer.cluster_size,
+        ));
+        assert_eq!(root_chain[0], ROOT_CLUSTER_NO);
+        if self.root_dot_entries {
+            writer.write_directory(root_children, &root_chain, Some(0));
+        } else {
+            writer.write_directory(root_children, &root_chain, None);
+        }
+        writer.finish()
+    }
+}
+
+struct ImageWriter {
+    bytes: Vec<u8>,
+    /// the FAT table, including the two reserved entries
+    fat: Vec<u32>,
+    reserved_sectors: usize,
+    sectors_per_fat: usize,
+    sectors_per_cluster: u8,
+    cluster_size: usize,
+    fragmented: bool,
+    next_free_fat_idx: usize,
+    short_name_counter: u32,
+}
+
+impl ImageWriter {
+    fn new(sector_count: usize, sectors_per_cluster: u8, fragmented: bool) -> Self {
+        let spc = usize::from(sectors_per_cluster);
+        let sectors_per_fat = ((sector_count / spc + 2) * size_of::<u32>()).div_ceil(&SECTOR_SIZE);
+        // the data region has to be cluster-aligned
+        let mut reserved_sectors = MIN_RESERVED_SECTORS;
+        while (reserved_sectors + FAT_COUNT * sectors_per_fat) % spc != 0 {
+            reserved_sectors += 1;
+        }
+        let first_data_sector = reserved_sectors + FAT_COUNT * sectors_per_fat;
+        let data_cluster_count = (sector_count - first_data_sector) / spc;
+
+        let mut fat = vec![0; data_cluster_count + 2];
+        fat[0] = 0x0FFF_FFF8;
+        fat[1] = CHAIN_END;
+        Self {
+            bytes: vec![0; sector_count * SECTOR_SIZE],
+            fat,
+            reserved_sectors,
+            sectors_per_fat,
+            sectors_per_cluster,
+            cluster_size: spc * SECTOR_SIZE,
+            fragmented,
+            next_free_fat_idx: 2,
+            short_name_counter: 0,
+        }
+    }
+
+    /// Returns the FAT indices of a newly allocated cluster chain.
+    fn allocate(&mut self, cluster_count: usize) -> Vec<u32> {
+        let step = if self.fragmented { 2 } else { 1 };
+        let mut chain = Vec::with_capacity(cluster_count);
+        let mut fat_idx = self.next_free_fat_idx;
+        while chain.len() < cluster_count {
+            assert!(fat_idx < self.fat.len(), "The test image is full");
+            if self.fat[fat_idx] == 0 {
+                chain.push(u32::try_from(fat_idx).unwrap());
+                self.fat[fat_idx] = CHAIN_END;
+                fat_idx += step;
+            } else {
+                fat_idx += 1;
+            }
+        }
+        // when fragmenting, the next file starts at the first free cluster again and fills the gaps
+        if !self.fragmented {
+            self.next_free_fat_idx = fat_idx;
+        }
+        for link in chain.windows(2) {
+            self.fat[usize::fromx(link[0])] = link[1];
+        }
+        chain
+    }
+
+    fn directory_cluster_count(children: &[TestFile], has_dot_entries: bool, cluster_size: usize) -> usize {
+        let dot_dir_count = if has_dot_entries { 2 } else { 0 };
+        let dentry_count: usize = children
+            .iter()
+            .map(|child| match child {
+                TestFile::Directory { name, .. }
+                | TestFile::RegularFile { name, .. }
+                | TestFile::RegularFileWithContent { name, .. } => Self::dentry_count(name),
+            })
+            .sum();
+        ((dot_dir_count + dentry_count) * size_of::<FatDentry>())
+            .div_ceil(&cluster_size)
+            .max(1)
+    }
+
+    /// The number of dentries required for a file called `name`, including its LFN entries.
+    fn dentry_count(name: &str) -> usize {
+        name.encode_utf16().count().div_ceil(&LFN_CHARS_PER_ENTRY) + 1
+    }
+
+    /// `parent_cluster_no` is `None` for a directory without dot entries (i.e. the root directory), and `Some(0)` for
+    /// a child of the root directory.
+    fn write_directory(&mut self, children: &[TestFile], chain: &[u32], parent_cluster_no: Option<u32>) {
+        let mut entries = Vec::new();
+        if let Some(parent_cluster_no) = parent_cluster_no {
+            entries.extend(dentry_bytes(&Self::dot_dentry(*b".       ", chain[0])));
+            entries.extend(dentry_bytes(&Self::dot_dentry(*b"..      ", parent_cluster_no)));
+        }
+        // `..` entries refer to the root directory as cluster 0
+        let own_cluster_no = if chain[0] == ROOT_CLUSTER_NO { 0 } else { chain[0] };
+
+        for child in children {
+            match child {
+                TestFile::Directory { name, children } => {
+                    let cluster_count = Self::directory_cluster_count(children, true, self.cluster_size);
+                    let child_chain = self.allocate(cluster_count);
+                    self.write_directory(children, &child_chain, Some(own_cluster_no));
+                    self.append_dentries(&mut entries, name, DIR_ATTR, child_chain[0], 0);
+                }
+                TestFile::RegularFile { name, size } => {
+                    let first_cluster_no = self.write_regular_file(usize::fromx(*size), &[]);
+                    self.append_dentries(&mut entries, name, ARCHIVE_ATTR, first_cluster_no, *size);
+                }
+                TestFile::RegularFileWithContent { name, content } => {
+                    let first_cluster_no = self.write_regular_file(content.len(), content);
+                    let size = u32::try_from(content.len()).unwrap();
+                    self.append_dentries(&mut entries, name, ARCHIVE_ATTR, first_cluster_no, size);
+                }
+            }
+        }
+
+        assert!(entries.len() <= chain.len() * self.cluster_size);
+        for (&cluster_no, chunk) in chain.iter().zip(entries.chunks(self.cluster_size)) {
+            let start = self.cluster_start_byte(cluster_no);
+            self.bytes[start..start + chunk.len()].copy_from_slice(chunk);
+        }
+    }
+
+    /// Allocates clusters for a file of `size` bytes starting with `content` and returns its first cluster number.
+    fn write_regular_file(&mut self, size: usize, content: &[u8]) -> u32 {
+        let cluster_count = size.div_ceil(&self.cluster_size);
+        if cluster_count == 0 {
+            return 0;
+        }
+        let chain = self.allocate(cluster_count);
+        for (&cluster_no, chunk) in chain.iter().zip(content.chunks(self.cluster_size)) {
+            let start = self.cluster_start_byte(cluster_no);
+            self.bytes[start..start + chunk.len()].copy_from_slice(chunk);
+        }
+        chain[0]
+    }
+
+    fn append_dentries(&mut self, entries: &mut Vec<u8>, name: &str, attrs: u8, first_cluster_no: u32, size: u32) {
+        self.short_name_counter += 1;
+        let mut short_name = [0; 8];
+        short_name.copy_from_slice(format!("F{:07}", self.short_name_counter).as_bytes());
+        let checksum = lfn_checksum(&short_name, b"   ");
+
+        let mut name: Vec<u16> = name.encode_utf16().collect();
+        let lfn_entry_count = name.len().div_ceil(&LFN_CHARS_PER_ENTRY);
+        if name.len() % LFN_CHARS_PER_ENTRY != 0 {
+            name.push(0x0000);
+        }
+        name.resize(lfn_entry_count * LFN_CHARS_PER_ENTRY, 0xFFFF);
+
+        // LFN entries are stored in reverse order
+        for (idx, part) in name.chunks(LFN_CHARS_PER_ENTRY).enumerate().rev() {
+            let mut sequence_no = u8::try_from(idx + 1).unwrap();
+            if idx + 1 == lfn_entry_count {
+                sequence_no |= LAST_LFN_FLAG;
+            }
+            entries.push(sequence_no);
+            entries.extend(part[0..5].iter().flat_map(|character| character.to_le_bytes()));
+            entries.extend([LFN_ATTR, 0, checksum]);
+            entries.extend(part[5..11].iter().flat_map(|character| character.to_le_bytes()));
+            entries.extend([0, 0]);
+            entries.extend(part[11..13].iter().flat_map(|character| character.to_le_bytes()));
+        }
+
+        let dentry = FatDentry {
+            short_name,
+            attrs,
+            file_size: size,
+            create_time_10_ms: CREATE_TIME_10_MS,
+            mod_time: MOD_TIME,
+            ..Self::dot_dentry(short_name, first_cluster_no)
+        };
+        entries.extend(dentry_bytes(&dentry));
+    }
+
+    fn dot_dentry(short_name: [u8; 8], cluster_no: u32) -> FatDentry {
+        FatDentry {
+            short_name,
+            short_extension: *b"   ",
+            attrs: DIR_ATTR,
+            create_date: DATE,
+            access_date: DATE,
+            mod_date: DATE,
+            first_fat_index_hi: u16::try_from(cluster_no >> 16).unwrap(),
+            first_fat_index_lo: u16::try_from(cluster_no & 0xFFFF).unwrap(),
+            ..FatDentry::default()
+        }
+    }
+
+    fn cluster_start_byte(&self, cluster_no: u32) -> usize {
+        let first_data_byte = (self.reserved_sectors + FAT_COUNT * self.sectors_per_fat) * SECTOR_SIZE;
+        first_data_byte + (usize::fromx(cluster_no) - 2) * self.cluster_size
+    }
+
+    fn finish(mut self) -> FatImage {
+        let boot_sector = BootSector {
+            jump_instruction: [0xEB, 0x58, 0x90],
+            oem_name: *b"MSWIN4.1",
+            bytes_per_sector: u16::try_from(SECTOR_SIZE).unwrap(),
+            sectors_per_cluster: self.sectors_per_cluster,
+            sectors_before_fat: u16::try_from(self.reserved_sectors).unwrap(),
+            fat_count: u8::try_from(FAT_COUNT).unwrap(),
+            dir_entries: 0,
+            sector_count_1: 0,
+            media_descriptor: 0xF8,
+            sectors_per_fat_16: 0,
+            sectors_per_disk_track: 32,
+            disk_heads: 64,
+            hidden_sectors_before_partition: 0,
+            sector_count_2: u32::try_from(self.bytes.len() / SECTOR_SIZE).unwrap(),
+            sectors_per_fat: u32::try_from(self.sectors_per_fat).unwrap(),
+            drive_description_flags: 0,
+            version: 0,
+            root_cluster_no: ROOT_CLUSTER_NO,
+            fs_info_sector_no: FS_INFO_SECTOR_NO,
+            backup_boot_sector_no: BACKUP_BOOT_SECTOR_NO,
+            reserved: [0; 12],
+            physical_drive_no: 0x80,
+            reserved2: 0,
+            ext_boot_signature: 0x29,
+            volume_id: 0x1234_5678,
+            volume_label: *b"TESTVOLUME ",
+            fs_type: *b"FAT32   ",
+        };
+        // SAFETY: Safe because `BootSector` is packed and consists only of integers.
+        let boot_sector_bytes =
+            unsafe { slice::from_raw_parts(&boot_sector as *const BootSector as *const u8, size_of::<BootSector>()) };
+        for sector_no in [0, usize::from(BACKUP_BOOT_SECTOR_NO)] {
+            let start = sector_no * SECTOR_SIZE;
+            self.bytes[start..start + boot_sector_bytes.len()].copy_from_slice(boot_sector_bytes);
+            self.bytes[start + 510..start + 512].copy_from_slice(&[0x55, 0xAA]);
+        }
+        for sector_no in [FS_INFO_SECTOR_NO, BACKUP_BOOT_SECTOR_NO + FS_INFO_SECTOR_NO] {
+            let start = usize::from(sector_no) * SECTOR_SIZE;
+            self.bytes[start..start + 4].copy_from_slice(b"RRaA");
+            self.bytes[start + 484..start + 488].copy_from_slice(b"rrAa");
+            // the free cluster count and the next free cluster are unknown
+            self.bytes[start + 488..start + 496].fill(0xFF);
+            self.bytes[start + 508..start + 512].copy_from_slice(&[0x00, 0x00, 0x55, 0xAA]);
+        }
+
+        let fat_bytes: Vec<u8> = self.fat.iter().flat_map(|entry| entry.to_le_bytes()).collect();
+        for fat_no in 0..FAT_COUNT {
+            let start = (self.reserved_sectors + fat_no * self.sectors_per_fat) * SECTOR_SIZE;
+            self.bytes[start..start + fat_bytes.len()].copy_from_slice(&fat_bytes);
+        }
+
+        FatImage(
+            self.bytes
+                .chunks_exact(size_of::<u64>())
+                .map(|chunk| u64::from_ne_bytes(<[u8; 8]>::try_from(chunk).unwrap()))
+                .collect(),
+        )
+    }
+}
+
+fn dentry_bytes(dentry: &FatDentry) -> &[u8] {
+    // SAFETY: Safe because `FatDentry` has no padding and consists only of integers.
+    unsafe { slice::from_raw_parts(dentry as *const FatDentry as *const u8, size_of::<FatDentry>()) }
+}
+
+fn lfn_checksum(short_name: &[u8; 8], short_extension: &[u8; 3]) -> u8 {
+    short_name
+        .iter()
+        .chain(short_extension)
+        .fold(0u8, |sum, &character| sum.rotate_right(1).wrapping_add(character))
+}
@@ -2,16 +2,28 @@
 // mod fs_tree_serializer;
 mod boot_sector;
 mod dentry;
+#[cfg(feature = "std")]
 mod file;
+#[cfg(feature = "std")]
 mod fs;
+mod fs_info;
+#[cfg(feature = "std")]
 mod fs_iter;
+#[cfg(feature = "std")]
+mod image_builder;
 mod table_index;
 
 pub use self::boot_sector::*;
 pub use self::dentry::*;
+#[cfg(feature = "std")]
 pub use self::file::*;
+#[cfg(feature = "std")]
 pub use self::fs::*;
+pub use self::fs_info::*;
+#[cfg(feature = "std")]
 pub use self::fs_iter::*;
+#[cfg(feature = "std")]
+pub use self::image_builder::*;
 pub use self::table_index::*;
 
 /// An index identifying a cluster in the filesystem.
@@ -1,6 +1,6 @@
-use std::convert::TryFrom;
-use std::iter::Step;
-use std::ops::Index;
+use core::convert::TryFrom;
+use core::iter::Step;
+use core::ops::Index;
 
 use crate::fat::{BootSector, ClusterIdx};
 use crate::util::FromU32;
@@ -60,7 +60,7 @@ impl Index<FatTableIndex> for [FatTableIndex] {
 }
 
 impl TryFrom<usize> for FatTableIndex {
-    type Error = std::num::TryFromIntError;
+    type Error = core::num::TryFromIntError;
     fn try_from(idx: usize) -> Result<Self, Self::Error> {
         Ok(Self(u32::try_from(idx)?))
     }
@@ -0,0 +1,218 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::fat::BootSector;
+use crate::util::FromUsize;
+
+/// The round constants of SHA-256, the first 32 bits of the fractional parts of the cube roots of the first 64 primes
+const K: [u32; 64] = [
+    0x428A_2F98,
+    0x7137_4491,
+    0xB5C0_FBCF,
+    0xE9B5_DBA5,
+    0x3956_C25B,
+    0x59F1_11F1,
+    0x923F_82A4,
+    0xAB1C_5ED5,
+    0xD807_AA98,
+    0x1283_5B01,
+    0x2431_85BE,
+    0x550C_7DC3,
+    0x72BE_5D74,
+    0x80DE_B1FE,
+    0x9BDC_06A7,
+    0xC19B_F174,
+    0xE49B_69C1,
+    0xEFBE_4786,
+    0x0FC1_9DC6,
+    0x240C_A1CC,
+    0x2DE9_2C6F,
+    0x4A74_84AA,
+    0x5CB0_A9DC,
+    0x76F9_88DA,
+    0x983E_5152,
+    0xA831_C66D,
+    0xB003_27C8,
+    0xBF59_7FC7,
+    0xC6E0_0BF3,
+    0xD5A7_9147,
+    0x06CA_6351,
+    0x1429_2967,
+    0x27B7_0A85,
+    0x2E1B_2138,
+    0x4D2C_6DFC,
+    0x5338_0D13,
+    0x650A_7354,
+    0x766A_0ABB,
+    0x81C2_C92E,
+    0x9272_2C85,
+    0xA2BF_E8A1,
+    0xA81A_664B,
+    0xC24B_8B70,
+    0xC76C_51A3,
+    0xD192_E819,
+    0xD699_0624,
+    0xF40E_3585,
+    0x106A_A070,
+    0x19A4_C116,
+    0x1E37_6C08,
+    0x2748_774C,
+    0x34B0_BCB5,
+    0x391C_0CB3,
+    0x4ED8_AA4A,
+    0x5B9C_CA4F,
+    0x682E_6FF3,
+    0x748F_82EE,
+    0x78A5_636F,
+    0x84C8_7814,
+    0x8CC7_0208,
+    0x90BE_FFFA,
+    0xA450_6CEB,
+    0xBEF9_A3F7,
+    0xC671_78F2,
+];
+/// The initial state of SHA-256, the first 32 bits of the fractional parts of the square roots of the first 8 primes
+const INITIAL_STATE: [u32; 8] = [
+    0x6A09_E667,
+    0xBB67_AE85,
+    0x3C6E_F372,
+    0xA54F_F53A,
+    0x510E_527F,
+    0x9B05_688C,
+    0x1F83_D9AB,
+    0x5BE0_CD19,
+];
+const BLOCK_LEN: usize = 64;
+
+/// The SHA-256 checksums of the FAT32 metadata as it was before the conversion, for `--checksum-fat-metadata`. The
+/// conversion overwrites the boot sector and the FATs with ext4 metadata, so the checksums are the only record that
+/// an audit can compare a backup of the original partition against.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FatMetadataChecksums {
+    /// the checksum of the first sector, which contains the boot sector
+    pub boot_sector: String,
+    /// the checksums of the copies of the FAT, in the order they are stored in
+    pub fat_tables: Vec<String>,
+}
+
+impl FatMetadataChecksums {
+    /// Hashes the boot sector and the FATs of the FAT32 filesystem at the start of `partition`. Fails if the boot
+    /// sector is invalid or its FATs do not fit into `partition`.
+    pub fn compute(partition: &[u8]) -> Result<Self> {
+        let boot_sector = BootSector::from_bytes(partition)?;
+        boot_sector.check_geometry(partition.len())?;
+        let boot_sector_len = usize::from(boot_sector.bytes_per_sector);
+        let fat_table_range = boot_sector.get_fat_table_range();
+        let fat_tables = (0..usize::from(boot_sector.fat_count))
+            .map(|fat_idx| {
+                let start = fat_table_range.start + fat_idx * fat_table_range.len();
+                to_hex(&sha256(&partition[start..start + fat_table_range.len()]))
+            })
+            .collect();
+        Ok(Self {
+            boot_sector: to_hex(&sha256(&partition[..boot_sector_len])),
+            fat_tables,
+        })
+    }
+
+    pub fn print(&self) {
+        println!("SHA-256 of the original boot sector: {}", self.boot_sector);
+        for (fat_idx, checksum) in self.fat_tables.iter().enumerate() {
+            println!("SHA-256 of the original FAT {}: {}", fat_idx + 1, checksum);
+        }
+    }
+}
+
+/// Computes the SHA-256 digest of `bytes` as specified in FIPS 180-4.
+fn sha256(bytes: &[u8]) -> [u8; 32] {
+    let mut state = INITIAL_STATE;
+    let mut blocks = bytes.chunks_exact(BLOCK_LEN);
+    for block in &mut blocks {
+        compress(&mut state, block);
+    }
+
+    // the message is padded with a 1 bit, zeros, and its length in bits, which may spill over into a second block
+    let rest = blocks.remainder();
+    let mut padding = [0; 2 * BLOCK_LEN];
+    padding[..rest.len()].copy_from_slice(rest);
+    padding[rest.len()] = 0x80;
+    let padding_len = if rest.len() < BLOCK_LEN - 8 {
+        BLOCK_LEN
+    } else {
+        2 * BLOCK_LEN
+    };
+    padding[padding_len - 8..padding_len].copy_from_slice(&(u64::fromx(bytes.len()) * 8).to_be_bytes());
+    for block in padding[..padding_len].chunks_exact(BLOCK_LEN) {
+        compress(&mut state, block);
+    }
+
+    let mut digest = [0; 32];
+    for (digest_chunk, word) in digest.chunks_exact_mut(4).zip(state) {
+        digest_chunk.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+fn compress(state: &mut [u32; 8], block: &[u8]) {
+    let mut schedule = [0; 64];
+    for (word, word_bytes) in schedule.iter_mut().zip(block.chunks_exact(4)) {
+        *word = u32::from_be_bytes(word_bytes.try_into().expect("the chunk has 4 bytes"));
+    }
+    for idx in 16..schedule.len() {
+        let s0 = schedule[idx - 15].rotate_right(7) ^ schedule[idx - 15].rotate_right(18) ^ (schedule[idx - 15] >> 3);
+        let s1 = schedule[idx - 2].rotate_right(17) ^ schedule[idx - 2].rotate_right(19) ^ (schedule[idx - 2] >> 10);
+        schedule[idx] = schedule[idx - 16]
+            .wrapping_add(s0)
+            .wrapping_add(schedule[idx - 7])
+            .wrapping_add(s1);
+    }
+
+    let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = *state;
+    for (k, word) in K.iter().zip(schedule) {
+        let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+        let choice = (e & f) ^ (!e & g);
+        let temp1 = h.wrapping_add(s1).wrapping_add(choice).wrapping_add(*k).wrapping_add(word);
+        let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+        let majority = (a & b) ^ (a & c) ^ (b & c);
+        let temp2 = s0.wrapping_add(majority);
+        h = g;
+        g = f;
+        f = e;
+        e = d.wrapping_add(temp1);
+        d = c;
+        c = b;
+        b = a;
+        a = temp1.wrapping_add(temp2);
+    }
+    for (word, value) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+        *word = word.wrapping_add(value);
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sha256_matches_the_test_vectors() {
+        let digest = |bytes: &[u8]| to_hex(&sha256(bytes));
+        assert_eq!(digest(b""), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(
+            digest(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        // 56 bytes, so the length spills over into a second padding block
+        assert_eq!(
+            digest(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+        assert_eq!(
+            digest(&[b'a'; 1000]),
+            "41edece42d63e8d9bf515a9ba6932e1c20cbc9f5a5d134645adb5db1b9737ea3"
+        );
+    }
+}
@@ -0,0 +1,123 @@
+use std::process::{Command, Output};
+
+use anyhow::{bail, Context, Result};
+
+/// The issues that fsck.fat reports although the conversion does not depend on them, by a part of the line that
+/// reports them and a description for the user
+const BENIGN_ISSUES: [(&str, &str); 4] = [
+    // the filesystem was not unmounted cleanly, but the check found no damage besides the flag itself
+    ("Dirty bit is set", "the dirty bit is set"),
+    // the conversion only reports the count cached in the FsInfo sector, see `SpaceEstimate`
+    ("Free cluster summary", "the free cluster count in the FsInfo sector is wrong"),
+    // the conversion reads the primary boot sector and erases the backup
+    (
+        "There are differences between boot sector and its backup",
+        "the backup boot sector differs",
+    ),
+    ("This is mostly harmless", "the backup boot sector differs"),
+];
+
+/// What `fsck.fat -n` found in a FAT32 filesystem
+#[derive(Debug, PartialEq)]
+pub enum FsckOutcome {
+    Clean,
+    /// only issues from `BENIGN_ISSUES`, by their descriptions
+    BenignOnly(Vec<&'static str>),
+    Errors,
+}
+
+/// Checks the FAT32 filesystem at `partition_path` with `fsck.fat -n`, which does not modify it, and passes its output
+/// on to the user. Fails if fsck.fat cannot be run (e.g. if the command `fsck.fat` is not found).
+pub fn check(partition_path: &str) -> Result<FsckOutcome> {
+    let output = run(Command::new("fsck.fat").arg("-n").arg(partition_path))?;
+    Ok(classify(output.status.code(), &String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Repairs the FAT32 filesystem at `partition_path` with `fsck.fat -a` for `--fsck-auto-fix`. fsck.fat exits with 1
+/// both if it fixed every error and if some remain, so `check` has to find out afterwards.
+pub fn auto_fix(partition_path: &str) -> Result<()> {
+    let output = run(Command::new("fsck.fat").arg("-a").arg(partition_path))?;
+    match output.status.code() {
+        Some(0 | 1) => Ok(()),
+        _ => bail!("fsck.fat -a failed ({})", output.status),
+    }
+}
+
+fn run(command: &mut Command) -> Result<Output> {
+    let output = command.output().context("Unable to run fsck.fat")?;
+    print!("{}", String::from_utf8_lossy(&output.stdout));
+    eprint!("{}", String::from_utf8_lossy(&output.stderr));
+    Ok(output)
+}
+
+/// Classifies the issues that fsck.fat listed in `stdout` before exiting with `exit_code`. Every line that is neither
+/// part of the frame around the issues nor indented like the details of an issue reports an issue. An unknown exit
+/// code or issue counts as an error.
+fn classify(exit_code: Option<i32>, stdout: &str) -> FsckOutcome {
+    match exit_code {
+        Some(0) => return FsckOutcome::Clean,
+        Some(1) => (),
+        _ => return FsckOutcome::Errors,
+    }
+    let mut benign_issues = Vec::new();
+    for line in stdout.lines() {
+        if line.trim().is_empty() || line.starts_with(char::is_whitespace) || is_frame(line) {
+            continue;
+        }
+        match BENIGN_ISSUES.iter().find(|(pattern, _)| line.contains(pattern)) {
+            Some((_, description)) if benign_issues.contains(description) => (),
+            Some((_, description)) => benign_issues.push(*description),
+            None => return FsckOutcome::Errors,
+        }
+    }
+    if benign_issues.is_empty() {
+        FsckOutcome::Errors
+    } else {
+        FsckOutcome::BenignOnly(benign_issues)
+    }
+}
+
+/// True for the lines that fsck.fat prints whether or not it finds issues: its version, the note that nothing was
+/// changed, and the summary like "/dev/sda1: 3 files, 5/8167 clusters".
+fn is_frame(line: &str) -> bool {
+    line.starts_with("fsck.fat ")
+        || line.starts_with("dosfsck ")
+        || line == "Leaving filesystem unchanged."
+        || (line.contains(" files, ") && line.ends_with(" clusters"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_benign_issues_are_tolerated() {
+        let dirty = "fsck.fat 4.2 (2021-01-31)\n0x41: Dirty bit is set. Fs was not properly unmounted and some data \
+                     may be corrupt.\n Automatically removing dirty bit.\nFree cluster summary wrong (8160 vs. really \
+                     8158)\n  Auto-correcting.\nLeaving filesystem unchanged.\n/dev/sda1: 3 files, 9/8167 clusters\n";
+        assert_eq!(
+            classify(Some(1), dirty),
+            FsckOutcome::BenignOnly(vec![
+                "the dirty bit is set",
+                "the free cluster count in the FsInfo sector is wrong"
+            ])
+        );
+        let backup = "fsck.fat 4.2 (2021-01-31)\nThere are differences between boot sector and its backup.\nThis is \
+                      mostly harmless. Differences: (offset:original/backup)\n  71:4e/45\n  Not automatically fixing \
+                      this.\n/dev/sda1: 3 files, 9/8167 clusters\n";
+        assert_eq!(
+            classify(Some(1), backup),
+            FsckOutcome::BenignOnly(vec!["the backup boot sector differs"])
+        );
+
+        let damaged = "fsck.fat 4.2 (2021-01-31)\n0x41: Dirty bit is set. Fs was not properly unmounted and some data \
+                       may be corrupt.\n Automatically removing dirty bit.\n/file\n  File size is 5000 bytes, cluster \
+                       chain length is 4096 bytes.\n  Truncating file to 4096 bytes.\nLeaving filesystem \
+                       unchanged.\n/dev/sda1: 3 files, 9/8167 clusters\n";
+        assert_eq!(classify(Some(1), damaged), FsckOutcome::Errors);
+        assert_eq!(classify(Some(1), "fsck.fat 4.2 (2021-01-31)\n"), FsckOutcome::Errors);
+        assert_eq!(classify(Some(2), ""), FsckOutcome::Errors);
+        assert_eq!(classify(None, dirty), FsckOutcome::Errors);
+        assert_eq!(classify(Some(0), ""), FsckOutcome::Clean);
+    }
+}
@@ -0,0 +1,201 @@
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+
+/// How often and how long to wait for an NBD device to report its size after `qemu-nbd` connected it
+const NBD_READY_ATTEMPTS: u32 = 50;
+const NBD_READY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The format of a disk image file. Every format except `Raw` is accessed through `qemu-nbd`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ImageFormat {
+    Raw,
+    Qcow2,
+    Vhdx,
+    /// VHD, which qemu calls VPC after Virtual PC
+    Vpc,
+    Vmdk,
+}
+
+impl ImageFormat {
+    /// Detects the format of the image at `path` by its magic number. Anything that is not a regular file (e.g. a
+    /// block device) is treated as raw.
+    pub fn detect<P: AsRef<Path>>(path: P) -> Result<Self> {
+        if !fs::metadata(&path)?.is_file() {
+            return Ok(Self::Raw);
+        }
+        let mut file = File::open(&path)?;
+        let mut header = [0; 8];
+        if file.read_exact(&mut header).is_err() {
+            return Ok(Self::Raw);
+        }
+
+        let format = match &header {
+            [b'Q', b'F', b'I', 0xfb, ..] => Self::Qcow2,
+            b"vhdxfile" => Self::Vhdx,
+            // dynamic VHDs start with a copy of the footer
+            b"conectix" => Self::Vpc,
+            [b'K', b'D', b'M', b'V', ..] => Self::Vmdk,
+            _ if Self::has_vhd_footer(&mut file)? => Self::Vpc,
+            _ => Self::Raw,
+        };
+        Ok(format)
+    }
+
+    /// The name of the format in `qemu-nbd --format`
+    pub fn qemu_name(self) -> &'static str {
+        match self {
+            Self::Raw => "raw",
+            Self::Qcow2 => "qcow2",
+            Self::Vhdx => "vhdx",
+            Self::Vpc => "vpc",
+            Self::Vmdk => "vmdk",
+        }
+    }
+
+    /// Fixed VHDs are raw images followed by a 512 byte footer.
+    fn has_vhd_footer(file: &mut File) -> Result<bool> {
+        const FOOTER_LEN: u64 = 512;
+        if file.metadata()?.len() < FOOTER_LEN {
+            return Ok(false);
+        }
+        let mut cookie = [0; 8];
+        file.seek(SeekFrom::End(-(FOOTER_LEN as i64)))?;
+        file.read_exact(&mut cookie)?;
+        Ok(&cookie == b"conectix")
+    }
+}
+
+/// An image file exported as a block device by `qemu-nbd`. The device is disconnected on drop, which flushes the
+/// writes to the image file.
+pub struct NbdDevice {
+    path: String,
+}
+
+impl NbdDevice {
+    /// Exports the image at `image_path` on the first unused NBD device. Requires `qemu-nbd` and the `nbd` kernel
+    /// module.
+    pub fn connect<P: AsRef<Path>>(image_path: P, format: ImageFormat) -> Result<Self> {
+        let device_name = find_unused_nbd_device()?;
+        let path = format!("/dev/{}", device_name);
+        Command::new("qemu-nbd")
+            .arg("--connect")
+            .arg(&path)
+            .arg("--format")
+            .arg(format.qemu_name())
+            .arg(image_path.as_ref())
+            .status()
+            .context("Unable to run qemu-nbd")?
+            .exit_ok()
+            .with_context(|| format!("qemu-nbd was unable to connect the image to {}", path))?;
+        let device = Self { path };
+
+        // the device's size is only set once the NBD handshake has completed
+        let size_path = sys_block_path(&device_name).join("size");
+        for _ in 0..NBD_READY_ATTEMPTS {
+            let size = fs::read_to_string(&size_path).unwrap_or_default();
+            if matches!(size.trim().parse::<u64>(), Ok(size) if size > 0) {
+                return Ok(device);
+            }
+            thread::sleep(NBD_READY_INTERVAL);
+        }
+        bail!("{} did not become ready", device.path)
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+impl Drop for NbdDevice {
+    fn drop(&mut self) {
+        let result = Command::new("qemu-nbd").arg("--disconnect").arg(&self.path).status();
+        if !matches!(result, Ok(status) if status.success()) {
+            eprintln!(
+                "Warning: Unable to disconnect {}. Run 'qemu-nbd --disconnect {}' before using the image.",
+                self.path, self.path
+            );
+        }
+    }
+}
+
+/// If the file at `path` is an image in a format other than raw, exports it with `qemu-nbd` and returns the NBD device.
+pub fn connect_if_not_raw(path: &str) -> Result<Option<NbdDevice>> {
+    let format = ImageFormat::detect(path).with_context(|| format!("Unable to detect the image format of {}", path))?;
+    if format == ImageFormat::Raw {
+        return Ok(None);
+    }
+    let device = NbdDevice::connect(path, format)?;
+    eprintln!("Converting the {} image {} through {}", format.qemu_name(), path, device.path());
+    Ok(Some(device))
+}
+
+/// Returns the name (e.g. "nbd0") of the NBD device with the lowest number that no process is using.
+fn find_unused_nbd_device() -> Result<String> {
+    if !Path::new("/sys/module/nbd").exists() {
+        bail!("The nbd kernel module is not loaded. Load it with 'modprobe nbd' and try again.");
+    }
+    let mut device_numbers: Vec<u32> = fs::read_dir("/sys/block")?
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.strip_prefix("nbd")?.parse().ok())
+        .collect();
+    device_numbers.sort_unstable();
+    device_numbers
+        .into_iter()
+        .map(|number| format!("nbd{}", number))
+        // a connected NBD device has the pid of the process serving it
+        .find(|name| !sys_block_path(name).join("pid").exists())
+        .context("All NBD devices are in use")
+}
+
+fn sys_block_path(device_name: &str) -> PathBuf {
+    Path::new("/sys/block").join(device_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use tempfile::NamedTempFile;
+
+    use super::*;
+
+    #[test]
+    fn detects_header_magic() {
+        let headers: [(&[u8], ImageFormat); 4] = [
+            (b"QFI\xfb\0\0\0\x03", ImageFormat::Qcow2),
+            (b"vhdxfile", ImageFormat::Vhdx),
+            (b"conectix", ImageFormat::Vpc),
+            (b"KDMV\x01\0\0\0", ImageFormat::Vmdk),
+        ];
+        for (header, format) in headers {
+            let image = image_file(&[header, &[0; 1024]].concat());
+            assert_eq!(ImageFormat::detect(image.path()).unwrap(), format);
+        }
+    }
+
+    #[test]
+    fn detects_fixed_vhd() {
+        let mut data = vec![0; 4096 + 512];
+        data[4096..4096 + 8].copy_from_slice(b"conectix");
+        let image = image_file(&data);
+        assert_eq!(ImageFormat::detect(image.path()).unwrap(), ImageFormat::Vpc);
+    }
+
+    #[test]
+    fn detects_raw() {
+        let image = image_file(&fs::read("test/example_fat.img").unwrap());
+        assert_eq!(ImageFormat::detect(image.path()).unwrap(), ImageFormat::Raw);
+        assert_eq!(ImageFormat::detect(image_file(b"tiny").path()).unwrap(), ImageFormat::Raw);
+    }
+
+    fn image_file(data: &[u8]) -> NamedTempFile {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(data).unwrap();
+        file
+    }
+}
@@ -0,0 +1,39 @@
+use anyhow::{bail, Result};
+use clap::ArgEnum;
+use nix::errno::Errno;
+use nix::libc;
+
+// from include/uapi/linux/ioprio.h, which the libc crate does not define
+const IOPRIO_CLASS_SHIFT: i32 = 13;
+const IOPRIO_CLASS_BE: i32 = 2;
+const IOPRIO_CLASS_IDLE: i32 = 3;
+const IOPRIO_WHO_PROCESS: i32 = 1;
+/// the lowest priority level of the best-effort class
+const IOPRIO_BE_LOWEST_LEVEL: i32 = 7;
+
+/// The IO priority of the conversion, like `ionice` sets it. The IO scheduler only honors it for the IO the process
+/// submits itself, i.e. the reads of the partition and, with --direct-io, its writes; the changes to a memory-mapped
+/// partition are written back by the kernel.
+#[derive(Clone, Copy, Debug, PartialEq, ArgEnum)]
+pub enum IoPriority {
+    /// only use the storage when no other process does, like `ionice -c 3`
+    Idle,
+    /// the lowest priority that still gets a share of the storage when it is busy, like `ionice -c 2 -n 7`
+    Low,
+}
+
+impl IoPriority {
+    /// Sets the IO priority of the calling thread, which the threads it starts afterwards inherit.
+    pub fn apply(self) -> Result<()> {
+        let ioprio = match self {
+            Self::Idle => IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+            Self::Low => (IOPRIO_CLASS_BE << IOPRIO_CLASS_SHIFT) | IOPRIO_BE_LOWEST_LEVEL,
+        };
+        // SAFETY: Safe because ioprio_set takes integers only.
+        let result = unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio) };
+        if result == -1 {
+            bail!("Unable to set the IO priority: {}", Errno::last());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,97 @@
+//! The converter behind the `ofs-convert-rs` binary, which converts a FAT32 filesystem into an ext4 filesystem in
+//! place. The binary only adds the command line on top.
+//!
+//! Without the default feature `std`, the library only contains the parts that do not depend on `std`, so that
+//! environments without an operating system, e.g. recovery firmware or UEFI tools, can reuse them with their own IO
+//! layer. They only require an allocator. These are the data structures shared by the FAT and ext4 code (`ranges`,
+//! `lohi`, the integer conversions in `util`) and the FAT on-disk structures in `fat`: the boot sector, the FsInfo
+//! sector, the FAT entries and the dentries, which are enough to walk a FAT32 filesystem read with the caller's IO.
+//!
+//! The core does not cover the conversion itself: `FatFs`, the ext4 construction and the `StreamArchiver` require
+//! `std`. `FatFs` and the `StreamArchiver` work on the partition through the concrete `Allocator`, which records its
+//! allocations in the `--trace` file, and the ext4 construction writes the block bitmaps with rayon, draws random UUIDs
+//! and generations from the operating system and reads the clock and the user's IDs for the inodes. Moving them into
+//! the core would mean making them generic over `ClusterAllocator` and putting rayon, uuid, the clock and the trace
+//! behind `std`, which has not been done.
+#![cfg_attr(not(feature = "std"), no_std)]
+#![feature(step_trait)]
+#![cfg_attr(
+    feature = "std",
+    feature(
+        iter_advance_by,
+        maybe_uninit_extra,
+        maybe_uninit_slice,
+        maybe_uninit_write_slice,
+        exit_status_error
+    )
+)]
+#![deny(unsafe_op_in_unsafe_fn)]
+// the unsafe functions document their requirements in a "SAFETY:" line like the rest of the code, and the partitions,
+// images and bitmaps have a fixed length that is never checked for zero
+#![allow(clippy::missing_safety_doc, clippy::len_without_is_empty)]
+
+extern crate alloc;
+
+pub mod fat;
+pub mod lohi;
+pub mod ranges;
+pub mod util;
+
+#[cfg(feature = "std")]
+pub mod allocator;
+#[cfg(feature = "std")]
+pub mod bitmap;
+#[cfg(feature = "std")]
+pub mod checkpoint;
+#[cfg(feature = "std")]
+pub mod conflicts;
+#[cfg(feature = "std")]
+pub mod conversion;
+#[cfg(feature = "std")]
+pub mod crtime;
+#[cfg(feature = "std")]
+pub mod diff_meta;
+#[cfg(feature = "std")]
+pub mod error;
+#[cfg(feature = "std")]
+pub mod estimate;
+#[cfg(feature = "std")]
+pub mod export_meta;
+#[cfg(feature = "std")]
+pub mod ext4;
+#[cfg(feature = "std")]
+pub mod fat_checksums;
+#[cfg(feature = "std")]
+pub mod fsck;
+#[cfg(all(feature = "std", feature = "image-formats"))]
+pub mod image;
+#[cfg(feature = "std")]
+pub mod io_priority;
+#[cfg(feature = "std")]
+pub mod messages;
+#[cfg(feature = "std")]
+pub mod options;
+#[cfg(feature = "std")]
+pub mod owner;
+#[cfg(feature = "std")]
+pub mod partition;
+#[cfg(feature = "std")]
+pub mod plan;
+#[cfg(feature = "std")]
+pub mod profile;
+#[cfg(feature = "std")]
+pub mod progress;
+#[cfg(feature = "std")]
+pub mod serialization;
+#[cfg(feature = "std")]
+pub mod skeleton;
+#[cfg(all(feature = "std", feature = "sparse-images"))]
+pub mod sparse;
+#[cfg(feature = "std")]
+pub mod spot_check;
+#[cfg(feature = "std")]
+pub mod trace;
+#[cfg(feature = "std")]
+pub mod tune;
+#[cfg(feature = "std")]
+pub mod unsupported;
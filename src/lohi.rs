@@ -1,8 +1,8 @@
-use std::convert::TryFrom;
-use std::fmt::Debug;
-use std::marker::PhantomData;
-use std::mem::size_of;
-use std::ops::{AddAssign, SubAssign};
+use core::convert::TryFrom;
+use core::fmt::Debug;
+use core::marker::PhantomData;
+use core::mem::size_of;
+use core::ops::{AddAssign, SubAssign};
 
 use num::PrimInt;
 
@@ -31,7 +31,7 @@ where
     LoHalf::Error: Debug,
     HiHalf::Error: Debug,
 {
-    const LO_HALF_BIT_COUNT: usize = std::mem::size_of::<LoHalf>() * 8;
+    const LO_HALF_BIT_COUNT: usize = size_of::<LoHalf>() * 8;
 
     // ideally would be const, but `zero` is not a const fn
     fn lo_half_mask() -> Full {
@@ -1,39 +1,41 @@
-#![feature(step_trait)]
-#![feature(iter_advance_by)]
-#![feature(maybe_uninit_extra)]
-#![feature(maybe_uninit_slice)]
-#![feature(maybe_uninit_write_slice)]
-#![feature(exit_status_error)]
 #![deny(unsafe_op_in_unsafe_fn)]
 
-mod allocator;
-mod bitmap;
-mod ext4;
-mod fat;
-mod lohi;
-mod partition;
-mod ranges;
-mod serialization;
-mod util;
-
-use std::convert::TryFrom;
-use std::io::{self, Write};
-use std::mem::size_of;
-use std::process::Command;
+mod batch;
+mod cli;
 
-use anyhow::{bail, Context, Result};
-use clap::{App, Arg};
-use static_assertions::const_assert;
-use text_io::try_read;
+use std::fs::File;
+use std::time::Duration;
+use std::{io, process};
 
-use crate::ext4::{BlockIdx, SuperBlock};
-use crate::fat::{ClusterIdx, FatFs};
-use crate::partition::Partition;
-use crate::ranges::Ranges;
-use crate::serialization::FatTreeSerializer;
+use anyhow::{bail, Context, Result};
+use clap::{CommandFactory, Parser};
+use ofs_convert_rs::checkpoint::Checkpoint;
+use ofs_convert_rs::conflicts::TerminalPrompter;
+use ofs_convert_rs::conversion::{
+    build_superblock, continue_path, convert_path, dry_run_path, inspect_boot_sector, lowered_inode_ratio_message,
+    stop_path_after_plan, warn_large_blocks, BootSectorState, ConversionStats,
+};
+use ofs_convert_rs::crtime::CrtimeMapping;
+use ofs_convert_rs::diff_meta::MetadataDump;
+use ofs_convert_rs::error::{exit_code, ErrorCategory, EXIT_FAILURE};
+use ofs_convert_rs::estimate::SpaceEstimate;
+use ofs_convert_rs::export_meta::export_metadata;
+use ofs_convert_rs::fat::{BootSector, FatFs};
+use ofs_convert_rs::messages::{tr, Lang};
+use ofs_convert_rs::options::{ConversionOptions, UuidSource};
+use ofs_convert_rs::owner::parse_owner;
+use ofs_convert_rs::partition::{Partition, ReadOnlyPartition};
+use ofs_convert_rs::plan::ConversionPlan;
+use ofs_convert_rs::serialization::{ArchiveBitFile, FileFilter};
+use ofs_convert_rs::skeleton::Skeleton;
+use ofs_convert_rs::trace::Trace;
+use ofs_convert_rs::tune::{tune, TuneSettings};
+use ofs_convert_rs::unsupported::UnsupportedReport;
 
-const_assert!(size_of::<usize>() >= size_of::<u32>());
-const_assert!(size_of::<usize>() <= size_of::<u64>());
+use crate::cli::{
+    Cli, ConvertArgs, DiffMetaArgs, EstimateArgs, ExecuteArgs, ExportMetadataArgs, InspectFatArgs, RestoreArgs,
+    TraceDumpArgs, TuneArgs,
+};
 
 // TODOs:
 // Features:
@@ -47,105 +49,354 @@ const_assert!(size_of::<usize>() <= size_of::<u64>());
 // - sometimes using Result where Option would be more idiomatic
 // - add context to Errs
 
-fn main() -> Result<()> {
-    let matches =
-        App::new("ofs-convert-rs")
-            .arg(Arg::with_name("PARTITION_PATH").required(true).help(
-                "The partition containing the FAT32 filesystem that should be converted. This will usually be a block \
-                 device (e.g. /dev/sda1), but it can also be a file containing a disk image. The filesystem must be \
-                 unmounted and cannot be modified by another process during the conversion",
-            ))
-            .arg(Arg::with_name("force").long("force").short("f").help(
-                "Skip fsck (can lead to unexpected errors and data loss if the input filesystem is inconsistent)",
-            ))
-            .get_matches();
-
-    let partition_path = matches.value_of("PARTITION_PATH").unwrap();
-    if !matches.is_present("force") {
-        match fsck_fat(partition_path) {
-            Ok(true) => (),
-            Ok(false) => bail!(
-                "fsck failed. Running ofs-convert-rs on an inconsistent FAT32 partition can lead to unexpected errors \
-                 and data loss. To force the conversion, run again with the '-f' flag."
-            ),
-            Err(e) => {
-                eprintln!("Error: {:#}", e);
-                eprintln!(
-                    "Running ofs-convert-rs on an inconsistent FAT32 partition can lead to unexpected errors and data \
-                     loss."
-                );
-                eprint!("Run anyway? [y/N] ");
-                io::stderr().flush()?;
-                let answer: String = try_read!("{}\n")?;
-                if !is_yes(&answer) {
-                    bail!("Aborted by user");
-                }
-            }
-        }
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("{}: {:?}", tr("Error"), e);
+        process::exit(exit_code(&e));
     }
+}
 
-    // SAFETY: We've done our best to ensure the partition at `partition_path` contains a consistent FAT32 filesystem
-    unsafe { ofs_convert(partition_path) }
+fn run() -> Result<()> {
+    let parsed = Cli::try_parse().unwrap_or_else(|e| {
+        // clap exits with 2 on usage errors, which would be mistaken for `ErrorCategory::FsckFailed`
+        if e.use_stderr() {
+            // the process exits anyway, so there is nothing to do if printing fails
+            let _ = e.print();
+            process::exit(EXIT_FAILURE);
+        }
+        e.exit()
+    });
+    parsed.lang.unwrap_or_else(Lang::from_env).set_current();
+    match parsed.into_command() {
+        cli::Command::Convert(args) => run_convert(args),
+        cli::Command::DryRun(args) => run_convert(args.into_dry_run()?),
+        cli::Command::Estimate(args) => run_estimate(args),
+        cli::Command::Execute(args) => run_execute(args),
+        cli::Command::InspectFat(args) => run_inspect_fat(args),
+        cli::Command::Restore(args) => run_restore(args),
+        cli::Command::DiffMeta(args) => run_diff_meta(args),
+        cli::Command::ExportMetadata(args) => run_export_metadata(args),
+        cli::Command::Tune(args) => run_tune(args),
+        cli::Command::TraceDump(args) => run_trace_dump(args),
+        cli::Command::Completions { shell } => {
+            clap_complete::generate(shell, &mut Cli::command(), "ofs-convert-rs", &mut io::stdout());
+            Ok(())
+        }
+    }
 }
 
-/// Returns `Ok(true)` if the filesystem check is successful, `Ok(false)` if it fails, and `Err` if fsck fails to run
-/// (e.g. if the command `fsck.fat` is not found).
-fn fsck_fat(partition_path: &str) -> Result<bool> {
-    Ok(Command::new("fsck.fat")
-        .arg("-n")
-        .arg(partition_path)
-        .status()
-        .context("Unable to run fsck.fat")?
-        .success())
+fn run_convert(args: ConvertArgs) -> Result<()> {
+    if let Some(threads) = args.threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()
+            .context("Unable to start the threads")?;
+    }
+    if let Some(io_priority) = args.io_priority {
+        io_priority.apply()?;
+    }
+    if let Some(checkpoint_path) = args.continue_from {
+        let checkpoint = Checkpoint::load(&checkpoint_path).context(ErrorCategory::Io)?;
+        return convert_and_report(&checkpoint.options, args.verbose, || {
+            continue_path(&checkpoint.partition_path, &checkpoint.options, &checkpoint.state)
+        });
+    }
+    let options = ConversionOptions {
+        filter: FileFilter {
+            max_size: args.exclude_size_over,
+            min_mod_time: args.exclude_older_than,
+        },
+        convert_shortcuts: args.convert_shortcuts,
+        wipe_fat_remnants: args.wipe_fat_remnants,
+        bigalloc: args.ext4.bigalloc,
+        ext4_params: args.ext4.ext4_params(),
+        direct_io: args.direct_io,
+        #[cfg(feature = "sparse-images")]
+        sparse_offset: args.sparse_offset.unwrap_or(0),
+        truncate_long_names: args.truncate_long_names,
+        dentry_order: args.dentry_order.unwrap_or_default(),
+        verify_archival: args.verify_archival,
+        spot_check: args.spot_check,
+        checksum_fat_metadata: args.checksum_fat_metadata,
+        allow_tight_fit: args.allow_tight_fit,
+        min_free_space_after: args.min_free_space_after.unwrap_or(0),
+        bwlimit: args.bwlimit,
+        randomize_generation: args.randomize_generation,
+        drop_atime: args.drop_atime,
+        dedup_hardlink: args.dedup_hardlink,
+        owner: args
+            .owner
+            .map(|owner| parse_owner(&owner, args.numeric_owner))
+            .transpose()
+            .context("Invalid --owner")?,
+        skeleton: args
+            .skeleton
+            .map(|path| Skeleton::load(&path, args.numeric_owner))
+            .transpose()
+            .context("Invalid --skeleton")?
+            .unwrap_or_default(),
+        archive_bit_list: args.archive_bit_list,
+        crtime_list: args.crtime_list,
+        trace: args.trace,
+        json_progress_fd: args.quiet_json_progress,
+        collect_errors: args.collect_errors,
+        invalid_attributes: args.invalid_attributes.unwrap_or_default(),
+        trial_run: args.trial_run,
+        mkfs_time: args.mkfs_time.unwrap_or_default(),
+        uuid: args.uuid.unwrap_or_default(),
+        force: args.force,
+        fsck_auto_fix: args.fsck_auto_fix,
+        force_reconvert: args.force_reconvert,
+        // stdin is taken by the partition paths
+        interactive: !args.stdin_paths,
+    };
+    if args.print_options {
+        options.print();
+        return Ok(());
+    }
+
+    if args.stdin_paths {
+        let stdin = io::stdin();
+        let stdout = io::stdout();
+        return batch::convert_paths(stdin.lock(), stdout.lock(), |partition_path| {
+            let (stats, elapsed) = convert_path(partition_path, &options)?;
+            stats.print_messages();
+            Ok(stats.summary(elapsed))
+        });
+    }
+
+    let partition_path = args.partition_path.expect("clap requires PARTITION_PATH without --stdin-paths");
+    if args.report_unsupported {
+        let report = UnsupportedReport::new(&partition_path, &options)?;
+        report.print(args.verbose);
+        if report.has_failures() {
+            bail!("The conversion would fail with these options");
+        }
+        return Ok(());
+    }
+    if let Some(plan_path) = args.save_plan {
+        let mut plan = ConversionPlan::new(partition_path, options)?;
+        if args.resolve_conflicts && !plan.resolve_conflicts(&mut TerminalPrompter)? {
+            println!("{}", tr("Every file can be converted as it is"));
+        }
+        plan.print();
+        plan.save(&plan_path).context(ErrorCategory::Io)?;
+        println!("Saved the plan to {}", plan_path);
+        return Ok(());
+    }
+    if args.dry_run {
+        let report = dry_run_path(&partition_path, &options)?;
+        report.report.print_messages();
+        report.print();
+        return Ok(());
+    }
+    if let Some(checkpoint_path) = args.stop_after_plan {
+        let state = stop_path_after_plan(&partition_path, &options)?;
+        state.report.print_messages();
+        Checkpoint::new(partition_path, options, state)
+            .save(&checkpoint_path)
+            .context(ErrorCategory::Io)?;
+        println!(
+            "Stopped the conversion before modifying the FAT32 filesystem and saved its state to {}. The filesystem \
+             must not be modified until `ofs-convert-rs --continue {}` finishes the conversion.",
+            checkpoint_path, checkpoint_path
+        );
+        return Ok(());
+    }
+    convert_and_report(&options, args.verbose, || convert_path(&partition_path, &options))
 }
 
-fn is_yes(s: &str) -> bool {
-    ["y", "yes"].contains(&s.trim().to_lowercase().as_str())
+fn run_execute(args: ExecuteArgs) -> Result<()> {
+    let plan = ConversionPlan::load(&args.plan_path).context(ErrorCategory::Io)?;
+    convert_and_report(&plan.options, args.verbose, || plan.execute())
 }
 
-/// SAFETY: `partition_path` must point to a partition containing a consistent FAT32 filesystem.
-unsafe fn ofs_convert(partition_path: &str) -> Result<()> {
-    let mut partition = Partition::open(partition_path)?;
-    // SAFETY: Safe because `partition`'s memory is valid and contains a FAT32 filesystem.
-    let (fat_fs, mut allocator) =
-        unsafe { FatFs::new_with_allocator(partition.as_mut_ptr(), partition.len(), partition.lifetime)? };
+fn run_inspect_fat(args: InspectFatArgs) -> Result<()> {
+    let partition = ReadOnlyPartition::open(&args.partition_path).context(ErrorCategory::Io)?;
+    let partition_bytes = partition.as_slice();
+    let backup_differs = match inspect_boot_sector(partition_bytes)? {
+        BootSectorState::Intact { backup_differs } => backup_differs,
+        BootSectorState::Restorable { error, .. } => {
+            return Err(error.context(ErrorCategory::InvalidFilesystem).context(format!(
+                "The boot sector is damaged, `ofs-convert-rs restore {}` restores it from the intact backup boot \
+                 sector",
+                args.partition_path
+            )));
+        }
+    };
+    // If the partition is mounted and modified meanwhile, the output may be inconsistent, but every access stays
+    // within the partition.
+    let fat_fs = FatFs::from_slice(partition_bytes).context(ErrorCategory::InvalidFilesystem)?;
     let boot_sector = fat_fs.boot_sector();
-    let superblock = SuperBlock::from(boot_sector)?;
+    println!("Volume label: {}", String::from_utf8_lossy(boot_sector.volume_label()));
+    println!("Volume ID: {:08X}", { boot_sector.volume_id });
+    println!("Size: {} bytes", boot_sector.fs_size());
+    println!("FATs: {} of {} sectors each", boot_sector.fat_count, {
+        boot_sector.sectors_per_fat
+    });
+    println!(
+        "Data clusters: {} of {} bytes each, {} of them free",
+        boot_sector.data_cluster_count(),
+        boot_sector.cluster_size(),
+        fat_fs.free_cluster_count()
+    );
+    println!("Files and directories: {}", fat_fs.file_count());
+    if backup_differs {
+        println!("The backup boot sector differs from the boot sector");
+    }
+    Ok(())
+}
 
-    let forbidden_ranges = forbidden_ranges(&superblock, fat_fs.cluster_count());
-    for range in &forbidden_ranges {
-        allocator.forbid(range.clone());
+fn run_restore(args: RestoreArgs) -> Result<()> {
+    let mut partition = Partition::open(&args.partition_path).context(ErrorCategory::Io)?;
+    let partition_bytes = partition.as_mut_slice();
+    match inspect_boot_sector(partition_bytes)? {
+        BootSectorState::Intact { .. } => println!("The boot sector is intact, there is nothing to restore"),
+        BootSectorState::Restorable { error, backup_range } => {
+            eprintln!("The boot sector is damaged: {:#}", error);
+            let backup_start = backup_range.start;
+            partition_bytes.copy_within(backup_range, 0);
+            partition
+                .flush()
+                .context(ErrorCategory::Io)
+                .context("Unable to restore the boot sector")?;
+            println!("Restored the boot sector from the backup boot sector at byte {}", backup_start);
+        }
     }
+    Ok(())
+}
+
+fn run_diff_meta(args: DiffMetaArgs) -> Result<()> {
+    let dump = |path: &str| -> Result<MetadataDump> {
+        let partition = ReadOnlyPartition::open(path).context(ErrorCategory::Io)?;
+        MetadataDump::new(partition.as_slice())
+            .with_context(|| format!("Unable to read the ext4 metadata of {}", path))
+            .context(ErrorCategory::InvalidFilesystem)
+    };
+    let differences = dump(&args.first_path)?.compare(&dump(&args.second_path)?);
+    for difference in &differences {
+        println!("{}", difference);
+    }
+    if !differences.is_empty() {
+        bail!("The ext4 metadata differs in {} places", differences.len());
+    }
+    Ok(())
+}
 
-    let mut serializer = FatTreeSerializer::new(allocator, fat_fs, forbidden_ranges);
-    serializer.serialize_directory_tree().context("Serialization failed")?;
-    // SAFETY: Safe because we have added the relevant blocks into the allocator's forbidden ranges
-    let mut deserializer = unsafe { serializer.into_deserializer().context("A dry run of the conversion failed")? };
+fn run_export_metadata(args: ExportMetadataArgs) -> Result<()> {
+    let partition = ReadOnlyPartition::open(&args.partition_path).context(ErrorCategory::Io)?;
+    let output = File::create(&args.output_path)
+        .with_context(|| format!("Unable to create {}", args.output_path))
+        .context(ErrorCategory::Io)?;
+    let export = export_metadata(partition.as_slice(), &output, args.keep_names)
+        .with_context(|| format!("Unable to export the ext4 metadata of {}", args.partition_path))
+        .context(ErrorCategory::InvalidFilesystem)?;
+    println!(
+        "Wrote {} metadata blocks, including {} directory blocks, to {}",
+        export.block_count, export.directory_block_count, args.output_path
+    );
+    if !args.keep_names {
+        println!("Scrambled {} file names", export.scrambled_name_count);
+    }
+    Ok(())
+}
 
-    deserializer
-        .deserialize_directory_tree()
-        .context("Conversion failed unexpectedly. The FAT partition may have been left in an inconsistent status.")?;
+fn run_tune(args: TuneArgs) -> Result<()> {
+    let mut partition = Partition::open(&args.partition_path).context(ErrorCategory::Io)?;
+    let settings = TuneSettings {
+        label: args.label,
+        uuid: args.uuid,
+        reserved_percent: args.reserved_percent,
+    };
+    let report = tune(partition.as_mut_slice(), &settings)
+        .with_context(|| format!("Unable to tune the ext4 filesystem on {}", args.partition_path))
+        .context(ErrorCategory::InvalidFilesystem)?;
+    partition.flush().context(ErrorCategory::Io)?;
+    println!("Updated {} superblocks", report.superblock_count);
+    if report.descriptor_count > 0 {
+        println!("Updated the checksums of {} group descriptors", report.descriptor_count);
+    }
     Ok(())
 }
 
-/// Returns the ranges of `ClusterIdx`s in the partition described by `superblock` that may not contain any file data.
-fn forbidden_ranges(superblock: &SuperBlock, cluster_count: u32) -> Ranges<ClusterIdx> {
-    let forbidden_ranges = superblock.block_group_overhead_ranges();
-    let mut forbidden_ranges = into_cluster_idx_ranges(forbidden_ranges);
-    let last_ext_cluster_idx = ClusterIdx::try_from(superblock.block_count_with_padding())
-        .expect("ext4 block count <= FAT32 cluster count, so the index fits into a ClusterIdx");
-    let overhanging_block_range = last_ext_cluster_idx..cluster_count;
-    forbidden_ranges.insert(overhanging_block_range);
-    forbidden_ranges
+fn run_trace_dump(args: TraceDumpArgs) -> Result<()> {
+    let file = File::open(&args.trace_path)
+        .with_context(|| format!("Unable to open {}", args.trace_path))
+        .context(ErrorCategory::Io)?;
+    let (events, error) = Trace::read(io::BufReader::new(file))?;
+    for event in &events {
+        println!("{}", event);
+    }
+    match error {
+        Some(error) => Err(error),
+        None => Ok(()),
+    }
+}
+
+/// Calls `convert`, which runs a conversion with `options`, and reports the result on the command line, including the
+/// lists that `options` ask for.
+fn convert_and_report<F>(options: &ConversionOptions, verbose: bool, convert: F) -> Result<()>
+where F: FnOnce() -> Result<(ConversionStats, Duration)> {
+    // create the list before converting, so that an unwritable path does not surface after the conversion
+    let archive_bit_list = options
+        .archive_bit_list
+        .as_ref()
+        .map(|path| File::create(path).with_context(|| format!("Unable to create {}", path)))
+        .transpose()
+        .context(ErrorCategory::Io)?;
+    let crtime_list = options
+        .crtime_list
+        .as_ref()
+        .map(|path| File::create(path).with_context(|| format!("Unable to create {}", path)))
+        .transpose()
+        .context(ErrorCategory::Io)?;
+    let (stats, elapsed) = convert()?;
+    stats.print_messages();
+    if let Some(archive_bit_list) = archive_bit_list {
+        ArchiveBitFile::write_list(&stats.archive_bit_files, io::BufWriter::new(archive_bit_list))
+            .context("Unable to write the list of files with the archive flag")
+            .context(ErrorCategory::Io)?;
+    }
+    if let Some(crtime_list) = crtime_list {
+        CrtimeMapping::write_list(&stats.crtime_mappings, io::BufWriter::new(crtime_list))
+            .context("Unable to write the list of creation times")
+            .context(ErrorCategory::Io)?;
+    }
+    if verbose {
+        stats.print();
+    }
+    stats.print_summary(elapsed);
+    Ok(())
 }
 
-fn into_cluster_idx_ranges(ranges: Ranges<BlockIdx>) -> Ranges<ClusterIdx> {
-    ranges
-        .into_iter()
-        .map(|range| {
-            ClusterIdx::try_from(range.start)
-                .expect("ext4 blocks count <= FAT32 cluster count, so the indices fit into a ClusterIdx")
-                ..ClusterIdx::try_from(range.end).unwrap()
-        })
-        .collect()
+fn run_estimate(args: EstimateArgs) -> Result<()> {
+    let partition = ReadOnlyPartition::open(&args.partition_path).context(ErrorCategory::Io)?;
+    let partition_bytes = partition.as_slice();
+    BootSector::from_bytes(partition_bytes).context(ErrorCategory::InvalidFilesystem)?;
+    // If the partition is mounted and modified meanwhile, the estimate may be inconsistent, but every access stays
+    // within the partition.
+    let fat_fs = FatFs::from_slice(partition_bytes).context(ErrorCategory::InvalidFilesystem)?;
+    let inode_ratio = args.ext4.ext4_params().inode_ratio;
+    let file_count = fat_fs.file_count();
+    let (superblock, actual_inode_ratio) = build_superblock(
+        fat_fs.boot_sector(),
+        args.ext4.bigalloc,
+        inode_ratio,
+        file_count,
+        0,
+        UuidSource::Random,
+    )?;
+    if actual_inode_ratio != inode_ratio {
+        eprintln!("{}", lowered_inode_ratio_message(actual_inode_ratio, file_count));
+    }
+
+    let estimate = SpaceEstimate::new(&fat_fs, &superblock);
+    estimate.print();
+    if !args.ext4.bigalloc {
+        warn_large_blocks(&fat_fs, &superblock, inode_ratio);
+    }
+    if estimate.remaining_clusters().is_none() {
+        return Err(ErrorCategory::InsufficientSpace.error("The ext4 metadata does not fit into the free space"));
+    }
+    Ok(())
 }
@@ -0,0 +1,297 @@
+use std::env;
+use std::fmt::Display;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use clap::ArgEnum;
+
+/// The language of the messages, which is chosen once at startup, like the locale of a gettext program
+static CURRENT_LANG: AtomicU8 = AtomicU8::new(Lang::En as u8);
+
+/// The languages the user-facing messages are available in
+#[derive(Clone, Copy, Debug, PartialEq, ArgEnum)]
+pub enum Lang {
+    En,
+    De,
+}
+
+impl Lang {
+    /// Chooses the language of the locale like gettext does, i.e. from the first of LC_ALL, LC_MESSAGES and LANG
+    /// that is set. Languages without a catalog fall back to English.
+    pub fn from_env() -> Self {
+        ["LC_ALL", "LC_MESSAGES", "LANG"]
+            .iter()
+            .filter_map(|name| env::var(name).ok())
+            .find(|locale| !locale.is_empty())
+            .map_or(Self::En, |locale| Self::from_locale(&locale))
+    }
+
+    /// Parses a locale like "de_DE.UTF-8".
+    fn from_locale(locale: &str) -> Self {
+        match locale.split(['_', '.', '@']).next() {
+            Some("de") => Self::De,
+            _ => Self::En,
+        }
+    }
+
+    /// Makes `self` the language of all messages from now on.
+    pub fn set_current(self) {
+        CURRENT_LANG.store(self as u8, Ordering::Relaxed);
+    }
+
+    pub fn current() -> Self {
+        match CURRENT_LANG.load(Ordering::Relaxed) {
+            lang if lang == Self::De as u8 => Self::De,
+            _ => Self::En,
+        }
+    }
+
+    /// The translations of the English messages, which serve as their IDs like in gettext
+    fn catalog(self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            Self::En => &[],
+            Self::De => DE_CATALOG,
+        }
+    }
+}
+
+#[rustfmt::skip]
+const DE_CATALOG: &[(&str, &str)] = &[
+    ("Error", "Fehler"),
+    ("y", "j"),
+    ("yes", "ja"),
+    ("[y/N]", "[j/N]"),
+    ("n (not interactive)", "n (nicht interaktiv)"),
+    ("Convert the partition?", "Die Partition konvertieren?"),
+    ("Run anyway?", "Trotzdem fortfahren?"),
+    ("Please answer {}", "Bitte antworten Sie mit {}"),
+    ("and {} more", "und {} weitere"),
+    ("rename all", "alle umbenennen"),
+    ("skip all", "alle überspringen"),
+    ("convert all to regular files", "alle in reguläre Dateien konvertieren"),
+    ("abort", "abbrechen"),
+    (
+        "{} names are longer than the 255 bytes ext4 allows:",
+        "{} Namen sind länger als die 255 Bytes, die ext4 erlaubt:",
+    ),
+    ("Truncate these names?", "Diese Namen kürzen?"),
+    ("{} files cannot be converted:", "{} Dateien können nicht konvertiert werden:"),
+    ("Leave these files out of the conversion?", "Diese Dateien bei der Konvertierung auslassen?"),
+    ("{} files have invalid attributes:", "{} Dateien haben ungültige Attribute:"),
+    ("How should these files be converted?", "Wie sollen diese Dateien konvertiert werden?"),
+    ("Every file can be converted as it is", "Jede Datei kann unverändert konvertiert werden"),
+    (
+        "The boot sector is damaged, but the backup boot sector is intact. Restore it from the backup?",
+        "Der Bootsektor ist beschädigt, aber die Sicherungskopie ist intakt. Den Bootsektor wiederherstellen?",
+    ),
+    (
+        "Running ofs-convert-rs on an inconsistent FAT32 partition can lead to unexpected errors and data loss.",
+        "ofs-convert-rs auf einer inkonsistenten FAT32-Partition auszuführen kann zu unerwarteten Fehlern und \
+         Datenverlust führen.",
+    ),
+    (
+        "fsck failed. Running ofs-convert-rs on an inconsistent FAT32 partition can lead to unexpected errors and data \
+         loss. To force the conversion, run again with the '-f' flag.",
+        "fsck ist fehlgeschlagen. ofs-convert-rs auf einer inkonsistenten FAT32-Partition auszuführen kann zu \
+         unerwarteten Fehlern und Datenverlust führen. Um die Konvertierung zu erzwingen, starten Sie es erneut mit \
+         '-f'.",
+    ),
+    (
+        "A dry run of the conversion failed",
+        "Ein Probelauf der Konvertierung ist fehlgeschlagen",
+    ),
+    (
+        "{} free clusters are required to relocate the file data in the way of the ext4 metadata but only {} are \
+         available",
+        "{} freie Cluster werden benötigt, um die Dateidaten im Bereich der ext4-Metadaten zu verschieben, aber nur {} \
+         sind verfügbar",
+    ),
+    (
+        "Only {} of the {} clusters for files ({}%) would be free after the conversion, but {}% are required",
+        "Nur {} der {} Cluster für Dateien ({}%) wären nach der Konvertierung frei, aber {}% sind erforderlich",
+    ),
+    (
+        "The dry run succeeded, the partition has not been modified. The conversion would create {} files and \
+         directories, using {} of {} inodes, {} clusters of {} bytes for file data and {} for directories, extent \
+         trees and symlinks, and leave {} of {} clusters free.",
+        "Der Probelauf war erfolgreich, die Partition wurde nicht verändert. Die Konvertierung würde {} Dateien und \
+         Verzeichnisse erstellen, {} von {} Inodes belegen, {} Cluster zu {} Bytes für Dateidaten und {} für \
+         Verzeichnisse, Extent-Bäume und symbolische Links verwenden und {} von {} Clustern frei lassen.",
+    ),
+    (
+        "The filesystem would only fit without the superblock backup in the last block group",
+        "Das Dateisystem würde nur ohne die Sicherungskopie des Superblocks in der letzten Blockgruppe passen",
+    ),
+    (
+        "{} files would be truncated, see the warnings above",
+        "{} Dateien würden gekürzt, siehe die Warnungen oben",
+    ),
+    (
+        "{} files would be skipped because they cannot be converted, see the warnings above",
+        "{} Dateien würden übersprungen, weil sie nicht konvertiert werden können, siehe die Warnungen oben",
+    ),
+    (
+        "Converted {} files and {} directories in {} s, moving {} bytes of file data to make room for ext4 metadata. \
+         The ext4 filesystem has {} bytes ({} blocks) and {} inodes left.",
+        "{} Dateien und {} Verzeichnisse in {} s konvertiert, dabei {} Bytes Dateidaten verschoben, um Platz für die \
+         ext4-Metadaten zu schaffen. Im ext4-Dateisystem sind noch {} Bytes ({} Blöcke) und {} Inodes frei.",
+    ),
+    (
+        "Skipped {} files that could not be converted, see the warnings above",
+        "{} Dateien, die nicht konvertiert werden konnten, wurden übersprungen, siehe die Warnungen oben",
+    ),
+    (
+        "The content of {} sampled files is unchanged after the conversion",
+        "Der Inhalt von {} Stichproben-Dateien ist nach der Konvertierung unverändert",
+    ),
+    (
+        "{} sampled files were not converted to regular files with the same path and were not checked",
+        "{} Stichproben-Dateien wurden nicht in reguläre Dateien mit demselben Pfad konvertiert und nicht geprüft",
+    ),
+    (
+        "Warning: fsck.fat only reported issues that do not affect the conversion: {}",
+        "Warnung: fsck.fat hat nur Probleme gemeldet, die die Konvertierung nicht beeinträchtigen: {}",
+    ),
+    ("fsck.fat found errors in the filesystem", "fsck.fat hat Fehler im Dateisystem gefunden"),
+    ("Aborted by user", "Vom Benutzer abgebrochen"),
+    ("Not a valid FAT32 filesystem", "Kein gültiges FAT32-Dateisystem"),
+    ("Unsupported filesystem geometry", "Nicht unterstützte Geometrie des Dateisystems"),
+    ("Insufficient free space", "Nicht genügend freier Speicherplatz"),
+    ("Unable to access the partition", "Kein Zugriff auf die Partition"),
+    (
+        "Conversion failed unexpectedly. The FAT partition may have been left in an inconsistent status.",
+        "Die Konvertierung ist unerwartet fehlgeschlagen. Die FAT-Partition ist möglicherweise in einem \
+         inkonsistenten Zustand.",
+    ),
+];
+
+/// Returns the translation of `message` into the current language, or `message` itself if there is none.
+pub fn tr(message: &'static str) -> &'static str {
+    translate(message, Lang::current())
+}
+
+/// Like `tr`, but replaces each `{}` in the translation by the next of `args`.
+pub fn trf(message: &'static str, args: &[&dyn Display]) -> String {
+    fill(tr(message), args)
+}
+
+fn translate(message: &'static str, lang: Lang) -> &'static str {
+    lang.catalog()
+        .iter()
+        .find(|(id, _)| *id == message)
+        .map_or(message, |(_, translation)| translation)
+}
+
+fn fill(template: &str, args: &[&dyn Display]) -> String {
+    let mut parts = template.split("{}");
+    let mut filled = parts.next().unwrap_or_default().to_string();
+    let mut args = args.iter();
+    for part in parts {
+        if let Some(arg) = args.next() {
+            filled.push_str(&arg.to_string());
+        }
+        filled.push_str(part);
+    }
+    filled
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use super::*;
+
+    #[test]
+    fn translations_keep_placeholders() {
+        for (id, translation) in Lang::De.catalog() {
+            assert_eq!(id.matches("{}").count(), translation.matches("{}").count(), "{}", id);
+            assert_eq!(translate(id, Lang::De), *translation);
+            assert_eq!(translate(id, Lang::En), *id);
+        }
+        assert_eq!(translate("not in the catalog", Lang::De), "not in the catalog");
+        assert_eq!(
+            fill(
+                translate("Skipped {} files that could not be converted, see the warnings above", Lang::De),
+                &[&3]
+            ),
+            "3 Dateien, die nicht konvertiert werden konnten, wurden übersprungen, siehe die Warnungen oben"
+        );
+    }
+
+    /// Checks that every message that is translated when it is shown has a German translation, i.e. every string
+    /// literal passed to `tr` or `trf` and every description of a `Choice`.
+    #[test]
+    fn catalog_covers_every_message() {
+        let src_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("src");
+        let mut missing = Vec::new();
+        for path in source_files(&src_dir) {
+            // this file only defines the lookup
+            if path.ends_with("messages.rs") {
+                continue;
+            }
+            let source = fs::read_to_string(&path).unwrap();
+            for message in translated_literals(&source) {
+                if !Lang::De.catalog().iter().any(|(id, _)| *id == message) {
+                    missing.push(format!("{}: {}", path.display(), message));
+                }
+            }
+        }
+        assert!(missing.is_empty(), "Messages without a translation:\n{}", missing.join("\n"));
+    }
+
+    fn source_files(dir: &Path) -> Vec<PathBuf> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                files.extend(source_files(&path));
+            } else if path.extension() == Some(OsStr::new("rs")) {
+                files.push(path);
+            }
+        }
+        files
+    }
+
+    /// Finds the string literals that directly follow one of the calls or fields that translate them.
+    fn translated_literals(source: &str) -> Vec<String> {
+        let mut literals = Vec::new();
+        for prefix in ["tr(", "trf(", "description: "] {
+            for (start, _) in source.match_indices(prefix) {
+                let preceded_by_identifier = source[..start].ends_with(|c: char| c.is_alphanumeric() || c == '_');
+                let rest = source[start + prefix.len()..].trim_start();
+                if !preceded_by_identifier && rest.starts_with('"') {
+                    literals.push(parse_literal(&rest[1..]));
+                }
+            }
+        }
+        literals
+    }
+
+    /// Parses the content of a string literal up to its closing quote, including the escapes the messages use.
+    fn parse_literal(literal: &str) -> String {
+        let mut parsed = String::new();
+        let mut chars = literal.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => break,
+                '\\' => match chars.next() {
+                    Some('\n') => while chars.next_if(|c| c.is_whitespace()).is_some() {},
+                    Some('n') => parsed.push('\n'),
+                    Some(escaped) => parsed.push(escaped),
+                    None => break,
+                },
+                _ => parsed.push(c),
+            }
+        }
+        parsed
+    }
+
+    #[test]
+    fn language_is_chosen_by_locale() {
+        assert_eq!(Lang::from_locale("de_DE.UTF-8"), Lang::De);
+        assert_eq!(Lang::from_locale("de"), Lang::De);
+        assert_eq!(Lang::from_locale("C.UTF-8"), Lang::En);
+        assert_eq!(Lang::from_locale("fr_FR"), Lang::En);
+    }
+}
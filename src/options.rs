@@ -0,0 +1,209 @@
+use std::os::unix::io::RawFd;
+
+use anyhow::{bail, Context, Result};
+use clap::ArgEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::ext4::Owner;
+use crate::fat::FatFs;
+use crate::profile::Ext4Params;
+use crate::serialization::{DentryOrder, FileFilter, InvalidAttributesPolicy};
+use crate::skeleton::Skeleton;
+
+/// The options of a conversion, which are the same for every partition converted by one invocation
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ConversionOptions {
+    pub filter: FileFilter,
+    pub convert_shortcuts: bool,
+    pub wipe_fat_remnants: bool,
+    pub bigalloc: bool,
+    pub ext4_params: Ext4Params,
+    pub direct_io: bool,
+    /// the byte offset of the FAT filesystem in the raw image that a sparse image expands to
+    #[cfg(feature = "sparse-images")]
+    #[serde(default)]
+    pub sparse_offset: usize,
+    pub truncate_long_names: bool,
+    pub dentry_order: DentryOrder,
+    pub verify_archival: bool,
+    /// the number of files whose content is compared before and after the conversion, see `SpotCheckSample`
+    #[serde(default)]
+    pub spot_check: Option<usize>,
+    /// record the checksums of the boot sector and the FATs before they are overwritten, see `FatMetadataChecksums`
+    #[serde(default)]
+    pub checksum_fat_metadata: bool,
+    /// retry without the superblock backup in the last block group if the conversion does not fit
+    pub allow_tight_fit: bool,
+    /// the percentage of the space for files that must remain free after the conversion
+    pub min_free_space_after: u8,
+    /// the maximum number of megabytes per second that are copied or zeroed, see `RateLimiter`
+    pub bwlimit: Option<u32>,
+    pub randomize_generation: bool,
+    /// use the modification time as the access time, see `Ext4TreeDeserializer::set_drop_atime`
+    #[serde(default)]
+    pub drop_atime: bool,
+    /// turn regular files with the same content into hard links, see `HardLinkFolder`
+    #[serde(default)]
+    pub dedup_hardlink: bool,
+    /// the owner of the converted files, or None for the effective user and group of the conversion
+    pub owner: Option<Owner>,
+    /// the empty directories that are added after the converted files
+    #[serde(default)]
+    pub skeleton: Skeleton,
+    /// the file to list the files with the FAT archive flag in, which are collected into
+    /// `ConversionStats::archive_bit_files`
+    pub archive_bit_list: Option<String>,
+    /// the file to list the creation times of the converted files in, which are collected into
+    /// `ConversionStats::crtime_mappings`
+    pub crtime_list: Option<String>,
+    /// the file to record the steps of the conversion in, see `Trace`
+    pub trace: Option<String>,
+    /// the file descriptor to write the progress to, see `JsonProgress`; not saved in a checkpoint, since it is only
+    /// valid in the process that was given it
+    #[serde(skip)]
+    pub json_progress_fd: Option<RawFd>,
+    /// skip the files that cannot be converted instead of failing, see `ErrorPolicy`
+    pub collect_errors: bool,
+    /// what to do with files whose attributes are an invalid combination
+    #[serde(default)]
+    pub invalid_attributes: InvalidAttributesPolicy,
+    /// convert a copy-on-write mapping of the partition first and ask before converting the partition itself
+    pub trial_run: bool,
+    pub mkfs_time: MkfsTime,
+    pub uuid: UuidSource,
+    /// skip fsck
+    pub force: bool,
+    /// repair the FAT32 filesystem with `fsck.fat -a` before checking it, see `fsck::auto_fix`
+    #[serde(default)]
+    pub fsck_auto_fix: bool,
+    /// convert the partition even if it contains an ext4 superblock, see `check_ext4_signature`
+    #[serde(default)]
+    pub force_reconvert: bool,
+    /// whether the user can answer questions on the command line; if not, every question is answered with no
+    pub interactive: bool,
+}
+
+impl ConversionOptions {
+    /// The maximum number of bytes per second that are copied or zeroed, if limited.
+    pub fn rate_limit(&self) -> Option<u64> {
+        self.bwlimit.map(|bwlimit| u64::from(bwlimit) * 1_000_000)
+    }
+
+    /// Prints the options as the arguments that select them, one per line.
+    pub fn print(&self) {
+        let yes_no = |value| if value { "yes" } else { "no" };
+        let mkfs_time = match self.mkfs_time {
+            MkfsTime::Now => "now".to_string(),
+            MkfsTime::FromFat => "from-fat".to_string(),
+            MkfsTime::Unix(time) => time.to_string(),
+        };
+        let or_none = |value: Option<String>| value.unwrap_or_else(|| "none".to_string());
+        println!("inode-ratio: {}", self.ext4_params.inode_ratio);
+        println!("reserved-percent: {}", self.ext4_params.reserved_percent);
+        println!("bigalloc: {}", yes_no(self.bigalloc));
+        println!("mkfs-time: {}", mkfs_time);
+        let uuid = self.uuid.to_possible_value().expect("no variant is skipped");
+        println!("uuid: {}", uuid.get_name());
+        println!("convert-shortcuts: {}", yes_no(self.convert_shortcuts));
+        println!("truncate-long-names: {}", yes_no(self.truncate_long_names));
+        let dentry_order = self.dentry_order.to_possible_value().expect("no variant is skipped");
+        println!("dentry-order: {}", dentry_order.get_name());
+        println!("wipe-fat-remnants: {}", yes_no(self.wipe_fat_remnants));
+        println!("direct-io: {}", yes_no(self.direct_io));
+        #[cfg(feature = "sparse-images")]
+        println!("sparse-offset: {}", self.sparse_offset);
+        println!("verify-archival: {}", yes_no(self.verify_archival));
+        println!("spot-check: {}", or_none(self.spot_check.map(|count| count.to_string())));
+        println!("checksum-fat-metadata: {}", yes_no(self.checksum_fat_metadata));
+        println!("allow-tight-fit: {}", yes_no(self.allow_tight_fit));
+        println!("min-free-space-after: {}", self.min_free_space_after);
+        println!("bwlimit: {}", or_none(self.bwlimit.map(|bwlimit| bwlimit.to_string())));
+        println!("randomize-generation: {}", yes_no(self.randomize_generation));
+        println!("drop-atime: {}", yes_no(self.drop_atime));
+        println!("dedup-hardlink: {}", yes_no(self.dedup_hardlink));
+        println!(
+            "owner: {}",
+            or_none(self.owner.map(|owner| format!("{}:{}", owner.uid, owner.gid)))
+        );
+        let skeleton = match self.skeleton.dir_count() {
+            0 => "none".to_string(),
+            dir_count => format!("{} directories", dir_count),
+        };
+        println!("skeleton: {}", skeleton);
+        println!("archive-bit-list: {}", or_none(self.archive_bit_list.clone()));
+        println!("crtime-list: {}", or_none(self.crtime_list.clone()));
+        println!("trace: {}", or_none(self.trace.clone()));
+        println!(
+            "quiet-json-progress: {}",
+            or_none(self.json_progress_fd.map(|fd| fd.to_string()))
+        );
+        println!("collect-errors: {}", yes_no(self.collect_errors));
+        let invalid_attributes = self.invalid_attributes.to_possible_value().expect("no variant is skipped");
+        println!("invalid-attributes: {}", invalid_attributes.get_name());
+        println!("trial-run: {}", yes_no(self.trial_run));
+        println!(
+            "exclude-size-over: {}",
+            or_none(self.filter.max_size.map(|size| size.to_string()))
+        );
+        println!(
+            "exclude-older-than: {}",
+            or_none(
+                self.filter
+                    .min_mod_time
+                    .map(|time| { chrono::NaiveDateTime::from_timestamp(time, 0).date().to_string() })
+            )
+        );
+        println!("force: {}", yes_no(self.force));
+        println!("fsck-auto-fix: {}", yes_no(self.fsck_auto_fix));
+        println!("force-reconvert: {}", yes_no(self.force_reconvert));
+    }
+}
+
+/// Where the UUID of the ext4 filesystem comes from
+#[derive(Clone, Copy, Debug, Default, PartialEq, ArgEnum, Serialize, Deserialize)]
+pub enum UuidSource {
+    /// a random UUID, like mke2fs creates
+    #[default]
+    Random,
+    /// derived from the FAT serial number, see `ext4::uuid_from_fat_serial`
+    FromFatSerial,
+}
+
+/// Where the creation time of the ext4 filesystem comes from
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum MkfsTime {
+    #[default]
+    Now,
+    /// the time the FAT volume label was set
+    FromFat,
+    Unix(u32),
+}
+
+impl MkfsTime {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "now" => Ok(Self::Now),
+            "from-fat" => Ok(Self::FromFat),
+            _ => Ok(Self::Unix(
+                value.parse().context("Expected 'now', 'from-fat' or a Unix timestamp")?,
+            )),
+        }
+    }
+
+    /// Returns the creation time as a Unix timestamp.
+    pub fn resolve(self, fat_fs: &FatFs) -> Result<u32> {
+        let now = u32::try_from(chrono::Utc::now().timestamp()).unwrap();
+        let time = match self {
+            Self::Now => now,
+            Self::FromFat => fat_fs
+                .volume_label_time()
+                .context("The FAT volume label has an invalid timestamp")?
+                .context("The FAT volume has no volume label, so its creation time is unknown. Use --mkfs-time now.")?,
+            Self::Unix(timestamp) => timestamp,
+        };
+        if time > now {
+            bail!("The creation time of the filesystem must not be in the future");
+        }
+        Ok(time)
+    }
+}
@@ -0,0 +1,86 @@
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+use nix::unistd::{Group, Uid, User};
+
+use crate::ext4::Owner;
+
+/// Resolves the argument of `--owner`, `USER[:GROUP]`, into numeric IDs. USER and GROUP may be names, which are looked
+/// up in the user and group databases, or numeric IDs, which are used as they are. If GROUP is omitted, it is USER's
+/// login group. If `numeric` is set, the databases are never consulted, since they may be missing in an initramfs or a
+/// container: USER and GROUP must be numeric IDs, and GROUP is required.
+pub fn parse_owner(value: &str, numeric: bool) -> Result<Owner> {
+    let (user, group) = match value.split_once(':') {
+        Some((user, group)) => (user, Some(group)),
+        None => (value, None),
+    };
+
+    if numeric {
+        let group = match group {
+            Some(group) => group,
+            None => bail!("With --numeric-owner, --owner must be USER:GROUP"),
+        };
+        return Ok(Owner {
+            uid: parse_id(user).with_context(|| format!("With --numeric-owner, the user '{}' must be an ID", user))?,
+            gid: parse_id(group)
+                .with_context(|| format!("With --numeric-owner, the group '{}' must be an ID", group))?,
+        });
+    }
+
+    let uid = match parse_id(user) {
+        Ok(uid) => uid,
+        Err(_) => find_user(User::from_name(user), user)?.uid.as_raw(),
+    };
+    let gid = match group {
+        Some(group) => match parse_id(group) {
+            Ok(gid) => gid,
+            Err(_) => find_group(Group::from_name(group), group)?.gid.as_raw(),
+        },
+        None => find_user(User::from_uid(Uid::from_raw(uid)), user)?.gid.as_raw(),
+    };
+    Ok(Owner { uid, gid })
+}
+
+fn parse_id(value: &str) -> Result<u32> {
+    // unlike `u32::from_str`, reject a leading '+', which is not part of an ID
+    if !value.bytes().all(|byte| byte.is_ascii_digit()) {
+        bail!("'{}' is not a number", value);
+    }
+    Ok(u32::from_str(value)?)
+}
+
+fn find_user(lookup: nix::Result<Option<User>>, user: &str) -> Result<User> {
+    match lookup.with_context(|| lookup_error("user", user))? {
+        Some(found) => Ok(found),
+        None => bail!("There is no user '{}'", user),
+    }
+}
+
+fn find_group(lookup: nix::Result<Option<Group>>, group: &str) -> Result<Group> {
+    match lookup.with_context(|| lookup_error("group", group))? {
+        Some(found) => Ok(found),
+        None => bail!("There is no group '{}'", group),
+    }
+}
+
+fn lookup_error(kind: &str, name: &str) -> String {
+    format!(
+        "Unable to look up the {} '{}'; without a user database, pass numeric IDs with --numeric-owner",
+        kind, name
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numeric_owner_needs_no_lookup() {
+        assert_eq!(parse_owner("1000:100", true).unwrap(), Owner { uid: 1000, gid: 100 });
+        assert_eq!(parse_owner("0:4294967295", false).unwrap(), Owner { uid: 0, gid: u32::MAX });
+        assert!(parse_owner("1000", true).is_err());
+        assert!(parse_owner("root:0", true).is_err());
+        assert!(parse_owner("0:+1", true).is_err());
+        assert!(parse_owner("0:4294967296", true).is_err());
+    }
+}
@@ -1,23 +1,68 @@
+use std::alloc::{self, Layout};
+use std::collections::BTreeSet;
 use std::convert::TryInto;
 use std::fs::{File, OpenOptions};
 use std::marker::PhantomData;
-use std::os::unix::fs::FileTypeExt;
-use std::os::unix::io::AsRawFd;
+use std::mem::size_of;
+use std::os::unix::fs::{FileExt as UnixFileExt, FileTypeExt, OpenOptionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::path::Path;
 use std::process::Command;
+use std::ptr::{self, NonNull};
+use std::slice;
+use std::thread::{self, JoinHandle};
 
 use anyhow::{bail, Context, Result};
 use fs2::FileExt;
-use memmap::{MmapMut, MmapOptions};
-use nix::ioctl_read;
+use memmap::{Mmap, MmapMut, MmapOptions};
+use nix::errno::Errno;
+use nix::fcntl::OFlag;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::mman::{mmap, munmap, MapFlags, ProtFlags};
+use nix::unistd::{pipe, sysconf, SysconfVar};
+use nix::{ioctl_read, ioctl_readwrite, libc};
+use num::Integer;
+
+/// The alignment of the memory, offsets and lengths of O_DIRECT transfers. It is a multiple of every logical sector
+/// size, so it satisfies the requirements of any block device.
+const DIRECT_IO_ALIGNMENT: usize = 4096;
+/// O_DIRECT transfers can always be as short as the smallest logical sector size, which is 512 bytes.
+const DIRECT_IO_MIN_LEN: usize = 512;
+/// The number of bytes that `BufferedPartition` and `DemandPagedPartition` transfer at a time
+const DIRECT_IO_CHUNK_SIZE: usize = 1 << 20;
+/// The version of the userfaultfd API that `DemandPagedPartition` uses, declared in linux/userfaultfd.h
+const UFFD_API: u64 = 0xAA;
+/// Registers a range so that accesses to pages that are not populated yet are reported
+const UFFDIO_REGISTER_MODE_MISSING: u64 = 1;
+/// The event of a `UffdMsg` that reports an access to a page that is not populated yet
+const UFFD_EVENT_PAGEFAULT: u8 = 0x12;
+/// Makes an unprivileged userfaultfd handle only the accesses from user space, see `ChunkLoader::open_userfaultfd`
+const UFFD_USER_MODE_ONLY: libc::c_int = 1;
 
 pub struct Partition<'a> {
     mmap: MmapMut,
     pub lifetime: PhantomData<&'a ()>,
 }
 
+/// A partition mapped for reading only. Unlike `Partition`, it can be opened while the partition is mounted, so it is
+/// only suitable for analyses that tolerate the content changing under them.
+pub struct ReadOnlyPartition {
+    mmap: Mmap,
+}
+
 impl<'a> Partition<'a> {
     pub fn open<P: AsRef<Path>>(partition_path: P) -> Result<Self> {
+        Self::open_mapped(partition_path, false)
+    }
+
+    /// Opens the partition like `open`, but with a private copy-on-write mapping: writes only modify a copy of the
+    /// written pages in memory and never reach the partition, and `flush` has no effect. Requires as much free memory
+    /// as is written.
+    pub fn open_copy_on_write<P: AsRef<Path>>(partition_path: P) -> Result<Self> {
+        Self::open_mapped(partition_path, true)
+    }
+
+    fn open_mapped<P: AsRef<Path>>(partition_path: P, copy_on_write: bool) -> Result<Self> {
         let partition_path = partition_path.as_ref().canonicalize()?;
         if Self::is_mounted(partition_path.as_path())? {
             bail!("Partition already mounted. Please unmount and try again.");
@@ -28,9 +73,15 @@ impl<'a> Partition<'a> {
         file.try_lock_exclusive()
             .context("The partition cannot be locked. Is another process using it?")?;
 
-        let size = Self::get_file_size(&file)?;
+        let size = get_file_size(&file)?;
+        let mut options = MmapOptions::new();
+        options.len(size);
         // SAFETY: We assume that no other process is modifying the partition
-        let mmap = unsafe { MmapOptions::new().len(size).map_mut(&file)? };
+        let mmap = if copy_on_write {
+            unsafe { options.map_copy(&file)? }
+        } else {
+            unsafe { options.map_mut(&file)? }
+        };
         Ok(Self { mmap, lifetime: PhantomData })
     }
 
@@ -42,19 +93,13 @@ impl<'a> Partition<'a> {
         self.mmap.as_mut_ptr()
     }
 
-    fn get_file_size(file: &File) -> Result<usize> {
-        let metadata = file.metadata()?;
-        let filetype = metadata.file_type();
-        let len = if filetype.is_file() {
-            metadata.len()
-        } else if filetype.is_block_device() {
-            Self::get_block_device_size(file)?
-        } else {
-            bail!("Expected path to a file or a block device")
-        };
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.mmap
+    }
 
-        len.try_into()
-            .with_context(|| format!("File size {} does not fit into a usize", len))
+    /// Writes changes made through `as_mut_slice` to the partition.
+    pub fn flush(&self) -> Result<()> {
+        Ok(self.mmap.flush()?)
     }
 
     fn is_mounted(partition_path: &Path) -> Result<bool> {
@@ -65,27 +110,567 @@ impl<'a> Partition<'a> {
         let output = String::from_utf8(command_output.stdout).expect("mount output is not valid UTF-8");
         Ok(output.lines().any(|line| line.starts_with(path_str)))
     }
+}
+
+/// Random access to the bytes of a partition without mapping it into memory.
+pub trait BlockAccess {
+    fn len(&self) -> usize;
+
+    /// Fills `buf` with the bytes starting at `offset`. Implementations may require `buf` and `offset` to be aligned.
+    fn read_at(&mut self, offset: usize, buf: &mut [u8]) -> Result<()>;
+
+    /// Writes `buf` to the bytes starting at `offset`. Implementations may require `buf` and `offset` to be aligned.
+    fn write_at(&mut self, offset: usize, buf: &[u8]) -> Result<()>;
+
+    /// Blocks until all writes have reached the partition.
+    fn sync(&mut self) -> Result<()>;
+}
+
+/// A partition accessed with O_DIRECT, bypassing the page cache, for storage stacks that reject writes through a
+/// memory mapping. All transfers must start at a multiple of `DIRECT_IO_ALIGNMENT` bytes, both in the partition and in
+/// memory, and their length must be a multiple of `DIRECT_IO_MIN_LEN`.
+pub struct DirectIoPartition {
+    file: File,
+    len: usize,
+}
+
+impl DirectIoPartition {
+    pub fn open<P: AsRef<Path>>(partition_path: P) -> Result<Self> {
+        let partition_path = partition_path.as_ref().canonicalize()?;
+        if Partition::is_mounted(partition_path.as_path())? {
+            bail!("Partition already mounted. Please unmount and try again.");
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(OFlag::O_DIRECT.bits())
+            .open(partition_path)
+            .context("Unable to open the partition with O_DIRECT")?;
+        file.try_lock_exclusive()
+            .context("The partition cannot be locked. Is another process using it?")?;
+
+        let len = get_file_size(&file)?;
+        if len % DIRECT_IO_MIN_LEN != 0 {
+            bail!(
+                "The partition size must be a multiple of {} bytes to access it with O_DIRECT",
+                DIRECT_IO_MIN_LEN
+            );
+        }
+        Ok(Self { file, len })
+    }
+
+    fn check_alignment(&self, offset: usize, buf: &[u8]) {
+        debug_assert!(offset % DIRECT_IO_ALIGNMENT == 0);
+        debug_assert!(buf.as_ptr() as usize % DIRECT_IO_ALIGNMENT == 0);
+        debug_assert!(buf.len() % DIRECT_IO_MIN_LEN == 0);
+        debug_assert!(offset + buf.len() <= self.len);
+    }
+}
+
+impl BlockAccess for DirectIoPartition {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn read_at(&mut self, offset: usize, buf: &mut [u8]) -> Result<()> {
+        self.check_alignment(offset, buf);
+        Ok(self.file.read_exact_at(buf, offset.try_into()?)?)
+    }
+
+    fn write_at(&mut self, offset: usize, buf: &[u8]) -> Result<()> {
+        self.check_alignment(offset, buf);
+        Ok(self.file.write_all_at(buf, offset.try_into()?)?)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        Ok(self.file.sync_all()?)
+    }
+}
+
+/// A copy of a partition in memory, for partitions that can only be accessed through `BlockAccess`. The conversion
+/// runs on the copy, and `write_back` transfers the modified parts to the partition afterwards, so the partition is
+/// left untouched if the conversion fails. Requires as much memory as the partition is large.
+pub struct BufferedPartition<'a, B: BlockAccess> {
+    backend: B,
+    buffer: AlignedBuffer,
+    pub lifetime: PhantomData<&'a ()>,
+}
+
+impl<'a, B: BlockAccess> BufferedPartition<'a, B> {
+    pub fn load(mut backend: B) -> Result<Self> {
+        let mut buffer = AlignedBuffer::new(backend.len(), DIRECT_IO_ALIGNMENT)?;
+        for (chunk_idx, chunk) in buffer.as_mut_slice().chunks_mut(DIRECT_IO_CHUNK_SIZE).enumerate() {
+            backend.read_at(chunk_idx * DIRECT_IO_CHUNK_SIZE, chunk)?;
+        }
+        Ok(Self { backend, buffer, lifetime: PhantomData })
+    }
+
+    pub fn len(&self) -> usize {
+        self.buffer.len
+    }
+
+    pub fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.buffer.ptr.as_ptr()
+    }
+
+    /// Writes the chunks of the partition that differ from the copy in memory to the partition. Returns the number of
+    /// bytes written.
+    pub fn write_back(&mut self) -> Result<usize> {
+        let mut scratch = AlignedBuffer::new(DIRECT_IO_CHUNK_SIZE.min(self.len()), DIRECT_IO_ALIGNMENT)?;
+        let mut written_bytes = 0;
+        for (chunk_idx, chunk) in self.buffer.as_mut_slice().chunks(DIRECT_IO_CHUNK_SIZE).enumerate() {
+            let offset = chunk_idx * DIRECT_IO_CHUNK_SIZE;
+            let on_disk = &mut scratch.as_mut_slice()[..chunk.len()];
+            self.backend.read_at(offset, on_disk)?;
+            if on_disk != chunk {
+                self.backend.write_at(offset, chunk)?;
+                written_bytes += chunk.len();
+            }
+        }
+        self.backend.sync()?;
+        Ok(written_bytes)
+    }
+}
+
+/// A copy of a partition in memory that is only read where it is accessed, for partitions that can only be accessed
+/// through `BlockAccess`, e.g. with O_DIRECT. Unlike `BufferedPartition`, which requires as much memory as the
+/// partition is large, the copy starts out as reserved address space, and the first access to each chunk of
+/// `DIRECT_IO_CHUNK_SIZE` bytes blocks until a `ChunkLoader` has read it from the partition. Since the conversion only
+/// accesses the FAT, the directories, the relocated file data and the ext4 metadata of the used block groups, only
+/// those take up memory. The conversion runs on the copy, and `write_back` transfers the modified chunks to the
+/// partition afterwards, so the partition is left untouched if the conversion fails.
+pub struct DemandPagedPartition<'a, B: BlockAccess + Send + 'static> {
+    region: NonNull<u8>,
+    /// the length of the partition rounded up to the page size, which the mapping of `region` requires
+    region_len: usize,
+    len: usize,
+    loader: LoaderState<B>,
+    pub lifetime: PhantomData<&'a ()>,
+}
+
+enum LoaderState<B: BlockAccess + Send + 'static> {
+    /// the loader runs on `thread` until `stop` is closed
+    Running {
+        thread: JoinHandle<ChunkLoader<B>>,
+        stop: File,
+    },
+    Stopped(ChunkLoader<B>),
+    /// the loader thread panicked, which leaves the loaded chunks unknown
+    Lost,
+}
+
+impl<'a, B: BlockAccess + Send + 'static> DemandPagedPartition<'a, B> {
+    /// Reserves the address space for the partition behind `backend` and starts the thread that loads its chunks.
+    pub fn new(backend: B) -> Result<Self> {
+        let len = backend.len();
+        let (stop_read, stop_write) = pipe()?;
+        // SAFETY: Safe because `pipe` has just created both file descriptors, which nothing else owns.
+        let (stop_read, stop) = unsafe { (File::from_raw_fd(stop_read), File::from_raw_fd(stop_write)) };
+        let page_size = sysconf(SysconfVar::PAGE_SIZE)?
+            .and_then(|page_size| usize::try_from(page_size).ok())
+            .context("Unable to determine the page size")?;
+        let region_len = len.max(1).div_ceil(&page_size) * page_size;
+        // SAFETY: Safe because the anonymous mapping does not alias any memory. MAP_NORESERVE keeps the kernel from
+        // accounting the whole partition as memory in use, since only the loaded chunks are ever populated.
+        let region = unsafe {
+            mmap(
+                ptr::null_mut(),
+                region_len,
+                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+                MapFlags::MAP_PRIVATE | MapFlags::MAP_ANONYMOUS | MapFlags::MAP_NORESERVE,
+                -1,
+                0,
+            )
+        }
+        .context("Unable to reserve the address space for the partition")?;
+        let region = NonNull::new(region.cast::<u8>()).expect("mmap does not return a null pointer on success");
+        let loader = ChunkLoader::register(backend, region, region_len, len).inspect_err(|_| {
+            // SAFETY: Safe because `region` was mapped with `region_len` bytes above and is not used afterwards.
+            let _ = unsafe { munmap(region.as_ptr().cast(), region_len) };
+        })?;
+        let thread = thread::spawn(move || loader.run(stop_read));
+        Ok(Self {
+            region,
+            region_len,
+            len,
+            loader: LoaderState::Running { thread, stop },
+            lifetime: PhantomData,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.region.as_ptr()
+    }
+
+    /// The number of bytes that have been loaded from the partition so far, once the loader has stopped
+    pub fn loaded_len(&self) -> usize {
+        match &self.loader {
+            LoaderState::Stopped(loader) => {
+                loader.loaded_chunks.iter().map(|&chunk_idx| loader.chunk_len(chunk_idx)).sum()
+            }
+            _ => 0,
+        }
+    }
+
+    /// Stops loading chunks, after which the copy must only be accessed where it has been loaded. Fails if a chunk
+    /// could not be read, in which case the conversion found zeros instead of its content.
+    pub fn stop(&mut self) -> Result<()> {
+        if let LoaderState::Running { .. } = self.loader {
+            if let LoaderState::Running { thread, stop } = std::mem::replace(&mut self.loader, LoaderState::Lost) {
+                // closing the pipe wakes up the loader
+                drop(stop);
+                if let Ok(loader) = thread.join() {
+                    self.loader = LoaderState::Stopped(loader);
+                }
+            }
+        }
+        match &self.loader {
+            LoaderState::Stopped(ChunkLoader { error: Some(error), .. }) => {
+                bail!("Unable to read the partition: {:#}", error)
+            }
+            LoaderState::Stopped(_) => Ok(()),
+            LoaderState::Running { .. } => unreachable!("The loader has been stopped"),
+            LoaderState::Lost => bail!("The thread reading the partition panicked"),
+        }
+    }
+
+    /// Stops loading chunks and writes the loaded chunks that differ from the partition to the partition, see `stop`.
+    /// Returns the number of bytes written.
+    pub fn write_back(&mut self) -> Result<usize> {
+        self.stop()?;
+        let loader = match &mut self.loader {
+            LoaderState::Stopped(loader) => loader,
+            _ => unreachable!("`stop` only succeeds once the loader has stopped"),
+        };
+        let mut written_bytes = 0;
+        for &chunk_idx in &loader.loaded_chunks {
+            let offset = chunk_idx * DIRECT_IO_CHUNK_SIZE;
+            let chunk_len = loader.chunk_len(chunk_idx);
+            // SAFETY: Safe because the chunk has been loaded, so its memory is populated, and lies within the region.
+            let chunk = unsafe { slice::from_raw_parts(self.region.as_ptr().add(offset), chunk_len) };
+            let on_disk = &mut loader.buffer.as_mut_slice()[..chunk_len];
+            loader.backend.read_at(offset, on_disk)?;
+            if on_disk != chunk {
+                loader.backend.write_at(offset, chunk)?;
+                written_bytes += chunk_len;
+            }
+        }
+        loader.backend.sync()?;
+        Ok(written_bytes)
+    }
+}
+
+impl<'a, B: BlockAccess + Send + 'static> Drop for DemandPagedPartition<'a, B> {
+    fn drop(&mut self) {
+        // the loader must not fill the region after it has been unmapped
+        let _ = self.stop();
+        // SAFETY: Safe because `self.region` was mapped with `self.region_len` bytes in `new`, and the lifetime of the
+        // pointers handed out by `as_mut_ptr` ends with `self`.
+        let _ = unsafe { munmap(self.region.as_ptr().cast(), self.region_len) };
+    }
+}
+
+/// Loads the chunks of a `DemandPagedPartition` on its own thread: the kernel reports the first access to a page of
+/// the region through a userfaultfd, and the loader reads the chunk containing the page from the partition and
+/// populates it, which lets the access continue.
+struct ChunkLoader<B: BlockAccess> {
+    backend: B,
+    /// closing it unregisters the region, so that accesses find zeroed memory instead of waiting for the loader
+    userfaultfd: Option<File>,
+    region_addr: usize,
+    region_len: usize,
+    len: usize,
+    loaded_chunks: BTreeSet<usize>,
+    /// the first error, after which the loader stops
+    error: Option<anyhow::Error>,
+    /// holds a chunk on its way between the partition and the region
+    buffer: AlignedBuffer,
+}
+
+impl<B: BlockAccess> ChunkLoader<B> {
+    /// Registers the `region_len` bytes at `region` with a new userfaultfd, so that their accesses are reported.
+    fn register(backend: B, region: NonNull<u8>, region_len: usize, len: usize) -> Result<Self> {
+        let userfaultfd = Self::open_userfaultfd()?;
+        let mut api = UffdioApi { api: UFFD_API, features: 0, ioctls: 0 };
+        // SAFETY: Safe because `api` is a valid `uffdio_api`.
+        unsafe { uffdio_api(userfaultfd.as_raw_fd(), &mut api) }.context("The kernel does not support userfaultfd")?;
+        let mut register = UffdioRegister {
+            range: UffdioRange {
+                start: region.as_ptr() as u64,
+                len: region_len as u64,
+            },
+            mode: UFFDIO_REGISTER_MODE_MISSING,
+            ioctls: 0,
+        };
+        // SAFETY: Safe because `register` is a valid `uffdio_register` whose range is the caller's mapping.
+        unsafe { uffdio_register(userfaultfd.as_raw_fd(), &mut register) }
+            .context("Unable to register the partition's memory with userfaultfd")?;
+        Ok(Self {
+            backend,
+            userfaultfd: Some(userfaultfd),
+            region_addr: region.as_ptr() as usize,
+            region_len,
+            len,
+            loaded_chunks: BTreeSet::new(),
+            error: None,
+            buffer: AlignedBuffer::new(DIRECT_IO_CHUNK_SIZE, DIRECT_IO_ALIGNMENT)?,
+        })
+    }
+
+    /// Without CAP_SYS_PTRACE, a userfaultfd can only be created if vm.unprivileged_userfaultfd is set, or if it only
+    /// handles accesses from user space. The conversion only passes loaded chunks to the kernel, in `write_back`.
+    fn open_userfaultfd() -> Result<File> {
+        let flags = libc::O_CLOEXEC | libc::O_NONBLOCK;
+        // SAFETY: Safe because the syscall only creates a file descriptor.
+        let mut fd = unsafe { libc::syscall(libc::SYS_userfaultfd, flags) };
+        if fd < 0 && Errno::last() == Errno::EPERM {
+            // SAFETY: See above.
+            fd = unsafe { libc::syscall(libc::SYS_userfaultfd, flags | UFFD_USER_MODE_ONLY) };
+        }
+        if fd < 0 {
+            return Err(Errno::last()).context("Unable to create a userfaultfd");
+        }
+        // SAFETY: Safe because the syscall has just created the file descriptor, which nothing else owns.
+        Ok(unsafe { File::from_raw_fd(fd as libc::c_int) })
+    }
+
+    /// Loads chunks until `stop` is closed or an error occurs, which is recorded in `self.error`.
+    fn run(mut self, stop: File) -> Self {
+        if let Err(error) = self.serve(&stop) {
+            self.error = Some(error);
+        }
+        self.userfaultfd = None;
+        self
+    }
+
+    fn serve(&mut self, stop: &File) -> Result<()> {
+        let userfaultfd = self
+            .userfaultfd
+            .as_ref()
+            .expect("the userfaultfd is open until `run` returns")
+            .as_raw_fd();
+        loop {
+            let mut poll_fds =
+                [PollFd::new(userfaultfd, PollFlags::POLLIN), PollFd::new(stop.as_raw_fd(), PollFlags::POLLIN)];
+            match poll(&mut poll_fds, -1) {
+                Err(Errno::EINTR) => continue,
+                result => result.context("Unable to wait for accesses to the partition")?,
+            };
+            if poll_fds[1].revents().is_some_and(|events| !events.is_empty()) {
+                return Ok(());
+            }
+            let mut msg = UffdMsg::default();
+            // SAFETY: Safe because `msg` is a plain `uffd_msg` that any bytes are valid for.
+            let msg_bytes =
+                unsafe { slice::from_raw_parts_mut((&mut msg as *mut UffdMsg).cast::<u8>(), size_of::<UffdMsg>()) };
+            match nix::unistd::read(userfaultfd, msg_bytes) {
+                Err(Errno::EAGAIN) => continue,
+                Ok(read_len) if read_len == size_of::<UffdMsg>() => (),
+                result => bail!("Unable to read the accesses to the partition ({:?})", result),
+            }
+            if msg.event == UFFD_EVENT_PAGEFAULT {
+                let offset = usize::try_from(msg.address)? - self.region_addr;
+                self.load(offset / DIRECT_IO_CHUNK_SIZE)?;
+            }
+        }
+    }
 
-    // declared in linux/fs.h
-    // The type is declared as size_t due to a bug that cannot be fixed due to backwards compatibility. If I understand
-    // correctly, passing u64 instead of usize should work even on 32bit systems, I haven't had a chance to test it
-    // though. cfr. https://lists.debian.org/debian-glibc/2005/12/msg00069.html
-    #[cfg(target_os = "linux")]
-    ioctl_read!(block_device_size, 0x12, 114, u64);
+    /// Reads the chunk with index `chunk_idx` from the partition into the region, which wakes up the threads waiting
+    /// for it. The chunk may already be loaded if several threads accessed it at once.
+    fn load(&mut self, chunk_idx: usize) -> Result<()> {
+        let userfaultfd = self
+            .userfaultfd
+            .as_ref()
+            .expect("the userfaultfd is open until `run` returns")
+            .as_raw_fd();
+        let offset = chunk_idx * DIRECT_IO_CHUNK_SIZE;
+        let populated_len = DIRECT_IO_CHUNK_SIZE.min(self.region_len - offset);
+        if !self.loaded_chunks.insert(chunk_idx) {
+            let mut range = UffdioRange {
+                start: (self.region_addr + offset) as u64,
+                len: populated_len as u64,
+            };
+            // SAFETY: Safe because `range` is a valid `uffdio_range` within the registered region.
+            unsafe { uffdio_wake(userfaultfd, &mut range) }.context("Unable to resume an access to the partition")?;
+            return Ok(());
+        }
 
-    /// PANICS: Panics if `file` is not a block device.
-    #[cfg(target_os = "linux")]
-    fn get_block_device_size(file: &File) -> Result<u64> {
-        assert!(file.metadata()?.file_type().is_block_device());
-        let mut size = 0;
-        // SAFETY: the nix crate provides no safety documentation, so we must just assume that this is safe.
-        unsafe {
-            Self::block_device_size(file.as_raw_fd(), &mut size)?;
+        let chunk_len = self.chunk_len(chunk_idx);
+        let buffer = &mut self.buffer.as_mut_slice()[..populated_len];
+        // the region extends beyond the partition to the end of the page
+        buffer[chunk_len..].fill(0);
+        self.backend
+            .read_at(offset, &mut buffer[..chunk_len])
+            .with_context(|| format!("Unable to read {} bytes at byte {}", chunk_len, offset))?;
+        let mut copied_len = 0;
+        while copied_len < populated_len {
+            let mut copy = UffdioCopy {
+                dst: (self.region_addr + offset + copied_len) as u64,
+                src: buffer[copied_len..].as_ptr() as u64,
+                len: (populated_len - copied_len) as u64,
+                mode: 0,
+                copy: 0,
+            };
+            // SAFETY: Safe because `copy` is a valid `uffdio_copy` from `buffer` into the registered region, which
+            // nothing else populates.
+            match unsafe { uffdio_copy(userfaultfd, &mut copy) } {
+                Ok(_) => break,
+                // the kernel copied a part, or none because the memory mappings were changing
+                Err(Errno::EAGAIN) => copied_len += usize::try_from(copy.copy.max(0))?,
+                Err(error) => return Err(error).context("Unable to populate the partition's memory"),
+            }
         }
-        Ok(size)
+        Ok(())
+    }
+
+    /// The number of bytes of the partition in the chunk with index `chunk_idx`
+    fn chunk_len(&self, chunk_idx: usize) -> usize {
+        DIRECT_IO_CHUNK_SIZE.min(self.len - chunk_idx * DIRECT_IO_CHUNK_SIZE)
+    }
+}
+
+/// A zero-initialized heap allocation with a given alignment
+struct AlignedBuffer {
+    ptr: NonNull<u8>,
+    len: usize,
+    layout: Layout,
+}
+
+impl AlignedBuffer {
+    fn new(len: usize, alignment: usize) -> Result<Self> {
+        // allocating 0 bytes is undefined behavior
+        let layout = Layout::from_size_align(len.max(1), alignment)?;
+        // SAFETY: Safe because `layout` has a non-zero size.
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        let ptr = NonNull::new(ptr).with_context(|| format!("Unable to allocate {} bytes", len))?;
+        Ok(Self { ptr, len, layout })
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        // SAFETY: Safe because the allocation is initialized and at least `self.len` bytes long.
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+// SAFETY: Safe because `AlignedBuffer` owns its allocation like a `Vec`, so it can be moved to another thread.
+unsafe impl Send for AlignedBuffer {}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        // SAFETY: Safe because `self.ptr` was allocated with `self.layout`.
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), self.layout) };
     }
 }
 
+impl ReadOnlyPartition {
+    /// Opens the partition without checking whether it is mounted. The shared lock only keeps out a concurrent
+    /// conversion by another instance of this program.
+    pub fn open<P: AsRef<Path>>(partition_path: P) -> Result<Self> {
+        let file = OpenOptions::new().read(true).open(partition_path)?;
+        file.try_lock_shared()
+            .context("The partition cannot be locked. Is it being converted by another process?")?;
+
+        let size = get_file_size(&file)?;
+        // SAFETY: The mapping is read-only; if the partition is mounted, its content may change while we read it
+        let mmap = unsafe { MmapOptions::new().len(size).map(&file)? };
+        Ok(Self { mmap })
+    }
+
+    pub fn len(&self) -> usize {
+        self.mmap.len()
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.mmap
+    }
+}
+
+fn get_file_size(file: &File) -> Result<usize> {
+    let metadata = file.metadata()?;
+    let filetype = metadata.file_type();
+    let len = if filetype.is_file() {
+        metadata.len()
+    } else if filetype.is_block_device() {
+        get_block_device_size(file)?
+    } else {
+        bail!("Expected path to a file or a block device")
+    };
+
+    len.try_into()
+        .with_context(|| format!("File size {} does not fit into a usize", len))
+}
+
+// declared in linux/fs.h
+// The type is declared as size_t due to a bug that cannot be fixed due to backwards compatibility. If I understand
+// correctly, passing u64 instead of usize should work even on 32bit systems, I haven't had a chance to test it
+// though. cfr. https://lists.debian.org/debian-glibc/2005/12/msg00069.html
+#[cfg(target_os = "linux")]
+ioctl_read!(block_device_size, 0x12, 114, u64);
+
+// declared in linux/userfaultfd.h, see `ChunkLoader`
+#[repr(C)]
+struct UffdioApi {
+    api: u64,
+    features: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+struct UffdioRange {
+    start: u64,
+    len: u64,
+}
+
+#[repr(C)]
+struct UffdioRegister {
+    range: UffdioRange,
+    mode: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+struct UffdioCopy {
+    dst: u64,
+    src: u64,
+    len: u64,
+    mode: u64,
+    /// the number of bytes copied, or a negated errno
+    copy: i64,
+}
+
+/// A `uffd_msg` with the fields of the `pagefault` variant of its union, which all other events fit into as well
+#[repr(C)]
+#[derive(Default)]
+struct UffdMsg {
+    event: u8,
+    reserved: [u8; 7],
+    flags: u64,
+    address: u64,
+    ptid: u32,
+    padding: u32,
+}
+
+ioctl_readwrite!(uffdio_api, 0xAA, 0x3F, UffdioApi);
+ioctl_readwrite!(uffdio_register, 0xAA, 0x00, UffdioRegister);
+ioctl_read!(uffdio_wake, 0xAA, 0x02, UffdioRange);
+ioctl_readwrite!(uffdio_copy, 0xAA, 0x03, UffdioCopy);
+
+/// PANICS: Panics if `file` is not a block device.
+#[cfg(target_os = "linux")]
+fn get_block_device_size(file: &File) -> Result<u64> {
+    assert!(file.metadata()?.file_type().is_block_device());
+    let mut size = 0;
+    // SAFETY: the nix crate provides no safety documentation, so we must just assume that this is safe.
+    unsafe {
+        block_device_size(file.as_raw_fd(), &mut size)?;
+    }
+    Ok(size)
+}
+
 #[cfg(test)]
 mod tests {
     use std::io::{self, Write};
@@ -102,7 +687,7 @@ mod tests {
     use tempfile::{tempdir, NamedTempFile};
 
     use super::*;
-    use crate::util::tests::backup_copy;
+    use crate::util::tests::{backup_copy, Mount};
 
     #[test]
     fn opens_file() {
@@ -117,6 +702,20 @@ mod tests {
         assert_eq!(part_content, content);
     }
 
+    #[test]
+    fn copy_on_write_leaves_file_unchanged() {
+        let content = rand::thread_rng().sample_iter(&Standard).take(4096).collect_vec();
+        let mut tmp_file = NamedTempFile::new().unwrap();
+        tmp_file.as_file_mut().write_all(&content).unwrap();
+
+        let mut partition = Partition::open_copy_on_write(tmp_file.path()).unwrap();
+        partition.as_mut_slice().fill(0);
+        partition.flush().unwrap();
+        assert!(partition.as_mut_slice().iter().all(|&byte| byte == 0));
+        drop(partition);
+        assert_eq!(std::fs::read(tmp_file.path()).unwrap(), content);
+    }
+
     #[test]
     #[ignore] // requires sudo or group membership in "disk"
     fn opens_block_device() {
@@ -177,6 +776,95 @@ mod tests {
         assert!(Partition::open(tmp_file.path()).is_err());
     }
 
+    #[test]
+    fn opens_file_read_only() {
+        const FILE_SIZE: usize = 6427;
+        let content = rand::thread_rng().sample_iter(&Standard).take(FILE_SIZE).collect_vec();
+        let mut tmp_file = NamedTempFile::new().unwrap();
+        tmp_file.as_file_mut().write_all(&content).unwrap();
+
+        let partition = ReadOnlyPartition::open(tmp_file.path()).unwrap();
+        assert_eq!(partition.len(), FILE_SIZE);
+        assert_eq!(partition.as_slice(), content);
+        // another read-only analysis may run at the same time, but not a conversion
+        assert!(ReadOnlyPartition::open(tmp_file.path()).is_ok());
+        assert!(Partition::open(tmp_file.path()).is_err());
+    }
+
+    #[test]
+    fn read_only_returns_err_if_file_locked() {
+        let mut tmp_file = NamedTempFile::new().unwrap();
+        tmp_file.write_all(&[0; 512]).unwrap();
+        let _partition = Partition::open(tmp_file.path()).unwrap();
+        assert!(ReadOnlyPartition::open(tmp_file.path()).is_err());
+    }
+
+    #[test]
+    fn direct_io_writes_back_modified_chunks() {
+        const FILE_SIZE: usize = 2 * DIRECT_IO_CHUNK_SIZE + 3 * DIRECT_IO_MIN_LEN;
+        let content = rand::thread_rng().sample_iter(&Standard).take(FILE_SIZE).collect_vec();
+        let mut tmp_file = NamedTempFile::new().unwrap();
+        tmp_file.as_file_mut().write_all(&content).unwrap();
+
+        let mut partition = BufferedPartition::load(DirectIoPartition::open(tmp_file.path()).unwrap()).unwrap();
+        assert_eq!(partition.len(), FILE_SIZE);
+        // SAFETY: Safe because `partition` has `FILE_SIZE` bytes and we don't access it otherwise during this borrow.
+        let part_content = unsafe { std::slice::from_raw_parts_mut(partition.as_mut_ptr(), FILE_SIZE) };
+        assert_eq!(part_content, content);
+        assert!(Partition::open(tmp_file.path()).is_err());
+
+        part_content[0] ^= 1;
+        part_content[FILE_SIZE - 1] ^= 1;
+        let mut expected = content;
+        expected[0] ^= 1;
+        expected[FILE_SIZE - 1] ^= 1;
+        assert_eq!(partition.write_back().unwrap(), DIRECT_IO_CHUNK_SIZE + 3 * DIRECT_IO_MIN_LEN);
+        drop(partition);
+        assert_eq!(std::fs::read(tmp_file.path()).unwrap(), expected);
+    }
+
+    #[test]
+    fn demand_paged_partition_loads_and_writes_back_accessed_chunks() {
+        const FILE_SIZE: usize = 2 * DIRECT_IO_CHUNK_SIZE + 3 * DIRECT_IO_MIN_LEN;
+        let content = rand::thread_rng().sample_iter(&Standard).take(FILE_SIZE).collect_vec();
+        let mut tmp_file = NamedTempFile::new().unwrap();
+        tmp_file.as_file_mut().write_all(&content).unwrap();
+
+        let mut partition = DemandPagedPartition::new(DirectIoPartition::open(tmp_file.path()).unwrap()).unwrap();
+        assert_eq!(partition.len(), FILE_SIZE);
+        // SAFETY: Safe because `partition` has `FILE_SIZE` bytes and we don't access it otherwise during this borrow.
+        let part_content = unsafe { std::slice::from_raw_parts_mut(partition.as_mut_ptr(), FILE_SIZE) };
+        assert_eq!(part_content[0], content[0]);
+        assert_eq!(part_content[FILE_SIZE - 1], content[FILE_SIZE - 1]);
+
+        part_content[0] ^= 1;
+        part_content[FILE_SIZE - 1] ^= 1;
+        let mut expected = content;
+        expected[0] ^= 1;
+        expected[FILE_SIZE - 1] ^= 1;
+        assert_eq!(partition.write_back().unwrap(), DIRECT_IO_CHUNK_SIZE + 3 * DIRECT_IO_MIN_LEN);
+        assert_eq!(partition.loaded_len(), DIRECT_IO_CHUNK_SIZE + 3 * DIRECT_IO_MIN_LEN);
+        drop(partition);
+        assert_eq!(std::fs::read(tmp_file.path()).unwrap(), expected);
+    }
+
+    #[test]
+    fn direct_io_returns_err_if_size_unaligned() {
+        let mut tmp_file = NamedTempFile::new().unwrap();
+        tmp_file.write_all(&[0; DIRECT_IO_MIN_LEN + 1]).unwrap();
+        assert!(DirectIoPartition::open(tmp_file.path()).is_err());
+    }
+
+    #[test]
+    #[ignore] // requires sudo
+    fn opens_mounted_file_read_only() {
+        const FAT_IMAGE_PATH: &str = "test/example_fat.img";
+        let image_copy = backup_copy(FAT_IMAGE_PATH).unwrap();
+        let mount_dir = tempdir().unwrap();
+        let _umount_on_drop = Mount::new(image_copy.path(), mount_dir.path()).unwrap();
+        assert!(ReadOnlyPartition::open(image_copy.path()).is_ok());
+    }
+
     #[test]
     #[ignore] // requires sudo
     fn returns_err_if_file_mounted() {
@@ -203,26 +891,6 @@ mod tests {
         err.chain().next().unwrap().downcast_ref::<io::Error>().unwrap().kind()
     }
 
-    struct Mount {
-        target: String,
-    }
-
-    impl Mount {
-        fn new(source: impl AsRef<Path>, target: impl AsRef<Path>) -> Result<Self> {
-            let source_str = source.as_ref().to_str().unwrap();
-            let target_str = target.as_ref().to_str().unwrap();
-            let mount_output = Command::new("mount").args([source_str, target_str]).output()?;
-            mount_output.status.exit_ok()?;
-            Ok(Self { target: target_str.to_string() })
-        }
-    }
-
-    impl Drop for Mount {
-        fn drop(&mut self) {
-            let _ = Command::new("umount").arg(&self.target).status();
-        }
-    }
-
     struct LoopDevice {
         path: PathBuf,
     }
@@ -0,0 +1,219 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::conflicts::{Conflicts, Prompter};
+use crate::conversion::{build_superblock, check_ext4_signature, convert_path, ConversionStats};
+use crate::error::ErrorCategory;
+use crate::estimate::SpaceEstimate;
+use crate::ext4::{BlockGroupCount, BlockGroupIdx, BlockSize, Overhead, SuperBlock};
+use crate::fat::{BootSector, FatFs};
+use crate::options::{ConversionOptions, UuidSource};
+use crate::partition::ReadOnlyPartition;
+use crate::util::FromUsize;
+
+/// A conversion that has been planned without modifying the partition. The plan contains everything that decides
+/// how the partition will be converted, so it can be saved, shown to the user and executed later. Executing it fails if
+/// the FAT filesystem has changed since it was planned, since the layout and the estimate may no longer apply.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ConversionPlan {
+    pub partition_path: String,
+    pub options: ConversionOptions,
+    pub layout: Ext4Layout,
+    pub estimate: SpaceEstimate,
+    /// the `FatFs::fingerprint` of the FAT filesystem when it was planned
+    pub fingerprint: u64,
+}
+
+/// The layout of the ext4 metadata, which the conversion keeps free of file data. Once the directory tree has been
+/// read, the conversion may shrink the inode tables to fit the files that are actually converted, and with
+/// `--allow-tight-fit` it may omit the last superblock backup, but it never needs more space than this.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Ext4Layout {
+    pub block_size: BlockSize,
+    pub block_count: u64,
+    pub block_group_count: BlockGroupCount,
+    pub inodes_per_group: u32,
+    /// the inode ratio the layout was created with, which is lower than the requested one if there are many files
+    pub inode_ratio: u32,
+    /// the block groups containing a backup of the superblock
+    pub backup_block_groups: Vec<BlockGroupIdx>,
+    pub bigalloc: bool,
+    pub overhead: Overhead,
+}
+
+impl ConversionPlan {
+    /// Plans the conversion of the partition at `partition_path` with `options`, only reading the partition.
+    pub fn new(partition_path: String, options: ConversionOptions) -> Result<Self> {
+        let partition = ReadOnlyPartition::open(&partition_path).context(ErrorCategory::Io)?;
+        let partition_bytes = partition.as_slice();
+        check_ext4_signature(partition_bytes, options.force_reconvert)?;
+        BootSector::from_bytes(partition_bytes).context(ErrorCategory::InvalidFilesystem)?;
+        let fat_fs = FatFs::from_slice(partition_bytes).context(ErrorCategory::InvalidFilesystem)?;
+
+        // the layout does not depend on the creation time, which is only resolved when the plan is executed, or on
+        // the UUID
+        let (superblock, inode_ratio) = build_superblock(
+            fat_fs.boot_sector(),
+            options.bigalloc,
+            options.ext4_params.inode_ratio,
+            fat_fs.file_count(),
+            0,
+            UuidSource::Random,
+        )?;
+        Ok(Self {
+            layout: Ext4Layout::new(&superblock, inode_ratio, fat_fs.boot_sector().fs_size()),
+            estimate: SpaceEstimate::new(&fat_fs, &superblock),
+            fingerprint: fat_fs.fingerprint().context(ErrorCategory::InvalidFilesystem)?,
+            partition_path,
+            options,
+        })
+    }
+
+    /// Looks for files that cannot be converted with `self.options` and asks `prompter` how to handle them, recording
+    /// the answers in `self.options` so that executing the plan is not interactive. Returns whether there were any.
+    pub fn resolve_conflicts(&mut self, prompter: &mut dyn Prompter) -> Result<bool> {
+        let partition = ReadOnlyPartition::open(&self.partition_path).context(ErrorCategory::Io)?;
+        let fat_fs = FatFs::from_slice(partition.as_slice()).context(ErrorCategory::InvalidFilesystem)?;
+        let conflicts = Conflicts::scan(&fat_fs).context(ErrorCategory::InvalidFilesystem)?;
+        if conflicts.is_empty() {
+            return Ok(false);
+        }
+        conflicts.resolve(&mut self.options, prompter)?;
+        Ok(true)
+    }
+
+    /// Checks that the FAT filesystem is still the one `self` was planned for, see `check`, and converts it with the
+    /// planned options. Returns the conversion's stats and duration.
+    pub fn execute(&self) -> Result<(ConversionStats, Duration)> {
+        self.check()?;
+        convert_path(&self.partition_path, &self.options)
+    }
+
+    pub fn load(path: &str) -> Result<Self> {
+        let file = File::open(path).with_context(|| format!("Unable to open {}", path))?;
+        serde_json::from_reader(BufReader::new(file)).with_context(|| format!("{} is not a valid plan", path))
+    }
+
+    pub fn save(&self, path: &str) -> Result<()> {
+        let file = File::create(path).with_context(|| format!("Unable to create {}", path))?;
+        serde_json::to_writer_pretty(BufWriter::new(file), self).with_context(|| format!("Unable to write {}", path))
+    }
+
+    /// Plans the conversion again and returns an error unless the result is the same, i.e. unless the FAT filesystem
+    /// is still the one `self` was planned for.
+    pub fn check(&self) -> Result<()> {
+        let replanned = Self::new(self.partition_path.clone(), self.options.clone())?;
+        if replanned.fingerprint != self.fingerprint {
+            bail!(
+                "The FAT filesystem on {} has been modified since the conversion was planned",
+                self.partition_path
+            );
+        }
+        if replanned != *self {
+            bail!(
+                "The plan for {} does not match the FAT filesystem on it, it may have been created by a different \
+                 version of ofs-convert-rs",
+                self.partition_path
+            );
+        }
+        Ok(())
+    }
+
+    pub fn print(&self) {
+        let layout = &self.layout;
+        println!(
+            "The ext4 filesystem will have {} blocks of {} bytes in {} block groups, with {} inodes per group (one \
+             per {} bytes) and superblock backups in the block groups {:?}",
+            layout.block_count,
+            layout.block_size,
+            layout.block_group_count,
+            layout.inodes_per_group,
+            layout.inode_ratio,
+            layout.backup_block_groups
+        );
+        println!(
+            "The ext4 metadata will occupy {} blocks ({} bytes)",
+            layout.overhead.total_block_count(),
+            layout.overhead.total_bytes()
+        );
+        self.estimate.print();
+    }
+}
+
+impl Ext4Layout {
+    fn new(superblock: &SuperBlock, inode_ratio: u32, fs_len: usize) -> Self {
+        Self {
+            block_size: superblock.block_size(),
+            block_count: u64::fromx(superblock.block_count_without_padding()),
+            block_group_count: superblock.block_group_count(),
+            inodes_per_group: superblock.s_inodes_per_group,
+            inode_ratio,
+            backup_block_groups: superblock.backup_bgs().collect(),
+            bigalloc: superblock.has_bigalloc(),
+            overhead: Overhead::from_superblock(superblock, fs_len),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Seek, Write};
+
+    use tempfile::NamedTempFile;
+
+    use super::*;
+    use crate::ext4::has_ext4_signature;
+    use crate::fat::{FatImageBuilder, TestFile};
+
+    fn write_image(file: &mut NamedTempFile, name: &str) {
+        let mut image = FatImageBuilder::new(32 * 1024 * 1024, 1024)
+            .build(&[TestFile::RegularFile { name: name.to_string(), size: 5000 }]);
+        file.rewind().unwrap();
+        file.write_all(image.as_mut_slice()).unwrap();
+        file.flush().unwrap();
+    }
+
+    #[test]
+    fn plan_detects_modified_filesystem() {
+        let mut image_file = NamedTempFile::new().unwrap();
+        write_image(&mut image_file, "file");
+        let image_path = image_file.path().to_str().unwrap().to_string();
+        let plan = ConversionPlan::new(image_path, ConversionOptions::default()).unwrap();
+        assert_eq!(plan.layout.backup_block_groups, [1, 3]);
+
+        let plan_file = NamedTempFile::new().unwrap();
+        let plan_path = plan_file.path().to_str().unwrap();
+        plan.save(plan_path).unwrap();
+        let loaded = ConversionPlan::load(plan_path).unwrap();
+        assert_eq!(loaded, plan);
+        loaded.check().unwrap();
+
+        write_image(&mut image_file, "renamed");
+        let error = loaded.check().unwrap_err();
+        assert!(
+            error.to_string().contains("has been modified since the conversion was planned"),
+            "{:#}",
+            error
+        );
+    }
+
+    #[test]
+    fn executes_plan() {
+        let mut image_file = NamedTempFile::new().unwrap();
+        write_image(&mut image_file, "file");
+        let image_path = image_file.path().to_str().unwrap().to_string();
+        // fsck.fat may not be installed
+        let options = ConversionOptions { force: true, ..ConversionOptions::default() };
+        let plan = ConversionPlan::new(image_path.clone(), options).unwrap();
+
+        let (stats, _) = plan.execute().unwrap();
+        assert_eq!(stats.fs_stats.regular_file_count, 1);
+        assert!(has_ext4_signature(&std::fs::read(&image_path).unwrap()));
+        // the plan no longer applies to the converted partition
+        assert!(plan.execute().is_err());
+    }
+}
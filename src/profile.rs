@@ -0,0 +1,85 @@
+use anyhow::{bail, Context, Result};
+use clap::ArgEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::ext4::{DEFAULT_INODE_RATIO, MAX_INODE_RATIO, MIN_INODE_RATIO};
+
+/// The highest percentage of blocks that can be reserved for root, like in mke2fs
+const MAX_RESERVED_PERCENT: u8 = 50;
+
+/// The parameters of the ext4 filesystem that can be chosen independently of the FAT filesystem's geometry. The block
+/// size is determined by the FAT cluster size (see `--bigalloc`), and the converter creates neither a journal nor
+/// flex_bg groups, so they are not part of the parameters.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Ext4Params {
+    /// the number of bytes per inode
+    pub inode_ratio: u32,
+    /// the percentage of blocks reserved for root
+    pub reserved_percent: u8,
+}
+
+impl Default for Ext4Params {
+    fn default() -> Self {
+        Self {
+            inode_ratio: DEFAULT_INODE_RATIO,
+            reserved_percent: 0,
+        }
+    }
+}
+
+/// A named set of `Ext4Params` for a typical use of the converted filesystem
+#[derive(Clone, Copy, Debug, PartialEq, ArgEnum)]
+pub enum Profile {
+    /// removable media that is mostly written by a single user: no reserved blocks
+    Sdcard,
+    /// a system volume: blocks reserved for root like mke2fs does by default
+    Server,
+    /// mostly large files that are rarely written: fewer inodes and no reserved blocks
+    Archive,
+}
+
+impl Profile {
+    pub fn ext4_params(self) -> Ext4Params {
+        match self {
+            Self::Sdcard => Ext4Params::default(),
+            Self::Server => Ext4Params { reserved_percent: 5, ..Ext4Params::default() },
+            Self::Archive => Ext4Params { inode_ratio: 65536, ..Ext4Params::default() },
+        }
+    }
+}
+
+pub fn parse_inode_ratio(value: &str) -> Result<u32> {
+    let inode_ratio: u32 = value.parse().context("Expected a number of bytes")?;
+    if !inode_ratio.is_power_of_two() || !(MIN_INODE_RATIO..=MAX_INODE_RATIO).contains(&inode_ratio) {
+        bail!("Expected a power of two between {} and {}", MIN_INODE_RATIO, MAX_INODE_RATIO);
+    }
+    Ok(inode_ratio)
+}
+
+pub fn parse_reserved_percent(value: &str) -> Result<u8> {
+    let reserved_percent: u8 = value.parse().context("Expected a whole percentage")?;
+    if reserved_percent > MAX_RESERVED_PERCENT {
+        bail!("Expected at most {} percent", MAX_RESERVED_PERCENT);
+    }
+    Ok(reserved_percent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_profiles_and_params() {
+        for name in ["sdcard", "server", "archive"] {
+            assert!(Profile::from_str(name, false).is_ok());
+        }
+        assert!(Profile::from_str("desktop", false).is_err());
+        assert_eq!(Profile::Server.ext4_params().reserved_percent, 5);
+
+        assert_eq!(parse_inode_ratio("65536").unwrap(), 65536);
+        assert!(parse_inode_ratio("10000").is_err());
+        assert!(parse_inode_ratio("512").is_err());
+        assert_eq!(parse_reserved_percent("50").unwrap(), 50);
+        assert!(parse_reserved_percent("51").is_err());
+    }
+}
@@ -0,0 +1,155 @@
+use std::cell::{Cell, RefCell};
+use std::io::{self, Write};
+use std::os::unix::io::RawFd;
+
+use anyhow::{Context, Result};
+use nix::fcntl::{fcntl, FcntlArg};
+use serde::Serialize;
+
+/// A phase of the conversion, in the order in which they run
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Phase {
+    /// reading the FAT directory tree into the archive, relocating file data as needed
+    Serialize,
+    /// checking that the ext4 filesystem fits, without writing it
+    DryRun,
+    /// writing the inodes and directories of the ext4 filesystem
+    Write,
+    /// writing the block group metadata and erasing the FAT signatures
+    Finalize,
+    Done,
+}
+
+/// Receives the progress of a conversion: the start of every phase, and every file that the phases which process the
+/// files one after another have reached.
+pub trait Progress {
+    fn start_phase(&self, phase: Phase);
+
+    /// The current phase has reached `path`, which is the `done`th of `total` files.
+    fn advance(&self, path: &str, done: usize, total: usize);
+}
+
+/// Writes the progress as newline-delimited JSON objects with the phase, the percentage of the phase that is complete
+/// and the path of the current file, if any, e.g. `{"phase":"write","percent":42,"path":"/DCIM/1.JPG"}`. To keep the
+/// stream short, an object is only written at the start of a phase and whenever the percentage changes. An error
+/// while writing, e.g. because the reading front-end has exited, does not stop the conversion, but ends the stream.
+pub struct JsonProgress {
+    writer: RefCell<Box<dyn Write>>,
+    phase: Cell<Phase>,
+    percent: Cell<u8>,
+    failed: Cell<bool>,
+}
+
+#[derive(Serialize)]
+struct ProgressEvent<'p> {
+    phase: Phase,
+    percent: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<&'p str>,
+}
+
+impl JsonProgress {
+    pub fn new<W: Write + 'static>(writer: W) -> Self {
+        Self {
+            writer: RefCell::new(Box::new(writer)),
+            phase: Cell::new(Phase::Serialize),
+            percent: Cell::new(0),
+            failed: Cell::new(false),
+        }
+    }
+
+    /// Writes to the open file descriptor `fd`, which is not closed afterwards.
+    pub fn to_fd(fd: RawFd) -> Result<Self> {
+        fcntl(fd, FcntlArg::F_GETFD).with_context(|| format!("{} is not an open file descriptor", fd))?;
+        Ok(Self::new(FdWriter(fd)))
+    }
+
+    fn write_event(&self, path: Option<&str>) {
+        if self.failed.get() {
+            return;
+        }
+        let event = ProgressEvent {
+            phase: self.phase.get(),
+            percent: self.percent.get(),
+            path,
+        };
+        let mut line = serde_json::to_vec(&event).expect("The event consists of strings and integers");
+        line.push(b'\n');
+        let mut writer = self.writer.borrow_mut();
+        if writer.write_all(&line).and_then(|_| writer.flush()).is_err() {
+            self.failed.set(true);
+        }
+    }
+}
+
+impl Progress for JsonProgress {
+    fn start_phase(&self, phase: Phase) {
+        self.phase.set(phase);
+        self.percent.set(if phase == Phase::Done { 100 } else { 0 });
+        self.write_event(None);
+    }
+
+    fn advance(&self, path: &str, done: usize, total: usize) {
+        // the total may be an estimate, e.g. if files are excluded, so the percentage must not exceed 100
+        let percent = (done.min(total) * 100).checked_div(total).unwrap_or(100);
+        let percent = u8::try_from(percent).expect("The percentage is at most 100");
+        if percent != self.percent.get() {
+            self.percent.set(percent);
+            self.write_event(Some(path));
+        }
+    }
+}
+
+/// Writes to a file descriptor without taking ownership of it, unlike `File::from_raw_fd`.
+struct FdWriter(RawFd);
+
+impl Write for FdWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        nix::unistd::write(self.0, buf).map_err(io::Error::from)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+
+    /// A writer whose bytes can be inspected after it has been moved into a `JsonProgress`
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn writes_an_event_per_percent() {
+        let buffer = SharedBuffer::default();
+        let progress = JsonProgress::new(buffer.clone());
+        progress.start_phase(Phase::Write);
+        for done in 1..=400 {
+            progress.advance(&format!("/{}", done), done, 200);
+        }
+        progress.start_phase(Phase::Done);
+
+        let output = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+        let lines: Vec<_> = output.lines().collect();
+        assert_eq!(lines.len(), 102);
+        assert_eq!(lines[0], r#"{"phase":"write","percent":0}"#);
+        assert_eq!(lines[1], r#"{"phase":"write","percent":1,"path":"/2"}"#);
+        assert_eq!(lines[100], r#"{"phase":"write","percent":100,"path":"/200"}"#);
+        assert_eq!(lines[101], r#"{"phase":"done","percent":100}"#);
+    }
+}
@@ -1,5 +1,6 @@
-use std::iter::{FromIterator, IntoIterator};
-use std::ops::Range;
+use alloc::vec::Vec;
+use core::iter::{FromIterator, IntoIterator};
+use core::ops::Range;
 
 
 /// A set of non-overlapping ranges
@@ -17,6 +18,18 @@ pub enum NotCoveredRange<T> {
     Unbounded(T),
 }
 
+/// A cursor over the gaps between the ranges of a `Ranges` that only moves forward. Unlike `Ranges::next_not_covered`,
+/// which binary searches from scratch on every call, it remembers the first range that may still cover its position,
+/// so walking over all gaps takes amortized constant time per gap.
+/// The cursor does not borrow the `Ranges` it walks over, so it can be stored alongside them; every method must be
+/// passed the same `Ranges` that the cursor was created for, and the cursor must be recreated after inserting a range.
+#[derive(Clone, Copy, Debug)]
+pub struct FreeRangeCursor<Idx> {
+    position: Idx,
+    /// the index of the first range that ends after `position`, or `ranges.len()` if there is none
+    candidate_idx: usize,
+}
+
 impl<Idx: Ord + Copy> Ranges<Idx> {
     pub fn new() -> Self {
         Self { ranges: Vec::new() }
@@ -70,17 +83,7 @@ impl<Idx: Ord + Copy> Ranges<Idx> {
     /// Returns the first range of non-covered items starting at or after `x`, whose end can either
     /// be bounded or unbounded.
     pub fn next_not_covered(&self, x: Idx) -> NotCoveredRange<Idx> {
-        let overlap_candidate_idx = self.first_overlap_candidate(&(x..x));
-        if overlap_candidate_idx == self.ranges.len() {
-            return NotCoveredRange::Unbounded(x);
-        }
-
-        let overlap_candidate = self.ranges[overlap_candidate_idx].clone();
-        if overlap_candidate.start > x {
-            NotCoveredRange::Bounded(x..overlap_candidate.start)
-        } else {
-            self.next_not_covered(overlap_candidate.end)
-        }
+        FreeRangeCursor::new(self, x).next_free(self)
     }
 
     /// Splits up a range into a Vec of subranges and a bool. Either every element in a subrange is contained in a range
@@ -136,7 +139,7 @@ impl<Idx: Ord + Copy> Ranges<Idx> {
 
 impl<'a, Idx: Ord + Copy> IntoIterator for &'a Ranges<Idx> {
     type Item = &'a Range<Idx>;
-    type IntoIter = std::slice::Iter<'a, Range<Idx>>;
+    type IntoIter = core::slice::Iter<'a, Range<Idx>>;
     fn into_iter(self) -> Self::IntoIter {
         (&self.ranges).iter()
     }
@@ -159,22 +162,60 @@ where Range<Idx>: ExactSizeIterator
     /// Returns the number of non-covered items within `within_range`.
     /// PANICS: Panics if the count would overflow `usize`.
     pub fn free_element_count(&self, within_range: Range<Idx>) -> usize {
-        let mut current_idx = within_range.start;
+        let mut cursor = FreeRangeCursor::new(self, within_range.start);
         let mut count = 0usize;
-        while current_idx < within_range.end {
-            let range = match self.next_not_covered(current_idx) {
+        while cursor.position() < within_range.end {
+            let range = match cursor.next_free(self) {
                 NotCoveredRange::Bounded(Range { start, end }) => start..end.min(within_range.end),
                 NotCoveredRange::Unbounded(start) => start..within_range.end,
             };
             count = count
                 .checked_add(range.len())
                 .expect("Number of free elements in range overflows usize");
-            current_idx = range.end;
+            cursor.advance_to(self, range.end);
         }
         count
     }
 }
 
+impl<Idx: Ord + Copy> FreeRangeCursor<Idx> {
+    pub fn new(ranges: &Ranges<Idx>, position: Idx) -> Self {
+        Self {
+            position,
+            candidate_idx: ranges.first_overlap_candidate(&(position..position)),
+        }
+    }
+
+    pub fn position(&self) -> Idx {
+        self.position
+    }
+
+    /// Moves the cursor forward to `position`. Does nothing if the cursor is already past `position`.
+    pub fn advance_to(&mut self, ranges: &Ranges<Idx>, position: Idx) {
+        if position <= self.position {
+            return;
+        }
+        self.position = position;
+        while matches!(ranges.ranges.get(self.candidate_idx), Some(candidate) if candidate.end <= position) {
+            self.candidate_idx += 1;
+        }
+    }
+
+    /// Returns the first range of non-covered items starting at or after the cursor's position and moves the cursor to
+    /// the start of that range. Like `Ranges::next_not_covered`, the range's end can either be bounded or unbounded.
+    pub fn next_free(&mut self, ranges: &Ranges<Idx>) -> NotCoveredRange<Idx> {
+        while let Some(candidate) = ranges.ranges.get(self.candidate_idx) {
+            if candidate.start > self.position {
+                return NotCoveredRange::Bounded(self.position..candidate.start);
+            }
+            // adjacent ranges are merged on insertion, so the next candidate starts after `candidate.end`
+            self.position = candidate.end;
+            self.candidate_idx += 1;
+        }
+        NotCoveredRange::Unbounded(self.position)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -258,6 +299,38 @@ mod tests {
         assert_eq!(ranges.next_not_covered(5), NotCoveredRange::Unbounded(5));
     }
 
+    #[test]
+    fn cursor_walks_gaps() {
+        let ranges = Ranges { ranges: vec![0..2, 6..9, 11..14] };
+        let mut cursor = FreeRangeCursor::new(&ranges, 0);
+        assert_eq!(cursor.next_free(&ranges), NotCoveredRange::Bounded(2..6));
+        cursor.advance_to(&ranges, 4);
+        assert_eq!(cursor.next_free(&ranges), NotCoveredRange::Bounded(4..6));
+        cursor.advance_to(&ranges, 6);
+        assert_eq!(cursor.next_free(&ranges), NotCoveredRange::Bounded(9..11));
+        cursor.advance_to(&ranges, 11);
+        assert_eq!(cursor.next_free(&ranges), NotCoveredRange::Unbounded(14));
+        assert_eq!(cursor.position(), 14);
+    }
+
+    #[test]
+    fn cursor_ignores_backward_moves() {
+        let ranges = Ranges { ranges: vec![0..2, 6..9, 11..14] };
+        let mut cursor = FreeRangeCursor::new(&ranges, 10);
+        cursor.advance_to(&ranges, 3);
+        assert_eq!(cursor.next_free(&ranges), NotCoveredRange::Bounded(10..11));
+    }
+
+    #[test]
+    fn cursor_matches_next_not_covered() {
+        let ranges = Ranges { ranges: vec![1..2, 3..5, 6..9, 11..14, 20..21] };
+        let mut cursor = FreeRangeCursor::new(&ranges, 0);
+        for x in 0..25 {
+            cursor.advance_to(&ranges, x);
+            assert_eq!(cursor.next_free(&ranges), ranges.next_not_covered(x));
+        }
+    }
+
     #[test]
     fn split_overlapping_short() {
         let ranges = Ranges { ranges: vec![0..2, 6..9, 11..14] };
@@ -0,0 +1,40 @@
+use std::io::{self, Write};
+
+use serde::{Deserialize, Serialize};
+
+/// A file whose FAT archive attribute is set. Backup tools set this attribute when a file is modified and clear it
+/// when the file has been backed up. ext4 has no equivalent, so these files are listed in a report instead.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ArchiveBitFile {
+    /// the path of the file, starting with '/' at the root of the FAT filesystem
+    pub path: String,
+    /// the modification time as a Unix timestamp
+    pub mod_time: u32,
+}
+
+impl ArchiveBitFile {
+    /// Writes one line per file to `writer`, consisting of its modification time and its path, separated by a tab.
+    /// FAT file names cannot contain control characters, so a path never contains a tab or a newline.
+    pub fn write_list<W: Write>(files: &[Self], mut writer: W) -> io::Result<()> {
+        for file in files {
+            writeln!(writer, "{}\t{}", file.mod_time, file.path)?;
+        }
+        writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_one_line_per_file() {
+        let files = [
+            ArchiveBitFile { path: "/a b".to_string(), mod_time: 1_577_836_800 },
+            ArchiveBitFile { path: "/dir/c".to_string(), mod_time: 0 },
+        ];
+        let mut list = Vec::new();
+        ArchiveBitFile::write_list(&files, &mut list).unwrap();
+        assert_eq!(String::from_utf8(list).unwrap(), "1577836800\t/a b\n0\t/dir/c\n");
+    }
+}
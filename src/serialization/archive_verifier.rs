@@ -0,0 +1,309 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::ops::Range;
+use std::rc::Rc;
+
+use anyhow::{anyhow, bail, Result};
+use num::Integer;
+
+use crate::fat::{ClusterIdx, FatFile, FatFs, FatTableIndex, ROOT_FAT_IDX};
+use crate::ranges::Ranges;
+use crate::serialization::{
+    make_regular_file, DentryRepresentation, Deserializer, DeserializerInternals, DirectoryWriter, Reader,
+    TruncatedFile,
+};
+use crate::skeleton::{Skeleton, SkeletonDir};
+
+
+pub type ArchiveVerifier<'a, 'f> = Deserializer<'a, ArchiveVerifierInternals<'a, 'f>>;
+
+/// Reads back the serialized directory tree and compares every record to the FAT dentry it was serialized from, which
+/// it finds by walking the FAT filesystem again. This catches a corrupted archive before the conversion starts
+/// overwriting the FAT filesystem. The comparison covers:
+/// - Names (after the renames by `ConversionPolicy`s, e.g. the truncation of long names)
+/// - Timestamps, sizes (after the truncation of files with short cluster chains) and file types
+/// - The number of clusters that the size of every regular file covers, which relocation must not change
+/// - Data ranges overlapping the clusters reserved for ext4 metadata
+///
+/// Files that are in the FAT filesystem but not in the archive are assumed to have been excluded by a policy or an op.
+/// Ops other than exclusion and symlink conversion are not accounted for, so the verification fails if an op renames
+/// files or changes their dentries. The owners, permissions and extended attributes decided by a policy are not
+/// compared, since FAT has no equivalent. Symlink targets are not compared, since they are not stored in the FAT
+/// dentry. Files with invalid attributes are compared as regular files, see `InvalidAttributesPolicy::RegularFile`.
+impl<'a, 'f> ArchiveVerifier<'a, 'f> {
+    pub fn verify(
+        reader: Reader<'a>,
+        fat_fs: &'f FatFs<'f>,
+        renames: HashMap<String, String>,
+        forbidden_ranges: &'f Ranges<ClusterIdx>,
+    ) -> Result<()> {
+        let mut instance = Self {
+            internals: ArchiveVerifierInternals {
+                reader,
+                fat_fs,
+                forbidden_ranges,
+                renames,
+                regular_file_count: 0,
+            },
+            _lifetime: PhantomData,
+        };
+        instance.deserialize_directory_tree()
+    }
+}
+
+pub struct ArchiveVerifierInternals<'a, 'f> {
+    reader: Reader<'a>,
+    fat_fs: &'f FatFs<'f>,
+    forbidden_ranges: &'f Ranges<ClusterIdx>,
+    /// maps the FAT paths of the files renamed by policies to their new names
+    renames: HashMap<String, String>,
+    /// the number of regular files verified so far, which hard links must refer to
+    regular_file_count: usize,
+}
+
+impl<'a, 'f> DeserializerInternals<'a> for ArchiveVerifierInternals<'a, 'f> {
+    type D = VerifiedDirectory;
+
+    fn read_next<T: Any>(&mut self) -> Vec<T> {
+        self.reader.next::<T>()
+    }
+
+    /// The skeleton is not part of the archive, so it is not verified.
+    fn skeleton(&self) -> Rc<Skeleton> {
+        Rc::default()
+    }
+
+    fn build_skeleton_dir(&mut self, _dir: &SkeletonDir, _parent: &mut VerifiedDirectory) -> Result<VerifiedDirectory> {
+        unreachable!("The archive is verified without a skeleton")
+    }
+
+    fn build_root(&mut self) -> Result<VerifiedDirectory> {
+        // SAFETY: safe because `ROOT_FAT_IDX` belongs to the root directory
+        Ok(unsafe { self.read_directory(ROOT_FAT_IDX, String::new()) })
+    }
+
+    fn deserialize_directory(
+        &mut self,
+        dentry: DentryRepresentation,
+        name: String,
+        _xattrs: Vec<u8>,
+        parent_directory_writer: &mut VerifiedDirectory,
+    ) -> Result<VerifiedDirectory> {
+        let (source, path) = parent_directory_writer.take_source(&name)?;
+        if !source.dentry.is_dir() {
+            bail!("{} was serialized as a directory, but it is not one", path);
+        }
+        compare_dentries(&path, dentry, DentryRepresentation::from(source.dentry)?)?;
+        // SAFETY: safe because `source` is a directory
+        Ok(unsafe { self.read_directory(source.dentry.first_fat_index(), path) })
+    }
+
+    fn deserialize_regular_file(
+        &mut self,
+        dentry: DentryRepresentation,
+        name: String,
+        _xattrs: Vec<u8>,
+        data_ranges: Vec<Range<ClusterIdx>>,
+        parent_directory_writer: &mut VerifiedDirectory,
+    ) -> Result<()> {
+        let (mut source, path) = parent_directory_writer.take_source(&name)?;
+        make_regular_file_if_invalid(&mut source, self.fat_fs.cluster_size());
+        if source.dentry.is_dir() {
+            bail!("{} was serialized as a regular file, but it is a directory", path);
+        }
+        TruncatedFile::normalize(&mut source, &parent_directory_writer.path, self.fat_fs.cluster_size());
+        compare_dentries(&path, dentry, DentryRepresentation::from(source.dentry)?)?;
+
+        let chain_cluster_count: u32 = source
+            .data_ranges
+            .iter()
+            .map(|range| u32::from(*range.end()) - u32::from(*range.start()) + 1)
+            .sum();
+        // the relocation leaves out the clusters of a longer cluster chain, see `FatTreeSerializer::relocate`
+        let source_cluster_count =
+            chain_cluster_count.min(source.dentry.file_size.div_ceil(&self.fat_fs.cluster_size()));
+        self.regular_file_count += 1;
+        let cluster_count: u32 = data_ranges.iter().map(|range| range.end - range.start).sum();
+        if cluster_count != source_cluster_count {
+            bail!(
+                "{} was serialized with {} clusters, but its size covers {}",
+                path,
+                cluster_count,
+                source_cluster_count
+            );
+        }
+        for range in data_ranges {
+            if self
+                .forbidden_ranges
+                .split_overlapping(range.clone())
+                .iter()
+                .any(|(_, forbidden)| *forbidden)
+            {
+                bail!(
+                    "{} was serialized with the clusters {:?}, which will be overwritten by ext4 metadata",
+                    path,
+                    range
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn deserialize_symlink(
+        &mut self,
+        dentry: DentryRepresentation,
+        name: String,
+        _xattrs: Vec<u8>,
+        _target: String,
+        parent_directory_writer: &mut VerifiedDirectory,
+    ) -> Result<()> {
+        let (mut source, path) = parent_directory_writer.take_source(&name)?;
+        make_regular_file_if_invalid(&mut source, self.fat_fs.cluster_size());
+        if source.dentry.is_dir() {
+            bail!("{} was serialized as a symlink, but it is a directory", path);
+        }
+        compare_dentries(&path, dentry, DentryRepresentation::from(source.dentry)?)
+    }
+
+    /// The content is not compared to that of the regular file the hard link refers to, which `HardLinkFolder` has
+    /// done already.
+    fn deserialize_hard_link(
+        &mut self,
+        dentry: DentryRepresentation,
+        name: String,
+        regular_file_idx: usize,
+        parent_directory_writer: &mut VerifiedDirectory,
+    ) -> Result<()> {
+        let (mut source, path) = parent_directory_writer.take_source(&name)?;
+        make_regular_file_if_invalid(&mut source, self.fat_fs.cluster_size());
+        if source.dentry.is_dir() {
+            bail!("{} was serialized as a hard link, but it is a directory", path);
+        }
+        if regular_file_idx >= self.regular_file_count {
+            bail!(
+                "{} was serialized as a hard link to regular file {}, but only {} precede it",
+                path,
+                regular_file_idx,
+                self.regular_file_count
+            );
+        }
+        TruncatedFile::normalize(&mut source, &parent_directory_writer.path, self.fat_fs.cluster_size());
+        compare_dentries(&path, dentry, DentryRepresentation::from(source.dentry)?)
+    }
+}
+
+impl<'a, 'f> ArchiveVerifierInternals<'a, 'f> {
+    /// SAFETY: safe if `first_fat_idx` points to a cluster belonging to a directory
+    unsafe fn read_directory(&self, first_fat_idx: FatTableIndex, path: String) -> VerifiedDirectory {
+        // SAFETY: safe because `first_fat_index` belongs to a directory
+        let children = unsafe { self.fat_fs.dir_content_iter(first_fat_idx) }
+            .map(|file| {
+                let name = self
+                    .renames
+                    .get(&format!("{}/{}", path, file.name))
+                    .cloned()
+                    .unwrap_or_else(|| file.name.clone());
+                (name, file)
+            })
+            .collect();
+        VerifiedDirectory { path, children }
+    }
+}
+
+/// Turns `source` into a regular file if its attributes are invalid, since it can only have been serialized because of
+/// `InvalidAttributesPolicy::RegularFile`, which did the same.
+fn make_regular_file_if_invalid(source: &mut FatFile, cluster_size: u32) {
+    if source.dentry.attribute_problem().is_some() {
+        make_regular_file(source, cluster_size);
+    }
+}
+
+/// Compares `serialized` to `source` field by field. The error names the fields that differ, since a timestamp that
+/// differs by a fraction of a second is easily overlooked in the complete dentries.
+fn compare_dentries(path: &str, serialized: DentryRepresentation, source: DentryRepresentation) -> Result<()> {
+    let fields = [
+        ("access time", serialized.access_time == source.access_time),
+        ("creation time", serialized.create_time == source.create_time),
+        (
+            "creation time's fraction of a second",
+            serialized.create_time_ns == source.create_time_ns,
+        ),
+        ("modification time", serialized.mod_time == source.mod_time),
+        ("size", serialized.file_size == source.file_size),
+        ("directory flag", serialized.is_dir == source.is_dir),
+        ("read-only flag", serialized.is_read_only == source.is_read_only),
+    ];
+    let differing_fields: Vec<_> = fields
+        .iter()
+        .filter(|(_, is_equal)| !is_equal)
+        .map(|(field, _)| *field)
+        .collect();
+    if !differing_fields.is_empty() {
+        bail!(
+            "The serialized dentry of {} is {:?}, but the FAT dentry is {:?}; they differ in the {}",
+            path,
+            serialized,
+            source,
+            differing_fields.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// A directory of the FAT filesystem whose children have not all been verified yet
+pub struct VerifiedDirectory {
+    /// the path of the directory, which is empty for the root directory
+    path: String,
+    /// the children that have not been verified yet, by the name they were serialized with
+    children: HashMap<String, FatFile>,
+}
+
+impl VerifiedDirectory {
+    /// Returns the child that was serialized as `name` along with its path. Every child can only be taken once, so a
+    /// file that was serialized twice is an error.
+    fn take_source(&mut self, name: &str) -> Result<(FatFile, String)> {
+        let path = format!("{}/{}", self.path, name);
+        let source = self
+            .children
+            .remove(name)
+            .ok_or_else(|| anyhow!("{} was serialized, but it is not in the FAT filesystem", path))?;
+        Ok((source, path))
+    }
+}
+
+impl DirectoryWriter for VerifiedDirectory {
+    fn finalize(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ext4::InodeOverrides;
+
+    #[test]
+    fn names_differing_fields() {
+        let source = DentryRepresentation {
+            access_time: 1_577_836_800,
+            create_time: 1_577_836_800,
+            create_time_ns: 500_000_000,
+            mod_time: 1_577_836_800,
+            file_size: 42,
+            is_dir: false,
+            is_read_only: false,
+            overrides: InodeOverrides::default(),
+            has_xattrs: false,
+        };
+        compare_dentries("/file", source, source).unwrap();
+
+        let serialized = DentryRepresentation { create_time_ns: 0, file_size: 41, ..source };
+        let error = compare_dentries("/file", serialized, source).unwrap_err().to_string();
+        assert!(
+            error.ends_with("they differ in the creation time's fraction of a second, size"),
+            "{}",
+            error
+        );
+    }
+}
@@ -1,19 +1,26 @@
 use anyhow::Result;
 
+use crate::ext4::InodeOverrides;
 use crate::fat::FatDentry;
 type Timestamp = u32;
 
 /// A slimmed down representation of the relevant components of a FAT dentry for serialization
 /// This excludes the file name and the file's data ranges: since they have variable length,
 /// they are treated separately.
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct DentryRepresentation {
     pub access_time: Timestamp,
     pub create_time: Timestamp,
+    /// the fraction of a second of `create_time` in nanoseconds
+    pub create_time_ns: u32,
     pub mod_time: Timestamp,
     pub file_size: u32,
     pub is_dir: bool,
     pub is_read_only: bool,
+    /// what a `ConversionPolicy` decided instead of the defaults, which FAT has no equivalent for
+    pub overrides: InodeOverrides,
+    /// whether the name is followed by the extended attributes that a `ConversionPolicy` decided, see `FileType`
+    pub has_xattrs: bool,
 }
 
 impl DentryRepresentation {
@@ -21,10 +28,13 @@ impl DentryRepresentation {
         Ok(Self {
             access_time: dentry.access_time_as_unix()?,
             create_time: dentry.create_time_as_unix()?,
+            create_time_ns: dentry.create_time_nanoseconds(),
             mod_time: dentry.modify_time_as_unix()?,
             file_size: dentry.file_size,
             is_dir: dentry.is_dir(),
             is_read_only: dentry.is_read_only(),
+            overrides: InodeOverrides::default(),
+            has_xattrs: false,
         })
     }
 }
@@ -0,0 +1,26 @@
+use clap::ArgEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::fat::FatFile;
+
+/// The order in which the dentries of a directory are written. The converter creates linear ext4 directories without
+/// an htree index, which `readdir` and `telldir` traverse in the order of their dentries, so the order is visible to
+/// every program listing the directory.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ArgEnum, Serialize, Deserialize)]
+pub enum DentryOrder {
+    /// the order of the dentries in the FAT directory
+    #[default]
+    Fat,
+    /// sorted by name, comparing the UTF-8 bytes like `LC_ALL=C ls`
+    Sorted,
+}
+
+impl DentryOrder {
+    /// Brings the files of a directory into this order. The files must be in the order of their FAT dentries.
+    pub fn apply(self, files: &mut [FatFile]) {
+        match self {
+            Self::Fat => (),
+            Self::Sorted => files.sort_by(|a, b| a.name.cmp(&b.name)),
+        }
+    }
+}
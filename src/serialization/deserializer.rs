@@ -1,14 +1,19 @@
 use std::any::Any;
 use std::marker::PhantomData;
 use std::ops::Range;
+use std::rc::Rc;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 
 use crate::fat::ClusterIdx;
 use crate::serialization::{DentryRepresentation, FileType};
+use crate::skeleton::{Skeleton, SkeletonDir};
 
 
-pub trait DirectoryWriter {}
+pub trait DirectoryWriter {
+    /// Completes the directory after all of its children have been deserialized.
+    fn finalize(self) -> Result<()>;
+}
 
 
 pub struct Deserializer<'a, I: DeserializerInternals<'a>> {
@@ -18,12 +23,11 @@ pub struct Deserializer<'a, I: DeserializerInternals<'a>> {
 
 impl<'a, I: DeserializerInternals<'a>> Deserializer<'a, I> {
     pub fn deserialize_directory_tree(&mut self) -> Result<()> {
+        let skeleton = self.internals.skeleton();
         let mut root_directory_writer = self.internals.build_root()?;
-
-        for _ in 0..self.internals.read_root_child_count() {
-            self.internals.deserialize_file(&mut root_directory_writer)?;
-        }
-        Ok(())
+        self.internals
+            .deserialize_children(&mut root_directory_writer, "", &skeleton.dirs)?;
+        root_directory_writer.finalize()
     }
 }
 
@@ -32,10 +36,13 @@ pub trait DeserializerInternals<'a> {
 
     fn build_root(&mut self) -> Result<Self::D>;
 
+    /// `xattrs` are the extended attributes of the file encoded by `encode_xattrs`, or empty if it has none, like for
+    /// `deserialize_regular_file` and `deserialize_symlink`.
     fn deserialize_directory(
         &mut self,
         dentry: DentryRepresentation,
         name: String,
+        xattrs: Vec<u8>,
         parent_directory_writer: &mut Self::D,
     ) -> Result<Self::D>;
 
@@ -43,39 +50,121 @@ pub trait DeserializerInternals<'a> {
         &mut self,
         dentry: DentryRepresentation,
         name: String,
+        xattrs: Vec<u8>,
         data_ranges: Vec<Range<ClusterIdx>>,
         parent_directory_writer: &mut Self::D,
     ) -> Result<()>;
 
+    fn deserialize_symlink(
+        &mut self,
+        dentry: DentryRepresentation,
+        name: String,
+        xattrs: Vec<u8>,
+        target: String,
+        parent_directory_writer: &mut Self::D,
+    ) -> Result<()>;
+
+    /// Adds a dentry for the regular file with the index `regular_file_idx` among the deserialized regular files.
+    fn deserialize_hard_link(
+        &mut self,
+        dentry: DentryRepresentation,
+        name: String,
+        regular_file_idx: usize,
+        parent_directory_writer: &mut Self::D,
+    ) -> Result<()>;
+
+    /// Creates the empty directory `dir` of the skeleton, without its children.
+    fn build_skeleton_dir(&mut self, dir: &SkeletonDir, parent_directory_writer: &mut Self::D) -> Result<Self::D>;
+
+    /// The directories to add to the deserialized tree, see `Skeleton`
+    fn skeleton(&self) -> Rc<Skeleton>;
+
     fn read_next<T: Any>(&mut self) -> Vec<T>;
 
 
-    fn deserialize_file(&mut self, parent_directory_writer: &mut Self::D) -> Result<()> {
-        let file_type = self.read_next::<FileType>()[0];
+    /// Deserializes the files up to the `EndOfDirectory` record into `directory_writer`, followed by the directories
+    /// of `skeleton`, the part of the skeleton in this directory, that have not been deserialized. `dir_path` is the
+    /// path of the directory, which is empty for the root directory.
+    fn deserialize_children(
+        &mut self,
+        directory_writer: &mut Self::D,
+        dir_path: &str,
+        skeleton: &[SkeletonDir],
+    ) -> Result<()> {
+        let mut existing = vec![false; skeleton.len()];
+        loop {
+            match self.read_next::<FileType>()[0] {
+                FileType::EndOfDirectory => break,
+                file_type => self.deserialize_file(file_type, directory_writer, dir_path, skeleton, &mut existing)?,
+            }
+        }
+        for (dir, _) in skeleton.iter().zip(existing).filter(|(_, existing)| !existing) {
+            self.deserialize_skeleton_dir(dir, directory_writer)?;
+        }
+        Ok(())
+    }
+
+    /// Deserializes the next file into `parent_directory_writer`. If it is one of the directories in `skeleton`, it
+    /// is marked in `existing`.
+    fn deserialize_file(
+        &mut self,
+        file_type: FileType,
+        parent_directory_writer: &mut Self::D,
+        dir_path: &str,
+        skeleton: &[SkeletonDir],
+        existing: &mut [bool],
+    ) -> Result<()> {
         let dentry = self.read_next::<DentryRepresentation>()[0];
         let name = String::from_utf8(self.read_next::<u8>())
             .expect("File name is no longer a valid String after deserialization");
+        let xattrs = if dentry.has_xattrs {
+            self.read_next::<u8>()
+        } else {
+            Vec::new()
+        };
+        let skeleton_idx = skeleton.iter().position(|dir| dir.name == name);
+        if let Some(skeleton_idx) = skeleton_idx {
+            if !matches!(file_type, FileType::Directory) {
+                bail!(
+                    "The skeleton directory {}/{} would replace a file that is not a directory",
+                    dir_path,
+                    name
+                );
+            }
+            existing[skeleton_idx] = true;
+        }
 
         match file_type {
-            FileType::Directory(child_count) => {
-                let mut directory_writer = self.deserialize_directory(dentry, name, parent_directory_writer)?;
-                for _ in 0..child_count {
-                    self.deserialize_file(&mut directory_writer)?;
-                }
+            FileType::Directory => {
+                let path = format!("{}/{}", dir_path, name);
+                let mut directory_writer = self.deserialize_directory(dentry, name, xattrs, parent_directory_writer)?;
+                let skeleton_children = skeleton_idx.map_or(&[][..], |idx| &skeleton[idx].children);
+                self.deserialize_children(&mut directory_writer, &path, skeleton_children)?;
+                directory_writer.finalize()?;
             }
             FileType::RegularFile => {
                 let data_ranges = self.read_next::<Range<ClusterIdx>>();
-                self.deserialize_regular_file(dentry, name, data_ranges, parent_directory_writer)?;
+                self.deserialize_regular_file(dentry, name, xattrs, data_ranges, parent_directory_writer)?;
+            }
+            FileType::Symlink => {
+                let target = String::from_utf8(self.read_next::<u8>())
+                    .expect("Symlink target is no longer a valid String after deserialization");
+                self.deserialize_symlink(dentry, name, xattrs, target, parent_directory_writer)?;
             }
+            FileType::HardLink => {
+                let regular_file_idx = self.read_next::<usize>()[0];
+                self.deserialize_hard_link(dentry, name, regular_file_idx, parent_directory_writer)?;
+            }
+            FileType::EndOfDirectory => unreachable!("`deserialize_children` consumes the end of every directory"),
         }
         Ok(())
     }
 
-    fn read_root_child_count(&mut self) -> u32 {
-        if let FileType::Directory(child_count) = self.read_next::<FileType>()[0] {
-            child_count
-        } else {
-            panic!("First StreamArchiver entry is not root directory child count");
+    fn deserialize_skeleton_dir(&mut self, dir: &SkeletonDir, parent_directory_writer: &mut Self::D) -> Result<()> {
+        let mut directory_writer = self.build_skeleton_dir(dir, parent_directory_writer)?;
+        for child in &dir.children {
+            self.deserialize_skeleton_dir(child, &mut directory_writer)?;
         }
+        directory_writer.finalize()
     }
 }
@@ -1,16 +1,27 @@
 use std::any::Any;
 use std::marker::PhantomData;
 use std::ops::Range;
+use std::rc::Rc;
 
-use anyhow::{bail, Context, Result};
+use anyhow::Result;
 
-use crate::ext4::{BlockCount, BlockSize, Ext4Dentry, Extent, ExtentTree, InodeCount};
+use crate::error::ErrorCategory;
+use crate::ext4::{BlockCount, BlockSize, DirBuilder, Ext4Dentry, Extent, ExtentTree, FileType, Inode, InodeCount};
 use crate::fat::ClusterIdx;
 use crate::serialization::{DentryRepresentation, Deserializer, DeserializerInternals, DirectoryWriter, Reader};
+use crate::skeleton::{Skeleton, SkeletonDir};
 use crate::util::FromU32;
-use crate::BlockIdx;
 
 
+/// The number of inodes and clusters that a conversion allocates, excluding the clusters of the ext4 metadata that
+/// exist independently of the directory tree (e.g. block group descriptors and inode tables).
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ResourceUsage {
+    pub inodes: InodeCount,
+    /// Counts clusters, which are the same as blocks unless bigalloc is enabled
+    pub clusters: BlockCount,
+}
+
 pub type DryRunDeserializer<'a> = Deserializer<'a, DryRunDeserializerInternals<'a>>;
 
 /// A mock version of `Ext4TreeDeserializer` which triggers all cases in which the actual deserializer would bail
@@ -22,17 +33,20 @@ pub type DryRunDeserializer<'a> = Deserializer<'a, DryRunDeserializerInternals<'
 /// - File name too long
 /// - Regular file has more than u32::MAX blocks
 /// - Directory has more than u32::MAX blocks
+/// - Symlink target is longer than the block size
 impl<'a> DryRunDeserializer<'a> {
     pub fn dry_run(
         reader: Reader<'a>,
         free_inodes: InodeCount,
         free_blocks: BlockCount,
         block_size: BlockSize,
-    ) -> Result<()> {
-        let mut instance = Self {
-            internals: DryRunDeserializerInternals::new(reader, block_size),
-            _lifetime: PhantomData,
-        };
+        blocks_per_cluster: u32,
+        has_checksum_tail: bool,
+        skeleton: Rc<Skeleton>,
+    ) -> Result<ResourceUsage> {
+        let mut internals = DryRunDeserializerInternals::new(reader, block_size, blocks_per_cluster, has_checksum_tail);
+        internals.skeleton = skeleton;
+        let mut instance = Self { internals, _lifetime: PhantomData };
         instance.deserialize_directory_tree()?;
         instance.internals.result(free_inodes, free_blocks)
     }
@@ -41,32 +55,48 @@ impl<'a> DryRunDeserializer<'a> {
 pub struct DryRunDeserializerInternals<'a> {
     reader: Reader<'a>,
     used_inodes: InodeCount,
+    /// Counts clusters, which are the same as blocks unless bigalloc is enabled
     used_blocks: BlockCount,
     block_size: BlockSize,
+    blocks_per_cluster: u32,
+    /// whether directory blocks end with an `Ext4DentryTail`, see `DirBuilder`
+    has_checksum_tail: bool,
+    skeleton: Rc<Skeleton>,
 }
 
 impl<'a> DryRunDeserializerInternals<'a> {
-    pub fn new(reader: Reader<'a>, block_size: BlockSize) -> Self {
-        Self { reader, used_inodes: 0, used_blocks: 0, block_size }
+    pub fn new(reader: Reader<'a>, block_size: BlockSize, blocks_per_cluster: u32, has_checksum_tail: bool) -> Self {
+        Self {
+            reader,
+            used_inodes: 0,
+            used_blocks: 0,
+            block_size,
+            blocks_per_cluster,
+            has_checksum_tail,
+            skeleton: Rc::default(),
+        }
     }
 
     // We perform the entire dry run and return a Result only afterward instead of bailing as soon a we know it will
     // fail. This is better because it lets the user know the required inode/block count.
-    fn result(&self, free_inodes: InodeCount, free_blocks: BlockCount) -> Result<()> {
+    fn result(&self, free_inodes: InodeCount, free_blocks: BlockCount) -> Result<ResourceUsage> {
         let enough_inodes = self.used_inodes <= free_inodes;
         let enough_blocks = self.used_blocks <= free_blocks;
-        match (enough_inodes, enough_blocks) {
-            (true, true) => Ok(()),
-            (true, false) => bail!("{} free blocks required but only {} available", self.used_blocks, free_blocks),
-            (false, true) => bail!("{} free inodes required but only {} available", self.used_inodes, free_inodes),
-            (false, false) => bail!(
+        let message = match (enough_inodes, enough_blocks) {
+            (true, true) => {
+                return Ok(ResourceUsage {
+                    inodes: self.used_inodes,
+                    clusters: self.used_blocks,
+                })
+            }
+            (true, false) => format!("{} free blocks required but only {} available", self.used_blocks, free_blocks),
+            (false, true) => format!("{} free inodes required but only {} available", self.used_inodes, free_inodes),
+            (false, false) => format!(
                 "{} free blocks required but only {} available; {} inodes required but only {} available",
-                self.used_blocks,
-                free_blocks,
-                self.used_inodes,
-                free_inodes
+                self.used_blocks, free_blocks, self.used_inodes, free_inodes
             ),
-        }
+        };
+        Err(ErrorCategory::InsufficientSpace.error(message))
     }
 }
 
@@ -77,10 +107,24 @@ impl<'a> DeserializerInternals<'a> for DryRunDeserializerInternals<'a> {
         self.reader.next::<T>()
     }
 
+    fn skeleton(&self) -> Rc<Skeleton> {
+        Rc::clone(&self.skeleton)
+    }
+
+    fn build_skeleton_dir(
+        &mut self,
+        dir: &SkeletonDir,
+        parent_directory_writer: &mut DryRunDirectoryWriter,
+    ) -> Result<DryRunDirectoryWriter> {
+        self.build_directory(dir.name.clone(), parent_directory_writer)
+    }
+
     fn build_root(&mut self) -> Result<DryRunDirectoryWriter> {
-        let mut dir_writer = DryRunDirectoryWriter::new(self.block_size);
+        let mut dir_writer = self.directory_writer();
+        self.used_blocks += dir_writer.builder.layout().used_clusters();
         self.used_blocks += dir_writer.add_dot_dirs()?;
-        self.build_directory("lost+found".to_string(), &mut dir_writer)?;
+        let mut lost_found_writer = self.build_directory("lost+found".to_string(), &mut dir_writer)?;
+        self.used_blocks += lost_found_writer.preallocate()?;
         Ok(dir_writer)
     }
 
@@ -88,6 +132,7 @@ impl<'a> DeserializerInternals<'a> for DryRunDeserializerInternals<'a> {
         &mut self,
         _dentry: DentryRepresentation,
         name: String,
+        _xattrs: Vec<u8>,
         parent_directory_writer: &mut DryRunDirectoryWriter,
     ) -> Result<DryRunDirectoryWriter> {
         self.build_directory(name, parent_directory_writer)
@@ -95,24 +140,58 @@ impl<'a> DeserializerInternals<'a> for DryRunDeserializerInternals<'a> {
 
     fn deserialize_regular_file(
         &mut self,
-        _dentry: DentryRepresentation,
+        dentry: DentryRepresentation,
         name: String,
+        _xattrs: Vec<u8>,
         data_ranges: Vec<Range<ClusterIdx>>,
         parent_directory_writer: &mut DryRunDirectoryWriter,
     ) -> Result<()> {
-        self.build_regular_file(name, parent_directory_writer, data_ranges)
+        let file_size = u64::from(dentry.file_size);
+        self.build_regular_file(name, parent_directory_writer, file_size, data_ranges)
+    }
+
+    fn deserialize_symlink(
+        &mut self,
+        _dentry: DentryRepresentation,
+        name: String,
+        _xattrs: Vec<u8>,
+        target: String,
+        parent_directory_writer: &mut DryRunDirectoryWriter,
+    ) -> Result<()> {
+        self.used_inodes += 1;
+        self.used_blocks += parent_directory_writer.add_dentry(&Ext4Dentry::new(0, name, FileType::Symlink)?)?;
+        // a single extent fits into the inode
+        self.used_blocks += Inode::symlink_cluster_count(target.len(), self.block_size)?;
+        Ok(())
+    }
+
+    /// A hard link only needs a dentry, since the inode and data belong to the regular file it links to.
+    fn deserialize_hard_link(
+        &mut self,
+        _dentry: DentryRepresentation,
+        name: String,
+        _regular_file_idx: usize,
+        parent_directory_writer: &mut DryRunDirectoryWriter,
+    ) -> Result<()> {
+        self.used_blocks += parent_directory_writer.add_dentry(&Ext4Dentry::new(0, name, FileType::RegularFile)?)?;
+        Ok(())
     }
 }
 
 impl<'a> DryRunDeserializerInternals<'a> {
+    fn directory_writer(&self) -> DryRunDirectoryWriter {
+        DryRunDirectoryWriter::new(self.block_size, self.blocks_per_cluster, self.has_checksum_tail)
+    }
+
     fn build_directory(
         &mut self,
         name: String,
         parent_directory_writer: &mut DryRunDirectoryWriter,
     ) -> Result<DryRunDirectoryWriter> {
-        let mut dir_writer = DryRunDirectoryWriter::new(self.block_size);
+        let mut dir_writer = self.directory_writer();
         self.used_inodes += 1;
-        self.used_blocks += parent_directory_writer.add_dentry(&Ext4Dentry::new(0, name)?)?;
+        self.used_blocks += parent_directory_writer.add_dentry(&Ext4Dentry::new(0, name, FileType::Directory)?)?;
+        self.used_blocks += dir_writer.builder.layout().used_clusters();
         self.used_blocks += dir_writer.add_dot_dirs()?;
         Ok(dir_writer)
     }
@@ -121,67 +200,62 @@ impl<'a> DryRunDeserializerInternals<'a> {
         &mut self,
         name: String,
         parent_directory_writer: &mut DryRunDirectoryWriter,
+        file_size: u64,
         data_ranges: Vec<Range<ClusterIdx>>,
     ) -> Result<()> {
         self.used_inodes += 1;
-        self.used_blocks += parent_directory_writer.add_dentry(&Ext4Dentry::new(0, name)?)?;
-        let data_ranges_iter = data_ranges
-            .into_iter()
-            .map(|range| BlockIdx::fromx(range.start)..BlockIdx::fromx(range.end));
-        let extents = Extent::from_ranges(data_ranges_iter)?;
+        self.used_blocks += parent_directory_writer.add_dentry(&Ext4Dentry::new(0, name, FileType::RegularFile)?)?;
+        // the data clusters are already in use, only the extent tree needs additional clusters
+        let extents = Extent::from_file_clusters(data_ranges, file_size, self.block_size, self.blocks_per_cluster)?;
         self.used_blocks += ExtentTree::required_block_count(extents.len(), self.block_size);
         Ok(())
     }
 }
 
+/// Mirrors `DentryWriter` with a `DirBuilder` in the simulation mode.
 pub struct DryRunDirectoryWriter {
-    used_dentry_blocks: u32, // a file's block count must fit into a u32
-    used_extent_blocks: BlockCount,
-    block_size: BlockSize,
-    position_in_block: u32,
+    builder: DirBuilder,
 }
 
-impl DirectoryWriter for DryRunDirectoryWriter {}
+impl DirectoryWriter for DryRunDirectoryWriter {
+    fn finalize(self) -> Result<()> {
+        Ok(())
+    }
+}
 
 impl DryRunDirectoryWriter {
-    fn new(block_size: BlockSize) -> Self {
+    /// Like `DentryWriter::new`, this accounts for the directory's first cluster.
+    fn new(block_size: BlockSize, blocks_per_cluster: u32, has_checksum_tail: bool) -> Self {
         debug_assert!(usize::fromx(block_size) >= Ext4Dentry::MAX_LEN);
         Self {
-            used_dentry_blocks: 0,
-            used_extent_blocks: 0,
-            block_size,
-            position_in_block: block_size, // to model the first block being allocated immediately
+            builder: DirBuilder::simulation(
+                usize::fromx(block_size),
+                usize::fromx(blocks_per_cluster),
+                has_checksum_tail,
+            ),
         }
     }
 
     fn add_dot_dirs(&mut self) -> Result<usize> {
-        let mut added_blocks = self.add_dentry(&Ext4Dentry::new(0, ".".to_string()).unwrap())?;
-        added_blocks += self.add_dentry(&Ext4Dentry::new(0, "..".to_string()).unwrap())?;
+        let mut added_blocks = self.add_dentry(&Ext4Dentry::new(0, ".".to_string(), FileType::Directory).unwrap())?;
+        added_blocks += self.add_dentry(&Ext4Dentry::new(0, "..".to_string(), FileType::Directory).unwrap())?;
         Ok(added_blocks)
     }
 
+    /// Returns the number of clusters that adding `dentry` requires.
     fn add_dentry(&mut self, dentry: &Ext4Dentry) -> Result<usize> {
-        let old_used_blocks = self.used_blocks();
-        if u32::from(dentry.dentry_len()) > self.remaining_space() {
-            self.used_dentry_blocks = self
-                .used_dentry_blocks
-                .checked_add(1)
-                .context("Directory contains too many files")?;
-            // This only fails with billions of files, so it's just a formality.
-            self.position_in_block = 0;
-            self.used_extent_blocks =
-                ExtentTree::required_block_count(BlockCount::fromx(self.used_dentry_blocks), self.block_size);
-        }
-        self.position_in_block += u32::from(dentry.dentry_len());
-
-        Ok(self.used_blocks() - old_used_blocks)
+        let old_used_blocks = self.builder.layout().used_clusters();
+        self.builder.make_room(usize::from(dentry.dentry_len()), None)?;
+        self.builder.write_dentry(dentry, None);
+        Ok(self.builder.layout().used_clusters() - old_used_blocks)
     }
 
-    fn used_blocks(&self) -> usize {
-        BlockCount::fromx(self.used_dentry_blocks) + self.used_extent_blocks
-    }
-
-    fn remaining_space(&self) -> u32 {
-        self.block_size - self.position_in_block
+    /// Mirrors `DentryWriter::finalize_preallocated`. Returns the number of clusters that the preallocation requires.
+    fn preallocate(&mut self) -> Result<usize> {
+        let old_used_blocks = self.builder.layout().used_clusters();
+        while self.builder.layout().cluster_count() < self.builder.layout().lost_found_cluster_count() {
+            self.builder.add_empty_cluster()?;
+        }
+        Ok(self.builder.layout().used_clusters() - old_used_blocks)
     }
 }
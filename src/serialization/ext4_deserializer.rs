@@ -4,35 +4,139 @@ use std::marker::PhantomData;
 use std::ops::Range;
 use std::rc::Rc;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 
-use crate::allocator::{AllocatedClusterIdx, Allocator};
-use crate::ext4::{BlockIdx, Ext4Dentry, Ext4DentrySized, Ext4Fs, Extent, Inode, SuperBlock};
+use crate::allocator::{AllocatedClusterIdx, AllocationPurpose, Allocator, AllocatorStats, ClusterAllocator};
+use crate::ext4::{
+    DirBuilder, Ext4Dentry, Ext4DentryTail, Ext4Fs, Ext4FsStats, Extent, FileType, Inode, InodeNo, Owner, SuperBlock,
+};
 use crate::fat::{ClusterIdx, FatFs};
 use crate::serialization::{
     DentryRepresentation, Deserializer, DeserializerInternals, DirectoryWriter, DryRunDeserializer, Reader,
+    ResourceUsage,
 };
-use crate::util::{FromU32, FromUsize};
+use crate::skeleton::{Skeleton, SkeletonDir};
+use crate::trace::TraceEvent;
+use crate::util::FromU32;
 
 
-pub type Ext4TreeDeserializer<'a> = Deserializer<'a, Ext4TreeDeserializerInternals<'a>>;
+pub type Ext4TreeDeserializer<'a, A = Allocator<'a>> = Deserializer<'a, Ext4TreeDeserializerInternals<'a, A>>;
 
-impl<'a> Ext4TreeDeserializer<'a> {
-    pub fn new(reader: Reader<'a>, allocator: Allocator<'a>, ext_fs: Ext4Fs<'a>) -> Self {
+impl<'a, A: ClusterAllocator + 'a> Ext4TreeDeserializer<'a, A> {
+    pub fn new(reader: Reader<'a>, allocator: A, ext_fs: Ext4Fs<'a>) -> Self {
         Self {
             internals: Ext4TreeDeserializerInternals::new(reader, allocator, ext_fs),
             _lifetime: PhantomData,
         }
     }
 
-    /// SAFETY: Safe if no block in `SuperBlock::from(fat_fs.boot_sector).block_group_overhead_ranges()` is accessed for
-    /// the duration of the lifetime 'a
-    pub unsafe fn new_with_dry_run(reader: Reader<'a>, allocator: Allocator<'a>, fat_fs: FatFs<'a>) -> Result<Self> {
-        let free_inodes = SuperBlock::from(fat_fs.boot_sector())?.allocatable_inode_count();
-        let free_blocks = allocator.free_block_count();
-        DryRunDeserializer::dry_run(reader.clone(), free_inodes, free_blocks, fat_fs.cluster_size())?;
-        let ext_fs = unsafe { fat_fs.into_ext4()? };
-        Ok(Self::new(reader, allocator, ext_fs))
+    /// SAFETY: Safe if `superblock` was created from `fat_fs.boot_sector()` and no block in
+    /// `superblock.block_group_overhead_ranges()` is accessed for the duration of the lifetime 'a
+    pub unsafe fn new_with_dry_run(
+        reader: Reader<'a>,
+        allocator: A,
+        fat_fs: FatFs<'a>,
+        superblock: SuperBlock,
+        skeleton: Rc<Skeleton>,
+    ) -> Result<Self> {
+        let predicted_usage = Self::dry_run(&reader, &allocator, &superblock, &skeleton)?;
+        Ok(unsafe { Self::after_dry_run(reader, allocator, fat_fs, superblock, skeleton, predicted_usage) })
+    }
+
+    /// Like `new_with_dry_run`, for a dry run with the same `skeleton` that `dry_run` has already performed and that
+    /// predicted `predicted_usage`.
+    /// SAFETY: See `new_with_dry_run`.
+    pub unsafe fn after_dry_run(
+        reader: Reader<'a>,
+        allocator: A,
+        fat_fs: FatFs<'a>,
+        superblock: SuperBlock,
+        skeleton: Rc<Skeleton>,
+        predicted_usage: ResourceUsage,
+    ) -> Self {
+        let ext_fs = unsafe { fat_fs.into_ext4(superblock) };
+        let mut instance = Self::new(reader, allocator, ext_fs);
+        instance.internals.predicted_usage = Some(predicted_usage);
+        instance.internals.skeleton = skeleton;
+        instance
+    }
+
+    /// Checks with a `DryRunDeserializer` whether the directory tree in `reader` and the directories of `skeleton` fit
+    /// into the ext4 filesystem described by `superblock` and the clusters that `allocator` has left. `allocator` must
+    /// be the one split off with `reader`, which does not allocate anything for the archive anymore. Does not modify
+    /// the partition.
+    pub fn dry_run(
+        reader: &Reader<'a>,
+        allocator: &A,
+        superblock: &SuperBlock,
+        skeleton: &Rc<Skeleton>,
+    ) -> Result<ResourceUsage> {
+        DryRunDeserializer::dry_run(
+            reader.clone(),
+            superblock.allocatable_inode_count(),
+            allocator.free_block_count(),
+            superblock.block_size(),
+            superblock.blocks_per_cluster(),
+            superblock.has_metadata_csum(),
+            Rc::clone(skeleton),
+        )
+    }
+
+    /// The resource usage determined by the dry run, if this deserializer was created by `new_with_dry_run`.
+    pub fn predicted_usage(&self) -> Option<ResourceUsage> {
+        self.internals.predicted_usage
+    }
+
+    /// The resources allocated by this deserializer so far.
+    pub fn actual_usage(&self) -> ResourceUsage {
+        let stats = self.allocator_stats();
+        ResourceUsage {
+            inodes: self.internals.ext_fs.allocated_inode_count(),
+            clusters: stats.metadata + stats.dentries,
+        }
+    }
+
+    /// The clusters allocated so far, including those allocated during the serialization.
+    pub fn allocator_stats(&self) -> AllocatorStats {
+        self.internals.allocator.stats()
+    }
+
+    /// The file counts and free resources of the ext4 filesystem, which are final after `deserialize_directory_tree`.
+    pub fn fs_stats(&self) -> Ext4FsStats {
+        self.internals.ext_fs.stats()
+    }
+
+    /// Gives every converted file a random generation number, see `Ext4Fs::set_randomize_generation`.
+    pub fn set_randomize_generation(&mut self, randomize_generation: bool) {
+        self.internals.ext_fs.set_randomize_generation(randomize_generation);
+    }
+
+    /// Sets the owner of the root directory and the converted files, see `Ext4Fs::set_owner`.
+    pub fn set_owner(&mut self, owner: Owner) {
+        self.internals.ext_fs.set_owner(owner);
+    }
+
+    /// Sets the access time of every converted file to its modification time instead of its FAT access date. FAT
+    /// only records the date of the last access, so without this, a file accessed today seems to have been accessed
+    /// at midnight, and many FAT drivers never update the access date at all.
+    pub fn set_drop_atime(&mut self, drop_atime: bool) {
+        self.internals.drop_atime = drop_atime;
+    }
+
+    /// Limits the rate at which inode tables are zeroed, see `Ext4Fs::set_rate_limit`.
+    pub fn set_rate_limit(&mut self, bytes_per_sec: u64) {
+        self.internals.ext_fs.set_rate_limit(bytes_per_sec);
+    }
+
+    /// Calls `callback` for every file and directory that `deserialize_directory_tree` converts, once its inode has
+    /// been created, so that the caller can build a catalog of the converted files.
+    pub fn on_file_converted<F: FnMut(ConvertedFile) + 'a>(&mut self, callback: F) {
+        self.internals.on_file_converted = Some(Box::new(callback));
+    }
+
+    /// Completes the ext4 filesystem after `deserialize_directory_tree`, see `Ext4Fs::finalize`.
+    pub fn finalize(self) -> Result<()> {
+        self.internals.ext_fs.finalize()
     }
 }
 
@@ -44,18 +148,43 @@ impl<'a> Ext4TreeDeserializer<'a> {
 // - File name too long
 // - Regular file has more than u32::MAX blocks
 // - Directory has more than u32::MAX blocks
-pub struct Ext4TreeDeserializerInternals<'a> {
-    allocator: Rc<Allocator<'a>>,
+// - Symlink target is longer than the block size
+pub struct Ext4TreeDeserializerInternals<'a, A: ClusterAllocator = Allocator<'a>> {
+    allocator: Rc<A>,
     reader: Reader<'a>,
     ext_fs: Ext4Fs<'a>,
+    predicted_usage: Option<ResourceUsage>,
+    on_file_converted: Option<FileCallback<'a>>,
+    /// see `Ext4TreeDeserializer::set_drop_atime`
+    drop_atime: bool,
+    skeleton: Rc<Skeleton>,
+    /// the inodes of the deserialized regular files in the order of their `RegularFile` records, which hard links
+    /// refer to
+    regular_file_inodes: Vec<InodeNo>,
 }
 
-impl<'a> DeserializerInternals<'a> for Ext4TreeDeserializerInternals<'a> {
-    type D = DentryWriter<'a>;
+type FileCallback<'a> = Box<dyn FnMut(ConvertedFile) + 'a>;
+
+/// A file or directory converted by `Ext4TreeDeserializer`, see `Ext4TreeDeserializer::on_file_converted`
+#[derive(Debug)]
+pub struct ConvertedFile<'e> {
+    /// the path of the file in the FAT filesystem (after the truncation of long names), e.g. "/dir/file"
+    pub path: &'e str,
+    pub inode_no: InodeNo,
+    /// the dentry that the inode was initialized from
+    pub dentry: DentryRepresentation,
+    /// the extents of a regular file's data; empty for directories, whose extents are only known once all of their
+    /// children have been converted, and for symlinks
+    pub extents: &'e [Extent],
+}
 
-    fn build_root(&mut self) -> Result<DentryWriter<'a>> {
-        let root_inode = self.ext_fs.build_root_inode();
-        let mut dentry_writer = DentryWriter::new(root_inode, Rc::clone(&self.allocator), &mut self.ext_fs)?;
+impl<'a, A: ClusterAllocator + 'a> DeserializerInternals<'a> for Ext4TreeDeserializerInternals<'a, A> {
+    type D = DentryWriter<'a, A>;
+
+    fn build_root(&mut self) -> Result<DentryWriter<'a, A>> {
+        let root_inode = self.ext_fs.build_root_inode()?;
+        let mut dentry_writer =
+            DentryWriter::new(root_inode, String::new(), Rc::clone(&self.allocator), &mut self.ext_fs)?;
         self.build_root_dot_dirs(&mut dentry_writer)?;
         self.build_lost_found(&mut dentry_writer)?;
         Ok(dentry_writer)
@@ -65,10 +194,13 @@ impl<'a> DeserializerInternals<'a> for Ext4TreeDeserializerInternals<'a> {
         &mut self,
         dentry: DentryRepresentation,
         name: String,
-        parent_dentry_writer: &mut DentryWriter<'a>,
-    ) -> Result<DentryWriter<'a>> {
-        let inode = self.build_file(dentry, name, parent_dentry_writer)?;
-        let mut dentry_writer = DentryWriter::new(inode, Rc::clone(&self.allocator), &mut self.ext_fs)?;
+        xattrs: Vec<u8>,
+        parent_dentry_writer: &mut DentryWriter<'a, A>,
+    ) -> Result<DentryWriter<'a, A>> {
+        let path = format!("{}/{}", parent_dentry_writer.path, name);
+        let inode = self.build_file(dentry, name, &xattrs, FileType::Directory, parent_dentry_writer)?;
+        self.report_converted_file(&path, inode.inode_no, dentry, &[]);
+        let mut dentry_writer = DentryWriter::new(inode, path, Rc::clone(&self.allocator), &mut self.ext_fs)?;
         self.build_dot_dirs(&mut dentry_writer, parent_dentry_writer)?;
         Ok(dentry_writer)
     }
@@ -77,72 +209,170 @@ impl<'a> DeserializerInternals<'a> for Ext4TreeDeserializerInternals<'a> {
         &mut self,
         dentry: DentryRepresentation,
         name: String,
+        xattrs: Vec<u8>,
         data_ranges: Vec<Range<ClusterIdx>>,
-        parent_directory_writer: &mut DentryWriter,
+        parent_directory_writer: &mut DentryWriter<'a, A>,
     ) -> Result<()> {
-        let mut inode = self.build_file(dentry, name, parent_directory_writer)?;
-        let data_ranges_iter = data_ranges
-            .into_iter()
-            .map(|range| BlockIdx::fromx(range.start)..BlockIdx::fromx(range.end));
-        self.ext_fs.set_extents(&mut inode, data_ranges_iter, &self.allocator)?;
-        inode.set_size(u64::from(dentry.file_size));
+        let path = format!("{}/{}", parent_directory_writer.path, name);
+        let mut inode = self.build_file(dentry, name, &xattrs, FileType::RegularFile, parent_directory_writer)?;
+        self.regular_file_inodes.push(inode.inode_no);
+        let file_size = u64::from(dentry.file_size);
+        let extents = Extent::from_file_clusters(
+            data_ranges,
+            file_size,
+            self.ext_fs.block_size(),
+            self.ext_fs.blocks_per_cluster(),
+        )?;
+        self.report_converted_file(&path, inode.inode_no, dentry, &extents);
+        self.ext_fs.set_extents(&mut inode, extents, &*self.allocator)?;
+        inode.set_size(file_size);
         Ok(())
     }
 
+    fn deserialize_symlink(
+        &mut self,
+        dentry: DentryRepresentation,
+        name: String,
+        xattrs: Vec<u8>,
+        target: String,
+        parent_directory_writer: &mut DentryWriter<'a, A>,
+    ) -> Result<()> {
+        let path = format!("{}/{}", parent_directory_writer.path, name);
+        let mut inode = self.build_file(dentry, name, &xattrs, FileType::Symlink, parent_directory_writer)?;
+        self.report_converted_file(&path, inode.inode_no, dentry, &[]);
+        self.ext_fs.init_symlink(&mut inode, target.as_bytes(), &*self.allocator)
+    }
+
+    /// Hard links are not reported to `on_file_converted`, since they share the inode and the dentry of the regular
+    /// file they link to.
+    fn deserialize_hard_link(
+        &mut self,
+        _dentry: DentryRepresentation,
+        name: String,
+        regular_file_idx: usize,
+        parent_directory_writer: &mut DentryWriter<'a, A>,
+    ) -> Result<()> {
+        let inode_no = match self.regular_file_inodes.get(regular_file_idx) {
+            Some(&inode_no) => inode_no,
+            None => bail!(
+                "{} links to regular file {}, which has not been converted",
+                name,
+                regular_file_idx
+            ),
+        };
+        // SAFETY: Safe because the `Inode` of a regular file is dropped once the file has been deserialized.
+        let mut inode = unsafe { self.ext_fs.allocated_inode(inode_no)? };
+        inode.add_hard_link()?;
+        let dentry = Ext4Dentry::new(inode_no, name, FileType::RegularFile)?;
+        parent_directory_writer.add_dentry(dentry, &mut self.ext_fs)
+    }
+
+    fn build_skeleton_dir(
+        &mut self,
+        dir: &SkeletonDir,
+        parent_dentry_writer: &mut DentryWriter<'a, A>,
+    ) -> Result<DentryWriter<'a, A>> {
+        let path = format!("{}/{}", parent_dentry_writer.path, dir.name);
+        let mut inode = self.ext_fs.allocate_inode(true)?;
+        inode.init_new_dir(dir.mode, dir.owner.unwrap_or_else(|| self.ext_fs.owner()));
+        let dentry = Ext4Dentry::new(inode.inode_no, dir.name.clone(), FileType::Directory)?;
+        parent_dentry_writer.add_dentry(dentry, &mut self.ext_fs)?;
+        let mut dentry_writer = DentryWriter::new(inode, path, Rc::clone(&self.allocator), &mut self.ext_fs)?;
+        self.build_dot_dirs(&mut dentry_writer, parent_dentry_writer)?;
+        Ok(dentry_writer)
+    }
+
+    fn skeleton(&self) -> Rc<Skeleton> {
+        Rc::clone(&self.skeleton)
+    }
+
     fn read_next<T: Any>(&mut self) -> Vec<T> {
         self.reader.next::<T>()
     }
 }
 
-impl<'a> Ext4TreeDeserializerInternals<'a> {
-    pub fn new(reader: Reader<'a>, allocator: Allocator<'a>, ext_fs: Ext4Fs<'a>) -> Self {
-        Self { reader, allocator: Rc::new(allocator), ext_fs }
+impl<'a, A: ClusterAllocator + 'a> Ext4TreeDeserializerInternals<'a, A> {
+    pub fn new(reader: Reader<'a>, allocator: A, ext_fs: Ext4Fs<'a>) -> Self {
+        Self {
+            reader,
+            allocator: Rc::new(allocator),
+            ext_fs,
+            predicted_usage: None,
+            on_file_converted: None,
+            drop_atime: false,
+            skeleton: Rc::default(),
+            regular_file_inodes: Vec::new(),
+        }
+    }
+
+    fn report_converted_file(
+        &mut self,
+        path: &str,
+        inode_no: InodeNo,
+        dentry: DentryRepresentation,
+        extents: &[Extent],
+    ) {
+        if let Some(callback) = &mut self.on_file_converted {
+            callback(ConvertedFile { path, inode_no, dentry, extents });
+        }
     }
 
     fn build_file(
         &mut self,
         dentry: DentryRepresentation,
         name: String,
-        parent_dentry_writer: &mut DentryWriter,
+        xattrs: &[u8],
+        file_type: FileType,
+        parent_dentry_writer: &mut DentryWriter<'a, A>,
     ) -> Result<Inode<'a>> {
-        let mut inode = self.ext_fs.allocate_inode(dentry.is_dir)?;
-        inode.init_from_dentry(dentry);
-        parent_dentry_writer.add_dentry(Ext4Dentry::new(inode.inode_no, name)?, &mut self.ext_fs)?;
+        let dentry = if self.drop_atime {
+            DentryRepresentation { access_time: dentry.mod_time, ..dentry }
+        } else {
+            dentry
+        };
+        let mut inode = self.ext_fs.allocate_inode(file_type == FileType::Directory)?;
+        inode.init_from_dentry(dentry, self.ext_fs.owner());
+        if !xattrs.is_empty() {
+            self.ext_fs.set_xattrs(&mut inode, xattrs);
+        }
+        parent_dentry_writer.add_dentry(Ext4Dentry::new(inode.inode_no, name, file_type)?, &mut self.ext_fs)?;
         Ok(inode)
     }
 
-    fn build_lost_found(&mut self, root_dentry_writer: &mut DentryWriter) -> Result<()> {
+    fn build_lost_found(&mut self, root_dentry_writer: &mut DentryWriter<'a, A>) -> Result<()> {
         let inode = self.ext_fs.build_lost_found_inode()?;
-        let dentry = Ext4Dentry::new(inode.inode_no, "lost+found".to_string())?;
+        let dentry = Ext4Dentry::new(inode.inode_no, "lost+found".to_string(), FileType::Directory)?;
 
         root_dentry_writer.add_dentry(dentry, &mut self.ext_fs)?;
-        let mut dentry_writer = DentryWriter::new(inode, Rc::clone(&self.allocator), &mut self.ext_fs)?;
+        let path = "/lost+found".to_string();
+        let mut dentry_writer = DentryWriter::new(inode, path, Rc::clone(&self.allocator), &mut self.ext_fs)?;
         self.build_dot_dirs(&mut dentry_writer, root_dentry_writer)?;
-        Ok(())
+        dentry_writer.finalize_preallocated(&mut self.ext_fs)
     }
 
     fn build_dot_dirs(
         &mut self,
-        dentry_writer: &mut DentryWriter,
-        parent_dentry_writer: &mut DentryWriter,
+        dentry_writer: &mut DentryWriter<'a, A>,
+        parent_dentry_writer: &mut DentryWriter<'a, A>,
     ) -> Result<()> {
-        let dot_dentry = Ext4Dentry::new(dentry_writer.inode.inode_no, ".".to_string())?;
+        let dot_dentry = Ext4Dentry::new(dentry_writer.inode.inode_no, ".".to_string(), FileType::Directory)?;
         dentry_writer.add_dentry(dot_dentry, &mut self.ext_fs)?;
         dentry_writer.increment_link_count();
 
-        let dot_dot_dentry = Ext4Dentry::new(parent_dentry_writer.inode.inode_no, "..".to_string())?;
+        let dot_dot_dentry =
+            Ext4Dentry::new(parent_dentry_writer.inode.inode_no, "..".to_string(), FileType::Directory)?;
         dentry_writer.add_dentry(dot_dot_dentry, &mut self.ext_fs)?;
         parent_dentry_writer.increment_link_count();
         Ok(())
     }
 
     // same as `build_dot_dirs` except `parent_inode` would alias `dentry_writer.inode`
-    fn build_root_dot_dirs(&mut self, dentry_writer: &mut DentryWriter) -> Result<()> {
-        let dot_dentry = Ext4Dentry::new(dentry_writer.inode.inode_no, ".".to_string())?;
+    fn build_root_dot_dirs(&mut self, dentry_writer: &mut DentryWriter<'a, A>) -> Result<()> {
+        let dot_dentry = Ext4Dentry::new(dentry_writer.inode.inode_no, ".".to_string(), FileType::Directory)?;
         dentry_writer.add_dentry(dot_dentry, &mut self.ext_fs)?;
         dentry_writer.increment_link_count();
 
-        let dot_dot_dentry = Ext4Dentry::new(dentry_writer.inode.inode_no, "..".to_string())?;
+        let dot_dot_dentry = Ext4Dentry::new(dentry_writer.inode.inode_no, "..".to_string(), FileType::Directory)?;
         dentry_writer.add_dentry(dot_dot_dentry, &mut self.ext_fs)?;
         dentry_writer.increment_link_count();
         Ok(())
@@ -150,60 +380,59 @@ impl<'a> Ext4TreeDeserializerInternals<'a> {
 }
 
 
-pub struct DentryWriter<'a> {
+pub struct DentryWriter<'a, A: ClusterAllocator = Allocator<'a>> {
     inode: Inode<'a>,
-    block_size: usize,
-    position_in_block: usize,
-    allocator: Rc<Allocator<'a>>,
-    block: AllocatedClusterIdx,
-    previous_dentry: Option<&'a mut Ext4DentrySized>,
-    block_count: usize,
+    /// the path of the directory, which is empty for the root directory
+    path: String,
+    builder: DirBuilder,
+    allocator: Rc<A>,
+    cluster: AllocatedClusterIdx,
     link_count_from_subdirs: u64,
+    /// Whether `finalize` has run, otherwise `drop` completes the directory as far as possible
+    finalized: bool,
 }
 
-impl<'a> DentryWriter<'a> {
-    pub fn new(mut inode: Inode<'a>, allocator: Rc<Allocator<'a>>, ext_fs: &mut Ext4Fs) -> Result<Self> {
-        assert!(allocator.block_size() >= Ext4Dentry::MAX_LEN);
-
-        let block = allocator.allocate_one()?;
-        let extent = Extent::new(block.as_block_idx()..block.as_block_idx() + 1, 0);
-        ext_fs.register_extent(&mut inode, extent, &allocator)?;
-        inode.increment_size(u64::fromx(allocator.block_size()));
+impl<'a, A: ClusterAllocator> DentryWriter<'a, A> {
+    pub fn new(inode: Inode<'a>, path: String, allocator: Rc<A>, ext_fs: &mut Ext4Fs) -> Result<Self> {
+        let block_size = usize::fromx(ext_fs.block_size());
+        debug_assert!(
+            block_size >= Ext4Dentry::MAX_LEN,
+            "SuperBlock ensures a block size of at least 1 KiB"
+        );
 
-        Ok(Self {
+        let checksum_seed = ext_fs.metadata_checksum_seed().map(|checksum_seed| {
+            Ext4DentryTail::directory_seed(checksum_seed, inode.inode_no, inode.inner.i_generation)
+        });
+        let cluster = allocator.allocate_one(AllocationPurpose::Dentries)?;
+        let mut instance = Self {
             inode,
-            block_size: allocator.block_size(),
-            /// Invariant: `position_in_block <= block_size`
-            position_in_block: 0,
+            path,
+            builder: DirBuilder::new(block_size, usize::fromx(ext_fs.blocks_per_cluster()), checksum_seed),
             allocator,
-            block,
-            previous_dentry: None,
-            block_count: 1,
+            cluster,
             link_count_from_subdirs: 0,
-        })
+            finalized: false,
+        };
+        instance.register_cluster(ext_fs)?;
+        Ok(instance)
     }
 
     fn add_dentry(&mut self, dentry: Ext4Dentry, ext_fs: &mut Ext4Fs) -> Result<()> {
-        if usize::from(dentry.dentry_len()) > self.remaining_space() {
-            self.allocate_block(ext_fs)?;
-        }
-        debug_assert!(self.remaining_space() >= usize::from(dentry.dentry_len()));
-
-        let name = dentry.serialize_name();
-        let block = self.allocator.cluster_mut(&mut self.block);
-        // SAFETY: Safe because by the invariant on `position_in_block` this still points inside the block.
-        let dentry_ptr = unsafe { block.as_mut_ptr().add(self.position_in_block) as *mut Ext4DentrySized };
-        // SAFETY: Safe because we made sure that the remaining space is sufficient for the entire dentry. Further,
-        // `block` is 4-aligned and `dentry.dentry_len` is always a multiple of 4, so `dentry_ptr` is 4-aligned.
-        unsafe {
-            dentry_ptr.write(dentry.inner);
-            let name_ptr = dentry_ptr.add(1) as *mut u8;
-            name_ptr.copy_from_nonoverlapping(name.as_ptr(), name.len());
-        }
-
-        self.position_in_block += usize::from(dentry.dentry_len());
-        // SAFETY: It's the pointer we just wrote to, so it's valid, aligned and initialized.
-        self.previous_dentry = unsafe { Some(&mut *dentry_ptr) };
+        let dentry_len = usize::from(dentry.dentry_len());
+        let cluster = self.allocator.cluster_mut(&mut self.cluster);
+        if self.builder.make_room(dentry_len, Some(cluster))? {
+            self.cluster = self.allocator.allocate_one(AllocationPurpose::Dentries)?;
+            self.register_cluster(ext_fs)?;
+        }
+
+        let cluster = self.allocator.cluster_mut(&mut self.cluster);
+        let position_in_cluster = self.builder.write_dentry(&dentry, Some(cluster));
+        self.allocator.trace(TraceEvent::Dentry {
+            directory_inode_no: self.inode.inode_no,
+            inode_no: dentry.inner.inode_no(),
+            cluster: self.cluster.as_cluster_idx(),
+            offset: u32::try_from(position_in_cluster)?,
+        });
         Ok(())
     }
 
@@ -211,43 +440,490 @@ impl<'a> DentryWriter<'a> {
         self.link_count_from_subdirs += 1;
     }
 
-    fn remaining_space(&self) -> usize {
-        self.block_size - self.position_in_block
+    /// Adds `self.cluster` to the end of the directory as the last of the clusters in `self.builder`'s layout.
+    fn register_cluster(&mut self, ext_fs: &mut Ext4Fs) -> Result<()> {
+        let blocks_per_cluster = self.builder.layout().blocks_per_cluster();
+        if blocks_per_cluster > 1 {
+            self.builder.clear_cluster(self.allocator.cluster_mut(&mut self.cluster));
+        }
+
+        // every cluster is a separate extent, which `DryRunDirectoryWriter` relies on
+        let first_block = self.cluster.first_block_idx(u32::try_from(blocks_per_cluster)?);
+        let logical_start = self.builder.layout().last_cluster_logical_start();
+        let extent = Extent::new(first_block..first_block + blocks_per_cluster, logical_start);
+        ext_fs.register_extent(&mut self.inode, extent, &*self.allocator)?;
+        self.inode.set_size(self.builder.layout().size());
+        Ok(())
     }
 
-    fn allocate_block(&mut self, ext_fs: &mut Ext4Fs) -> Result<()> {
-        self.pad_previous_dentry();
-        self.block = self.allocator.allocate_one()?;
+    /// Like `finalize`, but first adds empty clusters until the directory has
+    /// `DirectoryLayout::lost_found_cluster_count` clusters, as mke2fs does for lost+found.
+    fn finalize_preallocated(mut self, ext_fs: &mut Ext4Fs) -> Result<()> {
+        self.finish_block();
+        while self.builder.layout().cluster_count() < self.builder.layout().lost_found_cluster_count() {
+            self.builder.add_empty_cluster()?;
+            self.cluster = self.allocator.allocate_one(AllocationPurpose::Dentries)?;
+            self.builder.clear_cluster(self.allocator.cluster_mut(&mut self.cluster));
+            self.register_cluster(ext_fs)?;
+        }
+        self.finalize()
+    }
 
-        self.position_in_block = 0;
-        self.block_count += 1;
-        self.previous_dentry = None;
+    /// See `DirBuilder::finish_block`.
+    fn finish_block(&mut self) {
+        let cluster = self.allocator.cluster_mut(&mut self.cluster);
+        self.builder.finish_block(Some(cluster));
+    }
 
-        let extent = Extent::new(
-            self.block.as_block_idx()..self.block.as_block_idx() + 1,
-            u32::try_from(self.block_count - 1)?,
-        );
-        ext_fs.register_extent(&mut self.inode, extent, &self.allocator)?;
-        self.inode.increment_size(u64::fromx(self.block_size));
+    fn complete(&mut self) -> Result<()> {
+        self.finalized = true;
+        self.finish_block();
+        self.inode.set_link_count_from_subdirs(self.link_count_from_subdirs);
         Ok(())
     }
+}
 
-    fn pad_previous_dentry(&mut self) {
-        if self.previous_dentry.is_some() {
-            let remaining_space = u16::try_from(self.remaining_space()).expect(
-                "The only value that could overflow u16 is if `self.block_size == 2^16` and `self.position_in_block \
-                 == 0`. Since `self.previous_dentry` is Some, `self.position_in_block > 0`.",
-            );
-            self.previous_dentry.as_mut().unwrap().increment_dentry_len(remaining_space);
+impl<A: ClusterAllocator> DirectoryWriter for DentryWriter<'_, A> {
+    /// Pads the last dentry to the end of its block and sets the directory's link count.
+    fn finalize(mut self) -> Result<()> {
+        self.complete()
+    }
+}
+
+/// Fallback in case `finalize` is not called, e.g. because the conversion failed: errors are ignored because they
+/// cannot be reported.
+impl<A: ClusterAllocator> Drop for DentryWriter<'_, A> {
+    fn drop(&mut self) {
+        if !self.finalized {
+            let _ = self.complete();
         }
     }
 }
 
-impl DirectoryWriter for DentryWriter<'_> {}
+#[cfg(test)]
+mod tests {
+    use std::marker::PhantomData;
+    use std::mem::size_of;
+
+    use num::Integer;
+    use rand::rngs::ThreadRng;
+    use rand::Rng;
+
+    use super::*;
+    use crate::allocator::tests::FailingAllocator;
+    use crate::error::{exit_code, ErrorCategory};
+    use crate::ext4::{
+        crc32c, BlockIdx, InodeOverrides, DEFAULT_INODE_RATIO, FAST_SYMLINK_MAX_LEN, FEATURE_RO_COMPAT_METADATA_CSUM,
+        LOST_FOUND_INODE_NO, MAX_BLOCK_SIZE, MAX_INODE_RATIO, ROOT_INODE_NO,
+    };
+    use crate::ranges::{NotCoveredRange, Ranges};
+    use crate::serialization::{FileType, StreamArchiver};
+
+    const FS_SIZE: usize = 32 << 20;
+    const MAX_FILE_COUNT: u32 = 1200;
+    const MAX_DEPTH: usize = 3;
+
+    #[test]
+    fn dry_run_is_exact() {
+        let mut rng = rand::thread_rng();
+        for (block_size, cluster_size) in [(1024, 1024), (4096, 4096), (4096, 16384), (65536, 65536)] {
+            for _ in 0..3 {
+                assert_dry_run_is_exact(block_size, cluster_size, false, &mut rng);
+            }
+            assert_dry_run_is_exact(block_size, cluster_size, true, &mut rng);
+        }
+    }
+
+    #[test]
+    fn writes_checksum_tails() {
+        let mut memory = vec![0_u64; FS_SIZE / size_of::<u64>()];
+        let fs_ptr = memory.as_mut_ptr() as *mut u8;
+        let mut superblock = SuperBlock::new(FS_SIZE, 1024, 1024, DEFAULT_INODE_RATIO, &[], 0, None).unwrap();
+        superblock.s_feature_ro_compat |= FEATURE_RO_COMPAT_METADATA_CSUM;
+        // SAFETY: Safe because `memory` outlives `allocator` and is only accessed through it and `Ext4Fs`, which
+        // only accesses the block group overhead.
+        let allocator =
+            unsafe { Allocator::new(fs_ptr, FS_SIZE, 1024, overhead_cluster_ranges(&superblock), PhantomData) };
+
+        // 60 dentries of 28 bytes need two blocks
+        let mut archiver = StreamArchiver::new(Rc::new(allocator), 1024);
+        for file_idx in 0..60 {
+            archiver.archive(vec![FileType::RegularFile]).unwrap();
+            archiver
+                .archive(vec![DentryRepresentation {
+                    access_time: 0,
+                    create_time: 0,
+                    create_time_ns: 0,
+                    mod_time: 0,
+                    file_size: 0,
+                    is_dir: false,
+                    is_read_only: false,
+                    overrides: InodeOverrides::default(),
+                    has_xattrs: false,
+                }])
+                .unwrap();
+            archiver
+                .archive(format!("file with index {:04}", file_idx).into_bytes())
+                .unwrap();
+            archiver.archive(Vec::<Range<ClusterIdx>>::new()).unwrap();
+        }
+        archiver.archive(vec![FileType::EndOfDirectory]).unwrap();
+        let (reader, allocator) = archiver.into_reader().unwrap();
+        {
+            // SAFETY: See above.
+            let ext_fs = unsafe { Ext4Fs::from(fs_ptr, superblock) };
+            let mut deserializer = Ext4TreeDeserializer::new(reader, allocator, ext_fs);
+            deserializer.deserialize_directory_tree().unwrap();
+            deserializer.finalize().unwrap();
+        }
 
-impl Drop for DentryWriter<'_> {
-    fn drop(&mut self) {
-        self.pad_previous_dentry();
-        self.inode.set_link_count_from_subdirs(self.link_count_from_subdirs);
+        let tail_start = 1024 - Ext4DentryTail::LEN;
+        let tail_header = [0, 0, 0, 0, 12, 0, 0, 0xDE];
+        let blocks_with_tail: Vec<_> = memory
+            .chunks_exact(1024 / size_of::<u64>())
+            .map(|block| block.iter().flat_map(|word| word.to_le_bytes()).collect::<Vec<_>>())
+            .filter(|block| block[tail_start..tail_start + tail_header.len()] == tail_header)
+            .collect();
+        // two blocks of the root directory and 16 of lost+found
+        assert_eq!(blocks_with_tail.len(), 18);
+        for block in blocks_with_tail {
+            let checksum = u32::from_le_bytes(block[1020..].try_into().unwrap());
+            let checksum_of = |inode_no| {
+                let seed = Ext4DentryTail::directory_seed(superblock.checksum_seed(), inode_no, 0);
+                crc32c(seed, &block[..tail_start])
+            };
+            assert!(checksum == checksum_of(ROOT_INODE_NO) || checksum == checksum_of(LOST_FOUND_INODE_NO));
+        }
+    }
+
+    #[test]
+    fn reports_converted_files() {
+        let mut memory = vec![0_u64; FS_SIZE / size_of::<u64>()];
+        let fs_ptr = memory.as_mut_ptr() as *mut u8;
+        let superblock = SuperBlock::new(FS_SIZE, 1024, 1024, DEFAULT_INODE_RATIO, &[], 0, None).unwrap();
+        let mut used_ranges = overhead_cluster_ranges(&superblock);
+        let data_start = match used_ranges.next_not_covered(0) {
+            NotCoveredRange::Bounded(range) => range.start,
+            NotCoveredRange::Unbounded(start) => start,
+        };
+        let data_clusters = data_start..data_start + 3;
+        used_ranges.insert(data_clusters.clone());
+        // SAFETY: Safe because `memory` outlives `allocator` and is only accessed through it and `Ext4Fs`, which
+        // only accesses the block group overhead.
+        let allocator = unsafe { Allocator::new(fs_ptr, FS_SIZE, 1024, used_ranges, PhantomData) };
+
+        let dentry = |is_dir, file_size| DentryRepresentation {
+            access_time: 0,
+            create_time: 0,
+            create_time_ns: 0,
+            mod_time: 0,
+            file_size,
+            is_dir,
+            is_read_only: false,
+            overrides: InodeOverrides::default(),
+            has_xattrs: false,
+        };
+        let mut archiver = StreamArchiver::new(Rc::new(allocator), 1024);
+        archiver.archive(vec![FileType::Directory]).unwrap();
+        archiver.archive(vec![dentry(true, 0)]).unwrap();
+        archiver.archive(b"dir".to_vec()).unwrap();
+        archiver.archive(vec![FileType::RegularFile]).unwrap();
+        archiver.archive(vec![dentry(false, 3000)]).unwrap();
+        archiver.archive(b"file".to_vec()).unwrap();
+        archiver.archive(vec![data_clusters.clone()]).unwrap();
+        archiver.archive(vec![FileType::EndOfDirectory]).unwrap();
+        archiver.archive(vec![FileType::Symlink]).unwrap();
+        archiver.archive(vec![dentry(false, 0)]).unwrap();
+        archiver.archive(b"link".to_vec()).unwrap();
+        archiver.archive(b"dir/file".to_vec()).unwrap();
+        archiver.archive(vec![FileType::EndOfDirectory]).unwrap();
+        let (reader, allocator) = archiver.into_reader().unwrap();
+
+        let mut converted_files = Vec::new();
+        {
+            // SAFETY: See above.
+            let ext_fs = unsafe { Ext4Fs::from(fs_ptr, superblock) };
+            let mut deserializer = Ext4TreeDeserializer::new(reader, allocator, ext_fs);
+            deserializer.on_file_converted(|file| {
+                let ranges: Vec<_> = file.extents.iter().map(Extent::as_range).collect();
+                converted_files.push((file.path.to_string(), file.inode_no, ranges));
+            });
+            deserializer.deserialize_directory_tree().unwrap();
+            deserializer.finalize().unwrap();
+        }
+
+        let data_blocks = BlockIdx::fromx(data_clusters.start)..BlockIdx::fromx(data_clusters.end);
+        assert_eq!(
+            converted_files,
+            vec![
+                ("/dir".to_string(), 12, Vec::new()),
+                ("/dir/file".to_string(), 13, vec![data_blocks]),
+                ("/link".to_string(), 14, Vec::new()),
+            ]
+        );
+    }
+
+    #[test]
+    fn fails_cleanly_when_clusters_run_out() {
+        let deserialize = |allocation_count| {
+            let mut memory = vec![0_u64; FS_SIZE / size_of::<u64>()];
+            let fs_ptr = memory.as_mut_ptr() as *mut u8;
+            let superblock = SuperBlock::new(FS_SIZE, 1024, 1024, DEFAULT_INODE_RATIO, &[], 0, None).unwrap();
+            // SAFETY: Safe because `memory` outlives `allocator` and is only accessed through it and `Ext4Fs`, which
+            // only accesses the block group overhead.
+            let allocator =
+                unsafe { Allocator::new(fs_ptr, FS_SIZE, 1024, overhead_cluster_ranges(&superblock), PhantomData) };
+            let mut archiver = StreamArchiver::new(Rc::new(allocator), 1024);
+            // 60 dentries of 28 bytes need two blocks
+            for file_idx in 0..60 {
+                archiver.archive(vec![FileType::Symlink]).unwrap();
+                archiver
+                    .archive(vec![DentryRepresentation {
+                        access_time: 0,
+                        create_time: 0,
+                        create_time_ns: 0,
+                        mod_time: 0,
+                        file_size: 0,
+                        is_dir: false,
+                        is_read_only: false,
+                        overrides: InodeOverrides::default(),
+                        has_xattrs: false,
+                    }])
+                    .unwrap();
+                archiver
+                    .archive(format!("link with index {:04}", file_idx).into_bytes())
+                    .unwrap();
+                // too long for a fast symlink
+                archiver.archive(vec![b'x'; FAST_SYMLINK_MAX_LEN + 1]).unwrap();
+            }
+            archiver.archive(vec![FileType::EndOfDirectory]).unwrap();
+            let (reader, allocator) = archiver.into_reader().unwrap();
+
+            // SAFETY: See above.
+            let ext_fs = unsafe { Ext4Fs::from(fs_ptr, superblock) };
+            let allocator = FailingAllocator::new(allocator, allocation_count);
+            let mut deserializer = Ext4TreeDeserializer::new(reader, allocator, ext_fs);
+            deserializer.deserialize_directory_tree()
+        };
+
+        // the first block of the root directory, the 16 blocks of lost+found and the extent tree block they need, one
+        // block per slow symlink, and the second block of the root directory
+        let required_allocation_count = (0..)
+            .find(|&allocation_count| match deserialize(allocation_count) {
+                Ok(()) => true,
+                Err(error) => {
+                    assert_eq!(exit_code(&error), ErrorCategory::InsufficientSpace.exit_code());
+                    false
+                }
+            })
+            .unwrap();
+        assert_eq!(required_allocation_count, 1 + 16 + 1 + 60 + 1);
+    }
+
+    /// Converts a random directory tree with a skeleton and checks that the dry run requires exactly as many clusters
+    /// as the conversion actually allocated.
+    fn assert_dry_run_is_exact(block_size: u32, cluster_size: u32, metadata_csum: bool, rng: &mut ThreadRng) {
+        let mut memory = vec![0_u64; FS_SIZE / size_of::<u64>()];
+        let fs_ptr = memory.as_mut_ptr() as *mut u8;
+        // a block group of 64 KiB blocks is so large that the inode table for the default ratio exceeds `FS_SIZE`
+        let inode_ratio = if block_size == MAX_BLOCK_SIZE {
+            MAX_INODE_RATIO
+        } else {
+            DEFAULT_INODE_RATIO
+        };
+        let mut superblock = SuperBlock::new(FS_SIZE, block_size, cluster_size, inode_ratio, &[], 0, None).unwrap();
+        if metadata_csum {
+            superblock.s_feature_ro_compat |= FEATURE_RO_COMPAT_METADATA_CSUM;
+        }
+
+        let mut used_ranges = overhead_cluster_ranges(&superblock);
+        // the files' data must not cross block group boundaries, so it is placed within the first block group
+        let data_clusters = match used_ranges.next_not_covered(0) {
+            NotCoveredRange::Bounded(range) => range.start..range.start + (range.end - range.start) / 2,
+            NotCoveredRange::Unbounded(start) => start..start + (fs_cluster_count(&superblock) - start) / 2,
+        };
+        used_ranges.insert(data_clusters.clone());
+        // SAFETY: Safe because `memory` outlives `allocator` and is only accessed through it and `Ext4Fs`, which
+        // only accesses the block group overhead.
+        let allocator =
+            unsafe { Allocator::new(fs_ptr, FS_SIZE, usize::fromx(cluster_size), used_ranges, PhantomData) };
+
+        // the random names are lowercase, so the skeleton's directories are always added
+        let mut skeleton = Skeleton::default();
+        skeleton.add("/Skeleton/A", 0o700, None).unwrap();
+        skeleton
+            .add("/Skeleton/B/C", 0o755, Some(Owner { uid: 1000, gid: 1000 }))
+            .unwrap();
+        let skeleton = Rc::new(skeleton);
+        let skeleton_dir_count = u32::try_from(skeleton.dir_count()).unwrap();
+
+        let mut archiver = StreamArchiver::new(Rc::new(allocator), usize::fromx(cluster_size));
+        // one inode for lost+found
+        let file_count = MAX_FILE_COUNT.min(superblock.allocatable_inode_count() - 1 - skeleton_dir_count);
+        let mut generator = TreeGenerator {
+            rng,
+            remaining_files: file_count,
+            data_clusters,
+            cluster_size,
+            block_size,
+        };
+        generator.archive_root(&mut archiver);
+        let (reader, allocator) = archiver.into_reader().unwrap();
+
+        let free_clusters = allocator.free_block_count();
+        // SAFETY: See above.
+        let ext_fs = unsafe { Ext4Fs::from(fs_ptr, superblock) };
+        let mut deserializer = Ext4TreeDeserializer::new(reader.clone(), allocator, ext_fs);
+        deserializer.internals.skeleton = Rc::clone(&skeleton);
+        deserializer.deserialize_directory_tree().unwrap();
+        let used_clusters = free_clusters - deserializer.internals.allocator.free_block_count();
+        let actual_usage = deserializer.actual_usage();
+        deserializer.finalize().unwrap();
+        assert_eq!(actual_usage.clusters, used_clusters);
+
+        let free_inodes = superblock.allocatable_inode_count();
+        let block_size = superblock.block_size();
+        let blocks_per_cluster = superblock.blocks_per_cluster();
+        let dry_run = |free_clusters| {
+            DryRunDeserializer::dry_run(
+                reader.clone(),
+                free_inodes,
+                free_clusters,
+                block_size,
+                blocks_per_cluster,
+                metadata_csum,
+                Rc::clone(&skeleton),
+            )
+        };
+        let predicted_usage = dry_run(used_clusters).expect("Dry run requires more clusters than the conversion");
+        assert_eq!(predicted_usage, actual_usage);
+        dry_run(used_clusters - 1).expect_err("Dry run requires fewer clusters than the conversion");
+    }
+
+    fn overhead_cluster_ranges(superblock: &SuperBlock) -> Ranges<ClusterIdx> {
+        let blocks_per_cluster = BlockIdx::fromx(superblock.blocks_per_cluster());
+        let to_cluster_idx = |block_idx: BlockIdx| ClusterIdx::try_from(block_idx).unwrap();
+        let mut ranges: Ranges<ClusterIdx> = superblock
+            .block_group_overhead_ranges()
+            .into_iter()
+            .map(|range| {
+                to_cluster_idx(range.start / blocks_per_cluster)
+                    ..to_cluster_idx(range.end.div_ceil(&blocks_per_cluster))
+            })
+            .collect();
+        ranges.insert(to_cluster_idx(superblock.cluster_count_with_padding())..fs_cluster_count(superblock));
+        ranges
+    }
+
+    fn fs_cluster_count(superblock: &SuperBlock) -> ClusterIdx {
+        ClusterIdx::try_from(FS_SIZE / usize::fromx(superblock.cluster_size())).unwrap()
+    }
+
+    /// Archives random directory trees in the format written by `FatTreeSerializer`.
+    struct TreeGenerator<'r> {
+        rng: &'r mut ThreadRng,
+        remaining_files: u32,
+        /// The clusters not yet used by any file
+        data_clusters: Range<ClusterIdx>,
+        cluster_size: u32,
+        block_size: u32,
+    }
+
+    impl TreeGenerator<'_> {
+        fn archive_root(&mut self, archiver: &mut StreamArchiver) {
+            let child_count = self.child_count();
+            self.archive_children(archiver, child_count, 0);
+        }
+
+        fn archive_children(&mut self, archiver: &mut StreamArchiver, child_count: u32, depth: usize) {
+            for _ in 0..child_count {
+                if depth < MAX_DEPTH && self.rng.gen_bool(0.05) {
+                    self.archive_directory(archiver, depth + 1);
+                } else if self.rng.gen_bool(0.05) {
+                    self.archive_symlink(archiver);
+                } else {
+                    self.archive_regular_file(archiver);
+                }
+            }
+            archiver.archive(vec![FileType::EndOfDirectory]).unwrap();
+        }
+
+        fn archive_directory(&mut self, archiver: &mut StreamArchiver, depth: usize) {
+            let child_count = self.child_count();
+            archiver.archive(vec![FileType::Directory]).unwrap();
+            archiver.archive(vec![self.dentry(true, 0)]).unwrap();
+            archiver.archive(self.name().into_bytes()).unwrap();
+            self.archive_children(archiver, child_count, depth);
+        }
+
+        fn archive_regular_file(&mut self, archiver: &mut StreamArchiver) {
+            let (fragment_count, max_fragment_len) = if self.rng.gen_bool(0.005) {
+                (self.rng.gen_range(300..1500), 1)
+            } else {
+                (self.rng.gen_range(0..4), 2)
+            };
+            let data_ranges: Vec<_> = (0..fragment_count)
+                .map_while(|_| {
+                    let len = self.rng.gen_range(1..=max_fragment_len);
+                    self.take_data_clusters(len)
+                })
+                .collect();
+            let cluster_count: u32 = data_ranges.iter().map(|range| range.end - range.start).sum();
+            // the last cluster may be partially used
+            let file_size =
+                (cluster_count * self.cluster_size).saturating_sub(self.rng.gen_range(0..self.cluster_size));
+
+            archiver.archive(vec![FileType::RegularFile]).unwrap();
+            archiver.archive(vec![self.dentry(false, file_size)]).unwrap();
+            archiver.archive(self.name().into_bytes()).unwrap();
+            archiver.archive(data_ranges).unwrap();
+        }
+
+        /// Archives fast and slow symlinks in equal parts.
+        fn archive_symlink(&mut self, archiver: &mut StreamArchiver) {
+            let target_len = if self.rng.gen_bool(0.5) {
+                self.rng.gen_range(1..=FAST_SYMLINK_MAX_LEN)
+            } else {
+                self.rng.gen_range(FAST_SYMLINK_MAX_LEN + 1..usize::fromx(self.block_size))
+            };
+            let target: String = (0..target_len).map(|_| self.rng.gen_range('a'..='z')).collect();
+            archiver.archive(vec![FileType::Symlink]).unwrap();
+            archiver.archive(vec![self.dentry(false, 0)]).unwrap();
+            archiver.archive(self.name().into_bytes()).unwrap();
+            archiver.archive(target.into_bytes()).unwrap();
+        }
+
+        /// Returns None if there are not enough unused data clusters left.
+        fn take_data_clusters(&mut self, len: ClusterIdx) -> Option<Range<ClusterIdx>> {
+            let start = self.data_clusters.start;
+            let end = start.checked_add(len).filter(|&end| end <= self.data_clusters.end)?;
+            self.data_clusters.start = end;
+            Some(start..end)
+        }
+
+        fn child_count(&mut self) -> u32 {
+            let child_count = self.rng.gen_range(0..=self.remaining_files);
+            self.remaining_files -= child_count;
+            child_count
+        }
+
+        fn dentry(&self, is_dir: bool, file_size: u32) -> DentryRepresentation {
+            DentryRepresentation {
+                access_time: 0,
+                create_time: 0,
+                create_time_ns: 0,
+                mod_time: 0,
+                file_size,
+                is_dir,
+                is_read_only: false,
+                overrides: InodeOverrides::default(),
+                has_xattrs: false,
+            }
+        }
+
+        fn name(&mut self) -> String {
+            let len = self.rng.gen_range(1..=255);
+            (0..len).map(|_| self.rng.gen_range('a'..='z')).collect()
+        }
     }
 }
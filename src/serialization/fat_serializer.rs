@@ -1,29 +1,73 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
 use std::ops::Range;
 use std::rc::Rc;
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
+use num::Integer;
 
-use crate::allocator::Allocator;
+use crate::allocator::{AllocationPurpose, Allocator, ClusterAllocator};
+use crate::error::ErrorCategory;
+use crate::ext4::{encode_xattrs, InodeOverrides, SuperBlock, Xattr};
 use crate::fat::{ClusterIdx, DataClusterIdx, FatDentry, FatFile, FatFs, FatTableIndex, ROOT_FAT_IDX};
+use crate::messages::trf;
 use crate::ranges::Ranges;
-use crate::serialization::{DentryRepresentation, Ext4TreeDeserializer, FileType, StreamArchiver};
-use crate::util::FromU32;
+use crate::serialization::{
+    ArchiveBitFile, ArchiveLocation, ArchiveVerifier, ConversionPolicy, DentryOrder, DentryRepresentation, ErrorPolicy,
+    ExclusionStats, Ext4TreeDeserializer, FileOp, FileType, HardLinkFolder, HardLinkStats, InvalidAttributesFile,
+    InvalidAttributesPolicy, LongNameChecker, Reader, ResourceUsage, SkippedFile, StreamArchiver, TruncatedFile,
+    Verdict,
+};
+use crate::skeleton::Skeleton;
+use crate::util::{FromU32, RateLimiter};
 
 
-pub struct FatTreeSerializer<'a> {
+pub struct FatTreeSerializer<'a, A: ClusterAllocator = Allocator<'a>> {
     fat_fs: FatFs<'a>,
-    allocator: Rc<Allocator<'a>>, /* Rc to be shared with `self.stream_archiver` and nobody else, otherwise
-                                   * `into_deserializer` will panic */
-    stream_archiver: RefCell<StreamArchiver<'a>>, /* `serialize_directory` borrows `self.fat_fs` twice, so it has
-                                                   * to borrow `self` immutably. However, it also needs to mutate
-                                                   * `self.stream_archiver`, so we wrap it in a RefCell. */
+    allocator: Rc<A>, /* Rc to be shared with `self.stream_archiver` and nobody else, otherwise
+                       * `into_deserializer` will panic */
+    stream_archiver: RefCell<StreamArchiver<'a, A>>, /* `serialize_directory` borrows `self.fat_fs` twice, so it has
+                                                      * to borrow `self` immutably. However, it also needs to mutate
+                                                      * `self.stream_archiver`, so we wrap it in a RefCell. */
     forbidden_ranges: Ranges<ClusterIdx>, /* ranges that cannot contain any data as they will be overwritten with
                                            * ext4 metadata */
+    policies: RefCell<Vec<Box<dyn ConversionPolicy + 'a>>>, // RefCell for the same reason as `stream_archiver`
+    ops: RefCell<Vec<Box<dyn FileOp + 'a>>>,                // RefCell for the same reason as `stream_archiver`
+    exclusion_stats: Cell<ExclusionStats>,
+    long_names: RefCell<LongNameChecker>, // RefCell for the same reason as `stream_archiver`
+    /// maps the paths of the files that the policies renamed to their new names, for the `ArchiveVerifier`
+    renames: RefCell<HashMap<String, String>>, // RefCell for the same reason as `stream_archiver`
+    truncated_files: RefCell<Vec<TruncatedFile>>, // RefCell for the same reason as `stream_archiver`
+    verify_archival: bool,
+    /// the percentage of the clusters for files that must remain free after the conversion
+    min_free_percent: u8,
+    file_count: Cell<usize>,
+    /// the clusters of the serialized regular files, after the relocation
+    data_cluster_count: Cell<usize>,
+    /// None unless the files with the archive flag are listed
+    archive_bit_files: RefCell<Option<Vec<ArchiveBitFile>>>, // RefCell for the same reason as `stream_archiver`
+    error_policy: ErrorPolicy,
+    invalid_attributes_policy: InvalidAttributesPolicy,
+    /// the files with invalid attributes that were skipped or converted as regular files
+    invalid_attributes_files: RefCell<Vec<InvalidAttributesFile>>, // RefCell for the same reason as `stream_archiver`
+    dentry_order: DentryOrder,
+    /// limits the rate at which the relocation copies clusters
+    rate_limiter: Option<RateLimiter>,
+    skipped_files: RefCell<Vec<SkippedFile>>, // RefCell for the same reason as `stream_archiver`
+    /// called with the path of every file that the tree walk reaches
+    on_file_reached: RefCell<Option<PathCallback<'a>>>, // RefCell for the same reason as `stream_archiver`
+    /// the directories that the deserializer adds after the serialized files
+    skeleton: Rc<Skeleton>,
+    /// None unless duplicate regular files are turned into hard links
+    hard_link_folder: RefCell<Option<HardLinkFolder>>, // RefCell for the same reason as `stream_archiver`
+    /// the number of archived `RegularFile` records, which hard links refer to by their index
+    regular_file_count: Cell<usize>,
 }
 
-impl<'a> FatTreeSerializer<'a> {
-    pub fn new(allocator: Allocator<'a>, fat_fs: FatFs<'a>, forbidden_ranges: Ranges<ClusterIdx>) -> Self {
+type PathCallback<'a> = Box<dyn FnMut(&str) + 'a>;
+
+impl<'a, A: ClusterAllocator + 'a> FatTreeSerializer<'a, A> {
+    pub fn new(allocator: A, fat_fs: FatFs<'a>, forbidden_ranges: Ranges<ClusterIdx>) -> Self {
         let allocator = Rc::new(allocator);
         let stream_archiver = StreamArchiver::new(allocator.clone(), usize::fromx(fat_fs.cluster_size()));
         Self {
@@ -31,93 +75,484 @@ impl<'a> FatTreeSerializer<'a> {
             fat_fs,
             stream_archiver: RefCell::new(stream_archiver),
             forbidden_ranges,
+            policies: RefCell::new(Vec::new()),
+            ops: RefCell::new(Vec::new()),
+            exclusion_stats: Cell::new(ExclusionStats::default()),
+            long_names: RefCell::new(LongNameChecker::new()),
+            renames: RefCell::new(HashMap::new()),
+            truncated_files: RefCell::new(Vec::new()),
+            verify_archival: false,
+            min_free_percent: 0,
+            file_count: Cell::new(0),
+            data_cluster_count: Cell::new(0),
+            archive_bit_files: RefCell::new(None),
+            error_policy: ErrorPolicy::FailFast,
+            invalid_attributes_policy: InvalidAttributesPolicy::Reject,
+            invalid_attributes_files: RefCell::new(Vec::new()),
+            dentry_order: DentryOrder::Fat,
+            rate_limiter: None,
+            skipped_files: RefCell::new(Vec::new()),
+            on_file_reached: RefCell::new(None),
+            skeleton: Rc::default(),
+            hard_link_folder: RefCell::new(None),
+            regular_file_count: Cell::new(0),
         }
     }
 
+    /// Appends `policy` to the policies that decide for every file before the ops, see `ConversionPolicy`.
+    pub fn add_policy<P: ConversionPolicy + 'a>(&mut self, policy: P) {
+        self.policies.get_mut().push(Box::new(policy));
+    }
+
+    /// Appends `op` to the ops that are applied to every file, see `FileOp`.
+    pub fn add_op<O: FileOp + 'a>(&mut self, op: O) {
+        self.ops.get_mut().push(Box::new(op));
+    }
+
+    /// Sets whether `into_deserializer` reads back the serialized directory tree and compares it to the FAT
+    /// filesystem before the dry run, see `ArchiveVerifier`. By default, it does not.
+    pub fn set_verify_archival(&mut self, verify_archival: bool) {
+        self.verify_archival = verify_archival;
+    }
+
+    /// Sets the percentage of the clusters for files that must remain free after the conversion, which
+    /// `into_deserializer` and `into_archive_location` check after the dry run. By default, it is 0.
+    pub fn set_min_free_percent(&mut self, percent: u8) {
+        self.min_free_percent = percent;
+    }
+
+    /// Sets what to do with files that cannot be converted. By default, the serialization fails.
+    pub fn set_error_policy(&mut self, policy: ErrorPolicy) {
+        self.error_policy = policy;
+    }
+
+    /// Sets what to do with files whose attributes are an invalid combination. By default, they are rejected, which
+    /// fails the serialization unless the error policy is `ErrorPolicy::CollectErrors`.
+    pub fn set_invalid_attributes_policy(&mut self, policy: InvalidAttributesPolicy) {
+        self.invalid_attributes_policy = policy;
+    }
+
+    /// Sets the order in which the children of every directory are serialized, which is the order of their dentries in
+    /// ext4, since both the dry run and the conversion deserialize them in this order. By default, it is the FAT order.
+    pub fn set_dentry_order(&mut self, order: DentryOrder) {
+        self.dentry_order = order;
+    }
+
+    /// Limits the rate at which the relocation copies file data to `bytes_per_sec`. By default, it is unlimited.
+    pub fn set_rate_limit(&mut self, bytes_per_sec: u64) {
+        self.rate_limiter = Some(RateLimiter::new(bytes_per_sec));
+    }
+
+    /// Calls `callback` with the path of every file and directory that `serialize_directory_tree` reaches, before it is
+    /// relocated and archived, so that the caller can report the progress.
+    pub fn on_file_reached<F: FnMut(&str) + 'a>(&mut self, callback: F) {
+        *self.on_file_reached.get_mut() = Some(Box::new(callback));
+    }
+
+    /// Sets the directories that the deserializer adds after the serialized files, which the dry run of
+    /// `into_deserializer` and `into_archive_location` accounts for. By default, there are none.
+    pub fn set_skeleton(&mut self, skeleton: Rc<Skeleton>) {
+        self.skeleton = skeleton;
+    }
+
+    /// Makes the serializer turn regular files whose content is the same as that of a previously serialized one into
+    /// hard links to it, see `HardLinkFolder`.
+    pub fn fold_duplicates_into_hard_links(&mut self) {
+        self.hard_link_folder.get_mut().get_or_insert_with(HardLinkFolder::default);
+    }
+
+    /// Makes the serializer list the regular files and symlinks whose archive flag is set, see `archive_bit_files`.
+    pub fn list_archive_bit_files(&mut self) {
+        self.archive_bit_files.get_mut().get_or_insert_with(Vec::new);
+    }
+
+    /// The serialized regular files and symlinks whose archive flag is set, or an empty list unless
+    /// `list_archive_bit_files` was called.
+    pub fn archive_bit_files(&self) -> Vec<ArchiveBitFile> {
+        self.archive_bit_files.borrow().clone().unwrap_or_default()
+    }
+
+    /// Returns the files that were left out of the serialized directory tree by an op.
+    pub fn exclusion_stats(&self) -> ExclusionStats {
+        self.exclusion_stats.get()
+    }
+
+    /// Returns the regular files that were turned into hard links and the clusters that were saved, see
+    /// `fold_duplicates_into_hard_links`.
+    pub fn hard_link_stats(&self) -> HardLinkStats {
+        self.hard_link_folder
+            .borrow()
+            .as_ref()
+            .map(HardLinkFolder::stats)
+            .unwrap_or_default()
+    }
+
+    /// Returns the number of files and directories in the serialized directory tree that need an inode, i.e.
+    /// excluding the root directory and hard links.
+    pub fn file_count(&self) -> usize {
+        self.file_count.get()
+    }
+
+    /// Returns the regular files whose cluster chains were shorter than their size, which were truncated accordingly.
+    pub fn truncated_files(&self) -> Vec<TruncatedFile> {
+        self.truncated_files.borrow().clone()
+    }
+
+    /// Returns the files with invalid attributes that were skipped or converted as regular files, which is always empty
+    /// if the invalid attributes policy is `InvalidAttributesPolicy::Reject`.
+    pub fn invalid_attributes_files(&self) -> Vec<InvalidAttributesFile> {
+        self.invalid_attributes_files.borrow().clone()
+    }
+
+    /// Returns the files that could not be converted and were left out of the serialized directory tree, which is
+    /// always empty unless the error policy is `ErrorPolicy::CollectErrors`.
+    pub fn skipped_files(&self) -> Vec<SkippedFile> {
+        self.skipped_files.borrow().clone()
+    }
+
+    /// Serializes the directory tree in four stages per file: the tree walk (`serialize_children`) reads the file from
+    /// its parent directory, where `FatFileIter` decodes its name; the op stage (`included_children`) consults
+    /// `self.policies`, applies `self.ops` and consults the policies again for the whole directory; the relocation
+    /// stage (`relocate`) copies data that would be overwritten by ext4 metadata; and the archive stage (`archive_*`)
+    /// writes the result to `self.stream_archiver`. Files excluded by a policy or an op never reach the relocation
+    /// stage, so their data is never copied. Names that are still too long for ext4 after the op stage, e.g. because
+    /// no `LongNameTruncator` is among the policies, are rejected after the whole tree has been serialized, so that all
+    /// of them can be reported at once. Regular files whose cluster chain is shorter than their size are
+    /// truncated before the relocation stage, so that the dry run and the conversion agree on their size. Files that
+    /// cannot be converted because an op fails or `check_file` rejects them are handled according to the
+    /// `ErrorPolicy` at the end of the op stage; a skipped directory is skipped along with its content. Before the ops,
+    /// volume labels are left out and files with invalid attributes are handled according to the
+    /// `InvalidAttributesPolicy`, so that they never reach the tree walk as directories.
+    /// Directories are not relocated, even if their clusters lie in `self.forbidden_ranges`: a directory is read
+    /// completely before any of its children is archived, the relocation and the archive only write to free clusters,
+    /// and the deserializer rebuilds every directory from the archive in newly allocated clusters. The FAT directories
+    /// are only overwritten once the deserializer writes the ext4 metadata.
     pub fn serialize_directory_tree(&mut self) -> Result<()> {
+        self.fat_fs
+            .check_directory(ROOT_FAT_IDX)
+            .context("The root directory is damaged")?;
         // SAFETY: safe because `ROOT_FAT_IDX` belongs to the root directory
-        let root_child_count = unsafe { self.fat_fs.dir_content_iter(ROOT_FAT_IDX).count() };
-        self.archive_root_child_count(
-            u32::try_from(root_child_count).expect("Directory cannot have more children than fs has clusters"),
-        )?;
-        // SAFETY: safe because `ROOT_FAT_IDX` belongs to the root directory
-        unsafe { self.serialize_directory_content(ROOT_FAT_IDX) }
+        let root_children = unsafe { self.included_children(ROOT_FAT_IDX, "")? };
+        self.serialize_children(root_children, "")?;
+        self.long_names.borrow().result()
     }
 
-    fn serialize_directory(&self, file: FatFile) -> Result<()> {
-        assert!(file.dentry.is_dir());
-        let first_fat_idx = file.dentry.first_fat_index();
-        // SAFETY: safe because `first_fat_index` belongs to a directory
-        let child_count = unsafe { self.fat_fs.dir_content_iter(first_fat_idx).count() };
-        self.archive_directory(
-            file,
-            u32::try_from(child_count).expect("Directory cannot have more children than fs has clusters"),
-        )?;
-        // SAFETY: safe because `first_fat_index` belongs to a directory
-        unsafe {
-            self.serialize_directory_content(first_fat_idx)?;
+    /// Archives `children` followed by the end of their directory. `dir_path` is the path of the directory containing
+    /// `children`, which is empty for the root directory.
+    fn serialize_children(&self, children: Vec<FatFile>, dir_path: &str) -> Result<()> {
+        for mut file in children {
+            if let Some(callback) = self.on_file_reached.borrow_mut().as_mut() {
+                callback(&format!("{}/{}", dir_path, file.name));
+            }
+            if file.dentry.is_dir() {
+                let path = format!("{}/{}", dir_path, file.name);
+                // SAFETY: safe because `first_fat_index` belongs to a directory
+                let grandchildren = unsafe { self.included_children(file.dentry.first_fat_index(), &path)? };
+                self.archive_directory(file)?;
+                self.serialize_children(grandchildren, &path)?;
+                continue;
+            }
+
+            self.record_archive_bit(&file, dir_path)?;
+            if let Some(target) = file.symlink_target.take() {
+                self.archive_symlink(file, target)?;
+            } else {
+                let cluster_size = self.fat_fs.cluster_size();
+                if let Some(truncated) = TruncatedFile::normalize(&mut file, dir_path, cluster_size) {
+                    self.truncated_files.borrow_mut().push(truncated);
+                }
+                if let Some(regular_file_idx) = self.find_original(&file)? {
+                    self.archive_hard_link(file, regular_file_idx)?;
+                    continue;
+                }
+                let relocated = self.relocate(file)?;
+                self.archive_regular_file(relocated)?;
+            }
         }
-        Ok(())
+        self.archive_end_of_directory()
     }
 
+    /// The op stage: returns the files in the directory at `dir_path` that are neither volume labels nor skipped for
+    /// their attributes, that no policy in `self.policies` or op in `self.ops` excludes and that can be converted,
+    /// records the excluded ones in `self.exclusion_stats` and hands the others to `skip_file`. The files are returned
+    /// in `self.dentry_order`. Fails if the policies give two files the same name.
     /// SAFETY: safe if `first_fat_idx` points to a cluster belonging to a directory
-    unsafe fn serialize_directory_content(&self, first_fat_idx: FatTableIndex) -> Result<()> {
+    unsafe fn included_children(&self, first_fat_idx: FatTableIndex, dir_path: &str) -> Result<Vec<FatFile>> {
         // SAFETY: safe because `first_fat_index` belongs to a directory
         let iter = unsafe { self.fat_fs.dir_content_iter(first_fat_idx) };
-        for file in iter {
-            if file.dentry.is_dir() {
-                self.serialize_directory(file)?;
-            } else {
-                let non_overlapping = self.make_file_non_overlapping(file)?;
-                self.archive_regular_file(non_overlapping)?;
+        let mut policies = self.policies.borrow_mut();
+        let mut ops = self.ops.borrow_mut();
+        let mut included = Vec::new();
+        // maps the names decided by the policies to the FAT names, which ops may rename without being tracked
+        let mut fat_names = HashMap::new();
+        for mut file in iter {
+            let fat_name = file.name.clone();
+            let result = match self
+                .check_attributes(&mut file, dir_path)
+                .and_then(|verdict| match verdict {
+                    Verdict::Include => self.apply_policies(&mut policies, &mut file, dir_path),
+                    Verdict::Exclude => Ok(Verdict::Exclude),
+                })
+                .and_then(|verdict| match verdict {
+                    Verdict::Include => {
+                        fat_names.insert(file.name.clone(), fat_name);
+                        self.apply_ops(&mut ops, &mut file)
+                    }
+                    Verdict::Exclude => Ok(Verdict::Exclude),
+                }) {
+                Ok(Verdict::Include) => self.check_file(&file),
+                Ok(Verdict::Exclude) => continue,
+                Err(error) => Err(error),
+            };
+            match result {
+                Ok(()) => included.push(file),
+                Err(error) => self.skip_file(format!("{}/{}", dir_path, file.name), error)?,
+            }
+        }
+        let mut included = self.apply_directory_policies(&mut policies, included, dir_path, &mut fat_names)?;
+        if !policies.is_empty() {
+            check_unique_names(&included, dir_path)?;
+            self.record_renames(&included, dir_path, &fat_names);
+        }
+        self.long_names.borrow_mut().check_directory(dir_path, &included);
+        // after the policies, which may rename files
+        self.dentry_order.apply(&mut included);
+        Ok(included)
+    }
+
+    /// Excludes volume labels, which are not files, and applies `self.invalid_attributes_policy` to `file` if its
+    /// attributes are invalid. Fails if the policy rejects `file`.
+    fn check_attributes(&self, file: &mut FatFile, dir_path: &str) -> Result<Verdict> {
+        if file.dentry.is_volume_label() && file.dentry.attribute_problem().is_none() {
+            return Ok(Verdict::Exclude);
+        }
+        let cluster_size = self.fat_fs.cluster_size();
+        match self.invalid_attributes_policy.apply(file, dir_path, cluster_size)? {
+            Some(invalid) => {
+                let verdict = match invalid.policy {
+                    InvalidAttributesPolicy::Skip => Verdict::Exclude,
+                    _ => Verdict::Include,
+                };
+                self.invalid_attributes_files.borrow_mut().push(invalid);
+                Ok(verdict)
+            }
+            None => Ok(Verdict::Include),
+        }
+    }
+
+    fn apply_policies(
+        &self,
+        policies: &mut [Box<dyn ConversionPolicy + 'a>],
+        file: &mut FatFile,
+        dir_path: &str,
+    ) -> Result<Verdict> {
+        for policy in policies.iter_mut() {
+            let decision = policy.decide(file, &format!("{}/{}", dir_path, file.name))?;
+            if decision.exclude {
+                self.record_exclusion(file);
+                return Ok(Verdict::Exclude);
+            }
+            decision.apply(file)?;
+        }
+        Ok(Verdict::Include)
+    }
+
+    /// Consults `policies` for the `children` of the directory at `dir_path` that are left after the ops, see
+    /// `ConversionPolicy::decide_directory`, and returns those that they do not exclude. `fat_names` follows their
+    /// renames.
+    fn apply_directory_policies(
+        &self,
+        policies: &mut [Box<dyn ConversionPolicy + 'a>],
+        mut children: Vec<FatFile>,
+        dir_path: &str,
+        fat_names: &mut HashMap<String, String>,
+    ) -> Result<Vec<FatFile>> {
+        for policy in policies.iter_mut() {
+            let decisions = policy
+                .decide_directory(dir_path, &children)
+                .with_context(|| format!("Unable to convert the directory {}/", dir_path))?;
+            if decisions.len() != children.len() {
+                bail!(
+                    "A policy returned {} decisions for the {} files in {}/",
+                    decisions.len(),
+                    children.len(),
+                    dir_path
+                );
+            }
+            let mut decided = Vec::with_capacity(children.len());
+            for (mut file, decision) in children.into_iter().zip(decisions) {
+                if decision.exclude {
+                    self.record_exclusion(&file);
+                    continue;
+                }
+                let name = file.name.clone();
+                match decision.apply(&mut file) {
+                    Ok(()) => {
+                        if let Some(fat_name) = fat_names.remove(&name) {
+                            fat_names.insert(file.name.clone(), fat_name);
+                        }
+                        decided.push(file);
+                    }
+                    Err(error) => self.skip_file(format!("{}/{}", dir_path, name), error)?,
+                }
+            }
+            children = decided;
+        }
+        Ok(children)
+    }
+
+    /// Records the `children` of the directory at `dir_path` that the policies renamed in `self.renames`, by their
+    /// FAT names in `fat_names`.
+    fn record_renames(&self, children: &[FatFile], dir_path: &str, fat_names: &HashMap<String, String>) {
+        let mut renames = self.renames.borrow_mut();
+        for file in children {
+            match fat_names.get(&file.name) {
+                Some(fat_name) if *fat_name != file.name => {
+                    renames.insert(format!("{}/{}", dir_path, fat_name), file.name.clone());
+                }
+                _ => (),
+            }
+        }
+    }
+
+    fn apply_ops(&self, ops: &mut [Box<dyn FileOp + 'a>], file: &mut FatFile) -> Result<Verdict> {
+        for op in ops.iter_mut() {
+            if op.apply(file, &self.fat_fs)? == Verdict::Exclude {
+                self.record_exclusion(file);
+                return Ok(Verdict::Exclude);
+            }
+        }
+        Ok(Verdict::Include)
+    }
+
+    fn record_exclusion(&self, file: &FatFile) {
+        let mut stats = self.exclusion_stats.get();
+        stats.add(file);
+        self.exclusion_stats.set(stats);
+    }
+
+    /// Returns an error if `file` cannot be converted, see `check_convertible`. Only errors that concern this file
+    /// alone belong here, since `ErrorPolicy::CollectErrors` continues with the other files.
+    fn check_file(&self, file: &FatFile) -> Result<()> {
+        check_convertible(file, &self.fat_fs)
+    }
+
+    /// Fails with `error` if the error policy is `ErrorPolicy::FailFast`, otherwise records the file at `path` as
+    /// skipped.
+    fn skip_file(&self, path: String, error: anyhow::Error) -> Result<()> {
+        match self.error_policy {
+            ErrorPolicy::FailFast => Err(error.context(format!("Unable to convert {}", path))),
+            ErrorPolicy::CollectErrors => {
+                self.skipped_files
+                    .borrow_mut()
+                    .push(SkippedFile { path, error: format!("{:#}", error) });
+                Ok(())
+            }
+        }
+    }
+
+    fn record_archive_bit(&self, file: &FatFile, dir_path: &str) -> Result<()> {
+        if let Some(archive_bit_files) = self.archive_bit_files.borrow_mut().as_mut() {
+            if file.dentry.has_archive_flag() {
+                archive_bit_files.push(ArchiveBitFile {
+                    path: format!("{}/{}", dir_path, file.name),
+                    mod_time: file.dentry.modify_time_as_unix()?,
+                });
             }
         }
         Ok(())
     }
 
-    fn archive_root_child_count(&self, root_child_count: u32) -> Result<()> {
+    /// Returns the index of the regular file that `file` duplicates if duplicates are turned into hard links.
+    fn find_original(&self, file: &FatFile) -> Result<Option<usize>> {
+        match self.hard_link_folder.borrow_mut().as_mut() {
+            Some(folder) => folder.fold(file, self.regular_file_count.get(), &self.fat_fs),
+            None => Ok(None),
+        }
+    }
+
+    fn archive_end_of_directory(&self) -> Result<()> {
         let mut archiver = self.stream_archiver.borrow_mut();
-        archiver.archive(vec![FileType::Directory(root_child_count)])?;
+        archiver.archive(vec![FileType::EndOfDirectory])?;
         Ok(())
     }
 
     fn archive_regular_file(&self, file: NonOverlappingFatFile) -> Result<()> {
+        self.file_count.set(self.file_count.get() + 1);
+        self.regular_file_count.set(self.regular_file_count.get() + 1);
+        let cluster_count: usize = file.data_ranges.iter().map(ExactSizeIterator::len).sum();
+        self.data_cluster_count.set(self.data_cluster_count.get() + cluster_count);
         let mut archiver = self.stream_archiver.borrow_mut();
-        archiver.archive(vec![FileType::RegularFile])?;
-        archiver.archive(vec![DentryRepresentation::from(file.dentry)?])?;
-        archiver.archive(file.name.into_bytes())?;
+        archive_head(&mut archiver, FileType::RegularFile, file.head)?;
         archiver.archive(file.data_ranges)?;
         Ok(())
     }
 
-    fn archive_directory(&self, file: FatFile, child_count: u32) -> Result<()> {
+    /// The hard link shares the inode and data of the regular file with the index `regular_file_idx`, so its data is
+    /// not archived and does not need to be relocated. `HardLinkFolder` leaves files with extended attributes alone,
+    /// so it has none.
+    fn archive_hard_link(&self, file: FatFile, regular_file_idx: usize) -> Result<()> {
         let mut archiver = self.stream_archiver.borrow_mut();
-        archiver.archive(vec![FileType::Directory(child_count)])?;
-        archiver.archive(vec![DentryRepresentation::from(file.dentry)?])?;
-        archiver.archive(file.name.into_bytes())?;
+        archive_head(&mut archiver, FileType::HardLink, FileHead::from(file))?;
+        archiver.archive(vec![regular_file_idx])?;
         Ok(())
     }
 
-    fn make_file_non_overlapping(&self, file: FatFile) -> Result<NonOverlappingFatFile> {
-        let mut non_overlapping = NonOverlappingFatFile::new(file.name, file.dentry);
+    /// The symlink replaces the file, so its data is not archived and does not need to be relocated.
+    fn archive_symlink(&self, file: FatFile, target: String) -> Result<()> {
+        self.file_count.set(self.file_count.get() + 1);
+        let mut archiver = self.stream_archiver.borrow_mut();
+        archive_head(&mut archiver, FileType::Symlink, FileHead::from(file))?;
+        archiver.archive(target.into_bytes())?;
+        Ok(())
+    }
+
+    fn archive_directory(&self, file: FatFile) -> Result<()> {
+        self.file_count.set(self.file_count.get() + 1);
+        let mut archiver = self.stream_archiver.borrow_mut();
+        archive_head(&mut archiver, FileType::Directory, FileHead::from(file))
+    }
+
+    /// The relocation stage: copies the parts of `file`'s data that lie in `self.forbidden_ranges` to newly allocated
+    /// clusters and keeps the others in place. Only the clusters that the file's size covers are relocated and
+    /// archived, since the ext4 extents do not reference the rest of a cluster chain that is longer than the file.
+    /// Ranges that continue each other are merged, so that the file needs as few extents as possible.
+    fn relocate(&self, file: FatFile) -> Result<NonOverlappingFatFile> {
+        let head = FileHead {
+            name: file.name,
+            dentry: file.dentry,
+            overrides: file.overrides,
+            xattrs: file.xattrs,
+        };
+        let mut non_overlapping = NonOverlappingFatFile::new(head);
+        let mut remaining_cluster_count = file.dentry.file_size.div_ceil(&self.fat_fs.cluster_size());
 
         for mut data_cluster_range in file.data_ranges {
+            if remaining_cluster_count == 0 {
+                break;
+            }
             let start_cluster_idx = self.fat_fs.cluster_from_data_cluster(*data_cluster_range.start());
             let end_cluster_idx = self.fat_fs.cluster_from_data_cluster(*data_cluster_range.end()) + 1;
+            let end_cluster_idx = end_cluster_idx.min(start_cluster_idx + remaining_cluster_count);
+            remaining_cluster_count -= end_cluster_idx - start_cluster_idx;
             let cluster_range = start_cluster_idx..end_cluster_idx;
 
+            // only the overlapping fragments are copied, and `data_cluster_range` is only advanced as far as the
+            // fragments reach, so the clusters beyond the file's size are never read
             for (range_fragment, forbidden) in self.forbidden_ranges.split_overlapping(cluster_range) {
                 if forbidden {
-                    let mut copied_ranges = self.copy_data_to_new_clusters(
+                    let copied_ranges = self.copy_data_to_new_clusters(
                         &mut data_cluster_range,
                         range_fragment.end - range_fragment.start,
                     )?;
-                    non_overlapping.data_ranges.append(&mut copied_ranges);
+                    for copied_range in copied_ranges {
+                        non_overlapping.push_data_range(copied_range);
+                    }
                 } else {
                     data_cluster_range
                         .advance_by(range_fragment.len())
                         .expect("data_cluster_range is shorter than the sum of range_fragment lengths");
-                    non_overlapping.data_ranges.push(range_fragment);
+                    non_overlapping.push_data_range(range_fragment);
                 }
             }
         }
@@ -125,7 +560,8 @@ impl<'a> FatTreeSerializer<'a> {
     }
 
     /// Given an iterator over `DataClusterIdx`s, copy the first `len` to newly allocated clusters and return these
-    /// clusters' `ClusterIdx`s. `iter` must have at least `len` elements.
+    /// clusters' `ClusterIdx`s. `iter` must have at least `len` elements. Returns an error instead of looping forever
+    /// if an iteration makes no progress, which would mean that the allocator or `iter` broke its contract.
     fn copy_data_to_new_clusters<I: Iterator<Item = DataClusterIdx>>(
         &self,
         mut iter: &mut I,
@@ -133,12 +569,34 @@ impl<'a> FatTreeSerializer<'a> {
     ) -> Result<Vec<Range<ClusterIdx>>> {
         let mut copied_fragments = Vec::new();
         while len > 0 {
-            let mut allocated = self.allocator.allocate(len)?;
-            // zip in this order: this way, when `allocated` is empty, `iter.next()` is not called, and we consume
-            // exactly `allocated.len()` elements from `iter`.
+            let mut allocated = self.allocator.allocate(len, AllocationPurpose::Relocation)?;
+            if allocated.len() == 0 || allocated.len() > len {
+                bail!(
+                    "The relocation stalled: the allocator returned {} clusters for a request of {} ({} clusters \
+                     free, {} allocated)",
+                    allocated.len(),
+                    len,
+                    self.allocator.free_block_count(),
+                    self.allocator.stats().total()
+                );
+            }
+            let mut copied_count = 0;
+            // zip in this order: this way, when `allocated` is exhausted, `iter.next()` is not called, and we consume
+            // at most `allocated.len()` elements from `iter`.
             for (mut new_cluster_idx, old_data_cluster_idx) in allocated.iter_mut().zip(&mut iter) {
-                let old_cluster = self.fat_fs.data_cluster(old_data_cluster_idx);
+                let old_cluster = self.fat_fs.data_cluster(old_data_cluster_idx)?;
                 self.allocator.cluster_mut(&mut new_cluster_idx).copy_from_slice(old_cluster);
+                if let Some(rate_limiter) = &self.rate_limiter {
+                    rate_limiter.consume(old_cluster.len());
+                }
+                copied_count += 1;
+            }
+            if copied_count < allocated.len() {
+                bail!(
+                    "The relocation stalled: the file ended after {} of {} clusters left to copy",
+                    copied_count,
+                    len
+                );
             }
             len -= allocated.len();
             copied_fragments.push(allocated.into());
@@ -146,23 +604,757 @@ impl<'a> FatTreeSerializer<'a> {
         Ok(copied_fragments)
     }
 
-    /// SAFETY: Safe if no block in `SuperBlock::from(self.fat_fs.boot_sector).block_group_overhead_ranges()` is
-    /// accessed for the duration of the lifetime 'a
-    pub unsafe fn into_deserializer(self) -> Result<Ext4TreeDeserializer<'a>> {
+    /// SAFETY: Safe if `superblock` was created from `self.fat_fs.boot_sector()` and no block in
+    /// `superblock.block_group_overhead_ranges()` is accessed for the duration of the lifetime 'a
+    pub unsafe fn into_deserializer(self, superblock: SuperBlock) -> Result<Ext4TreeDeserializer<'a, A>> {
+        let free_space_check = self.free_space_check();
+        let skeleton = Rc::clone(&self.skeleton);
+        let (reader, allocator, fat_fs) = self.into_reader()?;
+        let predicted_usage = Ext4TreeDeserializer::dry_run(&reader, &allocator, &superblock, &skeleton)?;
+        free_space_check.predict(&superblock, predicted_usage)?;
+        Ok(unsafe {
+            Ext4TreeDeserializer::after_dry_run(reader, allocator, fat_fs, superblock, skeleton, predicted_usage)
+        })
+    }
+
+    /// Finishes the archive and performs the dry run for `superblock` like `into_deserializer`, but without modifying
+    /// the partition. Returns where the archive is stored, so that a later invocation can deserialize it with
+    /// `Reader::resume`.
+    pub fn into_archive_location(self, superblock: &SuperBlock) -> Result<ArchiveLocation> {
+        let (reader, _) = self.into_dry_run(superblock)?;
+        Ok(reader.location())
+    }
+
+    /// Finishes the archive and performs the dry run for `superblock` like `into_archive_location`, but returns what
+    /// the dry run predicts instead of where the archive is stored, for `--dry-run`.
+    pub fn into_prediction(self, superblock: &SuperBlock) -> Result<Prediction> {
+        let (_, prediction) = self.into_dry_run(superblock)?;
+        Ok(prediction)
+    }
+
+    fn into_dry_run(self, superblock: &SuperBlock) -> Result<(Reader<'a>, Prediction)> {
+        let free_space_check = self.free_space_check();
+        let skeleton = Rc::clone(&self.skeleton);
+        let (reader, allocator, _) = self.into_reader()?;
+        let predicted_usage = Ext4TreeDeserializer::dry_run(&reader, &allocator, superblock, &skeleton)?;
+        let prediction = free_space_check.predict(superblock, predicted_usage)?;
+        Ok((reader, prediction))
+    }
+
+    fn free_space_check(&self) -> FreeSpaceCheck {
+        FreeSpaceCheck {
+            min_free_percent: self.min_free_percent,
+            data_cluster_count: self.data_cluster_count.get(),
+        }
+    }
+
+    /// Finishes the archive and returns a reader for it, after verifying it if `self.verify_archival` is set.
+    fn into_reader(self) -> Result<(Reader<'a>, A, FatFs<'a>)> {
         std::mem::drop(self.allocator); // drop the Rc, allowing `self.stream_archiver` to unwrap it
         let (reader, allocator) = self.stream_archiver.into_inner().into_reader()?;
-        unsafe { Ext4TreeDeserializer::new_with_dry_run(reader, allocator, self.fat_fs) }
+        if self.verify_archival {
+            ArchiveVerifier::verify(reader.clone(), &self.fat_fs, self.renames.take(), &self.forbidden_ranges)
+                .context("The serialized directory tree does not match the FAT filesystem")?;
+        }
+        Ok((reader, allocator, self.fat_fs))
+    }
+}
+
+/// Returns an error if `file` cannot be converted, i.e. if one of its timestamps cannot be represented in ext4, its
+/// cluster chain leaves the data region or it is a directory whose clusters do not plausibly contain dentries, see
+/// `FatFs::check_directory`.
+pub fn check_convertible(file: &FatFile, fat_fs: &FatFs) -> Result<()> {
+    DentryRepresentation::from(file.dentry)?;
+    let data_cluster_count = fat_fs.boot_sector().data_cluster_count();
+    if file
+        .data_ranges
+        .iter()
+        .any(|range| u32::from(*range.end()) >= data_cluster_count)
+    {
+        bail!("Its cluster chain leaves the data region");
+    }
+    if file.dentry.is_dir() {
+        fat_fs
+            .check_directory(file.dentry.first_fat_index())
+            .context("Its clusters do not contain a valid directory")?;
+    }
+    Ok(())
+}
+
+/// What every file in the archive starts with, see `FileType`
+struct FileHead {
+    name: String,
+    dentry: FatDentry,
+    overrides: InodeOverrides,
+    xattrs: Vec<Xattr>,
+}
+
+impl From<FatFile> for FileHead {
+    fn from(file: FatFile) -> Self {
+        Self {
+            name: file.name,
+            dentry: file.dentry,
+            overrides: file.overrides,
+            xattrs: file.xattrs,
+        }
+    }
+}
+
+/// Archives `file_type` and `head`, followed by the encoded extended attributes if there are any.
+fn archive_head<'a, A: ClusterAllocator + 'a>(
+    archiver: &mut StreamArchiver<'a, A>,
+    file_type: FileType,
+    head: FileHead,
+) -> Result<()> {
+    let dentry = DentryRepresentation {
+        overrides: head.overrides,
+        has_xattrs: !head.xattrs.is_empty(),
+        ..DentryRepresentation::from(head.dentry)?
+    };
+    archiver.archive(vec![file_type])?;
+    archiver.archive(vec![dentry])?;
+    archiver.archive(head.name.into_bytes())?;
+    if dentry.has_xattrs {
+        archiver.archive(encode_xattrs(&head.xattrs)?)?;
+    }
+    Ok(())
+}
+
+/// Returns an error if two of `files`, the children of the directory at `dir_path`, have the same name. FAT names are
+/// unique, but a `ConversionPolicy` may rename a file to the name of another.
+fn check_unique_names(files: &[FatFile], dir_path: &str) -> Result<()> {
+    let mut names = HashSet::new();
+    for file in files {
+        if !names.insert(file.name.as_str()) {
+            bail!("Two files would be converted to {}/{}", dir_path, file.name);
+        }
+    }
+    Ok(())
+}
+
+/// What the dry run predicts about the ext4 filesystem after the conversion
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Prediction {
+    /// the inodes and clusters of the directories, extent trees and symlinks
+    pub usage: ResourceUsage,
+    /// the clusters of the serialized regular files
+    pub data_cluster_count: usize,
+    /// the clusters that the ext4 filesystem has for files, i.e. excluding the metadata of the block groups
+    pub cluster_count: usize,
+    /// the clusters for files that remain free after the conversion
+    pub free_cluster_count: usize,
+}
+
+/// Checks that enough of the ext4 filesystem remains free after the conversion, see `set_min_free_percent`
+struct FreeSpaceCheck {
+    min_free_percent: u8,
+    data_cluster_count: usize,
+}
+
+impl FreeSpaceCheck {
+    /// Returns an `ErrorCategory::InsufficientSpace` error if less than `self.min_free_percent` percent of the
+    /// clusters that the ext4 filesystem described by `superblock` has for files would be free after converting the
+    /// serialized files, whose directories and extent trees the dry run predicted to take up `predicted_usage`.
+    /// Otherwise, returns the prediction.
+    fn predict(&self, superblock: &SuperBlock, predicted_usage: ResourceUsage) -> Result<Prediction> {
+        let cluster_count = superblock.cluster_count_with_padding() - superblock.overhead_cluster_count();
+        let free_cluster_count = cluster_count.saturating_sub(self.data_cluster_count + predicted_usage.clusters);
+        if free_cluster_count * 100 < cluster_count * usize::from(self.min_free_percent) {
+            return Err(ErrorCategory::InsufficientSpace.error(trf(
+                "Only {} of the {} clusters for files ({}%) would be free after the conversion, but {}% are required",
+                &[
+                    &free_cluster_count,
+                    &cluster_count,
+                    &(free_cluster_count * 100 / cluster_count.max(1)),
+                    &self.min_free_percent,
+                ],
+            )));
+        }
+        Ok(Prediction {
+            usage: predicted_usage,
+            data_cluster_count: self.data_cluster_count,
+            cluster_count,
+            free_cluster_count,
+        })
     }
 }
 
 struct NonOverlappingFatFile {
-    pub name: String,
-    pub dentry: FatDentry,
+    pub head: FileHead,
     pub data_ranges: Vec<Range<ClusterIdx>>,
 }
 
 impl NonOverlappingFatFile {
-    pub fn new(name: String, dentry: FatDentry) -> Self {
-        Self { name, dentry, data_ranges: Vec::new() }
+    pub fn new(head: FileHead) -> Self {
+        Self { head, data_ranges: Vec::new() }
+    }
+
+    /// Appends `range` to the data ranges, or extends the last one if `range` continues it.
+    fn push_data_range(&mut self, range: Range<ClusterIdx>) {
+        match self.data_ranges.last_mut() {
+            Some(last) if last.end == range.start => last.end = range.end,
+            _ => self.data_ranges.push(range),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::allocator::tests::{limit_free_clusters, FailingAllocator, FragmentedAllocator, SingleClusterAllocator};
+    use crate::allocator::AllocatorStats;
+    use crate::conversion::ProvisionalLayout;
+    use crate::error::exit_code;
+    use crate::ext4::{decode_xattrs, Owner};
+    use crate::fat::{FatImageBuilder, TestFile};
+    use crate::options::ConversionOptions;
+    use crate::serialization::{FileDecision, LongNameTruncator};
+
+    struct Uppercase;
+    impl FileOp for Uppercase {
+        fn apply(&mut self, file: &mut FatFile, _fat_fs: &FatFs) -> Result<Verdict> {
+            file.name = file.name.to_uppercase();
+            Ok(Verdict::Include)
+        }
+    }
+
+    struct ExcludeName(&'static str);
+    impl FileOp for ExcludeName {
+        fn apply(&mut self, file: &mut FatFile, _fat_fs: &FatFs) -> Result<Verdict> {
+            Ok(if file.name == self.0 {
+                Verdict::Exclude
+            } else {
+                Verdict::Include
+            })
+        }
+    }
+
+    struct RecordNames(Rc<RefCell<Vec<String>>>);
+    impl FileOp for RecordNames {
+        fn apply(&mut self, file: &mut FatFile, _fat_fs: &FatFs) -> Result<Verdict> {
+            self.0.borrow_mut().push(file.name.clone());
+            Ok(Verdict::Include)
+        }
+    }
+
+    struct Enlarge(&'static str);
+    impl FileOp for Enlarge {
+        fn apply(&mut self, file: &mut FatFile, _fat_fs: &FatFs) -> Result<Verdict> {
+            if file.name == self.0 {
+                file.dentry.file_size += 10_000;
+            }
+            Ok(Verdict::Include)
+        }
+    }
+
+    #[test]
+    fn applies_ops_in_order() {
+        let file = |name: &str| TestFile::RegularFile { name: name.to_string(), size: 4096 };
+        let mut image = FatImageBuilder::new(32 * 1024 * 1024, 1024).build(&[
+            file("a"),
+            TestFile::Directory {
+                name: "excluded".to_string(),
+                children: vec![file("b")],
+            },
+            TestFile::Directory {
+                name: "included".to_string(),
+                children: vec![file("c")],
+            },
+        ]);
+        // SAFETY: Safe because `allocator` is the only `Allocator`.
+        let (fat_fs, allocator) = unsafe { FatFs::from_slice_with_allocator(image.as_mut_slice()).unwrap() };
+        let mut serializer = FatTreeSerializer::new(allocator, fat_fs, Ranges::new());
+        let names = Rc::new(RefCell::new(Vec::new()));
+        serializer.add_op(Uppercase);
+        serializer.add_op(ExcludeName("EXCLUDED"));
+        serializer.add_op(RecordNames(Rc::clone(&names)));
+        serializer.serialize_directory_tree().unwrap();
+
+        assert_eq!(*names.borrow(), ["A", "INCLUDED", "C"]);
+        assert_eq!(serializer.file_count(), 3);
+        // only the directory itself is counted, not its content
+        assert_eq!(serializer.exclusion_stats(), ExclusionStats { file_count: 1, cluster_count: 1 });
+    }
+
+    fn xattr(name: &str, value: &[u8]) -> Xattr {
+        Xattr { name: name.to_string(), value: value.to_vec() }
+    }
+
+    struct Decide<F>(F);
+    impl<F: FnMut(&str) -> FileDecision> ConversionPolicy for Decide<F> {
+        fn decide(&mut self, _file: &FatFile, path: &str) -> Result<FileDecision> {
+            Ok((self.0)(path))
+        }
+    }
+
+    #[test]
+    fn archives_policy_decisions() {
+        let file = |name: &str| TestFile::RegularFile { name: name.to_string(), size: 100 };
+        let files = [
+            file("a"),
+            TestFile::Directory {
+                name: "excluded".to_string(),
+                children: vec![file("b")],
+            },
+            file("c"),
+        ];
+        let mut image = FatImageBuilder::new(32 * 1024 * 1024, 1024).build(&files);
+        // SAFETY: Safe because `allocator` is the only `Allocator`.
+        let (fat_fs, allocator) = unsafe { FatFs::from_slice_with_allocator(image.as_mut_slice()).unwrap() };
+        let mut serializer = FatTreeSerializer::new(allocator, fat_fs, Ranges::new());
+        serializer.add_policy(Decide(|path: &str| match path {
+            "/excluded" => FileDecision::exclude(),
+            "/c" => FileDecision {
+                name: Some("renamed".to_string()),
+                owner: Some(Owner { uid: 33, gid: 33 }),
+                perms: Some(0o600),
+                xattrs: vec![xattr("user.a", b"1"), xattr("user.b", b"2")],
+                ..FileDecision::default()
+            },
+            _ => FileDecision::default(),
+        }));
+        // sees the name decided by the previous policy, overrides only the permissions and replaces one attribute
+        serializer.add_policy(Decide(|path: &str| match path {
+            "/renamed" => FileDecision {
+                perms: Some(0o640),
+                xattrs: vec![xattr("user.b", b"3")],
+                ..FileDecision::default()
+            },
+            _ => FileDecision::default(),
+        }));
+        serializer.add_op(Uppercase);
+        serializer.serialize_directory_tree().unwrap();
+        assert_eq!(serializer.exclusion_stats(), ExclusionStats { file_count: 1, cluster_count: 1 });
+
+        let (mut reader, _, _) = serializer.into_reader().unwrap();
+        let mut archived = Vec::new();
+        while let [FileType::RegularFile] = reader.next::<FileType>()[..] {
+            let dentry = reader.next::<DentryRepresentation>()[0];
+            let name = String::from_utf8(reader.next::<u8>()).unwrap();
+            let xattrs = if dentry.has_xattrs {
+                decode_xattrs(&reader.next::<u8>()).unwrap()
+            } else {
+                Vec::new()
+            };
+            reader.next::<Range<ClusterIdx>>();
+            archived.push((name, dentry.overrides, xattrs));
+        }
+        let renamed = InodeOverrides {
+            owner: Some(Owner { uid: 33, gid: 33 }),
+            perms: Some(0o640),
+        };
+        assert_eq!(
+            archived,
+            [
+                ("A".to_string(), InodeOverrides::default(), Vec::new()),
+                (
+                    "RENAMED".to_string(),
+                    renamed,
+                    vec![xattr("user.a", b"1"), xattr("user.b", b"3")]
+                )
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_policy_decisions() {
+        let serialize = |decision: FileDecision| {
+            let files = [
+                TestFile::RegularFile { name: "a".to_string(), size: 10 },
+                TestFile::RegularFile { name: "b".to_string(), size: 10 },
+            ];
+            let mut image = FatImageBuilder::new(32 * 1024 * 1024, 1024).build(&files);
+            // SAFETY: Safe because `allocator` is the only `Allocator`.
+            let (fat_fs, allocator) = unsafe { FatFs::from_slice_with_allocator(image.as_mut_slice()).unwrap() };
+            let mut serializer = FatTreeSerializer::new(allocator, fat_fs, Ranges::new());
+            serializer.add_policy(Decide(move |path: &str| match path {
+                "/a" => decision.clone(),
+                _ => FileDecision::default(),
+            }));
+            serializer.serialize_directory_tree().map_err(|error| format!("{:#}", error))
+        };
+        let rename = |name: &str| FileDecision {
+            name: Some(name.to_string()),
+            ..FileDecision::default()
+        };
+
+        serialize(rename("c")).unwrap();
+        for name in ["", ".", "..", "x/y"] {
+            let error = serialize(rename(name)).unwrap_err();
+            assert!(error.contains("invalid name"), "{}", error);
+        }
+        let error = serialize(rename("b")).unwrap_err();
+        assert!(error.contains("Two files would be converted to /b"), "{}", error);
+        let error = serialize(FileDecision { perms: Some(0o10_644), ..FileDecision::default() }).unwrap_err();
+        assert!(error.contains("invalid permissions 10644"), "{}", error);
+        let error = serialize(FileDecision {
+            xattrs: vec![xattr("system.a", b"")],
+            ..FileDecision::default()
+        })
+        .unwrap_err();
+        assert!(error.contains("not in one of the namespaces"), "{}", error);
+    }
+
+    struct ClearArchiveFlag(&'static str);
+    impl FileOp for ClearArchiveFlag {
+        fn apply(&mut self, file: &mut FatFile, _fat_fs: &FatFs) -> Result<Verdict> {
+            if file.name == self.0 {
+                file.dentry.attrs &= !0x20;
+            }
+            Ok(Verdict::Include)
+        }
+    }
+
+    #[test]
+    fn lists_files_with_archive_flag() {
+        let file = |name: &str| TestFile::RegularFile { name: name.to_string(), size: 1024 };
+        let mut image = FatImageBuilder::new(32 * 1024 * 1024, 1024).build(&[
+            file("modified"),
+            file("backed up"),
+            TestFile::Directory {
+                name: "dir".to_string(),
+                children: vec![file("nested")],
+            },
+        ]);
+        // SAFETY: Safe because `allocator` is the only `Allocator`.
+        let (fat_fs, allocator) = unsafe { FatFs::from_slice_with_allocator(image.as_mut_slice()).unwrap() };
+        let mut serializer = FatTreeSerializer::new(allocator, fat_fs, Ranges::new());
+        serializer.add_op(ClearArchiveFlag("backed up"));
+        serializer.list_archive_bit_files();
+        serializer.serialize_directory_tree().unwrap();
+
+        // the test images date every modification to 2020-01-01 12:34:56
+        let archive_bit_file = |path: &str| ArchiveBitFile { path: path.to_string(), mod_time: 1_577_882_096 };
+        assert_eq!(
+            serializer.archive_bit_files(),
+            [archive_bit_file("/modified"), archive_bit_file("/dir/nested")]
+        );
+    }
+
+    #[test]
+    fn truncates_files_with_short_cluster_chains() {
+        let mut image = FatImageBuilder::new(32 * 1024 * 1024, 1024).build(&[
+            TestFile::RegularFile { name: "intact".to_string(), size: 5000 },
+            TestFile::Directory {
+                name: "dir".to_string(),
+                children: vec![TestFile::RegularFile { name: "short".to_string(), size: 5000 }],
+            },
+        ]);
+        // SAFETY: Safe because `allocator` is the only `Allocator`.
+        let (fat_fs, allocator) = unsafe { FatFs::from_slice_with_allocator(image.as_mut_slice()).unwrap() };
+        let mut serializer = FatTreeSerializer::new(allocator, fat_fs, Ranges::new());
+        serializer.add_op(Enlarge("short"));
+        serializer.serialize_directory_tree().unwrap();
+
+        assert_eq!(
+            serializer.truncated_files(),
+            [TruncatedFile {
+                path: "/dir/short".to_string(),
+                announced_size: 15_000,
+                covered_size: 5120,
+            }]
+        );
+    }
+
+    struct Year2107(&'static str);
+    impl FileOp for Year2107 {
+        fn apply(&mut self, file: &mut FatFile, _fat_fs: &FatFs) -> Result<Verdict> {
+            if file.name.starts_with(self.0) {
+                // 2107-01-01, the latest FAT date, which is too late for a 32-bit Unix timestamp
+                file.dentry.mod_date = (2107 - 1980) << 9 | 1 << 5 | 1;
+            }
+            Ok(Verdict::Include)
+        }
+    }
+
+    #[test]
+    fn skips_files_that_cannot_be_converted() {
+        let file = |name: &str| TestFile::RegularFile { name: name.to_string(), size: 1024 };
+        let files = [
+            file("a"),
+            file("late"),
+            TestFile::Directory {
+                name: "dir".to_string(),
+                children: vec![file("b"), file("late")],
+            },
+            TestFile::Directory {
+                name: "late dir".to_string(),
+                children: vec![file("c")],
+            },
+        ];
+        let serialize = |policy| {
+            let mut image = FatImageBuilder::new(32 * 1024 * 1024, 1024).build(&files);
+            // SAFETY: Safe because `allocator` is the only `Allocator`.
+            let (fat_fs, allocator) = unsafe { FatFs::from_slice_with_allocator(image.as_mut_slice()).unwrap() };
+            let mut serializer = FatTreeSerializer::new(allocator, fat_fs, Ranges::new());
+            serializer.set_error_policy(policy);
+            serializer.add_op(Year2107("late"));
+            serializer
+                .serialize_directory_tree()
+                .map(|_| (serializer.file_count(), serializer.skipped_files()))
+        };
+
+        let error = format!("{:#}", serialize(ErrorPolicy::FailFast).unwrap_err());
+        assert!(
+            error.contains("Unable to convert /late: Timestamp after year 2038"),
+            "{}",
+            error
+        );
+
+        let (file_count, skipped_files) = serialize(ErrorPolicy::CollectErrors).unwrap();
+        // the content of a skipped directory is neither converted nor listed
+        assert_eq!(file_count, 3);
+        let paths: Vec<_> = skipped_files.iter().map(|file| file.path.as_str()).collect();
+        assert_eq!(paths, ["/late", "/late dir", "/dir/late"]);
+    }
+
+    #[test]
+    fn verifies_archive_against_fat() {
+        let files = [
+            TestFile::RegularFile { name: "a".to_string(), size: 5000 },
+            TestFile::RegularFile { name: "excluded".to_string(), size: 5000 },
+            TestFile::Directory {
+                name: "dir".to_string(),
+                children: vec![TestFile::RegularFile { name: "長".repeat(90), size: 1024 }],
+            },
+        ];
+        let verify = |op: Option<Box<dyn FileOp>>| {
+            let mut image = FatImageBuilder::new(32 * 1024 * 1024, 1024).build(&files);
+            // SAFETY: Safe because `allocator` is the only `Allocator`.
+            let (fat_fs, allocator) = unsafe { FatFs::from_slice_with_allocator(image.as_mut_slice()).unwrap() };
+            let mut serializer = FatTreeSerializer::new(allocator, fat_fs, Ranges::new());
+            serializer.set_verify_archival(true);
+            serializer.add_policy(LongNameTruncator::new());
+            serializer.add_op(ExcludeName("excluded"));
+            if let Some(op) = op {
+                serializer.ops.get_mut().push(op);
+            }
+            serializer.serialize_directory_tree().unwrap();
+            serializer.into_reader().map(|_| ())
+        };
+
+        verify(None).unwrap();
+        let renamed = format!("{:#}", verify(Some(Box::new(Uppercase))).err().unwrap());
+        assert!(renamed.contains("/A was serialized, but it is not in the FAT filesystem"));
+        let enlarged = format!("{:#}", verify(Some(Box::new(Enlarge("a")))).err().unwrap());
+        assert!(enlarged.contains("The serialized dentry of /a is"));
+        assert!(enlarged.ends_with("they differ in the size"), "{}", enlarged);
+    }
+
+    #[test]
+    fn handles_invalid_attributes() {
+        let files = [
+            TestFile::RegularFile { name: "label".to_string(), size: 0 },
+            TestFile::Directory {
+                name: "label dir".to_string(),
+                children: vec![TestFile::RegularFile { name: "child".to_string(), size: 1024 }],
+            },
+            TestFile::RegularFile { name: "a".to_string(), size: 1024 },
+        ];
+        let serialize = |policy| -> Result<(usize, Vec<InvalidAttributesFile>)> {
+            let mut image = FatImageBuilder::new(32 * 1024 * 1024, 1024).build(&files);
+            image.patch_root_dentry("label", |dentry| dentry.attrs = 0x08);
+            image.patch_root_dentry("label dir", |dentry| dentry.attrs = 0x18);
+            // SAFETY: Safe because `allocator` is the only `Allocator`.
+            let (fat_fs, allocator) = unsafe { FatFs::from_slice_with_allocator(image.as_mut_slice()).unwrap() };
+            let mut serializer = FatTreeSerializer::new(allocator, fat_fs, Ranges::new());
+            serializer.set_invalid_attributes_policy(policy);
+            serializer.set_verify_archival(true);
+            serializer.serialize_directory_tree()?;
+            let result = (serializer.file_count(), serializer.invalid_attributes_files());
+            serializer.into_reader()?;
+            Ok(result)
+        };
+
+        let error = format!("{:#}", serialize(InvalidAttributesPolicy::Reject).unwrap_err());
+        assert!(
+            error.contains("Unable to convert /label dir: Its attributes 0x18 are invalid"),
+            "{}",
+            error
+        );
+
+        // the volume label is left out, the directory's child is never read
+        let (file_count, invalid_files) = serialize(InvalidAttributesPolicy::Skip).unwrap();
+        assert_eq!(file_count, 1);
+        let paths: Vec<_> = invalid_files.iter().map(|file| file.path.as_str()).collect();
+        assert_eq!(paths, ["/label dir"]);
+
+        let (file_count, invalid_files) = serialize(InvalidAttributesPolicy::RegularFile).unwrap();
+        assert_eq!(file_count, 2);
+        assert_eq!(invalid_files[0].policy, InvalidAttributesPolicy::RegularFile);
+    }
+
+    #[test]
+    fn skips_directories_without_dentries() {
+        let files = [
+            TestFile::RegularFileWithContent {
+                name: "junk".to_string(),
+                content: (0..1024u32).map(|idx| (idx * 37 + 11) as u8).collect(),
+            },
+            TestFile::Directory {
+                name: "dir".to_string(),
+                children: vec![TestFile::RegularFile { name: "file".to_string(), size: 10 }],
+            },
+        ];
+        let serialize = |policy| {
+            let mut image = FatImageBuilder::new(32 * 1024 * 1024, 1024).build(&files);
+            let junk_idx = FatFs::from_slice(image.as_mut_slice())
+                .unwrap()
+                .root_dir()
+                .find(|file| file.name == "junk")
+                .unwrap()
+                .dentry
+                .first_fat_index();
+            image.patch_root_dentry("dir", |dentry| {
+                dentry.first_fat_index_lo = u32::from(junk_idx) as u16;
+                dentry.first_fat_index_hi = (u32::from(junk_idx) >> 16) as u16;
+            });
+            // SAFETY: Safe because `allocator` is the only `Allocator`.
+            let (fat_fs, allocator) = unsafe { FatFs::from_slice_with_allocator(image.as_mut_slice()).unwrap() };
+            let mut serializer = FatTreeSerializer::new(allocator, fat_fs, Ranges::new());
+            serializer.set_error_policy(policy);
+            serializer
+                .serialize_directory_tree()
+                .map(|_| (serializer.file_count(), serializer.skipped_files()))
+        };
+
+        let error = format!("{:#}", serialize(ErrorPolicy::FailFast).unwrap_err());
+        assert!(
+            error.contains("Unable to convert /dir: Its clusters do not contain a valid directory: Entry 0"),
+            "{}",
+            error
+        );
+        let (file_count, skipped_files) = serialize(ErrorPolicy::CollectErrors).unwrap();
+        assert_eq!(file_count, 1);
+        assert_eq!(skipped_files[0].path, "/dir");
+    }
+
+    #[test]
+    fn relocation_copes_with_failing_and_fragmenting_allocators() {
+        let content: Vec<u8> = (0..10 * 1024u32).map(|idx| (idx * 37 + 11) as u8).collect();
+        let files = [TestFile::RegularFileWithContent { name: "file".to_string(), content: content.clone() }];
+        let mut image = FatImageBuilder::new(32 * 1024 * 1024, 1024).build(&files);
+        // SAFETY: Safe because `allocator` is the only `Allocator`.
+        let (fat_fs, allocator) = unsafe { FatFs::from_slice_with_allocator(image.as_mut_slice()).unwrap() };
+        // every file has to be relocated
+        let forbidden_ranges = fat_fs.used_ranges();
+        let mut serializer = FatTreeSerializer::new(FailingAllocator::new(allocator, 0), fat_fs, forbidden_ranges);
+        let error = serializer.serialize_directory_tree().unwrap_err();
+        assert_eq!(exit_code(&error), ErrorCategory::InsufficientSpace.exit_code());
+
+        let mut image = FatImageBuilder::new(32 * 1024 * 1024, 1024).build(&files);
+        let relocated_ranges = {
+            // SAFETY: Safe because `allocator` is the only `Allocator`.
+            let (fat_fs, allocator) = unsafe { FatFs::from_slice_with_allocator(image.as_mut_slice()).unwrap() };
+            let forbidden_ranges = fat_fs.used_ranges();
+            let mut serializer = FatTreeSerializer::new(FragmentedAllocator(allocator), fat_fs, forbidden_ranges);
+            serializer.set_verify_archival(true);
+            serializer.serialize_directory_tree().unwrap();
+            let (mut reader, _, _) = serializer.into_reader().unwrap();
+            assert!(matches!(reader.next::<FileType>()[..], [FileType::RegularFile]));
+            reader.next::<DentryRepresentation>();
+            assert_eq!(reader.next::<u8>(), b"file");
+            reader.next::<Range<ClusterIdx>>()
+        };
+        assert_eq!(relocated_ranges.len(), 10);
+        assert!(relocated_ranges.iter().all(|range| range.len() == 1));
+        assert!(relocated_ranges.windows(2).all(|ranges| ranges[0].end < ranges[1].start));
+        let partition = image.as_mut_slice();
+        let relocated_content: Vec<u8> = relocated_ranges
+            .iter()
+            .flat_map(|range| &partition[usize::fromx(range.start) * 1024..usize::fromx(range.end) * 1024])
+            .copied()
+            .collect();
+        assert_eq!(relocated_content, content);
+    }
+
+    #[test]
+    fn relocates_only_the_overlapping_clusters_the_size_covers() {
+        let content: Vec<u8> = (0..8 * 1024u32).map(|idx| (idx * 37 + 11) as u8).collect();
+        let files = [TestFile::RegularFileWithContent { name: "file".to_string(), content: content.clone() }];
+        let mut image = FatImageBuilder::new(32 * 1024 * 1024, 1024).build(&files);
+        // the cluster chain has 8 clusters, of which the size only covers 6
+        image.patch_root_dentry("file", |dentry| dentry.file_size = 6000);
+        let (ranges, stats) = {
+            // SAFETY: Safe because `allocator` is the only `Allocator`.
+            let (fat_fs, allocator) = unsafe { FatFs::from_slice_with_allocator(image.as_mut_slice()).unwrap() };
+            let data_ranges = fat_fs.root_dir().next().unwrap().data_ranges;
+            assert_eq!(data_ranges.len(), 1);
+            let first = fat_fs.cluster_from_data_cluster(*data_ranges[0].start());
+            // the last forbidden range only covers clusters beyond the size, which must not be copied
+            let forbidden_ranges = Ranges::from([first + 1..first + 3, first + 4..first + 5, first + 6..first + 8]);
+            let mut serializer = FatTreeSerializer::new(SingleClusterAllocator(allocator), fat_fs, forbidden_ranges);
+            serializer.set_verify_archival(true);
+            serializer.serialize_directory_tree().unwrap();
+            let (mut reader, allocator, _) = serializer.into_reader().unwrap();
+            assert!(matches!(reader.next::<FileType>()[..], [FileType::RegularFile]));
+            reader.next::<DentryRepresentation>();
+            assert_eq!(reader.next::<u8>(), b"file");
+            let ranges = reader.next::<Range<ClusterIdx>>();
+            assert_eq!(ranges[0], first..first + 1);
+            assert_eq!(ranges[2], first + 3..first + 4);
+            assert_eq!(ranges[4], first + 5..first + 6);
+            (ranges, allocator.stats())
+        };
+        assert_eq!(stats.relocation, 3);
+        // the two clusters copied one at a time into adjacent clusters form a single extent
+        let lens: Vec<_> = ranges.iter().map(ExactSizeIterator::len).collect();
+        assert_eq!(lens, [1, 2, 1, 1, 1]);
+        let partition = image.as_mut_slice();
+        let relocated_content: Vec<u8> = ranges
+            .iter()
+            .flat_map(|range| &partition[usize::fromx(range.start) * 1024..usize::fromx(range.end) * 1024])
+            .copied()
+            .collect();
+        assert_eq!(relocated_content, content[..6 * 1024]);
+    }
+
+    #[test]
+    fn dry_run_sees_the_final_free_space() {
+        let files: Vec<_> = (0..300)
+            .map(|idx| TestFile::RegularFile {
+                name: format!("file with a long name {}", idx),
+                size: 3000,
+            })
+            .chain([TestFile::Directory {
+                name: "dir".to_string(),
+                children: vec![TestFile::RegularFile { name: "large".to_string(), size: 10_000_000 }],
+            }])
+            .collect();
+        // Converts the files with only `free_cluster_count` clusters left for the conversion, if given.
+        let convert = |free_cluster_count: Option<usize>| -> Result<AllocatorStats> {
+            let mut image = FatImageBuilder::new(32 * 1024 * 1024, 1024).build(&files);
+            // SAFETY: Safe because `allocator` is the only `Allocator`.
+            let (fat_fs, mut allocator) = unsafe { FatFs::from_slice_with_allocator(image.as_mut_slice())? };
+            let options = ConversionOptions::default();
+            let boot_sector = *fat_fs.boot_sector();
+            let layout = ProvisionalLayout::new(&fat_fs, &options, 0, false)?;
+            for range in &layout.forbidden_ranges {
+                allocator.forbid(range.clone());
+            }
+            if let Some(free_cluster_count) = free_cluster_count {
+                limit_free_clusters(&mut allocator, free_cluster_count);
+            }
+            let mut serializer = FatTreeSerializer::new(allocator, fat_fs, layout.forbidden_ranges.clone());
+            serializer.serialize_directory_tree()?;
+            let superblock =
+                layout.choose_superblock(&boot_sector, &options, serializer.file_count(), 0, &mut Vec::new())?;
+            // SAFETY: Safe because the allocator's forbidden ranges cover the ext4 metadata of `superblock`
+            let mut deserializer = unsafe { serializer.into_deserializer(superblock)? };
+            deserializer.deserialize_directory_tree()?;
+            let stats = deserializer.allocator_stats();
+            deserializer.finalize()?;
+            Ok(stats)
+        };
+
+        let stats = convert(None).unwrap();
+        assert!(stats.archive > 1 && stats.relocation > 0);
+        // the dry run has to count exactly what the archive and the relocation leave over, so the conversion succeeds
+        // with every cluster allocated and fails before modifying anything with one cluster less
+        assert_eq!(convert(Some(stats.total())).unwrap(), stats);
+        let error = convert(Some(stats.total() - 1)).err().unwrap();
+        assert_eq!(exit_code(&error), ErrorCategory::InsufficientSpace.exit_code());
+        assert!(format!("{:#}", error).contains("free blocks required"), "{:#}", error);
     }
 }
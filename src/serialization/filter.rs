@@ -0,0 +1,123 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::fat::FatFile;
+use crate::serialization::{ConversionPolicy, FileDecision};
+
+/// Decides which regular files are left out of the conversion. Directories are never excluded. An excluded file's
+/// clusters are not referenced by the ext4 filesystem, so they count as free space after the conversion.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct FileFilter {
+    /// Exclude files whose size in bytes is greater than this value
+    pub max_size: Option<u64>,
+    /// Exclude files whose modification time (as a Unix timestamp) is before this value
+    pub min_mod_time: Option<i64>,
+}
+
+impl FileFilter {
+    /// True if no files will be excluded
+    pub fn is_empty(&self) -> bool {
+        self.max_size.is_none() && self.min_mod_time.is_none()
+    }
+
+    pub fn excludes(&self, file: &FatFile) -> Result<bool> {
+        if file.dentry.is_dir() {
+            return Ok(false);
+        }
+
+        let too_big = match self.max_size {
+            Some(max_size) => u64::from(file.dentry.file_size) > max_size,
+            None => false,
+        };
+        let too_old = match self.min_mod_time {
+            Some(min_mod_time) => i64::from(file.dentry.modify_time_as_unix()?) < min_mod_time,
+            None => false,
+        };
+        Ok(too_big || too_old)
+    }
+}
+
+impl ConversionPolicy for FileFilter {
+    fn decide(&mut self, file: &FatFile, _path: &str) -> Result<FileDecision> {
+        Ok(if self.excludes(file)? {
+            FileDecision::exclude()
+        } else {
+            FileDecision::default()
+        })
+    }
+}
+
+/// Keeps track of the files excluded by a `ConversionPolicy` such as `FileFilter` or a `FileOp` so the user can be told
+/// how much was saved.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ExclusionStats {
+    pub file_count: usize,
+    pub cluster_count: usize,
+}
+
+impl ExclusionStats {
+    pub fn add(&mut self, file: &FatFile) {
+        self.file_count += 1;
+        for range in &file.data_ranges {
+            self.cluster_count += usize::from(*range.end()) - usize::from(*range.start()) + 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ext4::InodeOverrides;
+    use crate::fat::FatDentry;
+
+    const DIR_FLAG: u8 = 0x10;
+    // 2020-01-01 00:00:00 UTC
+    const MOD_DATE_2020: u16 = (2020 - 1980) << 9 | 1 << 5 | 1;
+    const UNIX_2021: i64 = 1_609_459_200;
+
+    fn file(file_size: u32, attrs: u8) -> FatFile {
+        let dentry = FatDentry {
+            file_size,
+            attrs,
+            mod_date: MOD_DATE_2020,
+            ..FatDentry::default()
+        };
+        FatFile {
+            name: "file".to_string(),
+            dentry,
+            data_ranges: Vec::new(),
+            symlink_target: None,
+            overrides: InodeOverrides::default(),
+            xattrs: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn empty_filter_excludes_nothing() {
+        let filter = FileFilter::default();
+        assert!(filter.is_empty());
+        assert!(!filter.excludes(&file(u32::MAX, 0)).unwrap());
+    }
+
+    #[test]
+    fn excludes_by_size() {
+        let filter = FileFilter { max_size: Some(1000), ..FileFilter::default() };
+        assert!(!filter.excludes(&file(1000, 0)).unwrap());
+        assert!(filter.excludes(&file(1001, 0)).unwrap());
+    }
+
+    #[test]
+    fn excludes_by_mod_time() {
+        let filter = FileFilter {
+            min_mod_time: Some(UNIX_2021),
+            ..FileFilter::default()
+        };
+        assert!(filter.excludes(&file(0, 0)).unwrap());
+    }
+
+    #[test]
+    fn never_excludes_directories() {
+        let filter = FileFilter { max_size: Some(0), min_mod_time: Some(UNIX_2021) };
+        assert!(!filter.excludes(&file(1, DIR_FLAG)).unwrap());
+    }
+}
@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+
+use crate::ext4::EXT2_LINK_MAX;
+use crate::fat::{FatFile, FatFs};
+use crate::util::{fnv1a, FNV_OFFSET_BASIS};
+
+/// Finds regular files whose content is the same as that of a regular file serialized before them, so that
+/// `--dedup-hardlink` can turn them into hard links to it, which saves their clusters. A candidate is found by its size
+/// and the `fnv1a` hash of its content, and then compared byte by byte, so a hash collision never links different
+/// files. The hard link shares the inode of the first file, so its own timestamps and read-only flag are lost. Empty
+/// files are never linked, since they have no clusters to save.
+#[derive(Default)]
+pub struct HardLinkFolder {
+    /// the files that later files may be linked to, by their size and content hash
+    originals: HashMap<(u32, u64), Vec<Original>>,
+    stats: HardLinkStats,
+}
+
+struct Original {
+    file: FatFile,
+    /// the index of the file among the `RegularFile` records of the archive, see `FileType::HardLink`
+    regular_file_idx: usize,
+    link_count: u16,
+}
+
+/// Keeps track of the files that `HardLinkFolder` turned into hard links, so the user can be told how much was saved.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct HardLinkStats {
+    pub link_count: usize,
+    pub cluster_count: usize,
+}
+
+impl HardLinkFolder {
+    /// Returns the index of the regular file whose content `file` duplicates. Otherwise, records `file` as the regular
+    /// file with the index `regular_file_idx`, which later files may duplicate, and returns None. `file` must be
+    /// truncated to its cluster chain, see `TruncatedFile::normalize`. Files with extended attributes are neither
+    /// folded nor recorded, since a hard link shares the extended attributes of its original. Fails if the content
+    /// cannot be read.
+    pub fn fold(&mut self, file: &FatFile, regular_file_idx: usize, fat_fs: &FatFs) -> Result<Option<usize>> {
+        if file.dentry.file_size == 0 || !file.xattrs.is_empty() {
+            return Ok(None);
+        }
+        let hash = fat_fs
+            .file_content(file)
+            .try_fold(FNV_OFFSET_BASIS, |hash, chunk| Ok::<_, anyhow::Error>(fnv1a(hash, chunk?)))?;
+        let originals = self.originals.entry((file.dentry.file_size, hash)).or_default();
+        for original in originals.iter_mut() {
+            // a file with `EXT2_LINK_MAX` links is kept, the next duplicate becomes the original of the next ones
+            if original.link_count < EXT2_LINK_MAX && same_content(&original.file, file, fat_fs)? {
+                original.link_count += 1;
+                self.stats.link_count += 1;
+                self.stats.cluster_count += file.data_ranges.iter().map(|range| range.clone().count()).sum::<usize>();
+                return Ok(Some(original.regular_file_idx));
+            }
+        }
+        originals.push(Original {
+            file: file.clone(),
+            regular_file_idx,
+            link_count: 1,
+        });
+        Ok(None)
+    }
+
+    pub fn stats(&self) -> HardLinkStats {
+        self.stats
+    }
+}
+
+/// Compares the content of two files of the same size.
+fn same_content(a: &FatFile, b: &FatFile, fat_fs: &FatFs) -> Result<bool> {
+    for (a_chunk, b_chunk) in fat_fs.file_content(a).zip(fat_fs.file_content(b)) {
+        if a_chunk? != b_chunk? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fat::{FatImageBuilder, TestFile};
+
+    #[test]
+    fn links_only_identical_content() {
+        let file = |name: &str, content: &[u8]| TestFile::RegularFileWithContent {
+            name: name.to_string(),
+            content: content.to_vec(),
+        };
+        let files = [
+            file("original", &[1; 3000]),
+            file("copy", &[1; 3000]),
+            file("different", &[2; 3000]),
+            file("shorter", &[1; 2999]),
+            file("empty", &[]),
+            file("empty copy", &[]),
+            file("another copy", &[1; 3000]),
+        ];
+        let mut image = FatImageBuilder::new(32 * 1024 * 1024, 1024).build(&files);
+        let fat_fs = FatFs::from_slice(image.as_mut_slice()).unwrap();
+
+        let mut folder = HardLinkFolder::default();
+        let originals: Vec<_> = fat_fs
+            .root_dir()
+            .enumerate()
+            .map(|(idx, file)| folder.fold(&file, idx, &fat_fs).unwrap())
+            .collect();
+        assert_eq!(originals, [None, Some(0), None, None, None, None, Some(0)]);
+        // 3000 bytes occupy 3 clusters of 1 KiB
+        assert_eq!(folder.stats(), HardLinkStats { link_count: 2, cluster_count: 6 });
+    }
+}
@@ -0,0 +1,87 @@
+use std::convert::TryFrom;
+use std::fmt::{self, Display, Formatter};
+
+use anyhow::{bail, Result};
+use clap::ArgEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::fat::FatFile;
+
+/// What to do with a file whose FAT attributes are an invalid combination, see `FatDentry::attribute_problem`. Such a
+/// file is never converted as a directory, since its clusters, if any, do not contain dentries.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ArgEnum, Serialize, Deserialize)]
+pub enum InvalidAttributesPolicy {
+    /// the file cannot be converted, so the conversion fails unless errors are collected, see `ErrorPolicy`
+    #[default]
+    Reject,
+    /// leave the file out of the conversion, like an excluded file
+    Skip,
+    /// convert the file as a regular file whose content is its cluster chain, see `make_regular_file`
+    RegularFile,
+}
+
+/// A file with invalid attributes that was skipped or converted as a regular file according to an
+/// `InvalidAttributesPolicy`
+#[derive(Clone, Debug, PartialEq)]
+pub struct InvalidAttributesFile {
+    /// the path of the file, starting with '/' at the root of the FAT filesystem
+    pub path: String,
+    /// the attributes in the FAT dentry
+    pub attrs: u8,
+    /// why the attributes are invalid
+    pub problem: &'static str,
+    pub policy: InvalidAttributesPolicy,
+}
+
+impl Display for InvalidAttributesFile {
+    fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+        let handling = match self.policy {
+            InvalidAttributesPolicy::Reject => "rejected",
+            InvalidAttributesPolicy::Skip => "skipped",
+            InvalidAttributesPolicy::RegularFile => "converted as a regular file",
+        };
+        write!(
+            formatter,
+            "{} (attributes {:#04x}: {}), {}",
+            self.path, self.attrs, self.problem, handling
+        )
+    }
+}
+
+impl InvalidAttributesPolicy {
+    /// If the attributes of `file`, which is in the directory at `dir_path`, are invalid, fails if the policy is
+    /// `Reject` and otherwise returns the decision, after turning `file` into a regular file if the policy is
+    /// `RegularFile`.
+    pub fn apply(self, file: &mut FatFile, dir_path: &str, cluster_size: u32) -> Result<Option<InvalidAttributesFile>> {
+        let problem = match file.dentry.attribute_problem() {
+            Some(problem) => problem,
+            None => return Ok(None),
+        };
+        let attrs = file.dentry.attrs;
+        match self {
+            Self::Reject => bail!("Its attributes {:#04x} are invalid: {}", attrs, problem),
+            Self::Skip => (),
+            Self::RegularFile => make_regular_file(file, cluster_size),
+        }
+        Ok(Some(InvalidAttributesFile {
+            path: format!("{}/{}", dir_path, file.name),
+            attrs,
+            problem,
+            policy: self,
+        }))
+    }
+}
+
+/// Turns `file` into a regular file whose content is its cluster chain. Directories have no size in FAT, so a former
+/// directory gets the size of its clusters, otherwise its clusters would lie beyond the end of the file.
+pub fn make_regular_file(file: &mut FatFile, cluster_size: u32) {
+    if file.dentry.is_dir() {
+        let cluster_count: u64 = file
+            .data_ranges
+            .iter()
+            .map(|range| u64::from(u32::from(*range.end()) - u32::from(*range.start()) + 1))
+            .sum();
+        file.dentry.file_size = u32::try_from(cluster_count * u64::from(cluster_size)).unwrap_or(u32::MAX);
+    }
+    file.dentry.make_regular_file();
+}
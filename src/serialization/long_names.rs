@@ -0,0 +1,220 @@
+use std::collections::HashSet;
+use std::fmt::{self, Display, Formatter};
+
+use anyhow::{bail, Result};
+
+use crate::ext4::EXT4_NAME_MAX_LEN;
+use crate::fat::FatFile;
+use crate::serialization::{ConversionPolicy, FileDecision};
+
+/// Extensions longer than this are not preserved when truncating a name, since they are unlikely to be extensions.
+const MAX_PRESERVED_EXTENSION_LEN: usize = 16;
+
+/// A file whose name is longer than ext4's limit of 255 bytes. FAT32 allows names of up to 255 UCS-2 characters, which
+/// take up to 765 bytes in UTF-8, e.g. if they consist of CJK characters.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LongName {
+    /// the path of the file, starting with '/' at the root of the FAT filesystem
+    pub path: String,
+    pub char_count: usize,
+    /// the name the file will have in the ext4 filesystem if it was truncated
+    pub truncated_name: Option<String>,
+}
+
+impl Display for LongName {
+    fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+        write!(
+            formatter,
+            "{} ({} characters, {} bytes in UTF-8)",
+            self.path,
+            self.char_count,
+            self.path.rsplit('/').next().unwrap_or_default().len()
+        )?;
+        if let Some(truncated_name) = &self.truncated_name {
+            write!(formatter, " -> {}", truncated_name)?;
+        }
+        Ok(())
+    }
+}
+
+impl LongName {
+    fn new(dir_path: &str, file: &FatFile) -> Self {
+        Self {
+            path: format!("{}/{}", dir_path, file.name),
+            char_count: file.name.chars().count(),
+            truncated_name: None,
+        }
+    }
+}
+
+/// Finds the file names that are still too long for ext4 after the `ConversionPolicy`s, so that the serialization can
+/// reject all of them at once.
+#[derive(Debug, Default)]
+pub struct LongNameChecker {
+    long_names: Vec<LongName>,
+}
+
+impl LongNameChecker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn long_names(&self) -> &[LongName] {
+        &self.long_names
+    }
+
+    /// Records the long names among `children`, the content of the directory at `dir_path`.
+    pub fn check_directory(&mut self, dir_path: &str, children: &[FatFile]) {
+        let long_names = children.iter().filter(|file| file.name.len() > EXT4_NAME_MAX_LEN);
+        self.long_names.extend(long_names.map(|file| LongName::new(dir_path, file)));
+    }
+
+    /// Returns Err listing every long name if there are any.
+    pub fn result(&self) -> Result<()> {
+        if self.long_names.is_empty() {
+            return Ok(());
+        }
+        let list: Vec<String> = self.long_names.iter().map(|long_name| format!("  {}", long_name)).collect();
+        bail!(
+            "{} file names exceed ext4's limit of {} bytes. Rename them or run again with --truncate-long-names:\n{}",
+            self.long_names.len(),
+            EXT4_NAME_MAX_LEN,
+            list.join("\n")
+        )
+    }
+}
+
+/// Truncates the names that are too long for ext4 to 255 bytes, keeping the extension and keeping the names in a
+/// directory unique, for `--truncate-long-names`. Since it has to know the other names in the directory, it decides
+/// after the ops and the other policies.
+#[derive(Debug, Default)]
+pub struct LongNameTruncator {
+    long_names: Vec<LongName>,
+}
+
+impl LongNameTruncator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The names truncated so far
+    pub fn long_names(&self) -> &[LongName] {
+        &self.long_names
+    }
+}
+
+impl ConversionPolicy for LongNameTruncator {
+    fn decide_directory(&mut self, dir_path: &str, children: &[FatFile]) -> Result<Vec<FileDecision>> {
+        let mut decisions = vec![FileDecision::default(); children.len()];
+        if children.iter().all(|file| file.name.len() <= EXT4_NAME_MAX_LEN) {
+            return Ok(decisions);
+        }
+
+        let mut taken_names: HashSet<String> = children.iter().map(|file| file.name.clone()).collect();
+        for (file, decision) in children.iter().zip(&mut decisions) {
+            if file.name.len() <= EXT4_NAME_MAX_LEN {
+                continue;
+            }
+            let truncated_name = truncate_name(&file.name, &taken_names);
+            taken_names.insert(truncated_name.clone());
+            decision.name = Some(truncated_name.clone());
+            self.long_names.push(LongName {
+                truncated_name: Some(truncated_name),
+                ..LongName::new(dir_path, file)
+            });
+        }
+        Ok(decisions)
+    }
+}
+
+/// Shortens `name` to at most `EXT4_NAME_MAX_LEN` bytes without splitting a character. The extension is preserved and
+/// a "~N" suffix is added to the stem if the shortened name would be in `taken_names`.
+fn truncate_name(name: &str, taken_names: &HashSet<String>) -> String {
+    let (stem, extension) = match name.rfind('.') {
+        Some(dot_idx) if dot_idx > 0 && name.len() - dot_idx <= MAX_PRESERVED_EXTENSION_LEN => name.split_at(dot_idx),
+        _ => (name, ""),
+    };
+
+    let mut suffix = String::new();
+    for counter in 1.. {
+        let stem_len = floor_char_boundary(stem, EXT4_NAME_MAX_LEN - extension.len() - suffix.len());
+        let candidate = format!("{}{}{}", &stem[..stem_len], suffix, extension);
+        if !taken_names.contains(&candidate) {
+            return candidate;
+        }
+        suffix = format!("~{}", counter);
+    }
+    unreachable!("A directory cannot contain infinitely many files")
+}
+
+/// Returns the largest index <= `max_len` that lies on a character boundary of `s`.
+fn floor_char_boundary(s: &str, max_len: usize) -> usize {
+    if max_len >= s.len() {
+        return s.len();
+    }
+    (0..=max_len).rev().find(|&idx| s.is_char_boundary(idx)).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ext4::InodeOverrides;
+    use crate::fat::FatDentry;
+
+    fn file(name: String) -> FatFile {
+        FatFile {
+            name,
+            dentry: FatDentry::default(),
+            data_ranges: Vec::new(),
+            symlink_target: None,
+            overrides: InodeOverrides::default(),
+            xattrs: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn truncates_at_char_boundary() {
+        // 100 characters, 300 bytes
+        let name = "語".repeat(100);
+        let truncated = truncate_name(&name, &HashSet::new());
+        assert_eq!(truncated, "語".repeat(85));
+    }
+
+    #[test]
+    fn keeps_extension() {
+        let name = format!("{}.txt", "a".repeat(300));
+        let truncated = truncate_name(&name, &HashSet::new());
+        assert_eq!(truncated.len(), EXT4_NAME_MAX_LEN);
+        assert!(truncated.ends_with("a.txt"));
+    }
+
+    #[test]
+    fn truncated_names_are_unique() {
+        let long_name = |last_char| format!("{}{}.txt", "ü".repeat(130), last_char);
+        let children = vec![file(long_name('a')), file(long_name('b')), file(long_name('c')), file("short".into())];
+        let mut truncator = LongNameTruncator::new();
+        let decisions = truncator.decide_directory("/dir", &children).unwrap();
+
+        let names: Vec<&str> = decisions.iter().filter_map(|decision| decision.name.as_deref()).collect();
+        assert_eq!(names.len(), 3);
+        assert_eq!(names.iter().collect::<HashSet<_>>().len(), 3);
+        assert!(names.iter().all(|name| name.len() <= EXT4_NAME_MAX_LEN));
+        assert!(names[1].ends_with("~1.txt"));
+        assert_eq!(truncator.long_names().len(), 3);
+        assert_eq!(truncator.long_names()[0].truncated_name.as_deref(), Some(names[0]));
+    }
+
+    #[test]
+    fn rejects_all_long_names_at_once() {
+        let mut checker = LongNameChecker::new();
+        checker.check_directory("", &[file("短".repeat(90)), file("short".to_string())]);
+        checker.check_directory("/a", &[file("b".repeat(256))]);
+
+        assert_eq!(checker.long_names().len(), 2);
+        assert_eq!(checker.long_names()[1].path, format!("/a/{}", "b".repeat(256)));
+        assert!(checker.long_names()[0].truncated_name.is_none());
+        let message = checker.result().unwrap_err().to_string();
+        assert!(message.starts_with("2 file names exceed"));
+        assert!(message.contains("(90 characters, 270 bytes in UTF-8)"));
+    }
+}
@@ -1,19 +1,54 @@
+mod archive_bit;
+mod archive_verifier;
 mod dentry;
+mod dentry_order;
 mod deserializer;
 mod dry_run_deserializer;
 mod ext4_deserializer;
 mod fat_serializer;
+mod filter;
+mod hard_links;
+mod invalid_attributes;
+mod long_names;
+mod ops;
+mod policy;
+mod shortcut;
+mod skipped_files;
 mod stream_archiver;
+mod truncated_files;
 
+pub use self::archive_bit::*;
+pub use self::archive_verifier::*;
 pub use self::dentry::*;
+pub use self::dentry_order::*;
 pub use self::deserializer::*;
 pub use self::dry_run_deserializer::*;
 pub use self::ext4_deserializer::*;
 pub use self::fat_serializer::*;
+pub use self::filter::*;
+pub use self::hard_links::*;
+pub use self::invalid_attributes::*;
+pub use self::long_names::*;
+pub use self::ops::*;
+pub use self::policy::*;
+pub use self::shortcut::*;
+pub use self::skipped_files::*;
 pub use self::stream_archiver::*;
+pub use self::truncated_files::*;
 
+/// The kind of the next record in the archive. Every file starts with its `FileType`, its dentry and its name, followed
+/// by its extended attributes encoded by `encode_xattrs` if the dentry has `has_xattrs` set.
 #[derive(Clone, Copy)]
 pub enum FileType {
-    Directory(u32), // contains child count
+    /// followed by the directory's dentry and name, its children, and an `EndOfDirectory` record
+    Directory,
     RegularFile,
+    Symlink,
+    /// followed by the dentry and name of a regular file whose content is the same as that of a previous one, and the
+    /// index of the previous one among the `RegularFile` records, see `HardLinkFolder`. A hard link shares the
+    /// extended attributes of the previous one and has none of its own.
+    HardLink,
+    /// ends the children of a directory; the children of the root directory start the archive without a `Directory`
+    /// record, but are ended by one as well
+    EndOfDirectory,
 }
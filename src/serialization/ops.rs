@@ -0,0 +1,23 @@
+use anyhow::Result;
+
+use crate::fat::{FatFile, FatFs};
+
+/// A step of the serialization pipeline that `FatTreeSerializer` applies to every file (including directories) after
+/// reading it from its parent directory and before relocating and archiving it. Ops run in the order in which they
+/// were added with `FatTreeSerializer::add_op`; they can exclude, rename or count files, or turn them into symlinks.
+/// They run after the `ConversionPolicy`s, which cannot read the data but can decide owners and permissions.
+///
+/// An op may change a file's name, dentry and symlink target, but not its data ranges: those have to keep referring
+/// to the clusters that the file occupies in the FAT filesystem, which the op can read through `fat_fs`.
+pub trait FileOp {
+    fn apply(&mut self, file: &mut FatFile, fat_fs: &FatFs) -> Result<Verdict>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Verdict {
+    /// Pass the file on to the next op
+    Include,
+    /// Leave the file out of the conversion and skip the remaining ops. If the file is a directory, its content is
+    /// left out as well.
+    Exclude,
+}
@@ -0,0 +1,117 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use anyhow::{bail, Result};
+
+use crate::ext4::{encode_xattrs, Owner, Xattr};
+use crate::fat::FatFile;
+
+/// The highest permissions including the setuid, setgid and sticky bits
+const MAX_PERMS: u16 = 0o7777;
+
+/// Decides per file what the conversion does with it, so that a library user can leave out, rename or re-own files or
+/// give them extended attributes by arbitrary rules. `FatTreeSerializer` consults its policies in the order in which
+/// they were added with `FatTreeSerializer::add_policy`, for every file (including directories) that is not a volume
+/// label or skipped for its attributes: `decide` before its ops, and `decide_directory` for the remaining files of a
+/// directory after them. Each policy sees the names and extended attributes decided by the previous ones, and its
+/// owner and permissions replace theirs. The decisions are stored in the archive, so the deserializer applies them
+/// even when a conversion is resumed.
+///
+/// The built-in policies are `FileFilter` behind `--exclude-size-over` and `--exclude-older-than`, `OwnerPolicy`
+/// behind `--owner` and `LongNameTruncator` behind `--truncate-long-names`, which `--resolve-conflicts` may enable.
+pub trait ConversionPolicy {
+    /// `path` is the path of `file` in the FAT filesystem, with its name as decided by the previous policies. By
+    /// default, the file is kept as it is.
+    fn decide(&mut self, _file: &FatFile, _path: &str) -> Result<FileDecision> {
+        Ok(FileDecision::default())
+    }
+
+    /// Decides for the `children` of the directory at `dir_path` at once, e.g. to rename them depending on the names
+    /// of their siblings. Returns one decision per child, in the same order. By default, the children are kept as
+    /// they are.
+    fn decide_directory(&mut self, _dir_path: &str, children: &[FatFile]) -> Result<Vec<FileDecision>> {
+        Ok(vec![FileDecision::default(); children.len()])
+    }
+}
+
+/// Lets a policy be shared with its creator, e.g. to read what it has recorded after the serialization.
+impl<P: ConversionPolicy + ?Sized> ConversionPolicy for Rc<RefCell<P>> {
+    fn decide(&mut self, file: &FatFile, path: &str) -> Result<FileDecision> {
+        self.borrow_mut().decide(file, path)
+    }
+
+    fn decide_directory(&mut self, dir_path: &str, children: &[FatFile]) -> Result<Vec<FileDecision>> {
+        self.borrow_mut().decide_directory(dir_path, children)
+    }
+}
+
+/// What a `ConversionPolicy` decided for a file. The default keeps the file as the conversion would convert it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FileDecision {
+    /// leave the file out of the conversion, along with its content if it is a directory
+    pub exclude: bool,
+    /// the name of the file in the ext4 filesystem
+    pub name: Option<String>,
+    pub owner: Option<Owner>,
+    /// the permission bits of `i_mode`, including the setuid, setgid and sticky bits; ignored for symlinks
+    pub perms: Option<u16>,
+    /// extended attributes to add to those decided by the previous policies, replacing those with the same name. They
+    /// have to fit into the inode, see `encode_xattrs`.
+    pub xattrs: Vec<Xattr>,
+}
+
+impl FileDecision {
+    pub fn exclude() -> Self {
+        Self { exclude: true, ..Self::default() }
+    }
+
+    /// Applies the decision to `file`. Fails if the name is not a valid ext4 file name, the permissions have bits
+    /// beyond `MAX_PERMS` or the extended attributes cannot be stored, in which case `file` is unchanged. Names that
+    /// are too long are left to the `LongNameChecker`.
+    pub fn apply(self, file: &mut FatFile) -> Result<()> {
+        if let Some(name) = &self.name {
+            if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\0']) {
+                bail!("The policy renamed {} to the invalid name {:?}", file.name, name);
+            }
+        }
+        if let Some(perms) = self.perms {
+            if perms > MAX_PERMS {
+                bail!("The policy gave {} the invalid permissions {:o}", file.name, perms);
+            }
+        }
+        let xattrs = if self.xattrs.is_empty() {
+            None
+        } else {
+            let mut xattrs = file.xattrs.clone();
+            xattrs.retain(|xattr| self.xattrs.iter().all(|new_xattr| new_xattr.name != xattr.name));
+            xattrs.extend(self.xattrs);
+            encode_xattrs(&xattrs)?;
+            Some(xattrs)
+        };
+
+        if let Some(name) = self.name {
+            file.name = name;
+        }
+        if self.owner.is_some() {
+            file.overrides.owner = self.owner;
+        }
+        if self.perms.is_some() {
+            file.overrides.perms = self.perms;
+        }
+        if let Some(xattrs) = xattrs {
+            file.xattrs = xattrs;
+        }
+        Ok(())
+    }
+}
+
+/// Gives every converted file the same owner, for `--owner`. The root directory is owned by the owner of the
+/// converted files as well, see `Ext4TreeDeserializer::set_owner`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OwnerPolicy(pub Owner);
+
+impl ConversionPolicy for OwnerPolicy {
+    fn decide(&mut self, _file: &FatFile, _path: &str) -> Result<FileDecision> {
+        Ok(FileDecision { owner: Some(self.0), ..FileDecision::default() })
+    }
+}
@@ -0,0 +1,323 @@
+use std::convert::TryFrom;
+
+use anyhow::{bail, Context, Result};
+
+use crate::ext4::FAST_SYMLINK_MAX_LEN;
+use crate::fat::{FatFile, FatFs};
+use crate::serialization::{FileOp, Verdict};
+use crate::util::FromU32;
+
+// cfr. [MS-SHLLINK]: Shell Link (.LNK) Binary File Format
+const HEADER_SIZE: u32 = 0x4C;
+const LINK_CLSID: [u8; 16] =
+    [0x01, 0x14, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0xC0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x46];
+const LINK_FLAGS_OFFSET: usize = 0x14;
+
+// LinkFlags
+const HAS_LINK_TARGET_ID_LIST: u32 = 0x1;
+const HAS_LINK_INFO: u32 = 0x2;
+const HAS_NAME: u32 = 0x4;
+const HAS_RELATIVE_PATH: u32 = 0x8;
+const IS_UNICODE: u32 = 0x80;
+
+// LinkInfoFlags
+const VOLUME_ID_AND_LOCAL_BASE_PATH: u32 = 0x1;
+const LINK_INFO_FLAGS_OFFSET: usize = 8;
+const LINK_INFO_VOLUME_ID_OFFSET: usize = 12;
+const VOLUME_ID_SERIAL_NUMBER_OFFSET: usize = 8;
+
+/// Shortcuts are small, anything bigger than this is not worth reading.
+const MAX_SHORTCUT_SIZE: u32 = 64 * 1024;
+
+/// Converts Windows shortcuts (.lnk files) into symlinks. A shortcut is only converted if it points to a file on the
+/// same volume via a relative path that fits into a fast symlink; all other shortcuts, including those that cannot be
+/// parsed, are kept as regular files. The converted symlink keeps the shortcut's name, since removing the extension
+/// could clash with another file in the same directory.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ShortcutConverter;
+
+impl FileOp for ShortcutConverter {
+    fn apply(&mut self, file: &mut FatFile, fat_fs: &FatFs) -> Result<Verdict> {
+        if is_shortcut(file) {
+            let content = file_content(file, fat_fs)?;
+            file.symlink_target = symlink_target(&content, fat_fs.boot_sector().volume_id).ok();
+        }
+        Ok(Verdict::Include)
+    }
+}
+
+fn is_shortcut(file: &FatFile) -> bool {
+    !file.dentry.is_dir() && file.dentry.file_size <= MAX_SHORTCUT_SIZE && file.name.to_lowercase().ends_with(".lnk")
+}
+
+fn file_content(file: &FatFile, fat_fs: &FatFs) -> Result<Vec<u8>> {
+    let mut content = Vec::new();
+    for data_cluster_idx in file.data_ranges.iter().cloned().flatten() {
+        content.extend_from_slice(fat_fs.data_cluster(data_cluster_idx)?);
+    }
+    content.truncate(usize::fromx(file.dentry.file_size));
+    Ok(content)
+}
+
+/// Returns the target of the shortcut in `bytes` as a relative Unix path if the shortcut points to a file on the volume
+/// with the serial number `volume_id`.
+pub fn symlink_target(bytes: &[u8], volume_id: u32) -> Result<String> {
+    let shortcut = Shortcut::parse(bytes)?;
+    if shortcut.volume_id != Some(volume_id) {
+        bail!("Shortcut does not point to a file on this volume");
+    }
+    let relative_path = shortcut.relative_path.context("Shortcut has no relative path")?;
+    let target = relative_path.replace('\\', "/");
+    if target.is_empty() || target.contains('\0') {
+        bail!("Invalid shortcut target '{}'", target);
+    }
+    if target.len() > FAST_SYMLINK_MAX_LEN {
+        bail!("Shortcut target '{}' is too long for a fast symlink", target);
+    }
+    Ok(target)
+}
+
+/// The parts of a shortcut that are needed to create a symlink
+#[derive(Debug, Default, PartialEq)]
+pub struct Shortcut {
+    /// The serial number of the volume containing the target
+    pub volume_id: Option<u32>,
+    /// The target's path relative to the shortcut, with backslashes as separators
+    pub relative_path: Option<String>,
+}
+
+impl Shortcut {
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        let mut reader = ByteReader::new(bytes);
+        if reader.u32()? != HEADER_SIZE || reader.bytes(LINK_CLSID.len())? != LINK_CLSID {
+            bail!("Not a shortcut");
+        }
+        reader.seek(LINK_FLAGS_OFFSET)?;
+        let link_flags = reader.u32()?;
+        reader.seek(usize::fromx(HEADER_SIZE))?;
+
+        if link_flags & HAS_LINK_TARGET_ID_LIST != 0 {
+            let id_list_size = reader.u16()?;
+            reader.skip(usize::from(id_list_size))?;
+        }
+
+        let mut shortcut = Self::default();
+        if link_flags & HAS_LINK_INFO != 0 {
+            // the size includes the size field itself
+            let link_info_start = reader.position;
+            let link_info_size = reader.u32()?;
+            reader.seek(link_info_start)?;
+            let link_info = reader.bytes(usize::fromx(link_info_size))?;
+            shortcut.volume_id = Self::parse_volume_id(link_info)?;
+        }
+
+        let is_unicode = link_flags & IS_UNICODE != 0;
+        if link_flags & HAS_NAME != 0 {
+            reader.string(is_unicode)?;
+        }
+        if link_flags & HAS_RELATIVE_PATH != 0 {
+            shortcut.relative_path = Some(reader.string(is_unicode)?);
+        }
+        Ok(shortcut)
+    }
+
+    /// `link_info` is the entire LinkInfo structure, including its size field
+    fn parse_volume_id(link_info: &[u8]) -> Result<Option<u32>> {
+        let mut reader = ByteReader::new(link_info);
+        reader.seek(LINK_INFO_FLAGS_OFFSET)?;
+        if reader.u32()? & VOLUME_ID_AND_LOCAL_BASE_PATH == 0 {
+            return Ok(None);
+        }
+        reader.seek(LINK_INFO_VOLUME_ID_OFFSET)?;
+        let volume_id_offset = reader.u32()?;
+        reader.seek(usize::fromx(volume_id_offset) + VOLUME_ID_SERIAL_NUMBER_OFFSET)?;
+        Ok(Some(reader.u32()?))
+    }
+}
+
+/// Reads little-endian values from a byte slice, failing instead of panicking if the slice is too short.
+struct ByteReader<'b> {
+    bytes: &'b [u8],
+    position: usize,
+}
+
+impl<'b> ByteReader<'b> {
+    fn new(bytes: &'b [u8]) -> Self {
+        Self { bytes, position: 0 }
+    }
+
+    fn seek(&mut self, position: usize) -> Result<()> {
+        if position > self.bytes.len() {
+            bail!("Shortcut is truncated");
+        }
+        self.position = position;
+        Ok(())
+    }
+
+    fn skip(&mut self, len: usize) -> Result<()> {
+        self.bytes(len).map(|_| ())
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'b [u8]> {
+        let bytes = self
+            .bytes
+            .get(self.position..self.position.saturating_add(len))
+            .context("Shortcut is truncated")?;
+        self.position += len;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(<[u8; 2]>::try_from(self.bytes(2)?).unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(<[u8; 4]>::try_from(self.bytes(4)?).unwrap()))
+    }
+
+    /// Reads a StringData structure, which is prefixed by its length in characters. Non-Unicode strings use the
+    /// system code page, so only ASCII is accepted.
+    fn string(&mut self, is_unicode: bool) -> Result<String> {
+        let char_count = usize::from(self.u16()?);
+        if is_unicode {
+            let units = self
+                .bytes(char_count * 2)?
+                .chunks_exact(2)
+                .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+                .collect::<Vec<_>>();
+            String::from_utf16(&units).context("Shortcut contains an invalid UTF-16 string")
+        } else {
+            let bytes = self.bytes(char_count)?;
+            if !bytes.is_ascii() {
+                bail!("Shortcut contains a non-ASCII string");
+            }
+            Ok(String::from_utf8(bytes.to_vec()).unwrap())
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::marker::PhantomData;
+
+    use super::*;
+    use crate::fat::{FatImageBuilder, TestFile, ROOT_FAT_IDX};
+
+    /// The volume ID of images created by `FatImageBuilder`
+    pub const TEST_VOLUME_ID: u32 = 0x1234_5678;
+
+    /// Builds a shortcut with a LinkInfo structure for a target on the volume `volume_id`, as Windows creates it for
+    /// a file on a local drive.
+    pub fn shortcut_bytes(volume_id: u32, relative_path: &str, is_unicode: bool) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend(HEADER_SIZE.to_le_bytes());
+        bytes.extend(LINK_CLSID);
+        let flags = HAS_LINK_TARGET_ID_LIST | HAS_LINK_INFO | HAS_NAME | HAS_RELATIVE_PATH;
+        let flags = if is_unicode { flags | IS_UNICODE } else { flags };
+        bytes.extend(flags.to_le_bytes());
+        bytes.resize(usize::fromx(HEADER_SIZE), 0);
+
+        let id_list = [0x14, 0x00, 0x1F, 0x50];
+        bytes.extend(u16::try_from(id_list.len()).unwrap().to_le_bytes());
+        bytes.extend(id_list);
+
+        let local_base_path = b"D:\\dir\\target.txt\0";
+        let volume_id_structure =
+            [16u32.to_le_bytes(), 3u32.to_le_bytes(), volume_id.to_le_bytes(), 16u32.to_le_bytes()].concat();
+        let link_info_header_size = 28u32;
+        let volume_id_offset = link_info_header_size;
+        let local_base_path_offset = volume_id_offset + u32::try_from(volume_id_structure.len()).unwrap();
+        let common_path_suffix_offset = local_base_path_offset + u32::try_from(local_base_path.len()).unwrap();
+        let link_info_size = common_path_suffix_offset + 1;
+        for field in [
+            link_info_size,
+            link_info_header_size,
+            VOLUME_ID_AND_LOCAL_BASE_PATH,
+            volume_id_offset,
+            local_base_path_offset,
+            0,
+            common_path_suffix_offset,
+        ] {
+            bytes.extend(field.to_le_bytes());
+        }
+        bytes.extend(volume_id_structure);
+        bytes.extend(local_base_path);
+        bytes.push(0);
+
+        for string in ["A comment", relative_path] {
+            if is_unicode {
+                bytes.extend(u16::try_from(string.encode_utf16().count()).unwrap().to_le_bytes());
+                bytes.extend(string.encode_utf16().flat_map(u16::to_le_bytes));
+            } else {
+                bytes.extend(u16::try_from(string.len()).unwrap().to_le_bytes());
+                bytes.extend(string.as_bytes());
+            }
+        }
+        bytes
+    }
+
+    #[test]
+    fn parses_shortcut() {
+        for is_unicode in [false, true] {
+            let shortcut = Shortcut::parse(&shortcut_bytes(42, "..\\dir\\target.txt", is_unicode)).unwrap();
+            assert_eq!(
+                shortcut,
+                Shortcut {
+                    volume_id: Some(42),
+                    relative_path: Some("..\\dir\\target.txt".to_string())
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn converts_relative_path_to_symlink_target() {
+        let bytes = shortcut_bytes(42, "..\\dir\\target.txt", true);
+        assert_eq!(symlink_target(&bytes, 42).unwrap(), "../dir/target.txt");
+    }
+
+    #[test]
+    fn rejects_shortcut_to_other_volume() {
+        let bytes = shortcut_bytes(42, ".\\target.txt", true);
+        assert!(symlink_target(&bytes, 43).is_err());
+    }
+
+    #[test]
+    fn rejects_target_too_long_for_fast_symlink() {
+        let bytes = shortcut_bytes(42, &"x".repeat(FAST_SYMLINK_MAX_LEN + 1), true);
+        assert!(symlink_target(&bytes, 42).is_err());
+        let bytes = shortcut_bytes(42, &"x".repeat(FAST_SYMLINK_MAX_LEN), true);
+        assert!(symlink_target(&bytes, 42).is_ok());
+    }
+
+    #[test]
+    fn rejects_truncated_shortcut() {
+        let bytes = shortcut_bytes(42, ".\\target.txt", true);
+        for len in [0, 4, usize::fromx(HEADER_SIZE), bytes.len() - 1] {
+            assert!(Shortcut::parse(&bytes[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn sets_symlink_target_of_valid_shortcuts_only() {
+        let shortcut = |name: &str, content| TestFile::RegularFileWithContent { name: name.to_string(), content };
+        let mut image = FatImageBuilder::new(32 * 1024 * 1024, 1024).build(&[
+            TestFile::RegularFile { name: "target.txt".to_string(), size: 10 },
+            shortcut("valid.lnk", shortcut_bytes(TEST_VOLUME_ID, ".\\target.txt", true)),
+            shortcut("other volume.LNK", shortcut_bytes(TEST_VOLUME_ID + 1, ".\\target.txt", true)),
+            shortcut("garbage.lnk", vec![0xFF; 2000]),
+            shortcut("not a shortcut.txt", shortcut_bytes(TEST_VOLUME_ID, ".\\target.txt", true)),
+        ]);
+        // SAFETY: Safe because `image` contains a FAT32 filesystem and outlives `fat_fs`.
+        let fat_fs = unsafe { FatFs::new(image.as_mut_ptr(), image.len(), PhantomData).unwrap() };
+        // SAFETY: Safe because `ROOT_FAT_IDX` belongs to the root directory.
+        let files = unsafe { fat_fs.dir_content_iter(ROOT_FAT_IDX) };
+        let targets: Vec<_> = files
+            .map(|mut file| {
+                assert_eq!(ShortcutConverter.apply(&mut file, &fat_fs).unwrap(), Verdict::Include);
+                file.symlink_target
+            })
+            .collect();
+        assert_eq!(targets, [None, Some("./target.txt".to_string()), None, None, None]);
+    }
+}
@@ -0,0 +1,28 @@
+use std::fmt::{self, Display, Formatter};
+
+/// What to do with a file that cannot be converted, e.g. because its dentry contains an invalid timestamp or its
+/// cluster chain leaves the data region. Only files are skipped, errors that affect the whole conversion (e.g. running
+/// out of free clusters) always fail it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorPolicy {
+    /// Fail the serialization at the first such file
+    FailFast,
+    /// Leave such files out of the conversion and report them afterwards. Their clusters are free after the
+    /// conversion, like those of excluded files
+    CollectErrors,
+}
+
+/// A file that was left out of the conversion because of `ErrorPolicy::CollectErrors`
+#[derive(Clone, Debug, PartialEq)]
+pub struct SkippedFile {
+    /// the path of the file, starting with '/' at the root of the FAT filesystem
+    pub path: String,
+    /// why the file could not be converted
+    pub error: String,
+}
+
+impl Display for SkippedFile {
+    fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+        write!(formatter, "{} ({})", self.path, self.error)
+    }
+}
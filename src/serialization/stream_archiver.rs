@@ -1,15 +1,20 @@
 use std::any::{type_name, Any, TypeId};
+use std::marker::PhantomData;
 use std::mem::size_of;
 use std::rc::Rc;
 
 use anyhow::Result;
+use serde::{Deserialize, Serialize};
 
-use crate::allocator::{AllocatedClusterIdx, AllocatedReader, Allocator};
+use crate::allocator::{
+    AllocatedClusterIdx, AllocatedReader, AllocationPurpose, Allocator, AllocatorStats, ClusterAllocator,
+};
+use crate::fat::ClusterIdx;
 
 type Page = [u8];
 type PageIdx = AllocatedClusterIdx;
 
-pub struct StreamArchiver<'a> {
+pub struct StreamArchiver<'a, A: ClusterAllocator = Allocator<'a>> {
     /// SAFETY: must not be used to access a cluster before `self` is dropped
     head: Option<PageIdx>,
     /// SAFETY: must not be leaked outside of `self`
@@ -18,7 +23,21 @@ pub struct StreamArchiver<'a> {
     current_page: Vec<u8>,
     page_size: usize,
     position_in_current_page: usize,
-    allocator: Rc<Allocator<'a>>,
+    allocator: Rc<A>,
+    _lifetime: PhantomData<&'a ()>,
+}
+
+/// Where a finished archive is stored, which allows reading it in a later invocation of the converter, as long as the
+/// clusters have not been modified in between.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ArchiveLocation {
+    /// the first page of the archive
+    pub head: ClusterIdx,
+    /// the index after the last cluster the `Allocator` had allocated when the archive was finished, which includes
+    /// the archive's pages and every other cluster allocated before
+    pub end: ClusterIdx,
+    /// the stats of the `Allocator` when the archive was finished
+    pub allocator_stats: AllocatorStats,
 }
 
 #[derive(Copy, Clone)]
@@ -27,11 +46,11 @@ struct Header {
     pub type_id: TypeId,
 }
 
-impl<'a> StreamArchiver<'a> {
+impl<'a, A: ClusterAllocator + 'a> StreamArchiver<'a, A> {
     /// `page_size` must be greater than or equal to `size_of::<Option<PageIdx>>() + size_of::<T>()` for every type `T`
     /// that will be archived.
     /// PANICS: Panics if `page_size < size_of::<Option<PageIdx>>() + size_of::<Header>()`.
-    pub fn new(allocator: Rc<Allocator<'a>>, page_size: usize) -> Self {
+    pub fn new(allocator: Rc<A>, page_size: usize) -> Self {
         assert!(page_size >= size_of::<Option<PageIdx>>() + size_of::<Header>());
 
         Self {
@@ -41,20 +60,28 @@ impl<'a> StreamArchiver<'a> {
             page_size,
             position_in_current_page: size_of::<Option<PageIdx>>(),
             allocator,
+            _lifetime: PhantomData,
         }
     }
 
-    pub fn into_reader(mut self) -> Result<(Reader<'a>, Allocator<'a>)> {
+    /// Finishes the archive and splits the allocator into a reader for the archive and an allocator for the rest of the
+    /// conversion. The last page is written before the split, so the archive allocates nothing afterwards, and the
+    /// `free_block_count` of the returned allocator is what the deserialization has left.
+    pub fn into_reader(mut self) -> Result<(Reader<'a>, A)> {
         self.finalize()?;
         self.write_page()?;
-        let allocator = Rc::try_unwrap(self.allocator).expect(
-            "StreamArchiver cannot take ownership of its allocator, somebody else still has a reference to it.",
-        );
+        let allocator = Rc::try_unwrap(self.allocator).unwrap_or_else(|_| {
+            panic!("StreamArchiver cannot take ownership of its allocator, somebody else still has a reference to it.")
+        });
         let (allocated_reader, new_allocator) = allocator.split_into_reader();
         let head = self
             .head
             .expect("StreamArchiver head is None despite a call to write_page succedding");
-        Ok((Reader::new(head, self.page_size, allocated_reader), new_allocator))
+        let allocator_stats = new_allocator.stats();
+        Ok((
+            Reader::new(head, self.page_size, allocated_reader, allocator_stats),
+            new_allocator,
+        ))
     }
 
     /// PANICS: Panics if `size_of::<Option<PageIdx>>() + size_of::<T>() > self.page_size`
@@ -91,7 +118,7 @@ impl<'a> StreamArchiver<'a> {
 
     /// Never returns `Ok(None)`
     fn allocate_page(&self) -> Result<PageIdx> {
-        self.allocator.allocate_one()
+        self.allocator.allocate_one(AllocationPurpose::Archive)
     }
 
     fn write_page(&mut self) -> Result<()> {
@@ -169,6 +196,7 @@ impl<'a> StreamArchiver<'a> {
 
 #[derive(Clone)]
 pub struct Reader<'a> {
+    location: ArchiveLocation,
     current_page: &'a Page,
     page_size: usize,
     position_in_current_page: usize,
@@ -177,8 +205,19 @@ pub struct Reader<'a> {
 }
 
 impl<'a> Reader<'a> {
-    pub fn new(first_page_idx: PageIdx, page_size: usize, allocated_reader: AllocatedReader<'a>) -> Self {
+    /// `allocator_stats` are the stats of the `Allocator` that `allocated_reader` was split from.
+    pub fn new(
+        first_page_idx: PageIdx,
+        page_size: usize,
+        allocated_reader: AllocatedReader<'a>,
+        allocator_stats: AllocatorStats,
+    ) -> Self {
         Self {
+            location: ArchiveLocation {
+                head: first_page_idx.as_cluster_idx(),
+                end: allocated_reader.end(),
+                allocator_stats,
+            },
             current_page: allocated_reader.cluster(&first_page_idx),
             page_size,
             position_in_current_page: size_of::<Option<PageIdx>>(),
@@ -187,8 +226,35 @@ impl<'a> Reader<'a> {
         }
     }
 
+    /// Reads the archive at `location` that an earlier `Allocator` allocated. Returns the reader and `allocator`,
+    /// which continues where the earlier `Allocator` stopped.
+    /// SAFETY: `location` must be the location of a finished archive, and `allocator` must have the same used ranges as
+    /// the `Allocator` that allocated it. The clusters before `location.end` must not have been modified since the
+    /// archive was finished.
+    pub unsafe fn resume(
+        location: ArchiveLocation,
+        page_size: usize,
+        mut allocator: Allocator<'a>,
+    ) -> (Self, Allocator<'a>) {
+        // SAFETY: Safe because the caller guarantees that the earlier `Allocator` allocated the clusters before
+        // `location.end` that are not used, and these clusters are only read through the reader.
+        unsafe { allocator.resume(location.end, location.allocator_stats) };
+        let (allocated_reader, allocator) = allocator.split_into_reader();
+        // SAFETY: Safe because `head` was allocated by the earlier `Allocator`, which no longer exists, so it is the
+        // only `AllocatedClusterIdx` for the first page.
+        let head = unsafe { AllocatedClusterIdx::new(location.head) };
+        let reader = Self::new(head, page_size, allocated_reader, location.allocator_stats);
+        (reader, allocator)
+    }
+
+    pub fn location(&self) -> ArchiveLocation {
+        self.location
+    }
+
     /// PANICS: Panics if called after reaching the end of the archive or if the next archived object is not of type
     /// `T`.
+    // the archived objects have different types, so `Reader` cannot be an `Iterator`
+    #[allow(clippy::should_implement_trait)]
     pub fn next<T>(&mut self) -> Vec<T>
     where T: Any {
         // SAFETY: Since `self` was created from a consistent `StreamArchiver`, right after instantiation the object at
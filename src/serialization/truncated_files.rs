@@ -0,0 +1,98 @@
+use std::fmt::{self, Display, Formatter};
+
+use crate::fat::FatFile;
+
+/// A regular file whose cluster chain covers fewer bytes than its size in the FAT dentry announces, e.g. because the
+/// FAT was damaged or a write was interrupted. Its size is truncated to the bytes that are covered, so that the ext4
+/// inode does not end in a hole that was not there in the FAT filesystem.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TruncatedFile {
+    /// the path of the file, starting with '/' at the root of the FAT filesystem
+    pub path: String,
+    /// the size in the FAT dentry
+    pub announced_size: u32,
+    /// the size of the ext4 file, i.e. the number of bytes in the cluster chain
+    pub covered_size: u32,
+}
+
+impl Display for TruncatedFile {
+    fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+        write!(
+            formatter,
+            "{} (size {} bytes, but its clusters only cover {} bytes)",
+            self.path, self.announced_size, self.covered_size
+        )
+    }
+}
+
+impl TruncatedFile {
+    /// If the data ranges of `file`, which is in the directory at `dir_path`, cover fewer than `file.dentry.file_size`
+    /// bytes, sets its size to the number of covered bytes and returns the decision.
+    pub fn normalize(file: &mut FatFile, dir_path: &str, cluster_size: u32) -> Option<Self> {
+        let cluster_count: u64 = file
+            .data_ranges
+            .iter()
+            .map(|range| u64::from(u32::from(*range.end()) - u32::from(*range.start()) + 1))
+            .sum();
+        let covered_size = cluster_count * u64::from(cluster_size);
+        let announced_size = file.dentry.file_size;
+        if covered_size >= u64::from(announced_size) {
+            return None;
+        }
+
+        let covered_size = u32::try_from(covered_size).expect("covered_size < announced_size, so it fits into a u32");
+        file.dentry.file_size = covered_size;
+        Some(Self {
+            path: format!("{}/{}", dir_path, file.name),
+            announced_size,
+            covered_size,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ext4::InodeOverrides;
+    use crate::fat::{FatDentry, FatTableIndex, ROOT_FAT_IDX};
+
+    fn file(file_size: u32, cluster_count: usize) -> FatFile {
+        let data_ranges = if cluster_count == 0 {
+            Vec::new()
+        } else {
+            let last_fat_idx = FatTableIndex::try_from(usize::from(ROOT_FAT_IDX) + cluster_count - 1).unwrap();
+            vec![ROOT_FAT_IDX.to_data_cluster_idx()..=last_fat_idx.to_data_cluster_idx()]
+        };
+        FatFile {
+            name: "file".to_string(),
+            dentry: FatDentry { file_size, ..FatDentry::default() },
+            data_ranges,
+            symlink_target: None,
+            overrides: InodeOverrides::default(),
+            xattrs: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn keeps_fully_covered_files() {
+        let mut covered = file(4000, 1);
+        assert_eq!(TruncatedFile::normalize(&mut covered, "", 4096), None);
+        assert_eq!(covered.dentry.file_size, 4000);
+
+        let mut empty = file(0, 0);
+        assert_eq!(TruncatedFile::normalize(&mut empty, "", 4096), None);
+    }
+
+    #[test]
+    fn truncates_to_covered_clusters() {
+        let mut short = file(10_000, 2);
+        let truncated = TruncatedFile::normalize(&mut short, "/dir", 4096).unwrap();
+        assert_eq!(short.dentry.file_size, 8192);
+        assert_eq!(truncated.path, "/dir/file");
+        assert_eq!((truncated.announced_size, truncated.covered_size), (10_000, 8192));
+
+        let mut without_clusters = file(1, 0);
+        TruncatedFile::normalize(&mut without_clusters, "", 4096).unwrap();
+        assert_eq!(without_clusters.dentry.file_size, 0);
+    }
+}
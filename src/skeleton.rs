@@ -0,0 +1,210 @@
+use std::convert::TryFrom;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::ext4::{Owner, EXT4_NAME_MAX_LEN};
+use crate::owner::parse_owner;
+
+/// The permissions of the directories that a skeleton spec only implies, like `mkdir -p`
+const DEFAULT_MODE: u16 = 0o755;
+/// The highest permissions including the setuid, setgid and sticky bits
+const MAX_MODE: u16 = 0o7777;
+
+/// The empty directories that `--skeleton` adds to the ext4 filesystem after the converted files. A directory that the
+/// conversion already creates is kept as it is, but the skeleton's directories inside it are added. lost+found always
+/// exists.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Skeleton {
+    /// the directories in the root directory
+    pub dirs: Vec<SkeletonDir>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SkeletonDir {
+    pub name: String,
+    /// the permission bits of `i_mode`
+    pub mode: u16,
+    /// the owner, or None for the owner of the converted files
+    pub owner: Option<Owner>,
+    pub children: Vec<SkeletonDir>,
+}
+
+impl Skeleton {
+    /// Reads the skeleton from `path`, which is either a directory, whose subdirectories are copied with their
+    /// permissions and owners, or a spec file. Every line of a spec file is `PATH [MODE [OWNER]]`, e.g.
+    /// `/srv/www 750 www-data:www-data`, where MODE is octal (default: 755) and OWNER is like `--owner`, resolved with
+    /// `numeric_owner` like `--numeric-owner` (default: the owner of the converted files). Missing parent directories
+    /// are added with the defaults. Empty lines and lines starting with '#' are ignored.
+    pub fn load(path: &str, numeric_owner: bool) -> Result<Self> {
+        let mut skeleton = Self::default();
+        if fs::metadata(path)
+            .with_context(|| format!("Unable to access {}", path))?
+            .is_dir()
+        {
+            skeleton.dirs = read_dirs(Path::new(path))?;
+            return Ok(skeleton);
+        }
+
+        let spec = fs::read_to_string(path).with_context(|| format!("Unable to read {}", path))?;
+        for (line_idx, line) in spec.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            skeleton
+                .add_spec_line(line, numeric_owner)
+                .with_context(|| format!("Invalid line {} of {}", line_idx + 1, path))?;
+        }
+        Ok(skeleton)
+    }
+
+    fn add_spec_line(&mut self, line: &str, numeric_owner: bool) -> Result<()> {
+        let mut fields = line.split_whitespace();
+        let path = fields.next().expect("the line is not empty");
+        let mode = match fields.next() {
+            Some(mode) => parse_mode(mode)?,
+            None => DEFAULT_MODE,
+        };
+        let owner = fields.next().map(|owner| parse_owner(owner, numeric_owner)).transpose()?;
+        if fields.next().is_some() {
+            bail!("Expected PATH [MODE [OWNER]]");
+        }
+        self.add(path, mode, owner)
+    }
+
+    /// Adds the directory at `path` and its missing parents, or updates its mode and owner if it has been added
+    /// already.
+    pub fn add(&mut self, path: &str, mode: u16, owner: Option<Owner>) -> Result<()> {
+        if !path.starts_with('/') {
+            bail!("The path '{}' is not absolute", path);
+        }
+        let names: Vec<_> = path.split('/').filter(|name| !name.is_empty()).collect();
+        match names.as_slice() {
+            [] => bail!("The root directory always exists"),
+            ["lost+found"] => return Ok(()),
+            ["lost+found", ..] => bail!("lost+found must stay empty"),
+            _ => (),
+        }
+        if let Some(name) = names.iter().find(|&&name| name == "." || name == "..") {
+            bail!("The path '{}' contains '{}'", path, name);
+        }
+        if let Some(name) = names.iter().find(|name| name.len() > EXT4_NAME_MAX_LEN) {
+            bail!("The name '{}' is longer than {} bytes", name, EXT4_NAME_MAX_LEN);
+        }
+
+        let mut dirs = &mut self.dirs;
+        for (idx, &name) in names.iter().enumerate() {
+            let pos = match dirs.iter().position(|dir| dir.name == name) {
+                Some(pos) => pos,
+                None => {
+                    dirs.push(SkeletonDir {
+                        name: name.to_string(),
+                        mode: DEFAULT_MODE,
+                        owner: None,
+                        children: Vec::new(),
+                    });
+                    dirs.len() - 1
+                }
+            };
+            if idx == names.len() - 1 {
+                dirs[pos].mode = mode;
+                dirs[pos].owner = owner;
+            }
+            dirs = &mut dirs[pos].children;
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dirs.is_empty()
+    }
+
+    /// The number of directories in the skeleton, including those below others.
+    pub fn dir_count(&self) -> usize {
+        fn count(dirs: &[SkeletonDir]) -> usize {
+            dirs.iter().map(|dir| 1 + count(&dir.children)).sum()
+        }
+        count(&self.dirs)
+    }
+}
+
+/// Returns the subdirectories of `path` with their permissions and owners, sorted by name. Symlinks are not followed.
+fn read_dirs(path: &Path) -> Result<Vec<SkeletonDir>> {
+    let mut dirs = Vec::new();
+    for entry in fs::read_dir(path).with_context(|| format!("Unable to read {}", path.display()))? {
+        let entry = entry.with_context(|| format!("Unable to read {}", path.display()))?;
+        let metadata = entry
+            .metadata()
+            .with_context(|| format!("Unable to access {}", entry.path().display()))?;
+        if !metadata.is_dir() {
+            continue;
+        }
+        let name = entry
+            .file_name()
+            .into_string()
+            .map_err(|name| anyhow::anyhow!("The name {:?} is not valid UTF-8", name))?;
+        if name.len() > EXT4_NAME_MAX_LEN {
+            bail!("The name '{}' is longer than {} bytes", name, EXT4_NAME_MAX_LEN);
+        }
+        dirs.push(SkeletonDir {
+            name,
+            mode: u16::try_from(metadata.mode() & u32::from(MAX_MODE)).expect("the mode is masked to 12 bits"),
+            owner: Some(Owner { uid: metadata.uid(), gid: metadata.gid() }),
+            children: read_dirs(&entry.path())?,
+        });
+    }
+    dirs.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(dirs)
+}
+
+fn parse_mode(value: &str) -> Result<u16> {
+    match u16::from_str_radix(value, 8) {
+        Ok(mode) if mode <= MAX_MODE => Ok(mode),
+        _ => bail!("'{}' is not an octal mode", value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spec_lines_add_parents() {
+        let mut skeleton = Skeleton::default();
+        skeleton.add_spec_line("/srv/www 750 33:33", true).unwrap();
+        skeleton.add_spec_line("/srv 700", true).unwrap();
+        skeleton.add_spec_line("/data/", true).unwrap();
+        skeleton.add_spec_line("/lost+found", true).unwrap();
+        assert_eq!(
+            skeleton.dirs,
+            [
+                SkeletonDir {
+                    name: "srv".to_string(),
+                    mode: 0o700,
+                    owner: None,
+                    children: vec![SkeletonDir {
+                        name: "www".to_string(),
+                        mode: 0o750,
+                        owner: Some(Owner { uid: 33, gid: 33 }),
+                        children: vec![],
+                    }],
+                },
+                SkeletonDir {
+                    name: "data".to_string(),
+                    mode: DEFAULT_MODE,
+                    owner: None,
+                    children: vec![],
+                },
+            ]
+        );
+        assert_eq!(skeleton.dir_count(), 3);
+
+        for line in ["srv", "/", "/a/../b", "/lost+found/x", "/a 888", "/a 755 0:0 extra", "/a 755 root"] {
+            assert!(skeleton.add_spec_line(line, true).is_err(), "{}", line);
+        }
+    }
+}
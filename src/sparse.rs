@@ -0,0 +1,475 @@
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Read, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use fs2::FileExt as LockExt;
+
+use crate::partition::BlockAccess;
+
+const SPARSE_MAGIC: u32 = 0xED26_FF3A;
+const MAJOR_VERSION: u16 = 1;
+const FILE_HEADER_LEN: usize = 28;
+const CHUNK_HEADER_LEN: usize = 12;
+
+const CHUNK_TYPE_RAW: u16 = 0xCAC1;
+const CHUNK_TYPE_FILL: u16 = 0xCAC2;
+const CHUNK_TYPE_DONT_CARE: u16 = 0xCAC3;
+const CHUNK_TYPE_CRC32: u16 = 0xCAC4;
+
+/// The maximum number of bytes in a RAW chunk that `SparseImage::sync` writes, which keeps a chunk's length well within
+/// its 32-bit length field
+const MAX_RAW_CHUNK_LEN: usize = 64 << 20;
+
+/// An Android sparse image ("simg"), as produced by `img2simg` and shipped in factory images, accessed as the raw image
+/// it expands to. The image is not expanded on disk: reads are served from its chunks, and written blocks are kept in
+/// memory until `sync` replaces the image with a new sparse image. DONT_CARE chunks are read as zeros and written as
+/// FILL chunks, because a filesystem converted from them may rely on them being zero, while flashing a DONT_CARE chunk
+/// leaves whatever the storage contained.
+///
+/// The view may start at a byte offset inside the expanded image, for images that contain more than the FAT
+/// filesystem, e.g. a partition table.
+pub struct SparseImage {
+    path: PathBuf,
+    file: File,
+    block_size: usize,
+    block_count: usize,
+    chunks: Vec<Chunk>,
+    /// the blocks written since the image was opened, by their index
+    modified: BTreeMap<usize, Box<[u8]>>,
+    /// the byte offset of the view in the expanded image
+    offset: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Chunk {
+    first_block: usize,
+    block_count: usize,
+    content: ChunkContent,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ChunkContent {
+    /// the blocks are stored in the image file starting at the given byte offset
+    Raw(u64),
+    /// every 4 bytes of the blocks are the given value, in little-endian byte order
+    Fill(u32),
+    DontCare,
+}
+
+impl SparseImage {
+    /// Returns whether the file at `path` starts with the magic number of a sparse image.
+    pub fn detect<P: AsRef<Path>>(path: P) -> Result<bool> {
+        let mut magic = [0; 4];
+        let mut file = File::open(path)?;
+        match file.read_exact(&mut magic) {
+            Ok(()) => Ok(u32::from_le_bytes(magic) == SPARSE_MAGIC),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Opens the sparse image at `path` as the raw image it expands to, starting at the byte `offset`.
+    pub fn open<P: AsRef<Path>>(path: P, offset: usize) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().read(true).write(true).open(&path)?;
+        file.try_lock_exclusive()
+            .context("The image cannot be locked. Is another process using it?")?;
+        let (block_size, block_count, chunks) = parse(&file).context("Invalid sparse image")?;
+        if offset > block_size * block_count {
+            bail!(
+                "The offset {} is beyond the end of the sparse image, which expands to {} bytes",
+                offset,
+                block_size * block_count
+            );
+        }
+        Ok(Self {
+            path,
+            file,
+            block_size,
+            block_count,
+            chunks,
+            modified: BTreeMap::new(),
+            offset,
+        })
+    }
+
+    /// Fills `buf` with the bytes of the expanded image starting at `start`.
+    fn read_expanded(&self, start: usize, buf: &mut [u8]) -> Result<()> {
+        let end = start + buf.len();
+        let first_chunk_idx = self
+            .chunks
+            .partition_point(|chunk| (chunk.first_block + chunk.block_count) * self.block_size <= start);
+        for chunk in &self.chunks[first_chunk_idx..] {
+            let chunk_start = chunk.first_block * self.block_size;
+            if chunk_start >= end {
+                break;
+            }
+            let chunk_end = chunk_start + chunk.block_count * self.block_size;
+            let overlap_start = chunk_start.max(start);
+            let overlap_end = chunk_end.min(end);
+            let target = &mut buf[overlap_start - start..overlap_end - start];
+            match chunk.content {
+                ChunkContent::Raw(data_offset) => {
+                    let file_offset = data_offset + u64::try_from(overlap_start - chunk_start)?;
+                    self.file.read_exact_at(target, file_offset)?;
+                }
+                ChunkContent::Fill(value) => {
+                    let pattern = value.to_le_bytes();
+                    for (idx, byte) in target.iter_mut().enumerate() {
+                        *byte = pattern[(overlap_start + idx) % pattern.len()];
+                    }
+                }
+                ChunkContent::DontCare => target.fill(0),
+            }
+        }
+
+        let first_block = start / self.block_size;
+        let last_block = (end - 1) / self.block_size;
+        for (&block_idx, block) in self.modified.range(first_block..=last_block) {
+            let block_start = block_idx * self.block_size;
+            let overlap_start = block_start.max(start);
+            let overlap_end = (block_start + self.block_size).min(end);
+            buf[overlap_start - start..overlap_end - start]
+                .copy_from_slice(&block[overlap_start - block_start..overlap_end - block_start]);
+        }
+        Ok(())
+    }
+
+    /// Writes the expanded image as a sparse image to `file`, storing every block in which all 4-byte words are equal
+    /// as a FILL chunk and the rest as RAW chunks. Returns the new chunks.
+    fn encode(&self, file: &mut File) -> Result<Vec<Chunk>> {
+        let mut chunks: Vec<Chunk> = Vec::new();
+        let mut block = vec![0; self.block_size];
+        let mut data_offset = u64::try_from(FILE_HEADER_LEN)?;
+        for block_idx in 0..self.block_count {
+            self.read_expanded(block_idx * self.block_size, &mut block)?;
+            let first_word = u32::from_le_bytes(block[..4].try_into().unwrap());
+            let is_fill = block.chunks_exact(4).all(|word| word == first_word.to_le_bytes());
+            let last_chunk = chunks.last_mut();
+            match last_chunk {
+                Some(Chunk {
+                    content: ChunkContent::Fill(value), block_count, ..
+                }) if is_fill && *value == first_word => {
+                    *block_count += 1;
+                }
+                Some(Chunk { content: ChunkContent::Raw(_), block_count, .. })
+                    if !is_fill && (*block_count + 1) * self.block_size <= MAX_RAW_CHUNK_LEN =>
+                {
+                    *block_count += 1;
+                    data_offset += u64::try_from(self.block_size)?;
+                }
+                _ => {
+                    data_offset += u64::try_from(CHUNK_HEADER_LEN)?;
+                    let content = if is_fill {
+                        data_offset += 4;
+                        ChunkContent::Fill(first_word)
+                    } else {
+                        let content = ChunkContent::Raw(data_offset);
+                        data_offset += u64::try_from(self.block_size)?;
+                        content
+                    };
+                    chunks.push(Chunk { first_block: block_idx, block_count: 1, content });
+                }
+            }
+        }
+
+        let mut writer = BufWriter::new(file);
+        writer.write_all(&SPARSE_MAGIC.to_le_bytes())?;
+        writer.write_all(&MAJOR_VERSION.to_le_bytes())?;
+        writer.write_all(&0u16.to_le_bytes())?;
+        writer.write_all(&u16::try_from(FILE_HEADER_LEN)?.to_le_bytes())?;
+        writer.write_all(&u16::try_from(CHUNK_HEADER_LEN)?.to_le_bytes())?;
+        writer.write_all(&u32::try_from(self.block_size)?.to_le_bytes())?;
+        writer.write_all(&u32::try_from(self.block_count)?.to_le_bytes())?;
+        writer.write_all(&u32::try_from(chunks.len())?.to_le_bytes())?;
+        // the checksum is optional, and 0 marks it as absent
+        writer.write_all(&0u32.to_le_bytes())?;
+        for chunk in &chunks {
+            let (chunk_type, data_len) = match chunk.content {
+                ChunkContent::Raw(_) => (CHUNK_TYPE_RAW, chunk.block_count * self.block_size),
+                ChunkContent::Fill(_) => (CHUNK_TYPE_FILL, 4),
+                ChunkContent::DontCare => unreachable!("DONT_CARE chunks are never written"),
+            };
+            writer.write_all(&chunk_type.to_le_bytes())?;
+            writer.write_all(&0u16.to_le_bytes())?;
+            writer.write_all(&u32::try_from(chunk.block_count)?.to_le_bytes())?;
+            writer.write_all(&u32::try_from(CHUNK_HEADER_LEN + data_len)?.to_le_bytes())?;
+            match chunk.content {
+                ChunkContent::Raw(_) => {
+                    for block_idx in chunk.first_block..chunk.first_block + chunk.block_count {
+                        self.read_expanded(block_idx * self.block_size, &mut block)?;
+                        writer.write_all(&block)?;
+                    }
+                }
+                ChunkContent::Fill(value) => writer.write_all(&value.to_le_bytes())?,
+                ChunkContent::DontCare => unreachable!("DONT_CARE chunks are never written"),
+            }
+        }
+        writer.flush()?;
+        Ok(chunks)
+    }
+
+    /// The path of the file that `sync` writes the new image to before it replaces the image
+    fn replacement_path(&self) -> Result<PathBuf> {
+        let file_name = self.path.file_name().context("The image path has no file name")?;
+        Ok(self
+            .path
+            .with_file_name(format!(".{}.ofs-convert", file_name.to_string_lossy())))
+    }
+}
+
+impl BlockAccess for SparseImage {
+    fn len(&self) -> usize {
+        self.block_size * self.block_count - self.offset
+    }
+
+    fn read_at(&mut self, offset: usize, buf: &mut [u8]) -> Result<()> {
+        if offset + buf.len() > self.len() {
+            bail!("Read beyond the end of the sparse image");
+        }
+        if buf.is_empty() {
+            return Ok(());
+        }
+        self.read_expanded(self.offset + offset, buf)
+    }
+
+    fn write_at(&mut self, offset: usize, buf: &[u8]) -> Result<()> {
+        if offset + buf.len() > self.len() {
+            bail!("Write beyond the end of the sparse image");
+        }
+        let start = self.offset + offset;
+        let end = start + buf.len();
+        let mut block_start = start - start % self.block_size;
+        while block_start < end {
+            let overlap_start = block_start.max(start);
+            let overlap_end = (block_start + self.block_size).min(end);
+            let mut block = vec![0; self.block_size].into_boxed_slice();
+            if overlap_end - overlap_start < self.block_size {
+                self.read_expanded(block_start, &mut block)?;
+            }
+            block[overlap_start - block_start..overlap_end - block_start]
+                .copy_from_slice(&buf[overlap_start - start..overlap_end - start]);
+            self.modified.insert(block_start / self.block_size, block);
+            block_start += self.block_size;
+        }
+        Ok(())
+    }
+
+    /// Replaces the image with a sparse image of its current content. The new image is written next to the image and
+    /// renamed over it, so the image is intact if the conversion is interrupted while writing it.
+    fn sync(&mut self) -> Result<()> {
+        if self.modified.is_empty() {
+            return Ok(());
+        }
+        let replacement_path = self.replacement_path()?;
+        let mut replacement = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&replacement_path)
+            .with_context(|| format!("Unable to create '{}'", replacement_path.display()))?;
+        let chunks = match self.encode(&mut replacement).and_then(|chunks| {
+            replacement.set_permissions(self.file.metadata()?.permissions())?;
+            replacement.sync_all()?;
+            replacement.try_lock_exclusive()?;
+            fs::rename(&replacement_path, &self.path)?;
+            Ok(chunks)
+        }) {
+            Ok(chunks) => chunks,
+            Err(e) => {
+                let _ = fs::remove_file(&replacement_path);
+                return Err(e.context("Unable to write the sparse image"));
+            }
+        };
+        if let Some(dir) = self.path.parent() {
+            File::open(if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                dir
+            })?
+            .sync_all()?;
+        }
+        self.file = replacement;
+        self.chunks = chunks;
+        self.modified.clear();
+        Ok(())
+    }
+}
+
+/// Parses the headers of the sparse image in `file`. Returns its block size, the number of blocks it expands to and its
+/// chunks, except for CRC32 chunks, which are not verified.
+fn parse(file: &File) -> Result<(usize, usize, Vec<Chunk>)> {
+    let mut header = [0; FILE_HEADER_LEN];
+    file.read_exact_at(&mut header, 0)?;
+    let u16_at = |bytes: &[u8], idx: usize| u16::from_le_bytes(bytes[idx..idx + 2].try_into().unwrap());
+    let u32_at = |bytes: &[u8], idx: usize| u32::from_le_bytes(bytes[idx..idx + 4].try_into().unwrap());
+    if u32_at(&header, 0) != SPARSE_MAGIC {
+        bail!("Not a sparse image");
+    }
+    if u16_at(&header, 4) != MAJOR_VERSION {
+        bail!("Unsupported sparse image version {}", u16_at(&header, 4));
+    }
+    let file_header_len = usize::from(u16_at(&header, 8));
+    let chunk_header_len = usize::from(u16_at(&header, 10));
+    let block_size = usize::try_from(u32_at(&header, 12))?;
+    let block_count = usize::try_from(u32_at(&header, 16))?;
+    let chunk_count = u32_at(&header, 20);
+    if file_header_len < FILE_HEADER_LEN || chunk_header_len < CHUNK_HEADER_LEN {
+        bail!("The headers of the sparse image are too short");
+    }
+    if block_size == 0 || block_size % 4 != 0 {
+        bail!("The block size {} of the sparse image is not a multiple of 4", block_size);
+    }
+
+    let mut chunks = Vec::new();
+    let mut file_offset = u64::try_from(file_header_len)?;
+    let mut next_block = 0;
+    for chunk_idx in 0..chunk_count {
+        let mut chunk_header = [0; CHUNK_HEADER_LEN];
+        file.read_exact_at(&mut chunk_header, file_offset)
+            .with_context(|| format!("Unable to read chunk {}", chunk_idx))?;
+        let chunk_type = u16_at(&chunk_header, 0);
+        let chunk_block_count = usize::try_from(u32_at(&chunk_header, 4))?;
+        let chunk_len = u64::from(u32_at(&chunk_header, 8));
+        let data_offset = file_offset + u64::try_from(chunk_header_len)?;
+        let data_len = chunk_len
+            .checked_sub(u64::try_from(chunk_header_len)?)
+            .with_context(|| format!("Chunk {} is shorter than its header", chunk_idx))?;
+        let content = match chunk_type {
+            CHUNK_TYPE_RAW => {
+                if data_len != u64::try_from(chunk_block_count * block_size)? {
+                    bail!("The length of RAW chunk {} does not match its number of blocks", chunk_idx);
+                }
+                ChunkContent::Raw(data_offset)
+            }
+            CHUNK_TYPE_FILL => {
+                let mut value = [0; 4];
+                file.read_exact_at(&mut value, data_offset)?;
+                ChunkContent::Fill(u32::from_le_bytes(value))
+            }
+            CHUNK_TYPE_DONT_CARE => ChunkContent::DontCare,
+            CHUNK_TYPE_CRC32 => {
+                file_offset += chunk_len;
+                continue;
+            }
+            _ => bail!("Chunk {} has the unknown type {:#x}", chunk_idx, chunk_type),
+        };
+        if chunk_block_count > 0 {
+            chunks.push(Chunk {
+                first_block: next_block,
+                block_count: chunk_block_count,
+                content,
+            });
+        }
+        next_block += chunk_block_count;
+        file_offset += chunk_len;
+    }
+    if next_block != block_count {
+        bail!(
+            "The chunks of the sparse image expand to {} blocks instead of {}",
+            next_block,
+            block_count
+        );
+    }
+    Ok((block_size, block_count, chunks))
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::distributions::Standard;
+    use rand::Rng;
+    use tempfile::NamedTempFile;
+
+    use super::*;
+    use crate::partition::BufferedPartition;
+
+    const BLOCK_SIZE: usize = 4096;
+
+    /// Builds a sparse image with a RAW, a FILL, a DONT_CARE and a CRC32 chunk, and the raw image it expands to.
+    fn sparse_image() -> (NamedTempFile, Vec<u8>) {
+        let raw_data: Vec<u8> = rand::thread_rng().sample_iter(&Standard).take(2 * BLOCK_SIZE).collect();
+        let mut image = Vec::new();
+        for (value, len) in
+            [(SPARSE_MAGIC, 4), (1, 2), (0, 2), (28, 2), (12, 2), (BLOCK_SIZE as u32, 4), (6, 4), (4, 4), (0, 4)]
+        {
+            image.extend_from_slice(&u32::to_le_bytes(value)[..len]);
+        }
+        let mut push_chunk = |chunk_type: u16, block_count: u32, data: &[u8]| {
+            image.extend_from_slice(&chunk_type.to_le_bytes());
+            image.extend_from_slice(&0u16.to_le_bytes());
+            image.extend_from_slice(&block_count.to_le_bytes());
+            image.extend_from_slice(&(12 + data.len() as u32).to_le_bytes());
+            image.extend_from_slice(data);
+        };
+        push_chunk(CHUNK_TYPE_RAW, 2, &raw_data);
+        push_chunk(CHUNK_TYPE_FILL, 3, &0x1234_5678u32.to_le_bytes());
+        push_chunk(CHUNK_TYPE_CRC32, 0, &[0; 4]);
+        push_chunk(CHUNK_TYPE_DONT_CARE, 1, &[]);
+
+        let mut expanded = raw_data;
+        expanded.extend(0x1234_5678u32.to_le_bytes().iter().cycle().take(3 * BLOCK_SIZE));
+        expanded.extend([0; BLOCK_SIZE]);
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(&image).unwrap();
+        (file, expanded)
+    }
+
+    #[test]
+    fn reads_and_rewrites_sparse_image() {
+        let (file, mut expected) = sparse_image();
+        assert!(SparseImage::detect(file.path()).unwrap());
+        const OFFSET: usize = 512;
+
+        let mut partition = BufferedPartition::load(SparseImage::open(file.path(), OFFSET).unwrap()).unwrap();
+        assert_eq!(partition.len(), 6 * BLOCK_SIZE - OFFSET);
+        // SAFETY: Safe because `partition` has `partition.len()` bytes and we don't access it otherwise during this
+        // borrow.
+        let content = unsafe { std::slice::from_raw_parts_mut(partition.as_mut_ptr(), partition.len()) };
+        assert_eq!(content, &expected[OFFSET..]);
+        // one byte in the FILL chunk and one in the DONT_CARE chunk
+        content[3 * BLOCK_SIZE] ^= 1;
+        content[6 * BLOCK_SIZE - OFFSET - 1] = 7;
+        expected[3 * BLOCK_SIZE + OFFSET] ^= 1;
+        expected[6 * BLOCK_SIZE - 1] = 7;
+        partition.write_back().unwrap();
+        drop(partition);
+
+        let mut image = SparseImage::open(file.path(), 0).unwrap();
+        let kinds = image
+            .chunks
+            .iter()
+            .map(|chunk| (chunk.block_count, chunk.content))
+            .collect::<Vec<_>>();
+        assert!(matches!(
+            kinds.as_slice(),
+            [
+                (2, ChunkContent::Raw(_)),
+                (1, ChunkContent::Fill(0x1234_5678)),
+                (1, ChunkContent::Raw(_)),
+                (1, ChunkContent::Fill(0x1234_5678)),
+                (1, ChunkContent::Raw(_)),
+            ]
+        ));
+        let mut actual = vec![0; 6 * BLOCK_SIZE];
+        image.read_at(0, &mut actual).unwrap();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn rejects_invalid_sparse_image() {
+        let (file, _) = sparse_image();
+        assert!(SparseImage::open(file.path(), 6 * BLOCK_SIZE + 1).is_err());
+        let mut image = fs::read(file.path()).unwrap();
+        // claim one more block than the chunks expand to
+        image[16] += 1;
+        fs::write(file.path(), &image).unwrap();
+        assert!(SparseImage::open(file.path(), 0).is_err());
+
+        let raw = NamedTempFile::new().unwrap();
+        assert!(!SparseImage::detect(raw.path()).unwrap());
+    }
+}
@@ -0,0 +1,255 @@
+use std::collections::{BTreeSet, HashMap};
+use std::convert::TryFrom;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::diff_meta::{read_data_extents, read_inode, read_superblock};
+use crate::ext4::{InodeInner, InodeNo};
+use crate::fat::{FatFile, FatFs};
+use crate::serialization::check_convertible;
+use crate::util::{fnv1a, FromUsize, FNV_OFFSET_BASIS};
+
+/// The number of zero bytes hashed at once for the holes and uninitialized extents of a file
+const ZERO_CHUNK: [u8; 4096] = [0; 4096];
+
+/// A regular file whose content `--spot-check` hashes before the conversion, by following its cluster chain, and after
+/// it, by following the extents of its inode. Unlike `--verify-archival`, which checks the serialized directory tree,
+/// this checks what ext4 sees, but only for a few files.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SpotCheckSample {
+    /// the path of the file, starting with '/' at the root of the FAT filesystem
+    pub path: String,
+    pub size: u64,
+    /// the `fnv1a` hash of the content
+    pub fat_hash: u64,
+}
+
+/// The outcome of `--spot-check`
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct SpotCheckReport {
+    /// the sampled files whose content is the same after the conversion
+    pub matched_count: usize,
+    /// the sampled files that were not converted to regular files with the same path, e.g. because they were excluded
+    /// or their names were truncated
+    pub unchecked_files: Vec<String>,
+}
+
+impl SpotCheckSample {
+    /// Chooses `count` regular files of `fat_fs` at random, or all of them if there are fewer, and hashes their
+    /// content. Only files that the conversion copies unchanged are candidates, i.e. not empty files, files that cannot
+    /// be converted and files whose cluster chains are shorter than their size.
+    pub fn choose(fat_fs: &FatFs, count: usize) -> Result<Vec<Self>> {
+        let mut candidate_count = 0;
+        visit_candidates(fat_fs, fat_fs.root_dir().collect(), "", &mut |_, _| candidate_count += 1);
+        let chosen = choose_indices(candidate_count, count);
+
+        let mut samples = Vec::with_capacity(chosen.len());
+        let mut result = Ok(());
+        let mut idx = 0;
+        visit_candidates(fat_fs, fat_fs.root_dir().collect(), "", &mut |file, path| {
+            if chosen.contains(&idx) && result.is_ok() {
+                match fat_hash(file, fat_fs) {
+                    Ok(fat_hash) => samples.push(Self {
+                        path: path.to_string(),
+                        size: u64::from(file.dentry.file_size),
+                        fat_hash,
+                    }),
+                    Err(error) => result = Err(error.context(format!("Unable to read {}", path))),
+                }
+            }
+            idx += 1;
+        });
+        result.map(|_| samples)
+    }
+}
+
+impl SpotCheckReport {
+    /// Hashes the content of every file in `samples` by following the extents of its inode in the ext4 filesystem in
+    /// `partition`, finding the inode in `inodes` by the file's path. Fails if a content differs from the content
+    /// before the conversion, naming the first damaged file.
+    pub fn read_back(partition: &[u8], samples: &[SpotCheckSample], inodes: &HashMap<String, InodeNo>) -> Result<Self> {
+        let block_size = u64::from(read_superblock(partition)?.block_size());
+        let mut report = Self::default();
+        let mut damaged_files = Vec::new();
+        for sample in samples {
+            let inode = match inodes.get(&sample.path) {
+                Some(&inode_no) => read_inode(partition, inode_no)
+                    .with_context(|| format!("Unable to read the inode of {}", sample.path))?,
+                None => {
+                    report.unchecked_files.push(sample.path.clone());
+                    continue;
+                }
+            };
+            if !inode.is_regular_file() {
+                report.unchecked_files.push(sample.path.clone());
+                continue;
+            }
+            let ext4_hash =
+                ext4_hash(partition, &inode, block_size).with_context(|| format!("Unable to read {}", sample.path))?;
+            if inode.size() == sample.size && ext4_hash == sample.fat_hash {
+                report.matched_count += 1;
+            } else {
+                damaged_files.push(&sample.path);
+            }
+        }
+
+        if let Some(first_damaged) = damaged_files.first() {
+            bail!(
+                "{} of {} sampled files differ from their content before the conversion, e.g. {}",
+                damaged_files.len(),
+                samples.len(),
+                first_damaged
+            );
+        }
+        Ok(report)
+    }
+}
+
+/// Calls `visit` with every candidate of `SpotCheckSample::choose` and its path in the same order every time.
+fn visit_candidates(fat_fs: &FatFs, children: Vec<FatFile>, dir_path: &str, visit: &mut dyn FnMut(&FatFile, &str)) {
+    let cluster_size = u64::from(fat_fs.cluster_size());
+    for file in children {
+        if file.dentry.attribute_problem().is_some() || file.dentry.is_volume_label() {
+            continue;
+        }
+        let path = format!("{}/{}", dir_path, file.name);
+        if file.dentry.is_dir() {
+            if let Some(grandchildren) = fat_fs.read_dir(&file) {
+                visit_candidates(fat_fs, grandchildren.collect(), &path, visit);
+            }
+            continue;
+        }
+        let cluster_count: u64 = file.data_ranges.iter().map(|range| u64::fromx(range.clone().count())).sum();
+        if file.dentry.file_size > 0
+            && cluster_count * cluster_size >= u64::from(file.dentry.file_size)
+            && check_convertible(&file, fat_fs).is_ok()
+        {
+            visit(&file, &path);
+        }
+    }
+}
+
+/// Returns `count` distinct random numbers less than `n`, or all of them if `count >= n`, using Floyd's algorithm.
+fn choose_indices(n: usize, count: usize) -> BTreeSet<usize> {
+    let mut chosen = BTreeSet::new();
+    for upper in n.saturating_sub(count)..n {
+        let idx = random_below(upper + 1);
+        if !chosen.insert(idx) {
+            chosen.insert(upper);
+        }
+    }
+    chosen
+}
+
+/// Returns a random number less than `n`, taken from the random part of a version 4 UUID.
+fn random_below(n: usize) -> usize {
+    let random = Uuid::new_v4().as_u128() % n as u128;
+    usize::try_from(random).expect("`random` is less than `n`")
+}
+
+fn fat_hash(file: &FatFile, fat_fs: &FatFs) -> Result<u64> {
+    fat_fs
+        .file_content(file)
+        .try_fold(FNV_OFFSET_BASIS, |hash, chunk| Ok(fnv1a(hash, chunk?)))
+}
+
+fn ext4_hash(partition: &[u8], inode: &InodeInner, block_size: u64) -> Result<u64> {
+    let size = inode.size();
+    let mut hash = FNV_OFFSET_BASIS;
+    let mut hashed_len = 0;
+    for extent in read_data_extents(partition, inode)? {
+        let start = extent.logical_start * block_size;
+        if start >= size {
+            break;
+        }
+        if start < hashed_len {
+            bail!("The extents overlap");
+        }
+        // the file has a hole before the extent
+        hash = hash_zeros(hash, start - hashed_len);
+        let len = (extent.len * block_size).min(size - start);
+        if extent.initialized {
+            let data = usize::try_from(extent.physical_start * block_size)
+                .ok()
+                .and_then(|offset| partition.get(offset..offset.checked_add(usize::try_from(len).ok()?)?))
+                .with_context(|| format!("Block {} lies outside the partition", extent.physical_start))?;
+            hash = fnv1a(hash, data);
+        } else {
+            hash = hash_zeros(hash, len);
+        }
+        hashed_len = start + len;
+    }
+    Ok(hash_zeros(hash, size - hashed_len))
+}
+
+fn hash_zeros(mut hash: u64, mut len: u64) -> u64 {
+    while len > 0 {
+        let chunk_len = len.min(u64::fromx(ZERO_CHUNK.len()));
+        hash = fnv1a(hash, &ZERO_CHUNK[..usize::try_from(chunk_len).unwrap()]);
+        len -= chunk_len;
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conversion::convert_slice;
+    use crate::ext4::LOST_FOUND_INODE_NO;
+    use crate::fat::{FatImageBuilder, TestFile};
+    use crate::options::ConversionOptions;
+
+    #[test]
+    fn sampled_files_match_after_conversion() {
+        let files = [
+            TestFile::RegularFile { name: "large".to_string(), size: 300_000 },
+            TestFile::RegularFile { name: "empty".to_string(), size: 0 },
+            TestFile::Directory {
+                name: "dir".to_string(),
+                children: vec![TestFile::RegularFile { name: "small".to_string(), size: 100 }],
+            },
+            TestFile::RegularFile { name: "medium".to_string(), size: 5000 },
+        ];
+        let mut image = FatImageBuilder::new(32 * 1024 * 1024, 1024).build(&files);
+        let fat_fs = FatFs::from_slice(image.as_mut_slice()).unwrap();
+        let samples = SpotCheckSample::choose(&fat_fs, 10).unwrap();
+        let paths: Vec<_> = samples.iter().map(|sample| sample.path.as_str()).collect();
+        assert_eq!(paths, ["/large", "/dir/small", "/medium"]);
+        assert_eq!(SpotCheckSample::choose(&fat_fs, 2).unwrap().len(), 2);
+        assert!(SpotCheckSample::choose(&fat_fs, 0).unwrap().is_empty());
+
+        let options = ConversionOptions {
+            spot_check: Some(10),
+            ..ConversionOptions::default()
+        };
+        let stats = convert_slice(image.as_mut_slice(), &options).unwrap();
+        let report = stats.spot_check.unwrap();
+        assert_eq!(report, SpotCheckReport { matched_count: 3, unchecked_files: vec![] });
+
+        // the first file in the FAT directory gets the first inode after lost+found
+        let partition = image.as_mut_slice();
+        let inodes = HashMap::from([("/large".to_string(), LOST_FOUND_INODE_NO + 1)]);
+        let report = SpotCheckReport::read_back(partition, &samples, &inodes).unwrap();
+        assert_eq!(report.matched_count, 1);
+        assert_eq!(report.unchecked_files, ["/dir/small", "/medium"]);
+
+        let mut damaged = samples.clone();
+        damaged[0].fat_hash ^= 1;
+        let error = SpotCheckReport::read_back(partition, &damaged, &inodes).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "1 of 3 sampled files differ from their content before the conversion, e.g. /large"
+        );
+    }
+
+    #[test]
+    fn chooses_distinct_indices() {
+        for (n, count) in [(10, 3), (10, 10), (3, 10), (0, 5), (1000, 999)] {
+            let chosen = choose_indices(n, count);
+            assert_eq!(chosen.len(), count.min(n));
+            assert!(chosen.iter().all(|&idx| idx < n));
+        }
+    }
+}
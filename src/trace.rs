@@ -0,0 +1,314 @@
+use std::cell::RefCell;
+use std::convert::{TryFrom, TryInto};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+
+use anyhow::{bail, Context, Result};
+
+use crate::allocator::AllocationPurpose;
+use crate::ext4::{BlockIdx, InodeNo};
+use crate::fat::ClusterIdx;
+use crate::util::FromUsize;
+
+/// The start of every trace file, followed by the format version
+const MAGIC: &[u8; 8] = b"OFSTRACE";
+const VERSION: u8 = 1;
+
+const ALLOCATION_TAG: u8 = 1;
+const EXTENT_TAG: u8 = 2;
+const DENTRY_TAG: u8 = 3;
+
+/// A step of the conversion that modifies the partition, as recorded by `--trace`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TraceEvent {
+    /// `Allocator` handed out the clusters `start..end`
+    Allocation {
+        purpose: AllocationPurpose,
+        start: ClusterIdx,
+        end: ClusterIdx,
+    },
+    /// `Ext4Fs` added the `len` blocks starting at `physical_start` to the inode `inode_no`
+    Extent {
+        inode_no: InodeNo,
+        logical_start: u32,
+        physical_start: BlockIdx,
+        len: u16,
+    },
+    /// `DentryWriter` wrote a dentry for the inode `inode_no` into the directory `directory_inode_no`, at `offset`
+    /// bytes into the cluster `cluster`
+    Dentry {
+        directory_inode_no: InodeNo,
+        inode_no: InodeNo,
+        cluster: ClusterIdx,
+        offset: u32,
+    },
+}
+
+/// Writes `TraceEvent`s to a file in a compact binary format: after `MAGIC` and `VERSION`, every event is a tag byte
+/// followed by its fields as little-endian integers. Since the trace is meant for failed conversions, an error while
+/// writing it does not stop the conversion, but is returned by `finish`.
+pub struct Trace {
+    writer: RefCell<Box<dyn Write>>,
+    /// the first error while writing, after which nothing more is written
+    error: RefCell<Option<io::Error>>,
+}
+
+impl fmt::Debug for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Trace").finish_non_exhaustive()
+    }
+}
+
+impl Trace {
+    pub fn create(path: &str) -> Result<Self> {
+        let file = File::create(path).with_context(|| format!("Unable to create {}", path))?;
+        Self::new(BufWriter::new(file)).with_context(|| format!("Unable to write to {}", path))
+    }
+
+    pub fn new<W: Write + 'static>(mut writer: W) -> io::Result<Self> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[VERSION])?;
+        Ok(Self {
+            writer: RefCell::new(Box::new(writer)),
+            error: RefCell::new(None),
+        })
+    }
+
+    pub fn record(&self, event: TraceEvent) {
+        if self.error.borrow().is_some() {
+            return;
+        }
+        let mut bytes = Vec::with_capacity(32);
+        match event {
+            TraceEvent::Allocation { purpose, start, end } => {
+                bytes.push(ALLOCATION_TAG);
+                bytes.push(purpose_tag(purpose));
+                bytes.extend_from_slice(&start.to_le_bytes());
+                bytes.extend_from_slice(&end.to_le_bytes());
+            }
+            TraceEvent::Extent { inode_no, logical_start, physical_start, len } => {
+                bytes.push(EXTENT_TAG);
+                bytes.extend_from_slice(&inode_no.to_le_bytes());
+                bytes.extend_from_slice(&logical_start.to_le_bytes());
+                bytes.extend_from_slice(&u64::fromx(physical_start).to_le_bytes());
+                bytes.extend_from_slice(&len.to_le_bytes());
+            }
+            TraceEvent::Dentry { directory_inode_no, inode_no, cluster, offset } => {
+                bytes.push(DENTRY_TAG);
+                bytes.extend_from_slice(&directory_inode_no.to_le_bytes());
+                bytes.extend_from_slice(&inode_no.to_le_bytes());
+                bytes.extend_from_slice(&cluster.to_le_bytes());
+                bytes.extend_from_slice(&offset.to_le_bytes());
+            }
+        }
+        if let Err(error) = self.writer.borrow_mut().write_all(&bytes) {
+            *self.error.borrow_mut() = Some(error);
+        }
+    }
+
+    /// Flushes the trace. Returns the first error that occurred while writing it, if any.
+    pub fn finish(&self) -> io::Result<()> {
+        if let Some(error) = self.error.borrow_mut().take() {
+            return Err(error);
+        }
+        self.writer.borrow_mut().flush()
+    }
+
+    /// Reads the events of a trace written by `Trace`. If the trace ends in the middle of an event, e.g. because the
+    /// converter crashed, the complete events are returned along with an error.
+    pub fn read<R: Read>(mut reader: R) -> Result<(Vec<TraceEvent>, Option<anyhow::Error>)> {
+        let mut header = [0; MAGIC.len() + 1];
+        reader.read_exact(&mut header).context("The file is too short to be a trace")?;
+        if header[..MAGIC.len()] != MAGIC[..] {
+            bail!("The file is not a trace");
+        }
+        if header[MAGIC.len()] != VERSION {
+            bail!(
+                "The trace has version {}, but only version {} is supported",
+                header[MAGIC.len()],
+                VERSION
+            );
+        }
+
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).context("Unable to read the trace")?;
+        let mut events = Vec::new();
+        let mut cursor = TraceCursor { bytes: &bytes, position: 0 };
+        while !cursor.is_at_end() {
+            match cursor.read_event() {
+                Ok(event) => events.push(event),
+                Err(error) => {
+                    let error = error.context(format!("The trace ends after {} events", events.len()));
+                    return Ok((events, Some(error)));
+                }
+            }
+        }
+        Ok((events, None))
+    }
+}
+
+impl Drop for Trace {
+    fn drop(&mut self) {
+        // a failed conversion returns before `finish`, but its trace is the most important one
+        let _ = self.writer.get_mut().flush();
+    }
+}
+
+impl fmt::Display for TraceEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Self::Allocation { purpose, start, end } => {
+                write!(f, "allocate clusters {}..{} for {:?}", start, end, purpose)
+            }
+            Self::Extent { inode_no, logical_start, physical_start, len } => write!(
+                f,
+                "extent of inode {}: logical block {} at blocks {}..{}",
+                inode_no,
+                logical_start,
+                physical_start,
+                physical_start + BlockIdx::from(len)
+            ),
+            Self::Dentry { directory_inode_no, inode_no, cluster, offset } => write!(
+                f,
+                "dentry of inode {} in directory {} at cluster {} offset {}",
+                inode_no, directory_inode_no, cluster, offset
+            ),
+        }
+    }
+}
+
+/// Reads the fields of `TraceEvent`s from the bytes of a trace after its header.
+struct TraceCursor<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl TraceCursor<'_> {
+    fn is_at_end(&self) -> bool {
+        self.position == self.bytes.len()
+    }
+
+    fn read_event(&mut self) -> Result<TraceEvent> {
+        let event = match self.read_u8()? {
+            ALLOCATION_TAG => TraceEvent::Allocation {
+                purpose: purpose_from_tag(self.read_u8()?)?,
+                start: self.read_u32()?,
+                end: self.read_u32()?,
+            },
+            EXTENT_TAG => TraceEvent::Extent {
+                inode_no: self.read_u32()?,
+                logical_start: self.read_u32()?,
+                physical_start: BlockIdx::try_from(u64::from_le_bytes(self.read_array()?))
+                    .context("A physical block does not fit into the address space")?,
+                len: u16::from_le_bytes(self.read_array()?),
+            },
+            DENTRY_TAG => TraceEvent::Dentry {
+                directory_inode_no: self.read_u32()?,
+                inode_no: self.read_u32()?,
+                cluster: self.read_u32()?,
+                offset: self.read_u32()?,
+            },
+            tag => bail!("Unknown event tag {} at byte {}", tag, MAGIC.len() + 1 + self.position - 1),
+        };
+        Ok(event)
+    }
+
+    fn read_u8(&mut self) -> Result<u8> {
+        Ok(self.read_array::<1>()?[0])
+    }
+
+    fn read_u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.read_array()?))
+    }
+
+    fn read_array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let bytes = match self.bytes.get(self.position..self.position + N) {
+            Some(bytes) => bytes,
+            None => bail!("The last event is incomplete"),
+        };
+        self.position += N;
+        Ok(bytes.try_into().expect("the slice has N bytes"))
+    }
+}
+
+fn purpose_tag(purpose: AllocationPurpose) -> u8 {
+    match purpose {
+        AllocationPurpose::Archive => 0,
+        AllocationPurpose::Relocation => 1,
+        AllocationPurpose::Metadata => 2,
+        AllocationPurpose::Dentries => 3,
+    }
+}
+
+fn purpose_from_tag(tag: u8) -> Result<AllocationPurpose> {
+    Ok(match tag {
+        0 => AllocationPurpose::Archive,
+        1 => AllocationPurpose::Relocation,
+        2 => AllocationPurpose::Metadata,
+        3 => AllocationPurpose::Dentries,
+        _ => bail!("Unknown allocation purpose {}", tag),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+
+    /// A writer whose bytes can be inspected after it has been moved into a `Trace`
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn reads_back_events() {
+        let events = [
+            TraceEvent::Allocation {
+                purpose: AllocationPurpose::Dentries,
+                start: 100,
+                end: 101,
+            },
+            TraceEvent::Extent {
+                inode_no: 12,
+                logical_start: 3,
+                physical_start: 1 << 33,
+                len: 8,
+            },
+            TraceEvent::Dentry {
+                directory_inode_no: 2,
+                inode_no: 12,
+                cluster: 100,
+                offset: 24,
+            },
+        ];
+        let buffer = SharedBuffer::default();
+        let trace = Trace::new(buffer.clone()).unwrap();
+        for event in events {
+            trace.record(event);
+        }
+        trace.finish().unwrap();
+
+        let bytes = buffer.0.borrow().clone();
+        let (read_events, error) = Trace::read(bytes.as_slice()).unwrap();
+        assert_eq!(read_events, events);
+        assert!(error.is_none());
+
+        // a crash in the middle of writing an event leaves the complete events readable
+        let (read_events, error) = Trace::read(&bytes[..bytes.len() - 1]).unwrap();
+        assert_eq!(read_events, events[..2]);
+        assert!(format!("{:#}", error.unwrap()).starts_with("The trace ends after 2 events"));
+
+        assert!(Trace::read(&b"OFSTRACE\x02"[..]).is_err());
+    }
+}
@@ -0,0 +1,184 @@
+use std::mem::size_of;
+use std::slice;
+
+use anyhow::{bail, Context, Result};
+use uuid::Uuid;
+
+use crate::diff_meta::{read, read_superblock};
+use crate::ext4::{Ext4GroupDescriptor, SuperBlock, FIRST_BLOCK_PADDING, SUPERBLOCK_MAGIC, VOLUME_NAME_LEN};
+use crate::util::FromU32;
+
+/// The changes `tune` makes to an ext4 filesystem
+#[derive(Debug, Default)]
+pub struct TuneSettings {
+    pub label: Option<String>,
+    pub uuid: Option<Uuid>,
+    pub reserved_percent: Option<u8>,
+}
+
+/// What `tune` has written
+#[derive(Debug, Default, PartialEq)]
+pub struct TuneReport {
+    pub superblock_count: usize,
+    /// the group descriptors whose checksums were updated for the new UUID, counting every copy
+    pub descriptor_count: usize,
+}
+
+/// Applies `settings` to the ext4 filesystem in `partition` like `tune2fs -L`, `-U` and `-m` do. Every copy of the
+/// superblock is modified in place, so the backups keep their block group numbers. If the UUID changes on a filesystem
+/// with uninit_bg, every copy of the group descriptors gets new checksums; with metadata_csum, the checksum seed is
+/// kept instead, see `SuperBlock::set_uuid`. Nothing is written unless every superblock can be read.
+/// PANICS: Panics if the label is longer than 16 bytes, see `parse_label`.
+pub fn tune(partition: &mut [u8], settings: &TuneSettings) -> Result<TuneReport> {
+    let primary = read_superblock(partition)?;
+    if primary.needs_recovery() {
+        bail!("The journal of the filesystem needs to be recovered, run e2fsck first");
+    }
+    if primary.has_meta_bg() {
+        bail!("Filesystems with the meta_bg feature are not supported");
+    }
+    let update_descriptors = settings.uuid.is_some() && primary.has_gdt_csum() && !primary.has_metadata_csum();
+    if update_descriptors && usize::from(primary.s_desc_size) != size_of::<Ext4GroupDescriptor>() {
+        bail!(
+            "Only group descriptors of {} bytes are supported",
+            size_of::<Ext4GroupDescriptor>()
+        );
+    }
+
+    let block_size = usize::fromx(primary.block_size());
+    let mut superblocks = Vec::new();
+    for block_group_idx in primary.superblock_bgs() {
+        let start_block = primary.block_group_start_block(block_group_idx);
+        // the primary superblock is always at byte 1024, the backups at the start of their block group
+        let offset = if block_group_idx == 0 {
+            FIRST_BLOCK_PADDING
+        } else {
+            start_block * block_size
+        };
+        // SAFETY: Safe because `SuperBlock` only consists of integers.
+        let superblock: SuperBlock = unsafe { read(partition, offset)? };
+        if superblock.s_magic != SUPERBLOCK_MAGIC {
+            bail!("The superblock in block group {} is damaged", block_group_idx);
+        }
+        superblocks.push((offset, superblock, (start_block + 1) * block_size));
+    }
+
+    let mut report = TuneReport::default();
+    for (offset, mut superblock, gdt_offset) in superblocks {
+        if let Some(label) = &settings.label {
+            superblock.set_volume_name(label.as_bytes());
+        }
+        if let Some(uuid) = settings.uuid {
+            superblock.set_uuid(uuid);
+        }
+        if let Some(reserved_percent) = settings.reserved_percent {
+            superblock.set_reserved_percent(reserved_percent);
+        }
+        if superblock.has_metadata_csum() {
+            superblock.update_checksum();
+        }
+        // SAFETY: Safe because `SuperBlock` has no padding.
+        unsafe { write(partition, offset, &superblock)? };
+        report.superblock_count += 1;
+
+        if update_descriptors {
+            for block_group_idx in 0..primary.block_group_count() {
+                let descriptor_offset = gdt_offset + usize::fromx(block_group_idx) * size_of::<Ext4GroupDescriptor>();
+                // SAFETY: Safe because `Ext4GroupDescriptor` only consists of integers.
+                let mut descriptor: Ext4GroupDescriptor = unsafe { read(partition, descriptor_offset)? };
+                descriptor.update_checksum(&superblock.s_uuid, block_group_idx);
+                // SAFETY: Safe because `Ext4GroupDescriptor` has no padding.
+                unsafe { write(partition, descriptor_offset, &descriptor)? };
+                report.descriptor_count += 1;
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Writes `value` to `bytes` at `offset`.
+/// SAFETY: `T` must not contain padding.
+unsafe fn write<T: Copy>(bytes: &mut [u8], offset: usize, value: &T) -> Result<()> {
+    // SAFETY: Safe because the caller guarantees that every byte of `value` is initialized.
+    let value_bytes = unsafe { slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) };
+    bytes
+        .get_mut(offset..offset.saturating_add(size_of::<T>()))
+        .with_context(|| format!("The metadata at byte {} lies outside the partition", offset))?
+        .copy_from_slice(value_bytes);
+    Ok(())
+}
+
+pub fn parse_label(value: &str) -> Result<String> {
+    if value.len() > VOLUME_NAME_LEN {
+        bail!("Expected at most {} bytes", VOLUME_NAME_LEN);
+    }
+    Ok(value.to_string())
+}
+
+/// Parses a UUID, or 'random' for a random one.
+pub fn parse_uuid(value: &str) -> Result<Uuid> {
+    if value == "random" {
+        return Ok(Uuid::new_v4());
+    }
+    Uuid::parse_str(value).context("Expected a UUID or 'random'")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conversion::convert_slice;
+    use crate::diff_meta::{Difference, MetadataDump, Structure};
+    use crate::fat::{FatImageBuilder, TestFile};
+    use crate::options::ConversionOptions;
+
+    #[test]
+    fn tunes_every_superblock() {
+        let files = [TestFile::RegularFile { name: "file".to_string(), size: 5000 }];
+        let mut image = FatImageBuilder::new(64 * 1024 * 1024, 1024).build(&files);
+        convert_slice(image.as_mut_slice(), &ConversionOptions::default()).unwrap();
+        let partition = image.as_mut_slice();
+        let before = MetadataDump::new(partition).unwrap();
+
+        let uuid = parse_uuid("0123abcd-0000-4000-8000-000000000000").unwrap();
+        let settings = TuneSettings {
+            label: Some(parse_label("backup").unwrap()),
+            uuid: Some(uuid),
+            reserved_percent: Some(7),
+        };
+        let report = tune(partition, &settings).unwrap();
+        let primary = read_superblock(partition).unwrap();
+        let superblock_bgs = primary.superblock_bgs();
+        assert!(superblock_bgs.len() > 1);
+        assert_eq!(report.superblock_count, superblock_bgs.len());
+        assert_eq!(
+            report.descriptor_count,
+            superblock_bgs.len() * usize::fromx(primary.block_group_count())
+        );
+
+        let block_size = usize::fromx(primary.block_size());
+        for &block_group_idx in &superblock_bgs[1..] {
+            let start = primary.block_group_start_block(block_group_idx) * block_size;
+            // SAFETY: Safe because `SuperBlock` only consists of integers.
+            let backup: SuperBlock = unsafe { read(partition, start).unwrap() };
+            assert_eq!(backup.s_uuid, *uuid.as_bytes());
+            assert_eq!(&backup.s_volume_name[..7], b"backup\0");
+            assert_eq!(backup.s_r_blocks_count_lo, primary.s_r_blocks_count_lo);
+            // SAFETY: Safe because `Ext4GroupDescriptor` only consists of integers.
+            let descriptor: Ext4GroupDescriptor = unsafe { read(partition, start + block_size).unwrap() };
+            let mut expected = descriptor;
+            expected.update_checksum(uuid.as_bytes(), 0);
+            assert_eq!(descriptor.bg_checksum, expected.bg_checksum);
+        }
+
+        // the dump ignores the UUID and the checksums that depend on it
+        let fields: Vec<_> = before
+            .compare(&MetadataDump::new(partition).unwrap())
+            .into_iter()
+            .map(|difference| match difference {
+                Difference::Field { structure: Structure::SuperBlock, field, .. } => field,
+                difference => panic!("Unexpected difference {}", difference),
+            })
+            .collect();
+        assert_eq!(fields, ["s_r_blocks_count_lo", "s_volume_name"]);
+    }
+}
@@ -0,0 +1,304 @@
+use std::collections::BTreeMap;
+
+use anyhow::{Context, Result};
+
+use crate::conversion::check_ext4_signature;
+use crate::error::ErrorCategory;
+use crate::ext4::Owner;
+use crate::fat::{BootSector, FatFile, FatFs, ROOT_FAT_IDX};
+use crate::options::ConversionOptions;
+use crate::partition::ReadOnlyPartition;
+use crate::serialization::{
+    check_convertible, ConversionPolicy, FileOp, InvalidAttributesPolicy, LongNameChecker, LongNameTruncator,
+    ShortcutConverter, TruncatedFile,
+};
+
+/// The number of files of a kind that are listed unless all of them are requested
+const LISTED_FILE_COUNT: usize = 5;
+
+/// A way in which the conversion alters or loses a file
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Change {
+    /// the file makes the conversion fail, e.g. because it cannot be converted without `--collect-errors`
+    Fails,
+    /// the file cannot be converted and is skipped, see `ErrorPolicy::CollectErrors`
+    Skipped,
+    /// the file is left out by `--exclude-size-over`, `--exclude-older-than` or `InvalidAttributesPolicy::Skip`
+    Excluded,
+    /// the file has invalid attributes and becomes a regular file, see `InvalidAttributesPolicy::RegularFile`
+    ConvertedAsRegularFile,
+    /// the file is a shortcut that becomes a symlink, see `ShortcutConverter`
+    ConvertedToSymlink,
+    /// the name of the file is truncated, see `LongNameTruncator`
+    Renamed,
+    /// the file loses the data its cluster chain does not cover, see `TruncatedFile`
+    Shortened,
+    /// the file loses its hidden or system attribute, which ext4 has no equivalent for
+    AttributesDropped,
+}
+
+impl Change {
+    fn description(self) -> &'static str {
+        match self {
+            Self::Fails => "files would make the conversion fail with these options",
+            Self::Skipped => "files cannot be converted and would be skipped",
+            Self::Excluded => "files would be left out",
+            Self::ConvertedAsRegularFile => "files with invalid attributes would become regular files",
+            Self::ConvertedToSymlink => "shortcuts would become symlinks",
+            Self::Renamed => "names would be truncated to the 255 bytes ext4 allows",
+            Self::Shortened => "files would be shortened to the data in their cluster chains",
+            Self::AttributesDropped => "files would lose their hidden or system attribute",
+        }
+    }
+}
+
+/// Everything that converting a FAT32 filesystem with some `ConversionOptions` would alter or lose, for
+/// `--report-unsupported`. The files are found by applying the same policies as the serializer in the same order, but
+/// without modifying anything, so unlike `Conflicts::scan`, the report takes the options into account.
+#[derive(Debug, PartialEq)]
+pub struct UnsupportedReport {
+    /// the files that are altered or lost, by the way they are
+    pub files: BTreeMap<Change, Vec<String>>,
+    /// the number of converted files with the archive attribute, which ext4 has no equivalent for
+    pub archive_flag_count: usize,
+    pub owner: Owner,
+    pub drop_atime: bool,
+    pub dedup_hardlink: bool,
+    pub archive_bit_list: bool,
+}
+
+impl UnsupportedReport {
+    /// Scans the FAT32 filesystem in the partition at `partition_path` without modifying it.
+    pub fn new(partition_path: &str, options: &ConversionOptions) -> Result<Self> {
+        let partition = ReadOnlyPartition::open(partition_path).context(ErrorCategory::Io)?;
+        let partition_bytes = partition.as_slice();
+        check_ext4_signature(partition_bytes, options.force_reconvert)?;
+        BootSector::from_bytes(partition_bytes).context(ErrorCategory::InvalidFilesystem)?;
+        let fat_fs = FatFs::from_slice(partition_bytes).context(ErrorCategory::InvalidFilesystem)?;
+        Self::scan(&fat_fs, options).context(ErrorCategory::InvalidFilesystem)
+    }
+
+    pub fn scan(fat_fs: &FatFs, options: &ConversionOptions) -> Result<Self> {
+        fat_fs.check_directory(ROOT_FAT_IDX).context("The root directory is damaged")?;
+        let mut report = Self {
+            files: BTreeMap::new(),
+            archive_flag_count: 0,
+            owner: options.owner.unwrap_or_else(Owner::effective),
+            drop_atime: options.drop_atime,
+            dedup_hardlink: options.dedup_hardlink,
+            archive_bit_list: options.archive_bit_list.is_some(),
+        };
+        report.scan_directory(fat_fs, options, fat_fs.root_dir().collect(), "");
+        Ok(report)
+    }
+
+    /// Like `FatTreeSerializer::included_children` followed by `serialize_children`.
+    fn scan_directory(&mut self, fat_fs: &FatFs, options: &ConversionOptions, children: Vec<FatFile>, dir_path: &str) {
+        let cluster_size = fat_fs.cluster_size();
+        let mut included = Vec::new();
+        for mut file in children {
+            let path = format!("{}/{}", dir_path, file.name);
+            if file.dentry.is_volume_label() && file.dentry.attribute_problem().is_none() {
+                continue;
+            }
+            match options.invalid_attributes.apply(&mut file, dir_path, cluster_size) {
+                Ok(Some(invalid)) if invalid.policy == InvalidAttributesPolicy::Skip => {
+                    self.add(Change::Excluded, invalid.to_string());
+                    continue;
+                }
+                Ok(Some(invalid)) => self.add(Change::ConvertedAsRegularFile, invalid.to_string()),
+                Ok(None) => (),
+                Err(error) => {
+                    self.add_unconvertible(options, path, error);
+                    continue;
+                }
+            }
+            match options.filter.excludes(&file) {
+                Ok(true) => {
+                    self.add(Change::Excluded, path);
+                    continue;
+                }
+                Ok(false) => (),
+                Err(error) => {
+                    self.add_unconvertible(options, path, error);
+                    continue;
+                }
+            }
+            if options.convert_shortcuts {
+                if let Err(error) = ShortcutConverter.apply(&mut file, fat_fs) {
+                    self.add_unconvertible(options, path, error);
+                    continue;
+                }
+            }
+            match check_convertible(&file, fat_fs) {
+                Ok(()) => included.push(file),
+                Err(error) => self.add_unconvertible(options, path, error),
+            }
+        }
+
+        if options.truncate_long_names {
+            let mut truncator = LongNameTruncator::new();
+            let decisions = truncator
+                .decide_directory(dir_path, &included)
+                .expect("`LongNameTruncator` decides for every directory");
+            for (file, decision) in included.iter_mut().zip(decisions) {
+                decision.apply(file).expect("truncated names are valid");
+            }
+            for long_name in truncator.long_names() {
+                self.add(Change::Renamed, long_name.to_string());
+            }
+        } else {
+            let mut checker = LongNameChecker::new();
+            checker.check_directory(dir_path, &included);
+            for long_name in checker.long_names() {
+                self.add(Change::Fails, long_name.to_string());
+            }
+        }
+
+        for mut file in included {
+            let path = format!("{}/{}", dir_path, file.name);
+            if file.dentry.is_hidden() || file.dentry.is_system() {
+                self.add(Change::AttributesDropped, path.clone());
+            }
+            if file.dentry.is_dir() {
+                let grandchildren = fat_fs
+                    .read_dir(&file)
+                    .expect("`check_convertible` has checked the directory")
+                    .collect();
+                self.scan_directory(fat_fs, options, grandchildren, &path);
+                continue;
+            }
+
+            if file.dentry.has_archive_flag() {
+                self.archive_flag_count += 1;
+            }
+            if let Some(target) = &file.symlink_target {
+                self.add(Change::ConvertedToSymlink, format!("{} -> {}", path, target));
+            } else if let Some(truncated) = TruncatedFile::normalize(&mut file, dir_path, cluster_size) {
+                self.add(Change::Shortened, truncated.to_string());
+            }
+        }
+    }
+
+    fn add(&mut self, change: Change, file: String) {
+        self.files.entry(change).or_default().push(file);
+    }
+
+    /// Records the file at `path` as skipped or, without `--collect-errors`, as failing the conversion.
+    fn add_unconvertible(&mut self, options: &ConversionOptions, path: String, error: anyhow::Error) {
+        let change = if options.collect_errors {
+            Change::Skipped
+        } else {
+            Change::Fails
+        };
+        self.add(change, format!("{}: {:#}", path, error));
+    }
+
+    /// True if the conversion fails because of one of the files.
+    pub fn has_failures(&self) -> bool {
+        self.files.contains_key(&Change::Fails)
+    }
+
+    /// Prints the files by the way they are altered, listing only the first few of each kind unless `list_all`, and
+    /// then what is altered for every file.
+    pub fn print(&self, list_all: bool) {
+        if self.files.is_empty() {
+            println!("No file would be left out, renamed or shortened");
+        }
+        for (change, files) in &self.files {
+            println!("{} {}:", files.len(), change.description());
+            let listed_count = if list_all { files.len() } else { LISTED_FILE_COUNT };
+            for file in files.iter().take(listed_count) {
+                println!("  {}", file);
+            }
+            if files.len() > listed_count {
+                println!("  and {} more (--verbose lists all)", files.len() - listed_count);
+            }
+        }
+
+        println!("For every file:");
+        println!(
+            "  the owner becomes {}:{}, since FAT has no owners",
+            self.owner.uid, self.owner.gid
+        );
+        println!("  the permissions become 755, or 555 if the file is read-only");
+        println!("  the timestamps are read as UTC, while Windows writes them in local time");
+        if self.drop_atime {
+            println!("  the access time becomes the modification time");
+        } else {
+            println!("  the access time becomes midnight of the access date, since FAT records no time of day");
+        }
+        println!("  only the long name is kept, not the 8.3 name");
+        if self.dedup_hardlink {
+            println!(
+                "  a file with the same content as a previous one becomes a hard link to it, sharing its timestamps"
+            );
+        }
+        if self.archive_flag_count > 0 {
+            let hint = if self.archive_bit_list {
+                "they are listed in the --archive-bit-list file"
+            } else {
+                "--archive-bit-list lists them"
+            };
+            println!(
+                "  {} files lose their archive attribute, which ext4 has no equivalent for; {}",
+                self.archive_flag_count, hint
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fat::{FatImageBuilder, TestFile};
+    use crate::serialization::FileFilter;
+
+    #[test]
+    fn reports_what_the_options_alter() {
+        let long_name = "長".repeat(90);
+        let files = [
+            TestFile::RegularFile { name: long_name.clone(), size: 10 },
+            TestFile::RegularFile { name: "undated".to_string(), size: 10 },
+            TestFile::Directory { name: "label dir".to_string(), children: vec![] },
+            TestFile::RegularFile { name: "hidden".to_string(), size: 10 },
+            TestFile::RegularFile { name: "large".to_string(), size: 5000 },
+            TestFile::Directory {
+                name: "dir".to_string(),
+                children: vec![TestFile::RegularFile { name: "fine".to_string(), size: 10 }],
+            },
+        ];
+        let mut image = FatImageBuilder::new(32 * 1024 * 1024, 1024).build(&files);
+        image.patch_root_dentry("undated", |dentry| dentry.mod_date = 0);
+        image.patch_root_dentry("label dir", |dentry| dentry.attrs = 0x18);
+        image.patch_root_dentry("hidden", |dentry| dentry.attrs |= 0x02);
+        let fat_fs = FatFs::from_slice(image.as_mut_slice()).unwrap();
+
+        let report = UnsupportedReport::scan(&fat_fs, &ConversionOptions::default()).unwrap();
+        let changes: Vec<_> = report.files.keys().copied().collect();
+        assert_eq!(changes, [Change::Fails, Change::AttributesDropped]);
+        assert_eq!(report.files[&Change::Fails].len(), 3);
+        assert_eq!(report.files[&Change::AttributesDropped], ["/hidden"]);
+        assert!(report.has_failures());
+        // every regular file built by `FatImageBuilder` has the archive attribute
+        assert_eq!(report.archive_flag_count, 4);
+
+        let options = ConversionOptions {
+            filter: FileFilter { max_size: Some(2000), min_mod_time: None },
+            truncate_long_names: true,
+            collect_errors: true,
+            invalid_attributes: InvalidAttributesPolicy::RegularFile,
+            ..ConversionOptions::default()
+        };
+        let report = UnsupportedReport::scan(&fat_fs, &options).unwrap();
+        assert!(!report.has_failures());
+        let renamed = &report.files[&Change::Renamed];
+        assert_eq!(renamed.len(), 1);
+        assert!(renamed[0].starts_with(&format!("/{}", long_name)));
+        assert_eq!(report.files[&Change::Skipped].len(), 1);
+        assert!(report.files[&Change::Skipped][0].starts_with("/undated: "));
+        assert_eq!(report.files[&Change::Excluded], ["/large"]);
+        assert_eq!(report.files[&Change::ConvertedAsRegularFile].len(), 1);
+        assert_eq!(report.archive_flag_count, 3);
+    }
+}
@@ -1,8 +1,15 @@
-use std::convert::TryFrom;
-use std::mem::size_of;
+use core::convert::TryFrom;
+use core::mem::size_of;
+#[cfg(feature = "std")]
+use std::cell::Cell;
+#[cfg(feature = "std")]
+use std::thread;
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
 
 use anyhow::{bail, Result};
 use num::CheckedAdd;
+use static_assertions::const_assert;
 
 
 /// Extension trait for a convenience method which transmutes a slice to a slice of another type
@@ -30,6 +37,9 @@ where V: TryFrom<T> + TryFrom<U> + CheckedAdd {
     lhs.checked_add(&rhs)
 }
 
+const_assert!(size_of::<usize>() >= size_of::<u32>());
+const_assert!(size_of::<usize>() <= size_of::<u64>());
+
 /// Converts a `usize` into a `u64`. Since `usize` is at most 64 bits wide, this conversion will never fail.
 pub trait FromUsize {
     fn fromx(n: usize) -> Self;
@@ -52,12 +62,106 @@ impl FromU32 for usize {
     }
 }
 
+/// A number of clusters or a cluster index. The FAT clusters become the ext4 clusters, so both share this unit.
+/// `Clusters`, `Blocks` and `Bytes` are 64 bits wide, so the conversions between them only fail if the result exceeds
+/// 64 bits, and the conversions to the integer types that index memory and the FAT table fail if the value does not
+/// fit. They are meant for the places where the FAT and the ext4 code meet; within one unit, plain integers suffice.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Clusters(pub u64);
+
+/// A number of ext4 blocks or a block index, see `Clusters`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Blocks(pub u64);
+
+/// A number of bytes or a byte offset, see `Clusters`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Bytes(pub u64);
+
+impl Clusters {
+    pub fn to_blocks(self, blocks_per_cluster: u32) -> Option<Blocks> {
+        self.0.checked_mul(u64::from(blocks_per_cluster)).map(Blocks)
+    }
+
+    pub fn to_bytes(self, cluster_size: u32) -> Option<Bytes> {
+        self.0.checked_mul(u64::from(cluster_size)).map(Bytes)
+    }
+
+    /// Returns the index of the first block of the cluster with the index `self`.
+    pub fn first_block_idx(self, blocks_per_cluster: u32) -> Option<usize> {
+        self.to_blocks(blocks_per_cluster)?.to_usize()
+    }
+
+    pub fn to_u32(self) -> Option<u32> {
+        u32::try_from(self.0).ok()
+    }
+}
+
+impl Blocks {
+    /// Returns the index of the cluster containing the block with the index `self`.
+    pub fn to_clusters_rounding_down(self, blocks_per_cluster: u32) -> Clusters {
+        Clusters(self.0 / u64::from(blocks_per_cluster))
+    }
+
+    /// Returns the number of clusters needed for `self` blocks.
+    pub fn to_clusters_rounding_up(self, blocks_per_cluster: u32) -> Clusters {
+        Clusters(num::Integer::div_ceil(&self.0, &u64::from(blocks_per_cluster)))
+    }
+
+    pub fn to_bytes(self, block_size: u32) -> Option<Bytes> {
+        self.0.checked_mul(u64::from(block_size)).map(Bytes)
+    }
+
+    pub fn to_usize(self) -> Option<usize> {
+        usize::try_from(self.0).ok()
+    }
+}
+
+impl Bytes {
+    pub fn to_usize(self) -> Option<usize> {
+        usize::try_from(self.0).ok()
+    }
+}
+
+impl From<u32> for Clusters {
+    fn from(n: u32) -> Self {
+        Self(u64::from(n))
+    }
+}
+
+impl From<usize> for Clusters {
+    fn from(n: usize) -> Self {
+        Self(u64::fromx(n))
+    }
+}
+
+impl From<usize> for Blocks {
+    fn from(n: usize) -> Self {
+        Self(u64::fromx(n))
+    }
+}
+
+impl From<usize> for Bytes {
+    fn from(n: usize) -> Self {
+        Self(u64::fromx(n))
+    }
+}
+
+pub const FNV_OFFSET_BASIS: u64 = 0xCBF2_9CE4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01B3;
+
+/// Updates `hash` with the 64 bit FNV-1a hash of `bytes`, starting with `FNV_OFFSET_BASIS`. Unlike the std's hashers,
+/// the hash is the same in every version of ofs-convert-rs.
+pub fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .fold(hash, |hash, &byte| (hash ^ u64::from(byte)).wrapping_mul(FNV_PRIME))
+}
+
 pub fn exact_log2(n: u32) -> Result<u8> {
-    let log = (f64::from(n)).log2().round() as u8;
-    if 2_u32.pow(u32::from(log)) != n {
+    if !n.is_power_of_two() {
         bail!("n is not a power of 2");
     }
-    Ok(log)
+    Ok(n.trailing_zeros() as u8) // at most 31
 }
 
 pub trait AddUsize<T> {
@@ -97,16 +201,118 @@ impl<T> AddUsize<T> for *mut T {
     }
 }
 
+/// How far a `RateLimiter` may get ahead of its rate before it sleeps, which keeps the number of sleeps low when the
+/// bytes are accounted for in small portions, e.g. one cluster at a time
+#[cfg(feature = "std")]
+const RATE_LIMITER_MAX_AHEAD: Duration = Duration::from_millis(50);
+
+/// Limits the rate at which bytes are copied or zeroed by sleeping whenever they are ahead of the rate. Time in which
+/// no bytes are accounted for is not saved up for later bursts.
+#[cfg(feature = "std")]
+pub struct RateLimiter {
+    bytes_per_sec: u64,
+    /// the time at which the bytes accounted for so far have been transferred at the rate
+    due: Cell<Option<Instant>>,
+}
+
+#[cfg(feature = "std")]
+impl RateLimiter {
+    /// PANICS: Panics if `bytes_per_sec` is 0.
+    pub fn new(bytes_per_sec: u64) -> Self {
+        assert!(bytes_per_sec > 0);
+        Self { bytes_per_sec, due: Cell::new(None) }
+    }
+
+    /// Accounts for `len` bytes, sleeping until the bytes accounted for are no longer ahead of the rate.
+    pub fn consume(&self, len: usize) {
+        let now = Instant::now();
+        let start = self.due.get().map_or(now, |due| due.max(now));
+        let due = start + Duration::from_secs_f64(len as f64 / self.bytes_per_sec as f64);
+        self.due.set(Some(due));
+        if due > now + RATE_LIMITER_MAX_AHEAD {
+            thread::sleep(due - now);
+        }
+    }
+}
+
 #[cfg(test)]
 pub mod tests {
     use std::path::Path;
+    use std::process::Command;
 
     use anyhow::Result;
     use tempfile::NamedTempFile;
 
+    use super::*;
+
     pub fn backup_copy(path: impl AsRef<Path>) -> Result<NamedTempFile> {
         let backup_copy = NamedTempFile::new()?;
         std::fs::copy(path, backup_copy.path())?;
         Ok(backup_copy)
     }
+
+    /// Mounts `source` at `target` until dropped. Requires superuser privileges.
+    pub struct Mount {
+        target: String,
+    }
+
+    impl Mount {
+        pub fn new(source: impl AsRef<Path>, target: impl AsRef<Path>) -> Result<Self> {
+            let source_str = source.as_ref().to_str().unwrap();
+            let target_str = target.as_ref().to_str().unwrap();
+            let mount_output = Command::new("mount").args([source_str, target_str]).output()?;
+            mount_output.status.exit_ok()?;
+            Ok(Self { target: target_str.to_string() })
+        }
+    }
+
+    impl Drop for Mount {
+        fn drop(&mut self) {
+            let _ = Command::new("umount").arg(&self.target).status();
+        }
+    }
+
+    #[test]
+    fn exact_log2_of_powers_of_two() {
+        for log in 0..32 {
+            assert_eq!(exact_log2(1 << log).unwrap(), log as u8);
+        }
+    }
+
+    #[test]
+    fn unit_conversions_are_checked() {
+        assert_eq!(Clusters(3).to_blocks(4), Some(Blocks(12)));
+        assert_eq!(Clusters(3).to_bytes(4096), Some(Bytes(12288)));
+        assert_eq!(Clusters(u64::MAX / 2).to_blocks(4), None);
+        assert_eq!(
+            Clusters(u64::from(u32::MAX)).to_bytes(u32::MAX),
+            Some(Bytes(u64::from(u32::MAX).pow(2)))
+        );
+        assert_eq!(Clusters(1 << 32).to_u32(), None);
+        assert_eq!(Clusters(5).first_block_idx(16), Some(80));
+
+        assert_eq!(Blocks(9).to_clusters_rounding_down(4), Clusters(2));
+        assert_eq!(Blocks(9).to_clusters_rounding_up(4), Clusters(3));
+        assert_eq!(Blocks(8).to_clusters_rounding_up(4), Clusters(2));
+        assert_eq!(Blocks(u64::MAX).to_bytes(1024), None);
+        assert_eq!(Bytes(u64::MAX).to_usize(), usize::try_from(u64::MAX).ok());
+    }
+
+    #[test]
+    fn rate_limiter_sleeps_when_ahead() {
+        let rate_limiter = RateLimiter::new(1_000_000);
+        let start = Instant::now();
+        for _ in 0..50 {
+            rate_limiter.consume(4000);
+        }
+        // 200 KB at 1 MB/s
+        assert!(start.elapsed() >= Duration::from_millis(200) - RATE_LIMITER_MAX_AHEAD);
+    }
+
+    #[test]
+    fn exact_log2_rejects_other_values() {
+        for n in [0, 3, 4095, 4097, (1 << 31) - 1, (1 << 31) + 1, u32::MAX] {
+            assert!(exact_log2(n).is_err());
+        }
+    }
 }
@@ -60,10 +60,10 @@ class OfsConvertTest(unittest.TestCase):
                 error_regex = self._expected_error_regex(input_dir)
                 if error_regex:
                     with self.assertRaises(subprocess.CalledProcessError) as context:
-                        self._convert_to_ext4(tool_runner, ext4_image_path)
+                        self._convert_to_ext4(tool_runner, ext4_image_path, input_dir)
                     self._check_error(context.exception, error_regex)
                 else:
-                    self._convert_to_ext4(tool_runner, ext4_image_path)
+                    self._convert_to_ext4(tool_runner, ext4_image_path, input_dir)
                     self._run_fsck_ext4(tool_runner, ext4_image_path)
                     self._check_contents(tool_runner, image_mounter, fat_image_path,
                                          ext4_image_path)
@@ -71,8 +71,9 @@ class OfsConvertTest(unittest.TestCase):
                 tool_runner.write_output()
                 raise
 
-    def _convert_to_ext4(self, tool_runner, fat_image_path):
-        tool_runner.run([self._OFS_CONVERT, str(fat_image_path)], 'ofs-convert')
+    def _convert_to_ext4(self, tool_runner, fat_image_path, input_dir):
+        args = [self._OFS_CONVERT] + self._ofs_convert_args(input_dir) + [str(fat_image_path)]
+        tool_runner.run(args, 'ofs-convert')
 
     def _handle_fsck_ext4_error(self, exc):
         if exc.returncode & ~12 == 0:
@@ -136,6 +137,14 @@ class OfsConvertTest(unittest.TestCase):
                             'gen script')
         return image_file_path
 
+    @staticmethod
+    def _ofs_convert_args(input_dir):
+        args_file = input_dir / 'ofs-convert.args'
+        if not args_file.exists():
+            return []
+
+        return args_file.read_text().split()
+
     @staticmethod
     def _expected_error_regex(input_dir):
         expected_error_file = input_dir / 'expected.err'
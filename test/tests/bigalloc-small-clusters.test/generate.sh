@@ -0,0 +1,2 @@
+#!/usr/bin/env bash
+touch "$1/file"
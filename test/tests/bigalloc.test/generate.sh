@@ -0,0 +1,6 @@
+#!/usr/bin/env bash
+mkdir "$1/dir"
+for i in $(seq 1 300); do
+	echo "$i" > "$1/dir/file-with-a-long-name-$i"
+done
+dd if=/dev/urandom of="$1/file" bs=1024 count=131073
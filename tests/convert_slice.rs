@@ -0,0 +1,165 @@
+//! Converts in-memory images through the library, the way a program embedding the converter would.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use anyhow::Result;
+use ofs_convert_rs::conversion::{
+    convert_slice, convert_slice_with_catalog, convert_slice_with_hooks, ConversionHooks,
+};
+use ofs_convert_rs::diff_meta::{read_data_extents, read_inode, read_superblock, read_xattrs};
+use ofs_convert_rs::ext4::{has_ext4_signature, Owner, Xattr, LOST_FOUND_INODE_NO, ROOT_INODE_NO};
+use ofs_convert_rs::fat::{FatFile, FatImageBuilder, TestFile};
+use ofs_convert_rs::options::ConversionOptions;
+use ofs_convert_rs::serialization::{ConversionPolicy, FileDecision};
+
+const MIB: usize = 1024 * 1024;
+const S_IFMT: u16 = 0o170_000;
+const S_IFDIR: u16 = 0o040_000;
+const S_IFREG: u16 = 0o100_000;
+
+#[test]
+fn converts_an_image_in_memory() {
+    let content: Vec<u8> = (0..10_000u32).map(|idx| (idx % 251) as u8).collect();
+    let files = [TestFile::Directory {
+        name: "dir".to_string(),
+        children: vec![TestFile::RegularFileWithContent { name: "file".to_string(), content: content.clone() }],
+    }];
+    let mut image = FatImageBuilder::new(32 * MIB, 4096).build(&files);
+    let partition = image.as_mut_slice();
+
+    let stats = convert_slice(partition, &ConversionOptions::default()).unwrap();
+    assert_eq!(stats.fs_stats.directory_count, 1);
+    assert_eq!(stats.fs_stats.regular_file_count, 1);
+    assert!(has_ext4_signature(partition));
+
+    let superblock = read_superblock(partition).unwrap();
+    let block_size = 1024 << superblock.s_log_block_size;
+    let root = read_inode(partition, ROOT_INODE_NO).unwrap();
+    assert_eq!(root.i_mode & S_IFMT, S_IFDIR);
+
+    // the inodes after lost+found are the converted directory and its file
+    let dir = read_inode(partition, LOST_FOUND_INODE_NO + 1).unwrap();
+    assert_eq!(dir.i_mode & S_IFMT, S_IFDIR);
+    let file = read_inode(partition, LOST_FOUND_INODE_NO + 2).unwrap();
+    assert_eq!(file.i_mode & S_IFMT, S_IFREG);
+    assert_eq!(file.i_size_lo as usize, content.len());
+
+    let mut data = Vec::new();
+    for extent in read_data_extents(partition, &file).unwrap() {
+        let start = extent.physical_start as usize * block_size;
+        data.extend_from_slice(&partition[start..start + extent.len as usize * block_size]);
+    }
+    data.truncate(content.len());
+    assert_eq!(data, content);
+}
+
+#[test]
+fn reports_the_converted_files() {
+    let files = [
+        TestFile::Directory {
+            name: "dir".to_string(),
+            children: vec![TestFile::RegularFile { name: "nested".to_string(), size: 5000 }],
+        },
+        TestFile::RegularFile { name: "file".to_string(), size: 0 },
+    ];
+    let mut image = FatImageBuilder::new(32 * MIB, 4096).build(&files);
+    let partition = image.as_mut_slice();
+
+    let catalog = Rc::new(RefCell::new(Vec::new()));
+    let entries = Rc::clone(&catalog);
+    convert_slice_with_catalog(partition, &ConversionOptions::default(), move |file| {
+        let extent_len: u64 = file.extents.iter().map(|extent| u64::from(extent.len)).sum();
+        entries.borrow_mut().push((file.path.to_string(), file.inode_no, extent_len));
+    })
+    .unwrap();
+
+    let mut catalog = catalog.take();
+    catalog.sort();
+    let paths: Vec<_> = catalog.iter().map(|(path, _, _)| path.as_str()).collect();
+    assert_eq!(paths, ["/dir", "/dir/nested", "/file"]);
+    for (path, inode_no, extent_len) in catalog {
+        let inode = read_inode(partition, inode_no).unwrap();
+        let data_len: u64 = read_data_extents(partition, &inode)
+            .unwrap()
+            .iter()
+            .map(|extent| extent.len)
+            .sum();
+        match path.as_str() {
+            "/dir" => assert_eq!(inode.i_mode & S_IFMT, S_IFDIR),
+            "/dir/nested" => {
+                assert_eq!(inode.i_size_lo, 5000);
+                assert_eq!(extent_len, data_len);
+                assert_eq!(data_len, 2);
+            }
+            _ => assert_eq!(inode.i_size_lo, 0),
+        }
+    }
+}
+
+/// Tags the files with their FAT path and gives "/private" to another owner under another name
+struct TagPolicy;
+
+impl ConversionPolicy for TagPolicy {
+    fn decide(&mut self, _file: &FatFile, path: &str) -> Result<FileDecision> {
+        let xattrs = vec![Xattr {
+            name: "user.fat_path".to_string(),
+            value: path.as_bytes().to_vec(),
+        }];
+        Ok(match path {
+            "/private" => FileDecision {
+                name: Some("renamed".to_string()),
+                owner: Some(Owner { uid: 1000, gid: 100 }),
+                perms: Some(0o600),
+                xattrs,
+                ..FileDecision::default()
+            },
+            _ => FileDecision { xattrs, ..FileDecision::default() },
+        })
+    }
+}
+
+#[test]
+fn applies_the_policies_of_the_hooks() {
+    let files = [
+        TestFile::RegularFile { name: "private".to_string(), size: 5000 },
+        TestFile::Directory { name: "dir".to_string(), children: Vec::new() },
+    ];
+    let mut image = FatImageBuilder::new(32 * MIB, 4096).build(&files);
+    let partition = image.as_mut_slice();
+
+    let catalog = Rc::new(RefCell::new(Vec::new()));
+    let entries = Rc::clone(&catalog);
+    let mut hooks = ConversionHooks::default();
+    hooks.add_policy(TagPolicy);
+    hooks.on_file_converted(move |file| entries.borrow_mut().push((file.path.to_string(), file.inode_no)));
+    convert_slice_with_hooks(partition, &ConversionOptions::default(), &hooks).unwrap();
+
+    let mut catalog = catalog.take();
+    catalog.sort();
+    let paths: Vec<_> = catalog.iter().map(|(path, _)| path.as_str()).collect();
+    assert_eq!(paths, ["/dir", "/renamed"]);
+    let (_, dir_inode_no) = catalog[0];
+    let (_, renamed_inode_no) = catalog[1];
+    let fat_path = |value: &str| {
+        vec![Xattr {
+            name: "user.fat_path".to_string(),
+            value: value.as_bytes().to_vec(),
+        }]
+    };
+    assert_eq!(read_xattrs(partition, dir_inode_no).unwrap(), fat_path("/dir"));
+    assert_eq!(read_xattrs(partition, renamed_inode_no).unwrap(), fat_path("/private"));
+    assert!(read_xattrs(partition, ROOT_INODE_NO).unwrap().is_empty());
+
+    let renamed = read_inode(partition, renamed_inode_no).unwrap();
+    assert_eq!((renamed.i_uid, renamed.i_gid), (1000, 100));
+    assert_eq!(renamed.i_mode, S_IFREG | 0o600);
+    let dir = read_inode(partition, dir_inode_no).unwrap();
+    assert_eq!((dir.i_uid, dir.i_gid), (0, 0));
+}
+
+#[test]
+fn rejects_a_misaligned_image() {
+    let mut image = FatImageBuilder::new(32 * MIB, 4096).build(&[]);
+    assert!(convert_slice(&mut image.as_mut_slice()[1..], &ConversionOptions::default()).is_err());
+}
@@ -0,0 +1,22 @@
+//! Predicts the ext4 metadata of a conversion through the library, the way a partition editor would.
+
+use ofs_convert_rs::conversion::convert_slice;
+use ofs_convert_rs::ext4::{Overhead, OverheadOptions};
+use ofs_convert_rs::fat::FatImageBuilder;
+use ofs_convert_rs::options::ConversionOptions;
+
+const MIB: usize = 1024 * 1024;
+
+#[test]
+fn overhead_predicts_free_space() {
+    let fs_len = 64 * MIB;
+    let mut image = FatImageBuilder::new(fs_len, 4096).build(&[]);
+    let stats = convert_slice(image.as_mut_slice(), &ConversionOptions::default()).unwrap();
+    let block_size = stats.fs_stats.block_size;
+
+    let overhead = Overhead::compute(fs_len, block_size, OverheadOptions::default()).unwrap();
+    let predicted_free_block_count = (fs_len / block_size as usize - overhead.total_block_count()) as u64;
+    // the root directory and lost+found take a few blocks of their own
+    assert!(stats.fs_stats.free_block_count <= predicted_free_block_count);
+    assert!(stats.fs_stats.free_block_count + 16 > predicted_free_block_count);
+}